@@ -0,0 +1,90 @@
+//! Protocol spoken over the vswitch's admin socket
+//!
+//! Admin clients connect to a Unix stream socket and send one
+//! command per line. Each response is terminated by a line
+//! containing only ".", and the responses to failed commands
+//! start with "error: "
+//!
+//! The monitor command is the exception, as the vswitch sends
+//! a line per frame in response until the client disconnects
+
+use crate::error::{Error, ProtocolError, TransportError};
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::Path,
+};
+
+/// Line which terminates every response
+pub const END_OF_RESPONSE: &str = ".";
+
+/// Prefix of the responses to failed commands
+pub const ERROR_PREFIX: &str = "error: ";
+
+/// Connection to the admin socket of a running vswitch
+pub struct AdminClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl AdminClient {
+    /// Connect to the admin socket at path
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<AdminClient, TransportError> {
+        let writer = UnixStream::connect(path)?;
+
+        Ok(AdminClient {
+            reader: BufReader::new(writer.try_clone()?),
+            writer,
+        })
+    }
+
+    /// Send command to the vswitch and return its response, or
+    /// a CommandFailed error if the vswitch refused the command
+    pub fn request(&mut self, command: &str) -> Result<String, Error> {
+        writeln!(self.writer, "{}", command).map_err(TransportError::from)?;
+        self.read_response(Vec::new())
+    }
+
+    /// Ask the vswitch to monitor the frames matching filter, and
+    /// call on_frame with the summary of each frame, until the
+    /// vswitch goes away or returns an error for the filter
+    pub fn monitor<F: FnMut(&str)>(&mut self, filter: &str, mut on_frame: F) -> Result<(), Error> {
+        writeln!(self.writer, "monitor {}", filter).map_err(TransportError::from)?;
+
+        loop {
+            let line = self.read_line()?;
+            if line.starts_with(ERROR_PREFIX) {
+                return self.read_response(vec![line]).map(|_| ());
+            }
+            on_frame(&line);
+        }
+    }
+
+    /// Read the rest of a response, which starts with lines
+    fn read_response(&mut self, mut lines: Vec<String>) -> Result<String, Error> {
+        loop {
+            let line = self.read_line()?;
+            if line == END_OF_RESPONSE {
+                break;
+            }
+            lines.push(line);
+        }
+
+        let response = lines.join("\n");
+        match response.strip_prefix(ERROR_PREFIX) {
+            Some(e) => Err(ProtocolError::CommandFailed(e.to_string()).into()),
+            None => Ok(response),
+        }
+    }
+
+    /// Read a line from the vswitch, without its newline
+    fn read_line(&mut self) -> Result<String, TransportError> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(TransportError::Closed);
+        }
+
+        line.truncate(line.trim_end_matches('\n').len());
+        Ok(line)
+    }
+}
@@ -0,0 +1,218 @@
+//! ARP suppression
+//!
+//! Every host which wants to reach another on the virtual LAN first
+//! broadcasts an ARP request for its address, which the vswitch has to
+//! flood to every vport, across the WAN, on large overlays many times
+//! a second. A vport with ARP suppression on keeps a cache of the MACs
+//! of the addresses behind other vports, learned from the ARP requests
+//! and replies it receives from the overlay, and from those the vswitch
+//! pushes to it, and answers its hosts' requests for those addresses
+//! itself, so only requests for addresses it doesn't know leave it
+//!
+//! Entries are forgotten once they haven't been learned again for a
+//! while, so a host which has gone away is asked for again, and a host
+//! which changes its MAC announces the change with a gratuitous ARP,
+//! which replaces the entry. Only untagged ARP for IPv4 over Ethernet
+//! is understood
+//!
+//! RARP announcements, as QEMU sends for a guest which has migrated, are
+//! built here too, for a vport to announce its hosts' MACs with when it
+//! moves to another vswitch, so the vswitch learns where they are
+
+use crate::{
+    mac::MacAddr,
+    utilities::{ETHER_FRAME_MIN, ETHER_HDR, VLAN_ETHER_TYPE},
+};
+use std::{
+    collections::HashMap,
+    net::Ipv4Addr,
+    time::{Duration, Instant},
+};
+
+/// Ether type of ARP messages
+pub const ARP_ETHER_TYPE: u16 = 0x0806;
+
+/// Ether type of RARP messages
+pub const RARP_ETHER_TYPE: u16 = 0x8035;
+
+/// How long an entry is used for after it was last learned
+pub const ARP_CACHE_AGE: Duration = Duration::from_secs(300);
+
+/// Most entries kept at once, so a flood of ARP can't exhaust memory
+const MAX_ENTRIES: usize = 8192;
+
+/* Opcodes of ARP requests and replies */
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+const RARP_REQUEST: u16 = 3;
+
+/// Hardware type, protocol type and address lengths
+/// which start every ARP message for IPv4 over Ethernet
+const ARP_IPV4_ETHERNET: [u8; 6] = [0, 1, 8, 0, 6, 4];
+
+/// Length of an ARP message for IPv4 over Ethernet
+const ARP_LEN: usize = 28;
+
+/// ARP message for IPv4 over Ethernet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Arp {
+    pub opcode: u16,
+    pub sender_mac: MacAddr,
+    pub sender_ip: Ipv4Addr,
+    pub target_mac: MacAddr,
+    pub target_ip: Ipv4Addr,
+}
+
+impl Arp {
+    /// Returns the ARP message for IPv4 over Ethernet which frame
+    /// carries, after a single 802.1Q tag if it has one, if any
+    pub fn parse(frame: &[u8]) -> Option<Arp> {
+        let offset = match is_tagged(frame) {
+            true => ETHER_HDR + 4,
+            false => ETHER_HDR,
+        };
+        if frame.get(offset - 2..offset)? != ARP_ETHER_TYPE.to_be_bytes() {
+            return None;
+        }
+        let arp = frame.get(offset..offset + ARP_LEN)?;
+        if arp[..6] != ARP_IPV4_ETHERNET {
+            return None;
+        }
+
+        Some(Arp {
+            opcode: u16::from_be_bytes([arp[6], arp[7]]),
+            sender_mac: MacAddr(arp[8..14].try_into().unwrap()),
+            sender_ip: Ipv4Addr::new(arp[14], arp[15], arp[16], arp[17]),
+            target_mac: MacAddr(arp[18..24].try_into().unwrap()),
+            target_ip: Ipv4Addr::new(arp[24], arp[25], arp[26], arp[27]),
+        })
+    }
+
+    /// Returns true if this is a request
+    pub fn is_request(&self) -> bool {
+        self.opcode == ARP_REQUEST
+    }
+
+    /// Returns the MAC which the sender says its address has, unless
+    /// it has none (as in probes), or either of them can't be a host's
+    pub fn binding(&self) -> Option<(Ipv4Addr, MacAddr)> {
+        let ip = self.sender_ip;
+        let usable = !ip.is_unspecified() && !ip.is_broadcast() && !ip.is_multicast();
+        (usable && !self.sender_mac.is_multicast() && self.sender_mac != MacAddr::default())
+            .then_some((ip, self.sender_mac))
+    }
+
+    /// Returns the untagged frame which answers this request,
+    /// saying that its target address has mac
+    pub fn reply(&self, mac: MacAddr) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(ETHER_FRAME_MIN);
+        frame.extend_from_slice(&self.sender_mac.0);
+        frame.extend_from_slice(&mac.0);
+        frame.extend_from_slice(&ARP_ETHER_TYPE.to_be_bytes());
+        frame.extend_from_slice(&ARP_IPV4_ETHERNET);
+        frame.extend_from_slice(&ARP_REPLY.to_be_bytes());
+        frame.extend_from_slice(&mac.0);
+        frame.extend_from_slice(&self.target_ip.octets());
+        frame.extend_from_slice(&self.sender_mac.0);
+        frame.extend_from_slice(&self.sender_ip.octets());
+        frame.resize(ETHER_FRAME_MIN, 0);
+        frame
+    }
+}
+
+/// MACs of the addresses behind other vports
+#[derive(Debug, Default)]
+pub struct ArpCache {
+    /* MAC of each address, and when it was last learned */
+    entries: HashMap<Ipv4Addr, (MacAddr, Instant)>,
+    answered: u64,
+}
+
+impl ArpCache {
+    /// Learn the sender's address from frame, if it carries untagged ARP
+    pub fn snoop(&mut self, frame: &[u8], now: Instant) {
+        let arp = Arp::parse(frame).filter(|_| !is_tagged(frame));
+        if let Some((ip, mac)) = arp.and_then(|arp| arp.binding()) {
+            self.insert(ip, mac, now);
+        }
+    }
+
+    /// Learn that ip has mac, e.g. as the vswitch said so
+    pub fn insert(&mut self, ip: Ipv4Addr, mac: MacAddr, now: Instant) {
+        if self.entries.len() >= MAX_ENTRIES && !self.entries.contains_key(&ip) {
+            self.entries
+                .retain(|_, (_, learned)| now.duration_since(*learned) < ARP_CACHE_AGE);
+            if self.entries.len() >= MAX_ENTRIES {
+                return;
+            }
+        }
+        self.entries.insert(ip, (mac, now));
+    }
+
+    /// Returns the MAC of ip, if it was learned recently enough
+    pub fn lookup(&self, ip: Ipv4Addr, now: Instant) -> Option<MacAddr> {
+        self.entries
+            .get(&ip)
+            .filter(|(_, learned)| now.duration_since(*learned) < ARP_CACHE_AGE)
+            .map(|(mac, _)| *mac)
+    }
+
+    /// Returns the reply to the ARP request which frame carries, if it
+    /// asks for an address in the cache. Gratuitous ARPs and probes are
+    /// never answered, as they check nobody else has the address, and
+    /// neither are tagged requests, as the cache doesn't know VLANs
+    pub fn answer(&mut self, frame: &[u8], now: Instant) -> Option<Vec<u8>> {
+        if is_tagged(frame) {
+            return None;
+        }
+        let request = Arp::parse(frame).filter(Arp::is_request)?;
+        if request.binding().is_none() || request.sender_ip == request.target_ip {
+            return None;
+        }
+        let mac = self.lookup(request.target_ip, now)?;
+        self.answered += 1;
+        Some(request.reply(mac))
+    }
+
+    /// Returns how many requests have been answered
+    pub fn answered(&self) -> u64 {
+        self.answered
+    }
+
+    /// Returns how many addresses are cached
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if no addresses are cached
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Returns the broadcast RARP request which announces mac, tagged with
+/// vlan if it is given, which switches learn where mac is from, and hosts
+/// ignore, as nobody answers RARP any more
+pub fn announcement(mac: MacAddr, vlan: Option<u16>) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(ETHER_FRAME_MIN);
+    frame.extend_from_slice(&MacAddr::BROADCAST.0);
+    frame.extend_from_slice(&mac.0);
+    if let Some(vid) = vlan {
+        frame.extend_from_slice(&VLAN_ETHER_TYPE.to_be_bytes());
+        frame.extend_from_slice(&(vid & 0x0FFF).to_be_bytes());
+    }
+    frame.extend_from_slice(&RARP_ETHER_TYPE.to_be_bytes());
+    frame.extend_from_slice(&ARP_IPV4_ETHERNET);
+    frame.extend_from_slice(&RARP_REQUEST.to_be_bytes());
+    for _ in 0..2 {
+        frame.extend_from_slice(&mac.0);
+        frame.extend_from_slice(&Ipv4Addr::UNSPECIFIED.octets());
+    }
+    frame.resize(ETHER_FRAME_MIN, 0);
+    frame
+}
+
+/// Returns true if frame has an 802.1Q tag
+fn is_tagged(frame: &[u8]) -> bool {
+    frame.get(12..14) == Some(&VLAN_ETHER_TYPE.to_be_bytes())
+}
@@ -0,0 +1,189 @@
+//! Pre-shared key authentication of the frames between vports and the vswitch
+//!
+//! With frame authentication on, every datagram sent over UDP between
+//! vports and the vswitch (and between peered vswitches) ends with its
+//! sender's ID, its next sequence number and a tag, the first TAG_LEN bytes of
+//! the HMAC-SHA256 of the rest of the datagram under a key they share.
+//! Datagrams whose tag doesn't match are dropped, so hosts without the
+//! key can't inject frames into the L2VPN network
+//!
+//! Datagrams are also dropped if their sequence number has already
+//! been received from their sender, or is too far behind the highest
+//! received from it to tell, so a datagram captured on the underlay
+//! can't be replayed to confuse MAC learning or deliver its frame
+//! again. Senders are told apart by the random ID they pick when they
+//! start, which is signed with the rest of the datagram, rather than by
+//! the address datagrams come from, so replaying a datagram from another
+//! address doesn't get past the window either. Senders start counting
+//! from the time they start, in nanoseconds, so their sequence numbers
+//! keep rising if they restart with the same ID
+//!
+//! Unlike DTLS, this needs no handshake, so it carries on across
+//! restarts and works between peered vswitches, but frames aren't
+//! encrypted
+
+use crate::{error::ConfigError, mtu::AUTH_OVERHEAD};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Lengths of the sender ID and sequence number which each datagram carries
+const SENDER_ID_LEN: usize = 8;
+const SEQUENCE_LEN: usize = 8;
+
+/// Length of the tag which each datagram carries, after its sequence number
+const TAG_LEN: usize = AUTH_OVERHEAD - SENDER_ID_LEN - SEQUENCE_LEN;
+
+/// How many of the sequence numbers below the highest received from
+/// a sender are tracked, so datagrams reordered by less than this are
+/// still accepted
+pub const REPLAY_WINDOW: u64 = 128;
+
+/// Shortest and longest pre-shared keys, in bytes
+const PSK_MIN: usize = 16;
+const PSK_MAX: usize = 64;
+
+/// Parse the contents of a pre-shared key file, which hold the key as
+/// hex on a single line, e.g. as written by openssl rand -hex 32
+pub fn parse_psk(contents: &str) -> Result<Vec<u8>, ConfigError> {
+    let hex = contents.trim_end_matches(['\r', '\n']);
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(ConfigError::Psk("expected the key as hex digits"));
+    }
+    let psk: Vec<u8> = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect();
+    if !(PSK_MIN..=PSK_MAX).contains(&psk.len()) {
+        return Err(ConfigError::Psk("key must be 16 to 64 bytes"));
+    }
+    Ok(psk)
+}
+
+/// Signs and verifies datagrams with a pre-shared key. Clones share
+/// the sequence numbers given to the datagrams they sign, and those
+/// received from each sender
+#[derive(Clone)]
+pub struct FrameAuth {
+    /// HMAC keyed with the pre-shared key, which is cloned for each datagram
+    hmac: Hmac<Sha256>,
+    sender_id: u64,
+    next_sequence: Arc<AtomicU64>,
+    replay_windows: Arc<Mutex<HashMap<u64, ReplayWindow>>>,
+}
+
+impl FrameAuth {
+    /// Returns the signer of datagrams with psk, which started at now
+    /// and signs them as sender_id, which is to be picked at random
+    pub fn new(psk: &[u8], sender_id: u64, now: SystemTime) -> FrameAuth {
+        let started = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        FrameAuth {
+            hmac: Hmac::new_from_slice(psk).expect("HMAC takes keys of any length"),
+            sender_id,
+            next_sequence: Arc::new(AtomicU64::new(
+                u64::try_from(started.as_nanos()).unwrap_or(u64::MAX),
+            )),
+            replay_windows: Arc::default(),
+        }
+    }
+
+    /// Returns frame followed by our sender ID, the next sequence
+    /// number and its tag, ready to be sent
+    pub fn sign(&self, frame: &[u8]) -> Vec<u8> {
+        let sequence = self.next_sequence.fetch_add(1, Ordering::Relaxed);
+        let mut datagram = Vec::with_capacity(frame.len() + AUTH_OVERHEAD);
+        datagram.extend_from_slice(frame);
+        datagram.extend_from_slice(&self.sender_id.to_be_bytes());
+        datagram.extend_from_slice(&sequence.to_be_bytes());
+
+        let mut hmac = self.hmac.clone();
+        hmac.update(&datagram);
+        datagram.extend_from_slice(&hmac.finalize().into_bytes()[..TAG_LEN]);
+        datagram
+    }
+
+    /// Returns the frame which datagram carries, without its sender ID,
+    /// sequence number and tag. None is returned if the tag is missing
+    /// or wasn't made with our key, or the datagram is a replay
+    pub fn verify<'a>(&self, datagram: &'a [u8]) -> Option<&'a [u8]> {
+        let signed_len = datagram.len().checked_sub(TAG_LEN)?;
+        let sequence_at = signed_len.checked_sub(SEQUENCE_LEN)?;
+        let frame_len = sequence_at.checked_sub(SENDER_ID_LEN)?;
+        let (signed, tag) = datagram.split_at(signed_len);
+
+        /* The tag is compared in constant time, so it can't be guessed byte by byte */
+        let mut hmac = self.hmac.clone();
+        hmac.update(signed);
+        hmac.verify_truncated_left(tag).ok()?;
+
+        /* Only authentic datagrams get a window, so forgeries can't fill the map */
+        let sender_id = u64::from_be_bytes(signed[frame_len..sequence_at].try_into().unwrap());
+        let sequence = u64::from_be_bytes(signed[sequence_at..].try_into().unwrap());
+        let mut replay_windows = self
+            .replay_windows
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        replay_windows
+            .entry(sender_id)
+            .or_default()
+            .accept(sequence)
+            .then_some(&datagram[..frame_len])
+    }
+}
+
+/* The key is kept out of debug output */
+impl fmt::Debug for FrameAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FrameAuth").finish_non_exhaustive()
+    }
+}
+
+/// Sequence numbers received from one sender: the highest, and which of
+/// the REPLAY_WINDOW below it have been received
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    highest: Option<u64>,
+    /// Bit n is set if highest - n has been received
+    received: u128,
+}
+
+impl ReplayWindow {
+    /// Returns true, and records sequence as received, if it is new.
+    /// A window which has received nothing yet accepts anything, so
+    /// only the sequence numbers of authentic datagrams are to be given
+    fn accept(&mut self, sequence: u64) -> bool {
+        let Some(highest) = self.highest else {
+            self.highest = Some(sequence);
+            self.received = 1;
+            return true;
+        };
+
+        if sequence > highest {
+            let ahead = sequence - highest;
+            self.received = match ahead < REPLAY_WINDOW {
+                true => self.received << ahead,
+                false => 0,
+            } | 1;
+            self.highest = Some(sequence);
+            return true;
+        }
+
+        /* Too old to tell whether it has been received, so it may be a replay */
+        let behind = highest - sequence;
+        if behind >= REPLAY_WINDOW {
+            return false;
+        }
+        let bit = 1u128 << behind;
+        let new = self.received & bit == 0;
+        self.received |= bit;
+        new
+    }
+}
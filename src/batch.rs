@@ -0,0 +1,142 @@
+//! Batching small frames into shared datagrams
+//!
+//! Small frames, such as ARP requests and TCP ACKs, each cost a system
+//! call and a datagram of their own, with its IP and UDP headers. A
+//! vport can ask the vswitch to batch them when it joins, and once the
+//! vswitch agrees, each of them holds the small frames it sends the
+//! other over UDP for a moment, so those which follow share a datagram
+//! with them. A batch is sent when the next frame wouldn't fit in it,
+//! once BATCH_TIMEOUT has passed since its first frame, or before a
+//! larger frame, which isn't batched, so frames stay in order
+//!
+//! A batch is sent after a header shaped like a control frame's, but
+//! sent to a reserved multicast MAC of its own, so it is never taken for
+//! a frame, and holds each frame after its length. A batch of one frame
+//! is sent as the frame alone, as the header would only make it larger
+
+use crate::{control::CONTROL_ETHER_TYPE, utilities::ETHER_HDR};
+use std::time::{Duration, Instant};
+
+/// Locally administered multicast MAC which batches are sent to
+pub const BATCH_MAC: [u8; 6] = [0x03, 0x4c, 0x32, 0x56, 0x50, 0x42];
+
+/// Length of the header in front of every batch
+pub const BATCH_HDR_LEN: usize = ETHER_HDR;
+
+/// Length of the length in front of each frame in a batch
+const FRAME_LEN_LEN: usize = 2;
+
+/// Largest frame which is batched, as larger ones fill enough of a
+/// datagram on their own
+pub const BATCH_FRAME_MAX: usize = 256;
+
+/// Largest batch, including its header, which fits through a 1500 byte
+/// underlay MTU with room for the headers of any tunnel it is sent in
+pub const BATCH_MAX: usize = 1200;
+
+/// How long the first frame of a batch waits for others to join it
+pub const BATCH_TIMEOUT: Duration = Duration::from_millis(1);
+
+/// Frames waiting to be sent together
+#[derive(Clone, Debug)]
+pub struct Batch {
+    /* Largest batch, including its header */
+    max: usize,
+    datagram: Vec<u8>,
+    frames: usize,
+    /* When the first frame was added */
+    started: Option<Instant>,
+}
+
+impl Batch {
+    /// Returns an empty batch which grows to at most max bytes,
+    /// including its header, or BATCH_MAX if that is smaller
+    pub fn new(max: usize) -> Batch {
+        let mut datagram = Vec::with_capacity(BATCH_MAX);
+        datagram.extend_from_slice(&BATCH_MAC);
+        datagram.extend_from_slice(&[0u8; 6]);
+        datagram.extend_from_slice(&CONTROL_ETHER_TYPE.to_be_bytes());
+        Batch {
+            max: max.min(BATCH_MAX),
+            datagram,
+            frames: 0,
+            started: None,
+        }
+    }
+
+    /// Returns true if frame is small enough to be batched, and would
+    /// fit in the batch on its own
+    pub fn holds(&self, frame: &[u8]) -> bool {
+        frame.len() <= BATCH_FRAME_MAX && BATCH_HDR_LEN + FRAME_LEN_LEN + frame.len() <= self.max
+    }
+
+    /// Add frame, which the batch holds, to the batch at now,
+    /// returning the batch to send first if frame doesn't fit in it
+    pub fn push(&mut self, frame: &[u8], now: Instant) -> Option<Vec<u8>> {
+        let full = match self.datagram.len() + FRAME_LEN_LEN + frame.len() > self.max {
+            true => self.take(),
+            false => None,
+        };
+        self.datagram
+            .extend_from_slice(&(frame.len() as u16).to_be_bytes());
+        self.datagram.extend_from_slice(frame);
+        self.frames += 1;
+        self.started.get_or_insert(now);
+        full
+    }
+
+    /// Returns the datagram which sends the frames in the batch,
+    /// emptying it, or None if it is already empty
+    pub fn take(&mut self) -> Option<Vec<u8>> {
+        let datagram = match self.frames {
+            0 => return None,
+            1 => self.datagram[BATCH_HDR_LEN + FRAME_LEN_LEN..].to_vec(),
+            _ => self.datagram.clone(),
+        };
+        self.datagram.truncate(BATCH_HDR_LEN);
+        self.frames = 0;
+        self.started = None;
+        Some(datagram)
+    }
+
+    /// Returns the number of frames in the batch
+    pub fn len(&self) -> usize {
+        self.frames
+    }
+
+    /// Returns true if the batch has no frames
+    pub fn is_empty(&self) -> bool {
+        self.frames == 0
+    }
+
+    /// Returns when the batch has to be sent, if it has any frames
+    pub fn deadline(&self) -> Option<Instant> {
+        self.started.map(|started| started + BATCH_TIMEOUT)
+    }
+}
+
+/// Returns true if datagram is a batch of frames, rather than a frame
+pub fn is_batch(datagram: &[u8]) -> bool {
+    datagram.len() > BATCH_HDR_LEN
+        && datagram[..6] == BATCH_MAC
+        && datagram[12..14] == CONTROL_ETHER_TYPE.to_be_bytes()
+}
+
+/// Returns the frames in batch, in the order they were added,
+/// or None if it isn't a batch, or a length overruns it
+pub fn unbatch(batch: &[u8]) -> Option<Vec<&[u8]>> {
+    if !is_batch(batch) {
+        return None;
+    }
+    let mut frames = Vec::new();
+    let mut rest = &batch[BATCH_HDR_LEN..];
+    while let [l0, l1, after @ ..] = rest {
+        let (frame, after) = after.split_at_checked(usize::from(u16::from_be_bytes([*l0, *l1])))?;
+        frames.push(frame);
+        rest = after;
+    }
+    match rest.is_empty() {
+        true => Some(frames),
+        false => None,
+    }
+}
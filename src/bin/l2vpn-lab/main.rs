@@ -0,0 +1,326 @@
+//! Lab orchestrator for the L2VPN
+//!
+//! This reads a YAML topology of vswitches and vports (see
+//! topology.rs for the format) and brings the whole network up on
+//! this host, so demos and bug reports can be reproduced with a
+//! single command, and torn down again with another
+//!
+//! Each vswitch runs in the host's network namespace. Each vport
+//! runs in a network namespace of its own, named <lab>-<vport>,
+//! which is joined to the host by a veth pair on 172.31.<n>.0/30,
+//! where n is the vport's position in the topology. Impairments are
+//! applied to the vport's end of the veth pair with netem, and its
+//! tap interface (or a VLAN interface on it) is given the vport's IP
+//!
+//! The vswitch and vport binaries are expected next to this one,
+//! as cargo builds them, and their output goes to <name>.log in
+//! the lab's directory, which also records their process IDs
+//!
+//! Running labs needs root, iproute2 and tc
+//!
+//! Usage: l2vpn-lab up|down|check <topology.yaml>
+
+mod topology;
+
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+use std::{
+    env, fs,
+    fs::File,
+    io,
+    net::Ipv4Addr,
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{Command, ExitCode, Stdio},
+    thread,
+    time::Duration,
+};
+use topology::{Topology, Vport};
+
+const USAGE: &str = "Usage: l2vpn-lab up|down|check <topology.yaml>";
+
+/// Name of the file in the lab's directory holding the IDs of its processes
+const PIDS_FILE: &str = "pids";
+
+/// How long the processes of a lab are given to exit when it is torn down
+const EXIT_GRACE: Duration = Duration::from_millis(500);
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().collect();
+    let [_, command, path] = args.as_slice() else {
+        eprintln!("{}", USAGE);
+        return ExitCode::FAILURE;
+    };
+
+    let topology = match Topology::load(Path::new(path)) {
+        Ok(topology) => topology,
+        Err(e) => {
+            eprintln!("Got error while loading topology '{}':\n{}", path, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let result = match command.as_str() {
+        "up" => up(&topology),
+        "down" => {
+            down(&topology);
+            Ok(())
+        }
+        "check" => {
+            println!("Topology OK");
+            Ok(())
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Bring up every vswitch and vport in topology, tearing
+/// down whatever was brought up if any of it fails
+fn up(topology: &Topology) -> Result<(), String> {
+    let dir = lab_dir(topology);
+    if dir.exists() {
+        return Err(format!(
+            "Lab '{}' is already up, as '{}' exists. Tear it down first",
+            topology.name,
+            dir.display()
+        ));
+    }
+
+    let bin_dir = env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(Path::to_path_buf))
+        .ok_or("Could not find the directory of l2vpn-lab")?;
+    let vswitch_bin = bin_dir.join("vswitch");
+    let vport_bin = bin_dir.join("vport");
+    for bin in [&vswitch_bin, &vport_bin] {
+        if !bin.is_file() {
+            return Err(format!(
+                "Could not find '{}', build it with 'cargo build' first",
+                bin.display()
+            ));
+        }
+    }
+
+    fs::create_dir_all(&dir)
+        .map_err(|e| format!("Got error while creating '{}': {}", dir.display(), e))?;
+
+    let mut pids = Vec::new();
+    let result = start(topology, &dir, &vswitch_bin, &vport_bin, &mut pids);
+
+    /* Record the processes even if some failed to start, so they can be stopped */
+    let pid_lines: String = pids.iter().map(|pid| format!("{}\n", pid)).collect();
+    if let Err(e) = fs::write(dir.join(PIDS_FILE), pid_lines) {
+        eprintln!("Got error while saving process IDs: {}", e);
+    }
+
+    if let Err(e) = result {
+        down(topology);
+        return Err(e);
+    }
+
+    println!(
+        "Lab '{}' is up, logs are in '{}'",
+        topology.name,
+        dir.display()
+    );
+    for vport in topology.vports.iter() {
+        println!(
+            "  vport '{}' on switch '{}': ip netns exec {} <command>",
+            vport.name,
+            vport.switch,
+            namespace(topology, vport)
+        );
+    }
+    Ok(())
+}
+
+/// Start the vswitches, then set up the namespace of each
+/// vport and start it, adding the ID of each process to pids
+fn start(
+    topology: &Topology,
+    dir: &Path,
+    vswitch_bin: &Path,
+    vport_bin: &Path,
+    pids: &mut Vec<u32>,
+) -> Result<(), String> {
+    for switch in topology.switches.iter() {
+        let mut args = vec![switch.port.to_string()];
+        for peer in topology.peers_of(switch) {
+            args.extend(["--peer".to_string(), format!("127.0.0.1:{}", peer.port)]);
+        }
+        args.extend(switch.args.iter().cloned());
+
+        let mut command = Command::new(vswitch_bin);
+        command.args(&args);
+        pids.push(spawn(command, &dir.join(format!("{}.log", switch.name)))?);
+    }
+
+    for (index, vport) in topology.vports.iter().enumerate() {
+        let switch = topology.switch(&vport.switch).unwrap();
+        let netns = namespace(topology, vport);
+        let host_veth = format!("{}-{}", topology.name, index);
+        let (host_ip, vport_ip) = underlay_ips(index);
+
+        ip(&["netns", "add", &netns])?;
+        ip(&[
+            "link", "add", &host_veth, "type", "veth", "peer", "name", "eth0", "netns", &netns,
+        ])?;
+        let host_cidr = format!("{}/30", host_ip);
+        let vport_cidr = format!("{}/30", vport_ip);
+        ip(&["addr", "add", &host_cidr, "dev", &host_veth])?;
+        ip(&["link", "set", &host_veth, "up"])?;
+        ip(&["-n", &netns, "addr", "add", &vport_cidr, "dev", "eth0"])?;
+        ip(&["-n", &netns, "link", "set", "eth0", "up"])?;
+        ip(&["-n", &netns, "link", "set", "lo", "up"])?;
+
+        if let Some(netem) = vport.impairment.netem_args() {
+            let mut args = vec!["netns", "exec", &netns, "tc", "qdisc", "add"];
+            args.extend(["dev", "eth0", "root", "netem"]);
+            args.extend(netem.iter().map(String::as_str));
+            ip(&args)?;
+        }
+
+        /* The vport attaches to tap0, which it expects to exist, as setup.sh would create it */
+        ip(&["-n", &netns, "tuntap", "add", "dev", "tap0", "mode", "tap"])?;
+        ip(&["-n", &netns, "link", "set", "tap0", "up"])?;
+        if let Some((addr, prefix_len)) = vport.ip {
+            let cidr = format!("{}/{}", addr, prefix_len);
+            match vport.vlan {
+                Some(vlan) => {
+                    let intf = format!("tap0.{}", vlan);
+                    let vlan = vlan.to_string();
+                    ip(&[
+                        "-n", &netns, "link", "add", "link", "tap0", "name", &intf, "type", "vlan",
+                        "id", &vlan,
+                    ])?;
+                    ip(&["-n", &netns, "link", "set", &intf, "up"])?;
+                    ip(&["-n", &netns, "addr", "add", &cidr, "dev", &intf])?;
+                }
+                None => ip(&["-n", &netns, "addr", "add", &cidr, "dev", "tap0"])?,
+            }
+        }
+
+        let mut command = Command::new("ip");
+        command.args(["netns", "exec", &netns]).arg(vport_bin);
+        command.args(&vport.args);
+        command.args([host_ip.to_string(), switch.port.to_string()]);
+        pids.push(spawn(command, &dir.join(format!("{}.log", vport.name)))?);
+    }
+
+    Ok(())
+}
+
+/// Stop the processes of the lab described by topology, and
+/// remove its namespaces and directory. Everything is attempted,
+/// so a lab which only partly came up can still be torn down
+fn down(topology: &Topology) {
+    let dir = lab_dir(topology);
+
+    let pids = fs::read_to_string(dir.join(PIDS_FILE)).unwrap_or_default();
+    let pids: Vec<Pid> = pids
+        .lines()
+        .filter_map(|line| line.parse().ok())
+        .map(Pid::from_raw)
+        .collect();
+    for pid in pids.iter() {
+        /* The process may have already exited */
+        let _ = kill(*pid, Signal::SIGTERM);
+    }
+    if !pids.is_empty() {
+        thread::sleep(EXIT_GRACE);
+    }
+
+    /* Removing a namespace removes the veth pair and tap interface in it */
+    for vport in topology.vports.iter() {
+        let netns = namespace(topology, vport);
+        if Path::new("/run/netns").join(&netns).exists() {
+            if let Err(e) = ip(&["netns", "del", &netns]) {
+                eprintln!("{}", e);
+            }
+        }
+    }
+
+    if let Err(e) = fs::remove_dir_all(&dir) {
+        if e.kind() != io::ErrorKind::NotFound {
+            eprintln!("Got error while removing '{}': {}", dir.display(), e);
+        }
+    }
+
+    println!("Lab '{}' is down", topology.name);
+}
+
+/// Start command in the background with its output going to the
+/// file at log_path, returning its process ID. It is put in a process
+/// group of its own, so it carries on after l2vpn-lab exits
+fn spawn(mut command: Command, log_path: &Path) -> Result<u32, String> {
+    let log = File::create(log_path)
+        .map_err(|e| format!("Got error while creating '{}': {}", log_path.display(), e))?;
+    let log_err = log
+        .try_clone()
+        .map_err(|e| format!("Got error while opening '{}': {}", log_path.display(), e))?;
+
+    command
+        .stdin(Stdio::null())
+        .stdout(log)
+        .stderr(log_err)
+        .process_group(0)
+        .spawn()
+        .map(|child| child.id())
+        .map_err(|e| {
+            format!(
+                "Got error while starting {:?}: {}",
+                command.get_program(),
+                e
+            )
+        })
+}
+
+/// Run the ip command with args, returning an error holding its output if it fails
+fn ip(args: &[&str]) -> Result<(), String> {
+    let output = Command::new("ip")
+        .args(args)
+        .output()
+        .map_err(|e| format!("Got error while running ip: {}", e))?;
+
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(format!(
+            "'ip {}' failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+    }
+}
+
+/// Returns the directory holding the logs and process IDs of the lab
+fn lab_dir(topology: &Topology) -> PathBuf {
+    env::temp_dir().join(format!("l2vpn-lab-{}", topology.name))
+}
+
+/// Returns the name of the network namespace of vport
+fn namespace(topology: &Topology, vport: &Vport) -> String {
+    format!("{}-{}", topology.name, vport.name)
+}
+
+/// Returns the addresses of the host's and the vport's ends of
+/// the veth pair of the vport at index in the topology
+fn underlay_ips(index: usize) -> (Ipv4Addr, Ipv4Addr) {
+    let subnet = index as u8;
+    (
+        Ipv4Addr::new(172, 31, subnet, 1),
+        Ipv4Addr::new(172, 31, subnet, 2),
+    )
+}
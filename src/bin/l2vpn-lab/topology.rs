@@ -0,0 +1,405 @@
+//! Topology files for l2vpn-lab
+//!
+//! A topology is a YAML file describing the vswitches, the vports
+//! attached to them, and the impairments of each vport's link, e.g.
+//!
+//! ```yaml
+//! name: demo
+//! switches:
+//!   sw1:
+//!     port: 5000
+//!     peers: [sw2]
+//!   sw2:
+//!     port: 5001
+//!     args: [--admin-socket, /tmp/sw2.sock]
+//! vports:
+//!   a:
+//!     switch: sw1
+//!     ip: 10.0.0.1/24
+//!   b:
+//!     switch: sw2
+//!     ip: 10.0.1.1/24
+//!     vlan: 10
+//!     impairment:
+//!       delay_ms: 50
+//!       loss_percent: 1
+//! ```
+//!
+//! The name defaults to the file's name without its extension
+
+use std::{fs, net::Ipv4Addr, path::Path};
+use yaml_rust2::{yaml::Hash, Yaml, YamlLoader};
+
+/// Longest lab name, so the names of its interfaces fit in IFNAMSIZ
+pub const MAX_NAME_LEN: usize = 8;
+
+/// Most vports in a lab, as each gets its own /30 of the lab's underlay
+pub const MAX_VPORTS: usize = 256;
+
+/// Lab described by a topology file
+#[derive(Debug)]
+pub struct Topology {
+    pub name: String,
+    pub switches: Vec<Switch>,
+    pub vports: Vec<Vport>,
+}
+
+/// vswitch in a lab, which runs in the host's network namespace
+#[derive(Debug)]
+pub struct Switch {
+    pub name: String,
+    pub port: u16,
+    /// Names of the switches this one is peered with
+    pub peers: Vec<String>,
+    /// Further command line arguments for the vswitch
+    pub args: Vec<String>,
+}
+
+/// vport in a lab, which runs in a network namespace of its own
+#[derive(Debug)]
+pub struct Vport {
+    pub name: String,
+    /// Name of the switch the vport connects to
+    pub switch: String,
+    /// Address (with prefix length) of the vport's tap interface
+    pub ip: Option<(Ipv4Addr, u8)>,
+    /// VLAN of the tap interface's address, if it is tagged
+    pub vlan: Option<u16>,
+    pub impairment: Impairment,
+    /// Further command line arguments for the vport
+    pub args: Vec<String>,
+}
+
+/// Impairments of the link between a vport and its switch,
+/// applied to the frames the vport sends with netem
+#[derive(Debug, Default)]
+pub struct Impairment {
+    pub delay_ms: Option<u32>,
+    pub jitter_ms: Option<u32>,
+    pub loss_percent: Option<f64>,
+    pub duplicate_percent: Option<f64>,
+    pub corrupt_percent: Option<f64>,
+    /// Rate limit, in tc's units, e.g. 10mbit
+    pub rate: Option<String>,
+}
+
+impl Impairment {
+    /// Returns the arguments which follow 'netem' in a tc
+    /// command, or None if the link is not impaired
+    pub fn netem_args(&self) -> Option<Vec<String>> {
+        let mut args = Vec::new();
+        if let Some(delay) = self.delay_ms {
+            args.extend(["delay".to_string(), format!("{}ms", delay)]);
+            if let Some(jitter) = self.jitter_ms {
+                args.push(format!("{}ms", jitter));
+            }
+        }
+        for (name, percent) in [
+            ("loss", self.loss_percent),
+            ("duplicate", self.duplicate_percent),
+            ("corrupt", self.corrupt_percent),
+        ] {
+            if let Some(percent) = percent {
+                args.extend([name.to_string(), format!("{}%", percent)]);
+            }
+        }
+        if let Some(rate) = &self.rate {
+            args.extend(["rate".to_string(), rate.clone()]);
+        }
+
+        match args.is_empty() {
+            true => None,
+            false => Some(args),
+        }
+    }
+}
+
+impl Topology {
+    /// Load and validate the topology in the file at path
+    pub fn load(path: &Path) -> Result<Topology, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let docs = YamlLoader::load_from_str(&contents).map_err(|e| e.to_string())?;
+        let [doc] = docs.as_slice() else {
+            return Err("Expected the file to hold one YAML document".to_string());
+        };
+        let top = as_hash(doc, "topology")?;
+        check_keys(top, "topology", &["name", "switches", "vports"])?;
+
+        let name = match get(top, "name") {
+            Some(name) => as_string(name, "name")?,
+            None => path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        };
+
+        let mut switches = Vec::new();
+        for (switch_name, switch) in entries(get(top, "switches"), "switches")? {
+            let switch_name = as_string(switch_name, "switch name")?;
+            let what = format!("switch '{}'", switch_name);
+            let switch = as_hash(switch, &what)?;
+            check_keys(switch, &what, &["port", "peers", "args"])?;
+
+            let port = get(switch, "port")
+                .and_then(Yaml::as_i64)
+                .and_then(|port| u16::try_from(port).ok())
+                .filter(|port| *port != 0)
+                .ok_or_else(|| format!("{} needs a port between 1 and 65535", what))?;
+
+            switches.push(Switch {
+                name: switch_name,
+                port,
+                peers: string_list(get(switch, "peers"), &format!("{} peers", what))?,
+                args: string_list(get(switch, "args"), &format!("{} args", what))?,
+            });
+        }
+
+        let mut vports = Vec::new();
+        for (vport_name, vport) in entries(get(top, "vports"), "vports")? {
+            let vport_name = as_string(vport_name, "vport name")?;
+            let what = format!("vport '{}'", vport_name);
+            let vport = as_hash(vport, &what)?;
+            check_keys(
+                vport,
+                &what,
+                &["switch", "ip", "vlan", "impairment", "args"],
+            )?;
+
+            let switch = get(vport, "switch")
+                .ok_or_else(|| format!("{} needs a switch", what))
+                .and_then(|switch| as_string(switch, &format!("{} switch", what)))?;
+            let ip = match get(vport, "ip") {
+                Some(ip) => Some(
+                    parse_cidr(&as_string(ip, &format!("{} ip", what))?)
+                        .ok_or_else(|| format!("{} ip must be an IPv4 address/prefix_len", what))?,
+                ),
+                None => None,
+            };
+            let vlan = match get(vport, "vlan") {
+                Some(vlan) => Some(
+                    vlan.as_i64()
+                        .and_then(|vlan| u16::try_from(vlan).ok())
+                        .filter(|vlan| (1..4095).contains(vlan))
+                        .ok_or_else(|| format!("{} vlan must be between 1 and 4094", what))?,
+                ),
+                None => None,
+            };
+            let impairment = match get(vport, "impairment") {
+                Some(impairment) => parse_impairment(impairment, &format!("{} impairment", what))?,
+                None => Impairment::default(),
+            };
+
+            vports.push(Vport {
+                name: vport_name,
+                switch,
+                ip,
+                vlan,
+                impairment,
+                args: string_list(get(vport, "args"), &format!("{} args", what))?,
+            });
+        }
+
+        let topology = Topology {
+            name,
+            switches,
+            vports,
+        };
+        let errors = topology.validate();
+        match errors.is_empty() {
+            true => Ok(topology),
+            false => Err(errors.join("\n")),
+        }
+    }
+
+    /// Returns every problem with the topology, such as
+    /// vports connecting to switches which don't exist
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        let name_ok = |name: &str| {
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        };
+        if !name_ok(&self.name) || self.name.len() > MAX_NAME_LEN {
+            errors.push(format!(
+                "Lab name '{}' must be 1 to {} letters, digits, '-' or '_'",
+                self.name, MAX_NAME_LEN
+            ));
+        }
+        if self.switches.is_empty() {
+            errors.push("The topology has no switches".to_string());
+        }
+        if self.vports.len() > MAX_VPORTS {
+            errors.push(format!("The topology has more than {} vports", MAX_VPORTS));
+        }
+
+        for (i, switch) in self.switches.iter().enumerate() {
+            if let Some(other) = self.switches[..i].iter().find(|s| s.port == switch.port) {
+                errors.push(format!(
+                    "Switches '{}' and '{}' both use port {}",
+                    other.name, switch.name, switch.port
+                ));
+            }
+            for peer in switch.peers.iter() {
+                if *peer == switch.name {
+                    errors.push(format!("Switch '{}' is peered with itself", peer));
+                } else if self.switch(peer).is_none() {
+                    errors.push(format!(
+                        "Switch '{}' is peered with unknown switch '{}'",
+                        switch.name, peer
+                    ));
+                }
+            }
+        }
+
+        for vport in self.vports.iter() {
+            if !name_ok(&vport.name) {
+                errors.push(format!(
+                    "vport name '{}' must be letters, digits, '-' or '_'",
+                    vport.name
+                ));
+            }
+            if self.switch(&vport.switch).is_none() {
+                errors.push(format!(
+                    "vport '{}' connects to unknown switch '{}'",
+                    vport.name, vport.switch
+                ));
+            }
+            if vport.vlan.is_some() && vport.ip.is_none() {
+                errors.push(format!("vport '{}' has a vlan but no ip", vport.name));
+            }
+            if vport.impairment.jitter_ms.is_some() && vport.impairment.delay_ms.is_none() {
+                errors.push(format!(
+                    "vport '{}' has jitter_ms but no delay_ms",
+                    vport.name
+                ));
+            }
+        }
+
+        errors
+    }
+
+    /// Returns the switch called name, if there is one
+    pub fn switch(&self, name: &str) -> Option<&Switch> {
+        self.switches.iter().find(|switch| switch.name == name)
+    }
+
+    /// Returns the names of the switches peered with switch, in either
+    /// direction, as peering has to be configured on both vswitches
+    pub fn peers_of(&self, switch: &Switch) -> Vec<&Switch> {
+        self.switches
+            .iter()
+            .filter(|other| {
+                switch.peers.contains(&other.name) || other.peers.contains(&switch.name)
+            })
+            .collect()
+    }
+}
+
+/// Parse the impairment of a vport's link
+fn parse_impairment(yaml: &Yaml, what: &str) -> Result<Impairment, String> {
+    let hash = as_hash(yaml, what)?;
+    check_keys(
+        hash,
+        what,
+        &[
+            "delay_ms",
+            "jitter_ms",
+            "loss_percent",
+            "duplicate_percent",
+            "corrupt_percent",
+            "rate",
+        ],
+    )?;
+
+    let millis = |key: &str| match get(hash, key) {
+        Some(value) => value
+            .as_i64()
+            .and_then(|ms| u32::try_from(ms).ok())
+            .map(Some)
+            .ok_or_else(|| format!("{} {} must be a whole number of milliseconds", what, key)),
+        None => Ok(None),
+    };
+    let percent = |key: &str| match get(hash, key) {
+        Some(value) => value
+            .as_f64()
+            .or_else(|| value.as_i64().map(|value| value as f64))
+            .filter(|percent| (0.0..=100.0).contains(percent))
+            .map(Some)
+            .ok_or_else(|| format!("{} {} must be between 0 and 100", what, key)),
+        None => Ok(None),
+    };
+
+    Ok(Impairment {
+        delay_ms: millis("delay_ms")?,
+        jitter_ms: millis("jitter_ms")?,
+        loss_percent: percent("loss_percent")?,
+        duplicate_percent: percent("duplicate_percent")?,
+        corrupt_percent: percent("corrupt_percent")?,
+        rate: match get(hash, "rate") {
+            Some(rate) => Some(as_string(rate, &format!("{} rate", what))?),
+            None => None,
+        },
+    })
+}
+
+/// Parse an IPv4 address with a prefix length, e.g. 10.0.0.1/24
+fn parse_cidr(value: &str) -> Option<(Ipv4Addr, u8)> {
+    let (ip, prefix_len) = value.split_once('/')?;
+    let prefix_len = prefix_len.parse().ok().filter(|len| *len <= 32)?;
+    Some((ip.parse().ok()?, prefix_len))
+}
+
+/// Returns the value of key in hash, if it is there
+fn get<'a>(hash: &'a Hash, key: &str) -> Option<&'a Yaml> {
+    hash.get(&Yaml::String(key.to_string()))
+}
+
+/// Returns an error naming the first key of hash which isn't one of keys
+fn check_keys(hash: &Hash, what: &str, keys: &[&str]) -> Result<(), String> {
+    for key in hash.keys() {
+        if !key.as_str().is_some_and(|key| keys.contains(&key)) {
+            return Err(format!(
+                "Unknown key '{}' in {}, expected one of {}",
+                as_string(key, "key").unwrap_or_default(),
+                what,
+                keys.join(", ")
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Returns yaml as a mapping
+fn as_hash<'a>(yaml: &'a Yaml, what: &str) -> Result<&'a Hash, String> {
+    yaml.as_hash()
+        .ok_or_else(|| format!("Expected {} to be a mapping", what))
+}
+
+/// Returns the entries of the mapping yaml, or none if it is absent
+fn entries<'a>(yaml: Option<&'a Yaml>, what: &str) -> Result<Vec<(&'a Yaml, &'a Yaml)>, String> {
+    match yaml {
+        None | Some(Yaml::Null) => Ok(Vec::new()),
+        Some(yaml) => Ok(as_hash(yaml, what)?.iter().collect()),
+    }
+}
+
+/// Returns yaml as a string, which numbers can be written as too
+fn as_string(yaml: &Yaml, what: &str) -> Result<String, String> {
+    match yaml {
+        Yaml::String(value) | Yaml::Real(value) => Ok(value.clone()),
+        Yaml::Integer(value) => Ok(value.to_string()),
+        _ => Err(format!("Expected {} to be a string", what)),
+    }
+}
+
+/// Returns yaml as a list of strings, or an empty list if it is absent
+fn string_list(yaml: Option<&Yaml>, what: &str) -> Result<Vec<String>, String> {
+    match yaml {
+        None | Some(Yaml::Null) => Ok(Vec::new()),
+        Some(Yaml::Array(items)) => items.iter().map(|item| as_string(item, what)).collect(),
+        Some(_) => Err(format!("Expected {} to be a list", what)),
+    }
+}
@@ -0,0 +1,266 @@
+//! Privileged helper which hands tap interfaces to unprivileged vports
+//!
+//! This creates and configures a tap (or tun) interface, which needs
+//! CAP_NET_ADMIN, then listens on a Unix socket and passes the
+//! interface's file handle to each vport which connects, started with
+//! --tap-helper <socket_path>, so the vport itself needs no privileges
+//! (see l2vpn::fdpass). It can be run as a systemd service of
+//! Type=notify, which is ready once it listens, and which the vport's
+//! service is ordered after
+//!
+//! The socket can only be connected to by its owner and group, which
+//! are given so that the vport's user can connect. The interface is kept
+//! open, so it lasts until the helper stops, and is handed to the vport
+//! again when it restarts. Only one vport should use it at a time, as
+//! each frame is read by one of them
+//!
+//! Usage: l2vpn-tap-helper [<options>] <socket_path>
+//!
+//! Options: --mode l2|l3
+//!          --tap-name <name>
+//!          --tap-addr <ip>/<prefix_len>...
+//!          --tap-up on|off
+//!          --mac <mac>
+//!          --mtu <mtu>
+//!          --socket-owner <user>
+//!          --socket-group <group>
+
+use l2vpn::{
+    error::{Error, TapError},
+    fdpass,
+    mac::MacAddr,
+    systemd,
+    tap::{self, VirtualNic},
+    utilities::{parse_ip_prefix, parse_overlay_mtu},
+};
+use nix::unistd::{chown, Gid, Group, Uid, User};
+use std::{
+    env,
+    fs::{self, Permissions},
+    io,
+    net::IpAddr,
+    os::unix::{fs::PermissionsExt, net::UnixListener},
+    process::ExitCode,
+};
+
+const USAGE: &str = "Usage: l2vpn-tap-helper [<options>] <socket_path>
+
+Options: --mode l2|l3
+         --tap-name <name>
+         --tap-addr <ip>/<prefix_len>...
+         --tap-up on|off
+         --mac <mac>
+         --mtu <mtu>
+         --socket-owner <user>
+         --socket-group <group>";
+
+/// Permissions of the socket, which only its owner and group can connect to
+const SOCKET_MODE: u32 = 0o660;
+
+/*
+ * How the interface is created and configured, and where it is handed
+ * out from
+ */
+struct Config {
+    socket_path: String,
+    l3: bool,
+    tap_name: Option<String>,
+    tap_addrs: Vec<(IpAddr, u8)>,
+    tap_up: Option<bool>,
+    mac: Option<MacAddr>,
+    mtu: Option<usize>,
+    socket_owner: Option<Uid>,
+    socket_group: Option<Gid>,
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let config = match parse_config(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("{}", USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let tap = match create_tap(&config) {
+        Ok(tap) => tap,
+        Err(e) => {
+            eprintln!("Got error while creating tap interface: '{}'", e);
+            return ExitCode::from(Error::from(e).exit_code());
+        }
+    };
+    let Some(fd) = tap.fd() else {
+        eprintln!("{} has no file handle to hand over", tap.name());
+        return ExitCode::FAILURE;
+    };
+
+    let listener = match listen(&config) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!(
+                "Got error while listening on {}: '{}'",
+                config.socket_path, e
+            );
+            return ExitCode::from(Error::from(e).exit_code());
+        }
+    };
+    println!(
+        "Handing {} to vports which connect to {}",
+        tap.name(),
+        config.socket_path
+    );
+    if let Err(e) = systemd::notify("READY=1") {
+        eprintln!("Got error while notifying systemd: '{}'", e);
+    }
+
+    /* A vport which fails to take the interface can connect again */
+    for stream in listener.incoming() {
+        let result = stream.and_then(|stream| {
+            fdpass::send_tap(&stream, fd, tap.name(), config.l3).map_err(io::Error::from)
+        });
+        match result {
+            Ok(()) => println!("Handed {} to a vport", tap.name()),
+            Err(e) => eprintln!("Got error while handing {} to a vport: '{}'", tap.name(), e),
+        }
+    }
+
+    ExitCode::FAILURE
+}
+
+/// Parse the command line arguments (without the program name)
+fn parse_config(args: &[String]) -> Result<Config, String> {
+    let mut l3 = None;
+    let mut tap_name = None;
+    let mut tap_addrs = Vec::new();
+    let mut tap_up = None;
+    let mut mac = None;
+    let mut mtu = None;
+    let mut socket_owner = None;
+    let mut socket_group = None;
+
+    let mut args = args;
+    while let [flag, value, rest @ ..] = args {
+        if !flag.starts_with("--") {
+            break;
+        }
+        let repeated = match flag.as_str() {
+            "--mode" => match value.as_str() {
+                "l2" => l3.replace(false).is_some(),
+                "l3" => l3.replace(true).is_some(),
+                _ => return Err(format!("Unknown mode '{}'", value)),
+            },
+            "--tap-name" => {
+                tap::check_name(value).map_err(|e| format!("--tap-name: {}", e))?;
+                tap_name.replace(value.clone()).is_some()
+            }
+            "--tap-addr" => {
+                let addr = parse_ip_prefix(value).ok_or_else(|| {
+                    format!("Could not parse '{}' as tap address and prefix", value)
+                })?;
+                tap_addrs.push(addr);
+                false
+            }
+            "--tap-up" => {
+                let on = match value.as_str() {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(format!("{}: Expected 'on' or 'off', not '{}'", flag, value)),
+                };
+                tap_up.replace(on).is_some()
+            }
+            "--mac" => {
+                let parsed = value
+                    .parse::<MacAddr>()
+                    .map_err(|_| format!("Could not parse '{}' as a MAC", value))?;
+                mac.replace(parsed).is_some()
+            }
+            "--mtu" => mtu
+                .replace(parse_overlay_mtu(value).map_err(|e| format!("--mtu: {}", e))?)
+                .is_some(),
+            "--socket-owner" => {
+                let user = User::from_name(value)
+                    .map_err(|e| format!("Could not look up user '{}': {}", value, e))?
+                    .ok_or_else(|| format!("No user called '{}'", value))?;
+                socket_owner.replace(user.uid).is_some()
+            }
+            "--socket-group" => {
+                let group = Group::from_name(value)
+                    .map_err(|e| format!("Could not look up group '{}': {}", value, e))?
+                    .ok_or_else(|| format!("No group called '{}'", value))?;
+                socket_group.replace(group.gid).is_some()
+            }
+            _ => return Err(format!("Unknown option '{}'", flag)),
+        };
+        if repeated {
+            return Err(format!("{} was given more than once", flag));
+        }
+        args = rest;
+    }
+
+    let [socket_path] = args else {
+        return Err("Expected the path of the socket to listen on".to_string());
+    };
+    if socket_path.starts_with("--") {
+        return Err(format!("{} needs a value", socket_path));
+    }
+    let l3 = l3.unwrap_or(false);
+    if l3 && mac.is_some() {
+        return Err("--mac can't be given with --mode l3".to_string());
+    }
+
+    Ok(Config {
+        socket_path: socket_path.clone(),
+        l3,
+        tap_name,
+        tap_addrs,
+        tap_up,
+        mac,
+        mtu,
+        socket_owner,
+        socket_group,
+    })
+}
+
+/// Create the tap (or tun) interface, and configure it as given
+fn create_tap(config: &Config) -> Result<Box<dyn VirtualNic>, TapError> {
+    let tap = match config.l3 {
+        true => tap::create_tun(config.tap_name.as_deref().unwrap_or(tap::DEFAULT_TUN_NAME))?,
+        false => tap::create(config.tap_name.as_deref().unwrap_or(tap::DEFAULT_TAP_NAME))?,
+    };
+    if let Some(mac) = config.mac {
+        tap.set_mac(&mac)?;
+        println!("Set the MAC of {} to {}", tap.name(), mac);
+    }
+    if let Some(mtu) = config.mtu {
+        tap.set_mtu(mtu)?;
+        println!("Set the MTU of {} to {}", tap.name(), mtu);
+    }
+    for (ip, prefix_len) in &config.tap_addrs {
+        tap.add_addr(*ip, *prefix_len)?;
+        println!("Gave {} the address {}/{}", tap.name(), ip, prefix_len);
+    }
+    if let Some(up) = config.tap_up {
+        tap.set_up(up)?;
+        println!("Brought {} {}", tap.name(), if up { "up" } else { "down" });
+    }
+    Ok(tap)
+}
+
+/// Listen on the socket, which only its owner and group can connect to,
+/// replacing any left behind by a helper which stopped
+fn listen(config: &Config) -> io::Result<UnixListener> {
+    let _ = fs::remove_file(&config.socket_path);
+    let listener = UnixListener::bind(&config.socket_path)?;
+    fs::set_permissions(&config.socket_path, Permissions::from_mode(SOCKET_MODE))?;
+    if config.socket_owner.is_some() || config.socket_group.is_some() {
+        chown(
+            config.socket_path.as_str(),
+            config.socket_owner,
+            config.socket_group,
+        )
+        .map_err(io::Error::from)?;
+    }
+    Ok(listener)
+}
@@ -3,340 +3,4950 @@
 //! This uses a TAP interface to send/receive
 //! the host's traffic to/from the vswitch
 //!
-//! Usage: vport <vswitch_ip> <vswitch_port>
+//! The vport runs on Linux and macOS, where tap and tun interfaces
+//! come from tuntaposx and utun respectively, and on Windows, where it
+//! only runs in L3 mode, over a wintun adapter (see l2vpn::tap)
+//!
+//! The vswitch is normally reached over UDP, but a vport
+//! running inside a VM can reach a vswitch running on the
+//! hypervisor over vsock instead, and a vport on the same
+//! host as the vswitch can use a Unix datagram socket, or
+//! shared memory rings
+//!
+//! A vport on a network which blocks UDP can reach the vswitch
+//! over TCP instead, through a SOCKS5 or HTTP CONNECT proxy if
+//! that is the only way out. The proxy's user name and password
+//! can be given in its URL, or kept in a credentials file so they
+//! don't show up in the process list
+//!
+//! The vport periodically tells the vswitch its session ID,
+//! which is kept in the session file if one is given, so the
+//! vswitch can recognise it after either of them restarts,
+//! and answers the vswitch's echo requests so it can measure
+//! the round trip time to the vport. The session's token is
+//! sent along with its ID, so the vswitch follows the vport
+//! to a new address (e.g. after NAT rebinding) without anyone
+//! else being able to take the session over
+//!
+//! When the vport is stopped by SIGINT or SIGTERM, it sends the frames
+//! it is holding in a batch or an FEC group, then tells the vswitch it
+//! is leaving, so the vswitch flushes its MACs straight away, rather
+//! than once it stops hearing from the vport, and exits successfully.
+//! A tap interface which isn't persistent is removed as it exits
+//!
+//! Before its first hello, the vport joins the vswitch, saying what it
+//! can do (e.g. whether it is multihomed) and which VLAN it would like
+//! its frames to be in, if it is given one, and logs the port it was
+//! given when the vswitch answers. A vswitch which doesn't answer, e.g.
+//! as it is down, is sent the join again after 1 second, then waiting
+//! twice as long each time, up to 32 seconds, so the vport joins soon
+//! after it comes up without flooding it meanwhile. Once a vswitch stops
+//! being heard from, which may be as it restarted and forgot the vport,
+//! the vport rejoins it the same way, logging when it does
+//!
+//! A vswitch which can't be connected to at startup, e.g. as it isn't
+//! listening on TCP or hasn't created its Unix socket yet, is connected
+//! to again with the same backoff, until it can be or the vport is
+//! stopped. A stream which the vswitch closes stops the vport, so
+//! whatever restarts it (e.g. systemd) connects it again
+//!
+//! A vport can ask to compress its frames with LZ4 when it joins, to
+//! save underlay bandwidth on metered links. Once a vswitch agrees,
+//! the frames each of them sends the other are compressed whenever
+//! that makes them smaller, with a flag saying which ones are
+//!
+//! A vport told its tunnel MTU can ask to fragment frames too large for
+//! it instead, so full-size frames cross paths which drop IP fragments.
+//! Once a vswitch agrees, each of them sends such frames over UDP in
+//! fragments with headers of their own, which the other reassembles,
+//! and the vport no longer clamps the MSS or refuses large packets
+//!
+//! The hellos double as keepalives, which the vswitch considers the
+//! vport down without, and the vport considers a vswitch down once it
+//! hasn't sent an echo request (or anything else) for a while. It logs
+//! when a vswitch stops being heard from and when it is heard from
+//! again, or can quit instead, so whatever restarts it (e.g. systemd)
+//! connects it to the vswitch again
+//!
+//! If built with the dtls feature and given the vswitch's pre-shared
+//! key, the vport carries its frames to a vswitch reached over UDP in
+//! a DTLS session, so nobody on the underlay can read or forge them.
+//! The session is set up again if the vswitch stops answering, e.g.
+//! after it restarts, and the tunnel MTU has to carry DTLS's overhead
+//!
+//! If built with the quic feature, the vport can reach the vswitch over
+//! QUIC, trusting the certificates issued by the given CA. Its frames
+//! are encrypted, with its control messages in a stream of their own,
+//! and it keeps its connection if a NAT moves it to another address
+//!
+//! Given a pre-shared key for frame authentication instead, the vport
+//! signs the frames it sends over UDP, and drops those it receives
+//! which weren't signed with the key, or which have already been
+//! received, as the vswitch does
+//!
+//! The datagrams sent to the vswitch over UDP can be marked with a DSCP,
+//! or with the class selector of each frame's 802.1p priority, so the
+//! underlay network can give the tunnel the QoS treatment it needs
+//!
+//! Given a VNI, the vport sends every datagram to the vswitch after a
+//! header holding it, and only takes those received with it, so the
+//! vports of many tenants can share the vswitch's port, each VNI being
+//! switched in a segment of its own
+//!
+//! With direct paths on, the vport asks the vswitch to introduce it to
+//! the vports it exchanges frames with, and punches holes through the
+//! NATs between them, sending the frames for the hosts behind each
+//! vport it reaches straight to it, rather than through the vswitch,
+//! which still relays the frames of vports it can't reach
+//!
+//! With batching on, the vport asks the vswitch to batch small frames,
+//! such as ARP requests and TCP ACKs, several to a datagram, and holds
+//! those it sends for a moment so the frames which follow share their
+//! datagram, saving a system call and the headers of a datagram for each
+//!
+//! Given an FEC group size, the vport asks the vswitch to protect the
+//! datagrams they exchange with forward error correction, sending a
+//! parity datagram after each group of that many, so any one datagram
+//! of a group which the underlay loses is rebuilt from the others
+//!
+//! Given STUN servers, the vport asks them where the datagrams it sends
+//! from the socket which reaches the vswitch arrive from when it starts,
+//! and reports that public endpoint when it joins, along with the type
+//! of NAT it is behind, found by whether two servers see the same one
+//!
+//! In VXLAN mode, the vport puts a VXLAN header carrying its VNI in
+//! front of the frames it sends over UDP, and only takes those received
+//! with its VNI, so the vswitch can be a VTEP such as a Linux VXLAN
+//! device, as well as a vswitch listening for VXLAN. VTEPs send to the
+//! port they receive on, so the vport receives on the vswitch's port.
+//! GENEVE mode is the same with GENEVE headers, e.g. to reach OVS, and
+//! logs the tenant ID and ingress port which the vswitch tells it.
+//! L2TP mode instead terminates an L2TPv3 pseudowire, with the session
+//! IDs it is given, against a router or Linux l2tp_eth interface
+//!
+//! The vswitch can be given by host name, which is resolved
+//! again every minute, so the vport follows a vswitch which
+//! is renumbered while it runs
+//!
+//! A vport can be connected to a second vswitch for redundancy, in
+//! which case it sends every frame to both vswitches, and drops the
+//! second copy of each frame it receives from them
+//!
+//! A vport can instead be given backup vswitches, which it joins and
+//! watches alongside the first one, but only sends frames to, and takes
+//! them from, the first of them it has joined, in the order given. When
+//! that one stops being heard from, the vport fails over to the next,
+//! and fails back once an earlier one answers its join again, telling
+//! the vswitch it left that it is leaving, so its MACs are flushed there,
+//! and announcing its hosts' MACs with RARP to the one it moved to
+//!
+//! In L3 mode, the vport uses a tun interface instead, for deployments
+//! which only need routed connectivity, and carries the host's IPv4
+//! packets in frames from and to MACs derived from their addresses, so
+//! the vswitch forwards them by destination address as it learns which
+//! vport each is behind (see l2vpn::tun), announcing the tun
+//! interface's addresses every HELLO_INTERVAL
+//!
+//! Rather than creating a tap interface, the vport can attach to an
+//! existing NIC with a raw AF_PACKET socket, bridging the NIC's whole
+//! segment into the overlay, so physical LANs can be stitched together
+//! (see l2vpn::nic)
+//!
+//! The vport can also be handed a tap interface which a privileged
+//! helper (l2vpn-tap-helper) created and configured, over a Unix
+//! socket, so it runs without CAP_NET_ADMIN (see l2vpn::fdpass)
+//!
+//! Run by systemd, the vport hands the tap interface it creates to
+//! systemd to keep, and is passed it back when it restarts, or can be
+//! passed one a socket unit opened, and tells systemd once it is ready,
+//! which vswitch port it joined as and when it is stopping. It pings
+//! systemd's watchdog for as long as its forwarding loops aren't stuck
+//! (see l2vpn::systemd)
+//!
+//! Without a supervisor, the vport can detach from its terminal as a
+//! daemon, writing its process ID to a pidfile, and logging to a file or
+//! syslog, which it can do in the foreground too (see l2vpn::daemon)
+//!
+//! The tap interface is called tap0 unless it is given another name,
+//! so several vports can run on one host. A name holding "%d" has it
+//! replaced by the kernel with the lowest number which makes it unique,
+//! and the vport logs the name it was given. A vport which is given the
+//! name of a tap interface which another process is attached to says so
+//!
+//! The tap interface can be made persistent, so it outlives the vport
+//! and keeps its addresses and routes when the vport restarts, and
+//! given an owner or group, so a vport running as that user or group
+//! can attach to it later without being root
+//!
+//! The vport can also give the tap interface IPv4 and IPv6 addresses,
+//! and bring it up, so nothing has to be configured by hand once the
+//! vport has created it
+//!
+//! The tap interface's MAC can be given, or derived from a seed, so
+//! it stays the same when the vport's host is reinstalled, rather
+//! than being whatever the kernel picks
+//!
+//! A vport on the same network as its vswitch can join an underlay
+//! multicast group, which the vswitch then sends flooded frames
+//! through once, rather than sending every vport its own copy. The
+//! vport drops the frames it sent itself when they come back to it
+//! through the group
+//!
+//! A vport with several underlay paths to its vswitch, e.g. through
+//! several NICs, can send from a further UDP socket bound to the address
+//! of each, which the vswitch aggregates into the vport's port. Frames
+//! are spread across the links by the hash of their flow, so each flow's
+//! frames stay in order, and frames arrive over any of them
+//!
+//! The sockets of links to vswitches over UDP are bound to any local
+//! address and an ephemeral port, unless the vport is told to bind them
+//! to a local address, or to an underlay interface (with SO_BINDTODEVICE),
+//! e.g. on a host with several uplinks. The link to the first vswitch
+//! can be bound to a fixed source port too, so firewalls can be pinned
+//! to the vport's datagrams
+//!
+//! If the tunnel MTU (the MTU of the network the L2VPN's datagrams
+//! cross) is given, the MSS option of TCP SYNs crossing the tap
+//! interface is clamped, so TCP segments fit through the tunnel, and
+//! other packets which are too large are refused with an ICMP error
+//!
+//! With path MTU discovery on, the vport finds the tunnel MTU itself,
+//! by probing each vswitch with frames of the sizes it tries, with the
+//! don't fragment flag set, and fits packets to the largest which gets
+//! through in the same way. It logs how large a packet the L2VPN can
+//! carry, and can set the tap interface's MTU to match, so the host
+//! never sends a frame too large to cross the path
+//!
+//! The MTU of the L2VPN network (the overlay MTU) is 1500 bytes, as on
+//! standard Ethernet, unless it is given, e.g. as 9000 to carry jumbo
+//! frames, in which case the tap interface is given it too. It must
+//! match the vswitch's, which refuses vports whose hellos carry another,
+//! and if the tunnel MTU is also given, it must carry the overlay MTU
+//!
+//! Frames from the host can be dropped rather than entering the overlay,
+//! and frames from the overlay rather than reaching the host, if they
+//! match a filter, written as for the vswitch's ACLs (see l2vpn::filter),
+//! e.g. to keep IPv6 router advertisements or LLDP from crossing it
+//!
+//! With ARP suppression on, the vport answers the host's ARP requests
+//! for the addresses of hosts behind other vports itself, learning their
+//! MACs from the ARP it receives from the overlay and from the vswitch,
+//! so only requests for addresses it doesn't know cross the WAN (see
+//! l2vpn::arp)
+//!
+//! What is done with each frame, and the hellos sent to the vswitch,
+//! are decided by l2vpn::endpoint::VportCore, which does no I/O, so
+//! this binary only moves frames between the tap interface and the
+//! vswitch
+//!
+//! Frames which can't be sent on are dropped and counted, a forwarding
+//! loop which fails is restarted, and the vport quits if one gets stuck
+//!
+//! `vport check-config` validates the rest of the command
+//! line without creating the tap interface
+//!
+//! Usage: vport [check-config] [<options>] <vswitch_host> <vswitch_port>
+//!        vport [check-config] [<options>] --vsock <vswitch_cid> <vswitch_vsock_port>
+//!        vport [check-config] [<options>] --tcp <vswitch_host> <vswitch_tcp_port>
+//!        vport [check-config] [<options>] --quic <vswitch_host> <vswitch_quic_port>
+//!        vport [check-config] [<options>] --unix <vswitch_socket_path>
+//!        vport [check-config] [<options>] --shm <vswitch_socket_path>
+//!        vport [check-config] [<options>] <vswitch address> --secondary <vswitch address>
+//!        vport [check-config] [<options>] <vswitch address> --backup <vswitch address>...
+//!
+//! Options: --session-file <path>
+//!          --mode l2|l3
+//!          --nic <interface>
+//!          --tap-helper <socket_path>
+//!          --tap-name <name>
+//!          --tap-persist on|off
+//!          --tap-owner <user> | --tap-group <group>
+//!          --tap-addr <ip>/<prefix_len>...
+//!          --tap-up on|off
+//!          --mac <mac> | --mac-seed <seed>
+//!          --mtu <bytes>
+//!          --tunnel-mtu <bytes>
+//!          --proxy socks5|http://[<user>:<password>@]<host>:<port>
+//!          --proxy-credentials-file <path>
+//!          --bum-group <group_ip:port>
+//!          --lag-link <local_ip>...
+//!          --bind-addr <local_ip>
+//!          --source-port <port>
+//!          --bind-device <interface>
+//!          --dtls-psk-file <path> | --auth-psk-file <path>
+//!          --quic-ca-file <path>
+//!          --vxlan <vni> | --geneve <vni>
+//!          --l2tp <session_id>:<peer_session_id>
+//!          --keepalive-interval <secs>
+//!          --vswitch-timeout <secs>
+//!          --vswitch-timeout-action log|exit
+//!          --vlan <vlan_id>
+//!          --compression lz4|off
+//!          --fragmentation on|off
+//!          --path-mtu-discovery on|off
+//!          --path-mtu-action clamp|set-tap-mtu
+//!          --dscp <value>|pcp
+//!          --vni <vni>
+//!          --direct-paths on|off
+//!          --batching on|off
+//!          --fec <group_size>
+//!          --arp-suppression on|off
+//!          --stun-server <host:port>...
+//!          --drop-egress <filter>...
+//!          --drop-ingress <filter>...
+//!          --daemon <pid_file>
+//!          --log <path>|syslog
 
-use l2vpn::utilities::get_frame_log_msg;
-use nix::{
-    ioctl_write_ptr,
-    libc::{ifreq, IFF_NO_PI, IFF_TAP, IFNAMSIZ},
+#[cfg(feature = "dtls")]
+use l2vpn::dtls::{self, DtlsLink, HANDSHAKE_TIMEOUT};
+use l2vpn::geneve::{self, GENEVE_OVERHEAD};
+use l2vpn::l2tp::{self, parse_sessions, L2TP_HDR_LEN};
+#[cfg(feature = "quic")]
+use l2vpn::quic::{self, QuicLink};
+#[cfg(target_os = "linux")]
+use l2vpn::shm::ShmLink;
+use l2vpn::{
+    arp,
+    auth::{self, FrameAuth},
+    batch::{is_batch, unbatch, Batch, BATCH_MAX, BATCH_TIMEOUT},
+    compression::compress,
+    control::{
+        CAP_ARP_SUPPRESSION, CAP_BATCHING, CAP_COMPRESSION, CAP_DIRECT_PATHS, CAP_FEC,
+        CAP_FRAGMENTATION, CAP_LAG, CAP_MULTIHOMED,
+    },
+    dedup::DuplicateFilter,
+    direct::{DirectPaths, PUNCH_INTERVAL},
+    dscp::{Dscp, Marker},
+    endpoint::{Action, Session, VportCore},
+    error::{ConfigError, Error, TapError, TransportError},
+    fec::{is_shard, Decoder, Encoder, FEC_FLUSH_TIMEOUT, FEC_GROUP_MAX, FEC_OVERHEAD},
+    filter::Filter,
+    fragment::{self, is_fragment, Reassembler},
+    frame::EthernetFrame,
+    lag::pick_link,
+    log_frame, logging,
+    mac::MacAddr,
+    mtu::{
+        tunnel_mtu_needed, AUTH_OVERHEAD, DTLS_OVERHEAD, MIN_TUNNEL_MTU, QUIC_OVERHEAD,
+        UDP_TUNNEL_OVERHEAD,
+    },
+    nic,
+    platform::{self, StopSignals},
+    pmtud::{self, PathMtuSearch, PROBE_TIMEOUT, REPROBE_INTERVAL},
+    proxy::{self, Proxy},
+    stun::{self, BindingResponse, NatType, PublicEndpoint, STUN_ATTEMPTS, STUN_TIMEOUT},
+    supervisor::{self, supervise, Heartbeat},
+    systemd,
+    tap::{self, VirtualNic},
+    tcp::TcpLink,
+    tenant::{self, VNI_HDR_LEN},
+    timer::{Backoff, Interval, Liveness},
+    tun,
+    tunnel::{HOP_LIMIT_TAG_LEN, TUNNEL_DATAGRAM_MAX, TUNNEL_FRAME_MAX},
+    utilities::{
+        frame_max, parse_ip_prefix, parse_overlay_mtu, vlan_tag, FrameLogMsg, DEFAULT_OVERLAY_MTU,
+        ETHER_HDR,
+    },
+    vxlan::{self, parse_vni, VXLAN_HDR_LEN},
+};
+#[cfg(unix)]
+use l2vpn::{
+    daemon::{self, LogTarget},
+    fdpass,
+    vsock::VsockStream,
 };
 use std::{
-    env,
-    error::Error,
-    ffi::{c_char, c_int},
-    fs::File,
-    io::{Read, Write},
-    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
-    os::fd::AsRawFd,
-    process::ExitCode,
+    collections::{HashMap, VecDeque},
+    env, fmt,
+    fs::{self, File},
+    io::{self, Read, Write},
+    iter,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs, UdpSocket},
+    path::Path,
+    process::{self, ExitCode},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex, PoisonError, RwLock,
+    },
     thread,
+    time::{Duration, Instant, SystemTime},
+};
+#[cfg(unix)]
+use {
+    nix::unistd::{Group, User},
+    std::os::unix::{fs::OpenOptionsExt, net::UnixDatagram},
 };
 
-/*
- * These constants are defined in linux/if_tun.h
- * and ioctl uses them to identify that an operation
- * should affect the tuntap driver, and that it should
- * be setting interface flags respectively
- */
-const TUNTAP_DRIVER: u8 = b'T';
-const TUNTAP_SET_FLAGS: u8 = 202;
+const USAGE: &str = "Usage: vport [check-config] [<options>] <vswitch_host> <vswitch_port>
+       vport [check-config] [<options>] --vsock <vswitch_cid> <vswitch_vsock_port>
+       vport [check-config] [<options>] --tcp <vswitch_host> <vswitch_tcp_port>
+       vport [check-config] [<options>] --quic <vswitch_host> <vswitch_quic_port>
+       vport [check-config] [<options>] --unix <vswitch_socket_path>
+       vport [check-config] [<options>] --shm <vswitch_socket_path>
+       vport [check-config] [<options>] <vswitch address> --secondary <vswitch address>
+       vport [check-config] [<options>] <vswitch address> --backup <vswitch address>...
 
-const ETHER_MTU: usize = 1518;
-const ETHER_MIN: usize = 64;
-const ETHER_HDR: usize = 14;
-const ETHER_FCS: usize = 4;
-const ETHER_DATA_MIN: usize = ETHER_MIN - ETHER_HDR - ETHER_FCS;
+Options: --session-file <path>
+         --mode l2|l3
+         --nic <interface>
+         --tap-helper <socket_path>
+         --tap-name <name>
+         --tap-persist on|off
+         --tap-owner <user> | --tap-group <group>
+         --tap-addr <ip>/<prefix_len>...
+         --tap-up on|off
+         --mac <mac> | --mac-seed <seed>
+         --mtu <bytes>
+         --tunnel-mtu <bytes>
+         --proxy socks5|http://[<user>:<password>@]<host>:<port>
+         --proxy-credentials-file <path>
+         --bum-group <group_ip:port>
+         --lag-link <local_ip>...
+         --bind-addr <local_ip>
+         --source-port <port>
+         --bind-device <interface>
+         --dtls-psk-file <path> | --auth-psk-file <path>
+         --quic-ca-file <path>
+         --vxlan <vni> | --geneve <vni>
+         --l2tp <session_id>:<peer_session_id>
+         --keepalive-interval <secs>
+         --vswitch-timeout <secs>
+         --vswitch-timeout-action log|exit
+         --vlan <vlan_id>
+         --compression lz4|off
+         --fragmentation on|off
+         --path-mtu-discovery on|off
+         --path-mtu-action clamp|set-tap-mtu
+         --dscp <value>|pcp
+         --vni <vni>
+         --direct-paths on|off
+         --batching on|off
+         --fec <group_size>
+         --arp-suppression on|off
+         --stun-server <host:port>...
+         --drop-egress <filter>...
+         --drop-ingress <filter>...
+         --daemon <pid_file>
+         --log <path>|syslog";
+
+/// How often the vport sends a hello to the vswitch, unless told otherwise
+const HELLO_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long the vswitch waits for a hello before it considers the vport down
+const VSWITCH_PORT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a vswitch can go without being heard from before the vport
+/// considers it down, unless told otherwise. vswitches send an echo
+/// request every ECHO_INTERVAL
+const VSWITCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often vswitches send echo requests to the vport
+const ECHO_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often the vport checks whether the vswitches are still being heard from
+const LIVENESS_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long after a host was last heard from its MAC is announced to a
+/// vswitch failed over to, and most MACs kept, so a host flooding the
+/// vport with MACs can't exhaust its memory
+const LOCAL_MAC_AGE: Duration = Duration::from_secs(300);
+const MAX_LOCAL_MACS: usize = 8192;
+
+/// Shortest and longest waits between attempts to connect to, or join,
+/// a vswitch which isn't answering
+const RECONNECT_DELAY_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(32);
+
+/// How often the host name of a vswitch is resolved again
+const RESOLVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the vport checks whether the vswitch has answered a path MTU probe
+const PROBE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long a forwarding loop can be stuck on one frame before the vport gives up
+const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Name of the tap interface in systemd's file descriptor store, which
+/// a socket unit can also pass one as (FileDescriptorName=)
+#[cfg(unix)]
+const SYSTEMD_TAP_NAME: &str = "tap";
 
 /*
  * Struct which contains information required for vport
  * to communicate with vswitch
  */
 struct Vport {
-    tap_file: File,
-    vswitch_addr: SocketAddr,
-    sock: UdpSocket,
+    /* The tap interface, shared by the clones of the vport, which each read or write it */
+    tap: Arc<dyn VirtualNic>,
+    /* Whether the tap interface is a tun interface, carrying IP packets rather than frames */
+    l3: bool,
+    link: VswitchLink,
+    /* Further links to the first vswitch, which frames are spread across along with link */
+    lag: Vec<VswitchLink>,
+    /* Link to the second vswitch, if the vport is multihomed */
+    secondary: Option<VswitchLink>,
+    /* Links to the backup vswitches, in the order they are failed over to */
+    backups: Vec<VswitchLink>,
+    /* Index of the vswitch frames are sent to and taken from, shared by the clones, if there are backups */
+    active: Option<Arc<AtomicUsize>>,
+    /* What is done with each frame, and the messages which register us with the vswitch */
+    core: VportCore,
+    /* Shared by the clones of the vport, so every thread's drops are counted together */
+    drops: Arc<Drops>,
+    /* Largest datagram payload which frames are fragmented to fit, if they are */
+    fragment_size: Option<usize>,
+    /* How many datagrams to the vswitch each FEC group protects, if FEC is asked for */
+    fec_group: Option<u8>,
+    /* The vports we were introduced to, shared by the clones of the vport, if direct paths are on */
+    direct: Option<Arc<Mutex<DirectPaths>>>,
+}
+
+/*
+ * Why the vport stopped
+ */
+enum Stop {
+    /* A forwarding loop stopped for good */
+    Loop(&'static str),
+    /* We were told to stop, and have left the vswitches */
+    Signal(&'static str),
+    /* The first vswitch (or, with backups, every vswitch) stopped being heard from, and we were told to quit when it does */
+    VswitchTimeout,
+}
+
+/*
+ * What the vport knows of a vswitch, shared by the threads talking to it
+ */
+struct VswitchStatus {
+    /* What the vswitch is called in logs */
+    name: String,
+    /* When the vswitch was last heard from */
+    liveness: Mutex<Liveness>,
+    /* Whether the vswitch has answered our join */
+    joined: AtomicBool,
+    /* Whether the vswitch agreed to compress the frames we exchange when it answered */
+    compressing: AtomicBool,
+    /* Whether the vswitch agreed to fragment frames too large for the tunnel MTU */
+    fragmenting: AtomicBool,
+    /* Whether the vswitch agreed to batch small frames into shared datagrams */
+    batching: AtomicBool,
+    /* Whether the vswitch agreed to protect the datagrams we exchange with FEC */
+    fec: AtomicBool,
+    /* ID of the last path MTU probe which the vswitch answered */
+    probe_acked: AtomicU64,
+    /* Largest frame which path MTU discovery found reaches the vswitch, or 0 until it is found */
+    largest_frame: AtomicUsize,
+}
+
+impl VswitchStatus {
+    fn new(name: String, timeout: Duration) -> VswitchStatus {
+        VswitchStatus {
+            name,
+            liveness: Mutex::new(Liveness::new(timeout, Instant::now())),
+            joined: AtomicBool::new(false),
+            compressing: AtomicBool::new(false),
+            fragmenting: AtomicBool::new(false),
+            batching: AtomicBool::new(false),
+            fec: AtomicBool::new(false),
+            probe_acked: AtomicU64::new(0),
+            largest_frame: AtomicUsize::new(0),
+        }
+    }
+
+    /* Forget that the vswitch answered our join, and what it agreed to, so it is joined again */
+    fn unjoin(&self) {
+        self.joined.store(false, Ordering::Relaxed);
+        self.compressing.store(false, Ordering::Relaxed);
+        self.fragmenting.store(false, Ordering::Relaxed);
+        self.batching.store(false, Ordering::Relaxed);
+        self.fec.store(false, Ordering::Relaxed);
+    }
+}
+
+/*
+ * What the vport does when the first vswitch stops being heard from
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TimeoutAction {
+    /* Log it, and keep sending hellos in case it comes back */
+    Log,
+    /* Quit, so the vport is restarted */
+    Exit,
+}
+
+/*
+ * Where the vport is in joining a vswitch
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum JoinState {
+    /* Sending our first join, until the vswitch answers it */
+    Joining,
+    /* The vswitch answered our join */
+    Joined,
+    /* The vswitch stopped being heard from, so we are joining it again */
+    Rejoining,
+}
+
+/*
+ * What the vport carries between the host and the vswitch
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    /* The host's Ethernet frames, through a tap interface */
+    L2,
+    /* The host's IPv4 packets, through a tun interface, in frames the vport builds */
+    L3,
+}
+
+/*
+ * What the vport does with the path MTU it finds
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PathMtuAction {
+    /* Fit packets to it, clamping MSSs and refusing those too large with ICMP errors */
+    Clamp,
+    /* Fit packets to it, and set the tap interface's MTU to match too */
+    SetTapMtu,
+}
+
+/*
+ * Frames dropped because they could not be passed on
+ */
+#[derive(Default)]
+struct Drops {
+    /* Frames from the tap interface which could not be sent to the vswitch */
+    tx: AtomicU64,
+    /* Frames from the vswitch which could not be written to the tap interface */
+    tap: AtomicU64,
+}
+
+/*
+ * MACs of the hosts behind the vport, which are announced to
+ * a vswitch failed over to, so it learns they are behind us
+ */
+#[derive(Default)]
+struct LocalMacs {
+    /* When each MAC was last heard from, in the VLAN it was tagged with, if any */
+    seen: HashMap<(MacAddr, Option<u16>), Instant>,
+}
+
+impl LocalMacs {
+    /* Note the source of frame, which a host sent, unless it is multicast */
+    fn saw(&mut self, frame: &[u8], now: Instant) {
+        let Ok(EthernetFrame { src: mac, .. }) = EthernetFrame::parse(frame) else {
+            return;
+        };
+        if mac.is_multicast() {
+            return;
+        }
+        let key = (mac, vlan_tag(frame).map(|tag| tag.vid));
+        if self.seen.len() >= MAX_LOCAL_MACS && !self.seen.contains_key(&key) {
+            self.seen
+                .retain(|_, seen| now.duration_since(*seen) < LOCAL_MAC_AGE);
+            if self.seen.len() >= MAX_LOCAL_MACS {
+                return;
+            }
+        }
+        self.seen.insert(key, now);
+    }
+
+    /* Returns the RARP announcements of the MACs heard from recently */
+    fn announcements(&self, now: Instant) -> Vec<Vec<u8>> {
+        self.seen
+            .iter()
+            .filter(|(_, seen)| now.duration_since(**seen) < LOCAL_MAC_AGE)
+            .map(|((mac, vlan), _)| arp::announcement(*mac, *vlan))
+            .collect()
+    }
+}
+
+/*
+ * Transport which the vport uses to exchange frames with the vswitch
+ */
+#[derive(Debug)]
+enum VswitchLink {
+    /*
+     * Each frame is sent as a single UDP datagram, to the address
+     * which the vswitch's host name last resolved to, followed by its
+     * sender ID, sequence number and tag if frames are authenticated,
+     * or after a VXLAN or GENEVE header in those modes, with the DSCP
+     * the marker gives it if there is one. Given a VNI, each datagram
+     * carries it in a header in front of the frame, before signing
+     */
+    Udp {
+        sock: UdpSocket,
+        vswitch_addr: Arc<RwLock<SocketAddr>>,
+        auth: Option<FrameAuth>,
+        encap: Option<Encap>,
+        marker: Option<Arc<Marker>>,
+        vni: Option<u32>,
+    },
+    /* Frames are sent length-prefixed over a vsock stream */
+    #[cfg(unix)]
+    Vsock(VsockStream),
+    /* Frames are sent length-prefixed over a TCP stream, which may go through a proxy */
+    Tcp(TcpLink),
+    /* Each frame is sent as a single Unix datagram */
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+    /* Frames are copied through rings in shared memory */
+    #[cfg(target_os = "linux")]
+    Shm(ShmLink),
+    /*
+     * Flooded frames which the vswitch sends through an underlay
+     * multicast group, each after the session ID of the vport it
+     * came from. Only datagrams from the vswitch are accepted, and
+     * anything sent goes to the vswitch over link
+     */
+    Group {
+        sock: UdpSocket,
+        vswitch_addr: Arc<RwLock<SocketAddr>>,
+        link: Box<VswitchLink>,
+        session_id: u64,
+    },
+    /* Each frame is encrypted in a DTLS session, and sent as a single UDP datagram */
+    #[cfg(feature = "dtls")]
+    Dtls(DtlsLink),
+    /* Frames are sent in QUIC datagrams, and control messages on a QUIC stream */
+    #[cfg(feature = "quic")]
+    Quic(QuicLink),
+}
+
+impl VswitchLink {
+    /// Send frame to the vswitch, returning the number of bytes sent
+    fn send(&self, frame: &[u8]) -> Result<usize, TransportError> {
+        match self {
+            VswitchLink::Udp { vswitch_addr, .. } => {
+                let vswitch_addr = *vswitch_addr.read().unwrap();
+                self.send_to(frame, vswitch_addr)
+            }
+            #[cfg(unix)]
+            VswitchLink::Vsock(stream) => stream.send_frame(frame).map(|_| frame.len()),
+            VswitchLink::Tcp(link) => link.send_frame(frame).map(|_| frame.len()),
+            #[cfg(unix)]
+            VswitchLink::Unix(sock) => Ok(sock.send(frame)?),
+            #[cfg(target_os = "linux")]
+            VswitchLink::Shm(link) => link.send_frame(frame).map(|_| frame.len()),
+            VswitchLink::Group { link, .. } => link.send(frame),
+            #[cfg(feature = "dtls")]
+            VswitchLink::Dtls(link) => link.send_frame(frame).map(|_| frame.len()),
+            #[cfg(feature = "quic")]
+            VswitchLink::Quic(link) => link.send_frame(frame).map(|_| frame.len()),
+        }
+    }
+
+    /// Send frame to dst over a UDP link, as it would be sent to the
+    /// vswitch, e.g. to another vport, returning the number of bytes sent
+    fn send_to(&self, frame: &[u8], dst: SocketAddr) -> Result<usize, TransportError> {
+        match self {
+            VswitchLink::Udp {
+                sock,
+                auth,
+                encap,
+                marker,
+                vni,
+                ..
+            } => {
+                if let Some(marker) = marker {
+                    marker.mark(sock, frame)?;
+                }
+                if let Some(vni) = vni {
+                    let datagram = tenant::encapsulate(*vni, frame);
+                    let datagram = match auth {
+                        Some(auth) => auth.sign(&datagram),
+                        None => datagram,
+                    };
+                    sock.send_to(&datagram, dst)?;
+                    return Ok(frame.len());
+                }
+                match (auth, encap) {
+                    (Some(auth), _) => {
+                        sock.send_to(&auth.sign(frame), dst)?;
+                        Ok(frame.len())
+                    }
+                    (None, Some(encap)) => {
+                        sock.send_to(&encap.encapsulate(frame), dst)?;
+                        Ok(frame.len())
+                    }
+                    (None, None) => Ok(sock.send_to(frame, dst)?),
+                }
+            }
+            /* Other links only lead to the vswitch */
+            _ => Err(io::Error::from(io::ErrorKind::Unsupported).into()),
+        }
+    }
+
+    /// Receive a frame from the vswitch into buf, returning its length
+    fn recv(&self, buf: &mut [u8]) -> Result<usize, TransportError> {
+        match self {
+            VswitchLink::Udp {
+                sock,
+                auth,
+                encap,
+                vni,
+                ..
+            } => loop {
+                let mut len = sock.recv_from(buf)?.0;
+                if let Some(vni) = vni {
+                    /* Datagrams which weren't signed with our key, or are replayed, are dropped */
+                    if let Some(auth) = auth {
+                        match auth.verify(&buf[..len]) {
+                            Some(frame) => len = frame.len(),
+                            None => continue,
+                        }
+                    }
+                    /* As are those for other VNIs */
+                    match tenant::decapsulate(&buf[..len]) {
+                        Some((received, frame)) if received == *vni => {
+                            let frame_len = frame.len();
+                            buf.copy_within(VNI_HDR_LEN..len, 0);
+                            return Ok(frame_len);
+                        }
+                        _ => continue,
+                    }
+                }
+                match (auth, encap) {
+                    /* Datagrams which weren't signed with our key, or are replayed, are dropped */
+                    (Some(auth), _) => {
+                        if let Some(frame) = auth.verify(&buf[..len]) {
+                            return Ok(frame.len());
+                        }
+                    }
+                    (None, Some(encap)) => {
+                        if let Some(frame_len) = encap.decapsulate(buf, len) {
+                            return Ok(frame_len);
+                        }
+                    }
+                    (None, None) => return Ok(len),
+                }
+            },
+            #[cfg(unix)]
+            VswitchLink::Vsock(stream) => stream.recv_frame(buf),
+            VswitchLink::Tcp(link) => link.recv_frame(buf),
+            #[cfg(unix)]
+            VswitchLink::Unix(sock) => Ok(sock.recv(buf)?),
+            #[cfg(target_os = "linux")]
+            VswitchLink::Shm(link) => link.recv_frame(buf),
+            VswitchLink::Group {
+                sock,
+                vswitch_addr,
+                session_id,
+                ..
+            } => loop {
+                let (len, src) = sock.recv_from(buf)?;
+
+                /* Our own frames come back to us through the group too */
+                let from_vswitch = src == *vswitch_addr.read().unwrap();
+                if !from_vswitch || len < 8 || buf[..8] == session_id.to_be_bytes() {
+                    continue;
+                }
+                buf.copy_within(8..len, 0);
+                return Ok(len - 8);
+            },
+            #[cfg(feature = "dtls")]
+            VswitchLink::Dtls(link) => link.recv_frame(buf),
+            #[cfg(feature = "quic")]
+            VswitchLink::Quic(link) => link.recv_frame(buf),
+        }
+    }
+
+    /// Set the don't fragment flag on every datagram sent over a UDP
+    /// link, so path MTU probes too large for the path are dropped
+    fn set_dont_fragment(&self) -> io::Result<()> {
+        match self {
+            VswitchLink::Udp { sock, .. } => pmtud::set_dont_fragment(sock),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns another handle to the same underlying socket
+    fn try_clone(&self) -> Result<VswitchLink, TransportError> {
+        Ok(match self {
+            VswitchLink::Udp {
+                sock,
+                vswitch_addr,
+                auth,
+                encap,
+                marker,
+                vni,
+            } => VswitchLink::Udp {
+                sock: sock.try_clone()?,
+                vswitch_addr: vswitch_addr.clone(),
+                auth: auth.clone(),
+                encap: *encap,
+                marker: marker.clone(),
+                vni: *vni,
+            },
+            #[cfg(unix)]
+            VswitchLink::Vsock(stream) => VswitchLink::Vsock(stream.try_clone()?),
+            VswitchLink::Tcp(link) => VswitchLink::Tcp(link.try_clone()?),
+            #[cfg(unix)]
+            VswitchLink::Unix(sock) => VswitchLink::Unix(sock.try_clone()?),
+            #[cfg(target_os = "linux")]
+            VswitchLink::Shm(link) => VswitchLink::Shm(link.try_clone()?),
+            VswitchLink::Group {
+                sock,
+                vswitch_addr,
+                link,
+                session_id,
+            } => VswitchLink::Group {
+                sock: sock.try_clone()?,
+                vswitch_addr: vswitch_addr.clone(),
+                link: Box::new(link.try_clone()?),
+                session_id: *session_id,
+            },
+            #[cfg(feature = "dtls")]
+            VswitchLink::Dtls(link) => VswitchLink::Dtls(link.try_clone()?),
+            #[cfg(feature = "quic")]
+            VswitchLink::Quic(link) => VswitchLink::Quic(link.try_clone()?),
+        })
+    }
 }
 
-/*
- * This macro generates a function called tunsetiff
- * which is a wrapper around the ioctl call which points
- * /dev/net/tun to the device specified in the ifreq struct,
- * and configures it with the flags set in the ifreq struct
- */
-ioctl_write_ptr!(tunsetiff, TUNTAP_DRIVER, TUNTAP_SET_FLAGS, c_int);
+/*
+ * Where the sockets of links to vswitches over UDP are bound, which
+ * is any local address and an ephemeral port by default
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+struct UnderlayBinding {
+    addr: Option<Ipv4Addr>,
+    /* Source port of the link to the first vswitch */
+    port: Option<u16>,
+    device: Option<String>,
+}
+
+impl UnderlayBinding {
+    /// Returns a UDP socket bound as given, with port as its source port
+    fn bind(&self, port: u16) -> io::Result<UdpSocket> {
+        let addr = SocketAddrV4::new(self.addr.unwrap_or(Ipv4Addr::UNSPECIFIED), port);
+        platform::bind_udp(addr, self.device.as_deref(), false)
+    }
+}
+
+impl fmt::Display for UnderlayBinding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}",
+            self.addr.unwrap_or(Ipv4Addr::UNSPECIFIED),
+            self.port.unwrap_or(0)
+        )?;
+        match &self.device {
+            Some(device) => write!(f, " on {}", device),
+            None => Ok(()),
+        }
+    }
+}
+
+/*
+ * Standard encapsulation which frames are exchanged with the vswitch
+ * in, and the VNI or L2TP session IDs they are sent with
+ */
+#[derive(Clone, Copy, Debug)]
+enum Encap {
+    Vxlan(u32),
+    Geneve(u32),
+    L2tp {
+        session_id: u32,
+        peer_session_id: u32,
+    },
+}
+
+impl Encap {
+    /// Returns frame in a datagram of this encapsulation, ready to be sent
+    fn encapsulate(&self, frame: &[u8]) -> Vec<u8> {
+        match *self {
+            Encap::Vxlan(vni) => vxlan::encapsulate(vni, frame),
+            Encap::Geneve(vni) => geneve::encapsulate(vni, &geneve::Metadata::default(), frame),
+            Encap::L2tp {
+                peer_session_id, ..
+            } => l2tp::encapsulate(peer_session_id, frame),
+        }
+    }
+
+    /// Move the frame carried by the datagram in buf, which is len bytes
+    /// long, to the start of buf, returning its length. None is returned,
+    /// so the datagram is dropped, if it isn't of this encapsulation or
+    /// is for another VNI or session
+    fn decapsulate(&self, buf: &mut [u8], len: usize) -> Option<usize> {
+        let (id, frame) = match *self {
+            Encap::Vxlan(_) => vxlan::decapsulate(&buf[..len])?,
+            Encap::Geneve(_) => {
+                let (vni, metadata, frame) = geneve::decapsulate(&buf[..len])?;
+                if let (Some(tenant_id), Some(ingress_port)) =
+                    (metadata.tenant_id, metadata.ingress_port)
+                {
+                    log_frame!(
+                        "Frame came from port {} of the vswitch, in tenant {}",
+                        ingress_port,
+                        tenant_id
+                    );
+                }
+                (vni, frame)
+            }
+            Encap::L2tp { .. } => l2tp::decapsulate(&buf[..len])?,
+        };
+        if id != self.receive_id() {
+            return None;
+        }
+        let frame_start = len - frame.len();
+        buf.copy_within(frame_start..len, 0);
+        Some(len - frame_start)
+    }
+
+    /// Returns the VNI or session ID which frames are received with
+    fn receive_id(&self) -> u32 {
+        match *self {
+            Encap::Vxlan(vni) | Encap::Geneve(vni) => vni,
+            Encap::L2tp { session_id, .. } => session_id,
+        }
+    }
+
+    /// Returns the most bytes of the tunnel MTU which the headers take
+    fn overhead(&self) -> usize {
+        match self {
+            Encap::Vxlan(_) => VXLAN_HDR_LEN,
+            Encap::Geneve(_) => GENEVE_OVERHEAD,
+            Encap::L2tp { .. } => L2TP_HDR_LEN,
+        }
+    }
+}
+
+/*
+ * Configuration given to the vport on the command line
+ */
+struct Config {
+    session_path: Option<String>,
+    mode: Option<Mode>,
+    /* NIC which is bridged into the overlay, rather than creating a tap interface */
+    nic: Option<String>,
+    /* Socket of the helper which hands over the tap interface, rather than creating one */
+    tap_helper: Option<String>,
+    /* Name of the tap interface, which may hold a %d, if not the default for the mode */
+    tap_name: Option<String>,
+    /* Whether the tap interface outlives the vport, and who else can attach to it */
+    tap_persist: Option<bool>,
+    tap_owner: Option<u32>,
+    tap_group: Option<u32>,
+    /* Addresses to give the tap interface, and whether to bring it up or down */
+    tap_addrs: Vec<(IpAddr, u8)>,
+    tap_up: Option<bool>,
+    /* MAC to give the tap interface, if not the one the kernel picks */
+    tap_mac: Option<MacAddr>,
+    vswitch_addr: VswitchAddr,
+    secondary_addr: Option<VswitchAddr>,
+    /* Vswitches to fail over to, in order, when the first one stops being heard from */
+    backup_addrs: Vec<VswitchAddr>,
+    /* MTU of the L2VPN network, if not the default */
+    mtu: Option<usize>,
+    tunnel_mtu: Option<usize>,
+    /* Proxy which TCP links to the vswitches go through */
+    proxy: Option<Proxy>,
+    /* File holding the proxy's user name and password, if not in its URL */
+    proxy_credentials_path: Option<String>,
+    /* Underlay multicast group which the vswitch floods frames through */
+    bum_group: Option<SocketAddrV4>,
+    /* Local addresses of the further links to the first vswitch */
+    lag_links: Vec<Ipv4Addr>,
+    /* Where the sockets of links to the vswitches over UDP are bound, if not anywhere */
+    bind_addr: Option<Ipv4Addr>,
+    source_port: Option<u16>,
+    bind_device: Option<String>,
+    /* File holding the key which links to the vswitches over UDP use for DTLS */
+    dtls_psk_path: Option<String>,
+    /* File holding the key which frames sent over UDP are signed with */
+    auth_psk_path: Option<String>,
+    /* VNI which frames are sent to the vswitch with, in VXLAN or GENEVE mode */
+    vxlan_vni: Option<u32>,
+    geneve_vni: Option<u32>,
+    /* Session IDs of the vport and its peer, in L2TP mode */
+    l2tp_sessions: Option<(u32, u32)>,
+    /* File of the CAs which vswitches reached over QUIC are checked against */
+    quic_ca_path: Option<String>,
+    /* How often hellos are sent, if not HELLO_INTERVAL */
+    keepalive_interval: Option<Duration>,
+    /* How long the vswitches can go unheard, if not VSWITCH_TIMEOUT, and what is done then */
+    vswitch_timeout: Option<Duration>,
+    vswitch_timeout_action: Option<TimeoutAction>,
+    /* VLAN which the vport asks the vswitch to put its frames in when it joins */
+    vlan: Option<u16>,
+    /* Whether the vport asks the vswitches to compress frames with LZ4 when it joins */
+    compression: Option<bool>,
+    /* Whether frames too large for the tunnel MTU are fragmented, rather than refused */
+    fragmentation: Option<bool>,
+    /* Whether the vport finds the path MTU to the vswitches, and what it does with it */
+    path_mtu_discovery: Option<bool>,
+    path_mtu_action: Option<PathMtuAction>,
+    /* DSCP which the datagrams sent to the vswitches over UDP are marked with */
+    dscp: Option<Dscp>,
+    /* VNI which the vport's datagrams to the vswitches are sent in, sharing its port */
+    vni: Option<u32>,
+    /* Whether the vport asks to be introduced to other vports, to send them frames directly */
+    direct_paths: Option<bool>,
+    /* Whether the vport asks the vswitch to batch small frames into shared datagrams */
+    batching: Option<bool>,
+    /* How many datagrams each FEC group protects, if the vport asks the vswitch for FEC */
+    fec_group: Option<u8>,
+    /* Whether the vport answers the host's ARP requests for addresses behind other vports */
+    arp_suppression: Option<bool>,
+    /* STUN servers which the vport asks for its public endpoint when it starts */
+    stun_servers: Vec<(String, u16)>,
+    /* Filters of the frames from the tap interface, and from the overlay, which are dropped */
+    drop_egress: Vec<Filter>,
+    drop_ingress: Vec<Filter>,
+    /* Pidfile of the daemon which the vport detaches as, if it does */
+    daemon: Option<String>,
+    /* File (or syslog) which the vport logs to, if not stdout and stderr */
+    log: Option<String>,
+}
+
+/*
+ * Where the vswitch can be reached, as given on the command line
+ */
+enum VswitchAddr {
+    /* Host name or IPv4 address, and port */
+    Udp(String, u16),
+    Vsock(u32, u32),
+    /* Host name or IP address, and TCP port */
+    Tcp(String, u16),
+    /* Host name or IP address, UDP port, and the file of the CAs its certificate is checked against */
+    Quic {
+        host: String,
+        port: u16,
+        ca_path: Option<String>,
+    },
+    Unix(String),
+    Shm(String),
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().collect();
+    logging::init();
+
+    if args.get(1).map(String::as_str) == Some("check-config") {
+        return check_config(&args[2..]);
+    }
+
+    let config = match parse_config(&args[1..]) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("{}", USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let errors = validate_config(&config);
+    if !errors.is_empty() {
+        for e in errors.iter() {
+            eprintln!("{}", e);
+        }
+        return ExitCode::FAILURE;
+    }
+
+    let Config {
+        session_path,
+        mode,
+        nic,
+        tap_helper,
+        tap_name,
+        tap_persist,
+        tap_owner,
+        tap_group,
+        tap_addrs,
+        tap_up,
+        tap_mac,
+        vswitch_addr,
+        secondary_addr,
+        backup_addrs,
+        mtu,
+        tunnel_mtu,
+        mut proxy,
+        proxy_credentials_path,
+        bum_group,
+        lag_links,
+        bind_addr,
+        source_port,
+        bind_device,
+        dtls_psk_path,
+        auth_psk_path,
+        vxlan_vni,
+        geneve_vni,
+        l2tp_sessions,
+        keepalive_interval,
+        vswitch_timeout,
+        vswitch_timeout_action,
+        vlan,
+        compression,
+        fragmentation,
+        path_mtu_discovery,
+        path_mtu_action,
+        dscp,
+        vni,
+        direct_paths,
+        batching,
+        fec_group,
+        arp_suppression,
+        stun_servers,
+        drop_egress,
+        drop_ingress,
+        #[cfg(unix)]
+        daemon,
+        #[cfg(unix)]
+        log,
+        ..
+    } = config;
+
+    /* Taken before any thread is started, as it changes the environment */
+    #[cfg(unix)]
+    let activated = systemd::listen_fds();
+
+    /* Only the main thread survives detaching, so it comes before any other is started */
+    #[cfg(unix)]
+    let mut daemon = match daemon.as_deref().map(daemon::daemonize).transpose() {
+        Ok(daemon) => daemon,
+        Err(e) => {
+            eprintln!("Got error while detaching as a daemon: '{}'", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    /*
+     * SIGINT and SIGTERM (or on Windows, Ctrl+C) are blocked in every
+     * thread, and waited for by a thread of its own, so we can leave the
+     * vswitches before stopping. They have to be blocked before any
+     * thread is started
+     */
+    let signals = match StopSignals::block() {
+        Ok(signals) => signals,
+        Err(e) => {
+            eprintln!("Got error while blocking signals: '{}'", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    /* A daemon has no terminal to log to, so logs to syslog unless told otherwise */
+    #[cfg(unix)]
+    let log = match (log.as_deref(), &daemon) {
+        (Some(log), _) => Some(LogTarget::parse(log)),
+        (None, Some(_)) => Some(LogTarget::Syslog),
+        (None, None) => None,
+    };
+    #[cfg(unix)]
+    if let Some(log) = &log {
+        if let Err(e) = daemon::redirect_logs(log, c"vport") {
+            eprintln!("Got error while redirecting logs: '{}'", e);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let session = match get_session(session_path.as_deref()) {
+        Ok(session) => session,
+        Err(e) => {
+            eprintln!("Got error while getting session ID: '{}'", e);
+            return ExitCode::from(e.exit_code());
+        }
+    };
+
+    /* Credentials from a file replace any given in the proxy's URL */
+    if let (Some(proxy), Some(path)) = (proxy.as_mut(), &proxy_credentials_path) {
+        match read_proxy_credentials(path) {
+            Ok(credentials) => proxy.credentials = Some(credentials),
+            Err(e) => {
+                eprintln!("{}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let dtls_psk = match dtls_psk_path.as_deref().map(read_dtls_psk).transpose() {
+        Ok(dtls_psk) => dtls_psk,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let frame_auth = match auth_psk_path.as_deref().map(frame_auth).transpose() {
+        Ok(frame_auth) => frame_auth,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let encap = encap(vxlan_vni, geneve_vni, l2tp_sessions);
+    let binding = UnderlayBinding {
+        addr: bind_addr,
+        port: source_port,
+        device: bind_device,
+    };
+
+    /*
+     * Initialise vport struct, which fits packets to what DTLS, QUIC, tags
+     * or headers leave of the tunnel MTU, unless it fragments frames to fit
+     * it instead, in which case that is the largest datagram payload sent
+     */
+    let overhead = tunnel_overhead(
+        dtls_psk.is_some(),
+        frame_auth.is_some(),
+        is_quic(
+            iter::once(&vswitch_addr)
+                .chain(&secondary_addr)
+                .chain(&backup_addrs),
+        ),
+        vni.is_some(),
+        fec_group.is_some(),
+        encap.as_ref(),
+    );
+    let fragment_size = tunnel_mtu
+        .filter(|_| fragmentation == Some(true))
+        .map(|tunnel_mtu| tunnel_mtu - UDP_TUNNEL_OVERHEAD - overhead);
+    let mut core = VportCore::new(
+        session,
+        mtu.unwrap_or(DEFAULT_OVERLAY_MTU),
+        tunnel_mtu
+            .filter(|_| fragment_size.is_none())
+            .map(|tunnel_mtu| tunnel_mtu - overhead),
+        bum_group,
+    );
+    core.set_filters(drop_egress, drop_ingress);
+    if arp_suppression == Some(true) {
+        core.suppress_arp();
+    }
+    let l3 = mode == Some(Mode::L3);
+
+    /*
+     * systemd passes back the tap interface an earlier vport handed it to
+     * keep, which is used rather than creating one, unless the vport has
+     * been told to attach to a NIC or be handed one by a helper since
+     */
+    #[cfg(unix)]
+    let stored = match activated
+        .into_iter()
+        .find(|(name, _)| name == SYSTEMD_TAP_NAME)
+        .filter(|_| nic.is_none() && tap_helper.is_none())
+        .map(|(_, fd)| tap::adopt(fd))
+    {
+        Some(Ok((stored, tun))) if tun == l3 => Some(stored),
+        Some(Ok((stored, _))) => {
+            eprintln!(
+                "systemd passed {}, which is a {} interface, but the vport is in {} mode",
+                stored.name(),
+                if l3 { "tap" } else { "tun" },
+                if l3 { "L3" } else { "L2" }
+            );
+            return ExitCode::FAILURE;
+        }
+        Some(Err(e)) => {
+            eprintln!(
+                "Got error while taking the tap interface systemd passed: '{}'",
+                e
+            );
+            return ExitCode::FAILURE;
+        }
+        None => None,
+    };
+    #[cfg(windows)]
+    let stored = None;
+    let tap = TapSettings {
+        name: nic.as_deref().or(tap_name.as_deref()).unwrap_or(if l3 {
+            tap::DEFAULT_TUN_NAME
+        } else {
+            tap::DEFAULT_TAP_NAME
+        }),
+        l3,
+        nic: nic.is_some(),
+        helper: tap_helper.as_deref(),
+        stored,
+        persist: tap_persist,
+        owner: tap_owner,
+        group: tap_group,
+        mac: tap_mac,
+        /* The overlay MTU is only given to a tap interface the vport creates, not to a NIC */
+        mtu: mtu.filter(|_| nic.is_none() && tap_helper.is_none()),
+        addrs: &tap_addrs,
+        up: tap_up,
+    };
+    let vswitch_addrs = VswitchAddrs {
+        first: &vswitch_addr,
+        secondary: secondary_addr.as_ref(),
+        backups: &backup_addrs,
+    };
+    let mut vport = match initialise_vport(
+        tap,
+        &vswitch_addrs,
+        &lag_links,
+        core,
+        proxy.as_ref(),
+        &signals,
+    )
+    .and_then(|mut vport| {
+        bind_links(&mut vport, &binding)?;
+        secure_links(vport, dtls_psk.as_deref())
+    }) {
+        Ok(vport) => vport,
+        Err(e) => {
+            eprintln!("Got error while initialising vport: '{}'", e);
+            eprintln!("Quitting");
+            return ExitCode::from(e.exit_code());
+        }
+    };
+    if let Some(frame_auth) = &frame_auth {
+        sign_links(&mut vport, frame_auth);
+    }
+    if let Some(vni) = vni {
+        tenant_links(&mut vport, vni);
+        println!("Sending frames to the vswitch in VNI {}", vni);
+    }
+    vport.fragment_size = fragment_size;
+    vport.fec_group = fec_group;
+    if direct_paths == Some(true) {
+        vport.direct = Some(Arc::default());
+    }
+    if path_mtu_discovery == Some(true) {
+        let mut links = iter::once(&vport.link)
+            .chain(vport.secondary.as_ref())
+            .chain(vport.backups.iter());
+        if let Err(e) = links.try_for_each(VswitchLink::set_dont_fragment) {
+            eprintln!("Got error while setting the don't fragment flag: '{}'", e);
+            return ExitCode::FAILURE;
+        }
+    }
+    if let Some(encap) = encap {
+        if let Err(e) = encapsulate_link(&mut vport, encap, &binding) {
+            eprintln!("Got error while switching to {:?}: '{}'", encap, e);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    /* The socket of the link may have been replaced, so links are marked last */
+    if let Some(dscp) = dscp {
+        mark_links(&mut vport, dscp);
+        println!("Marking the datagrams sent to the vswitch with {}", dscp);
+    }
+
+    /* The endpoint is only reported to the vswitch the socket reaches */
+    let public_endpoint = match stun_servers.is_empty() {
+        true => None,
+        false => discover_public_endpoint(&vport.link, &stun_servers),
+    };
+    match public_endpoint {
+        Some(public_endpoint) => {
+            println!("STUN servers see us from {}", public_endpoint)
+        }
+        None if !stun_servers.is_empty() => {
+            eprintln!("No STUN server answered, so our public endpoint isn't known")
+        }
+        None => {}
+    }
+
+    let vport_clone = match clone_vport(&vport) {
+        Ok(vport_clone) => vport_clone,
+        Err(e) => {
+            eprintln!("Failed to clone vport with error: '{}'", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let hello_links = match clone_links(&vport) {
+        Ok(hello_links) => hello_links,
+        Err(e) => {
+            eprintln!("Failed to clone link with error: '{}'", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let leave_links = match clone_links(&vport) {
+        Ok(leave_links) => leave_links,
+        Err(e) => {
+            eprintln!("Failed to clone link with error: '{}'", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let leave = vport.core.leave();
+    let lag_hello_links = match clone_lag_links(&vport) {
+        Ok(lag_hello_links) => lag_hello_links,
+        Err(e) => {
+            eprintln!("Failed to clone link with error: '{}'", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    /*
+     * A multihomed vport receives every frame twice, so the
+     * receiving threads share a filter to drop the second copies
+     */
+    let mut secondary_vport = None;
+    if vport.secondary.is_some() {
+        let duplicates = Arc::new(Mutex::new(DuplicateFilter::default()));
+        let secondary = match clone_vport(&vport) {
+            Ok(secondary) => Vport {
+                link: secondary.secondary.unwrap(),
+                lag: Vec::new(),
+                secondary: None,
+                backups: Vec::new(),
+                ..secondary
+            },
+            Err(e) => {
+                eprintln!("Failed to clone vport with error: '{}'", e);
+                return ExitCode::FAILURE;
+            }
+        };
+        secondary_vport = Some((secondary, duplicates));
+    }
+
+    /*
+     * Frames flooded through the BUM group arrive on a socket of
+     * their own, so are taken to the tap interface by another thread
+     */
+    let mut group_vport = None;
+    if let Some(group) = bum_group {
+        let group_link = match join_bum_group(group, &vport.link, session.id) {
+            Ok(group_link) => group_link,
+            Err(e) => {
+                eprintln!("Got error while joining BUM group {}: '{}'", group, e);
+                return ExitCode::FAILURE;
+            }
+        };
+        match clone_vport(&vport) {
+            Ok(group_clone) => {
+                group_vport = Some(Vport {
+                    link: group_link,
+                    lag: Vec::new(),
+                    secondary: None,
+                    backups: Vec::new(),
+                    ..group_clone
+                })
+            }
+            Err(e) => {
+                eprintln!("Failed to clone vport with error: '{}'", e);
+                return ExitCode::FAILURE;
+            }
+        }
+        println!("Joined BUM group {}", group);
+    }
+
+    /* Frames sent over the further links arrive on sockets of their own too */
+    let mut lag_vports = Vec::new();
+    let lag_links = match clone_lag_links(&vport) {
+        Ok(lag_links) => lag_links,
+        Err(e) => {
+            eprintln!("Failed to clone link with error: '{}'", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    for lag_link in lag_links {
+        match clone_vport(&vport) {
+            Ok(lag_clone) => lag_vports.push(Vport {
+                link: lag_link,
+                lag: Vec::new(),
+                secondary: None,
+                backups: Vec::new(),
+                ..lag_clone
+            }),
+            Err(e) => {
+                eprintln!("Failed to clone vport with error: '{}'", e);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    /* Frames from the backup vswitches arrive on sockets of their own as well */
+    let mut backup_vports = Vec::new();
+    for index in 0..vport.backups.len() {
+        match clone_vport(&vport) {
+            Ok(mut backup_clone) => backup_vports.push(Vport {
+                link: backup_clone.backups.remove(index),
+                lag: Vec::new(),
+                secondary: None,
+                backups: Vec::new(),
+                ..backup_clone
+            }),
+            Err(e) => {
+                eprintln!("Failed to clone vport with error: '{}'", e);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    println!("Starting vport with session {:016x}", session.id);
+
+    /*
+     * Start threads which periodically tell the vswitches
+     * our session ID. These aren't joined, as they only stop
+     * if a link fails, which the other threads will see
+     */
+    /*
+     * The vswitches send echo requests every ECHO_INTERVAL, so one which
+     * isn't heard from for the timeout has likely gone away, and answer
+     * our joins. VTEPs and L2TP peers do neither, so aren't watched or
+     * joined
+     */
+    let vswitch_timeout = vswitch_timeout.unwrap_or(VSWITCH_TIMEOUT);
+    let vswitches: Vec<Arc<VswitchStatus>> = match encap {
+        Some(_) => Vec::new(),
+        None => (0..1 + usize::from(secondary_addr.is_some()) + backup_addrs.len())
+            .map(|index| {
+                let name = vswitch_name(index, !backup_addrs.is_empty());
+                Arc::new(VswitchStatus::new(name, vswitch_timeout))
+            })
+            .collect(),
+    };
+
+    /*
+     * Start threads which periodically tell the vswitches
+     * our session ID, joining them first. These aren't joined,
+     * as they only stop if a link fails, which the other
+     * threads will see
+     */
+    let mut capabilities = 0;
+    if secondary_addr.is_some() {
+        capabilities |= CAP_MULTIHOMED;
+    }
+    if !lag_vports.is_empty() {
+        capabilities |= CAP_LAG;
+    }
+    if compression == Some(true) {
+        capabilities |= CAP_COMPRESSION;
+    }
+    if direct_paths == Some(true) {
+        capabilities |= CAP_DIRECT_PATHS;
+    }
+    if batching == Some(true) {
+        capabilities |= CAP_BATCHING;
+    }
+    let keepalive_interval = keepalive_interval.unwrap_or(HELLO_INTERVAL);
+    for (index, hello_link) in hello_links.into_iter().enumerate() {
+        let hellos = vport.core.hellos(index == 0);
+        let join = vswitches.get(index).map(|status| {
+            (
+                vport.core.join(
+                    index == 0,
+                    capabilities,
+                    vlan,
+                    fragment_size.map(|size| size as u16),
+                    public_endpoint.filter(|_| index == 0),
+                    fec_group.filter(|_| index == 0),
+                ),
+                status.clone(),
+            )
+        });
+        thread::spawn(move || {
+            let join = join
+                .as_ref()
+                .map(|(join, status)| (join.as_slice(), &**status, status.name.as_str()));
+            send_hellos(&hello_link, &hellos, join, keepalive_interval)
+        });
+    }
+    for lag_link in lag_hello_links {
+        let lag_member = vec![vport.core.lag_member()];
+        thread::spawn(move || send_hellos(&lag_link, &lag_member, None, keepalive_interval));
+    }
+
+    /*
+     * Start threads which find the path MTU to each vswitch, and
+     * fit packets to it. They aren't joined either, as they never stop
+     */
+    if path_mtu_discovery == Some(true) {
+        let probe_links = match clone_links(&vport) {
+            Ok(probe_links) => probe_links,
+            Err(e) => {
+                eprintln!("Failed to clone link with error: '{}'", e);
+                return ExitCode::FAILURE;
+            }
+        };
+        let set_tap_mtu = path_mtu_action == Some(PathMtuAction::SetTapMtu);
+        for (index, probe_link) in probe_links.into_iter().enumerate() {
+            let probed = vswitches.clone();
+            let core = vport.core.clone();
+            let tap = set_tap_mtu.then(|| vport.tap.clone());
+            thread::spawn(move || {
+                let tap = tap.as_deref();
+                discover_path_mtu(&probe_link, index, &probed, &core, overhead, tap)
+            });
+        }
+    }
+
+    /*
+     * Start the thread which punches holes through the NATs between
+     * us and the vports the vswitch introduces us to. It isn't joined
+     * either, as it never stops
+     */
+    if let Some(paths) = vport.direct.clone() {
+        let punch_link = match vport.link.try_clone() {
+            Ok(punch_link) => punch_link,
+            Err(e) => {
+                eprintln!("Failed to clone link with error: '{}'", e);
+                return ExitCode::FAILURE;
+            }
+        };
+        let core = vport.core.clone();
+        thread::spawn(move || punch_holes(&punch_link, &paths, &core));
+    }
+
+    /* Start thread which fails frames over to the backup vswitches, and back, if there are any */
+    if let Some(active) = vport.active.clone() {
+        let failover_links = match clone_links(&vport) {
+            Ok(failover_links) => failover_links,
+            Err(e) => {
+                eprintln!("Failed to clone link with error: '{}'", e);
+                return ExitCode::FAILURE;
+            }
+        };
+        let watched = vswitches.clone();
+        let leave = vport.core.leave();
+        thread::spawn(move || fail_over(&failover_links, &leave, &watched, &active));
+    }
+
+    /*
+     * Each forwarding loop is restarted if it fails, and reports when
+     * it stops for good, which ends the vport, as half of the tunnel
+     * is gone. A watchdog ends the vport if a loop gets stuck instead
+     */
+    let (stopped_tx, stopped_rx) = mpsc::channel::<Stop>();
+    let mut heartbeats = Vec::new();
+    let drops = vport.drops.clone();
+    let core = vport.core.clone();
+
+    /*
+     * Start thread which takes packets from
+     * tap interface and forwards to vswitch
+     */
+    let heartbeat = Heartbeat::new();
+    heartbeats.push(("tap_to_vswitch".to_string(), heartbeat.clone()));
+    let tap_stopped_tx = stopped_tx.clone();
+    let tap_vswitches = vswitches.clone();
+    let left = Arc::new(AtomicBool::new(false));
+    let tap_left = left.clone();
+    thread::spawn(move || {
+        supervise("tap_to_vswitch", || {
+            tap_to_vswitch(&mut vport, &tap_vswitches, &tap_left, &heartbeat)
+        });
+        let _ = tap_stopped_tx.send(Stop::Loop("tap_to_vswitch"));
+    });
+
+    /*
+     * Start thread which waits to be told to stop, then tells the
+     * vswitches we're leaving, so they flush our MACs straight away
+     * rather than black-holing frames sent to us until they stop
+     * hearing from us. The frames held in a batch or an FEC group
+     * have until their time is up to be sent first, and no frame is
+     * sent after the leave, which would bring our port back up
+     */
+    let signal_stopped_tx = stopped_tx.clone();
+    thread::spawn(move || {
+        let Ok(signal) = signals.wait() else {
+            return;
+        };
+        thread::sleep(BATCH_TIMEOUT.max(FEC_FLUSH_TIMEOUT));
+        left.store(true, Ordering::Relaxed);
+        for link in leave_links.iter() {
+            if let Err(e) = link.send(&leave) {
+                eprintln!("Got error while leaving vswitch: '{}'", e);
+            }
+        }
+        let _ = signal_stopped_tx.send(Stop::Signal(signal));
+    });
+
+    /* Start thread which logs the vswitches stopping being heard from, and quits if told to */
+    if !vswitches.is_empty() {
+        let watched = vswitches.clone();
+        let action = vswitch_timeout_action.unwrap_or(TimeoutAction::Log);
+        let timeout_stopped_tx = stopped_tx.clone();
+        let failover = !backup_addrs.is_empty();
+        thread::spawn(move || watch_vswitches(&watched, action, failover, &timeout_stopped_tx));
+    }
+
+    /*
+     * Start threads which take packets received from the vswitch
+     * and forward them to tap intf, and from the second vswitch, the
+     * BUM group and the further links, if there are any. The frames of
+     * the BUM group and the further links come from the same vswitch as
+     * the first link's, so share its index
+     *
+     * Only the first vswitch's thread stopping stops the vport, as
+     * the second vswitch and the backups are there in case the first
+     * one fails
+     */
+    let duplicates = secondary_vport.as_ref().map(|(_, d)| d.clone());
+    let mut receivers = vec![(
+        "vswitch_to_tap",
+        vport_clone,
+        0,
+        duplicates.clone(),
+        Some(stopped_tx),
+    )];
+    if let Some((secondary, duplicates)) = secondary_vport {
+        receivers.push((
+            "vswitch_to_tap (second vswitch)",
+            secondary,
+            1,
+            Some(duplicates),
+            None,
+        ));
+    }
+    for (index, backup_vport) in backup_vports.into_iter().enumerate() {
+        receivers.push((
+            "vswitch_to_tap (backup vswitch)",
+            backup_vport,
+            index + 1,
+            None,
+            None,
+        ));
+    }
+    for lag_vport in lag_vports {
+        receivers.push((
+            "vswitch_to_tap (LAG link)",
+            lag_vport,
+            0,
+            duplicates.clone(),
+            None,
+        ));
+    }
+    if let Some(group_vport) = group_vport {
+        receivers.push((
+            "vswitch_to_tap (BUM group)",
+            group_vport,
+            0,
+            duplicates,
+            None,
+        ));
+    }
+    for (name, mut receiver, link_index, duplicates, stopped_tx) in receivers {
+        let heartbeat = Heartbeat::new();
+        heartbeats.push((name.to_string(), heartbeat.clone()));
+        let status = vswitches.get(link_index).cloned();
+        thread::spawn(move || {
+            supervise(name, || {
+                vswitch_to_tap(
+                    &mut receiver,
+                    link_index,
+                    duplicates.as_deref(),
+                    status.as_deref(),
+                    &heartbeat,
+                )
+            });
+            if let Some(stopped_tx) = stopped_tx {
+                let _ = stopped_tx.send(Stop::Loop(name));
+            }
+        });
+    }
+
+    /* systemd restarts the vport if it stops hearing that the loops are fine */
+    systemd::watchdog(heartbeats.iter().map(|(_, h)| h.clone()).collect());
+    supervisor::watchdog(heartbeats, WATCHDOG_TIMEOUT, |name, busy_for| {
+        eprintln!("{} has been stuck for {:?}, quitting", name, busy_for);
+        process::exit(1);
+    });
+
+    /* Wait to be told to stop, or for a forwarding loop to stop, leaving the vport unable to do its job */
+    notify_systemd("READY=1");
+    #[cfg(unix)]
+    if let Some(daemon) = &mut daemon {
+        daemon.ready();
+    }
+    let stop = stopped_rx.recv();
+    notify_systemd("STOPPING=1");
+    let exit_code = match stop {
+        Ok(Stop::Loop(name)) => {
+            eprintln!("{} stopped", name);
+            ExitCode::FAILURE
+        }
+        Ok(Stop::Signal(signal)) => {
+            println!("Got {}, so left the vswitch", signal);
+            ExitCode::SUCCESS
+        }
+        Ok(Stop::VswitchTimeout) => {
+            eprintln!("Quitting, as the vswitch stopped being heard from");
+            ExitCode::FAILURE
+        }
+        Err(_) => ExitCode::SUCCESS,
+    };
+
+    println!(
+        "Terminating vport, having dropped {} frame(s) which could not be sent to the vswitch, and {} which could not be written to the tap interface",
+        drops.tx.load(Ordering::Relaxed),
+        drops.tap.load(Ordering::Relaxed)
+    );
+    if let Some((answered, cached)) = core.arp_stats() {
+        println!(
+            "Answered {} ARP request(s) locally, with {} address(es) cached",
+            answered, cached
+        );
+    }
+
+    /* The sockets bound for Unix links would otherwise be left behind */
+    #[cfg(unix)]
+    for addr in iter::once(&vswitch_addr)
+        .chain(&secondary_addr)
+        .chain(&backup_addrs)
+    {
+        if let VswitchAddr::Unix(vswitch_path) = addr {
+            let _ = fs::remove_file(unix_local_path(vswitch_path));
+        }
+    }
+
+    exit_code
+}
+
+/// Tell systemd state, such as READY=1, if it is listening
+fn notify_systemd(state: &str) {
+    if let Err(e) = systemd::notify(state) {
+        eprintln!("Got error while notifying systemd: '{}'", e);
+    }
+}
+
+/// Parse the command line arguments (without the program name)
+fn parse_config(args: &[String]) -> Result<Config, String> {
+    /* Take the options out, leaving just the vswitch address */
+    let mut session_path = None;
+    let mut mode = None;
+    let mut nic = None;
+    let mut tap_helper = None;
+    let mut tap_name = None;
+    let mut tap_persist = None;
+    let mut tap_owner = None;
+    let mut tap_group = None;
+    let mut tap_addrs = Vec::new();
+    let mut tap_up = None;
+    let mut tap_mac = None;
+    let mut mtu = None;
+    let mut tunnel_mtu = None;
+    let mut proxy = None;
+    let mut proxy_credentials_path = None;
+    let mut bum_group = None;
+    let mut lag_links = Vec::new();
+    let mut bind_addr = None;
+    let mut source_port = None;
+    let mut bind_device = None;
+    let mut dtls_psk_path = None;
+    let mut auth_psk_path = None;
+    let mut vxlan_vni = None;
+    let mut geneve_vni = None;
+    let mut l2tp_sessions = None;
+    let mut quic_ca_path = None;
+    let mut keepalive_interval = None;
+    let mut vswitch_timeout = None;
+    let mut vswitch_timeout_action = None;
+    let mut vlan = None;
+    let mut compression = None;
+    let mut fragmentation = None;
+    let mut path_mtu_discovery = None;
+    let mut path_mtu_action = None;
+    let mut dscp = None;
+    let mut vni = None;
+    let mut direct_paths = None;
+    let mut batching = None;
+    let mut fec_group = None;
+    let mut arp_suppression = None;
+    let mut stun_servers = Vec::new();
+    let mut drop_egress = Vec::new();
+    let mut drop_ingress = Vec::new();
+    let mut daemon = None;
+    let mut log = None;
+    let mut args = args;
+    while let [flag, rest @ ..] = args {
+        if ![
+            "--session-file",
+            "--mode",
+            "--nic",
+            "--tap-helper",
+            "--tap-name",
+            "--tap-persist",
+            "--tap-owner",
+            "--tap-group",
+            "--tap-addr",
+            "--tap-up",
+            "--mac",
+            "--mac-seed",
+            "--mtu",
+            "--tunnel-mtu",
+            "--proxy",
+            "--proxy-credentials-file",
+            "--bum-group",
+            "--lag-link",
+            "--bind-addr",
+            "--source-port",
+            "--bind-device",
+            "--dtls-psk-file",
+            "--auth-psk-file",
+            "--vxlan",
+            "--geneve",
+            "--l2tp",
+            "--quic-ca-file",
+            "--keepalive-interval",
+            "--vswitch-timeout",
+            "--vswitch-timeout-action",
+            "--vlan",
+            "--compression",
+            "--fragmentation",
+            "--path-mtu-discovery",
+            "--path-mtu-action",
+            "--dscp",
+            "--vni",
+            "--direct-paths",
+            "--batching",
+            "--fec",
+            "--arp-suppression",
+            "--stun-server",
+            "--drop-egress",
+            "--drop-ingress",
+            "--daemon",
+            "--log",
+        ]
+        .contains(&flag.as_str())
+        {
+            break;
+        }
+        let [value, rest @ ..] = rest else {
+            return Err(format!("Missing value for '{}'", flag));
+        };
+
+        let replaced = match flag.as_str() {
+            "--session-file" => session_path.replace(value.clone()).is_some(),
+            "--mode" => {
+                let parsed = match value.as_str() {
+                    "l2" => Mode::L2,
+                    "l3" => Mode::L3,
+                    _ => return Err(format!("Unknown mode '{}'", value)),
+                };
+                mode.replace(parsed).is_some()
+            }
+            "--nic" => nic.replace(value.clone()).is_some(),
+            "--tap-helper" => tap_helper.replace(value.clone()).is_some(),
+            "--tap-name" => tap_name.replace(value.clone()).is_some(),
+            /* The tap interface can be given an IPv4 address, and any number of IPv6 ones */
+            "--tap-addr" => {
+                let addr = parse_ip_prefix(value).ok_or_else(|| {
+                    format!("Could not parse '{}' as tap address and prefix", value)
+                })?;
+                tap_addrs.push(addr);
+                false
+            }
+            "--tap-owner" => tap_owner.replace(parse_user(value)?).is_some(),
+            "--tap-group" => tap_group.replace(parse_group(value)?).is_some(),
+            "--mac" => {
+                let mac = value
+                    .parse::<MacAddr>()
+                    .map_err(|_| format!("Could not parse '{}' as MAC", value))?;
+                tap_mac.replace(mac).is_some()
+            }
+            "--mtu" => mtu
+                .replace(parse_overlay_mtu(value).map_err(|e| format!("--mtu: {}", e))?)
+                .is_some(),
+            "--tunnel-mtu" => {
+                let mtu = value
+                    .parse::<u16>()
+                    .map_err(|e| format!("Could not parse '{}' as tunnel MTU: {}", value, e))?;
+                tunnel_mtu.replace(usize::from(mtu)).is_some()
+            }
+            "--proxy" => proxy
+                .replace(Proxy::parse(value).map_err(|e| e.to_string())?)
+                .is_some(),
+            "--proxy-credentials-file" => proxy_credentials_path.replace(value.clone()).is_some(),
+            "--dtls-psk-file" => dtls_psk_path.replace(value.clone()).is_some(),
+            "--auth-psk-file" => auth_psk_path.replace(value.clone()).is_some(),
+            "--quic-ca-file" => quic_ca_path.replace(value.clone()).is_some(),
+            "--keepalive-interval" | "--vswitch-timeout" => {
+                let (duration, name) = match flag.as_str() {
+                    "--keepalive-interval" => (&mut keepalive_interval, "keepalive interval"),
+                    _ => (&mut vswitch_timeout, "vswitch timeout"),
+                };
+                let secs = value
+                    .parse::<u64>()
+                    .map_err(|e| format!("Could not parse '{}' as {}: {}", value, name, e))?;
+                duration.replace(Duration::from_secs(secs)).is_some()
+            }
+            "--vlan" => {
+                let vid = value
+                    .parse::<u16>()
+                    .ok()
+                    .filter(|vid| (1..=4094).contains(vid))
+                    .ok_or_else(|| format!("Could not parse '{}' as VLAN ID", value))?;
+                vlan.replace(vid).is_some()
+            }
+            "--compression" => {
+                let lz4 = match value.as_str() {
+                    "lz4" => true,
+                    "off" => false,
+                    _ => return Err(format!("Unknown compression '{}'", value)),
+                };
+                compression.replace(lz4).is_some()
+            }
+            "--tap-persist"
+            | "--tap-up"
+            | "--fragmentation"
+            | "--path-mtu-discovery"
+            | "--direct-paths"
+            | "--batching"
+            | "--arp-suppression" => {
+                let setting = match flag.as_str() {
+                    "--tap-persist" => &mut tap_persist,
+                    "--tap-up" => &mut tap_up,
+                    "--fragmentation" => &mut fragmentation,
+                    "--path-mtu-discovery" => &mut path_mtu_discovery,
+                    "--direct-paths" => &mut direct_paths,
+                    "--batching" => &mut batching,
+                    _ => &mut arp_suppression,
+                };
+                let on = match value.as_str() {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(format!("{}: Expected 'on' or 'off', not '{}'", flag, value)),
+                };
+                setting.replace(on).is_some()
+            }
+            "--path-mtu-action" => {
+                let action = match value.as_str() {
+                    "clamp" => PathMtuAction::Clamp,
+                    "set-tap-mtu" => PathMtuAction::SetTapMtu,
+                    _ => return Err(format!("Unknown path MTU action '{}'", value)),
+                };
+                path_mtu_action.replace(action).is_some()
+            }
+            "--fec" => {
+                let group = value
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|group| (2..=FEC_GROUP_MAX).contains(group))
+                    .ok_or_else(|| {
+                        format!(
+                            "Could not parse '{}' as FEC group size between 2 and {}",
+                            value, FEC_GROUP_MAX
+                        )
+                    })?;
+                fec_group.replace(group).is_some()
+            }
+            "--dscp" => dscp
+                .replace(Dscp::parse(value).map_err(|e| format!("--dscp: {}", e))?)
+                .is_some(),
+            "--vni" => vni
+                .replace(
+                    parse_vni(value)
+                        .ok_or_else(|| format!("Could not parse '{}' as VNI", value))?,
+                )
+                .is_some(),
+            "--vswitch-timeout-action" => {
+                let action = match value.as_str() {
+                    "log" => TimeoutAction::Log,
+                    "exit" => TimeoutAction::Exit,
+                    _ => return Err(format!("Unknown vswitch timeout action '{}'", value)),
+                };
+                vswitch_timeout_action.replace(action).is_some()
+            }
+            "--vxlan" | "--geneve" => {
+                let vni = match flag.as_str() {
+                    "--vxlan" => &mut vxlan_vni,
+                    _ => &mut geneve_vni,
+                };
+                let value = parse_vni(value)
+                    .ok_or_else(|| format!("Could not parse '{}' as VNI", value))?;
+                vni.replace(value).is_some()
+            }
+            "--l2tp" => {
+                let value = parse_sessions(value)
+                    .ok_or_else(|| format!("Could not parse '{}' as L2TP session IDs", value))?;
+                l2tp_sessions.replace(value).is_some()
+            }
+            "--bum-group" => {
+                let group = value.parse::<SocketAddrV4>().map_err(|e| {
+                    format!("Could not parse '{}' as BUM group address: {}", value, e)
+                })?;
+                bum_group.replace(group).is_some()
+            }
+            /* A further link can be given for each underlay path */
+            "--lag-link" => {
+                let ip = value.parse::<Ipv4Addr>().map_err(|e| {
+                    format!("Could not parse '{}' as LAG link address: {}", value, e)
+                })?;
+                lag_links.push(ip);
+                false
+            }
+            "--bind-addr" => {
+                let ip = value
+                    .parse::<Ipv4Addr>()
+                    .map_err(|e| format!("Could not parse '{}' as bind address: {}", value, e))?;
+                bind_addr.replace(ip).is_some()
+            }
+            "--source-port" => {
+                let port = value
+                    .parse::<u16>()
+                    .ok()
+                    .filter(|port| *port != 0)
+                    .ok_or_else(|| format!("Could not parse '{}' as source port", value))?;
+                source_port.replace(port).is_some()
+            }
+            "--bind-device" => bind_device.replace(value.clone()).is_some(),
+            /* Two servers tell the NAT's type, by whether they see the same endpoint */
+            "--stun-server" => {
+                let (host, port) = value
+                    .rsplit_once(':')
+                    .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+                    .ok_or_else(|| format!("Could not parse '{}' as STUN server", value))?;
+                stun_servers.push((host.to_string(), port));
+                false
+            }
+            /* A filter is one argument, so its words have to be quoted together */
+            "--drop-egress" | "--drop-ingress" => {
+                let words: Vec<&str> = value.split_whitespace().collect();
+                if words.is_empty() {
+                    return Err(format!(
+                        "{} needs a filter, as one would drop every frame",
+                        flag
+                    ));
+                }
+                let filter = Filter::parse(&words).map_err(|e| format!("{}: {}", flag, e))?;
+                if filter.tests_port() {
+                    return Err(format!("{}: Only the vswitch has ports to match", flag));
+                }
+                match flag.as_str() {
+                    "--drop-egress" => drop_egress.push(filter),
+                    _ => drop_ingress.push(filter),
+                }
+                false
+            }
+            "--daemon" => daemon.replace(value.clone()).is_some(),
+            "--log" => log.replace(value.clone()).is_some(),
+            _ => tap_mac.replace(seeded_mac(value)).is_some(),
+        };
+        if replaced {
+            return Err(format!(
+                "'{}' given more than once, or with another MAC option",
+                flag
+            ));
+        }
+
+        args = rest;
+    }
+
+    /* The addresses of backup vswitches follow the first, each after --backup */
+    let mut addrs = args.split(|arg| arg == "--backup");
+    let args = addrs.next().unwrap_or_default();
+    let backup_addrs = addrs
+        .map(|addr| parse_vswitch_addr(addr, &quic_ca_path).map_err(|e| format!("--backup: {}", e)))
+        .collect::<Result<Vec<VswitchAddr>, String>>()?;
+
+    /* The address of a second vswitch follows the first, after --secondary */
+    let (args, secondary_addr) = match args.iter().position(|arg| arg == "--secondary") {
+        Some(index) => (
+            &args[..index],
+            Some(parse_vswitch_addr(&args[index + 1..], &quic_ca_path)?),
+        ),
+        None => (args, None),
+    };
+
+    Ok(Config {
+        session_path,
+        mode,
+        nic,
+        tap_helper,
+        tap_name,
+        tap_persist,
+        tap_owner,
+        tap_group,
+        tap_addrs,
+        tap_up,
+        tap_mac,
+        vswitch_addr: parse_vswitch_addr(args, &quic_ca_path)?,
+        secondary_addr,
+        backup_addrs,
+        mtu,
+        tunnel_mtu,
+        proxy,
+        proxy_credentials_path,
+        bum_group,
+        lag_links,
+        bind_addr,
+        source_port,
+        bind_device,
+        dtls_psk_path,
+        auth_psk_path,
+        vxlan_vni,
+        geneve_vni,
+        l2tp_sessions,
+        quic_ca_path,
+        keepalive_interval,
+        vswitch_timeout,
+        vswitch_timeout_action,
+        vlan,
+        compression,
+        fragmentation,
+        path_mtu_discovery,
+        path_mtu_action,
+        dscp,
+        vni,
+        direct_paths,
+        batching,
+        fec_group,
+        arp_suppression,
+        stun_servers,
+        drop_egress,
+        drop_ingress,
+        daemon,
+        log,
+    })
+}
+
+/// Returns a locally administered unicast MAC derived from seed, which
+/// is the same every time it is derived from the same seed
+///
+/// This uses FNV-1a rather than std's hashers, whose output
+/// may change between Rust releases
+fn seeded_mac(seed: &str) -> MacAddr {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in seed.bytes() {
+        hash ^= u64::from(b);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    let mut mac = [0u8; 6];
+    mac.copy_from_slice(&hash.to_be_bytes()[..6]);
+    mac[0] = (mac[0] & !0x01) | 0x02;
+    MacAddr(mac)
+}
+
+/// Parse the address of the vswitch from the command line arguments,
+/// which is checked against the CAs in quic_ca_path if it is reached over QUIC
+fn parse_vswitch_addr(
+    args: &[String],
+    quic_ca_path: &Option<String>,
+) -> Result<VswitchAddr, String> {
+    match args {
+        [flag, vswitch_cid, vswitch_port] if flag == "--vsock" => {
+            /* Get vswitch CID and vsock port from command line arguments */
+            let cid = vswitch_cid
+                .parse::<u32>()
+                .map_err(|e| format!("Could not parse '{}' as vsock CID: '{}'", vswitch_cid, e))?;
+            let port = vswitch_port.parse::<u32>().map_err(|e| {
+                format!("Could not parse '{}' as vsock port: '{}'", vswitch_port, e)
+            })?;
+
+            Ok(VswitchAddr::Vsock(cid, port))
+        }
+        [flag, vswitch_host, vswitch_port] if flag == "--tcp" => {
+            /* The vswitch host is resolved when connecting, by the proxy if there is one */
+            if vswitch_host.is_empty() {
+                return Err("vswitch host cannot be empty".to_string());
+            }
+            let port = vswitch_port
+                .parse::<u16>()
+                .map_err(|e| format!("Could not parse '{}' as TCP port: '{}'", vswitch_port, e))?;
+
+            Ok(VswitchAddr::Tcp(vswitch_host.clone(), port))
+        }
+        [flag, vswitch_host, vswitch_port] if flag == "--quic" => {
+            if vswitch_host.is_empty() {
+                return Err("vswitch host cannot be empty".to_string());
+            }
+            let port = vswitch_port
+                .parse::<u16>()
+                .map_err(|e| format!("Could not parse '{}' as QUIC port: '{}'", vswitch_port, e))?;
+
+            Ok(VswitchAddr::Quic {
+                host: vswitch_host.clone(),
+                port,
+                ca_path: quic_ca_path.clone(),
+            })
+        }
+        [flag, vswitch_path] if flag == "--unix" => Ok(VswitchAddr::Unix(vswitch_path.clone())),
+        [flag, vswitch_path] if flag == "--shm" => Ok(VswitchAddr::Shm(vswitch_path.clone())),
+        [flag, ..] if flag.starts_with("--") => {
+            Err(format!("Wrong number of arguments for '{}'", flag))
+        }
+        [vswitch_host, vswitch_port] => {
+            /* The vswitch host is resolved when the config is validated */
+            if vswitch_host.is_empty() {
+                return Err("vswitch host cannot be empty".to_string());
+            }
+
+            /* Get port number from command line argument */
+            let port = vswitch_port.parse::<u16>().map_err(|e| {
+                format!("Could not parse '{}' as port number: '{}'", vswitch_port, e)
+            })?;
+
+            Ok(VswitchAddr::Udp(vswitch_host.clone(), port))
+        }
+        _ => Err(format!(
+            "Expected 2 or 3 arguments for the vswitch address and got {}",
+            args.len()
+        )),
+    }
+}
+
+/// Returns every problem with config which would stop the
+/// vport from reaching the vswitch or resuming its session
+fn validate_config(config: &Config) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    errors.extend(validate_vswitch_addr(
+        &config.vswitch_addr,
+        config.proxy.as_ref(),
+    ));
+
+    if let Some(name) = &config.tap_name {
+        if let Err(e) = tap::check_name(name) {
+            errors.push(format!("--tap-name: {}", e));
+        }
+    }
+
+    /* Setting an interface's IPv4 address replaces the one it has */
+    if config
+        .tap_addrs
+        .iter()
+        .filter(|(ip, _)| ip.is_ipv4())
+        .count()
+        > 1
+    {
+        errors.push("--tap-addr can only be given one IPv4 address".to_string());
+    }
+
+    /* The NIC is left as it is, other than being put in promiscuous mode */
+    if config.nic.is_some() {
+        for (flag, given) in [
+            ("--mode l3", config.mode == Some(Mode::L3)),
+            ("--tap-name", config.tap_name.is_some()),
+            ("--tap-persist", config.tap_persist.is_some()),
+            ("--tap-owner", config.tap_owner.is_some()),
+            ("--tap-group", config.tap_group.is_some()),
+            ("--tap-addr", !config.tap_addrs.is_empty()),
+            ("--tap-up", config.tap_up.is_some()),
+            ("--mac and --mac-seed", config.tap_mac.is_some()),
+            (
+                "--path-mtu-action set-tap-mtu",
+                config.path_mtu_action == Some(PathMtuAction::SetTapMtu),
+            ),
+        ] {
+            if given {
+                errors.push(format!("{} can't be given with --nic", flag));
+            }
+        }
+    }
+
+    /*
+     * The helper configures the tap interface, as doing so needs
+     * privileges which the vport it hands the interface to lacks
+     */
+    if let Some(path) = &config.tap_helper {
+        for (flag, given) in [
+            ("--nic", config.nic.is_some()),
+            ("--tap-name", config.tap_name.is_some()),
+            ("--tap-persist", config.tap_persist.is_some()),
+            ("--tap-owner", config.tap_owner.is_some()),
+            ("--tap-group", config.tap_group.is_some()),
+            ("--tap-addr", !config.tap_addrs.is_empty()),
+            ("--tap-up", config.tap_up.is_some()),
+            ("--mac and --mac-seed", config.tap_mac.is_some()),
+            (
+                "--path-mtu-action set-tap-mtu",
+                config.path_mtu_action == Some(PathMtuAction::SetTapMtu),
+            ),
+        ] {
+            if given {
+                errors.push(format!("{} can't be given with --tap-helper", flag));
+            }
+        }
+        if !Path::new(path).exists() {
+            errors.push(format!("--tap-helper socket {} doesn't exist", path));
+        }
+    }
+
+    /* tun interfaces have no MAC to give them */
+    if config.mode == Some(Mode::L3) && config.tap_mac.is_some() {
+        errors.push("--mac and --mac-seed can't be given with --mode l3".to_string());
+    }
+    /* Hosts send no ARP through a tun interface */
+    if config.mode == Some(Mode::L3) && config.arp_suppression == Some(true) {
+        errors.push("--arp-suppression can't be given with --mode l3".to_string());
+    }
+
+    /*
+     * wintun has no tap interfaces, and its adapters have no owners and
+     * don't persist, and Windows has no Unix sockets to be handed one
+     * over, or fork to detach a daemon with
+     */
+    #[cfg(windows)]
+    {
+        if config.mode != Some(Mode::L3) {
+            errors.push("The vport only runs in L3 mode (--mode l3) on Windows".to_string());
+        }
+        for (flag, given) in [
+            ("--tap-persist", config.tap_persist.is_some()),
+            ("--tap-owner", config.tap_owner.is_some()),
+            ("--tap-group", config.tap_group.is_some()),
+            ("--tap-helper", config.tap_helper.is_some()),
+            ("--daemon", config.daemon.is_some()),
+            ("--log", config.log.is_some()),
+        ] {
+            if given {
+                errors.push(format!("{} isn't supported on Windows", flag));
+            }
+        }
+    }
+
+    /* Hosts can't send from group MACs, or the all-zeroes MAC */
+    if let Some(mac) = config.tap_mac {
+        if mac.is_multicast() || mac == MacAddr::default() {
+            errors.push(format!(
+                "--mac {} is not a unicast MAC which the tap interface can use",
+                mac
+            ));
+        }
+    }
+    /* DTLS, QUIC, tags, FEC and VNI, VXLAN or GENEVE headers take some of the tunnel MTU for themselves */
+    let encap = encap(config.vxlan_vni, config.geneve_vni, config.l2tp_sessions);
+    let overhead = tunnel_overhead(
+        config.dtls_psk_path.is_some(),
+        config.auth_psk_path.is_some(),
+        is_quic(vswitch_addrs(config)),
+        config.vni.is_some(),
+        config.fec_group.is_some(),
+        encap.as_ref(),
+    );
+    if config
+        .tunnel_mtu
+        .is_some_and(|mtu| mtu < MIN_TUNNEL_MTU + overhead)
+    {
+        errors.push(format!(
+            "--tunnel-mtu must be at least {}",
+            MIN_TUNNEL_MTU + overhead
+        ));
+    }
+
+    /* Larger frames would be fragmented by the underlay, or lost, unless we fragment them */
+    if let (Some(mtu), Some(tunnel_mtu)) = (config.mtu, config.tunnel_mtu) {
+        if tunnel_mtu < tunnel_mtu_needed(mtu) + overhead && config.fragmentation != Some(true) {
+            errors.push(format!(
+                "--mtu {} needs a --tunnel-mtu of at least {} to carry its frames, not {}",
+                mtu,
+                tunnel_mtu_needed(mtu) + overhead,
+                tunnel_mtu
+            ));
+        }
+    }
+    if let Some(secondary_addr) = &config.secondary_addr {
+        errors.extend(
+            validate_vswitch_addr(secondary_addr, config.proxy.as_ref())
+                .into_iter()
+                .map(|e| format!("--secondary: {}", e)),
+        );
+    }
+    for backup_addr in &config.backup_addrs {
+        errors.extend(
+            validate_vswitch_addr(backup_addr, config.proxy.as_ref())
+                .into_iter()
+                .map(|e| format!("--backup: {}", e)),
+        );
+    }
+    if !config.backup_addrs.is_empty() {
+        /* Frames go to one vswitch at a time, rather than to both */
+        if config.secondary_addr.is_some() {
+            errors.push("--backup can't be given with --secondary".to_string());
+        }
+        /* Which are further links to, and a group flooded by, the first vswitch only */
+        if !config.lag_links.is_empty() {
+            errors.push("--backup can't be given with --lag-link".to_string());
+        }
+        if config.bum_group.is_some() {
+            errors.push("--backup can't be given with --bum-group".to_string());
+        }
+    }
+
+    /* Only TCP links can go through the proxy */
+    let is_tcp = |addr: &VswitchAddr| matches!(addr, VswitchAddr::Tcp(..));
+    if config.proxy.is_some() && !vswitch_addrs(config).any(is_tcp) {
+        errors.push("--proxy given, but no vswitch is reached over --tcp".to_string());
+    }
+    if let Some(group) = config.bum_group {
+        if !group.ip().is_multicast() || group.port() == 0 {
+            errors.push(format!(
+                "--bum-group '{}' is not a multicast group address and port",
+                group
+            ));
+        }
+        if !matches!(config.vswitch_addr, VswitchAddr::Udp(..)) {
+            errors.push("--bum-group needs the vswitch to be reached over UDP".to_string());
+        }
+    }
+    if !config.lag_links.is_empty() && !matches!(config.vswitch_addr, VswitchAddr::Udp(..)) {
+        errors.push("--lag-link needs the vswitch to be reached over UDP".to_string());
+    }
+    for (index, ip) in config.lag_links.iter().enumerate() {
+        if config.lag_links[..index].contains(ip) {
+            errors.push(format!("--lag-link {} given more than once", ip));
+        } else if let Err(e) = UdpSocket::bind((*ip, 0)) {
+            errors.push(format!("--lag-link {} can't be bound: {}", ip, e));
+        }
+    }
+    for (flag, given) in [
+        ("--bind-addr", config.bind_addr.is_some()),
+        ("--source-port", config.source_port.is_some()),
+        ("--bind-device", config.bind_device.is_some()),
+    ] {
+        if !given {
+            continue;
+        }
+        if !matches!(config.vswitch_addr, VswitchAddr::Udp(..)) {
+            errors.push(format!("{} needs the vswitch to be reached over UDP", flag));
+        }
+        /* LAG links are bound to addresses of their own, to take their own paths */
+        if !config.lag_links.is_empty() {
+            errors.push(format!("{} can't be given with --lag-link", flag));
+        }
+    }
+    let binding = UnderlayBinding {
+        addr: config.bind_addr,
+        port: None,
+        device: config.bind_device.clone(),
+    };
+    if binding != UnderlayBinding::default() {
+        if let Err(e) = binding.bind(0) {
+            errors.push(format!("Sockets can't be bound to {}: {}", binding, e));
+        }
+    }
+    if let Some(path) = &config.dtls_psk_path {
+        if let Err(e) = read_dtls_psk(path) {
+            errors.push(e);
+        }
+
+        /* Only links over UDP are carried in DTLS sessions */
+        let is_udp = |addr: &VswitchAddr| matches!(addr, VswitchAddr::Udp(..));
+        if !vswitch_addrs(config).all(is_udp) {
+            errors.push("--dtls-psk-file needs the vswitches to be reached over UDP".to_string());
+        }
+        if config.bum_group.is_some() {
+            errors.push("--dtls-psk-file can't be given with --bum-group".to_string());
+        }
+        if !config.lag_links.is_empty() {
+            errors.push("--dtls-psk-file can't be given with --lag-link".to_string());
+        }
+        if config.auth_psk_path.is_some() {
+            errors.push("--dtls-psk-file can't be given with --auth-psk-file".to_string());
+        }
+    }
+    if let Some(path) = &config.auth_psk_path {
+        if let Err(e) = read_auth_psk(path) {
+            errors.push(e);
+        }
+
+        /* Only frames sent over UDP are signed */
+        let is_udp = |addr: &VswitchAddr| matches!(addr, VswitchAddr::Udp(..));
+        if !vswitch_addrs(config).all(is_udp) {
+            errors.push("--auth-psk-file needs the vswitches to be reached over UDP".to_string());
+        }
+        if config.bum_group.is_some() {
+            errors.push("--auth-psk-file can't be given with --bum-group".to_string());
+        }
+    }
+    if config.fragmentation == Some(true) {
+        if config.tunnel_mtu.is_none() {
+            errors.push(
+                "--fragmentation needs --tunnel-mtu, which frames are fragmented to fit"
+                    .to_string(),
+            );
+        }
+
+        /* Only frames sent over UDP are fragmented */
+        let is_udp = |addr: &VswitchAddr| matches!(addr, VswitchAddr::Udp(..));
+        if !vswitch_addrs(config).all(is_udp) {
+            errors.push("--fragmentation needs the vswitches to be reached over UDP".to_string());
+        }
+
+        /* Frames flooded through the group are sent whole */
+        if config.bum_group.is_some() {
+            errors.push("--fragmentation can't be given with --bum-group".to_string());
+        }
+    }
+    if config.path_mtu_discovery == Some(true) {
+        /* Probes are sent over UDP, with the don't fragment flag set */
+        let is_udp = |addr: &VswitchAddr| matches!(addr, VswitchAddr::Udp(..));
+        if !vswitch_addrs(config).all(is_udp) {
+            errors.push(
+                "--path-mtu-discovery needs the vswitches to be reached over UDP".to_string(),
+            );
+        }
+
+        /* The further links may cross other paths, which aren't probed */
+        if !config.lag_links.is_empty() {
+            errors.push("--path-mtu-discovery can't be given with --lag-link".to_string());
+        }
+        if config.dtls_psk_path.is_some() {
+            errors.push("--path-mtu-discovery can't be given with --dtls-psk-file".to_string());
+        }
+        /* Frames are fitted to the path, rather than fragmented */
+        if config.fragmentation == Some(true) {
+            errors.push("--path-mtu-discovery can't be given with --fragmentation".to_string());
+        }
+    } else if config.path_mtu_action.is_some() {
+        errors.push("--path-mtu-action given without --path-mtu-discovery on".to_string());
+    }
+    if config.dscp.is_some() {
+        /* Only datagrams sent over UDP are marked */
+        let is_udp = |addr: &VswitchAddr| matches!(addr, VswitchAddr::Udp(..));
+        if !vswitch_addrs(config).all(is_udp) {
+            errors.push("--dscp needs the vswitches to be reached over UDP".to_string());
+        }
+        if config.dtls_psk_path.is_some() {
+            errors.push("--dscp can't be given with --dtls-psk-file".to_string());
+        }
+    }
+    if config.vni.is_some() {
+        /* Only the vswitch's UDP port multiplexes VNIs */
+        let is_udp = |addr: &VswitchAddr| matches!(addr, VswitchAddr::Udp(..));
+        if !vswitch_addrs(config).all(is_udp) {
+            errors.push("--vni needs the vswitches to be reached over UDP".to_string());
+        }
+        if config.dtls_psk_path.is_some() {
+            errors.push("--vni can't be given with --dtls-psk-file".to_string());
+        }
+        /* The BUM group only carries the frames of segment 0 */
+        if config.bum_group.is_some() {
+            errors.push("--vni can't be given with --bum-group".to_string());
+        }
+    }
+    if config.direct_paths == Some(true) {
+        /* Punches and frames to other vports leave from the socket which reaches the vswitch */
+        if !matches!(config.vswitch_addr, VswitchAddr::Udp(..)) {
+            errors.push("--direct-paths needs the vswitch to be reached over UDP".to_string());
+        }
+        /* Which is the only link other vports are told of */
+        if config.secondary_addr.is_some() {
+            errors.push("--direct-paths can't be given with --secondary".to_string());
+        }
+        if !config.backup_addrs.is_empty() {
+            errors.push("--direct-paths can't be given with --backup".to_string());
+        }
+        if !config.lag_links.is_empty() {
+            errors.push("--direct-paths can't be given with --lag-link".to_string());
+        }
+        /* Other vports have no DTLS session with us */
+        if config.dtls_psk_path.is_some() {
+            errors.push("--direct-paths can't be given with --dtls-psk-file".to_string());
+        }
+    }
+    if config.batching == Some(true) {
+        /* Only datagrams are batched */
+        if !matches!(config.vswitch_addr, VswitchAddr::Udp(..)) {
+            errors.push("--batching needs the vswitch to be reached over UDP".to_string());
+        }
+        /* Frames are batched for the one link they are sent over */
+        if config.secondary_addr.is_some() {
+            errors.push("--batching can't be given with --secondary".to_string());
+        }
+        if !config.backup_addrs.is_empty() {
+            errors.push("--batching can't be given with --backup".to_string());
+        }
+        if !config.lag_links.is_empty() {
+            errors.push("--batching can't be given with --lag-link".to_string());
+        }
+    }
+    if config.fec_group.is_some() {
+        /* Only datagrams are protected, and lost */
+        if !matches!(config.vswitch_addr, VswitchAddr::Udp(..)) {
+            errors.push("--fec needs the vswitch to be reached over UDP".to_string());
+        }
+        /* Groups are of the datagrams sent over one link */
+        if config.secondary_addr.is_some() {
+            errors.push("--fec can't be given with --secondary".to_string());
+        }
+        if !config.backup_addrs.is_empty() {
+            errors.push("--fec can't be given with --backup".to_string());
+        }
+        if !config.lag_links.is_empty() {
+            errors.push("--fec can't be given with --lag-link".to_string());
+        }
+    }
+    if !config.stun_servers.is_empty() {
+        /* The servers are asked from the socket which reaches the vswitch */
+        if !matches!(config.vswitch_addr, VswitchAddr::Udp(..)) {
+            errors.push("--stun-server needs the vswitch to be reached over UDP".to_string());
+        }
+        if config.dtls_psk_path.is_some() {
+            errors.push("--stun-server can't be given with --dtls-psk-file".to_string());
+        }
+    }
+    let encap_flags = [
+        ("--vxlan", config.vxlan_vni.is_some()),
+        ("--geneve", config.geneve_vni.is_some()),
+        ("--l2tp", config.l2tp_sessions.is_some()),
+    ];
+    let encap_flags: Vec<&str> = encap_flags
+        .iter()
+        .filter_map(|(flag, given)| given.then_some(*flag))
+        .collect();
+    for flag in &encap_flags {
+        if !matches!(config.vswitch_addr, VswitchAddr::Udp(..)) {
+            errors.push(format!("{} needs the vswitch to be reached over UDP", flag));
+        }
+        /* Every link would have to receive on the vswitch's port */
+        if config.secondary_addr.is_some() {
+            errors.push(format!("{} can't be given with --secondary", flag));
+        }
+        if !config.backup_addrs.is_empty() {
+            errors.push(format!("{} can't be given with --backup", flag));
+        }
+        if !config.lag_links.is_empty() {
+            errors.push(format!("{} can't be given with --lag-link", flag));
+        }
+        if config.bum_group.is_some() {
+            errors.push(format!("{} can't be given with --bum-group", flag));
+        }
+        if config.source_port.is_some() {
+            errors.push(format!("{} can't be given with --source-port", flag));
+        }
+        /* VTEPs and L2TP peers send no echo requests to be heard from by */
+        if config.vswitch_timeout.is_some() {
+            errors.push(format!("{} can't be given with --vswitch-timeout", flag));
+        }
+        if config.vswitch_timeout_action.is_some() {
+            errors.push(format!(
+                "{} can't be given with --vswitch-timeout-action",
+                flag
+            ));
+        }
+        /* Nor do they answer joins, or have VLANs to put the vport in */
+        if config.vlan.is_some() {
+            errors.push(format!("{} can't be given with --vlan", flag));
+        }
+        if config.compression == Some(true) {
+            errors.push(format!("{} can't be given with --compression", flag));
+        }
+        if config.fragmentation == Some(true) {
+            errors.push(format!("{} can't be given with --fragmentation", flag));
+        }
+        if config.direct_paths == Some(true) {
+            errors.push(format!("{} can't be given with --direct-paths", flag));
+        }
+        if config.batching == Some(true) {
+            errors.push(format!("{} can't be given with --batching", flag));
+        }
+        if config.fec_group.is_some() {
+            errors.push(format!("{} can't be given with --fec", flag));
+        }
+        if !config.stun_servers.is_empty() {
+            errors.push(format!("{} can't be given with --stun-server", flag));
+        }
+        /* Nor answer path MTU probes */
+        if config.path_mtu_discovery == Some(true) {
+            errors.push(format!("{} can't be given with --path-mtu-discovery", flag));
+        }
+        /* VTEPs and L2TP peers can't take part in DTLS sessions or sign their datagrams */
+        if config.dtls_psk_path.is_some() {
+            errors.push(format!("{} can't be given with --dtls-psk-file", flag));
+        }
+        if config.auth_psk_path.is_some() {
+            errors.push(format!("{} can't be given with --auth-psk-file", flag));
+        }
+        /* Which carry a VNI of their own */
+        if config.vni.is_some() {
+            errors.push(format!("{} can't be given with --vni", flag));
+        }
+    }
+    if encap_flags.len() > 1 {
+        errors.push(format!(
+            "{} can't be given together",
+            encap_flags.join(", ")
+        ));
+    }
+    if config
+        .keepalive_interval
+        .is_some_and(|interval| interval.is_zero() || interval >= VSWITCH_PORT_TIMEOUT)
+    {
+        errors.push(format!(
+            "--keepalive-interval must be between 1 and {} seconds, as the vswitch considers vports down after {} seconds without one",
+            VSWITCH_PORT_TIMEOUT.as_secs() - 1,
+            VSWITCH_PORT_TIMEOUT.as_secs()
+        ));
+    }
+    if config
+        .vswitch_timeout
+        .is_some_and(|timeout| timeout < 2 * ECHO_INTERVAL)
+    {
+        errors.push(format!(
+            "--vswitch-timeout must be at least {} seconds, as vswitches send echo requests every {}",
+            (2 * ECHO_INTERVAL).as_secs(),
+            ECHO_INTERVAL.as_secs()
+        ));
+    }
+    if config.quic_ca_path.is_some() && !is_quic(vswitch_addrs(config)) {
+        errors.push("--quic-ca-file given, but no vswitch is reached over --quic".to_string());
+    }
+    if let Some(path) = &config.proxy_credentials_path {
+        if config.proxy.is_none() {
+            errors.push("--proxy-credentials-file given without --proxy".to_string());
+        }
+        if let Err(e) = read_proxy_credentials(path) {
+            errors.push(e);
+        }
+    }
+
+    if let Some(path) = &config.session_path {
+        match fs::read_to_string(path) {
+            Ok(contents) => {
+                if let Err(e) = parse_session(&contents) {
+                    errors.push(format!(
+                        "--session-file '{}' does not hold a session: {}",
+                        path, e
+                    ));
+                }
+            }
+            /* A new session ID is saved if the file doesn't exist yet */
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                if let Err(e) = check_parent_dir(path) {
+                    errors.push(format!("--session-file {}", e));
+                }
+            }
+            Err(e) => errors.push(format!("--session-file '{}': {}", path, e)),
+        }
+    }
+
+    let log_path = config.log.as_deref().filter(|log| *log != "syslog");
+    for (flag, path) in [("--daemon", config.daemon.as_deref()), ("--log", log_path)] {
+        if let Some(Err(e)) = path.map(check_parent_dir) {
+            errors.push(format!("{} {}", flag, e));
+        }
+    }
+
+    errors
+}
+
+/// Returns every problem with the address of a vswitch,
+/// which is reached through proxy if it is given
+fn validate_vswitch_addr(vswitch_addr: &VswitchAddr, proxy: Option<&Proxy>) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    match vswitch_addr {
+        VswitchAddr::Udp(host, port) => {
+            match host.parse::<Ipv4Addr>() {
+                Ok(ip) if ip.is_unspecified() || ip.is_broadcast() || ip.is_multicast() => {
+                    errors.push(format!("{} is not the address of a single vswitch", ip));
+                }
+                Ok(_) => {}
+                Err(_) => {
+                    if let Err(e) = resolve(host, *port) {
+                        errors.push(format!("Could not resolve vswitch host '{}': {}", host, e));
+                    }
+                }
+            }
+            if *port == 0 {
+                errors.push("vswitch port cannot be 0".to_string());
+            }
+        }
+        VswitchAddr::Vsock(cid, port) => {
+            /* VMADDR_CID_ANY and VMADDR_PORT_ANY can only be bound to, not connected to */
+            if *cid == u32::MAX {
+                errors.push(format!("vsock CID {} is not the CID of a vswitch", cid));
+            }
+            if *port == u32::MAX {
+                errors.push(format!("vsock port {} is not the port of a vswitch", port));
+            }
+        }
+        VswitchAddr::Tcp(host, port) => {
+            /* The proxy resolves the host, which we may not be able to */
+            if proxy.is_none() {
+                if let Err(e) = (host.as_str(), *port).to_socket_addrs() {
+                    errors.push(format!("Could not resolve vswitch host '{}': {}", host, e));
+                }
+            }
+            if *port == 0 {
+                errors.push("vswitch TCP port cannot be 0".to_string());
+            }
+        }
+        VswitchAddr::Quic {
+            host,
+            port,
+            ca_path,
+        } => {
+            if let Err(e) = (host.as_str(), *port).to_socket_addrs() {
+                errors.push(format!("Could not resolve vswitch host '{}': {}", host, e));
+            }
+            if *port == 0 {
+                errors.push("vswitch QUIC port cannot be 0".to_string());
+            }
+            match ca_path {
+                Some(path) => {
+                    if let Err(e) = quic_client_config(path) {
+                        errors.push(e.to_string());
+                    }
+                }
+                None => errors.push("--quic needs --quic-ca-file".to_string()),
+            }
+        }
+        VswitchAddr::Unix(path) | VswitchAddr::Shm(path) => {
+            if let Err(e) = check_parent_dir(path) {
+                errors.push(format!("vswitch socket {}", e));
+            }
+        }
+    }
+
+    errors
+}
+
+/// Returns the ID of the user called value, or with the ID value
+fn parse_user(value: &str) -> Result<u32, String> {
+    if let Ok(uid) = value.parse() {
+        return Ok(uid);
+    }
+    #[cfg(unix)]
+    return User::from_name(value)
+        .map_err(|e| format!("Could not look up user '{}': {}", value, e))?
+        .map(|user| user.uid.as_raw())
+        .ok_or_else(|| format!("No user called '{}'", value));
+    #[cfg(windows)]
+    Err(format!("No user with the ID '{}'", value))
+}
+
+/// Returns the ID of the group called value, or with the ID value
+fn parse_group(value: &str) -> Result<u32, String> {
+    if let Ok(gid) = value.parse() {
+        return Ok(gid);
+    }
+    #[cfg(unix)]
+    return Group::from_name(value)
+        .map_err(|e| format!("Could not look up group '{}': {}", value, e))?
+        .map(|group| group.gid.as_raw())
+        .ok_or_else(|| format!("No group called '{}'", value));
+    #[cfg(windows)]
+    Err(format!("No group with the ID '{}'", value))
+}
+
+/// Returns an error if the directory which would contain path doesn't exist
+fn check_parent_dir(path: &str) -> Result<(), String> {
+    match Path::new(path).parent() {
+        Some(dir) if dir.as_os_str().is_empty() || dir.is_dir() => Ok(()),
+        Some(dir) => Err(format!(
+            "'{}': directory '{}' does not exist",
+            path,
+            dir.display()
+        )),
+        None => Err(format!("'{}' is not a path to a file", path)),
+    }
+}
+
+/// Returns the proxy user name and password kept in the file at path
+fn read_proxy_credentials(path: &str) -> Result<(String, String), String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("--proxy-credentials-file '{}': {}", path, e))?;
+    proxy::parse_credentials(&contents).map_err(|e| {
+        format!(
+            "--proxy-credentials-file '{}' does not hold credentials: {}",
+            path, e
+        )
+    })
+}
+
+/// Returns the encapsulation given by --vxlan, --geneve or --l2tp, if any
+fn encap(
+    vxlan_vni: Option<u32>,
+    geneve_vni: Option<u32>,
+    l2tp_sessions: Option<(u32, u32)>,
+) -> Option<Encap> {
+    let l2tp = |(session_id, peer_session_id)| Encap::L2tp {
+        session_id,
+        peer_session_id,
+    };
+    vxlan_vni
+        .map(Encap::Vxlan)
+        .or(geneve_vni.map(Encap::Geneve))
+        .or(l2tp_sessions.map(l2tp))
+}
+
+/// Returns the number of bytes of the tunnel MTU which DTLS, the tags
+/// authenticating frames, VNI headers or VXLAN, GENEVE or L2TP headers
+/// take, if the vport uses them
+fn tunnel_overhead(
+    dtls: bool,
+    auth: bool,
+    quic: bool,
+    vni: bool,
+    fec: bool,
+    encap: Option<&Encap>,
+) -> usize {
+    let security = match (dtls, auth, quic) {
+        (true, _, _) => DTLS_OVERHEAD,
+        (false, true, _) => AUTH_OVERHEAD,
+        (false, false, true) => QUIC_OVERHEAD,
+        (false, false, false) => 0,
+    };
+    let vni = match vni {
+        true => VNI_HDR_LEN,
+        false => 0,
+    };
+    let fec = match fec {
+        true => FEC_OVERHEAD,
+        false => 0,
+    };
+    security + vni + fec + encap.map_or(0, Encap::overhead)
+}
+
+/// Whether any of the vswitches at addrs is reached over QUIC
+fn is_quic<'a>(mut addrs: impl Iterator<Item = &'a VswitchAddr>) -> bool {
+    addrs.any(|addr| matches!(addr, VswitchAddr::Quic { .. }))
+}
+
+/// Returns the addresses of the vswitches in config, the first one first
+fn vswitch_addrs(config: &Config) -> impl Iterator<Item = &VswitchAddr> {
+    iter::once(&config.vswitch_addr)
+        .chain(&config.secondary_addr)
+        .chain(&config.backup_addrs)
+}
+
+/// Returns the pre-shared key kept in the file at path, given with flag
+fn read_psk(flag: &str, path: &str) -> Result<Vec<u8>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("{} '{}': {}", flag, path, e))?;
+    auth::parse_psk(&contents).map_err(|e| format!("{} '{}': {}", flag, path, e))
+}
+
+/// Returns the pre-shared key for frame authentication kept in the file at path
+fn read_auth_psk(path: &str) -> Result<Vec<u8>, String> {
+    read_psk("--auth-psk-file", path)
+}
+
+/// Returns the signer of our datagrams with the pre-shared key for
+/// frame authentication kept in the file at path, under a random sender ID
+fn frame_auth(path: &str) -> Result<FrameAuth, String> {
+    let psk = read_auth_psk(path)?;
+    let sender_id = random_u64().map_err(|e| format!("Could not pick a sender ID: {}", e))?;
+    Ok(FrameAuth::new(&psk, sender_id, SystemTime::now()))
+}
+
+/// Returns the pre-shared key for DTLS kept in the file at path
+#[cfg(feature = "dtls")]
+fn read_dtls_psk(path: &str) -> Result<Vec<u8>, String> {
+    read_psk("--dtls-psk-file", path)
+}
+
+/// DTLS can't be used without the dtls feature
+#[cfg(not(feature = "dtls"))]
+fn read_dtls_psk(_path: &str) -> Result<Vec<u8>, String> {
+    Err("--dtls-psk-file given, but vport was built without the dtls feature".to_string())
+}
+
+/// Returns the QUIC configuration trusting the vswitch
+/// certificates issued by the CAs in the file at path
+#[cfg(feature = "quic")]
+fn quic_client_config(path: &str) -> Result<quinn_proto::ClientConfig, Error> {
+    quic::client_config(path)
+        .map_err(|e| TransportError::Quic(format!("--quic-ca-file '{}': {}", path, e)).into())
+}
+
+/// QUIC can't be used without the quic feature
+#[cfg(not(feature = "quic"))]
+fn quic_client_config(_path: &str) -> Result<(), Error> {
+    Err(
+        ConfigError::Unsupported("--quic given, but vport was built without the quic feature")
+            .into(),
+    )
+}
+
+/// Parse and validate the configuration in args, printing every
+/// problem found, without creating the tap interface or
+/// contacting the vswitch (its host name is still resolved)
+fn check_config(args: &[String]) -> ExitCode {
+    let config = match parse_config(args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("{}", USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let errors = validate_config(&config);
+    if !errors.is_empty() {
+        for e in errors.iter() {
+            eprintln!("{}", e);
+        }
+        eprintln!("Configuration has {} error(s)", errors.len());
+        return ExitCode::FAILURE;
+    }
+
+    println!("Configuration OK");
+    ExitCode::SUCCESS
+}
+
+/// Returns the session saved in the file at session_path,
+/// generating and saving a new one if the file doesn't exist
+///
+/// Without a session file, a new session is generated for
+/// every run, so the vswitch treats the vport as a new endpoint
+fn get_session(session_path: Option<&str>) -> Result<Session, Error> {
+    let mut session = None;
+    if let Some(path) = session_path {
+        match fs::read_to_string(path) {
+            Ok(contents) => {
+                let (id, token) =
+                    parse_session(&contents).map_err(|reason| ConfigError::Session {
+                        path: path.to_string(),
+                        reason,
+                    })?;
+
+                /* Session files written before tokens were added only hold the ID */
+                if let Some(token) = token {
+                    return Ok(Session { id, token });
+                }
+                session = Some(Session {
+                    id,
+                    token: random_u64()?,
+                });
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let session = match session {
+        Some(session) => session,
+        None => Session {
+            id: random_u64()?,
+            token: random_u64()?,
+        },
+    };
+
+    /* The token is a secret, so new session files can only be read by us */
+    if let Some(path) = session_path {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        options
+            .open(path)?
+            .write_all(format!("{:016x} {:016x}\n", session.id, session.token).as_bytes())?;
+    }
+
+    Ok(session)
+}
+
+/// Parse the contents of a session file into the session
+/// ID and, if the file has one, the session's token
+fn parse_session(contents: &str) -> Result<(u64, Option<u64>), String> {
+    let fields: Vec<&str> = contents.split_whitespace().collect();
+    let parse = |field: &str| u64::from_str_radix(field, 16).map_err(|e| e.to_string());
+
+    match fields.as_slice() {
+        [id] => Ok((parse(id)?, None)),
+        [id, token] => Ok((parse(id)?, Some(parse(token)?))),
+        _ => Err(format!("expected 1 or 2 fields, got {}", fields.len())),
+    }
+}
+
+/// Returns a random number read from /dev/urandom
+fn random_u64() -> io::Result<u64> {
+    let mut random = [0u8; 8];
+    File::open("/dev/urandom")?.read_exact(&mut random)?;
+    Ok(u64::from_ne_bytes(random))
+}
+
+/// Send the hellos which register us with the vswitch every
+/// interval, so it knows which session we belong to even if it
+/// has restarted, or we have moved, since we started, and knows
+/// we are still there
+///
+/// If join is given, with the name of the vswitch, its frame is sent
+/// before the hellos until the vswitch's status says it has answered it,
+/// and again whenever that is unset, as the vswitch stopped being heard
+/// from. A vswitch which doesn't answer is sent it again with backoff,
+/// from RECONNECT_DELAY_MIN up to RECONNECT_DELAY_MAX
+fn send_hellos(
+    link: &VswitchLink,
+    hellos: &[Vec<u8>],
+    join: Option<(&[u8], &VswitchStatus, &str)>,
+    interval: Duration,
+) {
+    let mut timer = Interval::new(interval);
+    let mut backoff = Backoff::new(RECONNECT_DELAY_MIN, RECONNECT_DELAY_MAX);
+    let mut state = JoinState::Joining;
+
+    /* Returns false if the link has failed for good */
+    let send = |frame: &[u8]| match link.send(frame) {
+        Ok(_) => true,
+        Err(e) => {
+            eprintln!("Got error while sending hello to vswitch: '{}'", e);
+
+            /* The vswitch or the network may only be down for now */
+            e.is_transient()
+        }
+    };
+
+    loop {
+        let now = Instant::now();
+        let mut wait = timer.remaining(now);
+
+        if let Some((join, status, name)) = join {
+            let joined = status.joined.load(Ordering::Relaxed);
+            match (state, joined) {
+                (JoinState::Joined, false) => {
+                    println!("Rejoining the {}", name);
+                    state = JoinState::Rejoining;
+                    backoff.reset();
+                }
+                (JoinState::Joining | JoinState::Rejoining, true) => {
+                    if state == JoinState::Rejoining {
+                        println!(
+                            "Rejoined the {} after {} attempt(s)",
+                            name,
+                            backoff.attempts()
+                        );
+                    }
+                    state = JoinState::Joined;
+
+                    /* Register our session with the vswitch now, rather than once the interval is up */
+                    timer = Interval::new(interval);
+                }
+                _ => {}
+            }
+
+            if !joined && backoff.due(now) {
+                if !send(join) {
+                    return;
+                }
+                if backoff.attempts() > 1 && !status.joined.load(Ordering::Relaxed) {
+                    println!(
+                        "The {} hasn't answered our join yet, so sent it again, and will again in {} seconds",
+                        name,
+                        backoff.remaining(now).as_secs()
+                    );
+                }
+            }
+
+            /* Notice soon after the vswitch stops being heard from, or answers */
+            wait = wait.min(match joined {
+                true => LIVENESS_CHECK_INTERVAL,
+                false => backoff.remaining(now),
+            });
+        }
+
+        if timer.due(now) {
+            for hello in hellos {
+                if !send(hello) {
+                    return;
+                }
+            }
+            wait = timer.remaining(now).min(wait);
+        }
+
+        thread::sleep(wait);
+    }
+}
+
+/// Log each vswitch (the first, then the second or the backups, in
+/// vswitches) which stops being heard from, and is then heard from again,
+/// until the first one stops being heard from when action is Exit, which
+/// stops the vport. If failover, it is only stopped once none of them
+/// is joined, as frames go through the backups meanwhile
+///
+/// A vswitch which stops being heard from may be restarting, and forget
+/// our join, so it is joined again, and has to agree to what it agreed
+/// to before again
+fn watch_vswitches(
+    vswitches: &[Arc<VswitchStatus>],
+    action: TimeoutAction,
+    failover: bool,
+    stopped_tx: &mpsc::Sender<Stop>,
+) {
+    loop {
+        thread::sleep(LIVENESS_CHECK_INTERVAL);
+
+        let now = Instant::now();
+        for (index, status) in vswitches.iter().enumerate() {
+            let name = &status.name;
+            let mut liveness = status
+                .liveness
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            match liveness.check(now) {
+                Some(false) => {
+                    eprintln!(
+                        "The {} hasn't been heard from for {} seconds, so may be down",
+                        name,
+                        liveness.silent_for(now).as_secs()
+                    );
+                    status.unjoin();
+                    let stranded = match failover {
+                        true => !vswitches.iter().any(|s| s.joined.load(Ordering::Relaxed)),
+                        false => index == 0,
+                    };
+                    if stranded && action == TimeoutAction::Exit {
+                        let _ = stopped_tx.send(Stop::VswitchTimeout);
+                        return;
+                    }
+                    notify_systemd(&format!("STATUS=Rejoining the {}", name));
+                }
+                Some(true) => println!("The {} is being heard from again", name),
+                None => {}
+            }
+        }
+    }
+}
+
+/// Send frames to, and take them from, the first of vswitches (the first,
+/// then the backups, in the order given) which has answered our join,
+/// which active says the index of, checking every LIVENESS_CHECK_INTERVAL,
+/// so frames fail over to a backup once the vswitch in use stops being
+/// heard from, and fail back once an earlier one is joined again
+///
+/// The vswitch failed over from is sent leave over its link in links, so
+/// it flushes our MACs rather than sending it frames for our hosts, and is
+/// then joined again, which it may be once it is heard from again
+fn fail_over(
+    links: &[VswitchLink],
+    leave: &[u8],
+    vswitches: &[Arc<VswitchStatus>],
+    active: &AtomicUsize,
+) {
+    loop {
+        thread::sleep(LIVENESS_CHECK_INTERVAL);
+
+        let from = active.load(Ordering::Relaxed);
+        let Some(to) = vswitches
+            .iter()
+            .position(|status| status.joined.load(Ordering::Relaxed))
+            .filter(|to| *to != from)
+        else {
+            continue;
+        };
+        active.store(to, Ordering::Relaxed);
+        match to < from {
+            true => println!(
+                "Failed back from the {} to the {}",
+                vswitches[from].name, vswitches[to].name
+            ),
+            false => println!(
+                "Failed over from the {} to the {}",
+                vswitches[from].name, vswitches[to].name
+            ),
+        }
+        notify_systemd(&format!(
+            "STATUS=Sending frames through the {}",
+            vswitches[to].name
+        ));
+
+        /* The vswitch may be down, in which case it forgot us anyway */
+        if let Err(e) = links[from].send(leave) {
+            eprintln!(
+                "Got error while leaving the {}: '{}'",
+                vswitches[from].name, e
+            );
+        }
+        vswitches[from].unjoin();
+    }
+}
+
+/// Find the largest frame which crosses the path to the vswitch with
+/// index in vswitches over link, once it has joined, and again every
+/// REPROBE_INTERVAL in case the path changed. core fits packets to the
+/// smallest found for any of the vswitches, which the MTU of the tap
+/// interface tap is set to match, if it is given. overhead is what tags or headers
+/// add to each frame, which is only needed for the path MTU logged
+fn discover_path_mtu(
+    link: &VswitchLink,
+    index: usize,
+    vswitches: &[Arc<VswitchStatus>],
+    core: &VportCore,
+    overhead: usize,
+    tap: Option<&dyn VirtualNic>,
+) {
+    let status = &vswitches[index];
+    let smallest = MIN_TUNNEL_MTU - UDP_TUNNEL_OVERHEAD - overhead;
+    let full_size = frame_max(core.mtu()) + HOP_LIMIT_TAG_LEN;
+    let mut id = 0;
+
+    loop {
+        /* A vswitch which hasn't answered our join may not be there to answer probes */
+        while !status.joined.load(Ordering::Relaxed) {
+            thread::sleep(LIVENESS_CHECK_INTERVAL);
+        }
 
-fn main() -> ExitCode {
-    let args: Vec<String> = env::args().collect();
+        let mut search = PathMtuSearch::new(smallest, full_size);
+        while let Some(size) = search.next_size() {
+            id += 1;
 
-    if args.len() != 3 {
-        eprintln!("Expected 3 command line arguments and got {}", args.len());
-        eprintln!("Usage: vport <vswitch_ip> <vswitch_port>");
-        return ExitCode::FAILURE;
+            /* Probes which can't be sent, e.g. as they are larger than the interface's MTU, are lost */
+            match link.send(&core.path_mtu_probe(id, size)).is_ok() && probe_acked(status, id) {
+                true => search.acked(size),
+                false => search.lost(size),
+            }
+        }
+
+        let largest = search.largest_frame();
+        if status.largest_frame.swap(largest, Ordering::Relaxed) != largest {
+            let packet = largest - HOP_LIMIT_TAG_LEN - ETHER_HDR;
+            match largest == full_size {
+                true => println!("The path to the {} carries full-size frames", status.name),
+                false => println!(
+                    "The path MTU to the {} is {} bytes, so it carries packets of up to {} bytes",
+                    status.name,
+                    largest + overhead + UDP_TUNNEL_OVERHEAD,
+                    packet
+                ),
+            }
+
+            /* Frames are sent to every vswitch, so must fit the narrowest path */
+            let largest = vswitches
+                .iter()
+                .map(|status| status.largest_frame.load(Ordering::Relaxed))
+                .filter(|len| *len != 0)
+                .min()
+                .unwrap_or(largest);
+            core.set_largest_frame(largest);
+            if let Some(tap) = tap {
+                let mtu = (largest - HOP_LIMIT_TAG_LEN - ETHER_HDR).min(core.mtu());
+                match tap.set_mtu(mtu) {
+                    Ok(()) => println!("Set the MTU of {} to {}", tap.name(), mtu),
+                    Err(e) => {
+                        eprintln!("Got error while setting the MTU of {}: '{}'", tap.name(), e)
+                    }
+                }
+            }
+        }
+
+        thread::sleep(REPROBE_INTERVAL);
     }
+}
 
-    /* Get vswitch IP from command line argument */
-    let vswitch_ip = match args[1].parse::<Ipv4Addr>() {
-        Ok(ip) => ip,
-        Err(e) => {
-            eprintln!(
-                "Got error while parsing IPv4 address from command line argument: '{}'",
-                e
-            );
-            eprintln!("Could not parse '{}' as IPv4 address", args[1]);
-            return ExitCode::FAILURE;
+/// Returns true once status says the vswitch has answered the path
+/// MTU probe with id, or false if it hasn't within PROBE_TIMEOUT
+fn probe_acked(status: &VswitchStatus, id: u64) -> bool {
+    let deadline = Instant::now() + PROBE_TIMEOUT;
+    while Instant::now() < deadline {
+        if status.probe_acked.load(Ordering::Relaxed) == id {
+            return true;
         }
-    };
+        thread::sleep(PROBE_POLL_INTERVAL);
+    }
+    false
+}
 
-    /* Get port number from command line argument */
-    let vswitch_port = match args[2].parse::<u16>() {
-        Ok(vswitch_port) => vswitch_port,
-        Err(e) => {
-            eprintln!(
-                "Got error while parsing port command line argument: '{}'",
-                e
+/// Punch holes through the NATs between the vport and the vports which
+/// paths says the vswitch introduced it to, over link, every PUNCH_INTERVAL,
+/// logging the direct paths which are lost
+fn punch_holes(link: &VswitchLink, paths: &Mutex<DirectPaths>, core: &VportCore) {
+    let punch = core.punch(false);
+    loop {
+        thread::sleep(PUNCH_INTERVAL);
+
+        let (endpoints, lost) = paths
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .punches(Instant::now());
+        for endpoint in lost {
+            println!(
+                "Lost the direct path to the vport at {}, so its frames go through the vswitch",
+                endpoint
             );
-            eprintln!("Could not parse '{}' as port number", args[2]);
-            return ExitCode::FAILURE;
         }
+        for endpoint in endpoints {
+            if let Err(e) = link.send_to(&punch, endpoint) {
+                eprintln!(
+                    "Got error while punching through to the vport at {}: '{}'",
+                    endpoint, e
+                );
+            }
+        }
+    }
+}
+
+/// Ask each of servers which endpoint the datagrams sent from link
+/// arrive from, and the other address of the first server to answer
+/// too, if it gives one and is the only server, returning the first
+/// endpoint seen and the type of NAT the endpoints show, or None if no
+/// server answered
+fn discover_public_endpoint(
+    link: &VswitchLink,
+    servers: &[(String, u16)],
+) -> Option<PublicEndpoint> {
+    let VswitchLink::Udp { sock, .. } = link else {
+        return None;
     };
 
-    /* Initialise vport struct */
-    let mut vport = match initialise_vport(vswitch_ip, vswitch_port) {
-        Ok(vport) => vport,
-        Err(e) => {
-            eprintln!("Got error while initialising vport: '{}'", e);
-            eprintln!("Quitting");
-            return ExitCode::FAILURE;
+    let mut mapped = Vec::new();
+    let mut local = None;
+    let mut servers: Vec<SocketAddrV4> = servers
+        .iter()
+        .filter_map(|(host, port)| match resolve(host, *port) {
+            Ok(SocketAddr::V4(server)) => Some(server),
+            Ok(SocketAddr::V6(_)) => None,
+            Err(e) => {
+                eprintln!("Could not resolve STUN server '{}': '{}'", host, e);
+                None
+            }
+        })
+        .collect();
+    let mut index = 0;
+    while let Some(server) = servers.get(index).copied() {
+        index += 1;
+        let response = match stun_binding(sock, server) {
+            Ok(response) => response,
+            Err(e) => {
+                eprintln!("Got no answer from STUN server {}: '{}'", server, e);
+                continue;
+            }
+        };
+        if local.is_none() {
+            local = local_endpoint(sock, server).ok();
         }
+        mapped.push(response.mapped);
+        if let Some(other) = response.other.filter(|_| servers.len() == 1) {
+            servers.push(other);
+        }
+    }
+
+    let addr = *mapped.first()?;
+    let nat = match local {
+        Some(local) => NatType::classify(local, &mapped),
+        None => NatType::Unknown,
     };
+    Some(PublicEndpoint { addr, nat })
+}
 
-    let mut vport_clone = match clone_vport(&vport) {
-        Ok(vport_clone) => vport_clone,
-        Err(e) => {
-            eprintln!("Failed to clone vport with error: '{}'", e);
-            return ExitCode::FAILURE;
+/// Returns the endpoint which STUN server answered the binding request
+/// sent from sock with, sending it up to STUN_ATTEMPTS times
+fn stun_binding(sock: &UdpSocket, server: SocketAddrV4) -> io::Result<BindingResponse> {
+    let mut transaction_id = [0u8; 12];
+    File::open("/dev/urandom")?.read_exact(&mut transaction_id)?;
+    let request = stun::binding_request(&transaction_id);
+
+    /* Datagrams from anywhere else, or with another transaction ID, are ignored */
+    let mut buf = [0u8; 1500];
+    let mut answer = None;
+    'attempts: for _ in 0..STUN_ATTEMPTS {
+        sock.send_to(&request, server)?;
+        let deadline = Instant::now() + STUN_TIMEOUT;
+        while let Some(timeout) = deadline
+            .checked_duration_since(Instant::now())
+            .filter(|timeout| !timeout.is_zero())
+        {
+            sock.set_read_timeout(Some(timeout))?;
+            let (len, from) = match sock.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    break;
+                }
+                Err(e) => {
+                    sock.set_read_timeout(None)?;
+                    return Err(e);
+                }
+            };
+            if from == SocketAddr::V4(server) {
+                answer = stun::parse_binding_response(&buf[..len], &transaction_id);
+                if answer.is_some() {
+                    break 'attempts;
+                }
+            }
         }
-    };
+    }
+    sock.set_read_timeout(None)?;
+    answer.ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "no binding response"))
+}
 
-    println!("Starting vport");
+/// Returns the endpoint which sock sends to server from, before any NAT
+fn local_endpoint(sock: &UdpSocket, server: SocketAddrV4) -> io::Result<SocketAddrV4> {
+    /* A socket bound to an address of its own always sends from it */
+    let local = sock.local_addr()?;
+    match local.ip() {
+        IpAddr::V4(ip) if !ip.is_unspecified() => return Ok(SocketAddrV4::new(ip, local.port())),
+        _ => {}
+    }
 
-    /*
-     * Start thread which takes packets from
-     * tap interface and forwards to vswitch
-     */
-    let tap_to_vswitch_handle = thread::spawn(move || tap_to_vswitch(&mut vport));
+    /* Connecting a socket of our own picks the source address without sending */
+    let probe = UdpSocket::bind("0.0.0.0:0")?;
+    probe.connect(server)?;
+    match probe.local_addr()?.ip() {
+        IpAddr::V4(ip) => Ok(SocketAddrV4::new(ip, sock.local_addr()?.port())),
+        IpAddr::V6(_) => Err(io::Error::from(io::ErrorKind::AddrNotAvailable)),
+    }
+}
 
-    /*
-     * Start thread which takes packets received
-     * from the vswitch and forwards them to tap intf
-     */
-    let vswitch_to_tap_handle = thread::spawn(move || vswitch_to_tap(&mut vport_clone));
+/// Returns what the vswitch with index link_index is called in logs,
+/// the ones after the first being backups if failover
+fn vswitch_name(link_index: usize, failover: bool) -> String {
+    match (link_index, failover) {
+        (0, _) => "vswitch".to_string(),
+        (index, true) => format!("backup vswitch {}", index),
+        (_, false) => "second vswitch".to_string(),
+    }
+}
+
+/// Returns the first IPv4 address which host resolves to, with port
+fn resolve(host: &str, port: u16) -> io::Result<SocketAddr> {
+    (host, port)
+        .to_socket_addrs()?
+        .find(SocketAddr::is_ipv4)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no IPv4 address found"))
+}
+
+/// Resolve host every RESOLVE_INTERVAL, pointing vswitch_addr at
+/// its new address if it changes, so frames follow the vswitch
+/// when it is renumbered
+fn follow_vswitch_host(host: &str, port: u16, vswitch_addr: &RwLock<SocketAddr>) {
+    loop {
+        thread::sleep(RESOLVE_INTERVAL);
+
+        /* Keep using the last address if the name can't be resolved for now */
+        let new_addr = match resolve(host, port) {
+            Ok(new_addr) => new_addr,
+            Err(e) => {
+                eprintln!("Got error while resolving vswitch '{}': '{}'", host, e);
+                continue;
+            }
+        };
 
-    let mut exit_code = ExitCode::SUCCESS;
+        let mut vswitch_addr = vswitch_addr.write().unwrap();
+        if *vswitch_addr != new_addr {
+            println!(
+                "vswitch '{}' moved from {} to {}",
+                host, *vswitch_addr, new_addr
+            );
+            *vswitch_addr = new_addr;
+        }
+    }
+}
+
+/*
+ * Where the vswitches the vport connects to are
+ */
+struct VswitchAddrs<'a> {
+    first: &'a VswitchAddr,
+    /* The second vswitch, if the vport is multihomed */
+    secondary: Option<&'a VswitchAddr>,
+    /* The vswitches to fail over to, in order */
+    backups: &'a [VswitchAddr],
+}
 
-    /* Wait for tap_to_vswitch thread to finish */
-    if let Err(e) = tap_to_vswitch_handle.join() {
-        eprintln!("tap_to_vswitch failed with error: '{:?}'", e);
-        exit_code = ExitCode::FAILURE;
+/*
+ * How the vport's tap interface is set up
+ */
+struct TapSettings<'a> {
+    /* Name of the interface, which may hold a %d for the kernel to number */
+    name: &'a str,
+    /* Whether it is a tun interface, for L3 mode */
+    l3: bool,
+    /* Whether it is an existing NIC, which is attached to rather than created */
+    nic: bool,
+    /* Socket of the helper which hands it over, already configured, rather than it being created */
+    helper: Option<&'a str>,
+    /* Interface which systemd kept for an earlier vport, and passed back, rather than it being created */
+    stored: Option<Box<dyn VirtualNic>>,
+    /* Whether it outlives the vport, and who else can attach to it, if not left as they are */
+    persist: Option<bool>,
+    owner: Option<u32>,
+    group: Option<u32>,
+    /* MAC and MTU to give the interface, if not the ones the kernel picks */
+    mac: Option<MacAddr>,
+    mtu: Option<usize>,
+    /* Addresses to give it, and whether to bring it up or down, if not left as it is */
+    addrs: &'a [(IpAddr, u8)],
+    up: Option<bool>,
+}
+
+/// Initialise vport struct so that it is
+/// ready to communicate on the L2VPN network
+fn initialise_vport(
+    tap: TapSettings,
+    vswitch_addrs: &VswitchAddrs,
+    lag_links: &[Ipv4Addr],
+    core: VportCore,
+    proxy: Option<&Proxy>,
+    signals: &StopSignals,
+) -> Result<Vport, Error> {
+    /* Configure the tap (or tun) interface, or attach to the NIC, or have the helper hand it over */
+    #[cfg(unix)]
+    let stored = tap.stored.is_some();
+    let nic = match (tap.stored, tap.helper, tap.nic, tap.l3) {
+        (Some(stored), _, _, _) => stored,
+        #[cfg(unix)]
+        (None, Some(path), _, _) => fdpass::receive_tap(path, tap.l3)?,
+        #[cfg(windows)]
+        (None, Some(_), _, _) => {
+            return Err(ConfigError::Unsupported("--tap-helper isn't supported on Windows").into())
+        }
+        (None, None, true, _) => nic::attach(tap.name)?,
+        (None, None, false, true) => tap::create_tun(tap.name)?,
+        (None, None, false, false) => tap::create(tap.name)?,
+    };
+    let tap_name = nic.name();
+    if let Some(path) = tap.helper {
+        println!("Received {} from the helper at {}", tap_name, path);
     }
 
-    /* Wait for vswitch_to_tap thread to finish */
-    if let Err(e) = vswitch_to_tap_handle.join() {
-        eprintln!("vswitch_to_tap failed with error: '{:?}'", e);
-        return ExitCode::FAILURE;
+    /* systemd keeps the interface the vport created, if it is told to keep file handles */
+    #[cfg(unix)]
+    match (stored, tap.nic || tap.helper.is_some(), nic.fd()) {
+        (true, _, _) => println!("Took {} back from systemd", tap_name),
+        (false, false, Some(fd)) => {
+            let state = format!("FDSTORE=1\nFDNAME={}", SYSTEMD_TAP_NAME);
+            match systemd::notify_with_fds(&state, &[fd]) {
+                Ok(true) => println!("Handed {} to systemd to keep across restarts", tap_name),
+                Ok(false) => {}
+                Err(e) => eprintln!("Got error while handing {} to systemd: '{}'", tap_name, e),
+            }
+        }
+        _ => {}
+    }
+    if let Some(owner) = tap.owner {
+        nic.set_owner(owner)?;
+        println!("Let user {} attach to {}", owner, tap_name);
+    }
+    if let Some(group) = tap.group {
+        nic.set_group(group)?;
+        println!("Let group {} attach to {}", group, tap_name);
+    }
+    if let Some(persist) = tap.persist {
+        nic.set_persist(persist)?;
+        match persist {
+            true => println!("Made {} persistent, so it outlives the vport", tap_name),
+            false => println!(
+                "Made {} non-persistent, so it is removed when the vport stops",
+                tap_name
+            ),
+        }
+    }
+    if let Some(mac) = tap.mac {
+        nic.set_mac(&mac)?;
+        println!("Set the MAC of {} to {}", tap_name, mac);
+    }
+    if let Some(mtu) = tap.mtu {
+        nic.set_mtu(mtu)?;
+        println!("Set the MTU of {} to {}", tap_name, mtu);
+    }
+    for (ip, prefix_len) in tap.addrs {
+        nic.add_addr(*ip, *prefix_len)?;
+        println!("Gave {} the address {}/{}", tap_name, ip, prefix_len);
+    }
+    if let Some(up) = tap.up {
+        nic.set_up(up)?;
+        println!("Brought {} {}", tap_name, if up { "up" } else { "down" });
     }
 
-    println!("Terminating vport");
+    let failover = !vswitch_addrs.backups.is_empty();
+    let link = connect_with_backoff(
+        vswitch_addrs.first,
+        proxy,
+        &vswitch_name(0, failover),
+        signals,
+    )?;
+    let lag = lag_links
+        .iter()
+        .map(|local_ip| connect_lag_link(*local_ip, &link))
+        .collect::<Result<Vec<VswitchLink>, _>>()?;
+    let secondary = vswitch_addrs
+        .secondary
+        .map(|addr| connect_with_backoff(addr, proxy, &vswitch_name(1, false), signals))
+        .transpose()?;
+    let backups = vswitch_addrs
+        .backups
+        .iter()
+        .enumerate()
+        .map(|(index, addr)| {
+            connect_with_backoff(addr, proxy, &vswitch_name(index + 1, true), signals)
+        })
+        .collect::<Result<Vec<VswitchLink>, _>>()?;
 
-    exit_code
+    let vport = Vport {
+        tap: Arc::from(nic),
+        l3: tap.l3,
+        link,
+        lag,
+        secondary,
+        backups,
+        active: failover.then(Arc::default),
+        core,
+        drops: Arc::default(),
+        fragment_size: None,
+        fec_group: None,
+        direct: None,
+    };
+
+    println!(
+        "Initialised vport using {} {}, and link {:?}",
+        match (tap.nic, vport.l3) {
+            (true, _) => "NIC",
+            (false, true) => "tun interface",
+            (false, false) => "tap interface",
+        },
+        vport.tap.name(),
+        vport.link
+    );
+    for lag_link in vport.lag.iter() {
+        println!("Also connected to vswitch over further link {:?}", lag_link);
+    }
+    if let Some(secondary) = &vport.secondary {
+        println!("Also connected to second vswitch over link {:?}", secondary);
+    }
+    for (index, backup) in vport.backups.iter().enumerate() {
+        println!(
+            "Also connected to {} over link {:?}",
+            vswitch_name(index + 1, true),
+            backup
+        );
+    }
+
+    Ok(vport)
 }
 
-/// Create and configure tap interface which will
-/// take the traffic that the underlay interface handles
-/// and insert it into the L2VPN network we are setting up
+/// Carry the frames of the vport's links to vswitches over UDP in DTLS
+/// sessions using dtls_psk, if it is given, waiting for their handshakes
 ///
-/// Returns the /dev/net/tun file handler on success
-fn create_tap_intf(ul_intf: &str) -> Result<File, Box<dyn Error>> {
-    /*
-     * Ensure the ul_intf name is valid ASCII and is <= IFNAMSIZ bytes
-     *
-     * The Linux/C network stack expects ASCII interface names, and if
-     * the interface name is ASCII encoded, then every UTF-8 char is 1 byte,
-     * so we do not need to worry about the distinction after this.
-     */
-    if !ul_intf.is_ascii() {
-        return Err(format!("Interface name is not valid ASCII: '{}'", ul_intf).into());
-    }
+/// Each session is kept up by a thread of its own, which isn't joined,
+/// as it runs for as long as the vport does
+#[cfg(feature = "dtls")]
+fn secure_links(vport: Vport, dtls_psk: Option<&[u8]>) -> Result<Vport, Error> {
+    let Some(psk) = dtls_psk else {
+        return Ok(vport);
+    };
+    let context = dtls::client_context(psk)?;
+    let secure = |link: VswitchLink| -> Result<VswitchLink, Error> {
+        let VswitchLink::Udp {
+            sock, vswitch_addr, ..
+        } = link
+        else {
+            return Err(ConfigError::Unsupported(
+                "DTLS can only be used with a vswitch reached over UDP",
+            )
+            .into());
+        };
+        let link = DtlsLink::connect(sock, vswitch_addr, context.clone())?;
+        match link.is_established() {
+            true => println!("Set up DTLS session with vswitch"),
+            false => println!(
+                "DTLS handshake with vswitch not finished yet (is its key the same as ours?), carrying on in the background"
+            ),
+        }
+
+        let keeper = link.try_clone()?;
+        thread::spawn(move || keep_dtls_session(&keeper));
+        Ok(VswitchLink::Dtls(link))
+    };
+
+    Ok(Vport {
+        link: secure(vport.link)?,
+        secondary: vport.secondary.map(secure).transpose()?,
+        backups: vport
+            .backups
+            .into_iter()
+            .map(secure)
+            .collect::<Result<_, _>>()?,
+        ..vport
+    })
+}
+
+/// Without the dtls feature, links are never carried in DTLS sessions
+#[cfg(not(feature = "dtls"))]
+fn secure_links(vport: Vport, _dtls_psk: Option<&[u8]>) -> Result<Vport, Error> {
+    Ok(vport)
+}
 
-    if ul_intf.len() > IFNAMSIZ {
-        return Err(format!("Interface name longer than IFNAMSIZ(16): '{}'", ul_intf).into());
+/// Sign the frames sent over the vport's links to vswitches over UDP
+/// with frame_auth, and drop those received over them it can't verify,
+/// or which have already been received
+fn sign_links(vport: &mut Vport, frame_auth: &FrameAuth) {
+    let links = iter::once(&mut vport.link)
+        .chain(vport.lag.iter_mut())
+        .chain(vport.secondary.iter_mut())
+        .chain(vport.backups.iter_mut());
+    for link in links {
+        if let VswitchLink::Udp { auth, .. } = link {
+            *auth = Some(frame_auth.clone());
+        }
     }
+}
 
-    /* Open the /dev/net/tun file which is the interface to the tun/tap driver */
-    let tap_file = File::options()
-        .read(true)
-        .write(true)
-        .open("/dev/net/tun")?;
+/// Send the datagrams over the vport's links to vswitches over UDP in
+/// vni, and drop those received over them in any other
+fn tenant_links(vport: &mut Vport, vni: u32) {
+    let links = iter::once(&mut vport.link)
+        .chain(vport.lag.iter_mut())
+        .chain(vport.secondary.iter_mut())
+        .chain(vport.backups.iter_mut());
+    for link in links {
+        if let VswitchLink::Udp { vni: link_vni, .. } = link {
+            *link_vni = Some(vni);
+        }
+    }
+}
 
-    /*
-     * Initialise the ifreq struct which indicates the
-     * underlay interface we are going to use, and specifies
-     * the IFF_TAP and IFF_NO_PI flags which indicate we want
-     * to configure it as an L2 tap interface, and that we want
-     * it to handle raw data without any extra headers
-     */
-    let mut ifr: ifreq = unsafe { std::mem::zeroed() };
-    ifr.ifr_ifru.ifru_flags = (IFF_TAP | IFF_NO_PI) as i16;
-    for (i, b) in ul_intf.bytes().enumerate() {
-        ifr.ifr_name[i] = b as c_char;
+/// Bind the sockets of the vport's links to vswitches over UDP as in
+/// binding, the link to the first vswitch to binding's source port, if
+/// it is given. Links which aren't carried in DTLS sessions yet can
+/// simply be given new sockets, as they haven't sent anything
+fn bind_links(vport: &mut Vport, binding: &UnderlayBinding) -> io::Result<()> {
+    if *binding == UnderlayBinding::default() {
+        return Ok(());
+    }
+    let links = iter::once((&mut vport.link, binding.port.unwrap_or(0)))
+        .chain(vport.secondary.iter_mut().map(|link| (link, 0)))
+        .chain(vport.backups.iter_mut().map(|link| (link, 0)));
+    for (link, port) in links {
+        if let VswitchLink::Udp { sock, .. } = link {
+            *sock = binding.bind(port)?;
+            println!("Bound link to vswitch to {}", sock.local_addr()?);
+        }
     }
+    Ok(())
+}
 
-    /* Perform the ioctl call to configure the tap interface */
-    unsafe {
-        match tunsetiff(tap_file.as_raw_fd(), &mut ifr as *mut _ as *const c_int) {
-            Ok(_) => Ok(tap_file),
-            Err(e) => Err(format!("tunsetiff failed with error: '{}'", e).into()),
+/// Mark the datagrams sent over the vport's links to vswitches over UDP
+/// with dscp, each link's own marker being shared by its clones
+fn mark_links(vport: &mut Vport, dscp: Dscp) {
+    let links = iter::once(&mut vport.link)
+        .chain(vport.lag.iter_mut())
+        .chain(vport.secondary.iter_mut())
+        .chain(vport.backups.iter_mut());
+    for link in links {
+        if let VswitchLink::Udp { marker, .. } = link {
+            *marker = Some(Arc::new(Marker::new(dscp)));
         }
     }
 }
 
-/// Initialise vport struct so that it is
-/// ready to communicate on the L2VPN network
-fn initialise_vport(vswitch_ip: Ipv4Addr, vswitch_port: u16) -> Result<Vport, Box<dyn Error>> {
-    /* Configure tap interface tap0 and return file handle to it */
-    let tap_file = create_tap_intf("tap0")?;
+/// Send the frames of the vport's link to the vswitch, which must be
+/// reached over UDP, in encap, from the vswitch's port, as VTEPs send
+/// to the port they receive on, on the address and interface of binding
+fn encapsulate_link(vport: &mut Vport, encap: Encap, binding: &UnderlayBinding) -> io::Result<()> {
+    if let VswitchLink::Udp {
+        sock,
+        vswitch_addr,
+        encap: link_encap,
+        ..
+    } = &mut vport.link
+    {
+        let port = vswitch_addr.read().unwrap().port();
+        *sock = binding.bind(port)?;
+        *link_encap = Some(encap);
+        println!("Sending frames to the vswitch in {:?}", encap);
+    }
+    Ok(())
+}
 
-    /*
-     * Create UDP socket which the vport will use to communicate with the vswitch
-     *
-     * It communicates on any available IP and a random ephemeral port, which is fine
-     * as the other vport requires the address of the tap interface, not this socket
-     */
-    let sock = UdpSocket::bind("0.0.0.0:0".to_string())?;
+/// Check link's DTLS session every HANDSHAKE_TIMEOUT, setting it
+/// up again if its handshake is stuck or the vswitch has gone quiet
+#[cfg(feature = "dtls")]
+fn keep_dtls_session(link: &DtlsLink) {
+    loop {
+        thread::sleep(HANDSHAKE_TIMEOUT);
+        if let Err(e) = link.keep(Instant::now()) {
+            eprintln!("Got error while keeping DTLS session with vswitch: '{}'", e);
+
+            /* The vswitch or the network may only be down for now */
+            if !e.is_transient() {
+                return;
+            }
+        }
+    }
+}
+
+/// Join the underlay multicast group which the vswitch at the other end
+/// of link floods frames through, returning a link which receives them
+fn join_bum_group(
+    group: SocketAddrV4,
+    link: &VswitchLink,
+    session_id: u64,
+) -> Result<VswitchLink, Error> {
+    let VswitchLink::Udp { vswitch_addr, .. } = link else {
+        return Err(ConfigError::Unsupported(
+            "the BUM group can only be used with a vswitch reached over UDP",
+        )
+        .into());
+    };
 
     /*
-     * Store address of vswitch as for the L2VPN to function
-     * properly, it must be able to communicate with the vswitch
+     * Several vports on the same host can join the same group, and
+     * binding to the group's address, rather than any, means only
+     * datagrams sent to the group are received
      */
-    let vswitch_addr = SocketAddr::new(IpAddr::V4(vswitch_ip), vswitch_port);
+    let sock = platform::bind_udp(group, None, true)?;
+    sock.join_multicast_v4(group.ip(), &Ipv4Addr::UNSPECIFIED)?;
 
-    let vport = Vport {
-        tap_file,
+    Ok(VswitchLink::Group {
         sock,
+        vswitch_addr: vswitch_addr.clone(),
+        link: Box::new(link.try_clone()?),
+        session_id,
+    })
+}
+
+/// Open a further link to the vswitch at the other end of link, which
+/// must be reached over UDP, from a socket bound to local_ip, so the
+/// frames sent over it take the underlay path of that address
+fn connect_lag_link(local_ip: Ipv4Addr, link: &VswitchLink) -> Result<VswitchLink, Error> {
+    let VswitchLink::Udp {
         vswitch_addr,
+        auth,
+        encap,
+        ..
+    } = link
+    else {
+        return Err(ConfigError::Unsupported(
+            "LAG links can only be used with a vswitch reached over UDP",
+        )
+        .into());
     };
 
-    println!(
-        "Initialised vport using tap interface tap0, and socket {:?}",
-        vport.sock
-    );
+    Ok(VswitchLink::Udp {
+        sock: UdpSocket::bind((local_ip, 0))?,
+        vswitch_addr: vswitch_addr.clone(),
+        auth: auth.clone(),
+        encap: *encap,
+        marker: None,
+        vni: None,
+    })
+}
 
-    Ok(vport)
+/// Connect to the vswitch at vswitch_addr, through proxy if
+/// it is given and the vswitch is reached over TCP
+fn connect_link(vswitch_addr: &VswitchAddr, proxy: Option<&Proxy>) -> Result<VswitchLink, Error> {
+    let link = match *vswitch_addr {
+        VswitchAddr::Udp(ref vswitch_host, vswitch_port) => {
+            /*
+             * Create UDP socket which the vport will use to communicate with the vswitch
+             *
+             * It communicates on any available IP and a random ephemeral port, which is fine
+             * as the other vport requires the address of the tap interface, not this socket
+             */
+            let sock = UdpSocket::bind("0.0.0.0:0".to_string())?;
+
+            /*
+             * Store address of vswitch as for the L2VPN to function
+             * properly, it must be able to communicate with the vswitch
+             */
+            let vswitch_addr = Arc::new(RwLock::new(resolve(vswitch_host, vswitch_port)?));
+
+            /*
+             * A vswitch given by name may be renumbered while we run,
+             * so keep resolving it. This thread isn't joined, as it
+             * runs for as long as the vport does
+             */
+            if vswitch_host.parse::<Ipv4Addr>().is_err() {
+                let host = vswitch_host.clone();
+                let addr = vswitch_addr.clone();
+                thread::spawn(move || follow_vswitch_host(&host, vswitch_port, &addr));
+            }
+
+            VswitchLink::Udp {
+                sock,
+                vswitch_addr,
+                auth: None,
+                encap: None,
+                marker: None,
+                vni: None,
+            }
+        }
+        /* Connect to the vswitch on the hypervisor over vsock */
+        #[cfg(unix)]
+        VswitchAddr::Vsock(cid, port) => VswitchLink::Vsock(VsockStream::connect(cid, port)?),
+        VswitchAddr::Tcp(ref vswitch_host, vswitch_port) => {
+            if let Some(proxy) = proxy {
+                println!("Connecting to the vswitch through proxy {}", proxy);
+            }
+            VswitchLink::Tcp(TcpLink::connect(vswitch_host, vswitch_port, proxy)?)
+        }
+        #[cfg(unix)]
+        VswitchAddr::Unix(ref vswitch_path) => {
+            /*
+             * The vswitch can only send frames back to a bound socket,
+             * so bind to a path next to the vswitch's socket which is
+             * unique to this process, which main() removes when the vport
+             * stops. Abstract addresses would not need cleaning up, but
+             * they can't be reached from other network namespaces, which
+             * is a common way to run local topologies
+             */
+            let local_path = unix_local_path(vswitch_path);
+            let _ = fs::remove_file(&local_path);
+            let sock = UnixDatagram::bind(&local_path)?;
+            sock.connect(vswitch_path)?;
+
+            VswitchLink::Unix(sock)
+        }
+        /* Set up rings shared with a vswitch on the same host */
+        #[cfg(target_os = "linux")]
+        VswitchAddr::Shm(ref vswitch_path) => VswitchLink::Shm(ShmLink::connect(vswitch_path)?),
+        #[cfg(not(target_os = "linux"))]
+        VswitchAddr::Shm(_) => {
+            return Err(
+                ConfigError::Unsupported("Shared memory links are only supported on Linux").into(),
+            )
+        }
+        #[cfg(windows)]
+        VswitchAddr::Vsock(..) | VswitchAddr::Unix(_) => {
+            return Err(ConfigError::Unsupported(
+                "vsock and Unix socket links aren't supported on Windows",
+            )
+            .into())
+        }
+        #[cfg(feature = "quic")]
+        VswitchAddr::Quic {
+            ref host,
+            port,
+            ref ca_path,
+        } => {
+            let ca_path = ca_path
+                .as_deref()
+                .ok_or(ConfigError::Unsupported("--quic needs --quic-ca-file"))?;
+            let config = quic_client_config(ca_path)?;
+            VswitchLink::Quic(QuicLink::connect(host, port, config)?)
+        }
+        #[cfg(not(feature = "quic"))]
+        VswitchAddr::Quic { .. } => {
+            return Err(ConfigError::Unsupported("vport was built without the quic feature").into())
+        }
+    };
+
+    Ok(link)
+}
+
+/// Returns the path which the socket of a link to the vswitch
+/// at vswitch_path is bound to
+#[cfg(unix)]
+fn unix_local_path(vswitch_path: &str) -> String {
+    format!("{}.vport-{}", vswitch_path, process::id())
+}
+
+/// Connect to the vswitch at vswitch_addr, called name, as connect_link
+/// does, trying again with backoff from RECONNECT_DELAY_MIN up to
+/// RECONNECT_DELAY_MAX while it fails in a way which may only last until
+/// the vswitch (or the network) comes up, unless we are told to stop
+fn connect_with_backoff(
+    vswitch_addr: &VswitchAddr,
+    proxy: Option<&Proxy>,
+    name: &str,
+    signals: &StopSignals,
+) -> Result<VswitchLink, Error> {
+    let mut backoff = Backoff::new(RECONNECT_DELAY_MIN, RECONNECT_DELAY_MAX);
+
+    loop {
+        let now = Instant::now();
+        backoff.due(now);
+        match connect_link(vswitch_addr, proxy) {
+            Ok(link) => {
+                if backoff.attempts() > 1 {
+                    println!(
+                        "Connected to the {} after {} attempts",
+                        name,
+                        backoff.attempts()
+                    );
+                }
+                return Ok(link);
+            }
+            Err(e) if can_retry(&e) => eprintln!(
+                "Could not connect to the {} ('{}'), so trying again in {} seconds",
+                name,
+                e,
+                backoff.remaining(now).as_secs()
+            ),
+            Err(e) => return Err(e),
+        }
+
+        if let Some(signal) = signals.wait_timeout(backoff.remaining(Instant::now()))? {
+            return Err(TransportError::Stopped(signal).into());
+        }
+    }
 }
 
-/// The tap file in the vport will be read from by one
+/// Returns whether connecting to a vswitch failed with e in a way
+/// which may only last until the vswitch (or the network) comes up,
+/// e.g. as it isn't listening yet, or hasn't created its socket yet
+fn can_retry(e: &Error) -> bool {
+    let not_found =
+        matches!(e, Error::Transport(TransportError::Io(e)) if e.kind() == io::ErrorKind::NotFound);
+    not_found || e.is_transient()
+}
+
+/// The tap interface in the vport will be read from by one
 /// thread and written to by another during the operation
 /// of the L2VPN session.
 ///
-/// These operations require a mutable reference to the
-/// tap_file File struct, and since we cannot have 2 mutable
-/// references to the same File, I wrote this clone_vport
-/// method which calls File::try_clone which returns another
-/// File struct which refers to the same file handle, thereby
-/// creating 2 separate mutable references to the same file handle
-/// (but different File structs)
+/// Reads and writes to it only require an immutable reference,
+/// so the clone shares it with the vport, and each thread can
+/// have its own Vport, with its own per-thread state.
 ///
-/// While reading to and writing from the same file in 2 different
-/// threads is normally unsafe, since this file represents /dev/net/tun
-/// which is the network I/O interface to a tap/tun interface, this is fine
-fn clone_vport(vport: &Vport) -> Result<Vport, Box<dyn Error>> {
+/// While reading to and writing from the same interface in 2 different
+/// threads is normally unsafe, since this is the network I/O interface
+/// to a tap/tun interface, which hands each frame to one reader, this is fine
+fn clone_vport(vport: &Vport) -> Result<Vport, Error> {
     Ok(Vport {
-        tap_file: vport.tap_file.try_clone()?,
-        vswitch_addr: vport.vswitch_addr,
+        tap: vport.tap.clone(),
+        l3: vport.l3,
         /*
-         * Reads and writes to UdpSockets only require an
+         * Reads and writes to the link only require an
          * immutable reference so this is technically not required,
          * however doing this allows me to bundle everything into
          * the Vport struct which is easier
          */
-        sock: vport.sock.try_clone()?,
+        link: vport.link.try_clone()?,
+        lag: clone_lag_links(vport)?,
+        secondary: vport
+            .secondary
+            .as_ref()
+            .map(VswitchLink::try_clone)
+            .transpose()?,
+        backups: vport
+            .backups
+            .iter()
+            .map(VswitchLink::try_clone)
+            .collect::<Result<_, _>>()?,
+        active: vport.active.clone(),
+        core: vport.core.clone(),
+        drops: vport.drops.clone(),
+        fragment_size: vport.fragment_size,
+        fec_group: vport.fec_group,
+        direct: vport.direct.clone(),
     })
 }
 
+/// Returns another handle to each of the vport's links, in the order of the vswitches' statuses
+fn clone_links(vport: &Vport) -> Result<Vec<VswitchLink>, TransportError> {
+    let mut links = vec![vport.link.try_clone()?];
+    if let Some(secondary) = &vport.secondary {
+        links.push(secondary.try_clone()?);
+    }
+    for backup in vport.backups.iter() {
+        links.push(backup.try_clone()?);
+    }
+    Ok(links)
+}
+
+/// Returns another handle to each of the vport's further links to the first vswitch
+fn clone_lag_links(vport: &Vport) -> Result<Vec<VswitchLink>, TransportError> {
+    vport.lag.iter().map(VswitchLink::try_clone).collect()
+}
+
 /// Take frame which the tap interface receives
 /// and inject it into the L2VPN network by forwarding
 /// it to the vswitch
-fn tap_to_vswitch(vport: &mut Vport) {
+///
+/// Frames are compressed for each of vswitches (the first, then the
+/// second) whose status says it agreed to compress them, and sent in
+/// fragments to each which agreed to fragment them, if they are too large.
+/// If there are backups, frames only go to the vswitch failed over to
+///
+/// Frames which can't be sent are counted and dropped, and an
+/// error is only returned if the tap interface can't be read
+fn tap_to_vswitch(
+    vport: &mut Vport,
+    vswitches: &[Arc<VswitchStatus>],
+    left: &AtomicBool,
+    heartbeat: &Heartbeat,
+) -> Result<(), TapError> {
     /* Buffer to store frames the tap interface receives */
-    let mut buf = [0u8; ETHER_MTU];
+    let mut buf = [0u8; TUNNEL_FRAME_MAX];
+
+    /* ID of the last frame sent, which its fragments carry */
+    let mut frame_id: u32 = 0;
+
+    /* Small frames waiting to be sent together, once the vswitch agrees */
+    let mut batch = Batch::new(vport.fragment_size.unwrap_or(BATCH_MAX));
+
+    /* Group which the datagrams sent to the vswitch are protected in, once it agrees to FEC */
+    let mut encoder = Encoder::new(vport.fec_group.unwrap_or(FEC_GROUP_MAX));
+
+    /*
+     * In L3 mode, no ARP has the vswitch learn the tun interface's
+     * addresses, so they are announced every HELLO_INTERVAL, with the
+     * announcements sent as if the host had sent them
+     */
+    let mut announce = vport.l3.then(|| Interval::new(HELLO_INTERVAL));
+    let mut announcements = Vec::new();
+
+    /*
+     * With backups, the vswitch frames fail over to has only learned
+     * the MACs of our hosts which sent frames while it was in use, so
+     * is sent an announcement from each, as it floods nothing to a
+     * port it has learned no MACs on. The vswitch in use is checked
+     * every LIVENESS_CHECK_INTERVAL, even if no frames arrive
+     */
+    let mut local_macs = LocalMacs::default();
+    let mut announced_to = 0;
 
     /*
      * Main loop which takes packets which the tap
      * interface receives and forwards them to the vswitch
      */
     loop {
-        /* Fill buffer with bytes read from tap interface */
-        let mut bytes_read = vport.tap_file.read(&mut buf).unwrap();
+        let now = Instant::now();
+        if announce.as_mut().is_some_and(|timer| timer.due(now)) {
+            match vport.tap.ipv4_addrs() {
+                Ok(addrs) => announcements.extend(addrs.into_iter().map(tun::announcement)),
+                Err(e) => eprintln!(
+                    "Got error while getting the tun interface's addresses: '{}'",
+                    e
+                ),
+            }
+        }
+
+        if let Some(active) = &vport.active {
+            let active = active.load(Ordering::Relaxed);
+            if active != announced_to {
+                announcements.extend(local_macs.announcements(now));
+                announced_to = active;
+            }
+        }
+
+        /*
+         * A batch, and an FEC group's parity, are only held until their
+         * time is up, even if no more frames arrive, and the tun interface's
+         * addresses are announced on time
+         */
+        let fec = vswitches[0].fec.load(Ordering::Relaxed);
+        let deadline = batch
+            .deadline()
+            .into_iter()
+            .chain(encoder.deadline().filter(|_| fec))
+            .chain(announce.map(|timer| now + timer.remaining(now)))
+            .chain(vport.active.as_ref().map(|_| now + LIVENESS_CHECK_INTERVAL))
+            .min()
+            .filter(|_| announcements.is_empty());
+        if let Some(deadline) = deadline {
+            let wait = deadline.saturating_duration_since(Instant::now());
+            if !vport.tap.readable(wait).map_err(TapError::Read)? {
+                let now = Instant::now();
+                if batch.deadline().is_some_and(|deadline| deadline <= now) {
+                    send_batch(
+                        vport,
+                        batch.len(),
+                        batch.take(),
+                        fec.then_some(&mut encoder),
+                    );
+                }
+                if fec && encoder.deadline().is_some_and(|deadline| deadline <= now) {
+                    if let Some(Err(e)) = encoder.flush().map(|parity| vport.link.send(&parity)) {
+                        eprintln!("Got error while sending FEC parity to vswitch: '{}'", e);
+                    }
+                }
+                continue;
+            }
+        }
 
-        /* If EOF reached, panic */
-        if bytes_read == 0 {
-            panic!("Reached EOF for /dev/net/tun which should not happen, quitting");
+        /* Fill buffer with bytes read from tap interface, or the next announcement */
+        let bytes_read = match announcements.pop() {
+            Some(frame) => {
+                buf[..frame.len()].copy_from_slice(&frame);
+                Some(frame.len())
+            }
+            None => {
+                heartbeat.idle();
+                let bytes_read = read_tap(vport, &mut buf[..frame_max(vport.core.mtu())])
+                    .map_err(TapError::Read)?;
+                heartbeat.busy(Instant::now());
+                bytes_read
+            }
+        };
+
+        /* /dev/net/tun never reaches EOF while the tap interface exists */
+        let bytes_read = match bytes_read {
+            Some(0) => return Err(TapError::Eof),
+            Some(bytes_read) => bytes_read,
+            None => continue,
+        };
+        if vport.active.is_some() {
+            local_macs.saw(&buf[..bytes_read], Instant::now());
         }
 
-        /* If data is less than 46 bytes, add some padding to the buffer */
-        if bytes_read < ETHER_DATA_MIN {
-            buf[bytes_read..ETHER_DATA_MIN].fill(0);
-            bytes_read = ETHER_DATA_MIN;
+        let tagged_len = match vport.core.from_tap(&mut buf, bytes_read) {
+            Action::Forward(tagged_len) => tagged_len,
+            /* The frame was refused with an ICMP error, or answered, for the host */
+            Action::Reply(reply) => {
+                if let Err(e) = write_tap(vport, &reply) {
+                    eprintln!("Got error while sending reply to tap interface: '{}'", e);
+                }
+                continue;
+            }
+            /* Only frames from the vswitch or other vports can answer our joins and probes */
+            Action::Drop
+            | Action::Joined { .. }
+            | Action::ProbeAcked(_)
+            | Action::Introduced { .. }
+            | Action::Punched { .. } => continue,
+        };
+        if left.load(Ordering::Relaxed) {
+            continue;
         }
 
-        /* Forward received frame to vswitch */
-        let bytes_sent = vport
-            .sock
-            .send_to(&buf[..bytes_read], vport.vswitch_addr)
-            .unwrap();
+        /* A flow's frames always take the same link, so they stay in order */
+        let active = vport
+            .active
+            .as_ref()
+            .map_or(0, |active| active.load(Ordering::Relaxed));
+        let link = match (active, pick_link(&buf[..tagged_len], vport.lag.len() + 1)) {
+            (0, 0) => &vport.link,
+            (0, index) => &vport.lag[index - 1],
+            (active, _) => &vport.backups[active - 1],
+        };
+
+        /* The frame is only compressed once, however many vswitches it is compressed for */
+        let compressing = |index: usize| {
+            vswitches
+                .get(index)
+                .is_some_and(|status| status.compressing.load(Ordering::Relaxed))
+        };
+        let compressed = match compressing(active) || vport.secondary.is_some() && compressing(1) {
+            true => compress(&buf[..tagged_len]),
+            false => None,
+        };
+        let frame_for = |index: usize| match &compressed {
+            Some(compressed) if compressing(index) => &compressed[..],
+            _ => &buf[..tagged_len],
+        };
+        let fragment_size = |index: usize| {
+            vport.fragment_size.filter(|_| {
+                vswitches
+                    .get(index)
+                    .is_some_and(|status| status.fragmenting.load(Ordering::Relaxed))
+            })
+        };
+        frame_id = frame_id.wrapping_add(1);
+        let frame = frame_for(active);
+
+        /*
+         * Frames to the hosts behind vports we have a direct path to skip
+         * the vswitch, unless they are too large to be sent whole
+         */
+        let direct = vport.direct.as_ref().and_then(|paths| {
+            paths.lock().unwrap_or_else(PoisonError::into_inner).route(
+                &EthernetFrame::parse(&buf[..tagged_len]).ok()?.dst,
+                Instant::now(),
+            )
+        });
+        if let Some(endpoint) =
+            direct.filter(|_| fragment_size(0).is_none_or(|size| frame.len() <= size))
+        {
+            match vport.link.send_to(frame, endpoint) {
+                Ok(_) => log_frame!(
+                    "Sent frame straight to the vport at {}: {}",
+                    endpoint,
+                    FrameLogMsg(&buf[..tagged_len], tagged_len - HOP_LIMIT_TAG_LEN)
+                ),
+                Err(e) => {
+                    vport.drops.tx.fetch_add(1, Ordering::Relaxed);
+                    eprintln!(
+                        "Dropped frame as sending it to the vport at {} failed: '{}'",
+                        endpoint, e
+                    );
+                }
+            }
+            continue;
+        }
 
-        /* If not all the bytes could be forwarded, fail */
-        if bytes_sent != bytes_read {
-            panic!(
-                "Frame was {} bytes but could only send {} bytes. Quitting.",
-                bytes_read, bytes_sent
+        /* Small frames wait for others to share their datagram */
+        if vswitches[0].batching.load(Ordering::Relaxed) && batch.holds(frame) {
+            let frames = batch.len();
+            let full = batch.push(frame, Instant::now());
+            send_batch(vport, frames, full, fec.then_some(&mut encoder));
+            log_frame!(
+                "Batched frame: {}",
+                FrameLogMsg(&buf[..tagged_len], tagged_len - HOP_LIMIT_TAG_LEN)
             );
+            continue;
+        }
+
+        /* Frames which aren't batched are sent after those which are, so they stay in order */
+        send_batch(
+            vport,
+            batch.len(),
+            batch.take(),
+            fec.then_some(&mut encoder),
+        );
+
+        /* Forward received frame to vswitch, dropping it if that fails */
+        match send_frame(
+            link,
+            frame,
+            fragment_size(active),
+            frame_id,
+            fec.then_some(&mut encoder),
+        ) {
+            Ok(bytes_sent) if bytes_sent == frame.len() => {}
+            Ok(bytes_sent) => {
+                vport.drops.tx.fetch_add(1, Ordering::Relaxed);
+                eprintln!(
+                    "Dropped frame of {} bytes as only {} bytes could be sent to the vswitch",
+                    frame.len(),
+                    bytes_sent
+                );
+                continue;
+            }
+            Err(e) => {
+                vport.drops.tx.fetch_add(1, Ordering::Relaxed);
+                eprintln!("Dropped frame as sending it to the vswitch failed: '{}'", e);
+                continue;
+            }
+        }
+
+        /*
+         * The second vswitch is there in case the first one fails,
+         * so failing to reach it is not a reason to stop
+         */
+        if let Some(secondary) = &vport.secondary {
+            if let Err(e) = send_frame(secondary, frame_for(1), fragment_size(1), frame_id, None) {
+                eprintln!("Got error while sending frame to second vswitch: '{}'", e);
+            }
         }
 
         /* Log frame */
-        println!(
+        log_frame!(
             "Sent frame: {}",
-            get_frame_log_msg(&buf[..bytes_read], bytes_read)
+            FrameLogMsg(&buf[..tagged_len], tagged_len - HOP_LIMIT_TAG_LEN)
+        );
+    }
+}
+
+/// Read the next frame which the host sends from the vport's tap
+/// interface into buf, returning its length. In L3 mode, the IPv4 packet
+/// read from the tun interface is put in a frame, and None is returned
+/// for other packets, which are dropped
+fn read_tap(vport: &mut Vport, buf: &mut [u8]) -> io::Result<Option<usize>> {
+    if !vport.l3 {
+        return vport.tap.read(buf).map(Some);
+    }
+    let len = vport.tap.read(&mut buf[ETHER_HDR..])?;
+    if len == 0 {
+        return Ok(Some(0));
+    }
+    let Some(header) = tun::frame_header(&buf[ETHER_HDR..ETHER_HDR + len]) else {
+        return Ok(None);
+    };
+    buf[..ETHER_HDR].copy_from_slice(&header);
+    Ok(Some(ETHER_HDR + len))
+}
+
+/// Write frame to the vport's tap interface, returning how many of its
+/// bytes were written. In L3 mode, only the IPv4 packet it carries is
+/// written to the tun interface, and frames carrying anything else are
+/// dropped, as if written
+fn write_tap(vport: &mut Vport, frame: &[u8]) -> io::Result<usize> {
+    if !vport.l3 {
+        return vport.tap.write(frame);
+    }
+    match tun::packet(frame) {
+        Some(packet) => Ok(ETHER_HDR + vport.tap.write(packet)?),
+        None => Ok(frame.len()),
+    }
+}
+
+/// Send batch, which holds frames frames, to the vswitch, if there is
+/// one, in encoder's group if it is given, counting its frames as
+/// dropped if that fails
+fn send_batch(vport: &Vport, frames: usize, batch: Option<Vec<u8>>, encoder: Option<&mut Encoder>) {
+    let Some(batch) = batch else {
+        return;
+    };
+    if let Err(e) = send_datagram(&vport.link, &batch, encoder) {
+        vport.drops.tx.fetch_add(frames as u64, Ordering::Relaxed);
+        eprintln!(
+            "Dropped {} frames as sending their batch to the vswitch failed: '{}'",
+            frames, e
         );
     }
 }
 
+/// Send frame to the vswitch over link, in fragments of up to
+/// fragment_size bytes, carrying id, if that is given and the frame is
+/// larger, and in encoder's group if that is given, returning the
+/// number of bytes of the frame which were sent
+fn send_frame(
+    link: &VswitchLink,
+    frame: &[u8],
+    fragment_size: Option<usize>,
+    id: u32,
+    mut encoder: Option<&mut Encoder>,
+) -> Result<usize, TransportError> {
+    match fragment_size.filter(|size| frame.len() > *size) {
+        Some(size) => {
+            for fragment in fragment::fragment(frame, id, size) {
+                send_datagram(link, &fragment, encoder.as_deref_mut())?;
+            }
+            Ok(frame.len())
+        }
+        None => send_datagram(link, frame, encoder),
+    }
+}
+
+/// Send datagram to the vswitch over link, as a data shard of encoder's
+/// group followed by the group's parity once it is full, if encoder is
+/// given, returning the number of bytes of the datagram which were sent
+fn send_datagram(
+    link: &VswitchLink,
+    datagram: &[u8],
+    encoder: Option<&mut Encoder>,
+) -> Result<usize, TransportError> {
+    let Some(encoder) = encoder else {
+        return link.send(datagram);
+    };
+    let (shard, parity) = encoder.encode(datagram, Instant::now());
+    link.send(&shard)?;
+    if let Some(parity) = parity {
+        link.send(&parity)?;
+    }
+    Ok(datagram.len())
+}
+
 /// Takes frames received from the vswitch in
 /// the L2VPN network and sends to the tap interface
 /// which will allow it to exit the emulated L2VPN network
-fn vswitch_to_tap(vport: &mut Vport) {
-    /* Buffer to store frames received from the vswitch */
-    let mut buf = [0u8; ETHER_MTU];
+///
+/// If the vport is multihomed, link_index identifies which vswitch
+/// vport.link leads to, and duplicates is shared by both vswitches'
+/// threads, to drop the copy of each frame which arrives second
+///
+/// Every frame received, and the vswitch's answer to our join, is recorded
+/// in its status, if it is watched
+///
+/// Frames which can't be written to the tap interface are counted and
+/// dropped. This returns once the vswitch closes the link, as nothing
+/// more will arrive over it, and returns an error if it can't be read
+fn vswitch_to_tap(
+    vport: &mut Vport,
+    link_index: usize,
+    duplicates: Option<&Mutex<DuplicateFilter>>,
+    status: Option<&VswitchStatus>,
+    heartbeat: &Heartbeat,
+) -> Result<(), TransportError> {
+    /* Buffer to store frames received from the vswitch, which is large enough for their tags */
+    let mut buf = [0u8; TUNNEL_DATAGRAM_MAX];
+
+    /* Frames which the vswitch has sent some of the fragments of */
+    let mut fragments = Reassembler::default();
+
+    /* Groups of FEC shards which the vswitch has sent some of */
+    let mut fec_groups = Decoder::default();
+
+    /* Datagrams from the last batch or FEC shard the vswitch sent which are yet to be handled */
+    let mut inner_frames: VecDeque<Vec<u8>> = VecDeque::new();
 
     /*
      * Main loop which takes packets received from the
      * vswitch and forwards them to the tap interface
      */
     loop {
-        /* Get virtual ethernet frame from socket */
-        let (bytes_read, _) = vport.sock.recv_from(&mut buf).unwrap();
+        /* Get virtual ethernet frame from the vswitch, or the batch or shard it arrived in */
+        let bytes_read = match inner_frames.pop_front() {
+            Some(frame) => {
+                buf[..frame.len()].copy_from_slice(&frame);
+                frame.len()
+            }
+            None => {
+                heartbeat.idle();
+                match vport.link.recv(&mut buf) {
+                    Ok(bytes_read) => bytes_read,
+                    Err(TransportError::Closed) => {
+                        println!("vswitch closed the link {:?}", vport.link);
+                        return Ok(());
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        };
+        let now = Instant::now();
+        heartbeat.busy(now);
 
-        /* Log any runt frames received, but do not terminate loop */
-        if bytes_read < ETHER_MIN {
-            eprintln!("Received runt frame which was {} bytes long", bytes_read);
+        /* Datagrams arrive as FEC shards, once the vswitch agrees, and lost ones are rebuilt */
+        if is_shard(&buf[..bytes_read]) {
+            match fec_groups.add((), &buf[..bytes_read], now) {
+                Some(datagrams) => {
+                    for datagram in datagrams.into_iter().rev() {
+                        inner_frames.push_front(datagram);
+                    }
+                }
+                None => eprintln!("Dropped FEC shard from the vswitch as it didn't fit its group"),
+            }
             continue;
         }
 
-        /* Forward virtual ethernet frame to tap interface */
-        let bytes_sent = vport.tap_file.write(&buf[..bytes_read]).unwrap();
+        /* Small frames arrive in batches, once the vswitch agrees */
+        if is_batch(&buf[..bytes_read]) {
+            match unbatch(&buf[..bytes_read]) {
+                Some(frames) => {
+                    for frame in frames.into_iter().rev() {
+                        inner_frames.push_front(frame.to_vec());
+                    }
+                }
+                None => eprintln!("Dropped batch from the vswitch as its frames overran it"),
+            }
+            continue;
+        }
 
-        /* If not all the bytes could be forwarded, fail */
-        if bytes_sent != bytes_read {
-            panic!(
-                "Received frame with {} bytes but forwarded it with {} bytes. Quitting.",
-                bytes_read, bytes_sent
-            );
+        /* Anything the vswitch sends, such as an echo request, shows it is still there */
+        if let Some(status) = status {
+            status
+                .liveness
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .heard(now);
+        }
+
+        /* Frames too large for the tunnel MTU arrive in fragments, once the vswitch agrees */
+        let bytes_read = match is_fragment(&buf[..bytes_read]) {
+            true => match fragments.add((), &buf[..bytes_read], now) {
+                Some(frame) => {
+                    buf[..frame.len()].copy_from_slice(&frame);
+                    frame.len()
+                }
+                None => continue,
+            },
+            false => bytes_read,
+        };
+
+        let bytes_read = match vport
+            .core
+            .from_vswitch(&mut buf, bytes_read, link_index, duplicates)
+        {
+            Action::Forward(len)
+                if vport
+                    .active
+                    .as_ref()
+                    .is_none_or(|active| active.load(Ordering::Relaxed) == link_index) =>
+            {
+                len
+            }
+            /* Frames flooded by the backups we aren't using are dropped, as hosts get them through the one we are */
+            Action::Forward(_) => continue,
+            /* Echo requests are answered, so the vswitch knows we are alive */
+            Action::Reply(reply) => {
+                if let Err(e) = vport.link.send(&reply) {
+                    eprintln!("Got error while sending echo reply to vswitch: '{}'", e);
+                }
+                continue;
+            }
+            /* Our join is answered once, though it may be sent again before the answer arrives */
+            Action::Joined {
+                port_id,
+                vlan,
+                capabilities,
+            } => {
+                let Some(status) = status else {
+                    continue;
+                };
+                let compressing = capabilities & CAP_COMPRESSION != 0;
+                let fragmenting = capabilities & CAP_FRAGMENTATION != 0;
+                let introducing = capabilities & CAP_DIRECT_PATHS != 0;
+                let batching = capabilities & CAP_BATCHING != 0;
+                let fec = capabilities & CAP_FEC != 0;
+                let telling = capabilities & CAP_ARP_SUPPRESSION != 0;
+                status.compressing.store(compressing, Ordering::Relaxed);
+                status.fragmenting.store(fragmenting, Ordering::Relaxed);
+                status.batching.store(batching, Ordering::Relaxed);
+                status.fec.store(fec, Ordering::Relaxed);
+                if !status.joined.swap(true, Ordering::Relaxed) {
+                    println!(
+                        "Joined the {} as port {}{}{}{}{}{}{}{}",
+                        status.name,
+                        port_id,
+                        vlan.map(|vlan| format!(", in VLAN {}", vlan))
+                            .unwrap_or_default(),
+                        match compressing {
+                            true => ", compressing frames with LZ4",
+                            false => "",
+                        },
+                        match fragmenting {
+                            true => ", fragmenting frames too large for the tunnel MTU",
+                            false => "",
+                        },
+                        match introducing {
+                            true => ", introducing us to other vports for direct paths",
+                            false => "",
+                        },
+                        match batching {
+                            true => ", batching small frames",
+                            false => "",
+                        },
+                        match fec {
+                            true => ", protecting datagrams with FEC",
+                            false => "",
+                        },
+                        match telling {
+                            true => ", telling us the addresses it learns from ARP",
+                            false => "",
+                        }
+                    );
+                    notify_systemd(&format!(
+                        "STATUS=Joined the {} as port {}",
+                        status.name, port_id
+                    ));
+                }
+                continue;
+            }
+            /* The thread probing the path MTU waits for its probes to be answered */
+            Action::ProbeAcked(id) => {
+                if let Some(status) = status {
+                    status.probe_acked.store(id, Ordering::Relaxed);
+                }
+                continue;
+            }
+            /* Direct paths are opened to the vports the vswitch introduces us to */
+            Action::Introduced {
+                session_id,
+                mac,
+                endpoint,
+            } => {
+                if let Some(paths) = &vport.direct {
+                    paths
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .introduce(session_id, mac, SocketAddr::V4(endpoint));
+                    log_frame!(
+                        "Introduced to session {:016x} at {}, which {} is behind",
+                        session_id,
+                        endpoint,
+                        mac
+                    );
+                }
+                continue;
+            }
+            /* A punch from a vport we were introduced to opens the path to it, and is answered */
+            Action::Punched { session_id, ack } => {
+                let Some(paths) = &vport.direct else {
+                    continue;
+                };
+                let heard = paths
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .heard(session_id, now);
+                let Some((endpoint, opened)) = heard else {
+                    continue;
+                };
+                if opened {
+                    println!("Opened a direct path to the vport at {}", endpoint);
+                }
+                if !ack {
+                    if let Err(e) = vport.link.send_to(&vport.core.punch(true), endpoint) {
+                        eprintln!(
+                            "Got error while answering punch from the vport at {}: '{}'",
+                            endpoint, e
+                        );
+                    }
+                }
+                continue;
+            }
+            Action::Drop => continue,
+        };
+
+        /* Forward virtual ethernet frame to tap interface, dropping it if that fails */
+        match write_tap(vport, &buf[..bytes_read]) {
+            Ok(bytes_sent) if bytes_sent == bytes_read => {}
+            Ok(bytes_sent) => {
+                vport.drops.tap.fetch_add(1, Ordering::Relaxed);
+                eprintln!(
+                    "Dropped frame of {} bytes as only {} bytes could be written to the tap interface",
+                    bytes_read, bytes_sent
+                );
+                continue;
+            }
+            Err(e) => {
+                vport.drops.tap.fetch_add(1, Ordering::Relaxed);
+                eprintln!(
+                    "Dropped frame as writing it to the tap interface failed: '{}'",
+                    e
+                );
+                continue;
+            }
         }
 
         /* Log frame */
-        println!(
+        log_frame!(
             "Received frame: {}",
-            get_frame_log_msg(&buf[..bytes_read], bytes_read)
+            FrameLogMsg(&buf[..bytes_read], bytes_read)
         );
     }
 }
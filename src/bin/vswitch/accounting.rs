@@ -0,0 +1,224 @@
+//! Accounting of the traffic through each port of the vswitch
+//!
+//! Each time a port comes up, a new accounting period starts. When
+//! it goes down (its vport leaves, disconnects or stops being heard
+//! from, or it is shut down), a record of how long it was up and the frames
+//! and bytes it received and sent in that time is written to the
+//! accounting file, or printed if there is no accounting file
+//!
+//! Ports can also be given a quota of bytes (received and sent),
+//! after which they are shut down, or rate limited, until an admin
+//! client resets their usage
+
+use crate::{
+    policer::Policer,
+    ports::{Port, PortCounters},
+    VportAddr,
+};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Frames per second which a port over its quota can send, when rate limited
+pub const QUOTA_FRAMES_PER_SEC: f64 = 100.0;
+
+/// What happens to a port which has used up its quota
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuotaAction {
+    /// Frames from the port are limited to QUOTA_FRAMES_PER_SEC
+    RateLimit,
+    /// The port is shut down, as an admin client could
+    Shutdown,
+}
+
+impl QuotaAction {
+    /// Parse the value of --quota-action
+    pub fn parse(value: &str) -> Option<QuotaAction> {
+        match value {
+            "rate-limit" => Some(QuotaAction::RateLimit),
+            "shutdown" => Some(QuotaAction::Shutdown),
+            _ => None,
+        }
+    }
+}
+
+/// Usage of a port in its current accounting period, and against its quota
+#[derive(Debug)]
+pub struct Usage {
+    /// When the accounting period started
+    started: Instant,
+    started_at: SystemTime,
+    /// Counters of the port when the accounting period started
+    base: PortCounters,
+    /// Bytes received and sent by the port when its quota was last reset
+    quota_base: u64,
+    /// True if the port has gone over its quota since it was last reset
+    over_quota: bool,
+    /// Limits the frames from the port once it is over its quota
+    policer: Option<Policer>,
+}
+
+impl Usage {
+    /// Returns the usage of a port with counters, starting now
+    pub fn new(counters: &PortCounters) -> Usage {
+        Usage {
+            started: Instant::now(),
+            started_at: SystemTime::now(),
+            base: *counters,
+            quota_base: counters.rx_bytes + counters.tx_bytes,
+            over_quota: false,
+            policer: None,
+        }
+    }
+
+    /// Start a new accounting period for a port with counters,
+    /// which has just come up
+    pub fn restart(&mut self, counters: &PortCounters) {
+        self.started = Instant::now();
+        self.started_at = SystemTime::now();
+        self.base = *counters;
+    }
+
+    /// Returns how long the accounting period has lasted so far
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Returns the bytes received and sent by a port with
+    /// counters in the accounting period so far
+    pub fn bytes(&self, counters: &PortCounters) -> (u64, u64) {
+        (
+            counters.rx_bytes - self.base.rx_bytes,
+            counters.tx_bytes - self.base.tx_bytes,
+        )
+    }
+
+    /// Returns the bytes received and sent by a port with
+    /// counters, since its quota was last reset
+    pub fn quota_used(&self, counters: &PortCounters) -> u64 {
+        (counters.rx_bytes + counters.tx_bytes).saturating_sub(self.quota_base)
+    }
+
+    /// Returns true if the port has gone over its quota since it was last reset
+    pub fn is_over_quota(&self) -> bool {
+        self.over_quota
+    }
+
+    /// Exclude counters, which have just been added to those of a port
+    /// (as they were counted before the vswitch restarted), from its usage
+    pub fn exclude(&mut self, counters: &PortCounters) {
+        self.base.add(counters);
+        self.quota_base += counters.rx_bytes + counters.tx_bytes;
+    }
+
+    /// Returns true if a frame from the port is allowed, which
+    /// it isn't if the port is over its rate limited quota
+    pub fn allow(&mut self) -> bool {
+        match &mut self.policer {
+            Some(policer) => policer.allow(),
+            None => true,
+        }
+    }
+
+    /// Reset the usage of a port with counters against its quota, lifting
+    /// its rate limit, and return true if it had gone over its quota
+    pub fn reset_quota(&mut self, counters: &PortCounters) -> bool {
+        self.quota_base = counters.rx_bytes + counters.tx_bytes;
+        self.policer = None;
+        std::mem::take(&mut self.over_quota)
+    }
+}
+
+/// Writes accounting records, and enforces the quota of each port
+pub struct Accounting {
+    file: Option<File>,
+    quota: Option<(u64, QuotaAction)>,
+}
+
+impl Accounting {
+    /// Returns an Accounting which appends records to the file at
+    /// path (if any), and gives each port a quota of bytes (if any)
+    pub fn open(path: Option<&str>, quota: Option<(u64, QuotaAction)>) -> io::Result<Accounting> {
+        let file = match path {
+            Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+            None => None,
+        };
+
+        Ok(Accounting { file, quota })
+    }
+
+    /// Returns the quota of bytes each port is given, and what
+    /// happens to ports over it, if there is a quota
+    pub fn quota(&self) -> Option<(u64, QuotaAction)> {
+        self.quota
+    }
+
+    /// Write the record of the accounting period of the port of the
+    /// vport at addr, which has just gone down for reason, one line of
+    /// space separated fields:
+    ///
+    /// <start_secs_since_epoch> <end_secs_since_epoch> <port_id> <vport>
+    /// <session_id or -> <duration_ms> <rx_frames> <rx_bytes> <tx_frames>
+    /// <tx_bytes> <reason>
+    pub fn record(&mut self, addr: &VportAddr, port: &Port, reason: &str) {
+        let usage = &port.usage;
+        let secs = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        };
+        let session = match port.session_id {
+            Some(session_id) => format!("{:016x}", session_id),
+            None => "-".to_string(),
+        };
+        let counters = &port.counters;
+        let (rx_bytes, tx_bytes) = usage.bytes(counters);
+
+        let record = format!(
+            "{} {} {} {} {} {} {} {} {} {} {}",
+            secs(usage.started_at),
+            secs(SystemTime::now()),
+            port.id,
+            addr,
+            session,
+            usage.elapsed().as_millis(),
+            counters.rx_frames - usage.base.rx_frames,
+            rx_bytes,
+            counters.tx_frames - usage.base.tx_frames,
+            tx_bytes,
+            reason
+        );
+
+        let Some(file) = &mut self.file else {
+            println!("Accounting: {}", record);
+            return;
+        };
+
+        /* Losing a record is not a reason to stop switching */
+        if let Err(e) = writeln!(file, "{}", record) {
+            eprintln!("Got error while writing accounting record: {}", e);
+        }
+    }
+
+    /// Check the quota of port, returning the action to take if it has
+    /// just gone over it. Ports which are rate limited are then policed
+    /// by allow()
+    ///
+    /// A port is only acted on once each time it goes over its quota,
+    /// so one which an admin client brings back up without resetting
+    /// its usage carries on past its quota
+    pub fn check_quota(&self, port: &mut Port) -> Option<QuotaAction> {
+        let (quota, action) = self.quota?;
+        if port.usage.over_quota || port.usage.quota_used(&port.counters) <= quota {
+            return None;
+        }
+
+        port.usage.over_quota = true;
+        if action == QuotaAction::RateLimit {
+            port.usage.policer = Some(Policer::new(QUOTA_FRAMES_PER_SEC, QUOTA_FRAMES_PER_SEC));
+        }
+        Some(action)
+    }
+}
@@ -0,0 +1,1556 @@
+//! Admin socket for the vswitch
+//!
+//! Admin clients (such as vswitchctl) send commands using the
+//! protocol in l2vpn::admin. Commands are executed by the
+//! switching loop, so they see a consistent view of its state
+//!
+//! The exception is monitor, which streams a line per frame
+//! until the client disconnects, without a terminating "."
+//!
+//! Commands such as set, shutdown and acl change the running
+//! vswitch's settings, which apply from the next frame onwards
+
+use crate::{
+    accounting::{Accounting, QuotaAction},
+    bum::BumGroup,
+    chaos::Chaos,
+    dhcp::DhcpServer,
+    events::EventLog,
+    igmp::IgmpSnooper,
+    latency,
+    mac_moves::{parse_move_rate, MacMoveAction, MacMoveLimit},
+    mirror::{Direction, Mirrors},
+    monitor::Monitors,
+    policer::{Policer, RateLimit, RateLimiter, ADMIN_COMMANDS_BURST, ADMIN_COMMANDS_PER_SEC},
+    port_security::{MacLimit, MacLimitAction},
+    ports::PortTable,
+    qos::{EgressQueues, Scheduler},
+    recorder::FlightRecorder,
+    reflector::Reflector,
+    segment_peers,
+    settings::{parse_on_off, AclAction, AclBinding, AclRule, Settings},
+    sizes::SIZE_BUCKETS,
+    stp::SpanningTree,
+    topology, trace,
+    vlan::{parse_vlan_id, Domain, PortVlan},
+    MacTables, RxEvent, VportAddr, Vports, DEFAULT_SEGMENT,
+};
+use l2vpn::{
+    admin::{END_OF_RESPONSE, ERROR_PREFIX},
+    control::capability_names,
+    filter::{Filter, FILTER_WORDS},
+    mac::MacAddr,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    sync::mpsc::{self, Sender},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+const HELP: &str = "Commands:
+  help                       Show this message
+  show mac-table             Show the learned MACs and the ports they were learned on
+  show ports                 Show the connected vports, their sessions and counters
+  show drops                 Show how many frames from each port were dropped, and why
+  show events                Show recent events, such as vports connecting and MACs moving
+  show latency               Show the 50th, 95th and 99th percentile forwarding latency
+                             and round trip time of each port
+  show sizes                 Show how many frames of each size were received from each port
+  show settings              Show the MAC aging, learning, flooding, MAC limit, MAC move
+                             limit and storm control settings, the ACL and the static MACs
+  show usage                 Show the traffic through each port since it came up, and
+                             against its quota
+  show dhcp                  Show the built-in DHCP server's pool, reservations and leases
+  show chaos                 Show the faults chaos mode has injected, and its seed
+  show reflector             Show the discovery protocols reflected between segments,
+                             and how many frames were reflected and filtered
+  show recorder              Show the frames the flight recorder keeps for each port
+  show vlans                 Show each VLAN's MAC count and access ports
+  show bum-group             Show the underlay multicast group flooded frames are sent
+                             through, and the vports which have joined it
+  show stp                   Show the root bridge of each segment's spanning tree, and
+                             the role and state of each port
+  show mirrors               Show the ports which are mirrored, where to, and how many
+                             frames have been copied
+  show igmp                  Show the vports which have joined each multicast group, and
+                             the router ports, learned by IGMP snooping
+  show lags                  Show the ports whose vports reach the vswitch over several
+                             links, and the address of each link
+  show split-horizon         Show the ports in each split-horizon group
+  show registrations         Show the ports whose vports have joined the vswitch, what
+                             they can do, the VLAN they asked to be in, and the public
+                             endpoint they found with STUN
+  show rate-limits           Show each port's ingress and egress rate limits, and how many
+                             frames they have dropped
+  show qos                   Show the frames waiting in, sent from and dropped by each
+                             priority's egress queue of each port with queues
+  stats                      Show the state shown by 'vswitchctl top', in a form for programs
+  trace <key=value>...       Show how the vswitch would handle a frame, described by
+                             in_port=<port_id> src=<mac> dst=<mac> [vlan=<vlan_id>]
+                             [type=<ethertype>] [ip_src=<ip>] [ip_dst=<ip>] [ip_proto=<proto>]
+  monitor [<filter>]         Show a summary of every frame matching the filter, until
+                             disconnected. Filters are made of tcpdump style terms, i.e.
+                             [not] src|dst|mac <mac>, type <ethertype>, arp, ip, ip6,
+                             vlan [<vlan_id>], broadcast, multicast, port <port_id>,
+                             joined by 'and'
+  set mac-aging <secs>|off   Age out learned MACs which haven't sent a frame for <secs>,
+                             or keep them until they move (the default)
+  set learning on|off        Learn the source MACs of frames (the default), or don't
+  set flooding on|off        Flood frames to unknown unicast and multicast MACs like
+                             broadcasts, or drop them (the default)
+  set mac-limit <n> [drop|log|shutdown]|off
+                             Limit the MACs learned on each vport to <n>, and drop frames
+                             from further MACs (the default), only log them, or shut the
+                             port down, or don't limit them (the default)
+  set mac-move-limit <moves>/<secs> [log|dampen|shutdown]|off
+                             Find MACs moving between vports more often than the given
+                             rate, and record it (the default), hold the MAC on the port
+                             it moved from, or shut the port it moved to down, or don't
+                             look for them (the default)
+  set storm-control <frames_per_sec>|off
+                             Drop the frames each vport floods beyond the given rate, or
+                             don't limit them (the default)
+  shutdown <port_id>         Stop receiving frames from and sending frames to a port,
+                             and flush its MACs
+  no-shutdown <port_id>      Bring a port which was shut down back up
+  acl add [in|out <port_id>] permit|deny <filter>
+                             Add a rule to the end of the ACL, using monitor's filters,
+                             which the frames received from every port, or received from
+                             or sent to the given port, are checked against in order
+  acl remove <index>         Remove the rule with the given index from the ACL
+  static-mac add <mac> <port_id> [<vlan_id>]
+                             Add a MAC which is never learned elsewhere, aged or flushed
+  static-mac remove <mac>    Remove a static MAC, which can then be learned again
+  vlan <port_id> access <vlan_id>
+                             Carry only the given VLAN on a port, untagged
+  vlan <port_id> tunnel <svlan_id>
+                             Put every frame from a port in the given S-VLAN, by pushing
+                             an 802.1ad tag in front of its own tags, popped on egress
+  vlan <port_id> trunk [native <vlan_id>] [allowed <vlan_list>]
+                             Carry VLANs on a port tagged, which are every VLAN (the
+                             default) or those allowed, such as 10,20-29, and put its
+                             untagged frames in the native VLAN, if it has one
+  mirror <port_id> rx|tx|both <dst_port_id>
+                             Copy the frames received from a port, sent to it, or both,
+                             to another port, such as a capture host's
+  no-mirror <port_id>        Stop mirroring a port
+  split-horizon <port_id> <group_id>
+                             Put a port in a split-horizon group, so frames received from
+                             it are never sent to the other ports in the group
+  no-split-horizon <port_id> Take a port out of its split-horizon group
+  rate-limit <port_id> in|out [pps <n>] [bps <n>]
+                             Limit the frames, bits, or both, per second which a port
+                             can receive, or be sent, dropping the frames beyond that
+  no-rate-limit <port_id> [in|out]
+                             Remove a port's ingress or egress rate limit, or both
+  qos <port_id> strict|weighted [<weights>]
+                             Queue the frames sent to a port by 802.1p priority while it
+                             is over its egress rate limit, and send them highest priority
+                             first, or each priority in turn, up to its weight of frames,
+                             where the weights of priorities 0 to 7 are 2,1,3,4,5,6,7,8
+                             unless given
+  no-qos <port_id>           Remove a port's egress queues, dropping any waiting frames
+  recorder dump <port_id>|all <path>
+                             Write the frames the flight recorder keeps for a port, or
+                             every port, to a pcap file
+  reset-quota <port_id>      Reset a port's usage against its quota, lifting its rate
+                             limit, or bringing it back up if it was shut down for it
+  complete <partial_command> List the words which could complete a partial command";
+
+/// Error for an acl command which is neither an add nor a remove
+const ACL_USAGE: &str =
+    "Expected 'acl add [in|out <port_id>] permit|deny <filter>' or 'acl remove <index>'";
+
+/// Commands, and the words which can follow them, used to complete partial commands
+const COMPLETIONS: [(&str, &[&str]); 22] = [
+    ("help", &[]),
+    (
+        "show",
+        &[
+            "mac-table",
+            "ports",
+            "drops",
+            "events",
+            "latency",
+            "sizes",
+            "settings",
+            "usage",
+            "dhcp",
+            "chaos",
+            "reflector",
+            "recorder",
+            "vlans",
+            "bum-group",
+            "stp",
+            "igmp",
+            "mirrors",
+            "lags",
+            "split-horizon",
+            "registrations",
+            "rate-limits",
+            "qos",
+        ],
+    ),
+    ("stats", &[]),
+    ("trace", &trace::TRACE_KEYS),
+    ("monitor", &FILTER_WORDS),
+    (
+        "set",
+        &[
+            "mac-aging",
+            "learning",
+            "flooding",
+            "mac-limit",
+            "mac-move-limit",
+            "storm-control",
+        ],
+    ),
+    ("shutdown", &[]),
+    ("no-shutdown", &[]),
+    ("acl", &["add", "remove"]),
+    ("static-mac", &["add", "remove"]),
+    ("reset-quota", &[]),
+    ("vlan", &[]),
+    ("mirror", &[]),
+    ("no-mirror", &[]),
+    ("split-horizon", &[]),
+    ("no-split-horizon", &[]),
+    ("rate-limit", &[]),
+    ("no-rate-limit", &[]),
+    ("qos", &[]),
+    ("no-qos", &[]),
+    ("recorder", &["dump"]),
+    ("complete", &[]),
+];
+
+/// State of the switching loop which admin commands see and change
+pub struct SwitchState<'a> {
+    pub mac_tables: &'a mut MacTables,
+    pub ports: &'a mut PortTable<VportAddr>,
+    pub peers: &'a [VportAddr],
+    pub settings: &'a mut Settings,
+    pub events: &'a mut EventLog,
+    pub vports: &'a Vports,
+    pub accounting: &'a mut Accounting,
+    pub dhcp: Option<&'a DhcpServer>,
+    pub chaos: Option<&'a Chaos>,
+    pub reflector: Option<&'a Reflector>,
+    pub recorder: Option<&'a FlightRecorder>,
+    pub bum_group: Option<&'a BumGroup>,
+    pub stp: Option<&'a SpanningTree>,
+    pub snooper: Option<&'a IgmpSnooper>,
+    pub mirrors: &'a mut Mirrors,
+}
+
+/// Accept admin clients, and start a thread to serve each of them
+pub fn admin_listener(listener: UnixListener, rx_tx: Sender<RxEvent>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Failed to accept admin client: {}", e);
+                continue;
+            }
+        };
+
+        let admin_tx = rx_tx.clone();
+        thread::spawn(move || {
+            if let Err(e) = admin_client(stream, admin_tx) {
+                eprintln!("Got error while serving admin client: {}", e);
+            }
+        });
+    }
+}
+
+/// Pass each command from an admin client to the switching
+/// loop, and send its response back, until the client disconnects
+fn admin_client(stream: UnixStream, rx_tx: Sender<RxEvent>) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut policer = Policer::new(ADMIN_COMMANDS_PER_SEC, ADMIN_COMMANDS_BURST);
+
+    for line in BufReader::new(stream).lines() {
+        let command = line?;
+        if command.trim().is_empty() {
+            continue;
+        }
+
+        /* Refuse commands beyond the client's rate, without bothering the switching loop */
+        if !policer.allow() {
+            writeln!(
+                writer,
+                "{}Too many commands, try again later\n{}",
+                ERROR_PREFIX, END_OF_RESPONSE
+            )?;
+            continue;
+        }
+
+        let monitoring = command.split_whitespace().next() == Some("monitor");
+
+        let (reply_tx, reply_rx) = mpsc::channel();
+        if rx_tx.send(RxEvent::Admin(command, reply_tx)).is_err() {
+            /* The switching loop has stopped */
+            return Ok(());
+        }
+
+        /* Stream frame summaries until the client goes away, unless the filter was invalid */
+        if monitoring {
+            for summary in reply_rx.iter() {
+                if summary.starts_with(ERROR_PREFIX) {
+                    writeln!(writer, "{}\n{}", summary, END_OF_RESPONSE)?;
+                    break;
+                }
+                writeln!(writer, "{}", summary)?;
+            }
+            continue;
+        }
+
+        let Ok(response) = reply_rx.recv() else {
+            return Ok(());
+        };
+
+        writeln!(writer, "{}\n{}", response, END_OF_RESPONSE)?;
+    }
+
+    Ok(())
+}
+
+/// Execute an admin command against the switching loop's
+/// state, and send the response to the client over reply_tx
+pub fn execute(
+    command: &str,
+    reply_tx: Sender<String>,
+    state: SwitchState,
+    monitors: &mut Monitors,
+) {
+    let SwitchState {
+        mac_tables,
+        ports,
+        peers,
+        settings,
+        events,
+        vports,
+        accounting,
+        dhcp,
+        chaos,
+        reflector,
+        recorder,
+        bum_group,
+        stp,
+        snooper,
+        mirrors,
+    } = state;
+
+    /* The trailing whitespace of a partial command matters, so it is passed on unsplit */
+    if let Some(partial) = command.trim_start().strip_prefix("complete ") {
+        let _ = reply_tx.send(complete(partial).join("\n"));
+        return;
+    }
+
+    let words: Vec<&str> = command.split_whitespace().collect();
+
+    /* Monitors keep the sender, to send frame summaries to */
+    if let ["monitor", filter @ ..] = words.as_slice() {
+        match Filter::parse(filter) {
+            Ok(filter) => monitors.add(filter, reply_tx),
+            Err(e) => {
+                let _ = reply_tx.send(format!("{}{}", ERROR_PREFIX, e));
+            }
+        }
+        return;
+    }
+
+    let result = match words.as_slice() {
+        ["help"] => Ok(HELP.to_string()),
+        ["complete"] => Ok(complete("").join("\n")),
+        ["show", "mac-table"] => Ok(show_mac_table(mac_tables, ports)),
+        ["show", "ports"] => Ok(show_ports(ports)),
+        ["show", "drops"] => Ok(show_drops(ports)),
+        ["show", "events"] => Ok(show_events(events)),
+        ["show", "latency"] => Ok(show_latency(ports)),
+        ["show", "sizes"] => Ok(show_sizes(ports)),
+        ["show", "settings"] => Ok(show_settings(settings, mac_tables, ports)),
+        ["show", "usage"] => Ok(show_usage(ports, accounting)),
+        ["show", "dhcp"] => dhcp
+            .map(DhcpServer::show)
+            .ok_or_else(|| "The DHCP server is not running".to_string()),
+        ["show", "chaos"] => chaos
+            .map(Chaos::show)
+            .ok_or_else(|| "Chaos mode is off".to_string()),
+        ["show", "reflector"] => reflector
+            .map(Reflector::show)
+            .ok_or_else(|| "Nothing is being reflected".to_string()),
+        ["show", "recorder"] => recorder
+            .map(FlightRecorder::show)
+            .ok_or_else(|| "The flight recorder is off".to_string()),
+        ["show", "vlans"] => Ok(show_vlans(mac_tables, ports, vports)),
+        ["show", "bum-group"] => bum_group
+            .map(|bum_group| bum_group.show(ports))
+            .ok_or_else(|| "No BUM group is configured".to_string()),
+        ["show", "stp"] => stp
+            .map(|stp| stp.show(ports))
+            .ok_or_else(|| "STP is off".to_string()),
+        ["show", "mirrors"] => Ok(mirrors.show(ports)),
+        ["show", "lags"] => Ok(vports.lags.show(ports)),
+        ["show", "split-horizon"] => Ok(show_split_horizon(ports)),
+        ["show", "registrations"] => Ok(show_registrations(ports)),
+        ["show", "rate-limits"] => Ok(show_rate_limits(ports)),
+        ["show", "qos"] => Ok(show_qos(ports)),
+        ["show", "igmp"] => snooper
+            .map(|snooper| snooper.show(ports, Instant::now()))
+            .ok_or_else(|| "IGMP snooping is off".to_string()),
+        ["show", ..] => Err("Expected 'show mac-table', 'show ports', 'show drops', \
+                             'show events', 'show latency', 'show sizes', 'show settings', \
+                             'show usage', 'show dhcp', 'show chaos', 'show reflector', \
+                             'show recorder', 'show vlans', 'show bum-group', 'show stp', \
+                             'show igmp', 'show mirrors', 'show lags', \
+                             'show split-horizon', 'show registrations', \
+                             'show rate-limits' or 'show qos'"
+            .to_string()),
+        ["stats"] => Ok(stats(mac_tables, ports, events)),
+        ["trace", args @ ..] => {
+            trace::trace(args, mac_tables, ports, peers, settings, vports, snooper)
+        }
+        ["set", args @ ..] => set(args, settings).map(|change| {
+            events.record(format!("Admin {}", change));
+            change
+        }),
+        ["shutdown", id] => find_port(ports, id).map(|addr| {
+            let port = ports.get_mut(&addr).unwrap();
+            let id = port.id;
+            if port.shutdown {
+                return format!("Port {} is already shut down", id);
+            }
+            port.shutdown = true;
+            if !port.down {
+                accounting.record(&addr, port, "shutdown");
+            }
+            let segment = vports.segment(&addr);
+            topology::port_down(
+                &addr,
+                format!("Admin shut down port {} ({})", id, addr),
+                mac_tables,
+                &settings.static_macs,
+                segment_peers(peers, segment),
+                vports,
+                events,
+            );
+            format!("Shut down port {}", id)
+        }),
+        ["no-shutdown", id] => find_port(ports, id).map(|addr| {
+            let port = ports.get_mut(&addr).unwrap();
+            let id = port.id;
+            if !port.shutdown {
+                return format!("Port {} is not shut down", id);
+            }
+            port.shutdown = false;
+            port.usage.restart(&port.counters);
+            events.record(format!("Admin brought port {} ({}) back up", id, addr));
+            format!("Brought port {} back up", id)
+        }),
+        ["reset-quota", id] => find_port(ports, id).map(|addr| {
+            let port = ports.get_mut(&addr).unwrap();
+            let id = port.id;
+            if !port.usage.reset_quota(&port.counters) {
+                return format!("Reset the quota of port {}", id);
+            }
+
+            /* The port is only shut down by its quota if the quota action is shutdown */
+            let action = accounting.quota().map(|(_, action)| action);
+            if port.shutdown && action == Some(QuotaAction::Shutdown) {
+                port.shutdown = false;
+                port.usage.restart(&port.counters);
+            }
+            events.record(format!("Admin reset the quota of port {} ({})", id, addr));
+            format!("Reset the quota of port {}, which had gone over it", id)
+        }),
+        ["shutdown" | "no-shutdown" | "reset-quota", ..] => Err("Expected a port ID".to_string()),
+        ["acl", args @ ..] => acl(args, settings, ports).map(|change| {
+            events.record(format!("Admin {}", change));
+            change
+        }),
+        ["static-mac", args @ ..] => {
+            static_mac(args, settings, mac_tables, ports, vports).map(|change| {
+                events.record(format!("Admin {}", change));
+                change
+            })
+        }
+        ["vlan", args @ ..] => port_vlan(args, mac_tables, ports, peers, settings, vports, events),
+        ["mirror", args @ ..] => mirror(args, mirrors, ports, peers).map(|change| {
+            events.record(format!("Admin {}", change));
+            change
+        }),
+        ["no-mirror", id] => find_port(ports, id).and_then(|addr| {
+            let id = ports.get(&addr).map_or(0, |port| port.id);
+            if !mirrors.remove(&addr) {
+                return Err(format!("Port {} is not mirrored", id));
+            }
+            events.record(format!("Admin stopped mirroring port {} ({})", id, addr));
+            Ok(format!("Stopped mirroring port {}", id))
+        }),
+        ["no-mirror", ..] => Err("Expected a port ID".to_string()),
+        ["split-horizon", id, group] => find_port(ports, id).and_then(|addr| {
+            let group = group
+                .parse()
+                .map_err(|_| format!("Invalid split-horizon group '{}'", group))?;
+            let port = ports.get_mut(&addr).unwrap();
+            let id = port.id;
+            if port.split_horizon.replace(group) == Some(group) {
+                return Ok(format!(
+                    "Port {} is already in split-horizon group {}",
+                    id, group
+                ));
+            }
+            events.record(format!(
+                "Admin put port {} ({}) in split-horizon group {}",
+                id, addr, group
+            ));
+            Ok(format!("Put port {} in split-horizon group {}", id, group))
+        }),
+        ["split-horizon", ..] => Err("Expected 'split-horizon <port_id> <group_id>'".to_string()),
+        ["no-split-horizon", id] => find_port(ports, id).and_then(|addr| {
+            let port = ports.get_mut(&addr).unwrap();
+            let id = port.id;
+            let Some(group) = port.split_horizon.take() else {
+                return Err(format!("Port {} is not in a split-horizon group", id));
+            };
+            events.record(format!(
+                "Admin took port {} ({}) out of split-horizon group {}",
+                id, addr, group
+            ));
+            Ok(format!(
+                "Took port {} out of split-horizon group {}",
+                id, group
+            ))
+        }),
+        ["no-split-horizon", ..] => Err("Expected a port ID".to_string()),
+        ["rate-limit", args @ ..] => rate_limit(args, ports).map(|change| {
+            events.record(format!("Admin {}", change));
+            change
+        }),
+        ["no-rate-limit", id, direction @ ..] if direction.len() <= 1 => find_port(ports, id)
+            .and_then(|addr| {
+                let port = ports.get_mut(&addr).unwrap();
+                let id = port.id;
+                let removed = match direction {
+                    ["in"] => port.rx_limit.take().is_some(),
+                    ["out"] => port.tx_limit.take().is_some(),
+                    [] => port.rx_limit.take().is_some() | port.tx_limit.take().is_some(),
+                    _ => return Err("Expected 'no-rate-limit <port_id> [in|out]'".to_string()),
+                };
+                if !removed {
+                    return Err(format!("Port {} has no such rate limit", id));
+                }
+                events.record(format!(
+                    "Admin removed the rate limit of port {} ({})",
+                    id, addr
+                ));
+                Ok(format!("Removed the rate limit of port {}", id))
+            }),
+        ["no-rate-limit", ..] => Err("Expected 'no-rate-limit <port_id> [in|out]'".to_string()),
+        ["qos", id, scheduler @ ..] => find_port(ports, id).and_then(|addr| {
+            let scheduler = Scheduler::parse(scheduler)?;
+            let port = ports.get_mut(&addr).unwrap();
+            let id = port.id;
+
+            /* Frames already waiting keep their place */
+            match &mut port.queues {
+                Some(queues) => queues.set_scheduler(scheduler),
+                None => port.queues = Some(EgressQueues::new(scheduler)),
+            }
+            events.record(format!(
+                "Admin gave port {} ({}) egress queues, served by {}",
+                id, addr, scheduler
+            ));
+            Ok(format!(
+                "Gave port {} egress queues, served by {}",
+                id, scheduler
+            ))
+        }),
+        ["qos", ..] => Err("Expected 'qos <port_id> strict|weighted [<weights>]'".to_string()),
+        ["no-qos", id] => find_port(ports, id).and_then(|addr| {
+            let port = ports.get_mut(&addr).unwrap();
+            let id = port.id;
+            let Some(queues) = port.queues.take() else {
+                return Err(format!("Port {} has no egress queues", id));
+            };
+            events.record(format!(
+                "Admin removed the egress queues of port {} ({})",
+                id, addr
+            ));
+            Ok(format!(
+                "Removed the egress queues of port {}, dropping {} waiting frame(s)",
+                id,
+                queues.len()
+            ))
+        }),
+        ["no-qos", ..] => Err("Expected a port ID".to_string()),
+        ["recorder", args @ ..] => match recorder {
+            Some(recorder) => dump_recorder(args, recorder),
+            None => Err("The flight recorder is off".to_string()),
+        },
+        [command, ..] => Err(format!("Unknown command '{}', try 'help'", command)),
+        [] => Err("Empty command".to_string()),
+    };
+
+    let _ = reply_tx.send(match result {
+        Ok(response) => response,
+        Err(e) => format!("{}{}", ERROR_PREFIX, e),
+    });
+}
+
+/// Returns the words which could complete the last word of partial
+fn complete(partial: &str) -> Vec<String> {
+    let mut words: Vec<&str> = partial.split_whitespace().collect();
+
+    /* If the partial command ends in whitespace, a new word is being started */
+    let current = match partial.ends_with(char::is_whitespace) {
+        true => "",
+        false => words.pop().unwrap_or(""),
+    };
+
+    let candidates: Vec<&str> = match words.as_slice() {
+        [] => COMPLETIONS.iter().map(|(command, _)| *command).collect(),
+        ["monitor", ..]
+        | ["acl", "add", "permit" | "deny", ..]
+        | ["acl", "add", "in" | "out", _, _, ..] => FILTER_WORDS.to_vec(),
+        ["acl", "add"] => vec!["in", "out", "permit", "deny"],
+        ["acl", "add", "in" | "out", _] => vec!["permit", "deny"],
+        ["set", "learning" | "flooding"] => vec!["on", "off"],
+        ["set", "mac-aging" | "mac-limit" | "mac-move-limit" | "storm-control"] => vec!["off"],
+        ["set", "mac-limit", _] => vec!["drop", "log", "shutdown"],
+        ["set", "mac-move-limit", _] => vec!["log", "dampen", "shutdown"],
+        ["vlan", _] => vec!["access", "tunnel", "trunk"],
+        /* Trunk options can be given in either order, but only once each */
+        ["vlan", _, "trunk", options @ ..] if options.len() % 2 == 0 => ["native", "allowed"]
+            .into_iter()
+            .filter(|option| !options.contains(option))
+            .collect(),
+        ["mirror", _] => vec!["rx", "tx", "both"],
+        ["rate-limit" | "no-rate-limit", _] => vec!["in", "out"],
+        ["qos", _] => vec!["strict", "weighted"],
+        /* Rates can be given in either order, but only once each */
+        ["rate-limit", _, _, rates @ ..] if rates.len() % 2 == 0 => ["pps", "bps"]
+            .into_iter()
+            .filter(|unit| !rates.contains(unit))
+            .collect(),
+        /* Trace keys can be given in any order, but only once each */
+        ["trace", ..] => trace::TRACE_KEYS
+            .iter()
+            .filter(|key| !words.iter().any(|word| word.starts_with(*key)))
+            .copied()
+            .collect(),
+        [command] => COMPLETIONS
+            .iter()
+            .find(|(name, _)| name == command)
+            .map(|(_, args)| args.to_vec())
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    };
+
+    candidates
+        .into_iter()
+        .filter(|candidate| candidate.starts_with(current))
+        .map(String::from)
+        .collect()
+}
+
+/// Returns the address of the vport connected as the port with the given ID
+fn find_port(ports: &PortTable<VportAddr>, id: &str) -> Result<VportAddr, String> {
+    let id = id
+        .parse()
+        .map_err(|_| format!("Invalid port ID '{}'", id))?;
+    ports
+        .find(id)
+        .ok_or_else(|| format!("No vport is connected as port {}", id))
+}
+
+/// Mirror the frames of a port to another, returning a description of the change
+fn mirror(
+    args: &[&str],
+    mirrors: &mut Mirrors,
+    ports: &PortTable<VportAddr>,
+    peers: &[VportAddr],
+) -> Result<String, String> {
+    let [src, direction, dst] = args else {
+        return Err("Expected 'mirror <port_id> rx|tx|both <dst_port_id>'".to_string());
+    };
+    let direction = Direction::parse(direction)
+        .ok_or_else(|| format!("Expected 'rx', 'tx' or 'both', not '{}'", direction))?;
+    let (src, dst) = (find_port(ports, src)?, find_port(ports, dst)?);
+    let id = |addr: &VportAddr| ports.get(addr).map_or(0, |port| port.id);
+
+    if src == dst {
+        return Err("A port can't be mirrored to itself".to_string());
+    }
+    /* Peers would forward the copies on, as if they were real frames */
+    if peers.contains(&dst) {
+        return Err(format!(
+            "Port {} is a peer vswitch, so can't be mirrored to",
+            id(&dst)
+        ));
+    }
+
+    let frames = match direction {
+        Direction::Rx => "received",
+        Direction::Tx => "sent",
+        Direction::Both => "received and sent",
+    };
+    let mirror = format!("copying the frames it has {} to port {}", frames, id(&dst));
+    Ok(match mirrors.add(src, direction, dst) {
+        true => format!(
+            "changed the mirror of port {} ({}) to {}",
+            id(&src),
+            src,
+            mirror
+        ),
+        false => format!("mirrored port {} ({}), {}", id(&src), src, mirror),
+    })
+}
+
+/// Write the frames the flight recorder keeps for a port, or
+/// every port, to a pcap file, returning how many were written
+fn dump_recorder(args: &[&str], recorder: &FlightRecorder) -> Result<String, String> {
+    let ["dump", port, path] = args else {
+        return Err("Expected 'recorder dump <port_id>|all <path>'".to_string());
+    };
+
+    /* Ports which have gone away still have the frames they sent kept */
+    let port_id = match *port {
+        "all" => None,
+        id => Some(
+            id.parse::<u32>()
+                .map_err(|_| format!("Invalid port ID '{}'", id))?,
+        ),
+    };
+    if port_id.is_some_and(|id| !recorder.has_port(id)) {
+        return Err(format!("No frames have been kept for port {}", port));
+    }
+
+    let frames = recorder
+        .dump(port_id, Path::new(path))
+        .map_err(|e| format!("Got error while writing '{}': {}", path, e))?;
+    Ok(format!("Wrote {} frame(s) to '{}'", frames, path))
+}
+
+/// Change one of the settings, returning a description of the change
+fn set(args: &[&str], settings: &mut Settings) -> Result<String, String> {
+    match args {
+        ["mac-aging", "off"] => {
+            settings.mac_aging = None;
+            Ok("turned MAC aging off".to_string())
+        }
+        ["mac-aging", secs] => match secs.parse::<u64>() {
+            Ok(secs) if secs > 0 => {
+                settings.mac_aging = Some(Duration::from_secs(secs));
+                Ok(format!("set MAC aging to {}s", secs))
+            }
+            _ => Err(format!(
+                "Expected a number of seconds or 'off', not '{}'",
+                secs
+            )),
+        },
+        ["learning", value] => {
+            settings.learning = parse_on_off(value)?;
+            Ok(format!("turned learning {}", value))
+        }
+        ["flooding", value] => {
+            settings.flooding = parse_on_off(value)?;
+            Ok(format!("turned flooding {}", value))
+        }
+        ["mac-limit", "off"] => {
+            settings.mac_limit = None;
+            Ok("turned the MAC limit off".to_string())
+        }
+        ["mac-limit", max, action @ ..] => {
+            let max = match max.parse::<usize>() {
+                Ok(max) if max > 0 => max,
+                _ => return Err(format!("Expected a number of MACs or 'off', not '{}'", max)),
+            };
+            let action = match action {
+                [] => MacLimitAction::Drop,
+                [action] => MacLimitAction::parse(action).ok_or_else(|| {
+                    format!("Expected 'drop', 'log' or 'shutdown', not '{}'", action)
+                })?,
+                _ => return Err("Expected 'set mac-limit <n> [drop|log|shutdown]'".to_string()),
+            };
+            let limit = MacLimit { max, action };
+            settings.mac_limit = Some(limit);
+            Ok(format!("limited MACs to {}", limit))
+        }
+        ["mac-move-limit", "off"] => {
+            settings.mac_move_limit = None;
+            Ok("turned the MAC move limit off".to_string())
+        }
+        ["mac-move-limit", rate, action @ ..] => {
+            let (moves, window) = parse_move_rate(rate)?;
+            let action = match action {
+                [] => MacMoveAction::Log,
+                [action] => MacMoveAction::parse(action).ok_or_else(|| {
+                    format!("Expected 'log', 'dampen' or 'shutdown', not '{}'", action)
+                })?,
+                _ => {
+                    return Err(
+                        "Expected 'set mac-move-limit <moves>/<secs> [log|dampen|shutdown]'"
+                            .to_string(),
+                    )
+                }
+            };
+            let limit = MacMoveLimit {
+                moves,
+                window,
+                action,
+            };
+            settings.mac_move_limit = Some(limit);
+            Ok(format!("limited MAC moves to {}", limit))
+        }
+        ["storm-control", "off"] => {
+            settings.storm_control = None;
+            Ok("turned storm control off".to_string())
+        }
+        ["storm-control", rate] => match rate.parse::<u32>() {
+            Ok(rate) if rate > 0 => {
+                settings.storm_control = Some(rate);
+                Ok(format!("limited flooded frames to {} per second", rate))
+            }
+            _ => Err(format!(
+                "Expected a number of frames per second or 'off', not '{}'",
+                rate
+            )),
+        },
+        _ => Err(
+            "Expected 'set mac-aging <secs>|off', 'set learning on|off', \
+                  'set flooding on|off', 'set mac-limit <n> [drop|log|shutdown]|off', \
+                  'set mac-move-limit <moves>/<secs> [log|dampen|shutdown]|off' \
+                  or 'set storm-control <frames_per_sec>|off'"
+                .to_string(),
+        ),
+    }
+}
+
+/// Change the ACL, returning a description of the change
+fn acl(
+    args: &[&str],
+    settings: &mut Settings,
+    ports: &PortTable<VportAddr>,
+) -> Result<String, String> {
+    match args {
+        ["add", rule @ ..] => {
+            /* Rules are bound to every port's ingress unless a port and direction are given */
+            let port_id = |id: &str| find_port(ports, id).map(|addr| ports.get(&addr).unwrap().id);
+            let (binding, action, filter) = match rule {
+                ["in", id, action, filter @ ..] => (AclBinding::In(port_id(id)?), action, filter),
+                ["out", id, action, filter @ ..] => (AclBinding::Out(port_id(id)?), action, filter),
+                [action, filter @ ..] => (AclBinding::Global, action, filter),
+                [] => return Err(ACL_USAGE.to_string()),
+            };
+            let action = match *action {
+                "permit" => AclAction::Permit,
+                "deny" => AclAction::Deny,
+                _ => return Err(format!("Expected 'permit' or 'deny', not '{}'", action)),
+            };
+            let text = filter.join(" ");
+            settings.acl.push(AclRule {
+                action,
+                binding,
+                filter: Filter::parse(filter).map_err(|e| e.to_string())?,
+                text: text.clone(),
+                hits: 0,
+            });
+            Ok(format!(
+                "added ACL rule {}: {} {} {}",
+                settings.acl.len(),
+                binding,
+                action.name(),
+                text
+            ))
+        }
+        ["remove", index] => {
+            let rule = index
+                .parse::<usize>()
+                .ok()
+                .filter(|index| (1..=settings.acl.len()).contains(index))
+                .map(|index| settings.acl.remove(index - 1))
+                .ok_or_else(|| format!("No ACL rule {}", index))?;
+            Ok(format!(
+                "removed ACL rule {}: {} {} {}",
+                index,
+                rule.binding,
+                rule.action.name(),
+                rule.text
+            ))
+        }
+        _ => Err(ACL_USAGE.to_string()),
+    }
+}
+
+/// Add or remove a static MAC, returning a description of the change
+///
+/// A static MAC is added to the MAC table of its port's segment, in
+/// the given VLAN, or the access port's VLAN, or untagged otherwise,
+/// and is not learned in any other segment or VLAN while it is static
+fn static_mac(
+    args: &[&str],
+    settings: &mut Settings,
+    mac_tables: &mut MacTables,
+    ports: &PortTable<VportAddr>,
+    vports: &Vports,
+) -> Result<String, String> {
+    let parse_mac = |mac: &str| {
+        mac.parse::<MacAddr>()
+            .map_err(|_| format!("Invalid MAC '{}'", mac))
+    };
+
+    match args {
+        ["add", mac, id, vlan @ ..] if vlan.len() <= 1 => {
+            let mac = parse_mac(mac)?;
+            let addr = find_port(ports, id)?;
+
+            /*
+             * Access and tunnel ports only carry their own VLAN, so that is
+             * where the MAC is, and it is in a trunk's native VLAN by default
+             */
+            let vlan = vlan.first().copied().map(parse_vlan_id).transpose()?;
+            let port_vlan = ports
+                .get(&addr)
+                .map(|port| port.vlan.clone())
+                .unwrap_or_default();
+            let vlan = match (vlan, &port_vlan) {
+                (None, PortVlan::Trunk { native, .. }) => *native,
+                (None, _) => port_vlan.untagged_vlan(),
+                (Some(vlan), _) => Some(vlan),
+            };
+            if !port_vlan.carries(vlan) {
+                return Err(format!(
+                    "Port {} is {}, so can't carry VLAN {}",
+                    id,
+                    port_vlan,
+                    vlan.unwrap_or_default()
+                ));
+            }
+
+            settings.static_macs.insert(mac);
+            for mac_table in mac_tables.values_mut() {
+                mac_table.remove(&mac);
+            }
+            let segment = vports.segment(&addr);
+            mac_tables
+                .entry(Domain { segment, vlan })
+                .or_default()
+                .insert(mac, addr);
+            Ok(match vlan {
+                Some(vlan) => format!("added static MAC {} on port {} in VLAN {}", mac, id, vlan),
+                None => format!("added static MAC {} on port {}", mac, id),
+            })
+        }
+        ["remove", mac] => {
+            let mac = parse_mac(mac)?;
+            if !settings.static_macs.remove(&mac) {
+                return Err(format!("{} is not a static MAC", mac));
+            }
+            for mac_table in mac_tables.values_mut() {
+                mac_table.remove(&mac);
+            }
+            Ok(format!("removed static MAC {}", mac))
+        }
+        _ => Err(
+            "Expected 'static-mac add <mac> <port_id> [<vlan_id>]' or 'static-mac remove <mac>'"
+                .to_string(),
+        ),
+    }
+}
+
+/// Returns the settings, ACL and static MACs in human readable format
+fn show_settings(
+    settings: &Settings,
+    mac_tables: &MacTables,
+    ports: &PortTable<VportAddr>,
+) -> String {
+    let on_off = |on| match on {
+        true => "on",
+        false => "off",
+    };
+    let mac_aging = match settings.mac_aging {
+        Some(aging) => format!("{}s", aging.as_secs()),
+        None => "off".to_string(),
+    };
+
+    let mut lines = vec![
+        format!("MAC aging: {}", mac_aging),
+        format!("Learning: {}", on_off(settings.learning)),
+        format!("Flooding: {}", on_off(settings.flooding)),
+        match settings.mac_limit {
+            Some(limit) => format!("MAC limit: {}", limit),
+            None => "MAC limit: off".to_string(),
+        },
+        match settings.mac_move_limit {
+            Some(limit) => format!("MAC move limit: {}", limit),
+            None => "MAC move limit: off".to_string(),
+        },
+        match settings.storm_control {
+            Some(rate) => format!("Storm control: {} flooded frames per second", rate),
+            None => "Storm control: off".to_string(),
+        },
+    ];
+
+    let shutdown: Vec<String> = ports
+        .iter()
+        .filter(|(_, port)| port.shutdown)
+        .map(|(_, port)| port.id.to_string())
+        .collect();
+    if !shutdown.is_empty() {
+        lines.push(format!("Shut down ports: {}", shutdown.join(", ")));
+    }
+
+    lines.push(format!(
+        "ACL ({} rule(s), frames matching no rule are permitted)",
+        settings.acl.len()
+    ));
+    for (index, rule) in settings.acl.iter().enumerate() {
+        lines.push(format!(
+            "  {:>3}  {:<7}  {:<6}  {:<40}  {} hit(s)",
+            index + 1,
+            rule.binding.to_string(),
+            rule.action.name(),
+            rule.text,
+            rule.hits
+        ));
+    }
+
+    let mut static_macs: Vec<String> = settings
+        .static_macs
+        .iter()
+        .map(|mac| {
+            let addr = mac_tables.values().find_map(|mac_table| mac_table.get(mac));
+            /* Static MACs given on the command line may be on vports which haven't been seen */
+            let port = match addr.map(|addr| (addr, ports.get(addr))) {
+                Some((_, Some(port))) => format!("port {}", port.id),
+                Some((addr, None)) => addr.to_string(),
+                None => "-".to_string(),
+            };
+            format!("  {}  {}", mac, port)
+        })
+        .collect();
+    static_macs.sort();
+    lines.push(format!("{} static MAC(s)", static_macs.len()));
+    lines.extend(static_macs);
+
+    lines.join("\n")
+}
+
+/// Returns the MAC tables in human readable format, where the MACs
+/// of VLANs, and of segments other than the default, are marked
+fn show_mac_table(mac_tables: &MacTables, ports: &PortTable<VportAddr>) -> String {
+    let mut entries: Vec<(Domain, MacAddr, String)> = mac_tables
+        .iter()
+        .flat_map(|(domain, mac_table)| mac_table.iter().map(move |entry| (*domain, entry)))
+        .map(|(domain, (mac, vport))| {
+            let port = match ports.get(vport) {
+                Some(port) => format!("port {} ({})", port.id, vport),
+                None => vport.to_string(),
+            };
+            (domain, *mac, port)
+        })
+        .collect();
+    entries.sort();
+
+    let mut lines = vec![format!("{} MAC(s) learned", entries.len())];
+    lines.extend(
+        entries
+            .iter()
+            .map(|(domain, mac, port)| match (domain.segment, domain.vlan) {
+                (DEFAULT_SEGMENT, None) => format!("  {}  {}", mac, port),
+                (DEFAULT_SEGMENT, Some(vlan)) => format!("  {}  {}  in VLAN {}", mac, port, vlan),
+                (segment, None) => format!("  {}  {}  in segment {}", mac, port, segment),
+                (segment, Some(vlan)) => {
+                    format!("  {}  {}  in segment {} VLAN {}", mac, port, segment, vlan)
+                }
+            }),
+    );
+    lines.join("\n")
+}
+
+/// Returns the ports in each split-horizon group in human readable format
+fn show_split_horizon(ports: &PortTable<VportAddr>) -> String {
+    let mut groups: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+    for (_, port) in ports.iter() {
+        if let Some(group) = port.split_horizon {
+            groups.entry(group).or_default().push(port.id);
+        }
+    }
+
+    let mut lines = vec![format!("{:>5}  {}", "group", "ports")];
+    for (group, mut ids) in groups {
+        ids.sort();
+        let ids: Vec<String> = ids.iter().map(u32::to_string).collect();
+        lines.push(format!("{:>5}  {}", group, ids.join(", ")));
+    }
+    lines.join("\n")
+}
+
+/// Returns the ports whose vports have joined the vswitch, with their
+/// capabilities, the VLAN they asked to be in, their VLAN mode and the
+/// public endpoint they found with STUN, in human readable format.
+/// Other ports are only known from their frames
+fn show_registrations(ports: &PortTable<VportAddr>) -> String {
+    let mut lines = vec![format!(
+        "{:>5}  {:<24}  {:<16}  {:<36}  {:>9}  {:<16}  {:<8}  {}",
+        "port",
+        "vport",
+        "session",
+        "capabilities",
+        "asked for",
+        "vlan mode",
+        "joined",
+        "public endpoint"
+    )];
+
+    for (addr, port) in ports.iter() {
+        let Some(registration) = &port.registration else {
+            continue;
+        };
+        let session = match port.session_id {
+            Some(session_id) => format!("{:016x}", session_id),
+            None => "-".to_string(),
+        };
+        let requested_vlan = match registration.requested_vlan {
+            Some(vlan) => vlan.to_string(),
+            None => "-".to_string(),
+        };
+        let public_endpoint = match registration.public_endpoint {
+            Some(public_endpoint) => public_endpoint.to_string(),
+            None => "-".to_string(),
+        };
+        lines.push(format!(
+            "{:>5}  {:<24}  {:<16}  {:<36}  {:>9}  {:<16}  {:<8}  {}",
+            port.id,
+            addr.to_string(),
+            session,
+            capability_names(registration.capabilities),
+            requested_vlan,
+            port.vlan.args(),
+            format!("{}s ago", registration.joined.elapsed().as_secs()),
+            public_endpoint
+        ));
+    }
+
+    lines.join("\n")
+}
+
+/// Returns each segment and VLAN which has MACs or access ports,
+/// with the number of MACs learned in it and its access ports
+/// (including QinQ tunnel ports, and trunks whose native VLAN it
+/// is), in human readable format. Trunks carry every other VLAN
+/// they allow
+fn show_vlans(mac_tables: &MacTables, ports: &PortTable<VportAddr>, vports: &Vports) -> String {
+    let mut domains: BTreeMap<Domain, (usize, Vec<String>)> = mac_tables
+        .iter()
+        .filter(|(_, mac_table)| !mac_table.is_empty())
+        .map(|(domain, mac_table)| (*domain, (mac_table.len(), Vec::new())))
+        .collect();
+    for (addr, port) in ports.iter() {
+        let (vlan, name) = match port.vlan {
+            PortVlan::Trunk { native: None, .. } => continue,
+            PortVlan::Trunk {
+                native: Some(vlan), ..
+            } => (vlan, format!("{} (native)", port.id)),
+            PortVlan::Access(vlan) => (vlan, port.id.to_string()),
+            PortVlan::Tunnel(vlan) => (vlan, format!("{} (qinq)", port.id)),
+        };
+        let segment = vports.segment(addr);
+        let (_, access_ports) = domains
+            .entry(Domain {
+                segment,
+                vlan: Some(vlan),
+            })
+            .or_default();
+        access_ports.push(name);
+    }
+
+    let mut lines = vec![format!(
+        "{:>7}  {:>8}  {:>6}  {}",
+        "segment", "vlan", "macs", "access ports"
+    )];
+    for (domain, (macs, access_ports)) in domains.iter() {
+        let vlan = match domain.vlan {
+            Some(vlan) => vlan.to_string(),
+            None => "untagged".to_string(),
+        };
+        let access_ports = match access_ports.is_empty() {
+            true => "-".to_string(),
+            false => access_ports.join(", "),
+        };
+        lines.push(format!(
+            "{:>7}  {:>8}  {:>6}  {}",
+            domain.segment, vlan, macs, access_ports
+        ));
+    }
+
+    lines.join("\n")
+}
+
+/// Make a port an access port in a VLAN, a tunnel port, or a trunk with
+/// an optional native VLAN and allowed VLANs, returning a description of
+/// the change. The port's MACs are flushed, as they were learned in the
+/// VLANs it carried before
+fn port_vlan(
+    args: &[&str],
+    mac_tables: &mut MacTables,
+    ports: &mut PortTable<VportAddr>,
+    peers: &[VportAddr],
+    settings: &Settings,
+    vports: &Vports,
+    events: &mut EventLog,
+) -> Result<String, String> {
+    let [id, mode @ ..] = args else {
+        return Err("Expected 'vlan <port_id> access <vlan_id>', \
+                    'vlan <port_id> tunnel <svlan_id>' or \
+                    'vlan <port_id> trunk [native <vlan_id>] [allowed <vlan_list>]'"
+            .to_string());
+    };
+    let mode = PortVlan::parse(mode)?;
+    let addr = find_port(ports, id)?;
+    let port = ports.port(addr);
+    let id = port.id;
+    if port.vlan == mode {
+        return Ok(format!("Port {} is already {}", id, mode));
+    }
+    port.vlan = mode.clone();
+
+    let segment = vports.segment(&addr);
+    topology::port_down(
+        &addr,
+        format!("Admin made port {} ({}) {}", id, addr, mode),
+        mac_tables,
+        &settings.static_macs,
+        segment_peers(peers, segment),
+        vports,
+        events,
+    );
+    Ok(format!("Made port {} {}", id, mode))
+}
+
+/// Limit the rate of the frames a port receives or is sent, returning
+/// a description of the change
+fn rate_limit(args: &[&str], ports: &mut PortTable<VportAddr>) -> Result<String, String> {
+    let usage = || "Expected 'rate-limit <port_id> in|out [pps <n>] [bps <n>]'".to_string();
+    let [id, direction, rates @ ..] = args else {
+        return Err(usage());
+    };
+    let addr = find_port(ports, id)?;
+
+    let mut limit = RateLimit {
+        pps: None,
+        bps: None,
+    };
+    for rate in rates.chunks(2) {
+        let (unit, value) = match rate {
+            ["pps", value] if limit.pps.is_none() => (&mut limit.pps, value),
+            ["bps", value] if limit.bps.is_none() => (&mut limit.bps, value),
+            _ => return Err(usage()),
+        };
+        *unit = match value.parse::<u64>() {
+            Ok(value) if value > 0 => Some(value),
+            _ => return Err(format!("Invalid rate '{}'", value)),
+        };
+    }
+    if limit.pps.is_none() && limit.bps.is_none() {
+        return Err(usage());
+    }
+
+    let port = ports.get_mut(&addr).unwrap();
+    let (slot, direction) = match *direction {
+        "in" => (&mut port.rx_limit, "ingress"),
+        "out" => (&mut port.tx_limit, "egress"),
+        _ => return Err(usage()),
+    };
+    *slot = Some(RateLimiter::new(limit));
+    Ok(format!(
+        "limited the {} rate of port {} ({}) to {}",
+        direction, port.id, addr, limit
+    ))
+}
+
+/// Returns the rate limits of each port, and the frames
+/// they have dropped, in human readable format
+fn show_rate_limits(ports: &PortTable<VportAddr>) -> String {
+    let mut limits: Vec<(u32, &str, &RateLimiter)> = ports
+        .iter()
+        .flat_map(|(_, port)| {
+            [("in", &port.rx_limit), ("out", &port.tx_limit)]
+                .into_iter()
+                .filter_map(|(direction, limiter)| {
+                    limiter
+                        .as_ref()
+                        .map(|limiter| (port.id, direction, limiter))
+                })
+        })
+        .collect();
+    limits.sort_by_key(|(id, direction, _)| (*id, *direction));
+
+    let mut lines = vec![format!(
+        "{:>5}  {:<9}  {:<32}  {:>10}",
+        "port", "direction", "limit", "dropped"
+    )];
+    for (id, direction, limiter) in limits {
+        lines.push(format!(
+            "{:>5}  {:<9}  {:<32}  {:>10}",
+            id,
+            direction,
+            limiter.limit().to_string(),
+            limiter.dropped()
+        ));
+    }
+    lines.join("\n")
+}
+
+/// Returns the scheduler of each port with egress queues, and the frames
+/// waiting in, sent from and dropped by each queue, in human readable format
+fn show_qos(ports: &PortTable<VportAddr>) -> String {
+    let mut queued: Vec<(u32, &EgressQueues)> = ports
+        .iter()
+        .filter_map(|(_, port)| port.queues.as_ref().map(|queues| (port.id, queues)))
+        .collect();
+    queued.sort_by_key(|(id, _)| *id);
+
+    let mut lines = Vec::new();
+    for (id, queues) in queued {
+        lines.push(format!("Port {}: {}", id, queues.scheduler()));
+        lines.push(format!(
+            "  {:>8}  {:>7}  {:>10}  {:>10}",
+            "priority", "waiting", "sent", "dropped"
+        ));
+        for (priority, waiting, sent, dropped) in queues.stats() {
+            lines.push(format!(
+                "  {:>8}  {:>7}  {:>10}  {:>10}",
+                priority, waiting, sent, dropped
+            ));
+        }
+    }
+    match lines.is_empty() {
+        true => "No port has egress queues".to_string(),
+        false => lines.join("\n"),
+    }
+}
+
+/// Returns the connected vports and their counters in human readable format
+fn show_ports(ports: &PortTable<VportAddr>) -> String {
+    let mut lines = vec![format!(
+        "{:>5}  {:<24}  {:<16}  {:>10}  {:>12}  {:>10}  {:>12}  {:>10}",
+        "port", "vport", "session", "rx frames", "rx bytes", "tx frames", "tx bytes", "dropped"
+    )];
+
+    for (addr, port) in ports.iter() {
+        let session = match port.session_id {
+            Some(session_id) => format!("{:016x}", session_id),
+            None => "-".to_string(),
+        };
+        let counters = &port.counters;
+        lines.push(format!(
+            "{:>5}  {:<24}  {:<16}  {:>10}  {:>12}  {:>10}  {:>12}  {:>10}",
+            port.id,
+            addr.to_string(),
+            session,
+            counters.rx_frames,
+            counters.rx_bytes,
+            counters.tx_frames,
+            counters.tx_bytes,
+            counters.drops.total()
+        ));
+    }
+
+    lines.join("\n")
+}
+
+/// Returns the frames dropped from each port for each reason in human readable format
+fn show_drops(ports: &PortTable<VportAddr>) -> String {
+    let mut lines = vec![format!(
+        "{:>5}  {:<24}  {:<22}  {:>10}",
+        "port", "vport", "reason", "dropped"
+    )];
+
+    for (addr, port) in ports.iter() {
+        for (reason, count) in port.counters.drops.iter() {
+            lines.push(format!(
+                "{:>5}  {:<24}  {:<22}  {:>10}",
+                port.id,
+                addr.to_string(),
+                reason.name(),
+                count
+            ));
+        }
+    }
+
+    lines.join("\n")
+}
+
+/// Returns the latency percentiles of each port in human readable format
+fn show_latency(ports: &PortTable<VportAddr>) -> String {
+    let mut lines = vec![format!(
+        "{:>5}  {:<24}  {:>26}  {:>26}",
+        "port", "vport", "forwarding p50/p95/p99", "round trip p50/p95/p99"
+    )];
+
+    for (addr, port) in ports.iter() {
+        let show = |histogram| match latency::percentiles(histogram) {
+            Some([p50, p95, p99]) => format!("{}/{}/{} us", p50, p95, p99),
+            None => "-".to_string(),
+        };
+        lines.push(format!(
+            "{:>5}  {:<24}  {:>26}  {:>26}",
+            port.id,
+            addr.to_string(),
+            show(&port.latency.forwarding),
+            show(&port.latency.rtt)
+        ));
+    }
+
+    lines.join("\n")
+}
+
+/// Returns the frame size distribution of each port in human readable format
+fn show_sizes(ports: &PortTable<VportAddr>) -> String {
+    let mut header = format!("{:>5}", "port");
+    for (label, _) in SIZE_BUCKETS {
+        header += &format!("  {:>10}", label);
+    }
+
+    let mut lines = vec![header];
+    for (_, port) in ports.iter() {
+        let mut line = format!("{:>5}", port.id);
+        for count in port.sizes.buckets {
+            line += &format!("  {:>10}", count);
+        }
+        lines.push(line);
+    }
+
+    lines.join("\n")
+}
+
+/// Returns the traffic through each port since it came up, and
+/// against its quota (if there is one), in human readable format
+fn show_usage(ports: &PortTable<VportAddr>, accounting: &Accounting) -> String {
+    let mut lines = vec![format!(
+        "{:>5}  {:>10}  {:>12}  {:>12}  {:>12}  {}",
+        "port", "up for", "rx bytes", "tx bytes", "quota used", "quota"
+    )];
+
+    for (_, port) in ports.iter() {
+        let usage = &port.usage;
+        let (rx_bytes, tx_bytes) = usage.bytes(&port.counters);
+        let quota = match accounting.quota() {
+            _ if usage.is_over_quota() => "over quota".to_string(),
+            Some((quota, _)) => format!("{} bytes", quota),
+            None => "-".to_string(),
+        };
+        let up_for = match port.down || port.shutdown {
+            true => "down".to_string(),
+            false => format!("{}s", usage.elapsed().as_secs()),
+        };
+        lines.push(format!(
+            "{:>5}  {:>10}  {:>12}  {:>12}  {:>12}  {}",
+            port.id,
+            up_for,
+            rx_bytes,
+            tx_bytes,
+            usage.quota_used(&port.counters),
+            quota
+        ));
+    }
+
+    lines.join("\n")
+}
+
+/// Returns the recent events in human readable format
+fn show_events(events: &EventLog) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let lines: Vec<String> = events
+        .iter()
+        .map(|(time, event)| format!("{:>6}s ago  {}", now.saturating_sub(time), event))
+        .collect();
+    match lines.is_empty() {
+        true => "No events yet".to_string(),
+        false => lines.join("\n"),
+    }
+}
+
+/// Returns the MAC table size, ports and events with one item per
+/// line, each line starting with its type and having space separated
+/// fields, which is easier for programs to parse than 'show':
+///
+/// macs <count>
+/// port <id> <vport> <session_id or -> <rx_frames> <rx_bytes> <tx_frames> <tx_bytes> <idle_ms>
+/// drops <id> <reason> <count>, where reason is one in 'show drops'
+/// sizes <id> <64> <65-127> <128-255> <256-511> <512-1023> <1024-1518> <jumbo>
+/// latency <id> forwarding|rtt <samples> <p50_us> <p95_us> <p99_us>
+/// event <secs_since_epoch> <event>
+fn stats(mac_tables: &MacTables, ports: &PortTable<VportAddr>, events: &EventLog) -> String {
+    let macs: usize = mac_tables.values().map(HashMap::len).sum();
+    let mut lines = vec![format!("macs {}", macs)];
+
+    for (addr, port) in ports.iter() {
+        let session = match port.session_id {
+            Some(session_id) => format!("{:016x}", session_id),
+            None => "-".to_string(),
+        };
+        let counters = &port.counters;
+        lines.push(format!(
+            "port {} {} {} {} {} {} {} {}",
+            port.id,
+            addr,
+            session,
+            counters.rx_frames,
+            counters.rx_bytes,
+            counters.tx_frames,
+            counters.tx_bytes,
+            port.last_seen.elapsed().as_millis()
+        ));
+    }
+
+    for (_, port) in ports.iter() {
+        for (reason, count) in port.counters.drops.iter() {
+            lines.push(format!("drops {} {} {}", port.id, reason.name(), count));
+        }
+    }
+
+    for (_, port) in ports.iter() {
+        let counts: Vec<String> = port.sizes.buckets.iter().map(u64::to_string).collect();
+        lines.push(format!("sizes {} {}", port.id, counts.join(" ")));
+    }
+
+    /* Histograms with no samples yet have no percentiles to report */
+    for (_, port) in ports.iter() {
+        let histograms = [
+            ("forwarding", &port.latency.forwarding),
+            ("rtt", &port.latency.rtt),
+        ];
+        for (name, histogram) in histograms {
+            if let Some([p50, p95, p99]) = latency::percentiles(histogram) {
+                lines.push(format!(
+                    "latency {} {} {} {} {} {}",
+                    port.id,
+                    name,
+                    histogram.len(),
+                    p50,
+                    p95,
+                    p99
+                ));
+            }
+        }
+    }
+
+    lines.extend(
+        events
+            .iter()
+            .map(|(time, event)| format!("event {} {}", time, event)),
+    );
+
+    lines.join("\n")
+}
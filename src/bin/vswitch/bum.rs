@@ -0,0 +1,135 @@
+//! Underlay multicast for broadcast, unknown unicast and multicast frames
+//!
+//! Without it, a frame which is flooded is sent to every vport in a
+//! datagram of its own, so the vswitch's uplink carries a copy per
+//! vport. vports on the same network as the vswitch can instead join
+//! an underlay multicast group, as VXLAN does in multicast mode, and
+//! tell the vswitch they have done so in their hellos. Flooded frames
+//! are then sent to the group once, and the network copies them to
+//! every member
+//!
+//! Each datagram sent to the group starts with the session ID of the
+//! vport the frame came from (0 if it has none), so that vport, which
+//! the group also delivers the frame to, can drop its own frame
+
+use crate::{
+    ports::{Port, PortTable},
+    topology::PORT_DOWN_TIMEOUT,
+    vlan::PortVlan,
+    VportAddr,
+};
+use std::{
+    io,
+    net::{SocketAddrV4, UdpSocket},
+};
+
+/// Underlay multicast group which flooded frames are sent through
+#[derive(Debug)]
+pub struct BumGroup {
+    group: SocketAddrV4,
+    /// Frames sent to the group
+    sent: u64,
+    /// Datagrams which would have been sent to the members one by one
+    copies_saved: u64,
+}
+
+impl BumGroup {
+    /// Returns a group which frames are sent to at group
+    pub fn new(group: SocketAddrV4) -> BumGroup {
+        BumGroup {
+            group,
+            sent: 0,
+            copies_saved: 0,
+        }
+    }
+
+    /// Returns the address of the group
+    pub fn group(&self) -> SocketAddrV4 {
+        self.group
+    }
+
+    /// Take the vports which the group reaches out of dst_vports, and
+    /// return them. These are the vports on the vswitch's own port which
+    /// have said they are members recently enough to still be trusted
+    ///
+    /// If a member's port is shut down or blocked by STP, is in a
+    /// split-horizon group, has an egress rate limit or queues, which the
+    /// group would get around, or is anything but a trunk carrying every VLAN
+    /// tagged, such as an access port, which must only be sent the frames of
+    /// its own VLAN, untagged, the group would still deliver it every flooded
+    /// frame, so nothing is taken, and every vport is sent its own copy
+    pub fn take_members(
+        &self,
+        ports: &PortTable<VportAddr>,
+        dst_vports: &mut Vec<VportAddr>,
+    ) -> Vec<VportAddr> {
+        let is_member = |addr: &VportAddr| {
+            matches!(addr, VportAddr::Udp(_))
+                && ports.get(addr).is_some_and(|port| {
+                    port.group_member_seen
+                        .is_some_and(|seen| seen.elapsed() < PORT_DOWN_TIMEOUT)
+                })
+        };
+        let excluded = |port: &Port| {
+            port.shutdown
+                || !port.stp.forwards()
+                || port.vlan != PortVlan::default()
+                || port.split_horizon.is_some()
+                || port.tx_limit.is_some()
+                || port.queues.is_some()
+        };
+        if ports
+            .iter()
+            .any(|(addr, port)| excluded(port) && is_member(addr))
+        {
+            return Vec::new();
+        }
+
+        let (members, others) = dst_vports.drain(..).partition(is_member);
+        *dst_vports = others;
+        members
+    }
+
+    /// Send frame, which came from the vport with session_id, to the
+    /// group through socket, in place of one copy for each of members
+    /// vports
+    pub fn send(
+        &mut self,
+        socket: &UdpSocket,
+        session_id: u64,
+        frame: &[u8],
+        members: usize,
+    ) -> io::Result<()> {
+        let mut datagram = Vec::with_capacity(8 + frame.len());
+        datagram.extend_from_slice(&session_id.to_be_bytes());
+        datagram.extend_from_slice(frame);
+        socket.send_to(&datagram, self.group)?;
+
+        self.sent += 1;
+        self.copies_saved += members.saturating_sub(1) as u64;
+        Ok(())
+    }
+
+    /// Returns the group, the frames sent to it and its members in human readable format
+    pub fn show(&self, ports: &PortTable<VportAddr>) -> String {
+        let mut lines = vec![
+            format!("Group: {}", self.group),
+            format!(
+                "Frames sent to the group: {}, copies saved: {}",
+                self.sent, self.copies_saved
+            ),
+            "Members:".to_string(),
+        ];
+
+        for (addr, port) in ports.iter() {
+            if let Some(seen) = port.group_member_seen {
+                let state = match seen.elapsed() < PORT_DOWN_TIMEOUT {
+                    true => "",
+                    false => " (lapsed)",
+                };
+                lines.push(format!("  port {} ({}){}", port.id, addr, state));
+            }
+        }
+        lines.join("\n")
+    }
+}
@@ -0,0 +1,296 @@
+//! Chaos mode for the vswitch
+//!
+//! To check how the L2VPN (and the applications using it) cope with
+//! an unreliable network, the vswitch can be asked to inject faults
+//! into the frames it receives: dropping, duplicating or corrupting
+//! them, losing control messages such as hellos and echo replies,
+//! and simulating the vport or peer vswitch behind a random port
+//! restarting, by flushing its MACs and ignoring it for a while
+//!
+//! Faults are chosen by a pseudo-random generator with a seed, which
+//! is printed at startup, so that a run can be repeated with the same
+//! faults (as long as the same frames arrive in the same order)
+
+use crate::VportAddr;
+use l2vpn::{
+    control::CONTROL_MAC,
+    tunnel::{hop_limit, HOP_LIMIT_TAG_LEN},
+    utilities::ETHER_HDR,
+};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// How long a port is ignored for while its restart is simulated
+pub const RESTART_OUTAGE: Duration = Duration::from_secs(3);
+
+/// Faults which chaos mode injects, and how often
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChaosConfig {
+    /// Percentage of data frames dropped
+    pub drop: f64,
+    /// Percentage of data frames received twice
+    pub duplicate: f64,
+    /// Percentage of data frames with a bit of their payload flipped
+    pub corrupt: f64,
+    /// Percentage of control messages dropped
+    pub control_loss: f64,
+    /// Average time between simulated restarts, if there are any
+    pub restart: Option<Duration>,
+}
+
+impl ChaosConfig {
+    /// Parse the value of --chaos, a comma separated list of
+    /// drop|duplicate|corrupt|control-loss=<percent> and restart=<secs>
+    pub fn parse(value: &str) -> Result<ChaosConfig, String> {
+        let mut config = ChaosConfig::default();
+
+        for fault in value.split(',') {
+            let Some((name, value)) = fault.split_once('=') else {
+                return Err(format!(
+                    "Expected <fault>=<value> in --chaos, got '{}'",
+                    fault
+                ));
+            };
+
+            if name == "restart" {
+                let secs = value
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|secs| secs.is_finite() && *secs > 0.0)
+                    .ok_or_else(|| {
+                        format!("Expected a number of seconds for restart, got '{}'", value)
+                    })?;
+                config.restart = Some(Duration::from_secs_f64(secs));
+                continue;
+            }
+
+            let percent = value
+                .parse::<f64>()
+                .ok()
+                .filter(|percent| (0.0..=100.0).contains(percent))
+                .ok_or_else(|| format!("Expected a percentage for {}, got '{}'", name, value))?;
+            match name {
+                "drop" => config.drop = percent,
+                "duplicate" => config.duplicate = percent,
+                "corrupt" => config.corrupt = percent,
+                "control-loss" => config.control_loss = percent,
+                _ => {
+                    return Err(format!(
+                        "Unknown fault '{}' in --chaos, expected drop, duplicate, \
+                         corrupt, control-loss or restart",
+                        name
+                    ))
+                }
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// Counts of the faults chaos mode has injected
+#[derive(Debug, Default)]
+struct FaultCounts {
+    dropped: u64,
+    duplicated: u64,
+    corrupted: u64,
+    control_lost: u64,
+    restarts: u64,
+    outage_dropped: u64,
+}
+
+/// Injects faults into the frames received by the vswitch
+#[derive(Debug)]
+pub struct Chaos {
+    config: ChaosConfig,
+    seed: u64,
+    rng: SplitMix64,
+    counts: FaultCounts,
+    next_restart: Option<Instant>,
+    /// Ports whose restart is being simulated, and when they come back
+    outages: HashMap<VportAddr, Instant>,
+    /// Copy of the last frame, which is to be received again
+    duplicate: Option<(VportAddr, Vec<u8>)>,
+    /// True while the duplicate is being handled, so it isn't disturbed again
+    replaying: bool,
+}
+
+impl Chaos {
+    /// Returns chaos mode injecting the faults in config, choosing them
+    /// with seed, or with a seed from the clock if none is given
+    pub fn new(config: ChaosConfig, seed: Option<u64>) -> Chaos {
+        let seed = seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64
+        });
+
+        let mut chaos = Chaos {
+            config,
+            seed,
+            rng: SplitMix64(seed),
+            counts: FaultCounts::default(),
+            next_restart: None,
+            outages: HashMap::new(),
+            duplicate: None,
+            replaying: false,
+        };
+        chaos.next_restart = chaos.restart_after(Instant::now());
+        chaos
+    }
+
+    /// Returns the seed faults are chosen with
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Decide what happens to frame, received from the vport at addr
+    /// (with its hop limit tag, if any), returning why it is to be
+    /// dropped if it is. Otherwise it may be corrupted in place, or
+    /// copied to be received again, once it has been handled
+    pub fn frame(&mut self, addr: &VportAddr, frame: &mut [u8]) -> Option<&'static str> {
+        if std::mem::take(&mut self.replaying) {
+            return None;
+        }
+
+        if let Some(until) = self.outages.get(addr) {
+            if Instant::now() < *until {
+                self.counts.outage_dropped += 1;
+                return Some("is simulating its port restarting");
+            }
+            self.outages.remove(addr);
+        }
+
+        /* Control messages are lost, rather than damaged, so the vswitch sees them or not */
+        if frame[..6] == CONTROL_MAC {
+            if self.chance(self.config.control_loss) {
+                self.counts.control_lost += 1;
+                return Some("lost the control message");
+            }
+            return None;
+        }
+
+        if self.chance(self.config.drop) {
+            self.counts.dropped += 1;
+            return Some("dropped it");
+        }
+
+        /* Only the payload is corrupted, so the frame is still switched */
+        let payload_start = match hop_limit(frame) {
+            Some(_) => ETHER_HDR + HOP_LIMIT_TAG_LEN,
+            None => ETHER_HDR,
+        };
+        if frame.len() > payload_start && self.chance(self.config.corrupt) {
+            let index = payload_start + self.below(frame.len() - payload_start);
+            frame[index] ^= 1 << self.below(8);
+            self.counts.corrupted += 1;
+        }
+
+        if self.chance(self.config.duplicate) {
+            self.counts.duplicated += 1;
+            self.duplicate = Some((*addr, frame.to_vec()));
+        }
+
+        None
+    }
+
+    /// Returns the copy of the last frame, if it was duplicated,
+    /// which is to be received before anything else
+    pub fn take_duplicate(&mut self) -> Option<(VportAddr, Vec<u8>)> {
+        let duplicate = self.duplicate.take()?;
+        self.replaying = true;
+        Some(duplicate)
+    }
+
+    /// If it is time to simulate a restart, pick one of addrs to
+    /// restart, and ignore frames from it for RESTART_OUTAGE
+    pub fn restart(&mut self, addrs: &[VportAddr]) -> Option<VportAddr> {
+        let now = Instant::now();
+        if self.next_restart.is_none_or(|next| now < next) {
+            return None;
+        }
+        self.next_restart = self.restart_after(now);
+
+        if addrs.is_empty() {
+            return None;
+        }
+        let addr = addrs[self.below(addrs.len())];
+        self.outages.insert(addr, now + RESTART_OUTAGE);
+        self.counts.restarts += 1;
+        Some(addr)
+    }
+
+    /// Returns the faults injected and the seed in human readable format
+    pub fn show(&self) -> String {
+        let config = &self.config;
+        let counts = &self.counts;
+        let restart = match config.restart {
+            Some(restart) => format!("every {:.1}s on average", restart.as_secs_f64()),
+            None => "never".to_string(),
+        };
+
+        [
+            format!("Seed: {}", self.seed),
+            format!("Dropped:      {:>10}  ({}%)", counts.dropped, config.drop),
+            format!(
+                "Duplicated:   {:>10}  ({}%)",
+                counts.duplicated, config.duplicate
+            ),
+            format!(
+                "Corrupted:    {:>10}  ({}%)",
+                counts.corrupted, config.corrupt
+            ),
+            format!(
+                "Control lost: {:>10}  ({}%)",
+                counts.control_lost, config.control_loss
+            ),
+            format!("Restarts:     {:>10}  ({})", counts.restarts, restart),
+            format!(
+                "Restart drops:{:>10}  (while restarts are simulated)",
+                counts.outage_dropped
+            ),
+        ]
+        .join("\n")
+    }
+
+    /// Returns when the restart after one at now should be simulated,
+    /// which is between half and one and a half times the average
+    fn restart_after(&mut self, now: Instant) -> Option<Instant> {
+        let average = self.config.restart?;
+        let factor = 0.5 + self.rng.next_f64();
+        Some(now + average.mul_f64(factor))
+    }
+
+    /// Returns true with the given percentage chance
+    fn chance(&mut self, percent: f64) -> bool {
+        percent > 0.0 && self.rng.next_f64() * 100.0 < percent
+    }
+
+    /// Returns a number from 0 up to, but not including, n
+    fn below(&mut self, n: usize) -> usize {
+        (self.rng.next_f64() * n as f64) as usize % n
+    }
+}
+
+/// SplitMix64 pseudo-random generator, which is small and good
+/// enough for choosing faults, but not for anything secret
+#[derive(Debug)]
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number from 0 up to, but not including, 1
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
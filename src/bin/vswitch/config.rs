@@ -0,0 +1,791 @@
+//! Command line configuration for the vswitch
+//!
+//! The configuration is parsed and then validated separately,
+//! so that `vswitch check-config` can report every problem
+//! with a configuration without binding any sockets
+
+use crate::{
+    accounting::QuotaAction,
+    chaos::ChaosConfig,
+    mac_moves::{parse_move_rate, MacMoveAction},
+    port_security::MacLimitAction,
+    recorder::RecorderConfig,
+    reflector::ReflectRule,
+    settings::parse_on_off,
+    vlan::parse_vlan_id,
+    DEFAULT_SEGMENT,
+};
+use l2vpn::{auth::parse_psk, dscp::Dscp, mac::MacAddr, utilities::parse_overlay_mtu};
+use std::{
+    fs,
+    net::{SocketAddr, SocketAddrV4},
+    path::Path,
+    time::Duration,
+};
+
+/// Configuration given to the vswitch on the command line
+pub struct Config {
+    pub port: u16,
+    pub listeners: ListenerOpts,
+    pub state_path: Option<String>,
+    /// File which the MAC tables are snapshotted to, and how often
+    pub mac_snapshot_path: Option<String>,
+    pub mac_snapshot_interval: Option<Duration>,
+    pub admin_path: Option<String>,
+    pub sample_collector: Option<SocketAddr>,
+    pub sample_rate: Option<u32>,
+    pub peers: Vec<SocketAddr>,
+    pub accounting_path: Option<String>,
+    /// Bytes each port can receive and send before quota_action is taken
+    pub quota: Option<u64>,
+    pub quota_action: Option<QuotaAction>,
+    /// Configuration of the built-in DHCP server, if it is to run
+    pub dhcp_path: Option<String>,
+    /// Faults to inject into the frames received, and the seed to choose them with
+    pub chaos: Option<ChaosConfig>,
+    pub chaos_seed: Option<u64>,
+    /// Discovery protocols to reflect between segments, and the services to reflect
+    pub reflect: Vec<ReflectRule>,
+    pub reflect_services: Vec<String>,
+    /// Limits on the frames kept for each port, and where they are dumped on alerts
+    pub flight_recorder: Option<RecorderConfig>,
+    pub flight_recorder_dir: Option<String>,
+    /// Underlay multicast group which flooded frames are sent to vports through
+    pub bum_group: Option<SocketAddrV4>,
+    /// MTU of the L2VPN network, if not the default
+    pub mtu: Option<usize>,
+    /// Whether frames to unknown MACs are flooded from the start,
+    /// rather than dropped until an admin client turns flooding on
+    pub flooding: Option<bool>,
+    /// Bridge priority to run STP with, if it is to run
+    pub stp: Option<u16>,
+    /// MACs which are always sent to the vport at an address on the
+    /// vswitch's own port, in a VLAN or untagged, and never learned elsewhere
+    pub static_macs: Vec<(MacAddr, SocketAddr, Option<u16>)>,
+    /// MACs which can be learned on each vport before mac_limit_action is taken
+    pub mac_limit: Option<usize>,
+    pub mac_limit_action: Option<MacLimitAction>,
+    /// Moves each MAC can make within a window before mac_move_action is taken
+    pub mac_move_limit: Option<(usize, Duration)>,
+    pub mac_move_action: Option<MacMoveAction>,
+    /// Frames each vport can flood per second, beyond which they are dropped
+    pub storm_control: Option<u32>,
+    /// Whether multicasts are only sent to the vports which have joined their group
+    pub igmp_snooping: Option<bool>,
+    /// Whether vports which join asking for a VLAN are made access ports in it
+    pub vlan_requests: Option<bool>,
+    /// Whether vports which join asking to compress frames are sent them compressed
+    pub compression: Option<bool>,
+    /// Whether vports which join asking to fragment large frames are sent them in fragments
+    pub fragmentation: Option<bool>,
+    /// Whether vports which join asking for direct paths are introduced to each other
+    pub direct_paths: Option<bool>,
+    /// Whether vports which join asking to batch small frames are sent them in batches
+    pub batching: Option<bool>,
+    /// Whether vports which join asking for FEC have their datagrams protected with it
+    pub fec: Option<bool>,
+    /// Whether vports which join asking for ARP suppression are told the
+    /// addresses learned from ARP
+    pub arp_suppression: Option<bool>,
+    /// Pidfile of the daemon which the vswitch detaches as, if it does
+    pub daemon: Option<String>,
+    /// File (or syslog) which the vswitch logs to, if not stdout and stderr
+    pub log: Option<String>,
+}
+
+/// Listeners which the vswitch was asked to start
+/// in addition to its UDP socket
+pub struct ListenerOpts {
+    /// Further UDP addresses to listen on, and the segment of each
+    pub listen: Vec<(SocketAddr, u32)>,
+    pub vhost_user_paths: Vec<String>,
+    pub vsock_port: Option<u32>,
+    /// Address to accept vports connecting over TCP on
+    pub tcp_addr: Option<SocketAddr>,
+    /// Address to accept vports connecting over QUIC on, and the
+    /// certificate presented to them, with its private key
+    pub quic_addr: Option<SocketAddr>,
+    pub quic_cert_path: Option<String>,
+    pub quic_key_path: Option<String>,
+    pub unix_path: Option<String>,
+    pub shm_path: Option<String>,
+    /// File holding the key which vports reached over UDP use for DTLS
+    pub dtls_psk_path: Option<String>,
+    /// File holding the key which frames sent over UDP are signed with
+    pub auth_psk_path: Option<String>,
+    /// Addresses to exchange VXLAN and GENEVE datagrams with VTEPs on
+    pub vxlan_addr: Option<SocketAddr>,
+    pub geneve_addr: Option<SocketAddr>,
+    /// DSCP which the datagrams sent to vports over UDP are marked with
+    pub dscp: Option<Dscp>,
+}
+
+/// Parse the command line arguments (without the program
+/// name) into a Config, returning an error for anything
+/// which is malformed
+pub fn parse_args(args: &[String]) -> Result<Config, String> {
+    let Some((port, opts)) = args.split_first() else {
+        return Err("Missing port number".to_string());
+    };
+
+    /* Get port number from command line argument */
+    let port = port
+        .parse::<u16>()
+        .map_err(|e| format!("Could not parse '{}' as port number: {}", port, e))?;
+
+    /* Get the additional listeners to start from the optional arguments */
+    let mut config = Config {
+        port,
+        listeners: ListenerOpts {
+            listen: Vec::new(),
+            vhost_user_paths: Vec::new(),
+            vsock_port: None,
+            tcp_addr: None,
+            quic_addr: None,
+            quic_cert_path: None,
+            quic_key_path: None,
+            unix_path: None,
+            shm_path: None,
+            dtls_psk_path: None,
+            auth_psk_path: None,
+            vxlan_addr: None,
+            geneve_addr: None,
+            dscp: None,
+        },
+        state_path: None,
+        mac_snapshot_path: None,
+        mac_snapshot_interval: None,
+        admin_path: None,
+        sample_collector: None,
+        sample_rate: None,
+        peers: Vec::new(),
+        accounting_path: None,
+        quota: None,
+        quota_action: None,
+        dhcp_path: None,
+        chaos: None,
+        chaos_seed: None,
+        reflect: Vec::new(),
+        reflect_services: Vec::new(),
+        flight_recorder: None,
+        flight_recorder_dir: None,
+        bum_group: None,
+        mtu: None,
+        flooding: None,
+        stp: None,
+        static_macs: Vec::new(),
+        mac_limit: None,
+        mac_limit_action: None,
+        mac_move_limit: None,
+        mac_move_action: None,
+        storm_control: None,
+        igmp_snooping: None,
+        vlan_requests: None,
+        compression: None,
+        fragmentation: None,
+        direct_paths: None,
+        batching: None,
+        fec: None,
+        arp_suppression: None,
+        daemon: None,
+        log: None,
+    };
+    let mut opts = opts.iter();
+    while let Some(opt) = opts.next() {
+        let value = opts
+            .next()
+            .ok_or_else(|| format!("Missing value for command line option '{}'", opt))?;
+
+        let listeners = &mut config.listeners;
+        let replaced = match opt.as_str() {
+            "--listen" => {
+                listeners.listen.push(parse_listen(value)?);
+                false
+            }
+            "--vhost-user" => {
+                listeners.vhost_user_paths.push(value.clone());
+                false
+            }
+            "--unix" => listeners.unix_path.replace(value.clone()).is_some(),
+            "--shm" => listeners.shm_path.replace(value.clone()).is_some(),
+            "--dtls-psk-file" => listeners.dtls_psk_path.replace(value.clone()).is_some(),
+            "--auth-psk-file" => listeners.auth_psk_path.replace(value.clone()).is_some(),
+            "--quic-cert-file" => listeners.quic_cert_path.replace(value.clone()).is_some(),
+            "--dscp" => listeners
+                .dscp
+                .replace(Dscp::parse(value).map_err(|e| format!("--dscp: {}", e))?)
+                .is_some(),
+            "--quic-key-file" => listeners.quic_key_path.replace(value.clone()).is_some(),
+            "--state-file" => config.state_path.replace(value.clone()).is_some(),
+            "--daemon" => config.daemon.replace(value.clone()).is_some(),
+            "--log" => config.log.replace(value.clone()).is_some(),
+            "--mac-snapshot" => config.mac_snapshot_path.replace(value.clone()).is_some(),
+            "--mac-snapshot-interval" => {
+                let secs = value
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|secs| *secs > 0)
+                    .ok_or_else(|| {
+                        format!(
+                            "Expected a number of seconds for --mac-snapshot-interval, got '{}'",
+                            value
+                        )
+                    })?;
+                config
+                    .mac_snapshot_interval
+                    .replace(Duration::from_secs(secs))
+                    .is_some()
+            }
+            "--admin-socket" => config.admin_path.replace(value.clone()).is_some(),
+            "--vsock" => {
+                let vsock_port = value
+                    .parse::<u32>()
+                    .map_err(|e| format!("Could not parse '{}' as vsock port: {}", value, e))?;
+                listeners.vsock_port.replace(vsock_port).is_some()
+            }
+            "--tcp" => {
+                let tcp_addr = value
+                    .parse::<SocketAddr>()
+                    .map_err(|e| format!("Could not parse '{}' as TCP address: {}", value, e))?;
+                listeners.tcp_addr.replace(tcp_addr).is_some()
+            }
+            "--quic" => {
+                let quic_addr = value
+                    .parse::<SocketAddr>()
+                    .map_err(|e| format!("Could not parse '{}' as QUIC address: {}", value, e))?;
+                listeners.quic_addr.replace(quic_addr).is_some()
+            }
+            "--vxlan" => {
+                let vxlan_addr = value
+                    .parse::<SocketAddr>()
+                    .map_err(|e| format!("Could not parse '{}' as VXLAN address: {}", value, e))?;
+                listeners.vxlan_addr.replace(vxlan_addr).is_some()
+            }
+            "--geneve" => {
+                let geneve_addr = value
+                    .parse::<SocketAddr>()
+                    .map_err(|e| format!("Could not parse '{}' as GENEVE address: {}", value, e))?;
+                listeners.geneve_addr.replace(geneve_addr).is_some()
+            }
+            "--bum-group" => {
+                let group = value.parse::<SocketAddrV4>().map_err(|e| {
+                    format!("Could not parse '{}' as BUM group address: {}", value, e)
+                })?;
+                config.bum_group.replace(group).is_some()
+            }
+            "--mtu" => config
+                .mtu
+                .replace(parse_overlay_mtu(value).map_err(|e| format!("--mtu: {}", e))?)
+                .is_some(),
+            "--flooding" => config
+                .flooding
+                .replace(parse_on_off(value).map_err(|e| format!("--flooding: {}", e))?)
+                .is_some(),
+            "--igmp-snooping" => config
+                .igmp_snooping
+                .replace(parse_on_off(value).map_err(|e| format!("--igmp-snooping: {}", e))?)
+                .is_some(),
+            "--vlan-requests" => config
+                .vlan_requests
+                .replace(parse_on_off(value).map_err(|e| format!("--vlan-requests: {}", e))?)
+                .is_some(),
+            "--compression" => config
+                .compression
+                .replace(parse_on_off(value).map_err(|e| format!("--compression: {}", e))?)
+                .is_some(),
+            "--fragmentation" => config
+                .fragmentation
+                .replace(parse_on_off(value).map_err(|e| format!("--fragmentation: {}", e))?)
+                .is_some(),
+            "--direct-paths" => config
+                .direct_paths
+                .replace(parse_on_off(value).map_err(|e| format!("--direct-paths: {}", e))?)
+                .is_some(),
+            "--batching" => config
+                .batching
+                .replace(parse_on_off(value).map_err(|e| format!("--batching: {}", e))?)
+                .is_some(),
+            "--fec" => config
+                .fec
+                .replace(parse_on_off(value).map_err(|e| format!("--fec: {}", e))?)
+                .is_some(),
+            "--arp-suppression" => config
+                .arp_suppression
+                .replace(parse_on_off(value).map_err(|e| format!("--arp-suppression: {}", e))?)
+                .is_some(),
+            "--static-mac" => {
+                config.static_macs.push(parse_static_mac(value)?);
+                false
+            }
+            "--mac-limit" => {
+                let limit = value
+                    .parse::<usize>()
+                    .map_err(|e| format!("Could not parse '{}' as MAC limit: {}", value, e))?;
+                config.mac_limit.replace(limit).is_some()
+            }
+            "--mac-limit-action" => {
+                let action = MacLimitAction::parse(value).ok_or_else(|| {
+                    format!(
+                        "Expected 'drop', 'log' or 'shutdown' for --mac-limit-action, got '{}'",
+                        value
+                    )
+                })?;
+                config.mac_limit_action.replace(action).is_some()
+            }
+            "--mac-move-limit" => {
+                let limit =
+                    parse_move_rate(value).map_err(|e| format!("--mac-move-limit: {}", e))?;
+                config.mac_move_limit.replace(limit).is_some()
+            }
+            "--mac-move-action" => {
+                let action = MacMoveAction::parse(value).ok_or_else(|| {
+                    format!(
+                        "Expected 'log', 'dampen' or 'shutdown' for --mac-move-action, got '{}'",
+                        value
+                    )
+                })?;
+                config.mac_move_action.replace(action).is_some()
+            }
+            "--storm-control" => {
+                let rate = value.parse::<u32>().map_err(|e| {
+                    format!("Could not parse '{}' as storm control rate: {}", value, e)
+                })?;
+                config.storm_control.replace(rate).is_some()
+            }
+            "--stp" => {
+                let priority = value.parse::<u16>().map_err(|e| {
+                    format!("Could not parse '{}' as STP bridge priority: {}", value, e)
+                })?;
+                config.stp.replace(priority).is_some()
+            }
+            "--sample-collector" => {
+                let collector = value.parse::<SocketAddr>().map_err(|e| {
+                    format!("Could not parse '{}' as collector address: {}", value, e)
+                })?;
+                config.sample_collector.replace(collector).is_some()
+            }
+            "--peer" => {
+                let peer = value
+                    .parse::<SocketAddr>()
+                    .map_err(|e| format!("Could not parse '{}' as peer address: {}", value, e))?;
+                config.peers.push(peer);
+                false
+            }
+            "--accounting-file" => config.accounting_path.replace(value.clone()).is_some(),
+            "--dhcp-config" => config.dhcp_path.replace(value.clone()).is_some(),
+            "--chaos" => config.chaos.replace(ChaosConfig::parse(value)?).is_some(),
+            "--chaos-seed" => {
+                let seed = value
+                    .parse::<u64>()
+                    .map_err(|e| format!("Could not parse '{}' as chaos seed: {}", value, e))?;
+                config.chaos_seed.replace(seed).is_some()
+            }
+            "--reflect" => {
+                config.reflect.push(ReflectRule::parse(value)?);
+                false
+            }
+            "--reflect-service" => {
+                config.reflect_services.push(value.clone());
+                false
+            }
+            "--flight-recorder" => config
+                .flight_recorder
+                .replace(RecorderConfig::parse(value)?)
+                .is_some(),
+            "--flight-recorder-dir" => config.flight_recorder_dir.replace(value.clone()).is_some(),
+            "--quota" => {
+                let quota = value
+                    .parse::<u64>()
+                    .map_err(|e| format!("Could not parse '{}' as quota: {}", value, e))?;
+                config.quota.replace(quota).is_some()
+            }
+            "--quota-action" => {
+                let action = QuotaAction::parse(value).ok_or_else(|| {
+                    format!(
+                        "Expected 'rate-limit' or 'shutdown' for --quota-action, got '{}'",
+                        value
+                    )
+                })?;
+                config.quota_action.replace(action).is_some()
+            }
+            "--sample-rate" => {
+                let rate = value
+                    .parse::<u32>()
+                    .map_err(|e| format!("Could not parse '{}' as sample rate: {}", value, e))?;
+                config.sample_rate.replace(rate).is_some()
+            }
+            _ => return Err(format!("Could not parse command line option '{}'", opt)),
+        };
+
+        if replaced {
+            return Err(format!(
+                "Command line option '{}' given more than once",
+                opt
+            ));
+        }
+    }
+
+    Ok(config)
+}
+
+/// Parse the value of --listen, which is an address and
+/// the segment which the vports reaching it belong to
+fn parse_listen(value: &str) -> Result<(SocketAddr, u32), String> {
+    let Some((addr, segment)) = value.rsplit_once('=') else {
+        return Err(format!(
+            "Expected '<ip:port>=<segment>' for --listen, got '{}'",
+            value
+        ));
+    };
+
+    let addr = addr
+        .parse::<SocketAddr>()
+        .map_err(|e| format!("Could not parse '{}' as listen address: {}", addr, e))?;
+    let segment = segment
+        .parse::<u32>()
+        .map_err(|e| format!("Could not parse '{}' as segment: {}", segment, e))?;
+
+    Ok((addr, segment))
+}
+
+/// Parse the value of --static-mac, which is a MAC, the address of
+/// the vport it is on, and optionally the VLAN it is in
+fn parse_static_mac(value: &str) -> Result<(MacAddr, SocketAddr, Option<u16>), String> {
+    let Some((mac, rest)) = value.split_once('=') else {
+        return Err(format!(
+            "Expected '<mac>=<ip:port>[/<vlan_id>]' for --static-mac, got '{}'",
+            value
+        ));
+    };
+    let (addr, vlan) = match rest.split_once('/') {
+        Some((addr, vlan)) => (addr, Some(parse_vlan_id(vlan)?)),
+        None => (rest, None),
+    };
+
+    let mac = mac
+        .parse::<MacAddr>()
+        .map_err(|_| format!("Could not parse '{}' as MAC", mac))?;
+    let addr = addr
+        .parse::<SocketAddr>()
+        .map_err(|e| format!("Could not parse '{}' as vport address: {}", addr, e))?;
+
+    Ok((mac, addr, vlan))
+}
+
+/// Returns every problem with config which would stop the
+/// vswitch from starting, or leave it misbehaving once started
+pub fn validate(config: &Config) -> Vec<String> {
+    let mut errors = Vec::new();
+    let listeners = &config.listeners;
+
+    if config.port == 0 {
+        errors.push("Port 0 would bind a random port, which vports could not find".to_string());
+    }
+
+    for (i, (addr, _)) in listeners.listen.iter().enumerate() {
+        if addr.port() == 0 {
+            errors.push(format!(
+                "--listen '{}' would bind a random port, which vports could not find",
+                addr
+            ));
+        }
+        /* The vswitch's own socket is bound to this port on every address */
+        if addr.port() == config.port {
+            errors.push(format!(
+                "--listen '{}' uses the vswitch's port {}",
+                addr, config.port
+            ));
+        }
+        if listeners.listen[..i].iter().any(|(other, _)| other == addr) {
+            errors.push(format!("--listen '{}' given more than once", addr));
+        }
+    }
+
+    if !cfg!(feature = "vhost-user") && !listeners.vhost_user_paths.is_empty() {
+        errors.push(
+            "--vhost-user given, but vswitch was built without the vhost-user feature".to_string(),
+        );
+    }
+
+    if !cfg!(feature = "dtls") && listeners.dtls_psk_path.is_some() {
+        errors.push(
+            "--dtls-psk-file given, but vswitch was built without the dtls feature".to_string(),
+        );
+    }
+    #[cfg(feature = "dtls")]
+    if let Some(path) = &listeners.dtls_psk_path {
+        if let Err(e) = read_psk("--dtls-psk-file", path) {
+            errors.push(e);
+        }
+    }
+    if listeners.dtls_psk_path.is_some() {
+        /* Frames sent to other vswitches and BUM groups aren't in a DTLS session */
+        if !config.peers.is_empty() {
+            errors.push("--dtls-psk-file can't be given with --peer".to_string());
+        }
+        if config.bum_group.is_some() {
+            errors.push("--dtls-psk-file can't be given with --bum-group".to_string());
+        }
+        if listeners.auth_psk_path.is_some() {
+            errors.push("--dtls-psk-file can't be given with --auth-psk-file".to_string());
+        }
+    }
+    if let Some(path) = &listeners.auth_psk_path {
+        if let Err(e) = read_psk("--auth-psk-file", path) {
+            errors.push(e);
+        }
+        /* vports in BUM groups couldn't tell the vswitch's flooded frames from anyone else's */
+        if config.bum_group.is_some() {
+            errors.push("--auth-psk-file can't be given with --bum-group".to_string());
+        }
+    }
+
+    /* VMADDR_PORT_ANY asks the kernel to pick a port, which vports could not find either */
+    if listeners.vsock_port == Some(u32::MAX) {
+        errors.push(format!(
+            "vsock port {} would bind a random port, which vports could not find",
+            u32::MAX
+        ));
+    }
+
+    /* Port 0 would bind a random port too */
+    if let Some(tcp_addr) = listeners.tcp_addr {
+        if tcp_addr.port() == 0 {
+            errors.push(format!(
+                "--tcp '{}' would bind a random port, which vports could not find",
+                tcp_addr
+            ));
+        }
+    }
+
+    if !cfg!(feature = "quic") && listeners.quic_addr.is_some() {
+        errors.push("--quic given, but vswitch was built without the quic feature".to_string());
+    }
+    if let Some(quic_addr) = listeners.quic_addr {
+        if quic_addr.port() == 0 {
+            errors.push(format!(
+                "--quic '{}' would bind a random port, which vports could not find",
+                quic_addr
+            ));
+        }
+        if quic_addr.port() == config.port {
+            errors.push(format!(
+                "--quic '{}' uses the vswitch's port {}",
+                quic_addr, config.port
+            ));
+        }
+        if listeners.quic_cert_path.is_none() || listeners.quic_key_path.is_none() {
+            errors.push("--quic needs --quic-cert-file and --quic-key-file".to_string());
+        }
+        #[cfg(feature = "quic")]
+        if let (Some(cert_path), Some(key_path)) =
+            (&listeners.quic_cert_path, &listeners.quic_key_path)
+        {
+            if let Err(e) = l2vpn::quic::server_config(cert_path, key_path) {
+                errors.push(e.to_string());
+            }
+        }
+    } else if listeners.quic_cert_path.is_some() || listeners.quic_key_path.is_some() {
+        errors.push("--quic-cert-file and --quic-key-file given without --quic".to_string());
+    }
+
+    let vteps = [
+        ("--vxlan", listeners.vxlan_addr),
+        ("--geneve", listeners.geneve_addr),
+    ];
+    for (flag, addr) in vteps {
+        let Some(addr) = addr else {
+            continue;
+        };
+        /* VTEPs send to the port they listen on, so it has to be known */
+        if addr.port() == 0 {
+            errors.push(format!(
+                "{} '{}' would bind a random port, which VTEPs could not find",
+                flag, addr
+            ));
+        }
+        if addr.port() == config.port {
+            errors.push(format!(
+                "{} '{}' uses the vswitch's port {}",
+                flag, addr, config.port
+            ));
+        }
+        /* VTEPs can't take part in DTLS sessions or sign their datagrams */
+        if listeners.dtls_psk_path.is_some() {
+            errors.push(format!("{} can't be given with --dtls-psk-file", flag));
+        }
+        if listeners.auth_psk_path.is_some() {
+            errors.push(format!("{} can't be given with --auth-psk-file", flag));
+        }
+    }
+    if listeners.vxlan_addr.is_some() && listeners.vxlan_addr == listeners.geneve_addr {
+        errors.push("--vxlan and --geneve can't be given the same address".to_string());
+    }
+
+    if let Some(group) = config.bum_group {
+        if !group.ip().is_multicast() || group.port() == 0 {
+            errors.push(format!(
+                "--bum-group '{}' is not a multicast group address and port",
+                group
+            ));
+        }
+    }
+
+    if config.sample_rate == Some(0) {
+        errors.push("--sample-rate must be at least 1".to_string());
+    }
+    if config.sample_rate.is_some() && config.sample_collector.is_none() {
+        errors.push("--sample-rate given without --sample-collector".to_string());
+    }
+    if let Some(collector) = config.sample_collector {
+        if collector.port() == 0 || collector.ip().is_unspecified() {
+            errors.push(format!(
+                "--sample-collector '{}' is not an address samples can be sent to",
+                collector
+            ));
+        }
+    }
+
+    if config.quota == Some(0) {
+        errors.push("--quota must be at least 1 byte".to_string());
+    }
+    if config.quota_action.is_some() && config.quota.is_none() {
+        errors.push("--quota-action given without --quota".to_string());
+    }
+
+    for (i, (mac, _, _)) in config.static_macs.iter().enumerate() {
+        if config.static_macs[..i]
+            .iter()
+            .any(|(other, _, _)| other == mac)
+        {
+            errors.push(format!("--static-mac '{}' given more than once", mac));
+        }
+    }
+
+    if config.mac_limit == Some(0) {
+        errors.push("--mac-limit must be at least 1 MAC".to_string());
+    }
+    if config.mac_limit_action.is_some() && config.mac_limit.is_none() {
+        errors.push("--mac-limit-action given without --mac-limit".to_string());
+    }
+    if config.mac_snapshot_interval.is_some() && config.mac_snapshot_path.is_none() {
+        errors.push("--mac-snapshot-interval given without --mac-snapshot".to_string());
+    }
+    if config.mac_move_action.is_some() && config.mac_move_limit.is_none() {
+        errors.push("--mac-move-action given without --mac-move-limit".to_string());
+    }
+
+    if config.storm_control == Some(0) {
+        errors.push("--storm-control must be at least 1 frame per second".to_string());
+    }
+
+    if config.chaos_seed.is_some() && config.chaos.is_none() {
+        errors.push("--chaos-seed given without --chaos".to_string());
+    }
+
+    if config.flight_recorder_dir.is_some() && config.flight_recorder.is_none() {
+        errors.push("--flight-recorder-dir given without --flight-recorder".to_string());
+    }
+    if let Some(dir) = &config.flight_recorder_dir {
+        if !Path::new(dir).is_dir() {
+            errors.push(format!(
+                "--flight-recorder-dir '{}' is not a directory",
+                dir
+            ));
+        }
+    }
+
+    if !config.reflect_services.is_empty() && config.reflect.is_empty() {
+        errors.push("--reflect-service given without --reflect".to_string());
+    }
+    for rule in config.reflect.iter() {
+        for endpoint in rule.endpoints.iter() {
+            let segment = endpoint.segment;
+            if segment != DEFAULT_SEGMENT && !listeners.listen.iter().any(|(_, s)| *s == segment) {
+                errors.push(format!(
+                    "--reflect '{}' names segment {}, which no --listen address leads to",
+                    rule, segment
+                ));
+            }
+        }
+    }
+
+    for (i, peer) in config.peers.iter().enumerate() {
+        if peer.port() == 0 || peer.ip().is_unspecified() || peer.ip().is_multicast() {
+            errors.push(format!("--peer '{}' is not an address of a vswitch", peer));
+        }
+        if config.peers[..i].contains(peer) {
+            errors.push(format!("--peer '{}' given more than once", peer));
+        }
+    }
+
+    /* Every file the vswitch creates needs its own path, in a directory which exists */
+    let mut paths: Vec<(&str, &String)> = listeners
+        .vhost_user_paths
+        .iter()
+        .map(|path| ("--vhost-user", path))
+        .collect();
+    paths.extend(listeners.unix_path.iter().map(|path| ("--unix", path)));
+    paths.extend(listeners.shm_path.iter().map(|path| ("--shm", path)));
+    paths.extend(config.state_path.iter().map(|path| ("--state-file", path)));
+    paths.extend(
+        config
+            .mac_snapshot_path
+            .iter()
+            .map(|path| ("--mac-snapshot", path)),
+    );
+    paths.extend(
+        config
+            .accounting_path
+            .iter()
+            .map(|path| ("--accounting-file", path)),
+    );
+    paths.extend(
+        config
+            .admin_path
+            .iter()
+            .map(|path| ("--admin-socket", path)),
+    );
+    paths.extend(config.daemon.iter().map(|path| ("--daemon", path)));
+    paths.extend(
+        config
+            .log
+            .iter()
+            .filter(|log| *log != "syslog")
+            .map(|path| ("--log", path)),
+    );
+
+    for (i, (opt, path)) in paths.iter().enumerate() {
+        if let Some((other_opt, _)) = paths[..i].iter().find(|(_, other)| other == path) {
+            errors.push(format!(
+                "{} '{}' is the same path as {} '{}'",
+                opt, path, other_opt, path
+            ));
+        }
+
+        let dir = match Path::new(path).parent() {
+            Some(dir) if dir.as_os_str().is_empty() => Path::new("."),
+            Some(dir) => dir,
+            None => {
+                errors.push(format!("{} '{}' is not a path to a file", opt, path));
+                continue;
+            }
+        };
+        if !dir.is_dir() {
+            errors.push(format!(
+                "{} '{}': directory '{}' does not exist",
+                opt,
+                path,
+                dir.display()
+            ));
+        }
+    }
+
+    errors
+}
+
+/// Returns the pre-shared key kept in the file at path, given with flag
+pub fn read_psk(flag: &str, path: &str) -> Result<Vec<u8>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("{} '{}': {}", flag, path, e))?;
+    parse_psk(&contents).map_err(|e| format!("{} '{}': {}", flag, path, e))
+}
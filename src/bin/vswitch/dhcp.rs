@@ -0,0 +1,562 @@
+//! Built-in DHCP server for the vswitch
+//!
+//! Small deployments can have the vswitch hand out IPv4 addresses to
+//! the hosts in one of its segments, rather than running a DHCP server
+//! on the virtual LAN. The server is configured by a file such as:
+//!
+//! ```text
+//! server 10.0.0.254
+//! pool 10.0.0.100 10.0.0.199
+//! netmask 255.255.255.0
+//! router 10.0.0.1
+//! dns 10.0.0.1 9.9.9.9
+//! domain lab.example
+//! lease 3600
+//! reserve 02:00:00:00:00:01 10.0.0.10
+//! segment 0
+//! ```
+//!
+//! where server, pool and netmask are required. DHCP requests from the
+//! segment (and ARP requests for the server's address) are answered by
+//! the vswitch, rather than being forwarded. Leases are only kept in
+//! memory, but clients which return after a restart are given their
+//! address back if nobody else has it
+
+use crate::events::EventLog;
+use l2vpn::{arp::Arp, mac::MacAddr, utilities::ETHER_HDR};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs,
+    net::Ipv4Addr,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// Locally administered MAC which the DHCP server sends from
+const SERVER_MAC: MacAddr = MacAddr([0x02, 0x4c, 0x32, 0x56, 0x50, 0x44]);
+
+/// How long leases last, unless the configuration says otherwise
+const DEFAULT_LEASE: Duration = Duration::from_secs(3600);
+
+/// How long an offered address is held for the client it was offered to
+const OFFER_HOLD: Duration = Duration::from_secs(60);
+
+const IPV4_ETHER_TYPE: u16 = 0x0800;
+const UDP_PROTO: u8 = 17;
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+
+/// Offset of the magic cookie in a DHCP message, after the fixed fields
+const DHCP_COOKIE_OFFSET: usize = 236;
+const DHCP_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+/* DHCP message types, carried in option 53 */
+const DHCP_DISCOVER: u8 = 1;
+const DHCP_OFFER: u8 = 2;
+const DHCP_REQUEST: u8 = 3;
+const DHCP_DECLINE: u8 = 4;
+const DHCP_ACK: u8 = 5;
+const DHCP_NAK: u8 = 6;
+const DHCP_RELEASE: u8 = 7;
+const DHCP_INFORM: u8 = 8;
+
+/* DHCP options */
+const OPT_PAD: u8 = 0;
+const OPT_NETMASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_DNS: u8 = 6;
+const OPT_DOMAIN: u8 = 15;
+const OPT_REQUESTED_IP: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MSG_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_RENEWAL_TIME: u8 = 58;
+const OPT_REBINDING_TIME: u8 = 59;
+const OPT_END: u8 = 255;
+
+/// Configuration of the DHCP server, as read from its file
+#[derive(Debug)]
+struct DhcpConfig {
+    segment: u32,
+    server: Ipv4Addr,
+    pool: (u32, u32),
+    netmask: Ipv4Addr,
+    router: Option<Ipv4Addr>,
+    dns: Vec<Ipv4Addr>,
+    domain: Option<String>,
+    lease: Duration,
+    reservations: HashMap<MacAddr, Ipv4Addr>,
+}
+
+/// Address handed out to a client
+#[derive(Debug)]
+struct Lease {
+    ip: Ipv4Addr,
+    expires: Instant,
+    /// False while the address has only been offered
+    bound: bool,
+}
+
+/// DHCP request received from a client
+struct Request {
+    msg_type: u8,
+    xid: [u8; 4],
+    flags: [u8; 2],
+    ciaddr: Ipv4Addr,
+    giaddr: Ipv4Addr,
+    chaddr: MacAddr,
+    requested_ip: Option<Ipv4Addr>,
+    server_id: Option<Ipv4Addr>,
+}
+
+/// DHCP server serving one segment of the vswitch
+#[derive(Debug)]
+pub struct DhcpServer {
+    config: DhcpConfig,
+    leases: HashMap<MacAddr, Lease>,
+}
+
+impl DhcpServer {
+    /// Load the DHCP server's configuration from the file at path
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<DhcpServer, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)?;
+
+        let mut segment = 0;
+        let mut server = None;
+        let mut pool = None;
+        let mut netmask = None;
+        let mut router = None;
+        let mut dns = Vec::new();
+        let mut domain = None;
+        let mut lease = DEFAULT_LEASE;
+        let mut reservations = HashMap::new();
+
+        for (line_no, line) in contents.lines().enumerate() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let parsed: Result<(), Box<dyn Error>> = (|| {
+                match fields.as_slice() {
+                    [] | ["#", ..] => {}
+                    ["segment", value] => segment = value.parse()?,
+                    ["server", ip] => server = Some(ip.parse()?),
+                    ["pool", start, end] => {
+                        let (start, end): (Ipv4Addr, Ipv4Addr) = (start.parse()?, end.parse()?);
+                        if start > end {
+                            return Err("pool starts after it ends".into());
+                        }
+                        pool = Some((u32::from(start), u32::from(end)));
+                    }
+                    ["netmask", ip] => netmask = Some(ip.parse()?),
+                    ["router", ip] => router = Some(ip.parse()?),
+                    ["dns", ips @ ..] if !ips.is_empty() => {
+                        for ip in ips {
+                            dns.push(ip.parse()?);
+                        }
+                    }
+                    ["domain", name] => domain = Some(name.to_string()),
+                    ["lease", secs] => lease = Duration::from_secs(secs.parse()?),
+                    ["reserve", mac, ip] => {
+                        let mac = mac
+                            .parse::<MacAddr>()
+                            .map_err(|_| format!("invalid MAC '{}'", mac))?;
+                        reservations.insert(mac, ip.parse()?);
+                    }
+                    _ => return Err("unrecognised line".into()),
+                }
+                Ok(())
+            })();
+
+            if let Err(e) = parsed {
+                return Err(format!("DHCP config line {}: {}", line_no + 1, e).into());
+            }
+        }
+
+        let (Some(server), Some(pool), Some(netmask)) = (server, pool, netmask) else {
+            return Err("DHCP config must give the server, pool and netmask".into());
+        };
+
+        Ok(DhcpServer {
+            config: DhcpConfig {
+                segment,
+                server,
+                pool,
+                netmask,
+                router,
+                dns,
+                domain,
+                lease,
+                reservations,
+            },
+            leases: HashMap::new(),
+        })
+    }
+
+    /// Returns the segment which the server hands out addresses in
+    pub fn segment(&self) -> u32 {
+        self.config.segment
+    }
+
+    /// Returns true if frame is meant for the server, i.e. it is a
+    /// DHCP request, or an ARP request for the server's address
+    pub fn is_request(&self, frame: &[u8]) -> bool {
+        parse_dhcp(frame).is_some() || self.arp_request(frame).is_some()
+    }
+
+    /// Returns the ARP request which frame carries,
+    /// if it asks for the MAC of the server's address
+    fn arp_request(&self, frame: &[u8]) -> Option<Arp> {
+        Arp::parse(frame).filter(|arp| arp.is_request() && arp.target_ip == self.config.server)
+    }
+
+    /// Returns the answer to frame, which is_request returned true
+    /// for, if it needs one, recording leases being handed out
+    pub fn handle(&mut self, frame: &[u8], events: &mut EventLog) -> Option<Vec<u8>> {
+        if let Some(request) = self.arp_request(frame) {
+            return Some(request.reply(SERVER_MAC));
+        }
+
+        let request = parse_dhcp(frame)?;
+        let mac = request.chaddr;
+        let now = Instant::now();
+        self.leases.retain(|_, lease| lease.expires > now);
+
+        match request.msg_type {
+            DHCP_DISCOVER => {
+                let ip = self.allocate(&mac, request.requested_ip)?;
+                self.leases.insert(
+                    mac,
+                    Lease {
+                        ip,
+                        expires: now + OFFER_HOLD,
+                        bound: false,
+                    },
+                );
+                Some(self.reply(&request, DHCP_OFFER, ip))
+            }
+            DHCP_REQUEST => {
+                /* The client has chosen another server's offer */
+                if request.server_id.is_some_and(|id| id != self.config.server) {
+                    if self.leases.get(&mac).is_some_and(|lease| !lease.bound) {
+                        self.leases.remove(&mac);
+                    }
+                    return None;
+                }
+
+                let requested = request.requested_ip.unwrap_or(request.ciaddr);
+                if self.allocate(&mac, Some(requested)) != Some(requested) {
+                    return Some(self.reply(&request, DHCP_NAK, Ipv4Addr::UNSPECIFIED));
+                }
+
+                let renewed = self
+                    .leases
+                    .get(&mac)
+                    .is_some_and(|lease| lease.bound && lease.ip == requested);
+                self.leases.insert(
+                    mac,
+                    Lease {
+                        ip: requested,
+                        expires: now + self.config.lease,
+                        bound: true,
+                    },
+                );
+                if !renewed {
+                    events.record(format!(
+                        "DHCP leased {} to {} for {}s",
+                        requested,
+                        mac,
+                        self.config.lease.as_secs()
+                    ));
+                }
+                Some(self.reply(&request, DHCP_ACK, requested))
+            }
+            DHCP_RELEASE | DHCP_DECLINE => {
+                if let Some(lease) = self.leases.remove(&mac) {
+                    events.record(format!(
+                        "DHCP lease of {} to {} ended by the client",
+                        lease.ip, mac
+                    ));
+                }
+                None
+            }
+            /* The client already has an address, and just wants the options */
+            DHCP_INFORM => Some(self.reply(&request, DHCP_ACK, Ipv4Addr::UNSPECIFIED)),
+            _ => None,
+        }
+    }
+
+    /// Returns the address for the client with mac, which is its
+    /// reservation, its current lease, preferred (if it is free),
+    /// or the first free address in the pool, in that order
+    fn allocate(&self, mac: &MacAddr, preferred: Option<Ipv4Addr>) -> Option<Ipv4Addr> {
+        if let Some(ip) = self.config.reservations.get(mac) {
+            return Some(*ip);
+        }
+        if let Some(lease) = self.leases.get(mac) {
+            return Some(lease.ip);
+        }
+
+        let is_free = |ip: Ipv4Addr| {
+            let (start, end) = self.config.pool;
+            (start..=end).contains(&u32::from(ip))
+                && ip != self.config.server
+                && !self.config.reservations.values().any(|r| *r == ip)
+                && !self.leases.values().any(|lease| lease.ip == ip)
+        };
+
+        match preferred {
+            Some(ip) if is_free(ip) => Some(ip),
+            _ => (self.config.pool.0..=self.config.pool.1)
+                .map(Ipv4Addr::from)
+                .find(|ip| is_free(*ip)),
+        }
+    }
+
+    /// Returns the frame carrying a reply of msg_type to request, giving
+    /// the client yiaddr, which is sent to the client's MAC and address,
+    /// or broadcast if the client asked for that or has no address yet
+    fn reply(&self, request: &Request, msg_type: u8, yiaddr: Ipv4Addr) -> Vec<u8> {
+        let config = &self.config;
+
+        let mut dhcp = vec![0u8; DHCP_COOKIE_OFFSET];
+        dhcp[0] = 2; /* BOOTREPLY */
+        dhcp[1] = 1; /* Ethernet */
+        dhcp[2] = 6;
+        dhcp[4..8].copy_from_slice(&request.xid);
+        dhcp[10..12].copy_from_slice(&request.flags);
+        dhcp[12..16].copy_from_slice(&request.ciaddr.octets());
+        dhcp[16..20].copy_from_slice(&yiaddr.octets());
+        dhcp[20..24].copy_from_slice(&config.server.octets());
+        dhcp[24..28].copy_from_slice(&request.giaddr.octets());
+        dhcp[28..34].copy_from_slice(&request.chaddr.0);
+        dhcp.extend_from_slice(&DHCP_COOKIE);
+
+        let mut option = |code: u8, value: &[u8]| {
+            dhcp.push(code);
+            dhcp.push(value.len() as u8);
+            dhcp.extend_from_slice(value);
+        };
+        option(OPT_MSG_TYPE, &[msg_type]);
+        option(OPT_SERVER_ID, &config.server.octets());
+        if msg_type != DHCP_NAK {
+            if msg_type != DHCP_ACK || !yiaddr.is_unspecified() {
+                let secs = u32::try_from(config.lease.as_secs()).unwrap_or(u32::MAX);
+                option(OPT_LEASE_TIME, &secs.to_be_bytes());
+                option(OPT_RENEWAL_TIME, &(secs / 2).to_be_bytes());
+                option(OPT_REBINDING_TIME, &(secs / 8 * 7).to_be_bytes());
+            }
+            option(OPT_NETMASK, &config.netmask.octets());
+            if let Some(router) = config.router {
+                option(OPT_ROUTER, &router.octets());
+            }
+            if !config.dns.is_empty() {
+                let dns: Vec<u8> = config.dns.iter().flat_map(|ip| ip.octets()).collect();
+                option(OPT_DNS, &dns);
+            }
+            if let Some(domain) = &config.domain {
+                option(OPT_DOMAIN, domain.as_bytes());
+            }
+        }
+        dhcp.push(OPT_END);
+
+        /* BOOTP relays and old clients expect messages of at least 300 bytes */
+        if dhcp.len() < 300 {
+            dhcp.resize(300, OPT_PAD);
+        }
+
+        /* Clients without an address yet may not be able to receive unicasts to it */
+        let broadcast = request.flags[0] & 0x80 != 0 || yiaddr.is_unspecified();
+        let (dst_mac, dst_ip) = match request.ciaddr.is_unspecified() {
+            _ if msg_type == DHCP_NAK => (MacAddr::BROADCAST, Ipv4Addr::BROADCAST),
+            false => (request.chaddr, request.ciaddr),
+            true if broadcast => (MacAddr::BROADCAST, Ipv4Addr::BROADCAST),
+            true => (request.chaddr, yiaddr),
+        };
+
+        udp_frame(dst_mac, config.server, dst_ip, &dhcp)
+    }
+
+    /// Returns the configuration and leases in human readable format
+    pub fn show(&self) -> String {
+        let config = &self.config;
+        let mut lines = vec![
+            format!(
+                "DHCP server {} in segment {}, handing out {} to {} (netmask {})",
+                config.server,
+                config.segment,
+                Ipv4Addr::from(config.pool.0),
+                Ipv4Addr::from(config.pool.1),
+                config.netmask
+            ),
+            format!("{} reservation(s)", config.reservations.len()),
+        ];
+
+        let mut reservations: Vec<String> = config
+            .reservations
+            .iter()
+            .map(|(mac, ip)| format!("  {}  {}", mac, ip))
+            .collect();
+        reservations.sort();
+        lines.extend(reservations);
+
+        let now = Instant::now();
+        let mut leases: Vec<(Ipv4Addr, String)> = self
+            .leases
+            .iter()
+            .filter(|(_, lease)| lease.expires > now)
+            .map(|(mac, lease)| {
+                let state = match lease.bound {
+                    true => "leased",
+                    false => "offered",
+                };
+                let line = format!(
+                    "  {:<15}  {}  {}, expires in {}s",
+                    lease.ip,
+                    mac,
+                    state,
+                    (lease.expires - now).as_secs()
+                );
+                (lease.ip, line)
+            })
+            .collect();
+        leases.sort();
+        lines.push(format!("{} lease(s)", leases.len()));
+        lines.extend(leases.into_iter().map(|(_, line)| line));
+
+        lines.join("\n")
+    }
+}
+
+/// Returns the DHCP request carried by frame, if it is an untagged
+/// IPv4 UDP datagram to the DHCP server port carrying one
+fn parse_dhcp(frame: &[u8]) -> Option<Request> {
+    let payload = ipv4_udp_payload(frame, DHCP_SERVER_PORT)?;
+    if payload.len() < DHCP_COOKIE_OFFSET + 4
+        || payload[0] != 1
+        || payload[1] != 1
+        || payload[2] != 6
+        || payload[DHCP_COOKIE_OFFSET..DHCP_COOKIE_OFFSET + 4] != DHCP_COOKIE
+    {
+        return None;
+    }
+
+    let ip = |offset: usize| {
+        Ipv4Addr::new(
+            payload[offset],
+            payload[offset + 1],
+            payload[offset + 2],
+            payload[offset + 3],
+        )
+    };
+    let mut request = Request {
+        msg_type: 0,
+        xid: payload[4..8].try_into().unwrap(),
+        flags: payload[10..12].try_into().unwrap(),
+        ciaddr: ip(12),
+        giaddr: ip(24),
+        chaddr: MacAddr(payload[28..34].try_into().unwrap()),
+        requested_ip: None,
+        server_id: None,
+    };
+
+    /* Options are (code, length, value), apart from pad and end */
+    let mut options = &payload[DHCP_COOKIE_OFFSET + 4..];
+    while let [code, rest @ ..] = options {
+        match *code {
+            OPT_PAD => {
+                options = rest;
+                continue;
+            }
+            OPT_END => break,
+            _ => {}
+        }
+        let [len, rest @ ..] = rest else {
+            break;
+        };
+        let value = rest.get(..*len as usize)?;
+        match (*code, value) {
+            (OPT_MSG_TYPE, [msg_type]) => request.msg_type = *msg_type,
+            (OPT_REQUESTED_IP, [a, b, c, d]) => {
+                request.requested_ip = Some(Ipv4Addr::new(*a, *b, *c, *d))
+            }
+            (OPT_SERVER_ID, [a, b, c, d]) => {
+                request.server_id = Some(Ipv4Addr::new(*a, *b, *c, *d))
+            }
+            _ => {}
+        }
+        options = &rest[*len as usize..];
+    }
+
+    match request.msg_type {
+        0 => None,
+        _ => Some(request),
+    }
+}
+
+/// Returns the payload of frame if it is an untagged IPv4
+/// UDP datagram (which isn't fragmented) to dst_port
+fn ipv4_udp_payload(frame: &[u8], dst_port: u16) -> Option<&[u8]> {
+    if frame.get(12..14)? != IPV4_ETHER_TYPE.to_be_bytes() {
+        return None;
+    }
+
+    let ip = &frame[ETHER_HDR..];
+    let header_len = usize::from(*ip.first()? & 0x0F) * 4;
+    let total_len = usize::from(u16::from_be_bytes(ip.get(2..4)?.try_into().unwrap()));
+    /* The more fragments flag, or a fragment offset */
+    let fragmented = ip.get(6..8)? != [0, 0] && (ip[6] & 0x3F != 0 || ip[7] != 0);
+    if ip[0] >> 4 != 4 || ip.get(9) != Some(&UDP_PROTO) || fragmented || total_len > ip.len() {
+        return None;
+    }
+
+    let udp = ip.get(header_len..total_len)?;
+    if udp.len() < 8 || udp[2..4] != dst_port.to_be_bytes() {
+        return None;
+    }
+    Some(&udp[8..])
+}
+
+/// Returns the frame carrying a UDP datagram from the DHCP
+/// server's port at src_ip to the client's port at dst_ip
+fn udp_frame(dst_mac: MacAddr, src_ip: Ipv4Addr, dst_ip: Ipv4Addr, payload: &[u8]) -> Vec<u8> {
+    let udp_len = 8 + payload.len();
+    let ip_len = 20 + udp_len;
+
+    let mut frame = Vec::with_capacity(ETHER_HDR + ip_len);
+    frame.extend_from_slice(&dst_mac.0);
+    frame.extend_from_slice(&SERVER_MAC.0);
+    frame.extend_from_slice(&IPV4_ETHER_TYPE.to_be_bytes());
+
+    /* IPv4 header, without options, and with the don't fragment flag */
+    let mut ip = [0u8; 20];
+    ip[0] = 0x45;
+    ip[2..4].copy_from_slice(&(ip_len as u16).to_be_bytes());
+    ip[6] = 0x40;
+    ip[8] = 64;
+    ip[9] = UDP_PROTO;
+    ip[12..16].copy_from_slice(&src_ip.octets());
+    ip[16..20].copy_from_slice(&dst_ip.octets());
+    let checksum = ipv4_checksum(&ip);
+    ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+    frame.extend_from_slice(&ip);
+
+    /* A UDP checksum of 0 means there is none, which IPv4 allows */
+    frame.extend_from_slice(&DHCP_SERVER_PORT.to_be_bytes());
+    frame.extend_from_slice(&DHCP_CLIENT_PORT.to_be_bytes());
+    frame.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    frame.extend_from_slice(&[0, 0]);
+    frame.extend_from_slice(payload);
+
+    frame
+}
+
+/// Returns the checksum of an IPv4 header
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum();
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
@@ -0,0 +1,44 @@
+//! Counts of the frames the vswitch drops
+//!
+//! Every frame the vswitch drops is dropped for one of the reasons in
+//! l2vpn::switching::DropReason, which is counted against the port the frame was received from,
+//! reported to monitors, and included in the frame log, so it is
+//! clear why traffic isn't getting through
+
+use l2vpn::switching::DropReason;
+
+/// Frames dropped from a port for each reason, which aren't saved in the state file
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DropCounters([u64; DropReason::ALL.len()]);
+
+impl DropCounters {
+    /// Count a frame dropped for reason
+    pub fn count(&mut self, reason: DropReason) {
+        self.0[reason as usize] += 1;
+    }
+
+    /// Returns the frames dropped for reason
+    pub fn get(&self, reason: DropReason) -> u64 {
+        self.0[reason as usize]
+    }
+
+    /// Returns the frames dropped for every reason
+    pub fn total(&self) -> u64 {
+        self.0.iter().sum()
+    }
+
+    /// Add the counts in other to these
+    pub fn add(&mut self, other: &DropCounters) {
+        for (count, other) in self.0.iter_mut().zip(other.0) {
+            *count += other;
+        }
+    }
+
+    /// Returns the reasons frames have been dropped for, and how many
+    pub fn iter(&self) -> impl Iterator<Item = (DropReason, u64)> + '_ {
+        DropReason::ALL
+            .into_iter()
+            .map(|reason| (reason, self.get(reason)))
+            .filter(|(_, count)| *count > 0)
+    }
+}
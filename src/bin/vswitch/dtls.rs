@@ -0,0 +1,156 @@
+//! DTLS sessions between the vswitch and the vports reached over UDP
+//!
+//! With DTLS on, every datagram received on the vswitch's UDP sockets
+//! (its port, and the --listen sockets) is taken by the DTLS session
+//! of the vport it came from. A vport which starts a handshake is given
+//! a new session, replacing any it had, and datagrams from vports with
+//! no session which don't start one are dropped, so frames sent in the
+//! clear are never switched. Frames are sent to each vport in its
+//! session, and dropped if it hasn't finished its handshake
+//!
+//! The sessions of vports which haven't been heard from in IDLE_TIMEOUT
+//! are forgotten as new sessions start, as those vports start new
+//! sessions of their own once they stop hearing from the vswitch
+//!
+//! Without the dtls feature, DTLS can't be turned on, so there are
+//! never any sessions
+
+#[cfg(feature = "dtls")]
+use crate::config::read_psk;
+use crate::VportAddr;
+#[cfg(feature = "dtls")]
+use l2vpn::dtls::{is_client_hello, server_context, DtlsSession, IDLE_TIMEOUT};
+use l2vpn::error::TransportError;
+#[cfg(feature = "dtls")]
+use openssl::ssl::SslContext;
+use std::net::{SocketAddr, UdpSocket};
+#[cfg(feature = "dtls")]
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::Instant,
+};
+
+/// DTLS sessions with the vports reached over UDP
+#[cfg(feature = "dtls")]
+#[derive(Debug)]
+pub struct DtlsPorts {
+    context: SslContext,
+    sessions: Mutex<HashMap<VportAddr, DtlsSession>>,
+}
+
+#[cfg(feature = "dtls")]
+impl DtlsPorts {
+    /// Returns the sessions of vports, which use the
+    /// pre-shared key in the file at psk_path
+    pub fn new(psk_path: &str) -> Result<DtlsPorts, String> {
+        let context = server_context(&read_psk("--dtls-psk-file", psk_path)?)
+            .map_err(|e| format!("Could not set up DTLS: {}", e))?;
+        Ok(DtlsPorts {
+            context,
+            sessions: Mutex::default(),
+        })
+    }
+
+    /// Take datagram, which the vport at vport sent from src to socket,
+    /// returning the frames it carried, and answering it on socket if
+    /// it is part of a handshake
+    pub fn receive(
+        &self,
+        socket: &UdpSocket,
+        src: SocketAddr,
+        vport: VportAddr,
+        datagram: &[u8],
+    ) -> Vec<Vec<u8>> {
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap_or_else(PoisonError::into_inner);
+        if is_client_hello(datagram) {
+            sessions.retain(|_, session| now.duration_since(session.last_heard()) < IDLE_TIMEOUT);
+            match DtlsSession::accept(&self.context, now) {
+                Ok(session) => sessions.insert(vport, session),
+                Err(e) => {
+                    eprintln!(
+                        "Got error while accepting DTLS session from '{}': {}",
+                        vport, e
+                    );
+                    return Vec::new();
+                }
+            };
+        }
+
+        /* The vport hasn't started a session, so its datagram can't be trusted */
+        let Some(session) = sessions.get_mut(&vport) else {
+            return Vec::new();
+        };
+        let frames = session.receive(datagram, now);
+        for datagram in session.take_datagrams() {
+            if let Err(e) = socket.send_to(&datagram, src) {
+                eprintln!(
+                    "Got error while sending DTLS handshake to '{}': {}",
+                    vport, e
+                );
+            }
+        }
+
+        frames.unwrap_or_else(|e| {
+            eprintln!("DTLS session with '{}' failed: {}", vport, e);
+            sessions.remove(&vport);
+            Vec::new()
+        })
+    }
+
+    /// Send frame to the vport at vport, whose address on socket is dst,
+    /// in its session. If it hasn't got one, there is nowhere to send it
+    pub fn send(
+        &self,
+        socket: &UdpSocket,
+        dst: SocketAddr,
+        vport: &VportAddr,
+        frame: &[u8],
+    ) -> Result<(), TransportError> {
+        let mut sessions = self.sessions.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(session) = sessions
+            .get_mut(vport)
+            .filter(|session| session.is_established())
+        else {
+            return Ok(());
+        };
+        session.seal(frame)?;
+        for datagram in session.take_datagrams() {
+            socket.send_to(&datagram, dst)?;
+        }
+        Ok(())
+    }
+}
+
+/// DTLS sessions, which can't be set up without the dtls feature
+#[cfg(not(feature = "dtls"))]
+#[derive(Debug)]
+pub enum DtlsPorts {}
+
+#[cfg(not(feature = "dtls"))]
+impl DtlsPorts {
+    pub fn new(_psk_path: &str) -> Result<DtlsPorts, String> {
+        Err("vswitch was built without the dtls feature".to_string())
+    }
+
+    pub fn receive(
+        &self,
+        _socket: &UdpSocket,
+        _src: SocketAddr,
+        _vport: VportAddr,
+        _datagram: &[u8],
+    ) -> Vec<Vec<u8>> {
+        match *self {}
+    }
+
+    pub fn send(
+        &self,
+        _socket: &UdpSocket,
+        _dst: SocketAddr,
+        _vport: &VportAddr,
+        _frame: &[u8],
+    ) -> Result<(), TransportError> {
+        match *self {}
+    }
+}
@@ -0,0 +1,43 @@
+//! Log of recent events in the vswitch
+//!
+//! Events such as vports connecting and MACs being learned are
+//! printed as they happen, and the most recent are also kept so
+//! that admin clients can show them
+
+use std::{
+    collections::VecDeque,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Number of events which are kept
+const EVENT_LOG_SIZE: usize = 64;
+
+/// Most recent events, oldest first
+#[derive(Default)]
+pub struct EventLog {
+    events: VecDeque<(SystemTime, String)>,
+}
+
+impl EventLog {
+    /// Print an event and add it to the log
+    pub fn record(&mut self, event: String) {
+        println!("{}", event);
+
+        if self.events.len() == EVENT_LOG_SIZE {
+            self.events.pop_front();
+        }
+        self.events.push_back((SystemTime::now(), event));
+    }
+
+    /// Returns the logged events, oldest first, along
+    /// with when they happened in seconds since the epoch
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &str)> {
+        self.events.iter().map(|(time, event)| {
+            let secs = time
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            (secs, event.as_str())
+        })
+    }
+}
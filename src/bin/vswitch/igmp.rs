@@ -0,0 +1,347 @@
+//! IGMP snooping for the vswitch
+//!
+//! Without snooping, a multicast is flooded like a broadcast, or dropped
+//! while flooding is off. With --igmp-snooping on, the vswitch watches
+//! the IGMP messages its vports send, so learns which of them have
+//! joined each group, and sends the group's multicasts only to them
+//!
+//! Multicasts are also sent to the ports which IGMP queries come from,
+//! as they lead to a multicast router, and to the peer vswitches, which
+//! may have members behind them. Link-local groups (224.0.0.0/24), such
+//! as those IGMP queries and v3 reports are sent to, are never joined,
+//! so they are always flooded. v1 and v2 reports are only sent towards
+//! the routers, so members don't suppress their own reports on hearing
+//! each other's, and leave the vswitch not knowing about them
+//!
+//! A vport's membership lasts for MEMBERSHIP_TIMEOUT unless it reports
+//! it again, which it does in answer to every query. A leave removes
+//! the vport from the group at once, as vports normally have one host.
+//! Memberships and router ports which have expired are swept away every
+//! SWEEP_INTERVAL, and at most MAX_GROUPS_PER_DOMAIN groups are joined in
+//! each domain, so a flood of reports can't exhaust memory
+
+use crate::{events::EventLog, ports::PortTable, vlan::Domain, VportAddr};
+use l2vpn::frame::EthernetFrame;
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::Ipv4Addr,
+    time::{Duration, Instant},
+};
+
+/// How long a membership or router port lasts without being heard from
+/// again, which is the default group membership interval of RFC 3376
+pub const MEMBERSHIP_TIMEOUT: Duration = Duration::from_secs(260);
+
+/// How often memberships and router ports which have expired are forgotten
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Most groups joined in each domain
+const MAX_GROUPS_PER_DOMAIN: usize = 8192;
+
+const IPV4_ETHER_TYPE: u16 = 0x0800;
+const IGMP_PROTOCOL: u8 = 2;
+
+/// Types of IGMP message
+const IGMP_QUERY: u8 = 0x11;
+const IGMP_V1_REPORT: u8 = 0x12;
+const IGMP_V2_REPORT: u8 = 0x16;
+const IGMP_LEAVE: u8 = 0x17;
+const IGMP_V3_REPORT: u8 = 0x22;
+
+/// Types of group record in an IGMPv3 report which, without any
+/// sources, mean the host no longer wants the group's multicasts
+const MODE_IS_INCLUDE: u8 = 1;
+const CHANGE_TO_INCLUDE: u8 = 3;
+
+/// Where the snooper has a frame sent
+#[derive(Debug, PartialEq, Eq)]
+pub enum Snooped {
+    /// Not an IPv4 multicast, so the frame is forwarded as usual
+    Normal,
+    /// Sent to a link-local group, so flooded even while flooding is off
+    Flood,
+    /// Sent to these vports, which are the group's members, the
+    /// router ports and the peers, before any are excluded
+    To(Vec<VportAddr>),
+}
+
+/// Group memberships and router ports learned from IGMP messages
+#[derive(Debug, Default)]
+pub struct IgmpSnooper {
+    /// Vports which have joined each group in each domain, and when they leave it
+    groups: HashMap<Domain, BTreeMap<Ipv4Addr, HashMap<VportAddr, Instant>>>,
+    /// Vports which IGMP queries were received from in each
+    /// domain, and when they stop being router ports
+    routers: HashMap<Domain, HashMap<VportAddr, Instant>>,
+}
+
+impl IgmpSnooper {
+    /// Learn from frame, received from src_vport in domain at now, if
+    /// it is an IGMP message, recording any memberships which changed
+    pub fn snoop(
+        &mut self,
+        domain: Domain,
+        src_vport: VportAddr,
+        frame: &[u8],
+        now: Instant,
+        events: &mut EventLog,
+    ) {
+        let Some(igmp) = ipv4_packet(frame).and_then(igmp_message) else {
+            return;
+        };
+        let expires = now + MEMBERSHIP_TIMEOUT;
+
+        match igmp[0] {
+            IGMP_QUERY => {
+                let routers = self.routers.entry(domain).or_default();
+                if routers
+                    .insert(src_vport, expires)
+                    .is_none_or(|old| old <= now)
+                {
+                    events.record(format!(
+                        "IGMP: {} is a router port in segment {}",
+                        src_vport, domain
+                    ));
+                }
+            }
+            IGMP_V1_REPORT | IGMP_V2_REPORT => {
+                if let Some(group) = group_addr(igmp.get(4..8)) {
+                    self.join(domain, group, src_vport, expires, now, events);
+                }
+            }
+            IGMP_LEAVE => {
+                if let Some(group) = group_addr(igmp.get(4..8)) {
+                    self.leave(domain, group, src_vport, events);
+                }
+            }
+            IGMP_V3_REPORT => {
+                let Some(count) = igmp.get(6..8) else {
+                    return;
+                };
+                let mut record = igmp.get(8..).unwrap_or_default();
+                for _ in 0..u16::from_be_bytes([count[0], count[1]]) {
+                    let Some(header) = record.get(..8) else {
+                        break;
+                    };
+                    let sources = usize::from(u16::from_be_bytes([header[2], header[3]]));
+                    let len = 8 + sources * 4 + usize::from(header[1]) * 4;
+                    if let Some(group) = group_addr(header.get(4..8)) {
+                        match (header[0], sources) {
+                            (MODE_IS_INCLUDE | CHANGE_TO_INCLUDE, 0) => {
+                                self.leave(domain, group, src_vport, events)
+                            }
+                            _ => self.join(domain, group, src_vport, expires, now, events),
+                        }
+                    }
+                    record = record.get(len..).unwrap_or_default();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns where frame, in domain, is sent at now, where
+    /// peers are the peer vswitches of the domain's segment
+    pub fn forwarding(
+        &self,
+        domain: Domain,
+        frame: &[u8],
+        peers: &[VportAddr],
+        now: Instant,
+    ) -> Snooped {
+        let Some(packet) = ipv4_packet(frame) else {
+            return Snooped::Normal;
+        };
+        let dst = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+        if !dst.is_multicast() {
+            return Snooped::Normal;
+        }
+
+        let mut dst_vports: Vec<VportAddr> = peers.to_vec();
+        let mut add = |vports: &HashMap<VportAddr, Instant>| {
+            for (vport, expires) in vports {
+                if *expires > now && !dst_vports.contains(vport) {
+                    dst_vports.push(*vport);
+                }
+            }
+        };
+        if let Some(routers) = self.routers.get(&domain) {
+            add(routers);
+        }
+
+        /* Reports only go towards the routers, so members don't suppress theirs */
+        let report = igmp_message(packet)
+            .is_some_and(|igmp| matches!(igmp[0], IGMP_V1_REPORT | IGMP_V2_REPORT));
+        if !report {
+            if is_link_local(&dst) {
+                return Snooped::Flood;
+            }
+            if let Some(members) = self.groups.get(&domain).and_then(|groups| groups.get(&dst)) {
+                add(members);
+            }
+        }
+        Snooped::To(dst_vports)
+    }
+
+    /// Returns the groups and router ports of each domain in human readable format
+    pub fn show(&self, ports: &PortTable<VportAddr>, now: Instant) -> String {
+        let port_ids = |vports: &HashMap<VportAddr, Instant>| {
+            let mut ids: Vec<u32> = vports
+                .iter()
+                .filter(|(_, expires)| **expires > now)
+                .map(|(vport, _)| ports.get(vport).map_or(0, |port| port.id))
+                .collect();
+            ids.sort();
+            ids.iter()
+                .map(u32::to_string)
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+
+        let mut domains: Vec<&Domain> = self.groups.keys().chain(self.routers.keys()).collect();
+        domains.sort();
+        domains.dedup();
+
+        let mut lines = vec![format!(
+            "{:>7}  {:>8}  {:<15}  {}",
+            "segment", "vlan", "group", "ports"
+        )];
+        for domain in domains {
+            let vlan = match domain.vlan {
+                Some(vlan) => vlan.to_string(),
+                None => "untagged".to_string(),
+            };
+            if let Some(routers) = self.routers.get(domain) {
+                let ids = port_ids(routers);
+                if !ids.is_empty() {
+                    lines.push(format!(
+                        "{:>7}  {:>8}  {:<15}  {}",
+                        domain.segment, vlan, "(routers)", ids
+                    ));
+                }
+            }
+            for (group, members) in self.groups.get(domain).into_iter().flatten() {
+                let ids = port_ids(members);
+                if !ids.is_empty() {
+                    lines.push(format!(
+                        "{:>7}  {:>8}  {:<15}  {}",
+                        domain.segment,
+                        vlan,
+                        group.to_string(),
+                        ids
+                    ));
+                }
+            }
+        }
+        lines.join("\n")
+    }
+
+    /// Forget the memberships and router ports which have expired by now,
+    /// and the groups and domains which are left without any
+    pub fn sweep(&mut self, now: Instant) {
+        for groups in self.groups.values_mut() {
+            for members in groups.values_mut() {
+                members.retain(|_, expires| *expires > now);
+            }
+            groups.retain(|_, members| !members.is_empty());
+        }
+        self.groups.retain(|_, groups| !groups.is_empty());
+        for routers in self.routers.values_mut() {
+            routers.retain(|_, expires| *expires > now);
+        }
+        self.routers.retain(|_, routers| !routers.is_empty());
+    }
+
+    /// Add src_vport to group in domain until expires
+    fn join(
+        &mut self,
+        domain: Domain,
+        group: Ipv4Addr,
+        src_vport: VportAddr,
+        expires: Instant,
+        now: Instant,
+        events: &mut EventLog,
+    ) {
+        let groups = self.groups.entry(domain).or_default();
+        if groups.len() >= MAX_GROUPS_PER_DOMAIN && !groups.contains_key(&group) {
+            for members in groups.values_mut() {
+                members.retain(|_, member_expires| *member_expires > now);
+            }
+            groups.retain(|_, members| !members.is_empty());
+            if groups.len() >= MAX_GROUPS_PER_DOMAIN {
+                return;
+            }
+        }
+        let members = groups.entry(group).or_default();
+        /* Memberships which have expired are forgotten as their groups are joined again */
+        members.retain(|_, member_expires| *member_expires > now);
+        if members.insert(src_vport, expires).is_none() {
+            events.record(format!(
+                "IGMP: {} joined {} in segment {}",
+                src_vport, group, domain
+            ));
+        }
+    }
+
+    /// Remove src_vport from group in domain
+    fn leave(
+        &mut self,
+        domain: Domain,
+        group: Ipv4Addr,
+        src_vport: VportAddr,
+        events: &mut EventLog,
+    ) {
+        let Some(groups) = self.groups.get_mut(&domain) else {
+            return;
+        };
+        let Some(members) = groups.get_mut(&group) else {
+            return;
+        };
+        if members.remove(&src_vport).is_some() {
+            events.record(format!(
+                "IGMP: {} left {} in segment {}",
+                src_vport, group, domain
+            ));
+        }
+        if members.is_empty() {
+            groups.remove(&group);
+        }
+    }
+}
+
+/// Returns the IPv4 packet which frame carries, untagged or with one
+/// 802.1Q tag, if its header is complete and it isn't a later fragment
+fn ipv4_packet(frame: &[u8]) -> Option<&[u8]> {
+    let frame = EthernetFrame::parse(frame).ok()?;
+    if frame.ether_type != IPV4_ETHER_TYPE {
+        return None;
+    }
+    let packet = frame.payload;
+    let header_len = usize::from(packet.first()? & 0x0F) * 4;
+    if header_len < 20 || packet.len() < header_len {
+        return None;
+    }
+    Some(packet)
+}
+
+/// Returns the IGMP message which packet carries, if it is at least 8 bytes long
+fn igmp_message(packet: &[u8]) -> Option<&[u8]> {
+    let header_len = usize::from(packet[0] & 0x0F) * 4;
+    if packet[9] != IGMP_PROTOCOL || u16::from_be_bytes([packet[6], packet[7]]) & 0x1FFF != 0 {
+        return None;
+    }
+    let igmp = packet.get(header_len..)?;
+    (igmp.len() >= 8).then_some(igmp)
+}
+
+/// Returns the group address in bytes, if it is a multicast group which can be joined
+fn group_addr(bytes: Option<&[u8]>) -> Option<Ipv4Addr> {
+    let bytes: [u8; 4] = bytes?.try_into().ok()?;
+    let group = Ipv4Addr::from(bytes);
+    (group.is_multicast() && !is_link_local(&group)).then_some(group)
+}
+
+/// Returns true if group is in 224.0.0.0/24, whose multicasts are for every host
+fn is_link_local(group: &Ipv4Addr) -> bool {
+    group.octets()[..3] == [224, 0, 0]
+}
@@ -0,0 +1,144 @@
+//! Link aggregation for the vswitch
+//!
+//! A vport with several underlay paths can reach the vswitch from a UDP
+//! address on each of them. It registers the first as usual, with hellos,
+//! and sends LAG member messages from the others, which have them join
+//! its port as further links, rather than becoming ports of their own
+//!
+//! Frames received over any link are taken as received from the port, so
+//! its MACs are learned there, and frames sent to the port are spread
+//! across its links by l2vpn::lag::pick_link. A link which stops sending
+//! LAG member messages for LINK_TIMEOUT leaves the port again
+//!
+//! The links of a port must be in its segment, and the port must have a
+//! session whose token the LAG member messages carry, so nobody else can
+//! have a port's frames sent to them
+
+use crate::{ports::PortTable, topology::PORT_DOWN_TIMEOUT, VportAddr};
+use l2vpn::lag::pick_link;
+use std::{
+    collections::HashMap,
+    iter,
+    time::{Duration, Instant},
+};
+
+/// How long a link lasts without a LAG member message, which
+/// is the same as a port lasts without being heard from
+pub const LINK_TIMEOUT: Duration = PORT_DOWN_TIMEOUT;
+
+/// Further link of an aggregated port
+#[derive(Debug)]
+struct Link {
+    /// Address of the port's vport, which its hellos come from
+    port: VportAddr,
+    /// When the link last sent a LAG member message
+    last_seen: Instant,
+}
+
+/// Links of the aggregated ports
+#[derive(Debug, Default)]
+pub struct Lags {
+    /// Each link, keyed by its address
+    links: HashMap<VportAddr, Link>,
+    /// Addresses of each aggregated port's further links, in the order they joined
+    members: HashMap<VportAddr, Vec<VportAddr>>,
+}
+
+impl Lags {
+    /// Make the vport at link a further link of the port at port, or keep
+    /// it as one, at now. Returns true if it wasn't a link of the port
+    /// before
+    pub fn join(&mut self, link: VportAddr, port: VportAddr, now: Instant) -> bool {
+        if let Some(existing) = self.links.get_mut(&link) {
+            existing.last_seen = now;
+            if existing.port == port {
+                return false;
+            }
+        }
+
+        /* The port's vport may have moved since the link joined it */
+        self.leave(&link);
+        self.links.insert(
+            link,
+            Link {
+                port,
+                last_seen: now,
+            },
+        );
+        self.members.entry(port).or_default().push(link);
+        true
+    }
+
+    /// Returns the address of the port which the vport at link is
+    /// a further link of, if it is one
+    pub fn port(&self, link: &VportAddr) -> Option<VportAddr> {
+        self.links.get(link).map(|link| link.port)
+    }
+
+    /// Returns the link which frame, for the port at dst, is sent over,
+    /// which is dst itself unless the port has further links
+    pub fn link<'a>(&'a self, dst: &'a VportAddr, frame: &[u8]) -> &'a VportAddr {
+        match self.members.get(dst) {
+            Some(members) => match pick_link(frame, members.len() + 1) {
+                0 => dst,
+                index => &members[index - 1],
+            },
+            None => dst,
+        }
+    }
+
+    /// Remove the links which haven't sent a LAG member message for
+    /// timeout, at now, returning the address of each and of its port
+    pub fn expire(&mut self, timeout: Duration, now: Instant) -> Vec<(VportAddr, VportAddr)> {
+        let expired: Vec<(VportAddr, VportAddr)> = self
+            .links
+            .iter()
+            .filter(|(_, link)| now.duration_since(link.last_seen) >= timeout)
+            .map(|(addr, link)| (*addr, link.port))
+            .collect();
+        for (addr, _) in expired.iter() {
+            self.leave(addr);
+        }
+        expired
+    }
+
+    /// Returns the aggregated ports and their links in human readable format
+    pub fn show(&self, ports: &PortTable<VportAddr>) -> String {
+        let port_id = |addr: &VportAddr| ports.get(addr).map_or(0, |port| port.id);
+
+        let mut lags: Vec<(u32, &VportAddr, &Vec<VportAddr>)> = self
+            .members
+            .iter()
+            .map(|(port, members)| (port_id(port), port, members))
+            .collect();
+        lags.sort_by_key(|(id, _, _)| *id);
+
+        let mut lines = vec![format!("{:>5}  {:<24}  {}", "port", "vport", "links")];
+        for (id, port, members) in lags {
+            let links: Vec<String> = iter::once(port)
+                .chain(members)
+                .map(VportAddr::to_string)
+                .collect();
+            lines.push(format!(
+                "{:>5}  {:<24}  {}",
+                id,
+                port.to_string(),
+                links.join(", ")
+            ));
+        }
+        lines.join("\n")
+    }
+
+    /// Remove link from the port it is a link of, if any
+    fn leave(&mut self, link: &VportAddr) {
+        let Some(Link { port, .. }) = self.links.remove(link) else {
+            return;
+        };
+        if let Some(members) = self.members.get_mut(&port) {
+            members.retain(|member| member != link);
+            if members.is_empty() {
+                self.members.remove(&port);
+            }
+        }
+    }
+}
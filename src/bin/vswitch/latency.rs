@@ -0,0 +1,70 @@
+//! Latency histograms for the vswitch
+//!
+//! Every port has two HDR histograms, recorded in microseconds:
+//! how long the vswitch took to forward the frames received from
+//! the port (from a listener receiving the frame, to the switching
+//! loop sending its last copy), and the round trip time of the echo
+//! requests sent to the port's vport. Their percentiles are reported
+//! by the admin socket, so overloaded hosts and performance
+//! regressions can be spotted
+
+use hdrhistogram::Histogram;
+use std::time::Duration;
+
+/// Latencies above this are recorded as this, in microseconds
+const MAX_LATENCY_US: u64 = 60_000_000;
+
+/// Number of significant figures the histograms keep
+const SIGNIFICANT_FIGURES: u8 = 3;
+
+/// Percentiles reported for each histogram
+const PERCENTILES: [f64; 3] = [50.0, 95.0, 99.0];
+
+/// Latency histograms kept for a port
+#[derive(Clone, Debug)]
+pub struct PortLatency {
+    /// Time from a frame being received to it being forwarded
+    pub forwarding: Histogram<u64>,
+    /// Round trip time of echo requests to the vport
+    pub rtt: Histogram<u64>,
+}
+
+impl Default for PortLatency {
+    fn default() -> Self {
+        let histogram = || {
+            Histogram::new_with_bounds(1, MAX_LATENCY_US, SIGNIFICANT_FIGURES)
+                .expect("latency histogram bounds are valid")
+        };
+
+        PortLatency {
+            forwarding: histogram(),
+            rtt: histogram(),
+        }
+    }
+}
+
+impl PortLatency {
+    /// Record that a frame took elapsed to be forwarded
+    pub fn record_forwarding(&mut self, elapsed: Duration) {
+        self.forwarding.saturating_record(micros(elapsed));
+    }
+
+    /// Record an echo request which took rtt to be answered
+    pub fn record_rtt(&mut self, rtt: Duration) {
+        self.rtt.saturating_record(micros(rtt));
+    }
+}
+
+/// Returns the PERCENTILES of histogram in microseconds,
+/// or None if nothing has been recorded in it yet
+pub fn percentiles(histogram: &Histogram<u64>) -> Option<[u64; 3]> {
+    match histogram.is_empty() {
+        true => None,
+        false => Some(PERCENTILES.map(|p| histogram.value_at_percentile(p))),
+    }
+}
+
+/// Returns duration in whole microseconds
+fn micros(duration: Duration) -> u64 {
+    u64::try_from(duration.as_micros()).unwrap_or(u64::MAX)
+}
@@ -0,0 +1,153 @@
+//! MAC move detection and dampening for the vswitch
+//!
+//! A MAC which keeps moving between two vports is the classic sign of
+//! a loop, where the frames of one host arrive through both ports in
+//! turn, so the moves of each MAC are counted. A MAC which moves more
+//! often than the MAC move limit allows is flapping, which is recorded
+//! as an event, and as configured, the MAC is also dampened, i.e. held
+//! on the port it moved from for DAMPEN_TIME, with its frames from other
+//! ports dropped, or the port it moved to is shut down
+//!
+//! Static MACs never move, so are never counted, and peer vswitches
+//! are never shut down, as they carry the MACs of everyone behind them
+
+use crate::{vlan::Domain, VportAddr};
+use l2vpn::mac::MacAddr;
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// How long a flapping MAC is held on the port it moved from, when dampened
+pub const DAMPEN_TIME: Duration = Duration::from_secs(30);
+
+/// What happens when a MAC moves more often than the limit allows
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MacMoveAction {
+    /// The MAC is left where it moved to, but the flapping is recorded
+    Log,
+    /// The MAC is held on the port it moved from, and its frames from
+    /// other ports are dropped, for DAMPEN_TIME
+    Dampen,
+    /// The port the MAC moved to is shut down, as an admin client could
+    Shutdown,
+}
+
+impl MacMoveAction {
+    /// Parse the value of --mac-move-action
+    pub fn parse(value: &str) -> Option<MacMoveAction> {
+        match value {
+            "log" => Some(MacMoveAction::Log),
+            "dampen" => Some(MacMoveAction::Dampen),
+            "shutdown" => Some(MacMoveAction::Shutdown),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the action, as given to --mac-move-action
+    pub fn name(&self) -> &'static str {
+        match self {
+            MacMoveAction::Log => "log",
+            MacMoveAction::Dampen => "dampen",
+            MacMoveAction::Shutdown => "shutdown",
+        }
+    }
+}
+
+/// Most times a MAC can move within a window, and what happens beyond that
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MacMoveLimit {
+    pub moves: usize,
+    pub window: Duration,
+    pub action: MacMoveAction,
+}
+
+impl fmt::Display for MacMoveLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} move(s) in {}s, then {}",
+            self.moves,
+            self.window.as_secs(),
+            self.action.name()
+        )
+    }
+}
+
+/// Parse a MAC move rate, such as 5/10 for 5 moves in 10 seconds,
+/// into the number of moves and the window they are counted over
+pub fn parse_move_rate(value: &str) -> Result<(usize, Duration), String> {
+    let invalid = || format!("Expected <moves>/<secs>, such as 5/10, not '{}'", value);
+    let (moves, secs) = value.split_once('/').ok_or_else(invalid)?;
+    match (moves.parse::<usize>(), secs.parse::<u64>()) {
+        (Ok(moves), Ok(secs)) if moves > 0 && secs > 0 => Ok((moves, Duration::from_secs(secs))),
+        _ => Err(invalid()),
+    }
+}
+
+/// Recent moves of each MAC, and the MACs which are dampened
+#[derive(Debug, Default)]
+pub struct MacMoves {
+    /// When each MAC which has moved recently moved, in each domain
+    moves: HashMap<(Domain, MacAddr), Vec<Instant>>,
+    /// Port each dampened MAC is held on, and when it was dampened
+    dampened: HashMap<(Domain, MacAddr), (VportAddr, Instant)>,
+}
+
+impl MacMoves {
+    /// Note that mac moved in domain at now, returning true if it has
+    /// now moved more often than limit allows, i.e. it is flapping. Its
+    /// moves are counted afresh from then, so a MAC which keeps flapping
+    /// is found flapping again once it has moved as often again
+    pub fn moved(
+        &mut self,
+        domain: Domain,
+        mac: MacAddr,
+        limit: &MacMoveLimit,
+        now: Instant,
+    ) -> bool {
+        let moves = self.moves.entry((domain, mac)).or_default();
+        moves.retain(|moved| now.duration_since(*moved) < limit.window);
+        moves.push(now);
+        if moves.len() <= limit.moves {
+            return false;
+        }
+        self.moves.remove(&(domain, mac));
+        true
+    }
+
+    /// Hold mac in domain on the vport at port from now until DAMPEN_TIME later
+    pub fn dampen(&mut self, domain: Domain, mac: MacAddr, port: VportAddr, now: Instant) {
+        self.dampened.insert((domain, mac), (port, now));
+    }
+
+    /// Returns the vport which mac in domain is held on, if it is dampened
+    pub fn dampened(&self, domain: Domain, mac: &MacAddr) -> Option<&VportAddr> {
+        self.dampened.get(&(domain, *mac)).map(|(port, _)| port)
+    }
+
+    /// Forget the moves made longer than window ago (or every move, if
+    /// there is no longer a limit), and release the MACs which have been
+    /// dampened for DAMPEN_TIME by now, returning them
+    pub fn expire(&mut self, window: Option<Duration>, now: Instant) -> Vec<MacAddr> {
+        match window {
+            Some(window) => self.moves.retain(|_, moves| {
+                moves.retain(|moved| now.duration_since(*moved) < window);
+                !moves.is_empty()
+            }),
+            None => self.moves.clear(),
+        }
+
+        let released: Vec<(Domain, MacAddr)> = self
+            .dampened
+            .iter()
+            .filter(|(_, (_, dampened))| now.duration_since(*dampened) >= DAMPEN_TIME)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in released.iter() {
+            self.dampened.remove(key);
+        }
+        released.into_iter().map(|(_, mac)| mac).collect()
+    }
+}
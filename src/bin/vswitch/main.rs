@@ -0,0 +1,3758 @@
+//! Virtual switch implementation
+//!
+//! This executable opens a UDP socket on the host,
+//! and handles the Ethernet frames sent to this
+//! socket as an Ethernet switch would
+//!
+//! Each UDP datagram carries exactly one raw Ethernet
+//! frame without an FCS. This is the same format that
+//! QEMU's `-netdev dgram` and `-netdev socket,udp=...`
+//! backends use, so VMs can be attached to the vswitch
+//! directly, in addition to vports
+//!
+//! Given a VXLAN address, the vswitch also exchanges frames in standard
+//! VXLAN datagrams there, with vports in VXLAN mode, Linux VXLAN devices
+//! and hardware VTEPs. The frames with each VNI are switched in the
+//! segment with the same number. Given a GENEVE address, it does the
+//! same with GENEVE datagrams, e.g. with OVS, adding options which tell
+//! their receivers the tenant ID and ingress port of each frame
+//!
+//! VMs can also attach to the vswitch over vhost-user, vports
+//! inside VMs can reach it over vsock, and vports on the same
+//! host can reach it over a Unix datagram socket, or over
+//! shared memory rings. vports on networks which block UDP
+//! can reach it over TCP, if need be through a proxy
+//!
+//! If built with the quic feature and given a QUIC address, with a
+//! certificate and its key, vports can connect to the vswitch over
+//! QUIC, which encrypts their frames and carries their control
+//! messages in a stream of their own, and keeps a vport's port when
+//! a NAT moves it to another address
+//!
+//! If built with the dtls feature and given a pre-shared key file, the
+//! vswitch only switches the frames of vports reached over UDP which
+//! have started a DTLS session with that key, and frames to and from
+//! them are encrypted in their sessions
+//!
+//! Given a pre-shared key for frame authentication instead, the vswitch
+//! drops the datagrams received over UDP which weren't signed with the
+//! key, and signs those it sends, so only vports and peered vswitches
+//! with the key can inject frames. Datagrams which have already been
+//! received from their sender are dropped too, so they can't be replayed,
+//! from any address
+//!
+//! A vport with several underlay paths can reach the vswitch over a
+//! link on each, which are aggregated into its one port, with the frames
+//! sent to it spread across the links by the hash of their flow
+//!
+//! Every vport is assigned a port, and if a state file is
+//! given, the ports and MAC table are saved to it so vports
+//! resume their previous ports after the vswitch restarts
+//!
+//! If a MAC snapshot file is given, the MAC tables and the VLAN
+//! assignments of the ports are periodically snapshotted to it, and
+//! restored when the vswitch starts, so frames aren't flooded (or
+//! dropped) after a restart while the MAC tables are learned again
+//!
+//! If an admin socket is given, vswitchctl can connect to it
+//! to inspect the running vswitch, including the latency of
+//! forwarding frames from each port and of the round trip to
+//! each vport, and to change its settings, such as MAC aging,
+//! the ACL and which ports are shut down, and to mirror the traffic
+//! of ports to another port, for a capture host
+//!
+//! vswitches can be peered with each other, in which case broadcasts
+//! are also sent to the peers. Every vswitch decrements the TTL
+//! which frames carry, so a loop of peers can't forward frames forever
+//!
+//! If a sample collector is given, a JSON summary of every Nth
+//! frame is sent to it over UDP
+//!
+//! When a vport leaves or goes down, a peer goes down, or a VM's
+//! bridge signals an STP topology change, the MACs affected are
+//! flushed, and the peers are told to flush them too
+//!
+//! The vswitch can run 802.1D STP itself, blocking the ports which
+//! would close a loop of peers, or of vports bridging back into it
+//!
+//! Control frames and admin commands are rate limited per source,
+//! so a misbehaving vport or admin client can't starve the vswitch
+//!
+//! The number of MACs which can be learned on each vport can be
+//! limited, so a vport can't fill the MAC tables with made-up MACs,
+//! and the rate of the frames each vport floods can be limited by
+//! storm control, so a noisy host can't flood the whole overlay
+//!
+//! A record of the traffic through each port is written when it goes
+//! down, and ports can be given a quota of bytes, after which they are
+//! shut down or rate limited
+//!
+//! The vswitch can listen on further UDP addresses, each of which
+//! leads to its own segment. Segments are separate networks with
+//! their own MAC tables, so one vswitch can serve several tenants.
+//! vports can also share the vswitch's own port between tenants, by
+//! sending every datagram after a header holding their VNI, in which
+//! case they are switched in the segment with the same number
+//!
+//! Segments are further divided into 802.1Q VLANs, with a MAC table
+//! each. Ports are trunks carrying tagged frames, access ports in
+//! one VLAN, whose frames are untagged, or QinQ tunnel ports, whose
+//! frames are put in a service VLAN by an outer 802.1ad tag
+//!
+//! vports join the vswitch when they start, saying what they can do,
+//! and are told their port. With VLAN requests on, a vport which asks
+//! for a VLAN when it joins is made an access port in it, unless an
+//! admin client has given its port a VLAN mode. Unless compression is
+//! off, a vport which asks to compress its frames with LZ4 when it joins
+//! is sent them compressed, whenever that makes them smaller. Unless
+//! fragmentation is off, a vport which says how large a fragment it can
+//! receive when it joins is sent the frames which are larger over UDP in
+//! fragments, and fragments from any vport are reassembled. vports
+//! finding their path MTU are answered as large as they probed
+//!
+//! With direct paths on, vports which reach the vswitch on its port and
+//! ask for direct paths when they join are introduced to each other when
+//! frames are forwarded between them, so they can punch holes through
+//! their NATs and send each other their frames without the vswitch. The
+//! frames they send directly skip its ACLs, accounting and mirrors, and
+//! two vports which found with STUN that they are both behind symmetric
+//! NATs are never introduced, as neither could reach the other
+//!
+//! Unless batching is off, vports on UDP which ask to batch small frames
+//! when they join are sent them several to a datagram, each batch held
+//! for at most a millisecond, and the batches they send are split back
+//! into their frames, each switched as if it had arrived on its own
+//!
+//! Unless FEC is off, vports on UDP which ask for FEC when they join are
+//! sent their datagrams in groups of the size they ask for, each group
+//! followed by a parity shard, and any one datagram lost from a group
+//! a vport sends is rebuilt from the rest of the group and its parity
+//!
+//! Unless ARP suppression is off, the vswitch learns the MAC of each IPv4
+//! address from the ARP it forwards, and tells the vports which ask for
+//! ARP suppression when they join the addresses in the domain they send
+//! untagged frames in, then each one as it is learned or changes MAC, so
+//! they answer their hosts' ARP requests themselves (see l2vpn::arp)
+//!
+//! Given a DSCP, the vswitch marks the datagrams it sends over UDP,
+//! VXLAN and GENEVE with it, or with the class selector of each frame's
+//! 802.1p priority, so the underlay can prioritise the tunnel's traffic
+//!
+//! If a DHCP configuration is given, the vswitch hands out IPv4
+//! addresses to the hosts in one segment itself
+//!
+//! With IGMP snooping, multicasts are only sent to the vports which
+//! have joined their group, rather than flooded or dropped
+//!
+//! mDNS and SSDP discovery can be reflected between chosen segments
+//! (or VLANs), so devices in one segment can be found from others
+//!
+//! Flooded frames can be sent once to an underlay multicast group
+//! which vports on the same network have joined, rather than to
+//! each of them in turn
+//!
+//! The flight recorder keeps the most recent frames from each port,
+//! which are written to a pcap file on request, or when an alert fires
+//!
+//! Chaos mode injects faults, such as dropped, duplicated and
+//! corrupted frames, to test how everything copes with them
+//!
+//! MAC learning, aging and the decision of where each frame goes
+//! are made by l2vpn::switching, which does no I/O, so they can be
+//! tested and reused apart from the vswitch's sockets
+//!
+//! The MTU of the L2VPN network (the overlay MTU) is 1500 bytes unless
+//! it is given, e.g. as 9000 to carry jumbo frames. Larger frames are
+//! dropped, as are the frames of vports whose hellos carry another MTU,
+//! as they would send frames too large for the others, or lose theirs
+//!
+//! Frames which can't be sent to their destination are dropped and
+//! counted, and the vswitch quits if its switching loop gets stuck
+//!
+//! Run by systemd, the vswitch takes its UDP socket from systemd if a
+//! socket unit opened it (socket activation), tells systemd once it is
+//! ready to switch frames, and pings systemd's watchdog for as long as
+//! its switching loop isn't stuck (see l2vpn::systemd)
+//!
+//! Without a supervisor, the vswitch can detach from its terminal as a
+//! daemon, writing its process ID to a pidfile, and logging to a file or
+//! syslog, which it can do in the foreground too (see l2vpn::daemon)
+//!
+//! SIGINT or SIGTERM stop the vswitch cleanly: the frames it is holding
+//! are sent, the sessions of the ports still up are accounted for, the
+//! state file and MAC snapshot are saved, and its socket files and
+//! pidfile are removed, before it exits successfully
+//!
+//! `vswitch check-config` validates the rest of the command
+//! line without starting the vswitch
+//!
+//! Usage: vswitch [check-config] <port> [--listen <ip:port>=<segment>]...
+//!                                      [--vhost-user <socket_path>]... [--vsock <vsock_port>]
+//!                                      [--tcp <ip:port>] [--mtu <bytes>]
+//!                                      [--vxlan <ip:port>] [--geneve <ip:port>]
+//!                                      [--unix <socket_path>] [--shm <socket_path>]
+//!                                      [--quic <ip:port> --quic-cert-file <path>
+//!                                       --quic-key-file <path>]
+//!                                      [--dtls-psk-file <path> | --auth-psk-file <path>]
+//!                                      [--state-file <path>] [--admin-socket <path>]
+//!                                      [--mac-snapshot <path> [--mac-snapshot-interval <secs>]]
+//!                                      [--sample-collector <ip:port> [--sample-rate <n>]]
+//!                                      [--peer <ip:port>]... [--bum-group <group_ip:port>]
+//!                                      [--flooding on|off] [--stp <bridge_priority>]
+//!                                      [--igmp-snooping on|off] [--vlan-requests on|off]
+//!                                      [--compression on|off] [--fragmentation on|off]
+//!                                      [--direct-paths on|off] [--batching on|off]
+//!                                      [--fec on|off] [--arp-suppression on|off]
+//!                                      [--dscp <value>|pcp]
+//!                                      [--static-mac <mac>=<ip:port>[/<vlan_id>]]...
+//!                                      [--mac-limit <n> [--mac-limit-action drop|log|shutdown]]
+//!                                      [--mac-move-limit <moves>/<secs>
+//!                                       [--mac-move-action log|dampen|shutdown]]
+//!                                      [--storm-control <frames_per_sec>]
+//!                                      [--accounting-file <path>]
+//!                                      [--quota <bytes> [--quota-action rate-limit|shutdown]]
+//!                                      [--dhcp-config <path>]
+//!                                      [--reflect mdns|ssdp=<segment>[/<vlan>],...]...
+//!                                      [--reflect-service <name>]...
+//!                                      [--flight-recorder <limit>=<value>[,...]
+//!                                       [--flight-recorder-dir <dir>]]
+//!                                      [--chaos <fault>=<value>[,...] [--chaos-seed <seed>]]
+//!                                      [--daemon <pid_file>] [--log <path>|syslog]
+
+mod accounting;
+mod admin;
+mod bum;
+mod chaos;
+mod config;
+mod dhcp;
+mod drops;
+mod dtls;
+mod events;
+mod igmp;
+mod lag;
+mod latency;
+mod mac_moves;
+mod mirror;
+mod monitor;
+mod neighbours;
+mod policer;
+mod port_security;
+mod ports;
+mod qos;
+#[cfg(feature = "quic")]
+mod quic;
+mod recorder;
+mod reflector;
+mod rendezvous;
+mod sampling;
+mod settings;
+mod sizes;
+mod snapshot;
+mod stp;
+mod topology;
+mod trace;
+mod vlan;
+
+use accounting::{Accounting, QuotaAction};
+use bum::BumGroup;
+use chaos::Chaos;
+use config::{read_psk, Config, ListenerOpts};
+use dhcp::DhcpServer;
+use dtls::DtlsPorts;
+use events::EventLog;
+use igmp::{IgmpSnooper, Snooped};
+use l2vpn::control::{
+    capability_names, is_control_frame, ControlMsg, CAP_ARP_SUPPRESSION, CAP_BATCHING,
+    CAP_COMPRESSION, CAP_DIRECT_PATHS, CAP_FEC, CAP_FRAGMENTATION,
+};
+use l2vpn::geneve::{self, GENEVE_HDR_MAX};
+use l2vpn::switching::DropReason;
+use l2vpn::utilities::{vlan_tag, FrameLogMsg, VlanLogMsg, DEFAULT_OVERLAY_MTU, ETHER_FRAME_MIN};
+#[cfg(feature = "vhost-user")]
+use l2vpn::vhost_user::VhostUserPort;
+use l2vpn::{
+    auth::FrameAuth,
+    batch::{is_batch, unbatch, Batch, BATCH_MAX, BATCH_TIMEOUT},
+    compression::{compress, decompress, is_compressed},
+    daemon::{self, LogTarget},
+    dscp::Marker,
+    error::TransportError,
+    fec::{is_shard, Decoder, Encoder, FEC_FLUSH_TIMEOUT},
+    fragment::{self, is_fragment, Reassembler, MIN_FRAGMENT_SIZE},
+    frame::EthernetFrame,
+    mac::MacAddr,
+    platform::StopSignals,
+    shm::{ShmLink, ShmListener},
+    stp::Bpdu,
+    supervisor::{self, Heartbeat},
+    switching::{self, Decision, Learned, MacAges, MacTable},
+    systemd,
+    tcp::TcpLink,
+    tenant,
+    timer::Interval,
+    transport::{OverlayTransport, UnixPeer, UnixTransport},
+    tunnel::{
+        hop_limit, pop_hop_limit, push_hop_limit, DEFAULT_TTL, HOP_LIMIT_TAG_LEN,
+        TUNNEL_DATAGRAM_MAX, TUNNEL_FRAME_MAX,
+    },
+    vsock::{VsockListener, VsockStream},
+    vxlan,
+};
+use l2vpn::{log_frame, logging};
+use lag::{Lags, LINK_TIMEOUT};
+use mac_moves::{MacMoveAction, MacMoveLimit, MacMoves, DAMPEN_TIME};
+use mirror::Mirrors;
+use monitor::Monitors;
+use neighbours::Neighbours;
+use nix::sys::socket::{getsockopt, sockopt, SockType};
+use policer::{is_link_local, storm_allows};
+use port_security::{MacLimit, MacLimitAction};
+use ports::PortTable;
+#[cfg(feature = "quic")]
+use quic::QuicPorts;
+use recorder::{Alert, FlightRecorder};
+use reflector::Reflector;
+use rendezvous::Rendezvous;
+use sampling::{Sampler, DEFAULT_SAMPLE_RATE};
+use settings::Settings;
+use snapshot::{MacSnapshot, SNAPSHOT_INTERVAL};
+use std::{
+    cell::{Cell, OnceCell, RefCell},
+    collections::{HashMap, VecDeque},
+    env, fmt,
+    fs::{self, File},
+    io::{self, Read},
+    iter,
+    net::{IpAddr, SocketAddr, TcpListener, UdpSocket},
+    os::{
+        fd::{AsRawFd, OwnedFd, RawFd},
+        unix::net::{UnixDatagram, UnixListener},
+    },
+    path::PathBuf,
+    process::{self, ExitCode},
+    sync::{
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, Mutex, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+use stp::SpanningTree;
+use topology::PORT_DOWN_TIMEOUT;
+use vlan::{retag, Domain, PortVlan};
+
+const USAGE: &str = "Usage: vswitch [check-config] <port> [--listen <ip:port>=<segment>]...
+                                     [--vhost-user <socket_path>]... [--vsock <vsock_port>]
+                                     [--tcp <ip:port>] [--mtu <bytes>]
+                                     [--vxlan <ip:port>] [--geneve <ip:port>]
+                                     [--unix <socket_path>] [--shm <socket_path>]
+                                     [--quic <ip:port> --quic-cert-file <path>
+                                      --quic-key-file <path>]
+                                     [--dtls-psk-file <path> | --auth-psk-file <path>]
+                                     [--state-file <path>] [--admin-socket <path>]
+                                     [--mac-snapshot <path> [--mac-snapshot-interval <secs>]]
+                                     [--sample-collector <ip:port> [--sample-rate <n>]]
+                                     [--peer <ip:port>]... [--bum-group <group_ip:port>]
+                                     [--flooding on|off] [--stp <bridge_priority>]
+                                     [--igmp-snooping on|off] [--vlan-requests on|off]
+                                     [--compression on|off] [--fragmentation on|off]
+                                     [--direct-paths on|off] [--batching on|off]
+                                     [--fec on|off] [--arp-suppression on|off]
+                                     [--dscp <value>|pcp]
+                                     [--static-mac <mac>=<ip:port>[/<vlan_id>]]...
+                                     [--mac-limit <n> [--mac-limit-action drop|log|shutdown]]
+                                     [--mac-move-limit <moves>/<secs>
+                                      [--mac-move-action log|dampen|shutdown]]
+                                     [--storm-control <frames_per_sec>]
+                                     [--accounting-file <path>]
+                                     [--quota <bytes> [--quota-action rate-limit|shutdown]]
+                                     [--dhcp-config <path>]
+                                     [--reflect mdns|ssdp=<segment>[/<vlan>],...]...
+                                     [--reflect-service <name>]...
+                                     [--flight-recorder <limit>=<value>[,...]
+                                      [--flight-recorder-dir <dir>]]
+                                     [--chaos <fault>=<value>[,...] [--chaos-seed <seed>]]
+                                     [--daemon <pid_file>] [--log <path>|syslog]";
+
+/// How often the port table is saved to the state file, if it has changed
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often echo requests are sent to the vports, to measure their round trip times
+const ECHO_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long the switching loop can spend on one frame or
+/// command before the watchdog decides it is stuck
+const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often frames waiting in egress queues are sent, as their ports' rate limits allow
+const QUEUE_DRAIN_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Segment of the vports which reach the vswitch on its port without a
+/// VNI header, or over any transport other than UDP, and of the peer vswitches
+const DEFAULT_SEGMENT: u32 = 0;
+
+/// MAC table of each segment and VLAN. Segments and the VLANs within
+/// them are separate networks, so the same MAC can be learned in several
+type MacTables = HashMap<Domain, MacTable<VportAddr>>;
+
+/// Identifies the vport which a frame was received from,
+/// and which frames destined for its MACs are sent to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum VportAddr {
+    /// vport (or QEMU netdev) reachable over UDP
+    Udp(SocketAddr),
+    /// vport reachable over UDP through the --listen socket with this index
+    Listen(usize, SocketAddr),
+    /// VTEP at this IP reachable through the --vxlan socket, in this VNI
+    Vxlan { vni: u32, ip: IpAddr },
+    /// VTEP at this IP reachable through the --geneve socket, in this VNI
+    Geneve { vni: u32, ip: IpAddr },
+    /// Guest attached to the vhost-user socket with this index
+    #[cfg(feature = "vhost-user")]
+    VhostUser(usize),
+    /// vport connected over vsock from the given CID and port
+    Vsock { cid: u32, port: u32 },
+    /// vport connected over TCP from the given address
+    Tcp(SocketAddr),
+    /// vport connected over QUIC, with this connection number
+    #[cfg(feature = "quic")]
+    Quic(usize),
+    /// vport on the same host which sends from this peer of the Unix socket
+    Unix(UnixPeer),
+    /// vport on the same host connected over shared memory with this id
+    Shm(usize),
+}
+
+impl fmt::Display for VportAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VportAddr::Udp(addr) => write!(f, "{}", addr),
+            VportAddr::Listen(index, addr) => write!(f, "{}@listen#{}", addr, index),
+            VportAddr::Vxlan { vni, ip } => write!(f, "{}@vxlan#{}", ip, vni),
+            VportAddr::Geneve { vni, ip } => write!(f, "{}@geneve#{}", ip, vni),
+            #[cfg(feature = "vhost-user")]
+            VportAddr::VhostUser(index) => write!(f, "vhost-user#{}", index),
+            VportAddr::Vsock { cid, port } => write!(f, "vsock:{}:{}", cid, port),
+            VportAddr::Tcp(addr) => write!(f, "tcp:{}", addr),
+            #[cfg(feature = "quic")]
+            VportAddr::Quic(id) => write!(f, "quic#{}", id),
+            VportAddr::Unix(peer) => write!(f, "{}", peer),
+            VportAddr::Shm(id) => write!(f, "shm#{}", id),
+        }
+    }
+}
+
+/// Event which is passed to the switching loop
+enum RxEvent {
+    /// Frame received by one of the vswitch's listeners, and when it was received
+    Frame(VportAddr, Vec<u8>, Instant),
+    /// Command from an admin client, whose response is sent back over the sender
+    Admin(String, Sender<String>),
+    /// vport whose connection to one of the vswitch's listeners has closed
+    Disconnected(VportAddr),
+    /// Error which stopped one of the vswitch's listeners
+    Error(io::Error),
+    /// Signal which stops the vswitch, by name
+    Stop(&'static str),
+}
+
+/// Streams to the vports connected over vsock, keyed by (CID, port)
+type VsockStreams = Arc<Mutex<HashMap<(u32, u32), VsockStream>>>;
+
+/// Links to the vports connected over TCP, keyed by their address
+type TcpLinks = Arc<Mutex<HashMap<SocketAddr, TcpLink>>>;
+
+/// Shared-memory links to vports, keyed by VportAddr::Shm id
+type ShmLinks = Arc<Mutex<HashMap<usize, ShmLink>>>;
+
+/// Handles which the vswitch uses to send frames to vports
+struct Vports {
+    socket: UdpSocket,
+    /* The --listen sockets, and the segment of each */
+    listen: Vec<(UdpSocket, u32)>,
+    /* The --vxlan and --geneve sockets, and the port which VTEPs receive on */
+    vxlan: Option<(UdpSocket, u16)>,
+    geneve: Option<(UdpSocket, u16)>,
+    /* The DTLS sessions of the vports reached over UDP, if DTLS is on */
+    dtls: Option<Arc<DtlsPorts>>,
+    /* What the frames sent over UDP are signed with, if frame authentication is on */
+    auth: Option<FrameAuth>,
+    #[cfg(feature = "vhost-user")]
+    vhost_user: Vec<VhostUserPort>,
+    vsock: VsockStreams,
+    tcp: TcpLinks,
+    #[cfg(feature = "quic")]
+    quic: Option<Arc<QuicPorts>>,
+    unix: Option<Arc<UnixTransport>>,
+    shm: ShmLinks,
+    /* Further links of the ports whose vports reach us over several underlay paths */
+    lags: Lags,
+    /* Largest fragment which each vport that agreed to fragmentation can receive */
+    fragment_sizes: HashMap<VportAddr, usize>,
+    /* ID of the last frame sent in fragments */
+    fragment_id: Cell<u32>,
+    /* What marks the datagrams sent from each UDP socket, if there is a DSCP */
+    markers: HashMap<RawFd, Marker>,
+    /* VNI of each link to a vport on our port which sends its datagrams after a VNI header */
+    vnis: HashMap<VportAddr, u32>,
+    /* Small frames waiting to be sent to each vport over UDP which agreed to batching */
+    batches: RefCell<HashMap<VportAddr, Batch>>,
+    /* What groups the datagrams sent to each vport over UDP which agreed to FEC */
+    fec_encoders: RefCell<HashMap<VportAddr, Encoder>>,
+}
+
+impl Vports {
+    /// Forget the address of the vport at addr, which has left or
+    /// stopped being heard from, if only its address identifies it
+    fn forget(&self, addr: &VportAddr) {
+        if let (VportAddr::Unix(peer), Some(unix)) = (addr, &self.unix) {
+            unix.forget(peer);
+        }
+    }
+
+    /// Send frame to the vport at dst, over one of its links if its port is aggregated
+    fn send_to(&self, frame: &[u8], dst: &VportAddr) -> Result<(), TransportError> {
+        self.send_on(frame, self.lags.link(dst, frame), None)
+    }
+
+    /// Send frame, which was received on the port with ID in_port, to the
+    /// vport at dst, as send_to does. GENEVE VTEPs are told in_port
+    fn forward(&self, frame: &[u8], dst: &VportAddr, in_port: u32) -> Result<(), TransportError> {
+        self.send_on(frame, self.lags.link(dst, frame), Some(in_port))
+    }
+
+    /// Send frame to the vport at link, whose address on socket is dst,
+    /// in a batch with the frames which follow it if its vport agreed to
+    /// batching and the frame is small enough, or else after the batch
+    /// waiting for it, as send_unbatched does
+    fn send_udp(
+        &self,
+        socket: &UdpSocket,
+        dst: SocketAddr,
+        link: &VportAddr,
+        frame: &[u8],
+    ) -> Result<(), TransportError> {
+        let pending = match self.batches.borrow_mut().get_mut(link) {
+            Some(batch) if batch.holds(frame) => {
+                return match batch.push(frame, Instant::now()) {
+                    Some(full) => self.send_unbatched(socket, dst, link, &full),
+                    None => Ok(()),
+                };
+            }
+            Some(batch) => batch.take(),
+            None => None,
+        };
+        if let Some(pending) = pending {
+            self.send_unbatched(socket, dst, link, &pending)?;
+        }
+        self.send_unbatched(socket, dst, link, frame)
+    }
+
+    /// Send the batches which have waited BATCH_TIMEOUT for more frames at now
+    fn flush_batches(&self, now: Instant) {
+        let due: Vec<(VportAddr, Vec<u8>)> = self
+            .batches
+            .borrow_mut()
+            .iter_mut()
+            .filter(|(_, batch)| batch.deadline().is_some_and(|deadline| deadline <= now))
+            .filter_map(|(link, batch)| Some((*link, batch.take()?)))
+            .collect();
+        for (link, batch) in due {
+            let sent = match link {
+                VportAddr::Udp(addr) => self.send_unbatched(&self.socket, addr, &link, &batch),
+                VportAddr::Listen(index, addr) => {
+                    self.send_unbatched(&self.listen[index].0, addr, &link, &batch)
+                }
+                _ => Ok(()),
+            };
+            if let Err(e) = sent {
+                eprintln!("Got error while sending batch to '{}': {}", link, e);
+            }
+        }
+    }
+
+    /// Returns when the first of the batches waiting for more frames is due
+    fn batch_deadline(&self) -> Option<Instant> {
+        self.batches
+            .borrow()
+            .values()
+            .filter_map(Batch::deadline)
+            .min()
+    }
+
+    /// Send the parity of the FEC groups which have waited
+    /// FEC_FLUSH_TIMEOUT for more datagrams at now
+    fn flush_parity(&self, now: Instant) {
+        let due: Vec<(VportAddr, Vec<u8>)> = self
+            .fec_encoders
+            .borrow_mut()
+            .iter_mut()
+            .filter(|(_, encoder)| encoder.deadline().is_some_and(|deadline| deadline <= now))
+            .filter_map(|(link, encoder)| Some((*link, encoder.flush()?)))
+            .collect();
+        for (link, parity) in due {
+            let sent = match link {
+                VportAddr::Udp(addr) => self.transmit(&self.socket, addr, &link, &parity),
+                VportAddr::Listen(index, addr) => {
+                    self.transmit(&self.listen[index].0, addr, &link, &parity)
+                }
+                _ => Ok(()),
+            };
+            if let Err(e) = sent {
+                eprintln!("Got error while sending FEC parity to '{}': {}", link, e);
+            }
+        }
+    }
+
+    /// Returns when the parity of the first of the FEC groups waiting
+    /// for more datagrams is due
+    fn parity_deadline(&self) -> Option<Instant> {
+        self.fec_encoders
+            .borrow()
+            .values()
+            .filter_map(Encoder::deadline)
+            .min()
+    }
+
+    /// Send frame to the vport at link, whose address on socket is dst,
+    /// in fragments if its vport agreed to them and the frame is too large
+    /// for one, each in its DTLS session if DTLS is on, or signed if frame
+    /// authentication is
+    fn send_unbatched(
+        &self,
+        socket: &UdpSocket,
+        dst: SocketAddr,
+        link: &VportAddr,
+        frame: &[u8],
+    ) -> Result<(), TransportError> {
+        self.mark(socket, frame)?;
+        let port = self.lags.port(link).unwrap_or(*link);
+        if let Some(size) = self.fragment_sizes.get(&port) {
+            if frame.len() > *size {
+                let id = self.fragment_id.get().wrapping_add(1);
+                self.fragment_id.set(id);
+                for fragment in fragment::fragment(frame, id, *size) {
+                    self.send_datagram(socket, dst, link, &fragment)?;
+                }
+                return Ok(());
+            }
+        }
+        self.send_datagram(socket, dst, link, frame)
+    }
+
+    /// Mark the datagram carrying frame, which is about to be sent from
+    /// socket, with the DSCP it should have, if there is one
+    fn mark(&self, socket: &UdpSocket, frame: &[u8]) -> io::Result<()> {
+        match self.markers.get(&socket.as_raw_fd()) {
+            Some(marker) => marker.mark(socket, frame),
+            None => Ok(()),
+        }
+    }
+
+    /// Send datagram to the vport at link, whose address on socket is dst,
+    /// as send_udp does, without fragmenting it, but as a data shard
+    /// followed by its group's parity once that is full, if its vport
+    /// agreed to FEC
+    fn send_datagram(
+        &self,
+        socket: &UdpSocket,
+        dst: SocketAddr,
+        link: &VportAddr,
+        frame: &[u8],
+    ) -> Result<(), TransportError> {
+        let encoded = self
+            .fec_encoders
+            .borrow_mut()
+            .get_mut(link)
+            .map(|encoder| encoder.encode(frame, Instant::now()));
+        let Some((shard, parity)) = encoded else {
+            return self.transmit(socket, dst, link, frame);
+        };
+        self.transmit(socket, dst, link, &shard)?;
+        match parity {
+            Some(parity) => self.transmit(socket, dst, link, &parity),
+            None => Ok(()),
+        }
+    }
+
+    /// Send datagram to the vport at link, whose address on socket is dst,
+    /// as send_datagram does, after a VNI header if the vport is in a VNI
+    fn transmit(
+        &self,
+        socket: &UdpSocket,
+        dst: SocketAddr,
+        link: &VportAddr,
+        frame: &[u8],
+    ) -> Result<(), TransportError> {
+        let with_vni;
+        let frame = match self.vnis.get(link) {
+            Some(vni) => {
+                with_vni = tenant::encapsulate(*vni, frame);
+                &with_vni
+            }
+            None => frame,
+        };
+        match (&self.dtls, &self.auth) {
+            (Some(dtls), _) => dtls.send(socket, dst, link, frame),
+            (None, Some(auth)) => {
+                socket.send_to(&auth.sign(frame), dst)?;
+                Ok(())
+            }
+            (None, None) => {
+                socket.send_to(frame, dst)?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Send frame to the vport at link, and if it is a GENEVE VTEP, tell
+    /// it the ID of the port the frame was received on, if it is given
+    fn send_on(
+        &self,
+        frame: &[u8],
+        link: &VportAddr,
+        in_port: Option<u32>,
+    ) -> Result<(), TransportError> {
+        match link {
+            VportAddr::Udp(addr) => self.send_udp(&self.socket, *addr, link, frame),
+            VportAddr::Listen(index, addr) => {
+                self.send_udp(&self.listen[*index].0, *addr, link, frame)
+            }
+            VportAddr::Vxlan { vni, ip } => match &self.vxlan {
+                Some((socket, port)) => {
+                    self.mark(socket, frame)?;
+                    socket.send_to(&vxlan::encapsulate(*vni, frame), (*ip, *port))?;
+                    Ok(())
+                }
+                None => Ok(()),
+            },
+            VportAddr::Geneve { vni, ip } => match &self.geneve {
+                Some((socket, port)) => {
+                    self.mark(socket, frame)?;
+                    let metadata = geneve::Metadata {
+                        tenant_id: Some(*vni),
+                        ingress_port: in_port,
+                    };
+                    socket.send_to(&geneve::encapsulate(*vni, &metadata, frame), (*ip, *port))?;
+                    Ok(())
+                }
+                None => Ok(()),
+            },
+            #[cfg(feature = "vhost-user")]
+            VportAddr::VhostUser(index) => self.vhost_user[*index].send(frame),
+            VportAddr::Vsock { cid, port } => {
+                match self
+                    .vsock
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .get(&(*cid, *port))
+                {
+                    Some(stream) => stream.send_frame(frame),
+                    /* The vport has disconnected, so there is nowhere to send the frame */
+                    None => Ok(()),
+                }
+            }
+            VportAddr::Tcp(addr) => match self
+                .tcp
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get(addr)
+            {
+                Some(link) => link.send_frame(frame),
+                /* The vport has disconnected, so there is nowhere to send the frame */
+                None => Ok(()),
+            },
+            #[cfg(feature = "quic")]
+            VportAddr::Quic(id) => match &self.quic {
+                Some(quic) => quic.send(*id, frame),
+                None => Ok(()),
+            },
+            VportAddr::Unix(peer) => match &self.unix {
+                Some(unix) => unix.send_to_peer(frame, peer),
+                None => Ok(()),
+            },
+            VportAddr::Shm(id) => match self
+                .shm
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get(id)
+            {
+                Some(link) => link.send_frame(frame),
+                /* The vport has disconnected, so there is nowhere to send the frame */
+                None => Ok(()),
+            },
+        }
+    }
+
+    /// Returns the segment which the vport at addr belongs to
+    fn segment(&self, addr: &VportAddr) -> u32 {
+        match addr {
+            VportAddr::Udp(_) => self.vnis.get(addr).copied().unwrap_or(DEFAULT_SEGMENT),
+            VportAddr::Listen(index, _) => self.listen[*index].1,
+            VportAddr::Vxlan { vni, .. } | VportAddr::Geneve { vni, .. } => *vni,
+            _ => DEFAULT_SEGMENT,
+        }
+    }
+
+    /// Note that the link at addr, on our port, sent its latest datagram
+    /// in vni, returning the VNI it was in before if that was another
+    fn set_vni(&mut self, addr: VportAddr, vni: u32) -> Option<u32> {
+        let previous = match vni {
+            DEFAULT_SEGMENT => self.vnis.remove(&addr),
+            _ => self.vnis.insert(addr, vni),
+        }
+        .unwrap_or(DEFAULT_SEGMENT);
+        (previous != vni).then_some(previous)
+    }
+}
+
+/// Returns the MAC tables of the VLANs in segment
+fn segment_tables(
+    mac_tables: &mut MacTables,
+    segment: u32,
+) -> impl Iterator<Item = &mut MacTable<VportAddr>> {
+    mac_tables
+        .iter_mut()
+        .filter(move |(domain, _)| domain.segment == segment)
+        .map(|(_, mac_table)| mac_table)
+}
+
+/// Returns the ports which the frames in domain from src_vport are flooded
+/// to even before any of their MACs are learned: the segment's peers
+/// (as given), and the access and QinQ tunnel ports in the domain's VLAN
+fn always_flooded(
+    peers: &[VportAddr],
+    ports: &PortTable<VportAddr>,
+    vports: &Vports,
+    domain: Domain,
+    src_vport: Option<&VportAddr>,
+) -> Vec<VportAddr> {
+    let mut flooded = peers.to_vec();
+    if let Some(vlan) = domain.vlan {
+        flooded.extend(
+            ports
+                .access_ports(vlan)
+                .filter(|addr| Some(*addr) != src_vport && vports.segment(addr) == domain.segment),
+        );
+    }
+    flooded
+}
+
+/// Returns the peer vswitches which frames in segment are sent to,
+/// as peering is only done through the vswitch's own port
+fn segment_peers(peers: &[VportAddr], segment: u32) -> &[VportAddr] {
+    match segment {
+        DEFAULT_SEGMENT => peers,
+        _ => &[],
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().collect();
+    logging::init();
+
+    /* Taken before any thread is started, as it changes the environment */
+    let activated = systemd::listen_fds();
+
+    if args.get(1).map(String::as_str) == Some("check-config") {
+        return check_config(&args[2..]);
+    }
+
+    let config = match config::parse_args(&args[1..]) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("{}", USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let errors = config::validate(&config);
+    if !errors.is_empty() {
+        for e in errors.iter() {
+            eprintln!("{}", e);
+        }
+        return ExitCode::FAILURE;
+    }
+
+    let Config {
+        port,
+        listeners: listener_opts,
+        state_path,
+        mac_snapshot_path,
+        mac_snapshot_interval,
+        admin_path,
+        sample_collector,
+        sample_rate,
+        peers,
+        accounting_path,
+        quota,
+        quota_action,
+        dhcp_path,
+        chaos,
+        chaos_seed,
+        reflect,
+        reflect_services,
+        flight_recorder,
+        flight_recorder_dir,
+        bum_group,
+        mtu,
+        flooding,
+        stp,
+        static_macs,
+        mac_limit,
+        mac_limit_action,
+        mac_move_limit,
+        mac_move_action,
+        storm_control,
+        igmp_snooping,
+        vlan_requests,
+        compression,
+        fragmentation,
+        direct_paths,
+        batching,
+        fec,
+        arp_suppression,
+        daemon,
+        log,
+    } = config;
+
+    /* Only the main thread survives detaching, so it comes before any other is started */
+    let mut daemon = match daemon.as_deref().map(daemon::daemonize).transpose() {
+        Ok(daemon) => daemon,
+        Err(e) => {
+            eprintln!("Got error while detaching as a daemon: '{}'", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    /* A daemon has no terminal to log to, so logs to syslog unless told otherwise */
+    let log = match (log.as_deref(), &daemon) {
+        (Some(log), _) => Some(LogTarget::parse(log)),
+        (None, Some(_)) => Some(LogTarget::Syslog),
+        (None, None) => None,
+    };
+    if let Some(log) = &log {
+        if let Err(e) = daemon::redirect_logs(log, c"vswitch") {
+            eprintln!("Got error while redirecting logs: '{}'", e);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    /* The signals which stop the vswitch are blocked before any thread is started */
+    let signals = match StopSignals::block() {
+        Ok(signals) => signals,
+        Err(e) => {
+            eprintln!("Got error while blocking signals: '{}'", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let quota = quota.map(|quota| (quota, quota_action.unwrap_or(QuotaAction::Shutdown)));
+    let mut accounting = match Accounting::open(accounting_path.as_deref(), quota) {
+        Ok(accounting) => accounting,
+        Err(e) => {
+            eprintln!("Got error while opening accounting file: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut dhcp = match dhcp_path.as_deref().map(DhcpServer::load) {
+        Some(Ok(dhcp)) => Some(dhcp),
+        Some(Err(e)) => {
+            eprintln!("Got error while loading DHCP configuration: {}", e);
+            return ExitCode::FAILURE;
+        }
+        None => None,
+    };
+
+    let mut reflector = match reflect.is_empty() {
+        true => None,
+        false => Some(Reflector::new(reflect, &reflect_services)),
+    };
+
+    let mut recorder = flight_recorder.map(|config| {
+        let dir = flight_recorder_dir.map_or_else(env::temp_dir, PathBuf::from);
+        FlightRecorder::new(config, dir)
+    });
+
+    let mut bum_group = bum_group.map(BumGroup::new);
+
+    let mtu = match mtu {
+        Some(mtu) => {
+            println!("Overlay MTU is {}", mtu);
+            mtu
+        }
+        None => DEFAULT_OVERLAY_MTU,
+    };
+
+    let mut snooper = igmp_snooping.unwrap_or(false).then(IgmpSnooper::default);
+
+    let mut stp = match stp.map(SpanningTree::new) {
+        Some(Ok(stp)) => {
+            println!("STP is on, with bridge ID {}", stp.id());
+            Some(stp)
+        }
+        Some(Err(e)) => {
+            eprintln!("Got error while choosing STP bridge ID: {}", e);
+            return ExitCode::FAILURE;
+        }
+        None => None,
+    };
+
+    let mut chaos = chaos.map(|chaos| Chaos::new(chaos, chaos_seed));
+    if let Some(chaos) = &chaos {
+        println!("Chaos mode is on, with seed {}", chaos.seed());
+    }
+
+    /* Create UDP socket to receive Ethernet frames on, unless systemd opened it for us */
+    let socket = match activated_socket(activated, port) {
+        Ok(Some(socket)) => socket,
+        Ok(None) => match UdpSocket::bind(format!("0.0.0.0:{}", port)) {
+            Ok(socket) => socket,
+            Err(e) => {
+                eprintln!("Got error: {}", e);
+                return ExitCode::FAILURE;
+            }
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    /*
+     * Every listener hands the frames it receives to this
+     * channel, so that a single loop does all the switching
+     */
+    let (rx_tx, rx_rx) = mpsc::channel::<RxEvent>();
+
+    /* Start thread which waits to be told to stop, and has the switching loop stop cleanly */
+    let stop_tx = rx_tx.clone();
+    thread::spawn(move || {
+        if let Ok(signal) = signals.wait() {
+            let _ = stop_tx.send(RxEvent::Stop(signal));
+        }
+    });
+
+    let mut vports = match start_listeners(socket, &listener_opts, &rx_tx) {
+        Ok(vports) => vports,
+        Err(e) => {
+            eprintln!("Got error while starting listeners: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if let Some(path) = &admin_path {
+        /* Remove the socket left behind by a previous vswitch, if any */
+        let _ = fs::remove_file(path);
+        let listener = match UnixListener::bind(path) {
+            Ok(listener) => listener,
+            Err(e) => {
+                eprintln!("Got error while binding admin socket '{}': {}", path, e);
+                return ExitCode::FAILURE;
+            }
+        };
+        let admin_tx = rx_tx.clone();
+        thread::spawn(move || admin::admin_listener(listener, admin_tx));
+        println!("Listening for admin clients on '{}'", path);
+    }
+
+    let mut sampler = match sample_collector {
+        Some(collector) => {
+            let rate = sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE);
+            match Sampler::new(collector, rate) {
+                Ok(sampler) => {
+                    println!("Sending 1 in {} frames to collector '{}'", rate, collector);
+                    Some(sampler)
+                }
+                Err(e) => {
+                    eprintln!("Got error while creating sampling socket: {}", e);
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        None => None,
+    };
+
+    println!("Starting vswitch");
+
+    let mut mac_tables = MacTables::new();
+
+    /* Load the ports saved before the vswitch last stopped */
+    let mut ports: PortTable<VportAddr> = match &state_path {
+        Some(path) => match PortTable::load(path) {
+            Ok(ports) => ports,
+            Err(e) => {
+                eprintln!("Got error while loading state file '{}': {}", path, e);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => PortTable::default(),
+    };
+    let mut events = EventLog::default();
+
+    /* Peer vswitches are sent hop limit tags from the start, as they understand them */
+    let peers: Vec<VportAddr> = peers.into_iter().map(VportAddr::Udp).collect();
+    for peer in peers.iter() {
+        let port = ports.port(*peer);
+        port.tunnel = true;
+        let id = port.id;
+        events.record(format!("Peer vswitch {} as port {}", peer, id));
+    }
+    let mut monitors = Monitors::default();
+    let mut mirrors = Mirrors::default();
+    let mut fragments = Reassembler::default();
+    let mut settings = Settings::new(flooding.unwrap_or(false));
+    settings.mac_limit = mac_limit.map(|max| MacLimit {
+        max,
+        action: mac_limit_action.unwrap_or(MacLimitAction::Drop),
+    });
+    settings.mac_move_limit = mac_move_limit.map(|(moves, window)| MacMoveLimit {
+        moves,
+        window,
+        action: mac_move_action.unwrap_or(MacMoveAction::Log),
+    });
+    settings.storm_control = storm_control;
+
+    /* Static MACs given on the command line are known before their vports ever transmit */
+    for (mac, addr, vlan) in static_macs {
+        settings.static_macs.insert(mac);
+        let domain = Domain {
+            segment: DEFAULT_SEGMENT,
+            vlan,
+        };
+        mac_tables
+            .entry(domain)
+            .or_default()
+            .insert(mac, VportAddr::Udp(addr));
+    }
+
+    /* Restore the MAC tables snapshotted before the vswitch last stopped */
+    let mut mac_snapshot = match &mac_snapshot_path {
+        Some(path) => match MacSnapshot::load(
+            path,
+            &vports,
+            &mut ports,
+            &mut mac_tables,
+            &settings.static_macs,
+        ) {
+            Ok((snapshot, macs)) => {
+                events.record(format!(
+                    "Restored {} MAC(s) from the MAC snapshot '{}'",
+                    macs, path
+                ));
+                Some(snapshot)
+            }
+            Err(e) => {
+                eprintln!("Got error while loading MAC snapshot '{}': {}", path, e);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
+    let mut mac_ages: HashMap<Domain, MacAges> = HashMap::new();
+    let mut mac_moves = MacMoves::default();
+    let mut rendezvous = Rendezvous::default();
+    let mut neighbours = Neighbours::default();
+
+    /* Frames received in a batch or FEC shard which haven't been switched yet */
+    let mut fec_groups: Decoder<VportAddr> = Decoder::default();
+    let mut inner_frames: VecDeque<(VportAddr, Vec<u8>, Instant)> = VecDeque::new();
+
+    /* Echo requests carry the time they were sent, relative to this */
+    let start = Instant::now();
+    let mut save_timer = Interval::starting_at(STATE_SAVE_INTERVAL, start);
+    let mut snapshot_timer =
+        Interval::starting_at(mac_snapshot_interval.unwrap_or(SNAPSHOT_INTERVAL), start);
+    let mut echo_timer = Interval::starting_at(ECHO_INTERVAL, start);
+    let mut igmp_timer = Interval::starting_at(igmp::SWEEP_INTERVAL, start);
+
+    /*
+     * A switching loop which is stuck stops the whole network, so quit
+     * and let whatever started the vswitch restart it, with its ports
+     * restored from the state file
+     */
+    let heartbeat = Heartbeat::new();
+    supervisor::watchdog(
+        vec![("switching loop".to_string(), heartbeat.clone())],
+        WATCHDOG_TIMEOUT,
+        |name, busy_for| {
+            eprintln!(
+                "vswitch {} has been stuck for {:?}, quitting",
+                name, busy_for
+            );
+            process::exit(1);
+        },
+    );
+
+    /* systemd restarts the vswitch if it stops hearing that the switching loop is fine */
+    systemd::watchdog(vec![heartbeat.clone()]);
+    if let Err(e) = systemd::notify(&format!("READY=1\nSTATUS=Switching on port {}", port)) {
+        eprintln!("Got error while notifying systemd: '{}'", e);
+    }
+    if let Some(daemon) = &mut daemon {
+        daemon.ready();
+    }
+
+    loop {
+        /*
+         * Get virtual ethernet frame from one of the listeners,
+         * waking up periodically to save the port table, to snapshot
+         * the MAC tables, to run STP's timers, and to send frames
+         * waiting in egress queues
+         */
+        heartbeat.idle();
+        let mut timeout = match &stp {
+            Some(stp) => stp.remaining(Instant::now()).min(STATE_SAVE_INTERVAL),
+            None => STATE_SAVE_INTERVAL,
+        };
+        if mac_snapshot.is_some() {
+            timeout = timeout.min(snapshot_timer.remaining(Instant::now()));
+        }
+        let queued: Vec<VportAddr> = ports
+            .iter()
+            .filter(|(_, port)| {
+                port.queues
+                    .as_ref()
+                    .is_some_and(|queues| !queues.is_empty())
+            })
+            .map(|(addr, _)| *addr)
+            .collect();
+        if !queued.is_empty() {
+            timeout = timeout.min(QUEUE_DRAIN_INTERVAL);
+        }
+        if let Some(deadline) = vports.batch_deadline() {
+            timeout = timeout.min(deadline.saturating_duration_since(Instant::now()));
+        }
+        if let Some(deadline) = vports.parity_deadline() {
+            timeout = timeout.min(deadline.saturating_duration_since(Instant::now()));
+        }
+        let duplicate = chaos.as_mut().and_then(Chaos::take_duplicate);
+        let mut inner = false;
+        let event = match duplicate {
+            /* Chaos mode receives the frame it duplicated again, as if the network had */
+            Some((src_vport, frame)) => Some(RxEvent::Frame(src_vport, frame, Instant::now())),
+            /* The frames of a batch or shard are switched before anything else is received */
+            None => match inner_frames.pop_front() {
+                Some((src_vport, frame, received)) => {
+                    inner = true;
+                    Some(RxEvent::Frame(src_vport, frame, received))
+                }
+                None => match rx_rx.recv_timeout(timeout) {
+                    Ok(event) => Some(event),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(e) => {
+                        eprintln!("All listeners have stopped: {}", e);
+                        eprintln!("Quitting");
+                        return ExitCode::FAILURE;
+                    }
+                },
+            },
+        };
+
+        let now = Instant::now();
+        heartbeat.busy(now);
+        if let Some(path) = &state_path {
+            if ports.is_dirty() && save_timer.due(now) {
+                if let Err(e) = ports.save(path, &mac_tables) {
+                    eprintln!("Got error while saving state file '{}': {}", path, e);
+                }
+            }
+        }
+        if let Some(snapshot) = &mac_snapshot {
+            if snapshot_timer.due(now) {
+                if let Err(e) = snapshot.save(&ports, &mac_tables, &settings.static_macs) {
+                    eprintln!("Got error while saving MAC snapshot: {}", e);
+                }
+            }
+        }
+
+        if echo_timer.due(now) {
+            send_echo_requests(&vports, &ports, &peers, start);
+        }
+        if let Some(snooper) = &mut snooper {
+            if igmp_timer.due(now) {
+                snooper.sweep(now);
+            }
+        }
+
+        for addr in queued {
+            drain_queues(addr, &mut ports, &vports, &mut mirrors);
+        }
+        vports.flush_batches(now);
+        vports.flush_parity(now);
+
+        if let Some(stp) = &mut stp {
+            for segment in stp.tick(now, &mut ports, &vports, &mut events) {
+                topology::flush_segment(
+                    segment,
+                    format!("STP: topology of segment {} changed", segment),
+                    &mut mac_tables,
+                    &settings.static_macs,
+                    segment_peers(&peers, segment),
+                    &vports,
+                    &mut events,
+                );
+            }
+        }
+
+        /* Flush the MACs of vports and peers which have stopped being heard from */
+        for addr in ports.expire(PORT_DOWN_TIMEOUT, &peers) {
+            let Some(port) = ports.get(&addr) else {
+                continue;
+            };
+            let id = port.id;
+            if !port.shutdown {
+                accounting.record(&addr, port, "timeout");
+            }
+            if let Some(recorder) = &mut recorder {
+                recorder.alert(id, Alert::Timeout, &mut events);
+            }
+            let segment = vports.segment(&addr);
+            topology::port_down(
+                &addr,
+                format!("Port {} ({}) stopped being heard from", id, addr),
+                &mut mac_tables,
+                &settings.static_macs,
+                segment_peers(&peers, segment),
+                &vports,
+                &mut events,
+            );
+            vports.forget(&addr);
+        }
+
+        /* Flush the restored MACs of vports which haven't returned since the restart */
+        let unreturned = mac_snapshot
+            .as_mut()
+            .map_or_else(Vec::new, |snapshot| snapshot.expire(&ports, now));
+        for addr in unreturned {
+            let id = ports.get(&addr).map_or(0, |port| port.id);
+            let segment = vports.segment(&addr);
+            topology::port_down(
+                &addr,
+                format!(
+                    "Port {} ({}) wasn't heard from after its MACs were restored",
+                    id, addr
+                ),
+                &mut mac_tables,
+                &settings.static_macs,
+                segment_peers(&peers, segment),
+                &vports,
+                &mut events,
+            );
+        }
+
+        for (link, addr) in vports.lags.expire(LINK_TIMEOUT, now) {
+            let id = ports.get(&addr).map_or(0, |port| port.id);
+            events.record(format!(
+                "LAG: link {} of port {} ({}) stopped being heard from",
+                link, id, addr
+            ));
+        }
+
+        let window = settings.mac_move_limit.map(|limit| limit.window);
+        for mac in mac_moves.expire(window, now) {
+            events.record(format!(
+                "MAC {} is no longer held on its port for flapping",
+                mac
+            ));
+        }
+
+        /* Chaos mode simulates the vport or peer behind a port restarting */
+        if let Some(chaos) = &mut chaos {
+            let up: Vec<VportAddr> = ports
+                .iter()
+                .filter(|(_, port)| !port.down && !port.shutdown)
+                .map(|(addr, _)| *addr)
+                .collect();
+            let restarted = chaos.restart(&up);
+            if let Some((addr, port)) = restarted.and_then(|a| Some((a, ports.get_mut(&a)?))) {
+                port.down = true;
+                accounting.record(&addr, port, "chaos");
+                let segment = vports.segment(&addr);
+                topology::port_down(
+                    &addr,
+                    format!(
+                        "Chaos mode is simulating port {} ({}) restarting",
+                        port.id, addr
+                    ),
+                    &mut mac_tables,
+                    &settings.static_macs,
+                    segment_peers(&peers, segment),
+                    &vports,
+                    &mut events,
+                );
+            }
+        }
+
+        let mut aged = 0;
+        for (domain, mac_table) in mac_tables.iter_mut() {
+            aged += mac_ages
+                .entry(*domain)
+                .or_insert_with(|| MacAges::new(now))
+                .expire(mac_table, settings.mac_aging, &settings.static_macs, now)
+                .len();
+        }
+        if aged > 0 {
+            events.record(format!("Aged out {} MAC(s)", aged));
+        }
+
+        let (src_vport, mut frame, received) = match event {
+            Some(RxEvent::Frame(src_vport, frame, received)) => (src_vport, frame, received),
+            Some(RxEvent::Admin(command, reply_tx)) => {
+                let state = admin::SwitchState {
+                    mac_tables: &mut mac_tables,
+                    ports: &mut ports,
+                    peers: &peers,
+                    settings: &mut settings,
+                    events: &mut events,
+                    vports: &vports,
+                    accounting: &mut accounting,
+                    dhcp: dhcp.as_ref(),
+                    chaos: chaos.as_ref(),
+                    reflector: reflector.as_ref(),
+                    recorder: recorder.as_ref(),
+                    bum_group: bum_group.as_ref(),
+                    stp: stp.as_ref(),
+                    snooper: snooper.as_ref(),
+                    mirrors: &mut mirrors,
+                };
+                admin::execute(&command, reply_tx, state, &mut monitors);
+                continue;
+            }
+            Some(RxEvent::Disconnected(addr)) => {
+                if let Some(port) = ports.get_mut(&addr) {
+                    if !port.down && !port.shutdown {
+                        accounting.record(&addr, port, "disconnected");
+                        if let Some(recorder) = &mut recorder {
+                            recorder.alert(port.id, Alert::Disconnected, &mut events);
+                        }
+                    }
+                    port.down = true;
+                    let segment = vports.segment(&addr);
+                    topology::port_down(
+                        &addr,
+                        format!("Port {} ({}) disconnected", port.id, addr),
+                        &mut mac_tables,
+                        &settings.static_macs,
+                        segment_peers(&peers, segment),
+                        &vports,
+                        &mut events,
+                    );
+                }
+                continue;
+            }
+            Some(RxEvent::Error(e)) => {
+                eprintln!("Got error while listening on socket: {}", e);
+                eprintln!("Quitting");
+                return ExitCode::FAILURE;
+            }
+            Some(RxEvent::Stop(signal)) => {
+                if let Err(e) = systemd::notify("STOPPING=1") {
+                    eprintln!("Got error while notifying systemd: '{}'", e);
+                }
+
+                /* Frames waiting in egress queues, batches and FEC groups are sent, not lost */
+                let addrs: Vec<VportAddr> = ports.iter().map(|(addr, _)| *addr).collect();
+                for addr in addrs {
+                    drain_queues(addr, &mut ports, &vports, &mut mirrors);
+                }
+                let flush_at = now + BATCH_TIMEOUT.max(FEC_FLUSH_TIMEOUT);
+                vports.flush_batches(flush_at);
+                vports.flush_parity(flush_at);
+
+                /* The sessions of the ports which are still up end with the vswitch */
+                for (addr, port) in ports.iter() {
+                    if !port.down && !port.shutdown {
+                        accounting.record(addr, port, "stopped");
+                    }
+                }
+                if let Some(path) = &state_path {
+                    if let Err(e) = ports.save(path, &mac_tables) {
+                        eprintln!("Got error while saving state file '{}': {}", path, e);
+                    }
+                }
+                if let Some(snapshot) = &mac_snapshot {
+                    if let Err(e) = snapshot.save(&ports, &mac_tables, &settings.static_macs) {
+                        eprintln!("Got error while saving MAC snapshot: {}", e);
+                    }
+                }
+
+                /* The socket files would otherwise be left behind for the next vswitch to remove */
+                let paths = [
+                    &admin_path,
+                    &listener_opts.unix_path,
+                    &listener_opts.shm_path,
+                ];
+                for path in paths.into_iter().flatten() {
+                    if let Err(e) = fs::remove_file(path) {
+                        eprintln!("Got error while removing socket '{}': {}", path, e);
+                    }
+                }
+
+                println!("Got {}, so stopped switching", signal);
+                return ExitCode::SUCCESS;
+            }
+            None => continue,
+        };
+
+        /*
+         * vports sharing our port with other tenants send every datagram
+         * after a header holding their VNI, and are switched in its segment.
+         * The segments of the other addresses are fixed, so VNI headers are
+         * only accepted on our port. A vport which moves to another VNI has
+         * its MACs in the old one flushed. The frames of a batch or shard
+         * were in its VNI
+         */
+        let vni = match tenant::decapsulate(&frame).filter(|_| !inner) {
+            Some((vni, payload)) => {
+                let payload = payload.to_vec();
+                frame = payload;
+                Some(vni)
+            }
+            None => None,
+        };
+        if let (VportAddr::Udp(_), false) = (src_vport, inner) {
+            let vni = vni.unwrap_or(DEFAULT_SEGMENT);
+            if let Some(previous) = vports.set_vni(src_vport, vni) {
+                let port = vports.lags.port(&src_vport).unwrap_or(src_vport);
+                if let Some(id) = ports.get(&port).map(|port| port.id) {
+                    topology::port_down(
+                        &port,
+                        format!(
+                            "Port {} ({}) moved from VNI {} to VNI {}",
+                            id, port, previous, vni
+                        ),
+                        &mut mac_tables,
+                        &settings.static_macs,
+                        segment_peers(&peers, previous),
+                        &vports,
+                        &mut events,
+                    );
+                }
+            }
+        } else if vni.is_some() {
+            if let Some(port) = ports.get_mut(&src_vport) {
+                port.counters.drops.count(DropReason::UnexpectedVni);
+            }
+            log_frame!(
+                "Received datagram with a VNI header from '{}', which isn't on our port",
+                src_vport
+            );
+            continue;
+        }
+
+        /*
+         * Discard datagrams which are too short to contain an Ethernet
+         * header, as we cannot switch them. These drops are counted, so
+         * are only logged with frame logging on, as a remote sender could
+         * otherwise flood stderr with them
+         */
+        if switching::is_runt(frame.len()) {
+            if let Some(port) = ports.get_mut(&src_vport) {
+                port.counters.drops.count(DropReason::Runt);
+            }
+            log_frame!(
+                "Received runt frame which was {} bytes long from '{}'",
+                frame.len(),
+                src_vport
+            );
+            continue;
+        }
+
+        /*
+         * vports which joined asking for FEC send their datagrams as the
+         * data shards of groups, which are handled as they arrive, followed
+         * by any datagram of the group which its parity rebuilds
+         */
+        if is_shard(&frame) {
+            match fec_groups.add(src_vport, &frame, now) {
+                Some(datagrams) => {
+                    for datagram in datagrams.into_iter().rev() {
+                        inner_frames.push_front((src_vport, datagram, received));
+                    }
+                }
+                None => {
+                    if let Some(port) = ports.get_mut(&src_vport) {
+                        port.counters.drops.count(DropReason::BadFec);
+                    }
+                    log_frame!(
+                        "Received FEC shard from '{}' which didn't fit its group",
+                        src_vport
+                    );
+                }
+            }
+            continue;
+        }
+
+        /*
+         * vports which joined asking to batch small frames send several in
+         * a datagram, which are switched one after another, as if each had
+         * arrived on its own
+         */
+        if is_batch(&frame) {
+            match unbatch(&frame) {
+                Some(frames) => {
+                    for batched in frames.into_iter().rev() {
+                        inner_frames.push_front((src_vport, batched.to_vec(), received));
+                    }
+                }
+                None => {
+                    if let Some(port) = ports.get_mut(&src_vport) {
+                        port.counters.drops.count(DropReason::BadBatch);
+                    }
+                    log_frame!(
+                        "Received batch from '{}' whose frames overran it",
+                        src_vport
+                    );
+                }
+            }
+            continue;
+        }
+
+        /*
+         * vports which joined asking to fragment frames send those too
+         * large for their tunnel MTU in fragments, which are held until
+         * the rest of their frame arrives
+         */
+        if is_fragment(&frame) {
+            match fragments.add(src_vport, &frame, now) {
+                Some(whole) => frame = whole,
+                None => continue,
+            }
+        }
+
+        /* Chaos mode may drop the frame, corrupt it, or duplicate it */
+        if let Some(chaos) = &mut chaos {
+            if let Some(reason) = chaos.frame(&src_vport, &mut frame) {
+                if let Some(port) = ports.get_mut(&src_vport) {
+                    port.counters.drops.count(DropReason::Chaos);
+                }
+                log_frame!(
+                    "Dropped frame from '{}' {}, as it {} ({})",
+                    src_vport,
+                    DropReason::Chaos,
+                    reason,
+                    DropReason::Chaos.name()
+                );
+                continue;
+            }
+        }
+
+        /*
+         * vports which joined asking to compress frames compress those
+         * which they can. The tag says which, so they are decompressed
+         * whether or not we agreed, e.g. before a restart
+         */
+        if is_compressed(&frame) {
+            match decompress(&frame) {
+                Some(decompressed) => frame = decompressed,
+                None => {
+                    if let Some(port) = ports.get_mut(&src_vport) {
+                        port.counters.drops.count(DropReason::BadCompression);
+                    }
+                    log_frame!(
+                        "Received compressed frame from '{}' which could not be decompressed",
+                        src_vport
+                    );
+                    continue;
+                }
+            }
+        }
+
+        /*
+         * Frames from vports and peer vswitches carry a TTL, while
+         * frames from anything else are entering the L2VPN network
+         */
+        let tagged_ttl = hop_limit(&frame);
+        let received_len = frame.len();
+        let untagged_len = pop_hop_limit(&mut frame, received_len);
+        frame.truncate(untagged_len);
+        let ttl = tagged_ttl.unwrap_or(DEFAULT_TTL).saturating_sub(1);
+
+        /*
+         * QEMU does not pad the frames its guests send, so pad
+         * short frames to the Ethernet minimum before forwarding
+         * them, as the vports drop anything shorter than that
+         */
+        if frame.len() < ETHER_FRAME_MIN {
+            frame.resize(ETHER_FRAME_MIN, 0);
+        }
+        let no_of_bytes = frame.len();
+
+        /* Extract src and dst MAC addresses, which runts have been dropped for lacking */
+        let Ok(EthernetFrame {
+            dst: dst_mac,
+            src: src_mac,
+            ..
+        }) = EthernetFrame::parse(&frame)
+        else {
+            continue;
+        };
+
+        /*
+         * Further links of aggregated ports join them, rather than becoming
+         * ports of their own, and the frames received over them are taken
+         * as received from their port
+         */
+        if let Ok(ControlMsg::LagMember { session_id, token }) = ControlMsg::decode(&frame) {
+            let port = ports
+                .lag_port(src_vport, session_id, token)
+                .filter(|addr| vports.segment(addr) == vports.segment(&src_vport));
+            match port {
+                Some(addr) => {
+                    ports.absorb_link(src_vport, addr, &mut mac_tables);
+                    if vports.lags.join(src_vport, addr, now) {
+                        events.record(format!(
+                            "LAG: {} joined port {} ({}) as a further link",
+                            src_vport,
+                            ports.port(addr).id,
+                            addr
+                        ));
+                    }
+                }
+                None => events.record(format!(
+                    "Ignored LAG member message for session {:016x} from {}, as the \
+                     session isn't connected in its segment, or its token was wrong",
+                    session_id, src_vport
+                )),
+            }
+            continue;
+        }
+        let src_vport = vports.lags.port(&src_vport).unwrap_or(src_vport);
+
+        /* The frame can only reach the vports in its own segment, unless it is reflected */
+        let all_peers = peers.as_slice();
+        let segment = vports.segment(&src_vport);
+        let peers = segment_peers(&peers, segment);
+
+        if ports.get(&src_vport).is_none() {
+            let id = ports.port(src_vport).id;
+            events.record(format!("New vport {} as port {}", src_vport, id));
+        }
+        let port = ports.port(src_vport);
+        port.counters.rx_frames += 1;
+        port.counters.rx_bytes += no_of_bytes as u64;
+        port.sizes.record(no_of_bytes);
+        port.last_seen = Instant::now();
+        if tagged_ttl.is_some() {
+            port.tunnel = true;
+        }
+        let in_port = port.id;
+        if let Some(recorder) = &mut recorder {
+            recorder.record(in_port, &frame);
+        }
+        if port.down {
+            port.down = false;
+            port.usage.restart(&port.counters);
+            events.record(format!("Port {} ({}) is up again", in_port, src_vport));
+        }
+
+        /* Nothing is received from ports which an admin client has shut down */
+        if port.shutdown {
+            drop_frame(
+                &mut ports,
+                &mut monitors,
+                src_vport,
+                &frame,
+                DropReason::PortShutdown,
+            );
+            continue;
+        }
+
+        /* The vports the frame is sent to would drop it anyway */
+        if switching::is_oversize(no_of_bytes, mtu) {
+            drop_frame(
+                &mut ports,
+                &mut monitors,
+                src_vport,
+                &frame,
+                DropReason::Oversize,
+            );
+            continue;
+        }
+
+        /* Peer vswitches carry everyone's traffic, so have no quota */
+        if !peers.contains(&src_vport) {
+            match accounting.check_quota(port) {
+                Some(QuotaAction::Shutdown) => {
+                    port.shutdown = true;
+                    accounting.record(&src_vport, port, "quota");
+                    if let Some(recorder) = &mut recorder {
+                        recorder.alert(in_port, Alert::Quota, &mut events);
+                    }
+                    topology::port_down(
+                        &src_vport,
+                        format!("Port {} ({}) went over its quota", in_port, src_vport),
+                        &mut mac_tables,
+                        &settings.static_macs,
+                        peers,
+                        &vports,
+                        &mut events,
+                    );
+                    drop_frame(
+                        &mut ports,
+                        &mut monitors,
+                        src_vport,
+                        &frame,
+                        DropReason::OverQuota,
+                    );
+                    continue;
+                }
+                Some(QuotaAction::RateLimit) => events.record(format!(
+                    "Port {} ({}) went over its quota, so is rate limited",
+                    in_port, src_vport
+                )),
+                None => {}
+            }
+
+            if !port.usage.allow() {
+                drop_frame(
+                    &mut ports,
+                    &mut monitors,
+                    src_vport,
+                    &frame,
+                    DropReason::OverQuota,
+                );
+                continue;
+            }
+        }
+
+        /*
+         * Frames for the vswitch itself, or for link-local protocols,
+         * are rate limited separately from data frames
+         */
+        if (is_control_frame(&frame) || is_link_local(&dst_mac))
+            && !ports.port(src_vport).control_policer.allow()
+        {
+            drop_frame(
+                &mut ports,
+                &mut monitors,
+                src_vport,
+                &frame,
+                DropReason::ControlPoliced,
+            );
+            continue;
+        }
+
+        /* Control frames are meant for the vswitch, so are never forwarded */
+        if is_control_frame(&frame) {
+            let Ok(msg) = ControlMsg::decode(&frame) else {
+                drop_frame(
+                    &mut ports,
+                    &mut monitors,
+                    src_vport,
+                    &frame,
+                    DropReason::MalformedControl,
+                );
+                continue;
+            };
+
+            monitors.frame(&frame, in_port, &src_vport, "consumed as control message");
+            match msg {
+                ControlMsg::Hello {
+                    session_id,
+                    token,
+                    mtu: vport_mtu,
+                }
+                | ControlMsg::Join {
+                    session_id,
+                    token,
+                    mtu: vport_mtu,
+                    ..
+                } => {
+                    /*
+                     * A vport with another MTU would send frames too large for
+                     * the others, or lose theirs, so its session isn't taken
+                     * up until its MTU matches ours. Older vports send none
+                     */
+                    let mismatch = switching::mtu_mismatch(vport_mtu, mtu);
+                    let port = ports.port(src_vport);
+                    if port.mtu_mismatch != mismatch {
+                        port.mtu_mismatch = mismatch;
+                        events.record(match mismatch {
+                            Some(vport_mtu) => format!(
+                                "Refused port {} ({}), as its MTU of {} doesn't match ours of {}",
+                                in_port, src_vport, vport_mtu, mtu
+                            ),
+                            None => format!(
+                                "Accepted port {} ({}), as its MTU now matches ours",
+                                in_port, src_vport
+                            ),
+                        });
+                    }
+                    if mismatch.is_some() {
+                        continue;
+                    }
+
+                    let hello = ports.hello(src_vport, session_id, token, &mut mac_tables, segment);
+                    if let Some(event) = hello {
+                        events.record(event);
+                    }
+
+                    /* A join also registers the vport, and is answered with its port */
+                    if let ControlMsg::Join {
+                        capabilities,
+                        vlan: requested_vlan,
+                        fragment_size,
+                        public_endpoint,
+                        fec_group,
+                        ..
+                    } = msg
+                    {
+                        let compressing =
+                            capabilities & CAP_COMPRESSION != 0 && compression.unwrap_or(true);
+
+                        /* Fragments too small would split frames into too many */
+                        let fragment_size = fragment_size
+                            .map(usize::from)
+                            .filter(|size| *size >= MIN_FRAGMENT_SIZE)
+                            .filter(|_| {
+                                capabilities & CAP_FRAGMENTATION != 0
+                                    && fragmentation.unwrap_or(true)
+                            });
+
+                        /* Only vports on our own port can be introduced to each other */
+                        let direct = capabilities & CAP_DIRECT_PATHS != 0
+                            && direct_paths.unwrap_or(false)
+                            && matches!(src_vport, VportAddr::Udp(SocketAddr::V4(_)));
+
+                        /* Only datagrams are batched */
+                        let batching = capabilities & CAP_BATCHING != 0
+                            && batching.unwrap_or(true)
+                            && matches!(src_vport, VportAddr::Udp(_) | VportAddr::Listen(..));
+                        let fec_group = fec_group.filter(|_| {
+                            capabilities & CAP_FEC != 0
+                                && fec.unwrap_or(true)
+                                && matches!(src_vport, VportAddr::Udp(_) | VportAddr::Listen(..))
+                        });
+                        let suppressing = capabilities & CAP_ARP_SUPPRESSION != 0
+                            && arp_suppression.unwrap_or(true);
+                        let Some(vlan_changed) = ports.join(
+                            src_vport,
+                            session_id,
+                            capabilities,
+                            requested_vlan,
+                            vlan_requests.unwrap_or(false),
+                            compressing,
+                        ) else {
+                            continue;
+                        };
+                        match fragment_size {
+                            Some(size) => vports.fragment_sizes.insert(src_vport, size),
+                            None => vports.fragment_sizes.remove(&src_vport),
+                        };
+                        /* Batches are never fragmented, so fit in the vport's fragments */
+                        match batching {
+                            true => vports
+                                .batches
+                                .borrow_mut()
+                                .insert(src_vport, Batch::new(fragment_size.unwrap_or(BATCH_MAX))),
+                            false => vports.batches.borrow_mut().remove(&src_vport),
+                        };
+                        match fec_group {
+                            Some(group) => vports
+                                .fec_encoders
+                                .borrow_mut()
+                                .insert(src_vport, Encoder::new(group)),
+                            None => vports.fec_encoders.borrow_mut().remove(&src_vport),
+                        };
+                        let port = ports.port(src_vport);
+                        let mode = port.vlan.clone();
+                        if let Some(registration) = &mut port.registration {
+                            registration.public_endpoint = public_endpoint;
+                        }
+                        events.record(match public_endpoint {
+                            Some(public_endpoint) => format!(
+                                "Port {} ({}) joined, with capabilities {}, from {} as STUN sees it",
+                                in_port,
+                                src_vport,
+                                capability_names(capabilities),
+                                public_endpoint
+                            ),
+                            None => format!(
+                                "Port {} ({}) joined, with capabilities {}",
+                                in_port,
+                                src_vport,
+                                capability_names(capabilities)
+                            ),
+                        });
+
+                        /* The MACs learned on the port were in the VLANs it carried before */
+                        if vlan_changed {
+                            topology::port_down(
+                                &src_vport,
+                                format!(
+                                    "Made port {} ({}) {}, as its vport asked when it joined",
+                                    in_port, src_vport, mode
+                                ),
+                                &mut mac_tables,
+                                &settings.static_macs,
+                                peers,
+                                &vports,
+                                &mut events,
+                            );
+                        }
+
+                        /* The vport is told which VLAN its untagged frames are put in */
+                        let vlan = match mode {
+                            PortVlan::Trunk { native, .. } => native,
+                            mode => mode.untagged_vlan(),
+                        };
+                        let mut reply = ControlMsg::Joined {
+                            session_id,
+                            port_id: in_port,
+                            vlan,
+                            capabilities: match compressing {
+                                true => CAP_COMPRESSION,
+                                false => 0,
+                            } | match fragment_size {
+                                Some(_) => CAP_FRAGMENTATION,
+                                None => 0,
+                            } | match direct {
+                                true => CAP_DIRECT_PATHS,
+                                false => 0,
+                            } | match batching {
+                                true => CAP_BATCHING,
+                                false => 0,
+                            } | match fec_group {
+                                Some(_) => CAP_FEC,
+                                None => 0,
+                            } | match suppressing {
+                                true => CAP_ARP_SUPPRESSION,
+                                false => 0,
+                            },
+                        }
+                        .encode();
+                        reply.resize(ETHER_FRAME_MIN, 0);
+                        if let Err(e) = vports.send_to(&reply, &src_vport) {
+                            eprintln!("Got error while answering join from '{}': {}", src_vport, e);
+                        }
+
+                        /* The vport is told the addresses already learned in its domain */
+                        if suppressing {
+                            let port = ports.port(src_vport);
+                            let domain =
+                                neighbours::untagged_domain(&vports, &src_vport, &port.vlan);
+                            let entries = neighbours.entries(domain, Instant::now());
+                            neighbours::tell(&vports, &src_vport, &entries);
+                        }
+                    }
+                }
+                /* Only vports are answered when they join */
+                ControlMsg::Joined { .. } => {}
+                ControlMsg::EchoReply { timestamp } => {
+                    let rtt = start
+                        .elapsed()
+                        .saturating_sub(Duration::from_micros(timestamp));
+                    ports.port(src_vport).latency.record_rtt(rtt);
+                }
+                /* Peer vswitches send echo requests to check we are still up */
+                ControlMsg::EchoRequest { timestamp } => {
+                    let mut reply = ControlMsg::EchoReply { timestamp }.encode();
+                    reply.resize(ETHER_FRAME_MIN, 0);
+                    if let Err(e) = vports.send_to(&reply, &src_vport) {
+                        eprintln!(
+                            "Got error while sending echo reply to '{}': {}",
+                            src_vport, e
+                        );
+                    }
+                }
+                /* vports finding their path MTU are answered as large as they probed */
+                ControlMsg::PathMtuProbe { id } => {
+                    let mut reply = ControlMsg::PathMtuAck { id }.encode();
+                    reply.resize(frame.len().max(ETHER_FRAME_MIN), 0);
+                    if let Err(e) = vports.send_to(&reply, &src_vport) {
+                        eprintln!(
+                            "Got error while answering path MTU probe from '{}': {}",
+                            src_vport, e
+                        );
+                    }
+                }
+                /* Only vports probe the path MTU */
+                ControlMsg::PathMtuAck { .. } => {}
+                /* Only vports are introduced, and punch through to each other */
+                ControlMsg::Introduce { .. }
+                | ControlMsg::Punch { .. }
+                | ControlMsg::PunchAck { .. } => {}
+                /* Only vports are told the neighbours we learn */
+                ControlMsg::Neighbours { .. } => {}
+                /* Only vports on our own port can be reached through the group */
+                ControlMsg::GroupMember { group } => {
+                    let joined = matches!(src_vport, VportAddr::Udp(_))
+                        && bum_group.as_ref().is_some_and(|bum| bum.group() == group);
+                    let port = ports.port(src_vport);
+                    if !joined {
+                        port.group_member_seen = None;
+                    } else if port.group_member_seen.replace(Instant::now()).is_none() {
+                        events.record(format!(
+                            "Port {} ({}) joined the BUM group {}",
+                            in_port, src_vport, group
+                        ));
+                    }
+                }
+                /* LAG member messages were handled as soon as they were received */
+                ControlMsg::LagMember { .. } => {}
+                /* The vport is stopping, so its MACs are flushed before it goes quiet */
+                ControlMsg::Leave { session_id, token } => {
+                    if !ports.leaves(&src_vport, session_id, token) {
+                        events.record(format!(
+                            "Ignored leave for session {:016x} from port {} ({}), as it isn't the port's",
+                            session_id, in_port, src_vport
+                        ));
+                        continue;
+                    }
+                    vports.fragment_sizes.remove(&src_vport);
+                    vports.batches.borrow_mut().remove(&src_vport);
+                    vports.fec_encoders.borrow_mut().remove(&src_vport);
+                    let port = ports.port(src_vport);
+                    accounting.record(&src_vport, port, "left");
+                    port.down = true;
+                    topology::port_down(
+                        &src_vport,
+                        format!("Port {} ({}) left", in_port, src_vport),
+                        &mut mac_tables,
+                        &settings.static_macs,
+                        peers,
+                        &vports,
+                        &mut events,
+                    );
+                    vports.forget(&src_vport);
+                }
+                /* The MACs are no longer reachable through the peer which sent this */
+                ControlMsg::TopologyChange { macs } => {
+                    let flushed: Vec<MacAddr> = segment_tables(&mut mac_tables, segment)
+                        .flat_map(|mac_table| {
+                            topology::flush_macs(
+                                mac_table,
+                                &settings.static_macs,
+                                &src_vport,
+                                &macs,
+                            )
+                        })
+                        .collect();
+                    if !flushed.is_empty() {
+                        events.record(format!(
+                            "Flushed {} MAC(s) after a topology change from port {}",
+                            flushed.len(),
+                            in_port
+                        ));
+                        topology::notify_peers(&vports, peers, Some(&src_vport), &flushed);
+                    }
+                }
+            }
+            continue;
+        }
+
+        /* The vport was refused for having another MTU than ours */
+        if ports.port(src_vport).mtu_mismatch.is_some() {
+            drop_frame(
+                &mut ports,
+                &mut monitors,
+                src_vport,
+                &frame,
+                DropReason::MtuMismatch,
+            );
+            continue;
+        }
+
+        /* BPDUs are meant for the vswitch's spanning tree, which only frames forward through */
+        if let Some(stp) = &mut stp {
+            if let Some(bpdu) = Bpdu::parse(&frame) {
+                monitors.frame(&frame, in_port, &src_vport, "consumed by STP");
+                let now = Instant::now();
+                if stp.receive(src_vport, bpdu, now, &mut ports, &vports, &mut events) {
+                    topology::flush_segment(
+                        segment,
+                        format!("STP: topology of segment {} changed", segment),
+                        &mut mac_tables,
+                        &settings.static_macs,
+                        peers,
+                        &vports,
+                        &mut events,
+                    );
+                }
+                continue;
+            }
+        }
+
+        /* Data frames are mirrored as they were received, even if they are then dropped */
+        mirrors.received(&src_vport, &frame, &ports, &vports);
+
+        if let Some(rx_limit) = &mut ports.port(src_vport).rx_limit {
+            if !rx_limit.allow(frame.len()) {
+                drop_frame(
+                    &mut ports,
+                    &mut monitors,
+                    src_vport,
+                    &frame,
+                    DropReason::RateLimit,
+                );
+                continue;
+            }
+        }
+
+        if !ports.port(src_vport).stp.learns() {
+            drop_frame(
+                &mut ports,
+                &mut monitors,
+                src_vport,
+                &frame,
+                DropReason::StpBlocked,
+            );
+            continue;
+        }
+
+        /*
+         * Frames from access ports, and untagged frames from trunks with
+         * a native VLAN, are tagged with the port's VLAN, and those from
+         * tunnel ports with the port's S-VLAN, so every frame carries the
+         * VLAN it is in from here on, and is forwarded and flooded within it
+         */
+        let port_vlan = &ports.port(src_vport).vlan;
+        let ingress = port_vlan
+            .ingress(&frame)
+            .map(|vlan| (vlan, port_vlan.tag(&frame, vlan)));
+        let vlan = match ingress {
+            Ok((vlan, tagged)) => {
+                if let Some(tagged) = tagged {
+                    frame = tagged;
+                }
+                vlan
+            }
+            Err(reason) => {
+                drop_frame(&mut ports, &mut monitors, src_vport, &frame, reason);
+                continue;
+            }
+        };
+        let domain = Domain { segment, vlan };
+
+        /* The frame has passed through too many vswitches, so is probably looping */
+        if ttl == 0 {
+            if let Some(recorder) = &mut recorder {
+                recorder.alert(in_port, Alert::TtlExpired, &mut events);
+            }
+            drop_frame(
+                &mut ports,
+                &mut monitors,
+                src_vport,
+                &frame,
+                DropReason::TtlExpired,
+            );
+            continue;
+        }
+
+        if !settings.acl_permits(&frame, in_port, None) {
+            drop_frame(
+                &mut ports,
+                &mut monitors,
+                src_vport,
+                &frame,
+                DropReason::AclDenied,
+            );
+            continue;
+        }
+
+        /* Requests for the built-in DHCP server are answered, rather than forwarded */
+        let serves_domain = |dhcp: &&mut DhcpServer| dhcp.segment() == segment && vlan.is_none();
+        if let Some(dhcp) = dhcp.as_mut().filter(serves_domain) {
+            if dhcp.is_request(&frame) {
+                let reply = dhcp.handle(&frame, &mut events);
+                monitors.frame(&frame, in_port, &src_vport, "answered by the DHCP server");
+                if let Some(reply) = reply {
+                    let copies = EgressFrames::new(&reply, None, DEFAULT_TTL);
+                    let out_frame = copies.to(&ports, &src_vport);
+                    if let Err(e) = vports.send_to(out_frame, &src_vport) {
+                        eprintln!(
+                            "Got error while sending DHCP reply to '{}': {}",
+                            src_vport, e
+                        );
+                    }
+                    count_tx(&mut ports, src_vport, reply.len());
+                    mirrors.sent(&src_vport, out_frame, &ports, &vports);
+                }
+                continue;
+            }
+        }
+
+        /* Addresses learned from ARP are told to the vports which answer it themselves */
+        if arp_suppression.unwrap_or(true) {
+            if let Some(entry) = neighbours.snoop(domain, &frame, received) {
+                neighbours::tell_domain(&vports, &ports, domain, entry, &src_vport);
+            }
+        }
+
+        /*
+         * A bridge in a VM has seen its topology change, so MACs learned
+         * elsewhere may now be reachable through a different vport
+         */
+        if topology::is_topology_change(&frame) {
+            let flushed: Vec<MacAddr> = segment_tables(&mut mac_tables, segment)
+                .flat_map(|mac_table| {
+                    topology::flush_others(mac_table, &settings.static_macs, &src_vport)
+                })
+                .collect();
+            if !flushed.is_empty() {
+                events.record(format!(
+                    "Flushed {} MAC(s) after an STP topology change from port {}",
+                    flushed.len(),
+                    in_port
+                ));
+                topology::notify_peers(&vports, peers, Some(&src_vport), &flushed);
+            }
+        }
+
+        /*
+         * A new source MAC on a port which has already learned its limit
+         * of MACs is a violation. Peers carry the MACs of everyone behind
+         * them, so have no limit
+         */
+        if let Some(limit) = settings.mac_limit {
+            if settings.learning
+                && !peers.contains(&src_vport)
+                && !settings.static_macs.contains(&src_mac)
+                && port_security::violates(
+                    &limit,
+                    &mac_tables,
+                    &settings.static_macs,
+                    &src_vport,
+                    &src_mac,
+                )
+            {
+                let port = ports.port(src_vport);
+                let event = format!(
+                    "Port {} ({}) went over its limit of {} MAC(s) with MAC {}",
+                    in_port, src_vport, limit.max, src_mac
+                );
+                match limit.action {
+                    MacLimitAction::Shutdown => {
+                        port.shutdown = true;
+                        accounting.record(&src_vport, port, "mac-limit");
+                        topology::port_down(
+                            &src_vport,
+                            event,
+                            &mut mac_tables,
+                            &settings.static_macs,
+                            peers,
+                            &vports,
+                            &mut events,
+                        );
+                    }
+                    /* Violations are only recorded once until the port learns a MAC again */
+                    _ if !port.over_mac_limit => {
+                        port.over_mac_limit = true;
+                        events.record(event);
+                    }
+                    _ => {}
+                }
+                if limit.action != MacLimitAction::Log {
+                    drop_frame(
+                        &mut ports,
+                        &mut monitors,
+                        src_vport,
+                        &frame,
+                        DropReason::MacLimit,
+                    );
+                    continue;
+                }
+            }
+        }
+
+        let eth_frame = &frame[..];
+        let mac_table = mac_tables.entry(domain).or_default();
+
+        if let Some(sampler) = &mut sampler {
+            sampler.frame(eth_frame, in_port);
+        }
+
+        log_frame!(
+            "vswitch: received frame ({}) on port {} ('{}')",
+            FrameLogMsg(eth_frame, no_of_bytes),
+            in_port,
+            src_vport,
+        );
+
+        /* A dampened MAC is held on its port, so its frames from anywhere else are dropped */
+        if mac_moves
+            .dampened(domain, &src_mac)
+            .is_some_and(|held_on| *held_on != src_vport)
+        {
+            drop_frame(
+                &mut ports,
+                &mut monitors,
+                src_vport,
+                eth_frame,
+                DropReason::MacMove,
+            );
+            continue;
+        }
+
+        /*
+         * If entry in MAC table contradicts source of received
+         * frame, then update table, unless learning is off or
+         * an admin client has fixed the MAC's port
+         */
+        mac_ages
+            .entry(domain)
+            .or_insert_with(|| MacAges::new(received))
+            .seen(src_mac, received);
+        let learned = match settings.learning && !settings.static_macs.contains(&src_mac) {
+            true => switching::learn(mac_table, src_mac, src_vport),
+            false => None,
+        };
+        if let Some(learned) = learned {
+            ports.port(src_vport).over_mac_limit = false;
+            let event = match learned {
+                Learned::Moved(old_vport) => {
+                    format!("MAC {} moved from {} to {}", src_mac, old_vport, src_vport)
+                }
+                Learned::New => format!("MAC {} learned on {}", src_mac, src_vport),
+            };
+            events.record(event);
+
+            /* A MAC which keeps moving between vports is probably looping */
+            let flapping = match (learned, settings.mac_move_limit) {
+                (Learned::Moved(old_vport), Some(limit))
+                    if mac_moves.moved(domain, src_mac, &limit, received) =>
+                {
+                    Some((old_vport, limit))
+                }
+                _ => None,
+            };
+            if let Some((old_vport, limit)) = flapping {
+                let event = format!(
+                    "MAC {} is flapping between {} and {}, having moved more than {} time(s) in {}s",
+                    src_mac,
+                    old_vport,
+                    src_vport,
+                    limit.moves,
+                    limit.window.as_secs()
+                );
+                match limit.action {
+                    MacMoveAction::Dampen => {
+                        mac_table.insert(src_mac, old_vport);
+                        mac_moves.dampen(domain, src_mac, old_vport, received);
+                        events.record(format!(
+                            "{}, so held it on {} for {}s",
+                            event,
+                            old_vport,
+                            DAMPEN_TIME.as_secs()
+                        ));
+                        drop_frame(
+                            &mut ports,
+                            &mut monitors,
+                            src_vport,
+                            eth_frame,
+                            DropReason::MacMove,
+                        );
+                        continue;
+                    }
+                    MacMoveAction::Shutdown if !peers.contains(&src_vport) => {
+                        let port = ports.port(src_vport);
+                        port.shutdown = true;
+                        accounting.record(&src_vport, port, "mac-move");
+                        topology::port_down(
+                            &src_vport,
+                            format!(
+                                "Shut down port {} ({}), as MAC {} is flapping between it and {}",
+                                in_port, src_vport, src_mac, old_vport
+                            ),
+                            &mut mac_tables,
+                            &settings.static_macs,
+                            peers,
+                            &vports,
+                            &mut events,
+                        );
+                        drop_frame(
+                            &mut ports,
+                            &mut monitors,
+                            src_vport,
+                            eth_frame,
+                            DropReason::MacMove,
+                        );
+                        continue;
+                    }
+                    /* Peers carry the MACs of everyone behind them, so are never shut down */
+                    _ => events.record(event),
+                }
+            }
+
+            /* Print updated MAC table */
+            print_mac_table(mac_table, &ports);
+        }
+
+        /* Ports which STP has learning are learned from, but not yet forwarded from */
+        if !ports.port(src_vport).stp.forwards() {
+            drop_frame(
+                &mut ports,
+                &mut monitors,
+                src_vport,
+                eth_frame,
+                DropReason::StpBlocked,
+            );
+            continue;
+        }
+
+        /*
+         * Forward the received packet out the appropriate vport(s)
+         */
+        let flooded = always_flooded(peers, &ports, &vports, domain, Some(&src_vport));
+        let snooped = match &mut snooper {
+            Some(snooper) => {
+                snooper.snoop(domain, src_vport, eth_frame, received, &mut events);
+                snooper.forwarding(domain, eth_frame, peers, received)
+            }
+            None => Snooped::Normal,
+        };
+        let to_listeners = matches!(snooped, Snooped::To(_));
+        let decision = snooped_forwarding(
+            snooped,
+            mac_table,
+            &ports,
+            &flooded,
+            &settings,
+            Some(&src_vport),
+            eth_frame,
+        );
+        let (decision, egress_denied) =
+            egress_acl(decision, &mut settings, &ports, eth_frame, in_port);
+        let decision = egress_rate_limit(decision, &mut ports, eth_frame.len());
+
+        /* Frames flooded beyond storm control's rate are dropped, except for peers' */
+        if let (Forwarding::Broadcast(_), Some(rate)) = (&decision, settings.storm_control) {
+            if !peers.contains(&src_vport)
+                && !storm_allows(&mut ports.port(src_vport).storm_policer, rate)
+            {
+                drop_frame(
+                    &mut ports,
+                    &mut monitors,
+                    src_vport,
+                    eth_frame,
+                    DropReason::StormControl,
+                );
+                continue;
+            }
+        }
+
+        if !monitors.is_empty() {
+            let outcome = match &decision {
+                Forwarding::Unicast(dst_vport) => format!("unicast to {}", dst_vport),
+                Forwarding::Broadcast(dst_vports) => {
+                    format!("broadcast to {} vport(s)", dst_vports.len())
+                }
+                Forwarding::Drop(reason) => format!("dropped {}", reason),
+            };
+            monitors.frame(eth_frame, in_port, &src_vport, &outcome);
+        }
+
+        let copies = EgressFrames::new(eth_frame, vlan, ttl);
+        let priority = vlan_tag(eth_frame).map_or(0, |tag| tag.pcp);
+
+        match decision {
+            /* If the vport for the dst_mac is known, forward it */
+            Forwarding::Unicast(dst_vport) => {
+                let out_frame = copies.to(&ports, &dst_vport);
+                if enqueue(&mut ports, src_vport, dst_vport, priority, out_frame) {
+                    drain_queues(dst_vport, &mut ports, &vports, &mut mirrors);
+                } else if let Err(e) = vports.forward(out_frame, &dst_vport, in_port) {
+                    eprintln!("Got error while forwarding frame unicast: {}", e);
+                    ports
+                        .port(src_vport)
+                        .counters
+                        .drops
+                        .count(DropReason::TxError);
+                    continue;
+                }
+                let out_port = count_tx(&mut ports, dst_vport, no_of_bytes);
+                mirrors.sent(&dst_vport, out_frame, &ports, &vports);
+                log_frame!(
+                    "Unicast forwarded to {} on port {} ('{}'), {}",
+                    dst_mac,
+                    out_port,
+                    dst_vport,
+                    VlanLogMsg(eth_frame)
+                );
+                ports
+                    .port(src_vport)
+                    .latency
+                    .record_forwarding(received.elapsed());
+
+                /* vports which asked for direct paths are told how to reach each other */
+                if direct_paths == Some(true) {
+                    let introductions = rendezvous.introductions(
+                        &ports,
+                        (src_vport, src_mac),
+                        (dst_vport, dst_mac),
+                        received,
+                    );
+                    for (to, msg) in introductions {
+                        let mut frame = msg.encode();
+                        frame.resize(ETHER_FRAME_MIN, 0);
+                        if let Err(e) = vports.send_to(&frame, &to) {
+                            eprintln!("Got error while sending introduction to '{}': {}", to, e);
+                        }
+                    }
+                }
+            }
+            Forwarding::Broadcast(mut dst_vports) => {
+                let flooded = !dst_vports.is_empty();
+
+                /*
+                 * vports which joined the BUM group are sent the frame once, through
+                 * it, unless it is a multicast only for the vports which joined its
+                 * IGMP group, or an egress ACL denies it to some vports, as the BUM
+                 * group would deliver it to the others too
+                 */
+                if let Some(bum) = bum_group
+                    .as_mut()
+                    .filter(|_| segment == DEFAULT_SEGMENT && !to_listeners && !egress_denied)
+                {
+                    let members = bum.take_members(&ports, &mut dst_vports);
+                    if !members.is_empty() {
+                        let session_id = ports
+                            .get(&src_vport)
+                            .and_then(|port| port.session_id)
+                            .unwrap_or(0);
+                        match bum.send(&vports.socket, session_id, &copies.tagged, members.len()) {
+                            Ok(()) => {
+                                for member in members.iter() {
+                                    count_tx(&mut ports, *member, no_of_bytes);
+                                    mirrors.sent(
+                                        member,
+                                        copies.to(&ports, member),
+                                        &ports,
+                                        &vports,
+                                    );
+                                }
+                                log_frame!(
+                                    "Broadcast forwarded to {} on {} port(s) through the BUM group, {}",
+                                    dst_mac,
+                                    members.len(),
+                                    VlanLogMsg(eth_frame)
+                                );
+                            }
+                            /* The members can still be sent their own copies */
+                            Err(e) => {
+                                eprintln!("Got error while sending frame to the BUM group: {}", e);
+                                dst_vports.extend(members);
+                            }
+                        }
+                    }
+                }
+
+                for dst_vport in dst_vports {
+                    let out_frame = copies.to(&ports, &dst_vport);
+                    if enqueue(&mut ports, src_vport, dst_vport, priority, out_frame) {
+                        drain_queues(dst_vport, &mut ports, &vports, &mut mirrors);
+                        continue;
+                    }
+                    if let Err(e) = vports.forward(out_frame, &dst_vport, in_port) {
+                        eprintln!("Got error while forwarding frame broadcast: {}", e);
+                        ports
+                            .port(src_vport)
+                            .counters
+                            .drops
+                            .count(DropReason::TxError);
+                        continue;
+                    }
+                    let out_port = count_tx(&mut ports, dst_vport, no_of_bytes);
+                    mirrors.sent(&dst_vport, out_frame, &ports, &vports);
+                    log_frame!(
+                        "Broadcast forwarded to {} on port {} ('{}'), {}",
+                        dst_mac,
+                        out_port,
+                        dst_vport,
+                        VlanLogMsg(eth_frame)
+                    );
+                }
+                if flooded {
+                    ports
+                        .port(src_vport)
+                        .latency
+                        .record_forwarding(received.elapsed());
+                }
+            }
+            /* The monitors have already been told why */
+            Forwarding::Drop(reason) => {
+                ports.port(src_vport).counters.drops.count(reason);
+                log_frame!(
+                    "Dropped frame from port {} ('{}'), {}, {} ({})",
+                    in_port,
+                    src_vport,
+                    VlanLogMsg(eth_frame),
+                    reason,
+                    reason.name()
+                );
+            }
+        }
+
+        /* Discovery multicasts are copied to the other segments or VLANs they are reflected to */
+        let reflections = match &mut reflector {
+            Some(reflector) => reflector.reflect(domain, eth_frame),
+            None => Vec::new(),
+        };
+        for (endpoint, copy) in reflections {
+            let copy_len = copy.len();
+            let copies = EgressFrames::new(&copy, endpoint.vlan, ttl);
+
+            let mac_table = mac_tables.entry(endpoint).or_default();
+            let mut dst_vports = reflection_targets(
+                mac_table,
+                &ports,
+                segment_peers(all_peers, endpoint.segment),
+                &src_vport,
+            );
+            dst_vports.retain(|dst_vport| {
+                let Some(port) = ports.get_mut(dst_vport) else {
+                    return settings.acl_permits(&copy, in_port, Some(0));
+                };
+                settings.acl_permits(&copy, in_port, Some(port.id))
+                    && port
+                        .tx_limit
+                        .as_mut()
+                        .is_none_or(|tx_limit| tx_limit.allow(copy_len))
+            });
+            for dst_vport in dst_vports {
+                let out_frame = copies.to(&ports, &dst_vport);
+                if enqueue(&mut ports, src_vport, dst_vport, priority, out_frame) {
+                    drain_queues(dst_vport, &mut ports, &vports, &mut mirrors);
+                    continue;
+                }
+                if let Err(e) = vports.forward(out_frame, &dst_vport, in_port) {
+                    eprintln!("Got error while reflecting frame: {}", e);
+                    ports
+                        .port(src_vport)
+                        .counters
+                        .drops
+                        .count(DropReason::TxError);
+                    continue;
+                }
+                let out_port = count_tx(&mut ports, dst_vport, copy_len);
+                mirrors.sent(&dst_vport, out_frame, &ports, &vports);
+                log_frame!(
+                    "Reflected frame from port {} to {} on port {} ('{}'), {}",
+                    in_port,
+                    endpoint,
+                    out_port,
+                    dst_vport,
+                    VlanLogMsg(&copy)
+                );
+            }
+        }
+    }
+}
+
+/// Where a frame is forwarded to, once its source MAC has been learned
+enum Forwarding {
+    /// Sent to the vport which the destination MAC was learned on
+    Unicast(VportAddr),
+    /// Sent to the vports of every MAC except the source MAC, and
+    /// to every peer vswitch and access port in the frame's VLAN
+    /// except the one it came from, as it is a broadcast, or is flooded,
+    /// or to the vports IGMP snooping found for a multicast
+    Broadcast(Vec<VportAddr>),
+    /// Dropped, as the destination MAC is unknown, or its port is shut
+    /// down, blocked by STP or in the source's split-horizon group
+    Drop(DropReason),
+}
+
+/// Decide where a frame from src_mac to dst_mac, received from src_vport,
+/// is forwarded to, where peers are the ports which are flooded even without
+/// any learned MACs, and frames to unknown MACs are flooded if flood_unknown
+/// is set
+///
+/// Ports which an admin client has shut down, or STP is blocking, are never
+/// sent frames, and ports in src_vport's split-horizon group are never sent
+/// its frames
+fn forwarding(
+    mac_table: &MacTable<VportAddr>,
+    ports: &PortTable<VportAddr>,
+    peers: &[VportAddr],
+    flood_unknown: bool,
+    src_vport: Option<&VportAddr>,
+    src_mac: &MacAddr,
+    dst_mac: &MacAddr,
+) -> Forwarding {
+    let split_horizon =
+        |vport: &VportAddr| src_vport.is_some_and(|src| ports.same_split_horizon(src, vport));
+    let decision = switching::decide(mac_table, peers, src_mac, dst_mac, flood_unknown, |vport| {
+        ports.is_shutdown(vport) || ports.is_blocked(vport) || split_horizon(vport)
+    });
+    match decision {
+        Decision::Unicast(dst_vport) => Forwarding::Unicast(dst_vport),
+        Decision::Flood(dst_vports) => Forwarding::Broadcast(dst_vports),
+        Decision::Excluded if ports.is_shutdown(&mac_table[dst_mac]) => {
+            Forwarding::Drop(DropReason::DestinationShutdown)
+        }
+        Decision::Excluded if ports.is_blocked(&mac_table[dst_mac]) => {
+            Forwarding::Drop(DropReason::StpBlocked)
+        }
+        Decision::Excluded => Forwarding::Drop(DropReason::SplitHorizon),
+        /* ARP resolution is outside the scope of this project */
+        Decision::Unknown => Forwarding::Drop(DropReason::UnknownUnicast),
+    }
+}
+
+/// Decide where a frame from src_vport is forwarded to, given the vports
+/// which IGMP snooping has it sent to, leaving out src_vport, the ports
+/// in its split-horizon group, and the ports which are down, shut down
+/// or blocked by STP
+fn snooped_forwarding(
+    snooped: Snooped,
+    mac_table: &MacTable<VportAddr>,
+    ports: &PortTable<VportAddr>,
+    peers: &[VportAddr],
+    settings: &Settings,
+    src_vport: Option<&VportAddr>,
+    frame: &[u8],
+) -> Forwarding {
+    let Ok(EthernetFrame {
+        dst: dst_mac,
+        src: src_mac,
+        ..
+    }) = EthernetFrame::parse(frame)
+    else {
+        return Forwarding::Drop(DropReason::Runt);
+    };
+    let dst_vports = match snooped {
+        Snooped::Normal => {
+            return forwarding(
+                mac_table,
+                ports,
+                peers,
+                settings.flooding,
+                src_vport,
+                &src_mac,
+                &dst_mac,
+            )
+        }
+        Snooped::Flood => {
+            return forwarding(mac_table, ports, peers, true, src_vport, &src_mac, &dst_mac)
+        }
+        Snooped::To(dst_vports) => dst_vports,
+    };
+
+    let dst_vports: Vec<VportAddr> = dst_vports
+        .into_iter()
+        .filter(|dst_vport| {
+            Some(dst_vport) != src_vport
+                && ports.get(dst_vport).is_none_or(|port| !port.down)
+                && !ports.is_shutdown(dst_vport)
+                && !ports.is_blocked(dst_vport)
+                && src_vport.is_none_or(|src| !ports.same_split_horizon(src, dst_vport))
+        })
+        .collect();
+    match dst_vports.is_empty() {
+        true => Forwarding::Drop(DropReason::NoListeners),
+        false => Forwarding::Broadcast(dst_vports),
+    }
+}
+
+/// Apply the ACL rules bound to the ports which decision sends frame to,
+/// where frame came from the port with ID in_port, returning where it is
+/// then sent, and whether any of the vports it was sent to were left out
+fn egress_acl(
+    decision: Forwarding,
+    settings: &mut Settings,
+    ports: &PortTable<VportAddr>,
+    frame: &[u8],
+    in_port: u32,
+) -> (Forwarding, bool) {
+    let mut permits = |dst_vport: &VportAddr| {
+        let out_port = ports.get(dst_vport).map_or(0, |port| port.id);
+        settings.acl_permits(frame, in_port, Some(out_port))
+    };
+    match decision {
+        Forwarding::Unicast(dst_vport) if !permits(&dst_vport) => {
+            (Forwarding::Drop(DropReason::AclDenied), true)
+        }
+        Forwarding::Broadcast(mut dst_vports) => {
+            let len = dst_vports.len();
+            dst_vports.retain(permits);
+            let denied = dst_vports.len() < len;
+            (Forwarding::Broadcast(dst_vports), denied)
+        }
+        decision => (decision, false),
+    }
+}
+
+/// Apply the egress rate limits of the ports which decision sends a frame
+/// of len bytes to, returning where it is then sent
+fn egress_rate_limit(
+    decision: Forwarding,
+    ports: &mut PortTable<VportAddr>,
+    len: usize,
+) -> Forwarding {
+    /* Frames for ports with egress queues wait there for their rate limit instead */
+    let mut allows = |dst_vport: &VportAddr| match ports.get_mut(dst_vport) {
+        Some(port) if port.queues.is_none() => port
+            .tx_limit
+            .as_mut()
+            .is_none_or(|tx_limit| tx_limit.allow(len)),
+        _ => true,
+    };
+    match decision {
+        Forwarding::Unicast(dst_vport) if !allows(&dst_vport) => {
+            Forwarding::Drop(DropReason::DestinationRateLimit)
+        }
+        Forwarding::Broadcast(mut dst_vports) => {
+            dst_vports.retain(allows);
+            Forwarding::Broadcast(dst_vports)
+        }
+        decision => decision,
+    }
+}
+
+/// Returns the vports which a discovery multicast reflected into the segment
+/// of mac_table is sent to, which are those of every MAC in it, and its peers,
+/// except the vport it came from, the ports in its split-horizon group,
+/// ports an admin client has shut down and ports which STP is blocking
+fn reflection_targets(
+    mac_table: &MacTable<VportAddr>,
+    ports: &PortTable<VportAddr>,
+    peers: &[VportAddr],
+    src_vport: &VportAddr,
+) -> Vec<VportAddr> {
+    let mut dst_vports: Vec<VportAddr> = Vec::new();
+    for dst_vport in mac_table.values().chain(peers) {
+        if dst_vport != src_vport
+            && !dst_vports.contains(dst_vport)
+            && !ports.is_shutdown(dst_vport)
+            && !ports.is_blocked(dst_vport)
+            && !ports.same_split_horizon(src_vport, dst_vport)
+        {
+            dst_vports.push(*dst_vport);
+        }
+    }
+    dst_vports
+}
+
+/// Parse and validate the configuration in args, printing every
+/// problem found, without binding any sockets or starting the vswitch
+fn check_config(args: &[String]) -> ExitCode {
+    let config = match config::parse_args(args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("{}", USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut errors = config::validate(&config);
+
+    /* Make sure the state file saved by a previous vswitch can be resumed from */
+    if let Some(path) = &config.state_path {
+        if let Err(e) = PortTable::<VportAddr>::load(path) {
+            errors.push(format!("--state-file '{}': {}", path, e));
+        }
+    }
+    if let Some(path) = &config.dhcp_path {
+        if let Err(e) = DhcpServer::load(path) {
+            errors.push(format!("--dhcp-config '{}': {}", path, e));
+        }
+    }
+
+    if !errors.is_empty() {
+        for e in errors.iter() {
+            eprintln!("{}", e);
+        }
+        eprintln!("Configuration has {} error(s)", errors.len());
+        return ExitCode::FAILURE;
+    }
+
+    println!("Configuration OK");
+    ExitCode::SUCCESS
+}
+
+/// Print MAC table in human readable format
+fn print_mac_table(mac_table: &MacTable<VportAddr>, ports: &PortTable<VportAddr>) {
+    println!("MAC Table:");
+
+    for (mac_addr, vport) in mac_table.iter() {
+        match ports.get(vport) {
+            Some(port) => println!("\t{}: {} (port {})", mac_addr, vport, port.id),
+            None => println!("\t{}: {}", mac_addr, vport),
+        }
+    }
+}
+
+/// Copies of a frame as they are sent to each vport, with a hop limit
+/// tag for those which send them, and without the frame's outer VLAN
+/// tag for access ports, QinQ tunnel ports and trunks whose native VLAN
+/// the frame is in. Copies with a hop limit tag are compressed for the
+/// vports which joined asking for that, when it makes them smaller
+struct EgressFrames<'a> {
+    plain: &'a [u8],
+    tagged: Vec<u8>,
+    vlan: Option<u16>,
+    /* Untagged copies, with and without a hop limit tag, if the frame is in a VLAN */
+    untagged: Option<(Vec<u8>, Vec<u8>)>,
+    /* Compressed copies of the tagged copies, made when first needed, which are None if they don't compress */
+    compressed: OnceCell<Option<Vec<u8>>>,
+    untagged_compressed: OnceCell<Option<Vec<u8>>>,
+}
+
+impl EgressFrames<'_> {
+    /// Returns the copies of frame, which is in vlan, and carries ttl
+    fn new(frame: &[u8], vlan: Option<u16>, ttl: u8) -> EgressFrames<'_> {
+        let untagged = vlan.map(|_| {
+            let plain = retag(frame, None);
+            let tagged = with_hop_limit(&plain, ttl);
+            (plain, tagged)
+        });
+        EgressFrames {
+            plain: frame,
+            tagged: with_hop_limit(frame, ttl),
+            vlan,
+            untagged,
+            compressed: OnceCell::new(),
+            untagged_compressed: OnceCell::new(),
+        }
+    }
+
+    /// Returns the copy to send to the vport at dst
+    fn to(&self, ports: &PortTable<VportAddr>, dst: &VportAddr) -> &[u8] {
+        let port = ports.get(dst);
+        let untagged_port = port.is_some_and(|port| port.vlan.untags(self.vlan));
+        let (plain, tagged, compressed) = match &self.untagged {
+            Some((plain, tagged)) if untagged_port => {
+                (&plain[..], tagged, &self.untagged_compressed)
+            }
+            _ => (self.plain, &self.tagged, &self.compressed),
+        };
+        match port {
+            Some(port) if port.tunnel && port.registration.is_some_and(|r| r.compressing) => {
+                compressed
+                    .get_or_init(|| compress(tagged))
+                    .as_deref()
+                    .unwrap_or(tagged)
+            }
+            Some(port) if port.tunnel => tagged,
+            _ => plain,
+        }
+    }
+}
+
+/// Returns a copy of frame with a hop limit tag carrying ttl, which frames
+/// keep when sent to those which understand the hop limit tag
+fn with_hop_limit(frame: &[u8], ttl: u8) -> Vec<u8> {
+    let mut tagged = frame.to_vec();
+    tagged.resize(frame.len() + HOP_LIMIT_TAG_LEN, 0);
+    push_hop_limit(&mut tagged, frame.len(), ttl);
+    tagged
+}
+
+/// Count a frame received from the vport at src_vport as dropped
+/// for reason, and tell the monitors and the frame log why
+fn drop_frame(
+    ports: &mut PortTable<VportAddr>,
+    monitors: &mut Monitors,
+    src_vport: VportAddr,
+    frame: &[u8],
+    reason: DropReason,
+) {
+    let port = ports.port(src_vport);
+    port.counters.drops.count(reason);
+    let in_port = port.id;
+
+    if !monitors.is_empty() {
+        monitors.frame(frame, in_port, &src_vport, &format!("dropped {}", reason));
+    }
+    log_frame!(
+        "Dropped frame from port {} ('{}'), {}, {} ({})",
+        in_port,
+        src_vport,
+        VlanLogMsg(frame),
+        reason,
+        reason.name()
+    );
+}
+
+/// Count a frame of the given size as sent to the vport
+/// at dst, and return the ID of the vport's port
+fn count_tx(ports: &mut PortTable<VportAddr>, dst: VportAddr, no_of_bytes: usize) -> u32 {
+    let port = ports.port(dst);
+    port.counters.tx_frames += 1;
+    port.counters.tx_bytes += no_of_bytes as u64;
+    port.id
+}
+
+/// Queue out_frame, from the vport at src, which has the given priority,
+/// for the vport at dst, returning true if dst's port has egress queues,
+/// in which case the frame is dropped if its queue is full, and false
+/// if it is sent straight away, as usual
+fn enqueue(
+    ports: &mut PortTable<VportAddr>,
+    src: VportAddr,
+    dst: VportAddr,
+    priority: u8,
+    out_frame: &[u8],
+) -> bool {
+    let in_port = ports.port(src).id;
+    let Some(queues) = ports.get_mut(&dst).and_then(|port| port.queues.as_mut()) else {
+        return false;
+    };
+    if !queues.push(priority, out_frame.to_vec(), in_port) {
+        ports.port(src).counters.drops.count(DropReason::QueueFull);
+    }
+    true
+}
+
+/// Send the frames waiting in the egress queues of the vport
+/// at addr which its egress rate limit allows now
+fn drain_queues(
+    addr: VportAddr,
+    ports: &mut PortTable<VportAddr>,
+    vports: &Vports,
+    mirrors: &mut Mirrors,
+) {
+    loop {
+        let Some(port) = ports.get_mut(&addr) else {
+            return;
+        };
+        let Some(queues) = &mut port.queues else {
+            return;
+        };
+        let tx_limit = &mut port.tx_limit;
+        let allows = |len| {
+            tx_limit
+                .as_mut()
+                .is_none_or(|tx_limit| tx_limit.admits(len))
+        };
+        let Some((frame, in_port)) = queues.pop(allows) else {
+            return;
+        };
+
+        if let Err(e) = vports.forward(&frame, &addr, in_port) {
+            eprintln!("Got error while sending queued frame: {}", e);
+            continue;
+        }
+        let out_port = count_tx(ports, addr, frame.len());
+        mirrors.sent(&addr, &frame, ports, vports);
+        log_frame!(
+            "Sent queued frame on port {} ('{}'), {}",
+            out_port,
+            addr,
+            VlanLogMsg(&frame)
+        );
+    }
+}
+
+/// Send an echo request to every vport which has sent a hello, and
+/// to every peer vswitch, as others (such as QEMU) would not answer it
+fn send_echo_requests(
+    vports: &Vports,
+    ports: &PortTable<VportAddr>,
+    peers: &[VportAddr],
+    start: Instant,
+) {
+    let timestamp = u64::try_from(start.elapsed().as_micros()).unwrap_or(u64::MAX);
+    let mut frame = ControlMsg::EchoRequest { timestamp }.encode();
+    frame.resize(ETHER_FRAME_MIN, 0);
+
+    for (addr, port) in ports.iter() {
+        if port.session_id.is_none() && !peers.contains(addr) {
+            continue;
+        }
+
+        /* A vport which can't be reached just has no round trip time recorded */
+        if let Err(e) = vports.send_to(&frame, addr) {
+            eprintln!("Got error while sending echo request to '{}': {}", addr, e);
+        }
+    }
+}
+
+/// Returns the UDP socket which systemd passed, if it passed one among
+/// fds (the rest are closed), which has to be bound to port
+fn activated_socket(fds: Vec<(String, OwnedFd)>, port: u16) -> Result<Option<UdpSocket>, String> {
+    let Some((name, fd)) = fds
+        .into_iter()
+        .find(|(_, fd)| getsockopt(fd, sockopt::SockType).is_ok_and(|ty| ty == SockType::Datagram))
+    else {
+        return Ok(None);
+    };
+
+    let socket = UdpSocket::from(fd);
+    match socket.local_addr() {
+        Ok(addr) if addr.port() == port => {
+            println!("Using the socket on {} which systemd passed", addr);
+            Ok(Some(socket))
+        }
+        Ok(addr) => Err(format!(
+            "systemd passed a socket on {}, rather than on port {}",
+            addr, port
+        )),
+        Err(e) => Err(format!(
+            "systemd passed '{}', which isn't a UDP socket: {}",
+            name, e
+        )),
+    }
+}
+
+/// Start the threads which receive frames from vports, and
+/// return the handles used to send frames back to them
+fn start_listeners(
+    socket: UdpSocket,
+    opts: &ListenerOpts,
+    rx_tx: &Sender<RxEvent>,
+) -> io::Result<Vports> {
+    let dtls = match &opts.dtls_psk_path {
+        Some(path) => Some(Arc::new(DtlsPorts::new(path).map_err(io::Error::other)?)),
+        None => None,
+    };
+    let auth = match &opts.auth_psk_path {
+        Some(path) => {
+            let psk = read_psk("--auth-psk-file", path).map_err(io::Error::other)?;
+            let mut sender_id = [0; 8];
+            File::open("/dev/urandom")?.read_exact(&mut sender_id)?;
+            Some(FrameAuth::new(
+                &psk,
+                u64::from_ne_bytes(sender_id),
+                SystemTime::now(),
+            ))
+        }
+        None => None,
+    };
+
+    let udp_socket = socket.try_clone()?;
+    let udp_tx = rx_tx.clone();
+    let (udp_dtls, udp_auth) = (dtls.clone(), auth.clone());
+    thread::spawn(move || udp_listener(udp_socket, udp_tx, udp_dtls, udp_auth, VportAddr::Udp));
+
+    let mut listen = Vec::new();
+    for (index, (addr, segment)) in opts.listen.iter().enumerate() {
+        let listen_socket = UdpSocket::bind(addr)?;
+        let udp_socket = listen_socket.try_clone()?;
+        let udp_tx = rx_tx.clone();
+        let (udp_dtls, udp_auth) = (dtls.clone(), auth.clone());
+        thread::spawn(move || {
+            udp_listener(udp_socket, udp_tx, udp_dtls, udp_auth, |src| {
+                VportAddr::Listen(index, src)
+            })
+        });
+        println!("Listening on {} for segment {}", addr, segment);
+
+        listen.push((listen_socket, *segment));
+    }
+
+    let vxlan = opts
+        .vxlan_addr
+        .map(|addr| start_vtep_listener(addr, "VXLAN", vxlan_frame, rx_tx))
+        .transpose()?;
+    let geneve = opts
+        .geneve_addr
+        .map(|addr| start_vtep_listener(addr, "GENEVE", geneve_frame, rx_tx))
+        .transpose()?;
+
+    #[cfg(feature = "vhost-user")]
+    let vhost_user = opts
+        .vhost_user_paths
+        .iter()
+        .enumerate()
+        .map(|(index, path)| {
+            let vhost_user_tx = rx_tx.clone();
+            VhostUserPort::spawn(path, move |frame| {
+                let _ = vhost_user_tx.send(RxEvent::Frame(
+                    VportAddr::VhostUser(index),
+                    frame.to_vec(),
+                    Instant::now(),
+                ));
+            })
+            .map_err(io::Error::from)
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    #[cfg(not(feature = "vhost-user"))]
+    if !opts.vhost_user_paths.is_empty() {
+        return Err(io::Error::other(
+            "vswitch was built without the vhost-user feature",
+        ));
+    }
+
+    let vsock = VsockStreams::default();
+    if let Some(vsock_port) = opts.vsock_port {
+        let listener = VsockListener::bind(vsock_port)?;
+        let vsock_streams = vsock.clone();
+        let vsock_tx = rx_tx.clone();
+        thread::spawn(move || vsock_listener(listener, vsock_streams, vsock_tx));
+        println!("Listening for vsock connections on port {}", vsock_port);
+    }
+
+    let tcp = TcpLinks::default();
+    if let Some(tcp_addr) = opts.tcp_addr {
+        let listener = TcpListener::bind(tcp_addr)?;
+        let tcp_links = tcp.clone();
+        let tcp_tx = rx_tx.clone();
+        thread::spawn(move || tcp_listener(listener, tcp_links, tcp_tx));
+        println!("Listening for TCP connections on {}", tcp_addr);
+    }
+
+    #[cfg(feature = "quic")]
+    let mut quic = None;
+    #[cfg(feature = "quic")]
+    if let Some(quic_addr) = opts.quic_addr {
+        let cert_path = opts.quic_cert_path.as_deref().unwrap_or_default();
+        let key_path = opts.quic_key_path.as_deref().unwrap_or_default();
+        let ports =
+            Arc::new(QuicPorts::new(quic_addr, cert_path, key_path).map_err(io::Error::other)?);
+        let quic_ports = ports.clone();
+        let quic_tx = rx_tx.clone();
+        thread::spawn(move || quic_ports.run(&quic_tx));
+        println!("Listening for QUIC connections on {}", quic_addr);
+        quic = Some(ports);
+    }
+    #[cfg(not(feature = "quic"))]
+    if opts.quic_addr.is_some() {
+        return Err(io::Error::other(
+            "vswitch was built without the quic feature",
+        ));
+    }
+
+    let mut unix = None;
+    if let Some(path) = &opts.unix_path {
+        /* Remove the socket left behind by a previous vswitch, if any */
+        let _ = fs::remove_file(path);
+        let unix_transport = Arc::new(UnixTransport::new(UnixDatagram::bind(path)?));
+
+        let listener_transport = unix_transport.clone();
+        let unix_tx = rx_tx.clone();
+        thread::spawn(move || unix_listener(&listener_transport, unix_tx));
+        println!("Listening for Unix datagrams on '{}'", path);
+
+        unix = Some(unix_transport);
+    }
+
+    let shm = ShmLinks::default();
+    if let Some(path) = &opts.shm_path {
+        /* Remove the socket left behind by a previous vswitch, if any */
+        let _ = fs::remove_file(path);
+        let listener = ShmListener::bind(path)?;
+        let shm_links = shm.clone();
+        let shm_tx = rx_tx.clone();
+        thread::spawn(move || shm_listener(listener, shm_links, shm_tx));
+        println!("Listening for shared memory vports on '{}'", path);
+    }
+
+    let markers = match opts.dscp {
+        Some(dscp) => {
+            println!("Marking the datagrams sent to vports with {}", dscp);
+            iter::once(&socket)
+                .chain(listen.iter().map(|(socket, _)| socket))
+                .chain(vxlan.iter().chain(geneve.iter()).map(|(socket, _)| socket))
+                .map(|socket| (socket.as_raw_fd(), Marker::new(dscp)))
+                .collect()
+        }
+        None => HashMap::new(),
+    };
+
+    Ok(Vports {
+        socket,
+        listen,
+        vxlan,
+        geneve,
+        dtls,
+        auth,
+        #[cfg(feature = "vhost-user")]
+        vhost_user,
+        vsock,
+        tcp,
+        #[cfg(feature = "quic")]
+        quic,
+        unix,
+        shm,
+        lags: Lags::default(),
+        fragment_sizes: HashMap::new(),
+        fragment_id: Cell::new(0),
+        markers,
+        vnis: HashMap::new(),
+        batches: RefCell::default(),
+        fec_encoders: RefCell::default(),
+    })
+}
+
+/// Receive frames from a UDP socket and pass them to the switching
+/// loop, as sent by the vport which vport_addr returns for their source.
+/// With DTLS on, the frames are opened by the vport's DTLS session, and
+/// with frame authentication on, those without a valid tag, or which
+/// have already been received from their sender, are dropped
+fn udp_listener(
+    socket: UdpSocket,
+    rx_tx: Sender<RxEvent>,
+    dtls: Option<Arc<DtlsPorts>>,
+    auth: Option<FrameAuth>,
+    vport_addr: impl Fn(SocketAddr) -> VportAddr,
+) {
+    /* Buffer to store received datagrams */
+    let mut buf: [u8; TUNNEL_DATAGRAM_MAX] = [0; TUNNEL_DATAGRAM_MAX];
+
+    loop {
+        let (no_of_bytes, src) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) => {
+                /* e.g. ICMP port unreachable for a frame sent to a vport which has gone */
+                let e = TransportError::from(e);
+                if e.is_transient() {
+                    eprintln!("Got error while receiving from UDP socket: {}", e);
+                    continue;
+                }
+                /* Stop, as the socket failed */
+                let _ = rx_tx.send(RxEvent::Error(e.into()));
+                return;
+            }
+        };
+
+        let received = Instant::now();
+        let vport = vport_addr(src);
+        let datagram = &buf[..no_of_bytes];
+        let frames = match (&dtls, &auth) {
+            (Some(dtls), _) => dtls.receive(&socket, src, vport, datagram),
+            (None, Some(auth)) => auth
+                .verify(datagram)
+                .map(<[u8]>::to_vec)
+                .into_iter()
+                .collect(),
+            (None, None) => vec![datagram.to_vec()],
+        };
+
+        for frame in frames {
+            /* Stop if the switching loop has gone */
+            if rx_tx.send(RxEvent::Frame(vport, frame, received)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Bind a socket to addr to exchange the datagrams of an encapsulation
+/// (named by kind) with VTEPs, receiving them with a thread which
+/// opens them with open, and return it with the port VTEPs receive on
+fn start_vtep_listener(
+    addr: SocketAddr,
+    kind: &'static str,
+    open: VtepOpener,
+    rx_tx: &Sender<RxEvent>,
+) -> io::Result<(UdpSocket, u16)> {
+    let socket = UdpSocket::bind(addr)?;
+    let listener_socket = socket.try_clone()?;
+    let vtep_tx = rx_tx.clone();
+    thread::spawn(move || vtep_listener(listener_socket, vtep_tx, kind, open));
+    println!("Listening for {} datagrams on {}", kind, addr);
+
+    /* VTEPs all receive on the port they send to */
+    Ok((socket, addr.port()))
+}
+
+/// Returns the VTEP at ip which sent a datagram, and the frame it carries
+type VtepOpener = fn(IpAddr, &[u8]) -> Option<(VportAddr, &[u8])>;
+
+/// Returns the VTEP at ip which sent the VXLAN datagram, and its frame,
+/// or None if it doesn't have a valid VNI
+fn vxlan_frame(ip: IpAddr, datagram: &[u8]) -> Option<(VportAddr, &[u8])> {
+    vxlan::decapsulate(datagram).map(|(vni, frame)| (VportAddr::Vxlan { vni, ip }, frame))
+}
+
+/// Returns the VTEP at ip which sent the GENEVE datagram, and its frame,
+/// or None if it can't be opened. The metadata in its options is
+/// only for the VTEPs, so isn't used
+fn geneve_frame(ip: IpAddr, datagram: &[u8]) -> Option<(VportAddr, &[u8])> {
+    geneve::decapsulate(datagram).map(|(vni, _, frame)| (VportAddr::Geneve { vni, ip }, frame))
+}
+
+/// Receive the datagrams of an encapsulation (named by kind) from
+/// VTEPs and pass the frames which open returns to the switching loop,
+/// as sent by the VTEP it returns. Datagrams it can't open are dropped
+fn vtep_listener(socket: UdpSocket, rx_tx: Sender<RxEvent>, kind: &str, open: VtepOpener) {
+    /* Buffer to store received datagrams, which may have GENEVE options */
+    let mut buf: [u8; TUNNEL_FRAME_MAX + GENEVE_HDR_MAX] = [0; TUNNEL_FRAME_MAX + GENEVE_HDR_MAX];
+
+    loop {
+        let (no_of_bytes, src) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) => {
+                let e = TransportError::from(e);
+                if e.is_transient() {
+                    eprintln!("Got error while receiving from {} socket: {}", kind, e);
+                    continue;
+                }
+                /* Stop, as the socket failed */
+                let _ = rx_tx.send(RxEvent::Error(e.into()));
+                return;
+            }
+        };
+
+        let Some((vport, frame)) = open(src.ip(), &buf[..no_of_bytes]) else {
+            continue;
+        };
+
+        /* Stop if the switching loop has gone */
+        if rx_tx
+            .send(RxEvent::Frame(vport, frame.to_vec(), Instant::now()))
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Accept vports connecting over vsock, and start
+/// a thread to receive frames from each of them
+fn vsock_listener(listener: VsockListener, streams: VsockStreams, rx_tx: Sender<RxEvent>) {
+    loop {
+        let stream = match listener.accept() {
+            Ok(stream) => stream,
+            Err(e) => {
+                let _ = rx_tx.send(RxEvent::Error(e.into()));
+                return;
+            }
+        };
+
+        /* Register the stream so frames can be sent back to the vport */
+        let registered = stream.peer_addr().and_then(|peer| {
+            streams
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(peer, stream.try_clone()?);
+            Ok(peer)
+        });
+        let peer = match registered {
+            Ok(peer) => peer,
+            Err(e) => {
+                eprintln!("Failed to register vsock connection: {}", e);
+                continue;
+            }
+        };
+
+        println!("vport connected over vsock from {}:{}", peer.0, peer.1);
+
+        let vsock_streams = streams.clone();
+        let vsock_tx = rx_tx.clone();
+        thread::spawn(move || vsock_receiver(stream, peer, vsock_streams, vsock_tx));
+    }
+}
+
+/// Receive frames from a vport connected over vsock and pass
+/// them to the switching loop, until the vport disconnects
+fn vsock_receiver(
+    stream: VsockStream,
+    (cid, port): (u32, u32),
+    streams: VsockStreams,
+    rx_tx: Sender<RxEvent>,
+) {
+    let mut buf: [u8; TUNNEL_FRAME_MAX] = [0; TUNNEL_FRAME_MAX];
+
+    loop {
+        match stream.recv_frame(&mut buf) {
+            Ok(no_of_bytes) => {
+                let src = VportAddr::Vsock { cid, port };
+                if rx_tx
+                    .send(RxEvent::Frame(
+                        src,
+                        buf[..no_of_bytes].to_vec(),
+                        Instant::now(),
+                    ))
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            Err(e) => {
+                /*
+                 * A vsock vport going away only affects that vport,
+                 * so unlike the UDP socket, it does not stop the vswitch
+                 */
+                if !matches!(e, TransportError::Closed) {
+                    eprintln!(
+                        "Got error while receiving from vsock:{}:{}: {}",
+                        cid, port, e
+                    );
+                }
+                println!("vport disconnected from vsock:{}:{}", cid, port);
+                streams
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .remove(&(cid, port));
+                let _ = rx_tx.send(RxEvent::Disconnected(VportAddr::Vsock { cid, port }));
+                return;
+            }
+        }
+    }
+}
+
+/// Accept vports connecting over TCP, and start
+/// a thread to receive frames from each of them
+fn tcp_listener(listener: TcpListener, links: TcpLinks, rx_tx: Sender<RxEvent>) {
+    loop {
+        let (stream, peer) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                let _ = rx_tx.send(RxEvent::Error(e));
+                return;
+            }
+        };
+
+        /* Register the link so frames can be sent back to the vport */
+        let registered = TcpLink::new(stream).and_then(|link| {
+            links
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(peer, link.try_clone()?);
+            Ok(link)
+        });
+        let link = match registered {
+            Ok(link) => link,
+            Err(e) => {
+                eprintln!("Failed to register TCP connection from {}: {}", peer, e);
+                continue;
+            }
+        };
+
+        println!("vport connected over TCP from {}", peer);
+
+        let tcp_links = links.clone();
+        let tcp_tx = rx_tx.clone();
+        thread::spawn(move || tcp_receiver(link, peer, tcp_links, tcp_tx));
+    }
+}
+
+/// Receive frames from a vport connected over TCP and pass
+/// them to the switching loop, until the vport disconnects
+fn tcp_receiver(link: TcpLink, peer: SocketAddr, links: TcpLinks, rx_tx: Sender<RxEvent>) {
+    let mut buf: [u8; TUNNEL_FRAME_MAX] = [0; TUNNEL_FRAME_MAX];
+
+    loop {
+        match link.recv_frame(&mut buf) {
+            Ok(no_of_bytes) => {
+                if rx_tx
+                    .send(RxEvent::Frame(
+                        VportAddr::Tcp(peer),
+                        buf[..no_of_bytes].to_vec(),
+                        Instant::now(),
+                    ))
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            Err(e) => {
+                /* As with vsock, one vport going away does not stop the vswitch */
+                if !matches!(e, TransportError::Closed) {
+                    eprintln!("Got error while receiving from tcp:{}: {}", peer, e);
+                }
+                println!("vport disconnected from tcp:{}", peer);
+                links
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .remove(&peer);
+                let _ = rx_tx.send(RxEvent::Disconnected(VportAddr::Tcp(peer)));
+                return;
+            }
+        }
+    }
+}
+
+/// Receive frames from the Unix datagram socket and pass them to the
+/// switching loop. The transport ignores frames from unbound sockets, as
+/// they can't be replied to, and remembers at most MAX_UNIX_PEERS vports
+fn unix_listener(transport: &UnixTransport, rx_tx: Sender<RxEvent>) {
+    let mut buf: [u8; TUNNEL_FRAME_MAX] = [0; TUNNEL_FRAME_MAX];
+
+    loop {
+        let (no_of_bytes, peer) = match transport.recv_from_peer(&mut buf) {
+            Ok(res) => res,
+            Err(e) => {
+                let _ = rx_tx.send(RxEvent::Error(e.into()));
+                return;
+            }
+        };
+
+        if rx_tx
+            .send(RxEvent::Frame(
+                VportAddr::Unix(peer),
+                buf[..no_of_bytes].to_vec(),
+                Instant::now(),
+            ))
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Accept vports connecting over shared memory, and start
+/// a thread to receive frames from each of them
+fn shm_listener(listener: ShmListener, links: ShmLinks, rx_tx: Sender<RxEvent>) {
+    for id in 0.. {
+        let pending = match listener.accept() {
+            Ok(pending) => pending,
+            Err(e) => {
+                let _ = rx_tx.send(RxEvent::Error(e.into()));
+                return;
+            }
+        };
+
+        /* The vport passes its rings on its own thread, so one which never does holds up no others */
+        let shm_links = links.clone();
+        let shm_tx = rx_tx.clone();
+        thread::spawn(move || {
+            let link = match pending.handshake() {
+                Ok(link) => link,
+                Err(e) => {
+                    eprintln!("Failed to accept shared memory vport: {}", e);
+                    return;
+                }
+            };
+
+            /* Register the link so frames can be sent back to the vport */
+            match link.try_clone() {
+                Ok(tx_link) => shm_links
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .insert(id, tx_link),
+                Err(e) => {
+                    eprintln!("Failed to register shared memory vport: {}", e);
+                    return;
+                }
+            };
+
+            println!("vport connected over shared memory as shm#{}", id);
+            shm_receiver(link, id, shm_links, shm_tx);
+        });
+    }
+}
+
+/// Receive frames from a vport connected over shared memory and
+/// pass them to the switching loop, until the vport disconnects
+fn shm_receiver(link: ShmLink, id: usize, links: ShmLinks, rx_tx: Sender<RxEvent>) {
+    let mut buf: [u8; TUNNEL_FRAME_MAX] = [0; TUNNEL_FRAME_MAX];
+
+    loop {
+        match link.recv_frame(&mut buf) {
+            Ok(no_of_bytes) => {
+                if rx_tx
+                    .send(RxEvent::Frame(
+                        VportAddr::Shm(id),
+                        buf[..no_of_bytes].to_vec(),
+                        Instant::now(),
+                    ))
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            Err(e) => {
+                if !matches!(e, TransportError::Closed) {
+                    eprintln!("Got error while receiving from shm#{}: {}", id, e);
+                }
+                println!("vport disconnected from shm#{}", id);
+                links
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .remove(&id);
+                let _ = rx_tx.send(RxEvent::Disconnected(VportAddr::Shm(id)));
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,186 @@
+//! Port mirroring for the vswitch
+//!
+//! An admin client can have the frames received from a port, sent to
+//! it, or both, copied to another port, as SPAN does on a hardware
+//! switch, so a capture host attached to the overlay through a vport
+//! can see another port's traffic with tcpdump
+//!
+//! Frames received are copied as they arrived, once any hop limit tag
+//! is removed, and frames sent are copied as they were sent to the
+//! port, but without a hop limit tag, or being compressed. The
+//! destination port is otherwise an ordinary port, so still carries
+//! its own traffic
+
+use crate::{ports::PortTable, VportAddr, Vports};
+use l2vpn::{
+    compression::{decompress, is_compressed},
+    tunnel::{hop_limit, pop_hop_limit},
+};
+use std::collections::HashMap;
+
+/// Which of a port's frames are mirrored
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Frames received from the port
+    Rx,
+    /// Frames sent to the port
+    Tx,
+    Both,
+}
+
+impl Direction {
+    /// Parse a direction, as given to the mirror command
+    pub fn parse(value: &str) -> Option<Direction> {
+        match value {
+            "rx" => Some(Direction::Rx),
+            "tx" => Some(Direction::Tx),
+            "both" => Some(Direction::Both),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the direction, as given to the mirror command
+    pub fn name(&self) -> &'static str {
+        match self {
+            Direction::Rx => "rx",
+            Direction::Tx => "tx",
+            Direction::Both => "both",
+        }
+    }
+}
+
+/// Where the frames of a mirrored port are copied to
+#[derive(Debug)]
+struct Mirror {
+    direction: Direction,
+    dst: VportAddr,
+    /// Frames copied to dst
+    frames: u64,
+}
+
+/// Ports whose frames are mirrored, keyed by the address of their vport
+#[derive(Debug, Default)]
+pub struct Mirrors {
+    mirrors: HashMap<VportAddr, Mirror>,
+}
+
+impl Mirrors {
+    /// Mirror the frames of the port at src in direction to the port at
+    /// dst, replacing any mirror the port already had, and returns true
+    /// if it did
+    pub fn add(&mut self, src: VportAddr, direction: Direction, dst: VportAddr) -> bool {
+        let mirror = Mirror {
+            direction,
+            dst,
+            frames: 0,
+        };
+        self.mirrors.insert(src, mirror).is_some()
+    }
+
+    /// Stop mirroring the port at src, returning false if it wasn't mirrored
+    pub fn remove(&mut self, src: &VportAddr) -> bool {
+        self.mirrors.remove(src).is_some()
+    }
+
+    /// Copy frame, received from the port at src_vport, to its mirror
+    /// destination if its received frames are mirrored
+    pub fn received(
+        &mut self,
+        src_vport: &VportAddr,
+        frame: &[u8],
+        ports: &PortTable<VportAddr>,
+        vports: &Vports,
+    ) {
+        self.copy(src_vport, Direction::Rx, frame, ports, vports);
+    }
+
+    /// Copy frame, as sent to the port at dst_vport, to its mirror
+    /// destination if its sent frames are mirrored
+    pub fn sent(
+        &mut self,
+        dst_vport: &VportAddr,
+        frame: &[u8],
+        ports: &PortTable<VportAddr>,
+        vports: &Vports,
+    ) {
+        self.copy(dst_vport, Direction::Tx, frame, ports, vports);
+    }
+
+    /// Returns the mirrored ports in human readable format
+    pub fn show(&self, ports: &PortTable<VportAddr>) -> String {
+        let port_id = |addr: &VportAddr| ports.get(addr).map_or(0, |port| port.id);
+
+        let mut mirrors: Vec<(u32, &VportAddr, &Mirror)> = self
+            .mirrors
+            .iter()
+            .map(|(src, mirror)| (port_id(src), src, mirror))
+            .collect();
+        mirrors.sort_by_key(|(id, _, _)| *id);
+
+        let mut lines = vec![format!(
+            "{:>5}  {:<24}  {:<9}  {:>11}  {:>10}",
+            "port", "vport", "direction", "destination", "frames"
+        )];
+        for (id, src, mirror) in mirrors {
+            lines.push(format!(
+                "{:>5}  {:<24}  {:<9}  {:>11}  {:>10}",
+                id,
+                src.to_string(),
+                mirror.direction.name(),
+                port_id(&mirror.dst),
+                mirror.frames
+            ));
+        }
+        lines.join("\n")
+    }
+
+    /// Copy frame, received from or sent to the port at addr as direction
+    /// says, to its mirror destination if its frames in that direction are
+    /// mirrored. Nothing is copied to destinations which are shut down
+    fn copy(
+        &mut self,
+        addr: &VportAddr,
+        direction: Direction,
+        frame: &[u8],
+        ports: &PortTable<VportAddr>,
+        vports: &Vports,
+    ) {
+        let Some(mirror) = self.mirrors.get_mut(addr) else {
+            return;
+        };
+        if (mirror.direction != direction && mirror.direction != Direction::Both)
+            || ports.is_shutdown(&mirror.dst)
+        {
+            return;
+        }
+
+        let decompressed;
+        let frame = match is_compressed(frame) {
+            true => {
+                let Some(frame) = decompress(frame) else {
+                    return;
+                };
+                decompressed = frame;
+                &decompressed[..]
+            }
+            false => frame,
+        };
+
+        let untagged;
+        let frame = match hop_limit(frame) {
+            Some(_) => {
+                let mut copy = frame.to_vec();
+                let len = pop_hop_limit(&mut copy, frame.len());
+                untagged = copy;
+                &untagged[..len]
+            }
+            None => frame,
+        };
+
+        /* A lost copy only leaves a gap in the capture */
+        match vports.send_to(frame, &mirror.dst) {
+            Ok(()) => mirror.frames += 1,
+            Err(e) => eprintln!("Got error while mirroring frame to '{}': {}", mirror.dst, e),
+        }
+    }
+}
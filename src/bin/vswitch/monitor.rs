@@ -0,0 +1,65 @@
+//! Live frame monitoring for admin clients
+//!
+//! An admin client which sends `monitor [filter]` is sent a one
+//! line summary of every frame the switching loop handles which
+//! matches the filter, until the client disconnects
+
+use crate::VportAddr;
+use l2vpn::{filter::Filter, utilities::get_frame_log_msg};
+use std::{
+    sync::mpsc::Sender,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Admin clients which are monitoring frames
+#[derive(Default)]
+pub struct Monitors {
+    monitors: Vec<(Filter, Sender<String>)>,
+}
+
+impl Monitors {
+    /// Start sending summaries of the frames matching filter to tx
+    pub fn add(&mut self, filter: Filter, tx: Sender<String>) {
+        self.monitors.push((filter, tx));
+    }
+
+    /// Returns true if no admin clients are monitoring frames
+    pub fn is_empty(&self) -> bool {
+        self.monitors.is_empty()
+    }
+
+    /// Send a summary of frame, which was received from src_vport
+    /// on the port with ID in_port, to every client whose filter
+    /// matches, where outcome describes what the vswitch did with it
+    pub fn frame(&mut self, frame: &[u8], in_port: u32, src_vport: &VportAddr, outcome: &str) {
+        /* Don't build the summary unless someone is going to see it */
+        if !self
+            .monitors
+            .iter()
+            .any(|(filter, _)| filter.matches(frame, in_port))
+        {
+            return;
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let secs = now.as_secs() % 86400;
+        let summary = format!(
+            "{:02}:{:02}:{:02}.{:03} port {} ({}) {} -> {}",
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60,
+            now.subsec_millis(),
+            in_port,
+            src_vport,
+            get_frame_log_msg(frame, frame.len()),
+            outcome
+        );
+
+        /* Forget clients which have disconnected */
+        self.monitors.retain(|(filter, tx)| {
+            !filter.matches(frame, in_port) || tx.send(summary.clone()).is_ok()
+        });
+    }
+}
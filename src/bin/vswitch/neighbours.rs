@@ -0,0 +1,145 @@
+//! ARP snooping for vports doing ARP suppression
+//!
+//! The vswitch learns the MAC of each IPv4 address from the ARP requests
+//! and replies it forwards in each broadcast domain, and tells the vports
+//! which agreed to ARP suppression when they joined, so they can answer
+//! their hosts' requests for those addresses without sending them across
+//! the WAN (see l2vpn::arp). A vport is told the whole table of the
+//! domain it sends untagged frames in when it joins, then each address
+//! as it is learned or changes MAC
+//!
+//! Addresses which haven't been learned again for ARP_CACHE_AGE are
+//! forgotten, as vports forget them, and those which still are
+//! learned are told to the vports again before they would forget them
+
+use crate::{ports::PortTable, vlan::Domain, vlan::PortVlan, VportAddr, Vports};
+use l2vpn::{
+    arp::{Arp, ARP_CACHE_AGE},
+    control::{ControlMsg, CAP_ARP_SUPPRESSION, MAX_NEIGHBOURS},
+    mac::MacAddr,
+    utilities::ETHER_FRAME_MIN,
+};
+use std::{collections::HashMap, net::Ipv4Addr, time::Instant};
+
+/// Most addresses learned in each domain, so a flood of ARP can't exhaust memory
+const MAX_NEIGHBOURS_PER_DOMAIN: usize = 8192;
+
+/// Address learned from ARP in a domain
+#[derive(Debug)]
+struct Neighbour {
+    mac: MacAddr,
+    learned: Instant,
+    /* When vports were last told the address */
+    told: Instant,
+}
+
+/// MACs of the IPv4 addresses in each domain
+#[derive(Debug, Default)]
+pub struct Neighbours {
+    domains: HashMap<Domain, HashMap<Ipv4Addr, Neighbour>>,
+}
+
+impl Neighbours {
+    /// Learn the sender's address from frame, which is in domain, if it
+    /// carries ARP, returning it if vports need to be told it, as it is
+    /// new, has changed MAC, or they would soon forget it
+    pub fn snoop(
+        &mut self,
+        domain: Domain,
+        frame: &[u8],
+        now: Instant,
+    ) -> Option<(Ipv4Addr, MacAddr)> {
+        let (ip, mac) = Arp::parse(frame)?.binding()?;
+        let neighbours = self.domains.entry(domain).or_default();
+        if neighbours.len() >= MAX_NEIGHBOURS_PER_DOMAIN && !neighbours.contains_key(&ip) {
+            neighbours.retain(|_, neighbour| now.duration_since(neighbour.learned) < ARP_CACHE_AGE);
+            if neighbours.len() >= MAX_NEIGHBOURS_PER_DOMAIN {
+                return None;
+            }
+        }
+
+        match neighbours.get_mut(&ip) {
+            Some(neighbour)
+                if neighbour.mac == mac
+                    && now.duration_since(neighbour.told) < ARP_CACHE_AGE / 2 =>
+            {
+                neighbour.learned = now;
+                None
+            }
+            _ => {
+                neighbours.insert(
+                    ip,
+                    Neighbour {
+                        mac,
+                        learned: now,
+                        told: now,
+                    },
+                );
+                Some((ip, mac))
+            }
+        }
+    }
+
+    /// Returns the addresses learned in domain which haven't been forgotten
+    pub fn entries(&self, domain: Domain, now: Instant) -> Vec<(Ipv4Addr, MacAddr)> {
+        self.domains
+            .get(&domain)
+            .map_or_else(Vec::new, |neighbours| {
+                neighbours
+                    .iter()
+                    .filter(|(_, neighbour)| now.duration_since(neighbour.learned) < ARP_CACHE_AGE)
+                    .map(|(ip, neighbour)| (*ip, neighbour.mac))
+                    .collect()
+            })
+    }
+}
+
+/// Returns the domain which the port at addr, which carries vlan, sends
+/// and receives untagged frames in, whose addresses its vport is told
+pub fn untagged_domain(vports: &Vports, addr: &VportAddr, vlan: &PortVlan) -> Domain {
+    Domain {
+        segment: vports.segment(addr),
+        vlan: match vlan {
+            PortVlan::Trunk { native, .. } => *native,
+            vlan => vlan.untagged_vlan(),
+        },
+    }
+}
+
+/// Tell the vport at addr the addresses in entries
+pub fn tell(vports: &Vports, addr: &VportAddr, entries: &[(Ipv4Addr, MacAddr)]) {
+    for chunk in entries.chunks(MAX_NEIGHBOURS) {
+        let mut frame = ControlMsg::Neighbours {
+            entries: chunk.to_vec(),
+        }
+        .encode();
+        frame.resize(frame.len().max(ETHER_FRAME_MIN), 0);
+
+        /* A vport which misses an address just asks for it across the WAN */
+        if let Err(e) = vports.send_to(&frame, addr) {
+            eprintln!("Got error while sending neighbours to '{}': {}", addr, e);
+        }
+    }
+}
+
+/// Tell the vports which agreed to ARP suppression and send untagged
+/// frames in domain the address in entry, apart from the one at except
+pub fn tell_domain(
+    vports: &Vports,
+    ports: &PortTable<VportAddr>,
+    domain: Domain,
+    entry: (Ipv4Addr, MacAddr),
+    except: &VportAddr,
+) {
+    let told = ports.iter().filter(|(addr, port)| {
+        *addr != except
+            && !port.down
+            && port
+                .registration
+                .is_some_and(|r| r.capabilities & CAP_ARP_SUPPRESSION != 0)
+            && untagged_domain(vports, addr, &port.vlan) == domain
+    });
+    for (addr, _) in told {
+        tell(vports, addr, &[entry]);
+    }
+}
@@ -0,0 +1,180 @@
+//! Control plane policing for the vswitch
+//!
+//! Frames meant for the vswitch itself (hellos, echo replies, and
+//! link-local protocols such as STP and LLDP) and admin commands are
+//! rate limited per source, separately from data frames. This stops a
+//! buggy or malicious vport or admin client from starving the
+//! switching loop, while leaving everyone else's control traffic alone
+//!
+//! Storm control limits the rate of the frames each port floods
+//! (broadcasts, and multicasts and unknown unicasts while flooding is
+//! on) in the same way, so one noisy host can't flood the whole overlay
+//!
+//! Ports can also be given rate limits, in frames and bits per second,
+//! on the data frames received from them and sent to them, so one chatty
+//! host can't saturate the vswitch's socket for everyone else
+
+use l2vpn::mac::MacAddr;
+use std::{fmt, time::Instant};
+
+/// Control frames each port can send per second, once its burst is used up
+pub const CONTROL_FRAMES_PER_SEC: f64 = 20.0;
+
+/// Control frames each port can send at once
+pub const CONTROL_FRAMES_BURST: f64 = 40.0;
+
+/// Commands each admin client can send per second, once its burst is used up
+pub const ADMIN_COMMANDS_PER_SEC: f64 = 10.0;
+
+/// Commands each admin client can send at once, which allows for
+/// the bursts of completions sent by an interactive console
+pub const ADMIN_COMMANDS_BURST: f64 = 50.0;
+
+/// Token bucket which limits the rate of some kind of traffic
+#[derive(Clone, Debug)]
+pub struct Policer {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Policer {
+    /// Returns a policer allowing rate per second on average,
+    /// and up to burst at once, which starts with a full burst
+    pub fn new(rate: f64, burst: f64) -> Policer {
+        Policer {
+            rate,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    /// Returns the average rate the policer allows per second
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// Returns true if one more unit of traffic is allowed now
+    pub fn allow(&mut self) -> bool {
+        if !self.has(1.0) {
+            return false;
+        }
+        self.take(1.0);
+        true
+    }
+
+    /// Returns true if amount more traffic is allowed now, without using it up
+    pub fn has(&mut self, amount: f64) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last);
+        self.last = now;
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.burst);
+        self.tokens >= amount
+    }
+
+    /// Use up amount of the traffic allowed, which has been checked for
+    pub fn take(&mut self, amount: f64) {
+        self.tokens -= amount;
+    }
+}
+
+/// Frames and bits per second which a port can receive or be sent
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    pub pps: Option<u64>,
+    pub bps: Option<u64>,
+}
+
+impl fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rates: Vec<String> = [(self.pps, "pps"), (self.bps, "bps")]
+            .iter()
+            .filter_map(|(rate, unit)| rate.map(|rate| format!("{} {}", rate, unit)))
+            .collect();
+        write!(f, "{}", rates.join(", "))
+    }
+}
+
+/// Rate limit of one direction of a port, with a second's worth of
+/// frames and bits allowed at once, and the frames dropped beyond it
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    limit: RateLimit,
+    frames: Option<Policer>,
+    bits: Option<Policer>,
+    dropped: u64,
+}
+
+impl RateLimiter {
+    /// Returns a rate limiter which allows limit
+    pub fn new(limit: RateLimit) -> RateLimiter {
+        let policer = |rate: u64| Policer::new(rate as f64, rate as f64);
+        RateLimiter {
+            limit,
+            frames: limit.pps.map(policer),
+            bits: limit.bps.map(policer),
+            dropped: 0,
+        }
+    }
+
+    /// Returns the rates the limiter allows
+    pub fn limit(&self) -> RateLimit {
+        self.limit
+    }
+
+    /// Returns the frames which have been dropped for going over the limit
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Returns true if one more frame of len bytes is allowed now,
+    /// which is only counted against the limit if it is allowed, and
+    /// is counted as dropped otherwise
+    pub fn allow(&mut self, len: usize) -> bool {
+        if !self.admits(len) {
+            self.dropped += 1;
+            return false;
+        }
+        true
+    }
+
+    /// Returns true if one more frame of len bytes is allowed now, which
+    /// is only counted against the limit if it is allowed, for frames which
+    /// can wait until they are allowed, rather than being dropped
+    pub fn admits(&mut self, len: usize) -> bool {
+        let bits = (len * 8) as f64;
+        let allowed = self.frames.as_mut().is_none_or(|frames| frames.has(1.0))
+            && self.bits.as_mut().is_none_or(|policer| policer.has(bits));
+        if !allowed {
+            return false;
+        }
+        if let Some(frames) = &mut self.frames {
+            frames.take(1.0);
+        }
+        if let Some(policer) = &mut self.bits {
+            policer.take(bits);
+        }
+        true
+    }
+}
+
+/// Returns true if the storm control policer of a port allows it to flood
+/// one more frame, where rate is the flooded frames allowed per second, with
+/// a second's worth at once. The policer is replaced if rate has changed
+pub fn storm_allows(policer: &mut Option<Policer>, rate: u32) -> bool {
+    let rate = f64::from(rate);
+    if policer.as_ref().map(Policer::rate) != Some(rate) {
+        *policer = Some(Policer::new(rate, rate));
+    }
+    policer.as_mut().is_some_and(Policer::allow)
+}
+
+/// Returns true if dst_mac is one of the IEEE 802.1 reserved
+/// link-local MACs (01:80:c2:00:00:00 to 01:80:c2:00:00:0f),
+/// which are used by STP, LLDP and other control protocols
+pub fn is_link_local(dst_mac: &MacAddr) -> bool {
+    let [a, b, c, d, e, f] = dst_mac.0;
+    [a, b, c, d, e] == [0x01, 0x80, 0xC2, 0x00, 0x00] && f & 0xF0 == 0
+}
@@ -0,0 +1,84 @@
+//! Port security for the vswitch
+//!
+//! A vport sending frames from a stream of made-up source MACs would
+//! otherwise fill the MAC tables, pushing out the real MACs, so each
+//! vport can be limited to a number of learned MACs. A frame whose
+//! source MAC would take its port over the limit is a violation, which
+//! is dropped, only logged, or has the port shut down, as configured
+//!
+//! Peer vswitches carry the MACs of everyone behind them, so are never
+//! limited, and static MACs don't count towards the limit
+
+use crate::{MacTables, VportAddr};
+use l2vpn::mac::MacAddr;
+use std::{collections::HashSet, fmt};
+
+/// What happens when a frame would take its port over the MAC limit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MacLimitAction {
+    /// The frame is dropped, and its source MAC isn't learned
+    Drop,
+    /// The frame is forwarded and its source MAC learned anyway,
+    /// but the violation is recorded
+    Log,
+    /// The port is shut down, as an admin client could
+    Shutdown,
+}
+
+impl MacLimitAction {
+    /// Parse the value of --mac-limit-action
+    pub fn parse(value: &str) -> Option<MacLimitAction> {
+        match value {
+            "drop" => Some(MacLimitAction::Drop),
+            "log" => Some(MacLimitAction::Log),
+            "shutdown" => Some(MacLimitAction::Shutdown),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the action, as given to --mac-limit-action
+    pub fn name(&self) -> &'static str {
+        match self {
+            MacLimitAction::Drop => "drop",
+            MacLimitAction::Log => "log",
+            MacLimitAction::Shutdown => "shutdown",
+        }
+    }
+}
+
+/// Most MACs which can be learned on each vport, and what happens beyond that
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MacLimit {
+    pub max: usize,
+    pub action: MacLimitAction,
+}
+
+impl fmt::Display for MacLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} per port, then {}", self.max, self.action.name())
+    }
+}
+
+/// Returns true if learning mac on the vport at addr would take it
+/// over limit, i.e. mac isn't learned there yet, and the vport
+/// already has as many MACs learned as the limit allows
+pub fn violates(
+    limit: &MacLimit,
+    mac_tables: &MacTables,
+    static_macs: &HashSet<MacAddr>,
+    addr: &VportAddr,
+    mac: &MacAddr,
+) -> bool {
+    let mut count = 0;
+    for (learned_mac, _) in mac_tables
+        .values()
+        .flat_map(|mac_table| mac_table.iter())
+        .filter(|(learned_mac, vport)| *vport == addr && !static_macs.contains(*learned_mac))
+    {
+        if learned_mac == mac {
+            return false;
+        }
+        count += 1;
+    }
+    count >= limit.max
+}
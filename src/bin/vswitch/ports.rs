@@ -0,0 +1,631 @@
+//! Port table for the vswitch
+//!
+//! Every vport is given a port ID and counters when it is first
+//! seen. vports identify themselves with a session ID, and the
+//! ports (along with the MACs learned on them) can be saved to a
+//! state file. After the vswitch restarts, returning vports then
+//! resume their previous port ID and counters, and their MACs are
+//! known before they next transmit, rather than them appearing as
+//! brand-new endpoints
+
+use crate::{
+    accounting::Usage,
+    drops::DropCounters,
+    latency::PortLatency,
+    policer::{Policer, RateLimiter, CONTROL_FRAMES_BURST, CONTROL_FRAMES_PER_SEC},
+    qos::EgressQueues,
+    sizes::FrameSizes,
+    vlan::{parse_vlan_id, Domain, PortVlan},
+};
+use l2vpn::{mac::MacAddr, stp::PortState, stun::PublicEndpoint, switching::MacTable};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs,
+    hash::Hash,
+    io::{self, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// Frame and byte counts for traffic received from and sent to a port
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PortCounters {
+    pub rx_frames: u64,
+    pub rx_bytes: u64,
+    pub tx_frames: u64,
+    pub tx_bytes: u64,
+    /// Frames received from the port which were dropped, by reason
+    pub drops: DropCounters,
+}
+
+impl PortCounters {
+    /// Add the counts in other to these
+    pub fn add(&mut self, other: &PortCounters) {
+        self.rx_frames += other.rx_frames;
+        self.rx_bytes += other.rx_bytes;
+        self.tx_frames += other.tx_frames;
+        self.tx_bytes += other.tx_bytes;
+        self.drops.add(&other.drops);
+    }
+}
+
+/// State the vswitch keeps for each vport
+#[derive(Debug)]
+pub struct Port {
+    pub id: u32,
+    pub session_id: Option<u64>,
+    /// Secret sent in the session's hellos, which a hello from another
+    /// address must carry to move the session there (0 if there is none)
+    pub token: u64,
+    pub counters: PortCounters,
+    /// When a frame was last received from the vport
+    pub last_seen: Instant,
+    /// Forwarding latency and round trip time histograms
+    pub latency: PortLatency,
+    /// Sizes of the frames received from the vport
+    pub sizes: FrameSizes,
+    /// True if the vport sends hop limit tags, so is sent them too
+    pub tunnel: bool,
+    /// Limits the rate of control frames from the vport
+    pub control_policer: Policer,
+    /// True if the vport has stopped being heard from, so its MACs were flushed
+    pub down: bool,
+    /// True if an admin client has shut the port down, so frames
+    /// are neither received from nor sent to the vport
+    pub shutdown: bool,
+    /// Traffic since the port came up, and against its quota
+    pub usage: Usage,
+    /// When the vport last said it has joined the vswitch's BUM group
+    pub group_member_seen: Option<Instant>,
+    /// Whether the port is a trunk, or an access port in one VLAN
+    pub vlan: PortVlan,
+    /// Whether STP lets the port forward, or learn from, frames.
+    /// Ports forward when STP is off
+    pub stp: PortState,
+    /// True if the port has gone over the MAC limit since it last
+    /// learned a MAC, so further violations aren't recorded again
+    pub over_mac_limit: bool,
+    /// Limits the rate of frames flooded from the vport while storm
+    /// control is on, created when it is first needed
+    pub storm_policer: Option<Policer>,
+    /// Split-horizon group the port is in, if any, whose other
+    /// ports are never sent the frames received from it
+    pub split_horizon: Option<u32>,
+    /// Limits the rate of the data frames received from the vport
+    pub rx_limit: Option<RateLimiter>,
+    /// Limits the rate of the data frames sent to the vport
+    pub tx_limit: Option<RateLimiter>,
+    /// Queues which the frames sent to the vport wait in, by priority,
+    /// while it is over its egress rate limit
+    pub queues: Option<EgressQueues>,
+    /// MTU which the vport's hellos carry, if it isn't the vswitch's,
+    /// in which case the frames received from it are dropped
+    pub mtu_mismatch: Option<usize>,
+    /// What the vport said when it joined, if it has
+    pub registration: Option<Registration>,
+}
+
+/// What a vport said about itself when it joined the vswitch
+#[derive(Clone, Copy, Debug)]
+pub struct Registration {
+    /// Capabilities the vport has, as in l2vpn::control
+    pub capabilities: u32,
+    /// VLAN the vport asked to be in, if any
+    pub requested_vlan: Option<u16>,
+    /// VLAN the port was made an access port in at the vport's
+    /// request, which it is moved out of if it asks for another
+    pub granted_vlan: Option<u16>,
+    /// True if the vport asked for frames to be compressed, and we agreed
+    pub compressing: bool,
+    /// Public endpoint and NAT type which the vport found with STUN, if it did
+    pub public_endpoint: Option<PublicEndpoint>,
+    /// When the vport (last) joined
+    pub joined: Instant,
+}
+
+/// Port saved in the state file whose vport has not returned yet
+#[derive(Debug)]
+struct SavedSession {
+    id: u32,
+    token: u64,
+    counters: PortCounters,
+    /// MACs learned on the port, and the VLAN each was learned in
+    macs: Vec<(MacAddr, Option<u16>)>,
+}
+
+/// Ports known to the vswitch, keyed by the address of their vport
+#[derive(Debug)]
+pub struct PortTable<A> {
+    ports: HashMap<A, Port>,
+    saved: HashMap<u64, SavedSession>,
+    next_id: u32,
+    dirty: bool,
+}
+
+impl<A> Default for PortTable<A> {
+    fn default() -> Self {
+        PortTable {
+            ports: HashMap::new(),
+            saved: HashMap::new(),
+            next_id: 1,
+            dirty: false,
+        }
+    }
+}
+
+impl<A: Copy + Eq + Hash> PortTable<A> {
+    /// Returns the port for the vport at addr, creating it if required
+    pub fn port(&mut self, addr: A) -> &mut Port {
+        self.dirty = true;
+        self.ports.entry(addr).or_insert_with(|| {
+            let id = self.next_id;
+            self.next_id += 1;
+            Port {
+                id,
+                session_id: None,
+                token: 0,
+                counters: PortCounters::default(),
+                last_seen: Instant::now(),
+                latency: PortLatency::default(),
+                sizes: FrameSizes::default(),
+                tunnel: false,
+                control_policer: Policer::new(CONTROL_FRAMES_PER_SEC, CONTROL_FRAMES_BURST),
+                down: false,
+                shutdown: false,
+                usage: Usage::new(&PortCounters::default()),
+                group_member_seen: None,
+                vlan: PortVlan::default(),
+                stp: PortState::Forwarding,
+                over_mac_limit: false,
+                storm_policer: None,
+                split_horizon: None,
+                rx_limit: None,
+                tx_limit: None,
+                queues: None,
+                mtu_mismatch: None,
+                registration: None,
+            }
+        })
+    }
+
+    /// Returns the address of the vport with the given port ID, if it is connected
+    pub fn find(&self, id: u32) -> Option<A> {
+        self.ports
+            .iter()
+            .find(|(_, port)| port.id == id)
+            .map(|(addr, _)| *addr)
+    }
+
+    /// Returns the port for the vport at addr, if it has been seen
+    pub fn get(&self, addr: &A) -> Option<&Port> {
+        self.ports.get(addr)
+    }
+
+    /// Returns the port for the vport at addr, if it has been seen,
+    /// without creating it
+    pub fn get_mut(&mut self, addr: &A) -> Option<&mut Port> {
+        self.ports.get_mut(addr)
+    }
+
+    /// Returns true if the vport at addr has been shut down by an admin client
+    pub fn is_shutdown(&self, addr: &A) -> bool {
+        self.ports.get(addr).is_some_and(|port| port.shutdown)
+    }
+
+    /// Returns true if STP is blocking the vport at addr, so frames are
+    /// neither received from nor sent to it
+    pub fn is_blocked(&self, addr: &A) -> bool {
+        self.ports
+            .get(addr)
+            .is_some_and(|port| !port.stp.forwards())
+    }
+
+    /// Returns true if the vports at src and dst are in the same
+    /// split-horizon group, so frames from src are never sent to dst
+    pub fn same_split_horizon(&self, src: &A, dst: &A) -> bool {
+        let group = |addr: &A| self.ports.get(addr).and_then(|port| port.split_horizon);
+        group(src).is_some_and(|src_group| group(dst) == Some(src_group))
+    }
+
+    /// Returns the addresses of the access ports and QinQ tunnel ports in vlan
+    pub fn access_ports(&self, vlan: u16) -> impl Iterator<Item = &A> {
+        self.ports
+            .iter()
+            .filter(move |(_, port)| port.vlan.untagged_vlan() == Some(vlan))
+            .map(|(addr, _)| addr)
+    }
+
+    /// Returns the connected vports and their ports, in port ID order
+    pub fn iter(&self) -> impl Iterator<Item = (&A, &Port)> {
+        let mut ports: Vec<(&A, &Port)> = self.ports.iter().collect();
+        ports.sort_by_key(|(_, port)| port.id);
+        ports.into_iter()
+    }
+
+    /// Mark the ports which should be heard from regularly (those with a
+    /// session, as their vports send hellos, and the peers, which answer
+    /// echo requests) as down if they have not been heard from for timeout
+    ///
+    /// Returns the addresses of the ports which have just gone down
+    pub fn expire(&mut self, timeout: Duration, peers: &[A]) -> Vec<A> {
+        let mut expired = Vec::new();
+
+        for (addr, port) in self.ports.iter_mut() {
+            let monitored = port.session_id.is_some() || peers.contains(addr);
+            if monitored && !port.down && port.last_seen.elapsed() >= timeout {
+                port.down = true;
+                expired.push(*addr);
+            }
+        }
+
+        expired
+    }
+
+    /// Returns true if the ports have changed since they were last saved
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Associate the vport at addr with session_id
+    ///
+    /// If the session belongs to a port saved before the vswitch
+    /// restarted, or to a port which was previously at another
+    /// address, the vport takes over that port's ID and counters,
+    /// and its MACs are pointed at addr in the MAC tables (those
+    /// saved are restored to the VLANs of segment). This only
+    /// happens if token matches the one the session was first seen
+    /// with, so a vport which roams to a new address (e.g. after NAT
+    /// rebinding) keeps its session, but nobody else can take it over
+    ///
+    /// Returns a description of what happened, if the port changed
+    pub fn hello(
+        &mut self,
+        addr: A,
+        session_id: u64,
+        token: u64,
+        mac_tables: &mut HashMap<Domain, MacTable<A>>,
+        segment: u32,
+    ) -> Option<String> {
+        if self.port(addr).session_id == Some(session_id) {
+            return None;
+        }
+
+        /* The session's vport has moved, e.g. after it was restarted */
+        let old_addr = self
+            .ports
+            .iter()
+            .find(|(a, p)| **a != addr && p.session_id == Some(session_id))
+            .map(|(a, _)| *a);
+        if let Some(old_addr) = old_addr {
+            if !token_matches(self.ports[&old_addr].token, token) {
+                return Some(wrong_token(session_id));
+            }
+
+            let mut port = self.ports.remove(&old_addr).unwrap();
+            if let Some(new_port) = self.ports.remove(&addr) {
+                port.counters.add(&new_port.counters);
+                port.sizes.add(&new_port.sizes);
+                port.last_seen = new_port.last_seen;
+            }
+            self.ports.insert(addr, port);
+
+            for port_addr in mac_tables.values_mut().flat_map(|table| table.values_mut()) {
+                if *port_addr == old_addr {
+                    *port_addr = addr;
+                }
+            }
+
+            return Some(format!(
+                "Session {:016x} moved to a new address",
+                session_id
+            ));
+        }
+
+        if let Some(saved) = self.saved.get(&session_id) {
+            if !token_matches(saved.token, token) {
+                return Some(wrong_token(session_id));
+            }
+        }
+
+        let port = self.ports.get_mut(&addr).unwrap();
+        port.session_id = Some(session_id);
+        port.token = token;
+
+        /* The session was saved before the vswitch restarted */
+        if let Some(saved) = self.saved.remove(&session_id) {
+            port.id = saved.id;
+            port.counters.add(&saved.counters);
+            port.usage.exclude(&saved.counters);
+            for (mac, vlan) in saved.macs {
+                let domain = Domain { segment, vlan };
+                mac_tables
+                    .entry(domain)
+                    .or_default()
+                    .entry(mac)
+                    .or_insert(addr);
+            }
+
+            return Some(format!(
+                "Resumed session {:016x} as port {}",
+                session_id, saved.id
+            ));
+        }
+
+        None
+    }
+
+    /// Returns true if a leave message for session_id carrying token,
+    /// from the vport at addr, is for the session of its port, so the
+    /// port can be taken down
+    pub fn leaves(&self, addr: &A, session_id: u64, token: u64) -> bool {
+        self.ports.get(addr).is_some_and(|port| {
+            port.session_id == Some(session_id) && token_matches(port.token, token)
+        })
+    }
+
+    /// Register the vport at addr as having joined with capabilities,
+    /// asking for its frames to be in requested_vlan. If vlan_requests
+    /// is true, its port is made an access port in that VLAN (or a trunk
+    /// again, if it no longer asks for one), unless an admin client has
+    /// given the port a VLAN mode of its own. If compressing is true,
+    /// the frames sent to the vport are compressed
+    ///
+    /// Returns None if the port isn't in session_id, as the join was
+    /// refused as a hello would be, or else whether its VLAN mode changed
+    pub fn join(
+        &mut self,
+        addr: A,
+        session_id: u64,
+        capabilities: u32,
+        requested_vlan: Option<u16>,
+        vlan_requests: bool,
+        compressing: bool,
+    ) -> Option<bool> {
+        let port = self
+            .ports
+            .get_mut(&addr)
+            .filter(|port| port.session_id == Some(session_id))?;
+
+        /* Only the mode the port was given at a vport's request is replaced */
+        let granted = port.registration.and_then(|r| r.granted_vlan);
+        let ours = port.vlan == PortVlan::default()
+            || granted.is_some_and(|vid| port.vlan == PortVlan::Access(vid));
+        let granted_vlan = requested_vlan.filter(|_| vlan_requests && ours);
+        let changed =
+            ours && port.vlan != granted_vlan.map_or_else(PortVlan::default, PortVlan::Access);
+        if changed {
+            port.vlan = granted_vlan.map_or_else(PortVlan::default, PortVlan::Access);
+        }
+
+        port.registration = Some(Registration {
+            capabilities,
+            requested_vlan,
+            granted_vlan,
+            compressing,
+            public_endpoint: None,
+            joined: Instant::now(),
+        });
+        self.dirty = true;
+        Some(changed)
+    }
+
+    /// Returns the address of the vport in session_id, if it is connected
+    /// and token is the session's, so the vport at link can join its port
+    /// as a further link. A vport with a session of its own can't, and
+    /// nor can the sessions of vports too old to send a token be joined
+    pub fn lag_port(&self, link: A, session_id: u64, token: u64) -> Option<A> {
+        if self
+            .ports
+            .get(&link)
+            .is_some_and(|port| port.session_id.is_some())
+        {
+            return None;
+        }
+        self.ports
+            .iter()
+            .find(|(addr, port)| {
+                **addr != link
+                    && port.session_id == Some(session_id)
+                    && port.token != 0
+                    && port.token == token
+            })
+            .map(|(addr, _)| *addr)
+    }
+
+    /// Fold any port which the vport at link was given, before it joined
+    /// the port at addr as a further link, into that port, pointing the
+    /// MACs learned on it at addr
+    pub fn absorb_link(&mut self, link: A, addr: A, mac_tables: &mut HashMap<Domain, MacTable<A>>) {
+        let Some(link_port) = self.ports.remove(&link) else {
+            return;
+        };
+        self.dirty = true;
+        let port = self.port(addr);
+        port.counters.add(&link_port.counters);
+        port.sizes.add(&link_port.sizes);
+
+        for port_addr in mac_tables.values_mut().flat_map(|table| table.values_mut()) {
+            if *port_addr == link {
+                *port_addr = addr;
+            }
+        }
+    }
+
+    /// Load the ports saved in the state file at path, returning
+    /// an empty table if no state has been saved yet
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<PortTable<A>, Box<dyn Error>> {
+        let mut table = PortTable::default();
+
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(table),
+            Err(e) => return Err(e.into()),
+        };
+
+        for (line_no, line) in contents.lines().enumerate() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let parsed = match fields.as_slice() {
+                [] | ["#", ..] => Ok(()),
+                ["session", session_id, id, counters @ .., token] if counters.len() == 4 => {
+                    parse_session(&mut table, session_id, id, counters, Some(token))
+                }
+                ["session", session_id, id, counters @ ..] if counters.len() == 4 => {
+                    parse_session(&mut table, session_id, id, counters, None)
+                }
+                ["mac", session_id, mac] => parse_mac(&mut table, session_id, mac, None),
+                ["mac", session_id, mac, vlan] => {
+                    parse_mac(&mut table, session_id, mac, Some(vlan))
+                }
+                _ => Err("unrecognised line".into()),
+            };
+
+            if let Err(e) = parsed {
+                return Err(format!("State file line {}: {}", line_no + 1, e).into());
+            }
+        }
+
+        table.next_id = table.saved.values().map(|s| s.id + 1).max().unwrap_or(1);
+
+        Ok(table)
+    }
+
+    /// Save every port with a session (including those whose vports
+    /// have not returned since the last restart) and their MACs from
+    /// the MAC table of each segment and VLAN to the state file at path
+    pub fn save<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        mac_tables: &HashMap<Domain, MacTable<A>>,
+    ) -> io::Result<()> {
+        let mut contents = String::from("# l2vpn vswitch state, written automatically\n");
+
+        for (addr, port) in self.ports.iter() {
+            let Some(session_id) = port.session_id else {
+                continue;
+            };
+
+            contents += &session_line(session_id, port.id, port.token, &port.counters);
+            let macs = mac_tables.iter().flat_map(|(domain, mac_table)| {
+                mac_table.iter().map(move |entry| (domain.vlan, entry))
+            });
+            for (vlan, (mac, _)) in macs.filter(|(_, (_, a))| *a == addr) {
+                contents += &mac_line(session_id, mac, vlan);
+            }
+        }
+
+        for (session_id, saved) in self.saved.iter() {
+            contents += &session_line(*session_id, saved.id, saved.token, &saved.counters);
+            for (mac, vlan) in saved.macs.iter() {
+                contents += &mac_line(*session_id, mac, *vlan);
+            }
+        }
+
+        /* Write to a temporary file first, so a crash can't leave a half-written state file */
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+/// Returns true if a hello carrying token may take over a
+/// session which was first seen with session_token
+///
+/// Sessions of vports too old to send a token can't be authenticated,
+/// so they move wherever their session ID is heard from, as they did
+/// before tokens were added
+fn token_matches(session_token: u64, token: u64) -> bool {
+    session_token == 0 || session_token == token
+}
+
+/// Returns the event recorded when a hello carrying the wrong token is ignored
+fn wrong_token(session_id: u64) -> String {
+    format!(
+        "Ignored hello for session {:016x} from a new address, as its token was wrong",
+        session_id
+    )
+}
+
+/// Returns the state file line describing a session's port
+fn session_line(session_id: u64, id: u32, token: u64, counters: &PortCounters) -> String {
+    format!(
+        "session {:016x} {} {} {} {} {} {:016x}\n",
+        session_id,
+        id,
+        counters.rx_frames,
+        counters.rx_bytes,
+        counters.tx_frames,
+        counters.tx_bytes,
+        token
+    )
+}
+
+/// Returns the state file line describing a MAC learned on a session's
+/// port, which is followed by its VLAN, if it was learned in one
+fn mac_line(session_id: u64, mac: &MacAddr, vlan: Option<u16>) -> String {
+    match vlan {
+        Some(vlan) => format!("mac {:016x} {} {}\n", session_id, mac, vlan),
+        None => format!("mac {:016x} {}\n", session_id, mac),
+    }
+}
+
+/// Parse a session line from the state file into table, where
+/// state files saved before tokens were added have no token
+fn parse_session<A>(
+    table: &mut PortTable<A>,
+    session_id: &str,
+    id: &str,
+    counters: &[&str],
+    token: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let counters = counters
+        .iter()
+        .map(|c| c.parse::<u64>())
+        .collect::<Result<Vec<u64>, _>>()?;
+
+    table.saved.insert(
+        u64::from_str_radix(session_id, 16)?,
+        SavedSession {
+            id: id.parse()?,
+            token: token.map_or(Ok(0), |token| u64::from_str_radix(token, 16))?,
+            counters: PortCounters {
+                rx_frames: counters[0],
+                rx_bytes: counters[1],
+                tx_frames: counters[2],
+                tx_bytes: counters[3],
+                drops: DropCounters::default(),
+            },
+            macs: Vec::new(),
+        },
+    );
+
+    Ok(())
+}
+
+/// Parse a MAC line from the state file into table, where
+/// MACs which weren't learned in a VLAN have no VLAN ID
+fn parse_mac<A>(
+    table: &mut PortTable<A>,
+    session_id: &str,
+    mac: &str,
+    vlan: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let session_id = u64::from_str_radix(session_id, 16)?;
+    let mac = mac
+        .parse::<MacAddr>()
+        .map_err(|_| format!("invalid MAC '{}'", mac))?;
+    let vlan = vlan.map(parse_vlan_id).transpose()?;
+
+    match table.saved.get_mut(&session_id) {
+        Some(saved) => saved.macs.push((mac, vlan)),
+        None => return Err(format!("MAC for unknown session {:016x}", session_id).into()),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,194 @@
+//! Priority-aware egress queueing for the vswitch
+//!
+//! Ports can be given egress queues, one for each 802.1p priority, which
+//! the frames sent to them wait in until the port's egress rate limit
+//! allows them to be sent, so latency-sensitive traffic, such as VoIP,
+//! isn't stuck behind bulk transfers. Frames take the priority in the PCP
+//! field of their VLAN tag, or 0 if they are untagged
+//!
+//! The queues are served in strict priority order, where a frame is only
+//! sent while no frame of a higher priority is waiting, or by weighted
+//! round robin, where each priority in turn is sent up to its weight of
+//! frames, so the lower priorities aren't starved. As in 802.1Q, priority
+//! 1 (background) ranks below priority 0 (best effort)
+//!
+//! Frames only wait while the port is over its egress rate limit, so the
+//! queues of a port without one are emptied as soon as a frame is queued
+
+use std::{collections::VecDeque, fmt};
+
+/// Frames which can wait in each queue, beyond which frames are dropped
+pub const QUEUE_DEPTH: usize = 128;
+
+/// Number of 802.1p priorities, which each have their own queue
+const PRIORITIES: usize = 8;
+
+/// Priorities from the highest ranked to the lowest
+const BY_RANK: [usize; PRIORITIES] = [7, 6, 5, 4, 3, 2, 0, 1];
+
+/// Order in which the queues of a port are served
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scheduler {
+    /// Highest priority first
+    Strict,
+    /// Each priority in turn, up to its weight of frames each
+    Weighted([u32; PRIORITIES]),
+}
+
+impl Scheduler {
+    /// Parse the arguments of the qos command, i.e. "strict", or "weighted"
+    /// followed by the weights of priorities 0 to 7, such as 2,1,3,4,5,6,7,8
+    /// (which are the weights by default, i.e. each priority's rank)
+    pub fn parse(args: &[&str]) -> Result<Scheduler, String> {
+        match args {
+            ["strict"] => Ok(Scheduler::Strict),
+            ["weighted"] => {
+                let mut weights = [0; PRIORITIES];
+                for (rank, priority) in BY_RANK.iter().rev().enumerate() {
+                    weights[*priority] = rank as u32 + 1;
+                }
+                Ok(Scheduler::Weighted(weights))
+            }
+            ["weighted", weights] => {
+                let invalid = || {
+                    format!(
+                        "Expected the weights of priorities 0 to 7, such as 2,1,3,4,5,6,7,8, not '{}'",
+                        weights
+                    )
+                };
+                let weights: Vec<u32> = weights
+                    .split(',')
+                    .map(|weight| weight.parse::<u32>().ok().filter(|weight| *weight > 0))
+                    .collect::<Option<Vec<u32>>>()
+                    .ok_or_else(invalid)?;
+                Ok(Scheduler::Weighted(
+                    weights.try_into().map_err(|_| invalid())?,
+                ))
+            }
+            _ => Err("Expected 'strict' or 'weighted [<weights>]'".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Scheduler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Scheduler::Strict => write!(f, "strict priority"),
+            Scheduler::Weighted(weights) => {
+                let weights: Vec<String> = weights.iter().map(u32::to_string).collect();
+                write!(f, "weighted round robin ({})", weights.join(","))
+            }
+        }
+    }
+}
+
+/// Egress queues of a port, and the frames sent from and dropped by each
+#[derive(Debug)]
+pub struct EgressQueues {
+    scheduler: Scheduler,
+    /// Frames waiting to be sent, and the IDs of the ports they were
+    /// received on, by priority
+    queues: [VecDeque<(Vec<u8>, u32)>; PRIORITIES],
+    /// Rank of the priority weighted round robin is serving,
+    /// and the frames it has been sent in this turn
+    turn: (usize, u32),
+    sent: [u64; PRIORITIES],
+    dropped: [u64; PRIORITIES],
+}
+
+impl EgressQueues {
+    /// Returns empty queues served by scheduler
+    pub fn new(scheduler: Scheduler) -> EgressQueues {
+        EgressQueues {
+            scheduler,
+            queues: Default::default(),
+            turn: (0, 0),
+            sent: [0; PRIORITIES],
+            dropped: [0; PRIORITIES],
+        }
+    }
+
+    /// Returns the order the queues are served in
+    pub fn scheduler(&self) -> Scheduler {
+        self.scheduler
+    }
+
+    /// Serve the queues in the order of scheduler from now on
+    pub fn set_scheduler(&mut self, scheduler: Scheduler) {
+        self.scheduler = scheduler;
+        self.turn = (0, 0);
+    }
+
+    /// Returns the number of frames waiting
+    pub fn len(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+
+    /// Returns true if no frame is waiting
+    pub fn is_empty(&self) -> bool {
+        self.queues.iter().all(VecDeque::is_empty)
+    }
+
+    /// Queue frame, which has the given priority and was received on the
+    /// port with ID in_port, returning false (and dropping it) if its
+    /// queue is full
+    pub fn push(&mut self, priority: u8, frame: Vec<u8>, in_port: u32) -> bool {
+        let priority = usize::from(priority) % PRIORITIES;
+        if self.queues[priority].len() >= QUEUE_DEPTH {
+            self.dropped[priority] += 1;
+            return false;
+        }
+        self.queues[priority].push_back((frame, in_port));
+        true
+    }
+
+    /// Take the frame which is sent next, and the ID of the port it was
+    /// received on, if allows, given its length, says it can be sent now
+    pub fn pop(&mut self, allows: impl FnOnce(usize) -> bool) -> Option<(Vec<u8>, u32)> {
+        let priority = self.next()?;
+        if !allows(self.queues[priority].front()?.0.len()) {
+            return None;
+        }
+        self.turn.1 += 1;
+        self.sent[priority] += 1;
+        self.queues[priority].pop_front()
+    }
+
+    /// Returns the frames waiting in, sent from and dropped by each
+    /// queue, from the highest ranked priority to the lowest
+    pub fn stats(&self) -> impl Iterator<Item = (usize, usize, u64, u64)> + '_ {
+        BY_RANK.iter().map(|priority| {
+            (
+                *priority,
+                self.queues[*priority].len(),
+                self.sent[*priority],
+                self.dropped[*priority],
+            )
+        })
+    }
+
+    /// Returns the priority whose queue is served next, if any frame is waiting
+    fn next(&mut self) -> Option<usize> {
+        let weights = match self.scheduler {
+            Scheduler::Strict => {
+                return BY_RANK
+                    .into_iter()
+                    .find(|priority| !self.queues[*priority].is_empty())
+            }
+            Scheduler::Weighted(weights) => weights,
+        };
+        if self.is_empty() {
+            return None;
+        }
+
+        /* Move on to the next priority with frames waiting once this one has had its turn */
+        loop {
+            let (rank, sent) = self.turn;
+            let priority = BY_RANK[rank];
+            if !self.queues[priority].is_empty() && sent < weights[priority] {
+                return Some(priority);
+            }
+            self.turn = ((rank + 1) % PRIORITIES, 0);
+        }
+    }
+}
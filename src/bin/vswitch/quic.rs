@@ -0,0 +1,73 @@
+//! QUIC connections between the vswitch and vports
+//!
+//! Given a QUIC address, the vswitch accepts connections there from
+//! vports, presenting its certificate, and each connection is a port
+//! of its own, which keeps its number when the vport it comes from
+//! moves to another address. The connections' endpoint is driven by a
+//! thread of its own, which passes their frames to the switching loop
+
+use crate::RxEvent;
+use crate::VportAddr;
+use l2vpn::error::TransportError;
+use l2vpn::quic::{server_config, QuicEndpoint, QuicEvent};
+use std::{io, net::UdpSocket, time::Instant};
+use std::{net::SocketAddr, sync::mpsc::Sender};
+
+/// Connections with the vports which reach the vswitch over QUIC
+#[derive(Debug)]
+pub struct QuicPorts {
+    endpoint: QuicEndpoint,
+}
+
+impl QuicPorts {
+    /// Returns the endpoint accepting connections on addr, presenting the
+    /// certificate in cert_path, whose private key is in key_path
+    pub fn new(addr: SocketAddr, cert_path: &str, key_path: &str) -> Result<QuicPorts, String> {
+        let config =
+            server_config(cert_path, key_path).map_err(|e| format!("Could not set up {}", e))?;
+        let sock = UdpSocket::bind(addr)
+            .map_err(|e| format!("Could not bind QUIC address {}: {}", addr, e))?;
+        Ok(QuicPorts {
+            endpoint: QuicEndpoint::server(sock, config),
+        })
+    }
+
+    /// Send frame over the connection numbered id. If it has
+    /// closed, there is nowhere to send the frame
+    pub fn send(&self, id: usize, frame: &[u8]) -> Result<(), TransportError> {
+        match self.endpoint.send_frame(id, frame) {
+            Err(TransportError::Closed) => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Accept connections and pass the frames received over them
+    /// to the switching loop, until the socket fails
+    pub fn run(&self, rx_tx: &Sender<RxEvent>) {
+        let mut events = Vec::new();
+        loop {
+            if let Err(e) = self.endpoint.poll(&mut events) {
+                let _ = rx_tx.send(RxEvent::Error(io::Error::other(e)));
+                return;
+            }
+            for event in events.drain(..) {
+                let rx_event = match event {
+                    QuicEvent::Connected(id, addr) => {
+                        println!("vport connected over QUIC from {} as quic#{}", addr, id);
+                        continue;
+                    }
+                    QuicEvent::Frame(id, frame) => {
+                        RxEvent::Frame(VportAddr::Quic(id), frame, Instant::now())
+                    }
+                    QuicEvent::Closed(id) => {
+                        println!("vport disconnected from quic#{}", id);
+                        RxEvent::Disconnected(VportAddr::Quic(id))
+                    }
+                };
+                if rx_tx.send(rx_event).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
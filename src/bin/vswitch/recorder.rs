@@ -0,0 +1,317 @@
+//! Flight recorder for the vswitch
+//!
+//! The recorder keeps the frames most recently received from each
+//! port in memory, within a time and size limit per port, so that
+//! what led up to a problem can be looked at after it happens. The
+//! frames can be written to a pcap file by an admin client, and are
+//! written automatically when an alert fires, i.e. when a port stops
+//! being heard from, disconnects or goes over its quota, or a frame's
+//! TTL expires, which suggests a loop
+
+use crate::events::EventLog;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// How long after an automatic dump of a port another is skipped,
+/// so a flapping port or a loop doesn't fill the disk with dumps
+const ALERT_DUMP_HOLDOFF: Duration = Duration::from_secs(60);
+
+/// pcap link type of Ethernet frames
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// Problem which has the frames of a port dumped when it happens
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Alert {
+    /// The port stopped being heard from
+    Timeout,
+    /// The port's vport disconnected
+    Disconnected,
+    /// The port went over its quota, and was shut down
+    Quota,
+    /// A frame from the port had its TTL expire, so is probably looping
+    TtlExpired,
+}
+
+impl Alert {
+    /// Returns the name of the alert used in the names of dump files
+    pub fn name(&self) -> &'static str {
+        match self {
+            Alert::Timeout => "timeout",
+            Alert::Disconnected => "disconnected",
+            Alert::Quota => "quota",
+            Alert::TtlExpired => "ttl-expired",
+        }
+    }
+}
+
+/// Describes the alert, following "after it", e.g. "after it went over its quota"
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Alert::Timeout => "stopped being heard from",
+            Alert::Disconnected => "disconnected",
+            Alert::Quota => "went over its quota",
+            Alert::TtlExpired => "sent a frame whose TTL expired",
+        })
+    }
+}
+
+/// Limits on the frames the flight recorder keeps for each port
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecorderConfig {
+    /// Frames received longer ago than this are forgotten
+    pub max_age: Option<Duration>,
+    /// Oldest frames are forgotten when more bytes than this are kept
+    pub max_bytes: Option<usize>,
+    /// Bytes of each frame kept, so only headers can be kept
+    pub snaplen: usize,
+}
+
+impl RecorderConfig {
+    /// Parse the value of --flight-recorder, a comma separated
+    /// list of seconds=<secs>, megabytes=<MB> and snaplen=<bytes>,
+    /// with at least one of seconds and megabytes
+    pub fn parse(value: &str) -> Result<RecorderConfig, String> {
+        let mut config = RecorderConfig {
+            max_age: None,
+            max_bytes: None,
+            snaplen: usize::from(u16::MAX),
+        };
+
+        for limit in value.split(',') {
+            let Some((name, value)) = limit.split_once('=') else {
+                return Err(format!(
+                    "Expected <limit>=<value> in --flight-recorder, got '{}'",
+                    limit
+                ));
+            };
+            let number = value
+                .parse::<f64>()
+                .ok()
+                .filter(|number| number.is_finite() && *number > 0.0)
+                .ok_or_else(|| {
+                    format!("Expected a positive number for {}, got '{}'", name, value)
+                })?;
+
+            match name {
+                "seconds" => config.max_age = Some(Duration::from_secs_f64(number)),
+                "megabytes" => config.max_bytes = Some((number * 1_000_000.0) as usize),
+                "snaplen" => config.snaplen = (number as usize).max(1),
+                _ => {
+                    return Err(format!(
+                        "Unknown limit '{}' in --flight-recorder, expected \
+                         seconds, megabytes or snaplen",
+                        name
+                    ))
+                }
+            }
+        }
+
+        if config.max_age.is_none() && config.max_bytes.is_none() {
+            return Err("--flight-recorder needs a limit of seconds or megabytes".to_string());
+        }
+        Ok(config)
+    }
+}
+
+/// Frame kept by the flight recorder
+#[derive(Debug)]
+struct Recorded {
+    /// When the frame was received, for the pcap file
+    time: SystemTime,
+    /// When the frame was received, to age it out
+    received: Instant,
+    /// Length of the frame, which may be more than was kept
+    len: usize,
+    data: Vec<u8>,
+}
+
+/// Frames kept for one port, oldest first
+#[derive(Debug, Default)]
+struct Ring {
+    frames: VecDeque<Recorded>,
+    bytes: usize,
+    /// When the port's frames were last dumped because of an alert
+    last_alert_dump: Option<Instant>,
+}
+
+/// Keeps the most recent frames received from each port
+#[derive(Debug)]
+pub struct FlightRecorder {
+    config: RecorderConfig,
+    /// Directory which frames are dumped to when an alert fires
+    dir: PathBuf,
+    /// Frames kept for each port, keyed by port ID
+    rings: HashMap<u32, Ring>,
+}
+
+impl FlightRecorder {
+    /// Returns a recorder keeping frames within the limits in config,
+    /// which dumps them to files in dir when an alert fires
+    pub fn new(config: RecorderConfig, dir: PathBuf) -> FlightRecorder {
+        FlightRecorder {
+            config,
+            dir,
+            rings: HashMap::new(),
+        }
+    }
+
+    /// Keep frame, which was received from the port with the given
+    /// ID, forgetting the port's frames which are now beyond the limits
+    pub fn record(&mut self, port_id: u32, frame: &[u8]) {
+        let now = Instant::now();
+        let data = frame[..frame.len().min(self.config.snaplen)].to_vec();
+        let ring = self.rings.entry(port_id).or_default();
+        ring.bytes += data.len();
+        ring.frames.push_back(Recorded {
+            time: SystemTime::now(),
+            received: now,
+            len: frame.len(),
+            data,
+        });
+
+        while let Some(oldest) = ring.frames.front() {
+            let too_old = self
+                .config
+                .max_age
+                .is_some_and(|max_age| now.duration_since(oldest.received) > max_age);
+            let too_big = self
+                .config
+                .max_bytes
+                .is_some_and(|max_bytes| ring.bytes > max_bytes);
+            if !too_old && !too_big {
+                break;
+            }
+            ring.bytes -= oldest.data.len();
+            ring.frames.pop_front();
+        }
+    }
+
+    /// Returns true if frames have been kept for the port with the given ID
+    pub fn has_port(&self, port_id: u32) -> bool {
+        self.rings.contains_key(&port_id)
+    }
+
+    /// Write the frames kept for the given port (or for every port,
+    /// if None) to a pcap file at path, oldest first, returning how
+    /// many frames were written
+    pub fn dump(&self, port_id: Option<u32>, path: &Path) -> io::Result<usize> {
+        let mut frames: Vec<&Recorded> = self
+            .rings
+            .iter()
+            .filter(|(id, _)| port_id.is_none_or(|port_id| **id == port_id))
+            .flat_map(|(_, ring)| ring.frames.iter())
+            .collect();
+        frames.sort_by_key(|frame| frame.time);
+
+        let mut file = BufWriter::new(File::create(path)?);
+        let snaplen = u32::try_from(self.config.snaplen).unwrap_or(u32::MAX);
+        file.write_all(&0xA1B2_C3D4u32.to_le_bytes())?;
+        file.write_all(&2u16.to_le_bytes())?;
+        file.write_all(&4u16.to_le_bytes())?;
+        file.write_all(&[0u8; 8])?;
+        file.write_all(&snaplen.to_le_bytes())?;
+        file.write_all(&LINKTYPE_ETHERNET.to_le_bytes())?;
+
+        for frame in frames.iter() {
+            let since_epoch = frame.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            file.write_all(&(since_epoch.as_secs() as u32).to_le_bytes())?;
+            file.write_all(&since_epoch.subsec_micros().to_le_bytes())?;
+            file.write_all(&(frame.data.len() as u32).to_le_bytes())?;
+            file.write_all(&(frame.len as u32).to_le_bytes())?;
+            file.write_all(&frame.data)?;
+        }
+        file.flush()?;
+
+        Ok(frames.len())
+    }
+
+    /// An alert has fired for the port with the given ID, so dump its
+    /// frames to a file named after it, the time and the alert, unless
+    /// they were dumped for an alert less than ALERT_DUMP_HOLDOFF ago
+    pub fn alert(&mut self, port_id: u32, alert: Alert, events: &mut EventLog) {
+        let Some(ring) = self.rings.get_mut(&port_id) else {
+            return;
+        };
+        if ring.frames.is_empty()
+            || ring
+                .last_alert_dump
+                .is_some_and(|last| last.elapsed() < ALERT_DUMP_HOLDOFF)
+        {
+            return;
+        }
+        ring.last_alert_dump = Some(Instant::now());
+
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let path = self.dir.join(format!(
+            "flight-port{}-{}-{}.pcap",
+            port_id,
+            secs,
+            alert.name()
+        ));
+        match self.dump(Some(port_id), &path) {
+            Ok(frames) => events.record(format!(
+                "Dumped {} frame(s) from port {} to '{}' after it {}",
+                frames,
+                port_id,
+                path.display(),
+                alert
+            )),
+            Err(e) => eprintln!(
+                "Got error while dumping frames to '{}': {}",
+                path.display(),
+                e
+            ),
+        }
+    }
+
+    /// Returns the limits and the frames kept for each port in human readable format
+    pub fn show(&self) -> String {
+        let max_age = match self.config.max_age {
+            Some(max_age) => format!("{:.1}s", max_age.as_secs_f64()),
+            None => "no age limit".to_string(),
+        };
+        let max_bytes = match self.config.max_bytes {
+            Some(max_bytes) => format!("{} bytes", max_bytes),
+            None => "no size limit".to_string(),
+        };
+        let mut lines = vec![
+            format!(
+                "Limits per port: {}, {}, snaplen {}",
+                max_age, max_bytes, self.config.snaplen
+            ),
+            format!("Alert dumps go to '{}'", self.dir.display()),
+            format!(
+                "{:<8} {:>10} {:>12} {:>10}",
+                "Port", "Frames", "Bytes", "Span (s)"
+            ),
+        ];
+
+        let mut ids: Vec<&u32> = self.rings.keys().collect();
+        ids.sort();
+        for id in ids {
+            let ring = &self.rings[id];
+            let span = match (ring.frames.front(), ring.frames.back()) {
+                (Some(oldest), Some(newest)) => newest.received.duration_since(oldest.received),
+                _ => Duration::ZERO,
+            };
+            lines.push(format!(
+                "{:<8} {:>10} {:>12} {:>10.1}",
+                id,
+                ring.frames.len(),
+                ring.bytes,
+                span.as_secs_f64()
+            ));
+        }
+        lines.join("\n")
+    }
+}
@@ -0,0 +1,421 @@
+//! mDNS and SSDP reflector for the vswitch
+//!
+//! Segments (and VLANs within them) are separate networks, so devices
+//! which are found through multicast discovery, such as printers and
+//! Chromecasts, can't be found from any other segment. The reflector
+//! copies the mDNS and SSDP multicasts received in one of a chosen set
+//! of segments or VLANs to all the others, retagging them for each
+//!
+//! Reflection can be limited to chosen services, e.g. `_ipp._tcp` or
+//! `urn:dial-multiscreen-org:service:dial:1`. mDNS messages are then
+//! reflected if they name one of the services, or a host which one of
+//! the services was announced on, and SSDP messages are reflected if
+//! their NT, ST or USN header names one of the services
+//!
+//! Only multicasts are reflected, so devices must answer discovery
+//! with multicasts (as mDNS responders normally do), and segments
+//! must share an IP subnet, or be routed, for the devices to be used
+
+use crate::vlan::{parse_vlan_id, retag, Domain};
+use l2vpn::frame::EthernetFrame;
+use std::{
+    collections::HashSet,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+/// Most hosts remembered as offering a reflected service, after
+/// which they are forgotten, and learned again from new announcements
+const MAX_SERVICE_HOSTS: usize = 1024;
+
+/// Most compression pointers followed in a name of an mDNS message
+const MAX_NAME_POINTERS: usize = 16;
+
+/// DNS resource record types whose data holds a name
+const DNS_TYPE_PTR: u16 = 12;
+const DNS_TYPE_SRV: u16 = 33;
+
+const IPV4_ETHER_TYPE: u16 = 0x0800;
+const IPV6_ETHER_TYPE: u16 = 0x86DD;
+const UDP_PROTOCOL: u8 = 17;
+const IPV6_HDR: usize = 40;
+const UDP_HDR: usize = 8;
+
+/// Discovery protocol which the reflector can copy between segments
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Mdns,
+    Ssdp,
+}
+
+impl Protocol {
+    /// Returns the UDP port the protocol's multicasts are sent to
+    fn port(&self) -> u16 {
+        match self {
+            Protocol::Mdns => 5353,
+            Protocol::Ssdp => 1900,
+        }
+    }
+
+    /// Returns true if addr is the IPv4 or link-local IPv6
+    /// group which the protocol's multicasts are sent to
+    fn is_group(&self, addr: &IpAddr) -> bool {
+        let (ipv4, ipv6) = match self {
+            Protocol::Mdns => (
+                Ipv4Addr::new(224, 0, 0, 251),
+                Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb),
+            ),
+            Protocol::Ssdp => (
+                Ipv4Addr::new(239, 255, 255, 250),
+                Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xc),
+            ),
+        };
+        match addr {
+            IpAddr::V4(addr) => *addr == ipv4,
+            IpAddr::V6(addr) => *addr == ipv6,
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Protocol::Mdns => "mdns",
+            Protocol::Ssdp => "ssdp",
+        })
+    }
+}
+
+/// Protocol to reflect between a set of segments or VLANs
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReflectRule {
+    pub protocol: Protocol,
+    pub endpoints: Vec<Domain>,
+}
+
+impl ReflectRule {
+    /// Parse the value of --reflect, which is mdns|ssdp=<endpoint>,<endpoint>[,...],
+    /// where each endpoint is a segment, or <segment>/<vlan> for a VLAN in it
+    pub fn parse(value: &str) -> Result<ReflectRule, String> {
+        let Some((protocol, endpoints)) = value.split_once('=') else {
+            return Err(format!(
+                "Expected 'mdns|ssdp=<segment>[/<vlan>],...' for --reflect, got '{}'",
+                value
+            ));
+        };
+
+        let protocol = match protocol {
+            "mdns" => Protocol::Mdns,
+            "ssdp" => Protocol::Ssdp,
+            _ => {
+                return Err(format!(
+                    "Unknown protocol '{}' in --reflect, expected mdns or ssdp",
+                    protocol
+                ))
+            }
+        };
+
+        let mut rule = ReflectRule {
+            protocol,
+            endpoints: Vec::new(),
+        };
+        for endpoint in endpoints.split(',') {
+            let (segment, vlan) = match endpoint.split_once('/') {
+                Some((segment, vlan)) => (segment, Some(vlan)),
+                None => (endpoint, None),
+            };
+            let segment = segment
+                .parse::<u32>()
+                .map_err(|e| format!("Could not parse '{}' as segment: {}", segment, e))?;
+            let vlan = vlan.map(parse_vlan_id).transpose()?;
+
+            let endpoint = Domain { segment, vlan };
+            if rule.endpoints.contains(&endpoint) {
+                return Err(format!("'{}' given more than once in --reflect", endpoint));
+            }
+            rule.endpoints.push(endpoint);
+        }
+
+        if rule.endpoints.len() < 2 {
+            return Err(format!(
+                "--reflect '{}' needs at least two segments or VLANs to reflect between",
+                value
+            ));
+        }
+        Ok(rule)
+    }
+}
+
+impl fmt::Display for ReflectRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let endpoints: Vec<String> = self.endpoints.iter().map(Domain::to_string).collect();
+        write!(f, "{}={}", self.protocol, endpoints.join(","))
+    }
+}
+
+/// Counts of the frames each rule has matched
+#[derive(Debug, Default)]
+struct RuleCounts {
+    reflected: u64,
+    filtered: u64,
+}
+
+/// Copies discovery multicasts between the segments and VLANs of its rules
+#[derive(Debug)]
+pub struct Reflector {
+    rules: Vec<(ReflectRule, RuleCounts)>,
+    /// Services to reflect, in lower case, or empty to reflect every service
+    services: Vec<String>,
+    /// Hosts which a service to be reflected has been announced on
+    service_hosts: HashSet<String>,
+}
+
+impl Reflector {
+    /// Returns a reflector for rules, which only reflects the
+    /// given services, or every service if there are none
+    pub fn new(rules: Vec<ReflectRule>, services: &[String]) -> Reflector {
+        Reflector {
+            rules: rules
+                .into_iter()
+                .map(|rule| (rule, RuleCounts::default()))
+                .collect(),
+            services: services.iter().map(|name| name.to_lowercase()).collect(),
+            service_hosts: HashSet::new(),
+        }
+    }
+
+    /// Returns the copies of frame, received in the domain from, which are to
+    /// be sent to other segments or VLANs, each retagged for the VLAN it is sent to
+    pub fn reflect(&mut self, from: Domain, frame: &[u8]) -> Vec<(Domain, Vec<u8>)> {
+        let mut copies = Vec::new();
+        let Some((protocol, payload)) = discovery_payload(frame) else {
+            return copies;
+        };
+
+        /* The message is only checked against the services once, when a rule needs it */
+        let mut wanted = None;
+        for (rule, counts) in self.rules.iter_mut() {
+            if rule.protocol != protocol || !rule.endpoints.contains(&from) {
+                continue;
+            }
+
+            let wanted = *wanted.get_or_insert_with(|| {
+                wanted_service(protocol, payload, &self.services, &mut self.service_hosts)
+            });
+            if !wanted {
+                counts.filtered += 1;
+                continue;
+            }
+
+            for to in rule.endpoints.iter().filter(|to| **to != from) {
+                if !copies.iter().any(|(other, _)| other == to) {
+                    copies.push((*to, retag(frame, to.vlan)));
+                }
+            }
+            counts.reflected += 1;
+        }
+
+        copies
+    }
+
+    /// Returns the rules, services and frames reflected in human readable format
+    pub fn show(&self) -> String {
+        let mut lines = vec![match self.services.is_empty() {
+            true => "Services: all".to_string(),
+            false => format!("Services: {}", self.services.join(", ")),
+        }];
+        lines.extend(self.rules.iter().map(|(rule, counts)| {
+            format!(
+                "{:<32} reflected {:>10}  filtered {:>10}",
+                rule.to_string(),
+                counts.reflected,
+                counts.filtered
+            )
+        }));
+        lines.push(format!(
+            "Hosts offering reflected services: {}",
+            self.service_hosts.len()
+        ));
+        lines.join("\n")
+    }
+}
+
+/// Returns the protocol and UDP payload of frame, if it is an
+/// mDNS or SSDP multicast, untagged or with one 802.1Q tag
+fn discovery_payload(frame: &[u8]) -> Option<(Protocol, &[u8])> {
+    let frame = EthernetFrame::parse(frame).ok()?;
+    let packet = frame.payload;
+
+    let (udp, dst) = match frame.ether_type {
+        IPV4_ETHER_TYPE => {
+            let header_len = usize::from(packet.first()? & 0x0F) * 4;
+            if header_len < 20 || packet.len() < header_len + UDP_HDR || packet[9] != UDP_PROTOCOL {
+                return None;
+            }
+            /* Fragments can't be inspected, and discovery messages are never fragmented */
+            if u16::from_be_bytes([packet[6], packet[7]]) & 0x3FFF != 0 {
+                return None;
+            }
+            let dst: [u8; 4] = packet[16..20].try_into().unwrap();
+            let total_len = usize::from(u16::from_be_bytes([packet[2], packet[3]]));
+            let end = total_len.clamp(header_len, packet.len());
+            (&packet[header_len..end], IpAddr::from(dst))
+        }
+        IPV6_ETHER_TYPE => {
+            if packet.len() < IPV6_HDR + UDP_HDR || packet[6] != UDP_PROTOCOL {
+                return None;
+            }
+            let dst: [u8; 16] = packet[24..40].try_into().unwrap();
+            let payload_len = usize::from(u16::from_be_bytes([packet[4], packet[5]]));
+            let end = (IPV6_HDR + payload_len).clamp(IPV6_HDR, packet.len());
+            (&packet[IPV6_HDR..end], IpAddr::from(dst))
+        }
+        _ => return None,
+    };
+
+    let dst_port = u16::from_be_bytes([*udp.get(2)?, *udp.get(3)?]);
+    let protocol = [Protocol::Mdns, Protocol::Ssdp]
+        .into_iter()
+        .find(|protocol| protocol.port() == dst_port && protocol.is_group(&dst))?;
+    Some((protocol, udp.get(UDP_HDR..).unwrap_or_default()))
+}
+
+/// Returns true if the discovery message in payload is for one of
+/// services, or if there are no services, so every message is wanted
+fn wanted_service(
+    protocol: Protocol,
+    payload: &[u8],
+    services: &[String],
+    service_hosts: &mut HashSet<String>,
+) -> bool {
+    if services.is_empty() {
+        return true;
+    }
+    let names_service = |name: &str| {
+        let name = name.to_lowercase();
+        services
+            .iter()
+            .any(|service| name.contains(service.as_str()))
+    };
+
+    match protocol {
+        Protocol::Ssdp => String::from_utf8_lossy(payload).lines().any(|line| {
+            let Some((header, value)) = line.split_once(':') else {
+                return false;
+            };
+            matches!(
+                header.trim().to_ascii_uppercase().as_str(),
+                "NT" | "ST" | "USN"
+            ) && names_service(value)
+        }),
+        Protocol::Mdns => {
+            let Some(records) = mdns_records(payload) else {
+                return false;
+            };
+
+            /* The hosts which the services are on are remembered, so their addresses are reflected */
+            let mut wanted = false;
+            for record in records.iter() {
+                if names_service(&record.name) || names_service(&record.data_name) {
+                    wanted = true;
+                    if record.rr_type == DNS_TYPE_SRV && !record.data_name.is_empty() {
+                        if service_hosts.len() >= MAX_SERVICE_HOSTS {
+                            service_hosts.clear();
+                        }
+                        service_hosts.insert(record.data_name.to_lowercase());
+                    }
+                }
+            }
+            wanted
+                || records
+                    .iter()
+                    .any(|record| service_hosts.contains(&record.name.to_lowercase()))
+        }
+    }
+}
+
+/// Name, type and (for PTR and SRV records) data name of a
+/// question or resource record in an mDNS message
+struct MdnsRecord {
+    name: String,
+    rr_type: u16,
+    /// Name the record points to, or empty if it isn't a PTR or SRV record
+    data_name: String,
+}
+
+/// Returns the questions and resource records of the mDNS
+/// message in msg, or None if it is malformed
+fn mdns_records(msg: &[u8]) -> Option<Vec<MdnsRecord>> {
+    let count = |offset: usize| -> Option<usize> {
+        Some(usize::from(u16::from_be_bytes([
+            *msg.get(offset)?,
+            *msg.get(offset + 1)?,
+        ])))
+    };
+    let questions = count(4)?;
+    let records = count(6)? + count(8)? + count(10)?;
+
+    let mut parsed = Vec::new();
+    let mut offset = 12;
+    for _ in 0..questions {
+        let (name, next) = read_name(msg, offset)?;
+        let rr_type = count(next)? as u16;
+        parsed.push(MdnsRecord {
+            name,
+            rr_type,
+            data_name: String::new(),
+        });
+        offset = next + 4;
+    }
+
+    for _ in 0..records {
+        let (name, next) = read_name(msg, offset)?;
+        let rr_type = count(next)? as u16;
+        let data_len = count(next + 8)?;
+        let data_start = next + 10;
+        let data_name = match rr_type {
+            DNS_TYPE_PTR => read_name(msg, data_start)?.0,
+            DNS_TYPE_SRV => read_name(msg, data_start + 6)?.0,
+            _ => String::new(),
+        };
+        parsed.push(MdnsRecord {
+            name,
+            rr_type,
+            data_name,
+        });
+        offset = data_start + data_len;
+    }
+
+    Some(parsed)
+}
+
+/// Read the (possibly compressed) DNS name at offset in msg, returning
+/// it and the offset of what follows it, or None if it is malformed
+fn read_name(msg: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    let mut pointers = 0;
+
+    loop {
+        let len = *msg.get(offset)?;
+        match len {
+            0 => break,
+            len if len & 0xC0 == 0xC0 => {
+                pointers += 1;
+                if pointers > MAX_NAME_POINTERS {
+                    return None;
+                }
+                end.get_or_insert(offset + 2);
+                offset = usize::from(u16::from_be_bytes([len, *msg.get(offset + 1)?]) & 0x3FFF);
+            }
+            len if len & 0xC0 != 0 => return None,
+            len => {
+                let label = msg.get(offset + 1..offset + 1 + usize::from(len))?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                offset += 1 + usize::from(len);
+            }
+        }
+    }
+
+    Some((labels.join("."), end.unwrap_or(offset + 1)))
+}
@@ -0,0 +1,110 @@
+//! Introducing vports to each other, so they can open direct paths
+//!
+//! When the vswitch forwards a frame between two vports which asked for
+//! direct paths when they joined, and which reach it on its own port,
+//! each is told the session of the other, the MAC the frame came from or
+//! went to behind it, and the endpoint its datagrams come from, which is
+//! its NAT's public endpoint for it if it is behind one. The vports then
+//! punch holes through their NATs to each other, as in l2vpn::direct
+//!
+//! Each vport is only told of each MAC again after INTRODUCTION_INTERVAL,
+//! so vports whose punches didn't get through try again while their
+//! frames are still relayed, without an introduction for every frame.
+//! Ports which tag or untag frames are never introduced, as the frames
+//! sent between their vports directly would skip the VLAN handling, and
+//! nor are two vports which both found with STUN that they are behind
+//! symmetric NATs, as neither could punch through to the other
+
+use crate::{ports::PortTable, vlan::PortVlan, VportAddr};
+use l2vpn::{
+    control::{ControlMsg, CAP_DIRECT_PATHS},
+    mac::MacAddr,
+    stun::NatType,
+};
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, SocketAddrV4},
+    time::{Duration, Instant},
+};
+
+/// How long before a vport is told of the same MAC again
+pub const INTRODUCTION_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The introductions sent to vports
+#[derive(Debug, Default)]
+pub struct Rendezvous {
+    /* When each vport was last told of each MAC */
+    introduced: HashMap<(VportAddr, MacAddr), Instant>,
+}
+
+impl Rendezvous {
+    /// Returns the introductions to send, each with the vport it is sent
+    /// to, for a frame at now from src_mac behind src to dst_mac behind
+    /// dst, leaving out those sent within INTRODUCTION_INTERVAL
+    pub fn introductions(
+        &mut self,
+        ports: &PortTable<VportAddr>,
+        (src, src_mac): (VportAddr, MacAddr),
+        (dst, dst_mac): (VportAddr, MacAddr),
+        now: Instant,
+    ) -> Vec<(VportAddr, ControlMsg)> {
+        let (Some((src_session, src_endpoint)), Some((dst_session, dst_endpoint))) =
+            (endpoint(ports, &src), endpoint(ports, &dst))
+        else {
+            return Vec::new();
+        };
+        if src == dst || (symmetric(ports, &src) && symmetric(ports, &dst)) {
+            return Vec::new();
+        }
+
+        let mut introductions = Vec::new();
+        for (to, session_id, mac, endpoint) in [
+            (src, dst_session, dst_mac, dst_endpoint),
+            (dst, src_session, src_mac, src_endpoint),
+        ] {
+            let due = self
+                .introduced
+                .get(&(to, mac))
+                .is_none_or(|sent| now.duration_since(*sent) >= INTRODUCTION_INTERVAL);
+            if due {
+                self.introduced
+                    .retain(|_, sent| now.duration_since(*sent) < INTRODUCTION_INTERVAL);
+                self.introduced.insert((to, mac), now);
+                introductions.push((
+                    to,
+                    ControlMsg::Introduce {
+                        session_id,
+                        mac,
+                        endpoint,
+                    },
+                ));
+            }
+        }
+        introductions
+    }
+}
+
+/// Returns the session of the vport at addr, and the endpoint its
+/// datagrams come from, if it can be introduced to other vports
+fn endpoint(ports: &PortTable<VportAddr>, addr: &VportAddr) -> Option<(u64, SocketAddrV4)> {
+    let VportAddr::Udp(SocketAddr::V4(endpoint)) = addr else {
+        return None;
+    };
+    let port = ports.get(addr)?;
+    let asked = port
+        .registration
+        .is_some_and(|r| r.capabilities & CAP_DIRECT_PATHS != 0);
+    match asked && !port.down && !port.shutdown && port.vlan == PortVlan::default() {
+        true => Some((port.session_id?, *endpoint)),
+        false => None,
+    }
+}
+
+/// Returns true if the vport at addr reported it is behind a symmetric
+/// NAT, which maps each destination to another public endpoint
+fn symmetric(ports: &PortTable<VportAddr>, addr: &VportAddr) -> bool {
+    ports
+        .get(addr)
+        .and_then(|port| port.registration?.public_endpoint)
+        .is_some_and(|public_endpoint| public_endpoint.nat == NatType::Symmetric)
+}
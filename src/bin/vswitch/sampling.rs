@@ -0,0 +1,95 @@
+//! 1-in-N frame sampling for the vswitch
+//!
+//! When a sample collector is given, a summary of the headers of
+//! every Nth frame the vswitch switches is sent to it as a JSON
+//! object in a UDP datagram, e.g.
+//!
+//! {"sequence":1,"rate":100,"time_ms":1760000000000,"in_port":2,
+//!  "src_mac":"86:4a:01:2b:53:a8","dst_mac":"ff:ff:ff:ff:ff:ff",
+//!  "ether_type":2054,"vlan":null,"size":60}
+//!
+//! This is much cheaper than monitoring every frame, so gives
+//! some visibility of the traffic on hosts which are short of
+//! resources. Sizes exclude the FCS, and the sequence number
+//! lets the collector spot samples lost on the way
+
+use l2vpn::frame::EthernetFrame;
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Sampling rate used if a collector is given without one
+pub const DEFAULT_SAMPLE_RATE: u32 = 1000;
+
+/// Sends a summary of every Nth frame to a collector
+pub struct Sampler {
+    socket: UdpSocket,
+    collector: SocketAddr,
+    rate: u32,
+    /* Frames seen since the last one was sampled */
+    skipped: u32,
+    sequence: u64,
+}
+
+impl Sampler {
+    /// Returns a sampler which sends 1 in rate frames to collector
+    pub fn new(collector: SocketAddr, rate: u32) -> io::Result<Sampler> {
+        let bind_addr = match collector {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        };
+
+        Ok(Sampler {
+            socket: UdpSocket::bind(bind_addr)?,
+            collector,
+            rate,
+            skipped: 0,
+            sequence: 0,
+        })
+    }
+
+    /// Count frame, received from the port with ID in_port,
+    /// and send its summary to the collector if it is sampled
+    pub fn frame(&mut self, frame: &[u8], in_port: u32) {
+        self.skipped += 1;
+        if self.skipped < self.rate {
+            return;
+        }
+        self.skipped = 0;
+        self.sequence += 1;
+
+        let Ok(eth_frame) = EthernetFrame::parse(frame) else {
+            return;
+        };
+        let sample = summary(&eth_frame, frame.len(), in_port, self.sequence, self.rate);
+
+        /* Losing a sample is harmless, so this never stops the vswitch */
+        if let Err(e) = self.socket.send_to(sample.as_bytes(), self.collector) {
+            eprintln!(
+                "Got error while sending sample to '{}': {}",
+                self.collector, e
+            );
+        }
+    }
+}
+
+/// Returns the JSON summary of the headers of frame, which is size bytes long
+fn summary(frame: &EthernetFrame, size: usize, in_port: u32, sequence: u64, rate: u32) -> String {
+    let time_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+
+    let vlan = match frame.vlan {
+        Some(tag) => tag.vid.to_string(),
+        None => "null".to_string(),
+    };
+
+    format!(
+        "{{\"sequence\":{},\"rate\":{},\"time_ms\":{},\"in_port\":{},\"src_mac\":\"{}\",\
+         \"dst_mac\":\"{}\",\"ether_type\":{},\"vlan\":{},\"size\":{}}}",
+        sequence, rate, time_ms, in_port, frame.src, frame.dst, frame.ether_type, vlan, size
+    )
+}
@@ -0,0 +1,153 @@
+//! Settings of the vswitch which admin clients can change at runtime
+//!
+//! Admin commands are executed by the switching loop between frames,
+//! so every change applies to all of the frames handled after it,
+//! and to none of those handled before it, without a restart
+
+use crate::{mac_moves::MacMoveLimit, port_security::MacLimit};
+use l2vpn::{filter::Filter, mac::MacAddr};
+use std::{collections::HashSet, fmt, time::Duration};
+
+/// What happens to the frames matching an ACL rule
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AclAction {
+    Permit,
+    Deny,
+}
+
+impl AclAction {
+    /// Returns the name of the action, as given to 'acl add'
+    pub fn name(&self) -> &'static str {
+        match self {
+            AclAction::Permit => "permit",
+            AclAction::Deny => "deny",
+        }
+    }
+}
+
+/// Frames which an ACL rule is applied to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AclBinding {
+    /// The frames received from every port
+    Global,
+    /// The frames received from the port with this ID
+    In(u32),
+    /// The frames sent to the port with this ID
+    Out(u32),
+}
+
+impl fmt::Display for AclBinding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AclBinding::Global => write!(f, "in all"),
+            AclBinding::In(id) => write!(f, "in {}", id),
+            AclBinding::Out(id) => write!(f, "out {}", id),
+        }
+    }
+}
+
+/// Rule which the frames received or sent by the vswitch are checked against
+#[derive(Debug)]
+pub struct AclRule {
+    pub action: AclAction,
+    pub binding: AclBinding,
+    pub filter: Filter,
+    /// Filter as it was given, to show it back to admin clients
+    pub text: String,
+    /// Number of frames which have matched the rule
+    pub hits: u64,
+}
+
+/// Switching behaviour which can be changed while the vswitch runs
+#[derive(Debug)]
+pub struct Settings {
+    /// How long a learned MAC is kept without a frame being received
+    /// from it, or None to keep learned MACs until they move
+    pub mac_aging: Option<Duration>,
+    /// Whether source MACs are learned
+    pub learning: bool,
+    /// Whether frames to unknown unicast and multicast MACs
+    /// are flooded like broadcasts, rather than dropped
+    pub flooding: bool,
+    /// Rules which frames are checked against in order, where the first
+    /// matching rule bound to where the frame is received from or sent to
+    /// applies, and frames matching no rule are permitted
+    pub acl: Vec<AclRule>,
+    /// MACs added by admin clients, which are never learned
+    /// on another port, aged out or flushed
+    pub static_macs: HashSet<MacAddr>,
+    /// Most MACs which can be learned on each vport other than
+    /// a peer, and what happens beyond that, or None for no limit
+    pub mac_limit: Option<MacLimit>,
+    /// Most times a MAC can move between vports within a window,
+    /// and what happens beyond that, or None for no limit
+    pub mac_move_limit: Option<MacMoveLimit>,
+    /// Frames each vport other than a peer can flood per second,
+    /// beyond which they are dropped, or None for no limit
+    pub storm_control: Option<u32>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            mac_aging: None,
+            learning: true,
+            flooding: false,
+            acl: Vec::new(),
+            static_macs: HashSet::new(),
+            mac_limit: None,
+            mac_move_limit: None,
+            storm_control: None,
+        }
+    }
+}
+
+impl Settings {
+    /// Returns the settings which the vswitch starts with, where
+    /// flooding is whether unknown MACs are flooded from the start
+    pub fn new(flooding: bool) -> Settings {
+        Settings {
+            flooding,
+            ..Settings::default()
+        }
+    }
+
+    /// Returns true if the ACL permits frame, received from the port with
+    /// ID in_port, and sent to the port with ID out_port (or as it is
+    /// received, if there is none), and counts the hit against the rule
+    /// which matched it
+    pub fn acl_permits(&mut self, frame: &[u8], in_port: u32, out_port: Option<u32>) -> bool {
+        match self.acl_rule(frame, in_port, out_port) {
+            Some(index) => {
+                let rule = &mut self.acl[index];
+                rule.hits += 1;
+                rule.action == AclAction::Permit
+            }
+            None => true,
+        }
+    }
+
+    /// Returns the index of the first ACL rule matching frame, received
+    /// from the port with ID in_port, and sent to the port with ID out_port
+    /// (or as it is received, if there is none), if any
+    pub fn acl_rule(&self, frame: &[u8], in_port: u32, out_port: Option<u32>) -> Option<usize> {
+        let bound = |binding: AclBinding| match (binding, out_port) {
+            (AclBinding::Global, None) => true,
+            (AclBinding::In(id), None) => id == in_port,
+            (AclBinding::Out(id), Some(out_port)) => id == out_port,
+            _ => false,
+        };
+        self.acl
+            .iter()
+            .position(|rule| bound(rule.binding) && rule.filter.matches(frame, in_port))
+    }
+}
+
+/// Parse "on" or "off"
+pub fn parse_on_off(value: &str) -> Result<bool, String> {
+    match value {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(format!("Expected 'on' or 'off', not '{}'", value)),
+    }
+}
@@ -0,0 +1,45 @@
+//! Frame size distribution for the vswitch
+//!
+//! Every port counts the frames received from it in the size
+//! buckets of RMON's etherStats, which helps with capacity planning
+//! and spotting unusual traffic. As in RMON, sizes include the FCS,
+//! even though frames are carried without one
+
+use l2vpn::utilities::ETHER_FCS;
+
+/// Labels and upper bounds (inclusive) of each bucket, where the last
+/// bucket counts the jumbo frames larger than the Ethernet maximum
+pub const SIZE_BUCKETS: [(&str, usize); 7] = [
+    ("64", 64),
+    ("65-127", 127),
+    ("128-255", 255),
+    ("256-511", 511),
+    ("512-1023", 1023),
+    ("1024-1518", 1518),
+    ("jumbo", usize::MAX),
+];
+
+/// Count of frames received in each of the SIZE_BUCKETS
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FrameSizes {
+    pub buckets: [u64; SIZE_BUCKETS.len()],
+}
+
+impl FrameSizes {
+    /// Count a frame of no_of_bytes, excluding its FCS
+    pub fn record(&mut self, no_of_bytes: usize) {
+        let size = no_of_bytes + ETHER_FCS;
+        let bucket = SIZE_BUCKETS
+            .iter()
+            .position(|(_, max)| size <= *max)
+            .unwrap_or(SIZE_BUCKETS.len() - 1);
+        self.buckets[bucket] += 1;
+    }
+
+    /// Add the frames counted in other
+    pub fn add(&mut self, other: &FrameSizes) {
+        for (count, other_count) in self.buckets.iter_mut().zip(other.buckets) {
+            *count += other_count;
+        }
+    }
+}
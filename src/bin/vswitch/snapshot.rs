@@ -0,0 +1,266 @@
+//! MAC table snapshots for the vswitch
+//!
+//! The state file only restores the MACs of a vport with a session
+//! once it says hello again, and doesn't restore the MACs of vports
+//! without one at all, so after a restart, frames to the hosts behind
+//! them are flooded (or dropped, while flooding is off) until their
+//! MACs are learned again. With a MAC snapshot file, the MAC tables
+//! and the VLAN assignments of the ports are written to it periodically,
+//! and loaded when the vswitch starts, so frames are sent where their
+//! destinations were from the start
+//!
+//! Only the vports whose addresses stay the same across a restart are
+//! snapshotted, i.e. those reached over UDP, which keep their sockets,
+//! VTEPs reached over VXLAN or GENEVE, and VMs attached over vhost-user. vports connected over vsock, TCP,
+//! Unix sockets and shared memory connect again from new addresses. The
+//! MACs restored for a vport which isn't heard from within
+//! PORT_DOWN_TIMEOUT of the restart are flushed, as if it had gone down
+
+use crate::{
+    ports::PortTable,
+    topology::PORT_DOWN_TIMEOUT,
+    vlan::{parse_vlan_id, Domain, PortVlan},
+    MacTables, VportAddr, Vports,
+};
+use l2vpn::{mac::MacAddr, vxlan::parse_vni};
+use std::{
+    collections::HashSet,
+    error::Error,
+    fs,
+    io::{self, Write},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// How often the MAC tables are snapshotted, unless configured otherwise
+pub const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// MAC snapshot file, and the vports whose MACs were restored from it
+#[derive(Debug)]
+pub struct MacSnapshot {
+    path: PathBuf,
+    /// vports whose MACs or VLAN assignment were restored, which
+    /// haven't been heard from since, and when they were restored
+    restored: Vec<VportAddr>,
+    restored_at: Instant,
+}
+
+impl MacSnapshot {
+    /// Restore the MACs and VLAN assignments snapshotted at path into
+    /// mac_tables and ports, other than static_macs and those of vports
+    /// which vports can no longer reach us at, returning the snapshot
+    /// and the number of MACs restored. Nothing is restored if no
+    /// snapshot has been written yet
+    pub fn load<P: AsRef<Path>>(
+        path: P,
+        vports: &Vports,
+        ports: &mut PortTable<VportAddr>,
+        mac_tables: &mut MacTables,
+        static_macs: &HashSet<MacAddr>,
+    ) -> Result<(MacSnapshot, usize), Box<dyn Error>> {
+        let mut snapshot = MacSnapshot {
+            path: path.as_ref().to_path_buf(),
+            restored: Vec::new(),
+            restored_at: Instant::now(),
+        };
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((snapshot, 0)),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut macs = 0;
+        for (line_no, line) in contents.lines().enumerate() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let parsed = match fields.as_slice() {
+                [] | ["#", ..] => Ok(None),
+                ["port", addr, mode @ ..] => parse_addr(addr).and_then(|addr| {
+                    let mode = PortVlan::parse(mode)?;
+                    Ok(reachable(vports, &addr).then(|| {
+                        ports.port(addr).vlan = mode;
+                        addr
+                    }))
+                }),
+                ["mac", segment, vlan, mac, addr] => {
+                    parse_mac_line(segment, vlan, mac, addr).map(|(domain, mac, addr)| {
+                        let restore = reachable(vports, &addr)
+                            && vports.segment(&addr) == domain.segment
+                            && !static_macs.contains(&mac);
+                        restore.then(|| {
+                            mac_tables.entry(domain).or_default().insert(mac, addr);
+                            macs += 1;
+                            addr
+                        })
+                    })
+                }
+                _ => Err("unrecognised line".to_string()),
+            };
+
+            match parsed {
+                Ok(Some(addr)) if !snapshot.restored.contains(&addr) => {
+                    snapshot.restored.push(addr)
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    return Err(format!("MAC snapshot line {}: {}", line_no + 1, e).into());
+                }
+            }
+        }
+
+        /* Restored vports are only counted as heard from once they send something */
+        for addr in snapshot.restored.iter() {
+            ports.port(*addr);
+        }
+        snapshot.restored_at = Instant::now();
+
+        Ok((snapshot, macs))
+    }
+
+    /// Write the MACs in mac_tables (other than static_macs) and the VLAN
+    /// assignments of ports, of the vports whose addresses stay the same
+    /// across restarts, to the snapshot file
+    pub fn save(
+        &self,
+        ports: &PortTable<VportAddr>,
+        mac_tables: &MacTables,
+        static_macs: &HashSet<MacAddr>,
+    ) -> io::Result<()> {
+        let mut contents =
+            String::from("# l2vpn vswitch MAC table snapshot, written automatically\n");
+
+        for (addr, port) in ports.iter() {
+            if is_stable(addr) && port.vlan != PortVlan::default() {
+                contents += &format!("port {} {}\n", addr, port.vlan.args());
+            }
+        }
+
+        for (domain, mac_table) in mac_tables.iter() {
+            let vlan = domain
+                .vlan
+                .map_or_else(|| "-".to_string(), |vlan| vlan.to_string());
+            for (mac, addr) in mac_table.iter() {
+                if is_stable(addr) && !static_macs.contains(mac) {
+                    contents += &format!("mac {} {} {} {}\n", domain.segment, vlan, mac, addr);
+                }
+            }
+        }
+
+        /* Write to a temporary file first, so a crash can't leave a half-written snapshot */
+        let tmp_path = self.path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)
+    }
+
+    /// Returns the restored vports which haven't been heard from within
+    /// PORT_DOWN_TIMEOUT of being restored, by now, whose MACs are to be
+    /// flushed. Restored vports are only returned once
+    pub fn expire(&mut self, ports: &PortTable<VportAddr>, now: Instant) -> Vec<VportAddr> {
+        let restored_at = self.restored_at;
+        let heard_from = |addr: &VportAddr| {
+            ports
+                .get(addr)
+                .is_some_and(|port| port.last_seen > restored_at)
+        };
+        self.restored.retain(|addr| !heard_from(addr));
+        if self.restored.is_empty() || now.duration_since(restored_at) < PORT_DOWN_TIMEOUT {
+            return Vec::new();
+        }
+        std::mem::take(&mut self.restored)
+    }
+}
+
+/// Returns true if addr stays the same across restarts of the vswitch
+fn is_stable(addr: &VportAddr) -> bool {
+    match addr {
+        VportAddr::Udp(_)
+        | VportAddr::Listen(..)
+        | VportAddr::Vxlan { .. }
+        | VportAddr::Geneve { .. } => true,
+        #[cfg(feature = "vhost-user")]
+        VportAddr::VhostUser(_) => true,
+        _ => false,
+    }
+}
+
+/// Returns true if the vswitch still has the listener which
+/// addr, read from a snapshot, was reached through
+fn reachable(vports: &Vports, addr: &VportAddr) -> bool {
+    match addr {
+        VportAddr::Listen(index, _) => *index < vports.listen.len(),
+        VportAddr::Vxlan { .. } => vports.vxlan.is_some(),
+        VportAddr::Geneve { .. } => vports.geneve.is_some(),
+        #[cfg(feature = "vhost-user")]
+        VportAddr::VhostUser(index) => *index < vports.vhost_user.len(),
+        _ => true,
+    }
+}
+
+/// Parse the address of a vport, as it is displayed, which must
+/// be one which stays the same across restarts of the vswitch
+fn parse_addr(value: &str) -> Result<VportAddr, String> {
+    let invalid = || {
+        format!(
+            "'{}' is not the address of a UDP, VXLAN, GENEVE or vhost-user vport",
+            value
+        )
+    };
+    if let Some(index) = value.strip_prefix("vhost-user#") {
+        let index = index.parse::<usize>().map_err(|_| invalid())?;
+        #[cfg(feature = "vhost-user")]
+        return Ok(VportAddr::VhostUser(index));
+        #[cfg(not(feature = "vhost-user"))]
+        return Err(format!(
+            "vhost-user#{} can't be restored, as the vswitch was built without the vhost-user feature",
+            index
+        ));
+    }
+
+    if let Some((ip, vni)) = value.split_once("@vxlan#") {
+        return Ok(VportAddr::Vxlan {
+            vni: parse_vni(vni).ok_or_else(invalid)?,
+            ip: ip.parse::<IpAddr>().map_err(|_| invalid())?,
+        });
+    }
+    if let Some((ip, vni)) = value.split_once("@geneve#") {
+        return Ok(VportAddr::Geneve {
+            vni: parse_vni(vni).ok_or_else(invalid)?,
+            ip: ip.parse::<IpAddr>().map_err(|_| invalid())?,
+        });
+    }
+
+    match value.split_once("@listen#") {
+        Some((addr, index)) => Ok(VportAddr::Listen(
+            index.parse::<usize>().map_err(|_| invalid())?,
+            addr.parse::<SocketAddr>().map_err(|_| invalid())?,
+        )),
+        None => value
+            .parse::<SocketAddr>()
+            .map(VportAddr::Udp)
+            .map_err(|_| invalid()),
+    }
+}
+
+/// Parse a MAC line of the snapshot, which gives the segment and VLAN
+/// (or - for untagged frames) the MAC was learned in, and its vport
+fn parse_mac_line(
+    segment: &str,
+    vlan: &str,
+    mac: &str,
+    addr: &str,
+) -> Result<(Domain, MacAddr, VportAddr), String> {
+    let segment = segment
+        .parse::<u32>()
+        .map_err(|_| format!("'{}' is not a segment", segment))?;
+    let vlan = match vlan {
+        "-" => None,
+        vlan => Some(parse_vlan_id(vlan)?),
+    };
+    let mac = mac
+        .parse::<MacAddr>()
+        .map_err(|_| format!("'{}' is not a MAC", mac))?;
+    Ok((Domain { segment, vlan }, mac, parse_addr(addr)?))
+}
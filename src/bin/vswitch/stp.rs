@@ -0,0 +1,247 @@
+//! Spanning tree in the vswitch
+//!
+//! With --stp, the vswitch runs 802.1D on its ports, using the I/O-free
+//! bridge in l2vpn::stp, so a loop of peered vswitches, or a vport which
+//! bridges back into the overlay, has a port blocked rather than carrying
+//! broadcasts around until their TTL expires. Segments are separate
+//! networks, so each of them has a spanning tree of its own
+//!
+//! The state of each port is kept in the port table, which the switching
+//! loop checks for every frame it receives and sends
+
+use crate::{events::EventLog, ports::PortTable, VportAddr, Vports};
+use l2vpn::{
+    mac::MacAddr,
+    stp::{Bpdu, Bridge, BridgeId, Change, PortRole, PortState},
+    timer::Interval,
+};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, Read},
+    time::{Duration, Instant},
+};
+
+/// Priority of every port, in the top 4 bits of the port IDs sent in BPDUs
+const PORT_PRIORITY: u16 = 0x8000;
+
+/// How often the bridges' timers are run, which is often enough for
+/// their hellos to be sent on time, and ports to move on from listening
+/// and learning within a second of the forward delay
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Bridges running a spanning tree in each segment, which share a bridge ID
+#[derive(Debug)]
+pub struct SpanningTree {
+    id: BridgeId,
+    bridges: BTreeMap<u32, Bridge<VportAddr>>,
+    timer: Interval,
+}
+
+impl SpanningTree {
+    /// Returns a spanning tree whose bridges have the given priority,
+    /// and a random locally administered MAC
+    pub fn new(priority: u16) -> io::Result<SpanningTree> {
+        let mut mac = [0u8; 6];
+        File::open("/dev/urandom")?.read_exact(&mut mac)?;
+        mac[0] = (mac[0] & 0xFE) | 0x02;
+
+        Ok(SpanningTree {
+            id: BridgeId {
+                priority,
+                mac: MacAddr(mac),
+            },
+            bridges: BTreeMap::new(),
+            timer: Interval::new(TICK_INTERVAL),
+        })
+    }
+
+    /// Returns the ID of the vswitch's bridges
+    pub fn id(&self) -> BridgeId {
+        self.id
+    }
+
+    /// Returns how long after now the bridges' timers are next due to be run
+    pub fn remaining(&self, now: Instant) -> Duration {
+        self.timer.remaining(now)
+    }
+
+    /// Handle bpdu, received from src_vport at now, returning
+    /// true if the topology of src_vport's segment changed
+    pub fn receive(
+        &mut self,
+        src_vport: VportAddr,
+        bpdu: Bpdu,
+        now: Instant,
+        ports: &mut PortTable<VportAddr>,
+        vports: &Vports,
+        events: &mut EventLog,
+    ) -> bool {
+        let segment = vports.segment(&src_vport);
+        let id = port_id(ports.port(src_vport).id);
+        let bridge = self
+            .bridges
+            .entry(segment)
+            .or_insert_with(|| Bridge::new(self.id));
+
+        let replies = bridge.receive(src_vport, id, bpdu, now);
+        send(&self.id, &replies, vports);
+        apply(&self.id, segment, bridge, ports, events)
+    }
+
+    /// Run the bridges' timers if they are due at now, sending
+    /// their BPDUs, and return the segments whose topology changed
+    pub fn tick(
+        &mut self,
+        now: Instant,
+        ports: &mut PortTable<VportAddr>,
+        vports: &Vports,
+        events: &mut EventLog,
+    ) -> Vec<u32> {
+        if !self.timer.due(now) {
+            return Vec::new();
+        }
+
+        /* Only the ports which are up and not shut down take part */
+        let mut up: BTreeMap<u32, Vec<(VportAddr, u16)>> = BTreeMap::new();
+        for (addr, port) in ports.iter() {
+            if !port.down && !port.shutdown {
+                up.entry(vports.segment(addr))
+                    .or_default()
+                    .push((*addr, port_id(port.id)));
+            }
+        }
+        for segment in up.keys() {
+            self.bridges
+                .entry(*segment)
+                .or_insert_with(|| Bridge::new(self.id));
+        }
+
+        let mut changed = Vec::new();
+        for (segment, bridge) in self.bridges.iter_mut() {
+            let up = up.get(segment).map_or(&[][..], Vec::as_slice);
+            let bpdus = bridge.tick(up, now);
+            send(&self.id, &bpdus, vports);
+            if apply(&self.id, *segment, bridge, ports, events) {
+                changed.push(*segment);
+            }
+        }
+        changed
+    }
+
+    /// Returns the spanning tree of each segment in human readable format
+    pub fn show(&self, ports: &PortTable<VportAddr>) -> String {
+        let mut lines = vec![format!("Bridge ID: {}", self.id)];
+
+        for (segment, bridge) in self.bridges.iter() {
+            let root = match bridge.root_port() {
+                Some(root_port) => format!(
+                    "{}, cost {} through {}",
+                    bridge.root(),
+                    bridge.root_cost(),
+                    port_name(ports, root_port)
+                ),
+                None => format!("{} (this vswitch)", bridge.root()),
+            };
+            lines.push(format!("Segment {}: root {}", segment, root));
+            lines.push(format!(
+                "{:>5}  {:<24}  {:<10}  {}",
+                "port", "vport", "role", "state"
+            ));
+
+            let mut bridge_ports: Vec<_> = bridge
+                .ports()
+                .map(|(addr, status)| (ports.get(addr).map_or(0, |port| port.id), addr, status))
+                .collect();
+            bridge_ports.sort_by_key(|(id, _, _)| *id);
+            for (id, addr, status) in bridge_ports {
+                lines.push(format!(
+                    "{:>5}  {:<24}  {:<10}  {}",
+                    id,
+                    addr.to_string(),
+                    status.role.name(),
+                    status.state.name()
+                ));
+            }
+        }
+
+        lines.join("\n")
+    }
+}
+
+/// Returns the port ID sent in BPDUs for the port with ID id
+fn port_id(id: u32) -> u16 {
+    PORT_PRIORITY | (id & 0x0FFF) as u16
+}
+
+/// Returns how the vport at addr is described
+fn port_name(ports: &PortTable<VportAddr>, addr: &VportAddr) -> String {
+    match ports.get(addr) {
+        Some(port) => format!("port {} ({})", port.id, addr),
+        None => addr.to_string(),
+    }
+}
+
+/// Send bpdus from the bridge with ID id to their vports
+fn send(id: &BridgeId, bpdus: &[(VportAddr, Bpdu)], vports: &Vports) {
+    for (addr, bpdu) in bpdus {
+        /* A lost BPDU is made up for by the next one, a hello time later */
+        if let Err(e) = vports.send_to(&bpdu.encode(&id.mac), addr) {
+            eprintln!("Got error while sending BPDU to '{}': {}", addr, e);
+        }
+    }
+}
+
+/// Apply the changes bridge made to the spanning tree of segment
+/// to the port table, and record them, returning true if the
+/// topology changed, so MACs must be flushed
+fn apply(
+    id: &BridgeId,
+    segment: u32,
+    bridge: &mut Bridge<VportAddr>,
+    ports: &mut PortTable<VportAddr>,
+    events: &mut EventLog,
+) -> bool {
+    let mut topology_change = false;
+
+    for change in bridge.take_changes() {
+        match change {
+            Change::Root(root) if root == *id => events.record(format!(
+                "STP: this vswitch became the root bridge of segment {}",
+                segment
+            )),
+            Change::Root(root) => events.record(format!(
+                "STP: {} became the root bridge of segment {}",
+                root, segment
+            )),
+            Change::State(addr, state) => {
+                let Some(port) = ports.get_mut(&addr) else {
+                    continue;
+                };
+                /*
+                 * A port which has gone down leaves the spanning tree, and
+                 * forwards as a new edge port if it comes back up
+                 */
+                port.stp = match state {
+                    PortState::Disabled => PortState::Forwarding,
+                    state => state,
+                };
+
+                /* Ports leading to hosts always forward while they are up */
+                let edge = bridge
+                    .ports()
+                    .any(|(edge, status)| *edge == addr && status.role == PortRole::Edge);
+                if !edge && state != PortState::Disabled {
+                    events.record(format!(
+                        "STP: port {} ({}) is {}",
+                        port.id,
+                        addr,
+                        state.name()
+                    ));
+                }
+            }
+            Change::TopologyChange => topology_change = true,
+        }
+    }
+    topology_change
+}
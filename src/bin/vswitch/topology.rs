@@ -0,0 +1,171 @@
+//! Topology changes in the vswitch
+//!
+//! When a vport goes down (it leaves, its connection closes, or it stops
+//! being heard from), a peer vswitch stops answering echo requests, or a VM's
+//! bridge signals an STP topology change, the MACs which can no longer
+//! be reached where they were learned are flushed straight away, rather
+//! than black-holing frames sent to them until they are learned again
+//!
+//! The flushed MACs are sent to the peer vswitches in a topology change
+//! message. Each peer flushes those which it learned through this
+//! vswitch, and passes the ones it flushed on to its own peers
+//!
+//! When STP is on and the spanning tree changes, every MAC learned in
+//! the segment is flushed, as any of them may now be reached another way
+//!
+//! Static MACs added by admin clients are never flushed
+
+use crate::{events::EventLog, MacTables, VportAddr, Vports};
+use l2vpn::{
+    control::{ControlMsg, MAX_TOPOLOGY_CHANGE_MACS},
+    mac::MacAddr,
+    stp::Bpdu,
+    switching::{ForwardingTable, MacTable},
+    utilities::ETHER_FRAME_MIN,
+};
+use std::{collections::HashSet, time::Duration};
+
+/// How long a vport with a session, or a peer vswitch, can go without
+/// being heard from before it is considered down. vports send a hello
+/// every 10 seconds (unless told otherwise), and peers
+/// answer the echo requests sent every 5
+pub const PORT_DOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Remove the MACs learned on the vport at addr from mac_table, returning them
+pub fn flush_port(
+    mac_table: &mut MacTable<VportAddr>,
+    static_macs: &HashSet<MacAddr>,
+    addr: &VportAddr,
+) -> Vec<MacAddr> {
+    flush(mac_table, static_macs, |vport| vport == addr)
+}
+
+/// Remove the MACs learned on every vport except the one at addr
+/// from mac_table, returning them. This is what a bridge does when
+/// it hears of a topology change from the vport at addr
+pub fn flush_others(
+    mac_table: &mut MacTable<VportAddr>,
+    static_macs: &HashSet<MacAddr>,
+    addr: &VportAddr,
+) -> Vec<MacAddr> {
+    flush(mac_table, static_macs, |vport| vport != addr)
+}
+
+/// Remove those of macs which were learned on the vport at addr
+/// from mac_table, returning them
+pub fn flush_macs(
+    mac_table: &mut MacTable<VportAddr>,
+    static_macs: &HashSet<MacAddr>,
+    addr: &VportAddr,
+    macs: &[MacAddr],
+) -> Vec<MacAddr> {
+    let mut flushed = Vec::new();
+    for mac in macs {
+        if mac_table.get(mac) == Some(addr) && !static_macs.contains(mac) {
+            mac_table.remove(mac);
+            flushed.push(*mac);
+        }
+    }
+
+    flushed
+}
+
+/// Remove the MACs whose vport matches from mac_table
+/// (except static_macs), returning them
+fn flush(
+    mac_table: &mut MacTable<VportAddr>,
+    static_macs: &HashSet<MacAddr>,
+    matches: impl Fn(&VportAddr) -> bool,
+) -> Vec<MacAddr> {
+    mac_table.flush(&mut |mac, vport| matches(vport) && !static_macs.contains(mac))
+}
+
+/// Returns true if frame is an STP BPDU signalling a topology change,
+/// i.e. a topology change notification, or a configuration or RST
+/// BPDU with the topology change flag set
+pub fn is_topology_change(frame: &[u8]) -> bool {
+    Bpdu::parse(frame).is_some_and(|bpdu| bpdu.is_topology_change())
+}
+
+/// Tell every peer vswitch except the one at except (if any)
+/// that macs can no longer be reached through this vswitch
+pub fn notify_peers(
+    vports: &Vports,
+    peers: &[VportAddr],
+    except: Option<&VportAddr>,
+    macs: &[MacAddr],
+) {
+    for chunk in macs.chunks(MAX_TOPOLOGY_CHANGE_MACS) {
+        let mut frame = ControlMsg::TopologyChange {
+            macs: chunk.to_vec(),
+        }
+        .encode();
+        if frame.len() < ETHER_FRAME_MIN {
+            frame.resize(ETHER_FRAME_MIN, 0);
+        }
+
+        for peer in peers.iter().filter(|peer| Some(*peer) != except) {
+            /* A peer which misses the change just keeps its stale MACs until they move */
+            if let Err(e) = vports.send_to(&frame, peer) {
+                eprintln!(
+                    "Got error while notifying peer '{}' of topology change: {}",
+                    peer, e
+                );
+            }
+        }
+    }
+}
+
+/// Flush the MACs learned on the vport at addr, which has gone down,
+/// in every VLAN, and tell the peers to flush them too. The event
+/// describing why the port went down is recorded along with the
+/// number of MACs flushed
+pub fn port_down(
+    addr: &VportAddr,
+    event: String,
+    mac_tables: &mut MacTables,
+    static_macs: &HashSet<MacAddr>,
+    peers: &[VportAddr],
+    vports: &Vports,
+    events: &mut EventLog,
+) {
+    let mut flushed: Vec<MacAddr> = mac_tables
+        .values_mut()
+        .flat_map(|mac_table| flush_port(mac_table, static_macs, addr))
+        .collect();
+    flushed.sort();
+    flushed.dedup();
+    events.record(format!(
+        "{}, so flushed its {} MAC(s)",
+        event,
+        flushed.len()
+    ));
+
+    notify_peers(vports, peers, Some(addr), &flushed);
+}
+
+/// Flush every learned MAC in segment, in every VLAN, after the spanning
+/// tree changed, as any of them may now be reached through another port,
+/// and tell the peers to flush those they learned through this vswitch.
+/// The event describing the change is recorded along with the number of
+/// MACs flushed
+pub fn flush_segment(
+    segment: u32,
+    event: String,
+    mac_tables: &mut MacTables,
+    static_macs: &HashSet<MacAddr>,
+    peers: &[VportAddr],
+    vports: &Vports,
+    events: &mut EventLog,
+) {
+    let mut flushed: Vec<MacAddr> = mac_tables
+        .iter_mut()
+        .filter(|(domain, _)| domain.segment == segment)
+        .flat_map(|(_, mac_table)| flush(mac_table, static_macs, |_| true))
+        .collect();
+    flushed.sort();
+    flushed.dedup();
+    events.record(format!("{}, so flushed {} MAC(s)", event, flushed.len()));
+
+    notify_peers(vports, peers, None, &flushed);
+}
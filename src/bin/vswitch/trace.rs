@@ -0,0 +1,434 @@
+//! Packet tracer for the vswitch
+//!
+//! This builds a frame from a description such as
+//! `in_port=1 src=aa:bb:cc:dd:ee:01 dst=ff:ff:ff:ff:ff:ff vlan=10`
+//! and reports how the vswitch would handle it given its current
+//! MAC table and ports, without learning from or forwarding it
+
+use crate::{
+    always_flooded,
+    igmp::{IgmpSnooper, Snooped},
+    port_security::{self, MacLimitAction},
+    ports::PortTable,
+    segment_peers,
+    settings::{AclAction, Settings},
+    snooped_forwarding,
+    vlan::Domain,
+    Forwarding, MacTables, VportAddr, Vports, DEFAULT_SEGMENT,
+};
+use l2vpn::{
+    control::is_control_frame,
+    filter::parse_ether_type,
+    frame::EthernetFrame,
+    mac::MacAddr,
+    stp::PortState,
+    switching::DropReason,
+    utilities::{get_frame_log_msg, ETHER_FRAME_MIN, VLAN_ETHER_TYPE},
+};
+use std::{net::Ipv4Addr, time::Instant};
+
+/// EtherType of IPv4
+const IPV4_ETHER_TYPE: u16 = 0x0800;
+
+/// Keys which can be used to describe a frame
+pub const TRACE_KEYS: [&str; 8] = [
+    "in_port=",
+    "src=",
+    "dst=",
+    "vlan=",
+    "type=",
+    "ip_src=",
+    "ip_dst=",
+    "ip_proto=",
+];
+
+/// Frame described by the arguments to the trace command
+struct TraceFrame {
+    in_port: Option<u32>,
+    frame: Vec<u8>,
+}
+
+/// Returns a report of how the frame described by args would be
+/// handled, or an error if the description is invalid
+pub fn trace(
+    args: &[&str],
+    mac_tables: &MacTables,
+    ports: &PortTable<VportAddr>,
+    peers: &[VportAddr],
+    settings: &Settings,
+    vports: &Vports,
+    snooper: Option<&IgmpSnooper>,
+) -> Result<String, String> {
+    let TraceFrame { in_port, mut frame } = parse_frame(args)?;
+    let (src_mac, dst_mac) = EthernetFrame::parse(&frame)
+        .map(|eth| (eth.src, eth.dst))
+        .map_err(|e| e.to_string())?;
+
+    let mut report = vec![format!("Frame: {}", get_frame_log_msg(&frame, frame.len()))];
+
+    /* Find the vport which the frame would have been received from */
+    let src_vport = match in_port {
+        Some(id) => match ports.find(id) {
+            Some(src_vport) => {
+                report.push(format!("Ingress: port {} ({})", id, src_vport));
+                Some(src_vport)
+            }
+            None => return Err(format!("No vport is connected as port {}", id)),
+        },
+        None => {
+            report.push("Ingress: a vport which hasn't been seen before".to_string());
+            None
+        }
+    };
+
+    /* Frames are only forwarded within the segment of the ingress port */
+    let segment = src_vport.map_or(DEFAULT_SEGMENT, |src_vport| vports.segment(&src_vport));
+    if segment != DEFAULT_SEGMENT {
+        report.push(format!("Segment: {}", segment));
+    }
+    let peers = segment_peers(peers, segment);
+
+    if src_vport.is_some_and(|src_vport| ports.is_shutdown(&src_vport)) {
+        report.push("Result: dropped, as the ingress port is shut down".to_string());
+        return Ok(report.join("\n"));
+    }
+
+    if is_control_frame(&frame) {
+        report.push("Result: consumed by the vswitch as a control message".to_string());
+        return Ok(report.join("\n"));
+    }
+
+    let stp_state = src_vport
+        .and_then(|src_vport| ports.get(&src_vport))
+        .map_or(PortState::Forwarding, |port| port.stp);
+    if !stp_state.learns() {
+        report.push(format!(
+            "Result: dropped, as STP has the ingress port {} ({})",
+            stp_state.name(),
+            DropReason::StpBlocked.name()
+        ));
+        return Ok(report.join("\n"));
+    }
+
+    /* Frames are only forwarded within their VLAN, which the ingress port may decide */
+    let port_vlan = src_vport
+        .and_then(|src_vport| ports.get(&src_vport))
+        .map(|port| port.vlan.clone())
+        .unwrap_or_default();
+    let vlan = match port_vlan.ingress(&frame) {
+        Ok(vlan) => vlan,
+        Err(reason) => {
+            report.push(format!("Result: dropped {} ({})", reason, reason.name()));
+            return Ok(report.join("\n"));
+        }
+    };
+    if let Some(vlan) = vlan {
+        report.push(format!("VLAN: {}", vlan));
+    }
+    if let Some(tagged) = port_vlan.tag(&frame, vlan) {
+        frame = tagged;
+    }
+    let domain = Domain { segment, vlan };
+    let mut mac_table = mac_tables.get(&domain).cloned().unwrap_or_default();
+
+    if let Some(index) = settings.acl_rule(&frame, in_port.unwrap_or(0), None) {
+        let rule = &settings.acl[index];
+        report.push(format!(
+            "ACL: matches rule {}: {} {} {}",
+            index + 1,
+            rule.binding,
+            rule.action.name(),
+            rule.text
+        ));
+        if rule.action == AclAction::Deny {
+            report.push("Result: dropped, as the ACL denies it".to_string());
+            return Ok(report.join("\n"));
+        }
+    }
+
+    /* A new source MAC may take the ingress port over the MAC limit */
+    if let (Some(limit), Some(src_vport)) = (settings.mac_limit, src_vport) {
+        if settings.learning
+            && !peers.contains(&src_vport)
+            && !settings.static_macs.contains(&src_mac)
+            && port_security::violates(
+                &limit,
+                mac_tables,
+                &settings.static_macs,
+                &src_vport,
+                &src_mac,
+            )
+        {
+            report.push(format!(
+                "MAC limit: {} would take the ingress port over its limit of {} MAC(s)",
+                src_mac, limit.max
+            ));
+            match limit.action {
+                MacLimitAction::Log => {}
+                MacLimitAction::Drop => {
+                    report.push(format!(
+                        "Result: dropped {} ({})",
+                        DropReason::MacLimit,
+                        DropReason::MacLimit.name()
+                    ));
+                    return Ok(report.join("\n"));
+                }
+                MacLimitAction::Shutdown => {
+                    report.push(format!(
+                        "Result: dropped, and the ingress port shut down ({})",
+                        DropReason::MacLimit.name()
+                    ));
+                    return Ok(report.join("\n"));
+                }
+            }
+        }
+    }
+
+    /* Report what would be learned from the source MAC */
+    let learned_on = mac_table.get(&src_mac).copied();
+    let learning = match (learned_on, src_vport) {
+        _ if settings.static_macs.contains(&src_mac) => {
+            "is a static MAC, so would not be learned".to_string()
+        }
+        _ if !settings.learning => "would not be learned, as learning is off".to_string(),
+        (Some(learned_on), Some(src_vport)) if learned_on == src_vport => {
+            "already learned on the ingress port".to_string()
+        }
+        (Some(learned_on), _) => format!("would move from {}", port_name(ports, &learned_on)),
+        (None, _) => "would be learned on the ingress port".to_string(),
+    };
+    report.push(format!("Learning: {} {}", src_mac, learning));
+
+    if !stp_state.forwards() {
+        report.push(format!(
+            "Result: dropped, as STP has the ingress port learning, but not yet forwarding ({})",
+            DropReason::StpBlocked.name()
+        ));
+        return Ok(report.join("\n"));
+    }
+
+    /*
+     * The destination is looked up after learning, so apply
+     * the learning outcome to the segment's copy of the table
+     */
+    match src_vport {
+        _ if settings.static_macs.contains(&src_mac) || !settings.learning => {}
+        Some(src_vport) => {
+            mac_table.insert(src_mac, src_vport);
+        }
+        None => {
+            mac_table.remove(&src_mac);
+        }
+    }
+
+    let flooded = always_flooded(peers, ports, vports, domain, src_vport.as_ref());
+    let snooped = match snooper {
+        Some(snooper) => snooper.forwarding(domain, &frame, peers, Instant::now()),
+        None => Snooped::Normal,
+    };
+    if let Snooped::To(_) = snooped {
+        report.push("IGMP snooping: sent to the group's members and router ports".to_string());
+    }
+    let forwarding = snooped_forwarding(
+        snooped,
+        &mac_table,
+        ports,
+        &flooded,
+        settings,
+        src_vport.as_ref(),
+        &frame,
+    );
+
+    /* Egress ACLs leave out the vports whose ports deny the frame */
+    let denies = |dst_vport: &VportAddr| {
+        let out_port = ports.get(dst_vport).map_or(0, |port| port.id);
+        settings
+            .acl_rule(&frame, in_port.unwrap_or(0), Some(out_port))
+            .filter(|index| settings.acl[*index].action == AclAction::Deny)
+    };
+    let mut egress_denied = |dst_vport: &VportAddr| match denies(dst_vport) {
+        Some(index) => {
+            report.push(format!(
+                "Egress ACL: {} is denied it by rule {}",
+                port_name(ports, dst_vport),
+                index + 1
+            ));
+            true
+        }
+        None => false,
+    };
+    let forwarding = match forwarding {
+        Forwarding::Unicast(dst_vport) if egress_denied(&dst_vport) => {
+            Forwarding::Drop(DropReason::AclDenied)
+        }
+        Forwarding::Broadcast(mut dst_vports) => {
+            dst_vports.retain(|dst_vport| !egress_denied(dst_vport));
+            Forwarding::Broadcast(dst_vports)
+        }
+        forwarding => forwarding,
+    };
+
+    let result = match forwarding {
+        Forwarding::Unicast(dst_vport) if Some(dst_vport) == src_vport => {
+            "unicast back out of the ingress port".to_string()
+        }
+        Forwarding::Unicast(dst_vport) => format!("unicast to {}", port_name(ports, &dst_vport)),
+        Forwarding::Broadcast(dst_vports) if dst_vports.is_empty() => {
+            "broadcast, but there are no other vports to send to".to_string()
+        }
+        Forwarding::Broadcast(dst_vports) => format!(
+            "broadcast to {}",
+            dst_vports
+                .iter()
+                .map(|dst_vport| port_name(ports, dst_vport))
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+        Forwarding::Drop(DropReason::NoListeners) => format!(
+            "dropped {} ({})",
+            DropReason::NoListeners,
+            DropReason::NoListeners.name()
+        ),
+        Forwarding::Drop(DropReason::AclDenied) => format!(
+            "dropped, as the egress ACL denies it ({})",
+            DropReason::AclDenied.name()
+        ),
+        Forwarding::Drop(reason) => match mac_table.get(&dst_mac) {
+            Some(dst_vport) if reason == DropReason::StpBlocked => format!(
+                "dropped, as STP is blocking {} ({})",
+                port_name(ports, dst_vport),
+                reason.name()
+            ),
+            Some(dst_vport) if reason == DropReason::SplitHorizon => format!(
+                "dropped, as {} is in the ingress port's split-horizon group ({})",
+                port_name(ports, dst_vport),
+                reason.name()
+            ),
+            Some(dst_vport) => format!(
+                "dropped, as {} is shut down ({})",
+                port_name(ports, dst_vport),
+                reason.name()
+            ),
+            None => format!("dropped, as {} is unknown ({})", dst_mac, reason.name()),
+        },
+    };
+    report.push(format!("Result: {}", result));
+
+    Ok(report.join("\n"))
+}
+
+/// Returns how the vport at addr is described in the trace
+fn port_name(ports: &PortTable<VportAddr>, addr: &VportAddr) -> String {
+    match ports.get(addr) {
+        Some(port) => format!("port {} ({})", port.id, addr),
+        None => addr.to_string(),
+    }
+}
+
+/// Parse a frame description made up of key=value arguments
+fn parse_frame(args: &[&str]) -> Result<TraceFrame, String> {
+    let mut in_port = None;
+    let mut src_mac = None;
+    let mut dst_mac = None;
+    let mut vlan = None;
+    let mut ether_type = None;
+    let mut ip_src = None;
+    let mut ip_dst = None;
+    let mut ip_proto = None;
+
+    for arg in args {
+        let Some((key, value)) = arg.split_once('=') else {
+            return Err(format!("Expected key=value but got '{}'", arg));
+        };
+
+        let bad_value =
+            |e: &dyn ToString| format!("Invalid {} '{}': {}", key, value, e.to_string());
+        match key {
+            "in_port" => in_port = Some(value.parse::<u32>().map_err(|e| bad_value(&e))?),
+            "src" => {
+                src_mac = Some(
+                    value
+                        .parse::<MacAddr>()
+                        .map_err(|_| bad_value(&"not a MAC"))?,
+                )
+            }
+            "dst" => {
+                dst_mac = Some(
+                    value
+                        .parse::<MacAddr>()
+                        .map_err(|_| bad_value(&"not a MAC"))?,
+                )
+            }
+            "vlan" => match value.parse::<u16>() {
+                Ok(vid) if vid < 4095 => vlan = Some(vid),
+                Ok(_) => return Err(bad_value(&"VLAN IDs must be less than 4095")),
+                Err(e) => return Err(bad_value(&e)),
+            },
+            "type" => ether_type = Some(parse_ether_type(value).map_err(|e| e.to_string())?),
+            "ip_src" => ip_src = Some(value.parse::<Ipv4Addr>().map_err(|e| bad_value(&e))?),
+            "ip_dst" => ip_dst = Some(value.parse::<Ipv4Addr>().map_err(|e| bad_value(&e))?),
+            "ip_proto" => ip_proto = Some(value.parse::<u8>().map_err(|e| bad_value(&e))?),
+            _ => return Err(format!("Unknown key '{}'", key)),
+        }
+    }
+
+    let src_mac = src_mac.ok_or("Missing src=<mac>")?;
+    let dst_mac = dst_mac.ok_or("Missing dst=<mac>")?;
+
+    let has_ip = ip_src.is_some() || ip_dst.is_some() || ip_proto.is_some();
+    let ether_type = match ether_type {
+        Some(ether_type) if has_ip && ether_type != IPV4_ETHER_TYPE => {
+            return Err("IP header fields given, but type is not IPv4".to_string())
+        }
+        Some(ether_type) => ether_type,
+        None if has_ip => IPV4_ETHER_TYPE,
+        None => return Err("Missing type=<ethertype> or IP header fields".to_string()),
+    };
+
+    let mut frame = Vec::with_capacity(ETHER_FRAME_MIN);
+    frame.extend_from_slice(&dst_mac.0);
+    frame.extend_from_slice(&src_mac.0);
+    if let Some(vid) = vlan {
+        frame.extend_from_slice(&VLAN_ETHER_TYPE.to_be_bytes());
+        frame.extend_from_slice(&vid.to_be_bytes());
+    }
+    frame.extend_from_slice(&ether_type.to_be_bytes());
+
+    if has_ip {
+        frame.extend_from_slice(&ipv4_header(
+            ip_src.unwrap_or(Ipv4Addr::UNSPECIFIED),
+            ip_dst.unwrap_or(Ipv4Addr::UNSPECIFIED),
+            ip_proto.unwrap_or(0),
+        ));
+    }
+
+    /* Pad the frame as the vswitch would */
+    if frame.len() < ETHER_FRAME_MIN {
+        frame.resize(ETHER_FRAME_MIN, 0);
+    }
+
+    Ok(TraceFrame { in_port, frame })
+}
+
+/// Returns a minimal IPv4 header with no payload
+fn ipv4_header(src: Ipv4Addr, dst: Ipv4Addr, proto: u8) -> [u8; 20] {
+    let mut header = [0u8; 20];
+    header[0] = 0x45; /* Version 4, 5 word header */
+    header[2..4].copy_from_slice(&20u16.to_be_bytes());
+    header[8] = 64; /* TTL */
+    header[9] = proto;
+    header[12..16].copy_from_slice(&src.octets());
+    header[16..20].copy_from_slice(&dst.octets());
+
+    /* Header checksum, which is the ones' complement of the ones' complement sum */
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+        .sum();
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    header[10..12].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+
+    header
+}
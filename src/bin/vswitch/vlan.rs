@@ -0,0 +1,316 @@
+//! 802.1Q VLANs in the vswitch
+//!
+//! Each segment is divided into VLANs, which are separate broadcast
+//! domains with their own MAC tables, so frames are only forwarded and
+//! flooded within the VLAN they were received in. Frames without a VLAN
+//! tag are in their segment's untagged domain
+//!
+//! Ports are trunks unless an admin client says otherwise, and carry
+//! frames tagged with their VLAN. An access port is in a single VLAN,
+//! for hosts which don't tag their frames: the untagged frames received
+//! from it are put in its VLAN, and frames are sent to it untagged
+//!
+//! A trunk may be limited to a list of allowed VLANs, and may have a
+//! native VLAN, which its untagged frames are put in, and whose frames
+//! are sent to it untagged, so a host can reach one VLAN untagged and
+//! others tagged. A trunk without a native VLAN carries untagged frames
+//! in the segment's untagged domain
+//!
+//! A QinQ tunnel port is in a single service VLAN (S-VLAN), for a
+//! customer whose frames may carry VLAN tags of their own. Every frame
+//! received from it has an 802.1ad tag for the S-VLAN pushed in front of
+//! any tags it has, and is forwarded by that outer tag, which is popped
+//! when frames are sent to it, so customers' VLANs can't collide. Trunks
+//! carry the frames of S-VLANs with their 802.1ad tags, and S-VLAN IDs
+//! share the space of VLAN IDs
+
+use l2vpn::switching::DropReason;
+use l2vpn::{
+    frame::{pop_vlan, push_vlan, rewrite_vid, VlanHeader},
+    utilities::{vlan_tag, VlanTag, QINQ_ETHER_TYPE, VLAN_ETHER_TYPE},
+};
+use std::fmt;
+
+/// Modes which PortVlan::parse accepts
+const VLAN_MODE_USAGE: &str = "Expected 'access <vlan_id>', 'tunnel <svlan_id>' or \
+                               'trunk [native <vlan_id>] [allowed <vlan_list>]'";
+
+/// Segment, or VLAN within a segment, which is a broadcast domain of its own
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Domain {
+    pub segment: u32,
+    /// VLAN ID of the frames, or None for untagged frames
+    pub vlan: Option<u16>,
+}
+
+impl fmt::Display for Domain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.vlan {
+            Some(vlan) => write!(f, "{}/{}", self.segment, vlan),
+            None => write!(f, "{}", self.segment),
+        }
+    }
+}
+
+/// Which VLANs a port carries
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PortVlan {
+    /// Carries frames tagged with their VLAN, and untagged frames
+    Trunk {
+        /// VLAN which untagged frames are in, rather than the untagged domain
+        native: Option<u16>,
+        /// VLANs carried, or None for every VLAN
+        allowed: Option<VlanList>,
+    },
+    /// Carries the frames of one VLAN, untagged
+    Access(u16),
+    /// Carries the frames of one S-VLAN, without their outer tag
+    Tunnel(u16),
+}
+
+impl Default for PortVlan {
+    /// Ports are trunks carrying every VLAN, with untagged frames in the untagged domain
+    fn default() -> PortVlan {
+        PortVlan::Trunk {
+            native: None,
+            allowed: None,
+        }
+    }
+}
+
+impl PortVlan {
+    /// Parse the mode given to the vlan admin command after the port ID,
+    /// i.e. "access <vlan_id>", "tunnel <svlan_id>", or "trunk" with an
+    /// optional native VLAN and allowed VLANs, in either order
+    pub fn parse(args: &[&str]) -> Result<PortVlan, String> {
+        match args {
+            ["access", vlan] => Ok(PortVlan::Access(parse_vlan_id(vlan)?)),
+            ["tunnel", svid] => Ok(PortVlan::Tunnel(parse_vlan_id(svid)?)),
+            ["trunk", options @ ..] => {
+                let (mut native, mut allowed) = (None, None);
+                for option in options.chunks(2) {
+                    match option {
+                        ["native", vlan] if native.is_none() => native = Some(parse_vlan_id(vlan)?),
+                        ["allowed", vlans] if allowed.is_none() => {
+                            allowed = Some(VlanList::parse(vlans)?)
+                        }
+                        _ => return Err(VLAN_MODE_USAGE.to_string()),
+                    }
+                }
+                if let (Some(native), Some(allowed)) = (native, &allowed) {
+                    if !allowed.contains(native) {
+                        return Err(format!("Native VLAN {} isn't allowed on the trunk", native));
+                    }
+                }
+                Ok(PortVlan::Trunk { native, allowed })
+            }
+            _ => Err(VLAN_MODE_USAGE.to_string()),
+        }
+    }
+
+    /// Returns the mode as given to the vlan admin command, which parse reads back
+    pub fn args(&self) -> String {
+        match self {
+            PortVlan::Trunk { native, allowed } => {
+                let mut args = String::from("trunk");
+                if let Some(native) = native {
+                    args += &format!(" native {}", native);
+                }
+                if let Some(allowed) = allowed {
+                    args += &format!(" allowed {}", allowed);
+                }
+                args
+            }
+            PortVlan::Access(vid) => format!("access {}", vid),
+            PortVlan::Tunnel(svid) => format!("tunnel {}", svid),
+        }
+    }
+
+    /// Returns the VLAN which frame, received on a port in this
+    /// mode, is in, or the reason it is dropped if the port can't
+    /// carry it. Access ports accept untagged and priority tagged
+    /// frames, and those already tagged with their own VLAN
+    pub fn ingress(&self, frame: &[u8]) -> Result<Option<u16>, DropReason> {
+        match self {
+            PortVlan::Trunk { native, allowed } => {
+                let vlan = frame_vlan(frame).or(*native);
+                match (vlan, allowed) {
+                    (Some(vlan), Some(allowed)) if !allowed.contains(vlan) => {
+                        Err(DropReason::VlanMismatch)
+                    }
+                    _ => Ok(vlan),
+                }
+            }
+            PortVlan::Access(vid) => match vlan_tag(frame).map(|tag| tag.vid) {
+                None | Some(0) => Ok(Some(*vid)),
+                Some(tagged) if tagged == *vid && frame_vlan(frame).is_some() => Ok(Some(*vid)),
+                Some(_) => Err(DropReason::VlanMismatch),
+            },
+            /* Customers' own tags are carried, not interpreted */
+            PortVlan::Tunnel(svid) => Ok(Some(*svid)),
+        }
+    }
+
+    /// Returns frame, received on a port in this mode and found to be in
+    /// vlan by ingress, tagged as it is forwarded, or None if it already
+    /// is. Tunnel ports push an outer tag, whatever tags frame already has
+    pub fn tag(&self, frame: &[u8], vlan: Option<u16>) -> Option<Vec<u8>> {
+        match (self, vlan) {
+            (PortVlan::Tunnel(_), Some(svid)) => Some(push_tag(frame, QINQ_ETHER_TYPE, svid)),
+            _ if vlan != frame_vlan(frame) => Some(retag(frame, vlan)),
+            _ => None,
+        }
+    }
+
+    /// Returns the only VLAN the port carries, whose frames are sent
+    /// to it without their outer tag, unless it is a trunk
+    pub fn untagged_vlan(&self) -> Option<u16> {
+        match self {
+            PortVlan::Trunk { .. } => None,
+            PortVlan::Access(vid) | PortVlan::Tunnel(vid) => Some(*vid),
+        }
+    }
+
+    /// Returns true if the frames in vlan are sent to the port without
+    /// their outer tag, as it is the port's own or native VLAN
+    pub fn untags(&self, vlan: Option<u16>) -> bool {
+        match self {
+            PortVlan::Trunk { native, .. } => vlan.is_some() && vlan == *native,
+            PortVlan::Access(vid) | PortVlan::Tunnel(vid) => vlan == Some(*vid),
+        }
+    }
+
+    /// Returns true if the port carries the frames in vlan
+    pub fn carries(&self, vlan: Option<u16>) -> bool {
+        match (self, vlan) {
+            (PortVlan::Trunk { native, .. }, None) => native.is_none(),
+            (PortVlan::Trunk { allowed, .. }, Some(vlan)) => allowed
+                .as_ref()
+                .is_none_or(|allowed| allowed.contains(vlan)),
+            (PortVlan::Access(vid) | PortVlan::Tunnel(vid), _) => vlan == Some(*vid),
+        }
+    }
+}
+
+impl fmt::Display for PortVlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PortVlan::Trunk { native, allowed } => {
+                f.write_str("a trunk")?;
+                if let Some(native) = native {
+                    write!(f, " with native VLAN {}", native)?;
+                }
+                match allowed {
+                    Some(allowed) => write!(f, " carrying VLANs {}", allowed),
+                    None => Ok(()),
+                }
+            }
+            PortVlan::Access(vid) => write!(f, "an access port in VLAN {}", vid),
+            PortVlan::Tunnel(svid) => write!(f, "a QinQ tunnel port in S-VLAN {}", svid),
+        }
+    }
+}
+
+/// VLANs allowed on a trunk, as ranges of VLAN IDs
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VlanList {
+    ranges: Vec<(u16, u16)>,
+}
+
+impl VlanList {
+    /// Parse a comma separated list of VLAN IDs and ranges
+    /// of them, such as 10,20-29
+    pub fn parse(value: &str) -> Result<VlanList, String> {
+        let ranges = value
+            .split(',')
+            .map(|range| {
+                let (first, last) = range.split_once('-').unwrap_or((range, range));
+                let (first, last) = (parse_vlan_id(first)?, parse_vlan_id(last)?);
+                if first > last {
+                    return Err(format!("VLAN range '{}' is backwards", range));
+                }
+                Ok((first, last))
+            })
+            .collect::<Result<Vec<(u16, u16)>, String>>()?;
+        Ok(VlanList { ranges })
+    }
+
+    /// Returns true if vlan is in the list
+    pub fn contains(&self, vlan: u16) -> bool {
+        self.ranges
+            .iter()
+            .any(|(first, last)| (*first..=*last).contains(&vlan))
+    }
+}
+
+impl fmt::Display for VlanList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ranges: Vec<String> = self
+            .ranges
+            .iter()
+            .map(|(first, last)| match first == last {
+                true => first.to_string(),
+                false => format!("{}-{}", first, last),
+            })
+            .collect();
+        f.write_str(&ranges.join(","))
+    }
+}
+
+/// Parse a VLAN ID, which must be from 1 to 4094, as 0 means
+/// a frame is only tagged for its priority, and 4095 is reserved
+pub fn parse_vlan_id(value: &str) -> Result<u16, String> {
+    value
+        .parse::<u16>()
+        .ok()
+        .filter(|vlan| (1..4095).contains(vlan))
+        .ok_or_else(|| format!("Expected a VLAN ID from 1 to 4094, got '{}'", value))
+}
+
+/// Returns the VLAN ID of frame's outer 802.1Q or 802.1ad tag, or
+/// None if it is untagged, or is only tagged for its priority
+pub fn frame_vlan(frame: &[u8]) -> Option<u16> {
+    let tpid = frame.get(12..14)?;
+    if tpid != VLAN_ETHER_TYPE.to_be_bytes() && tpid != QINQ_ETHER_TYPE.to_be_bytes() {
+        return None;
+    }
+    vlan_tag(frame).map(|tag| tag.vid).filter(|vid| *vid != 0)
+}
+
+/// Returns a copy of frame with its outer tag, if any, replaced by one
+/// for vlan, of the same kind (802.1Q by default), or removed if None
+pub fn retag(frame: &[u8], vlan: Option<u16>) -> Vec<u8> {
+    let mut copy = frame.to_vec();
+    match vlan {
+        /* The priority of the original tag, if any, is kept */
+        Some(vlan) => {
+            if rewrite_vid(&mut copy, vlan).is_none() {
+                push_tag_onto(&mut copy, VlanHeader::new(vlan));
+            }
+        }
+        None => {
+            pop_vlan(&mut copy);
+        }
+    }
+    copy
+}
+
+/// Returns a copy of frame with a tag for vid, whose EtherType is tpid,
+/// pushed in front of any tags it has, with the priority of its outer tag
+fn push_tag(frame: &[u8], tpid: u16, vid: u16) -> Vec<u8> {
+    let pcp = vlan_tag(frame).map(|tag| tag.pcp).unwrap_or(0);
+    let mut copy = frame.to_vec();
+    let tag = VlanTag {
+        pcp,
+        dei: false,
+        vid,
+    };
+    push_tag_onto(&mut copy, VlanHeader { tpid, tag });
+    copy
+}
+
+/// Push header onto frame, whose Ethernet header the vswitch has
+/// already parsed, so which holds the MACs the tag goes after
+fn push_tag_onto(frame: &mut Vec<u8>, header: VlanHeader) {
+    push_vlan(frame, header).expect("switched frames hold their MACs");
+}
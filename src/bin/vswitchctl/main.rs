@@ -0,0 +1,189 @@
+//! Admin client for the vswitch
+//!
+//! This sends a command to the admin socket of a running
+//! vswitch and prints its response. Run `vswitchctl <admin_socket> help`
+//! for the list of commands the vswitch supports
+//!
+//! If no command is given, an interactive console is started,
+//! which completes commands with tab (using the vswitch's own
+//! list of commands), and remembers previous commands
+//!
+//! `vswitchctl <admin_socket> top` shows a live dashboard of the vswitch,
+//! and `vswitchctl <admin_socket> monitor [<filter>]` prints a summary of
+//! each frame the vswitch handles which matches the filter
+//!
+//! Usage: vswitchctl <admin_socket> [top | monitor [<filter>] | <command> [<args>]...]
+
+mod top;
+
+use l2vpn::admin::AdminClient;
+use rustyline::{
+    completion::Completer, error::ReadlineError, history::DefaultHistory, Context, Editor, Helper,
+    Highlighter, Hinter, Validator,
+};
+use std::{cell::RefCell, env, path::PathBuf, process::ExitCode};
+
+const USAGE: &str =
+    "Usage: vswitchctl <admin_socket> [top | monitor [<filter>] | <command> [<args>]...]";
+
+/// Name of the file in the user's home directory which keeps the console's history
+const HISTORY_FILE: &str = ".vswitchctl_history";
+
+/// Line editor helper which asks the vswitch how to complete commands
+#[derive(Helper, Highlighter, Hinter, Validator)]
+struct AdminHelper {
+    client: RefCell<AdminClient>,
+}
+
+impl Completer for AdminHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let partial = &line[..pos];
+        let start = partial
+            .rfind(char::is_whitespace)
+            .map(|i| i + 1)
+            .unwrap_or(0);
+
+        /* Offer no completions rather than failing if the vswitch has gone */
+        let candidates = match self
+            .client
+            .borrow_mut()
+            .request(&format!("complete {}", partial))
+        {
+            Ok(response) => response
+                .lines()
+                .filter(|candidate| !candidate.is_empty())
+                .map(String::from)
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+
+        Ok((start, candidates))
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().collect();
+
+    if args.len() < 2 {
+        eprintln!(
+            "Expected at least 2 command line arguments and got {}",
+            args.len()
+        );
+        eprintln!("{}", USAGE);
+        return ExitCode::FAILURE;
+    }
+
+    let mut client = match AdminClient::connect(&args[1]) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Could not connect to admin socket '{}': {}", args[1], e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if args.len() == 2 {
+        return match console(client) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("Console failed with error: {}", e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    if args.len() == 3 && args[2] == "top" {
+        return match top::top(&mut client, &args[1]) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("Dashboard failed with error: {}", e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    if args[2] == "monitor" {
+        let result = client.monitor(&args[3..].join(" "), |summary| println!("{}", summary));
+        if let Err(e) = result {
+            eprintln!("{}", e);
+        }
+        return ExitCode::FAILURE;
+    }
+
+    match client.request(&args[2..].join(" ")) {
+        Ok(response) => {
+            println!("{}", response);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Run an interactive console which sends each line
+/// entered to the vswitch, until the user quits
+fn console(client: AdminClient) -> rustyline::Result<()> {
+    let mut editor: Editor<AdminHelper, DefaultHistory> = Editor::new()?;
+    editor.set_helper(Some(AdminHelper {
+        client: RefCell::new(client),
+    }));
+
+    let history_path = env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE));
+    if let Some(path) = &history_path {
+        /* There is no history the first time the console is used */
+        let _ = editor.load_history(path);
+    }
+
+    println!("Type 'help' for the vswitch's commands, and 'quit' to leave");
+
+    loop {
+        let line = match editor.readline("vswitch> ") {
+            Ok(line) => line,
+            /* Ctrl-C abandons the current line, as in a shell */
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e),
+        };
+
+        let command = line.trim();
+        if command.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(command)?;
+
+        if command == "quit" || command == "exit" {
+            break;
+        }
+
+        /* Monitoring only stops when the client disconnects, which would end the console */
+        if command.split_whitespace().next() == Some("monitor") {
+            eprintln!("error: monitor runs until interrupted, so run it as 'vswitchctl <admin_socket> monitor [<filter>]'");
+            continue;
+        }
+
+        let response = editor
+            .helper()
+            .unwrap()
+            .client
+            .borrow_mut()
+            .request(command);
+        match response {
+            Ok(response) => println!("{}", response),
+            Err(e) => eprintln!("error: {}", e),
+        }
+    }
+
+    if let Some(path) = &history_path {
+        editor.save_history(path)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,316 @@
+//! Live dashboard for a running vswitch
+//!
+//! `vswitchctl <admin_socket> top` polls the vswitch's stats once
+//! a second and redraws the terminal in place, showing the rate of
+//! traffic through each port, whether each vport is still being
+//! heard from, the 99th percentile forwarding latency and round
+//! trip time of each port, the size of the MAC table and the most
+//! recent events
+
+use l2vpn::admin::AdminClient;
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Style, Stylize},
+    widgets::{Block, List, Paragraph, Row, Table},
+    DefaultTerminal, Frame,
+};
+use std::{
+    collections::HashMap,
+    error::Error,
+    time::{Duration, Instant},
+};
+
+/// How often the stats are polled and the dashboard redrawn
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a vport can go without sending anything before it is
+/// shown as stale, which allows for a couple of missed hellos
+const STALE_AFTER_MS: u64 = 30_000;
+
+/// Number of events shown at the bottom of the dashboard
+const EVENTS_SHOWN: u16 = 10;
+
+/// State of a port, as reported by the vswitch's stats command
+struct PortStats {
+    id: u32,
+    vport: String,
+    session: String,
+    rx_frames: u64,
+    rx_bytes: u64,
+    tx_frames: u64,
+    tx_bytes: u64,
+    idle_ms: u64,
+}
+
+/// Everything reported by the vswitch's stats command
+struct Stats {
+    macs: usize,
+    ports: Vec<PortStats>,
+    /// 99th percentile latencies in microseconds, keyed by port ID and histogram name
+    p99: HashMap<(u32, String), u64>,
+    events: Vec<(u64, String)>,
+}
+
+/// Per-second traffic through a port, since the previous poll
+#[derive(Default)]
+struct Rates {
+    rx_frames: f64,
+    rx_bytes: f64,
+    tx_frames: f64,
+    tx_bytes: f64,
+}
+
+/// Show the dashboard until the user presses q or Esc
+pub fn top(client: &mut AdminClient, socket_path: &str) -> Result<(), Box<dyn Error>> {
+    let mut terminal = ratatui::try_init()?;
+    let result = run(&mut terminal, client, socket_path);
+
+    /* Always give the terminal back, even if the vswitch went away */
+    ratatui::try_restore()?;
+    result
+}
+
+/// Poll the vswitch and redraw the dashboard, until the user quits
+fn run(
+    terminal: &mut DefaultTerminal,
+    client: &mut AdminClient,
+    socket_path: &str,
+) -> Result<(), Box<dyn Error>> {
+    let mut previous: Option<(Instant, Stats)> = None;
+
+    loop {
+        let stats = parse_stats(&client.request("stats")?)?;
+        let now = Instant::now();
+
+        let rates = match &previous {
+            Some((then, prev_stats)) => rates(prev_stats, &stats, now - *then),
+            None => HashMap::new(),
+        };
+        terminal.draw(|frame| draw(frame, socket_path, &stats, &rates))?;
+        previous = Some((now, stats));
+
+        /* Wait for the next refresh, unless the user quits first */
+        let deadline = now + REFRESH_INTERVAL;
+        while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+            if !event::poll(timeout)? {
+                break;
+            }
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press
+                    && matches!(key.code, KeyCode::Char('q') | KeyCode::Esc)
+                {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Parse the response to the stats command
+fn parse_stats(response: &str) -> Result<Stats, String> {
+    let mut stats = Stats {
+        macs: 0,
+        ports: Vec::new(),
+        p99: HashMap::new(),
+        events: Vec::new(),
+    };
+
+    for line in response.lines() {
+        let bad_line = || format!("Could not parse stats line '{}'", line);
+        let (kind, fields) = line.split_once(' ').ok_or_else(bad_line)?;
+
+        match kind {
+            "macs" => stats.macs = fields.parse().map_err(|_| bad_line())?,
+            "port" => {
+                let fields: Vec<&str> = fields.split(' ').collect();
+                let [id, vport, session, counters @ ..] = fields.as_slice() else {
+                    return Err(bad_line());
+                };
+                let counters = counters
+                    .iter()
+                    .map(|c| c.parse::<u64>())
+                    .collect::<Result<Vec<u64>, _>>()
+                    .map_err(|_| bad_line())?;
+                let [rx_frames, rx_bytes, tx_frames, tx_bytes, idle_ms] = counters[..] else {
+                    return Err(bad_line());
+                };
+
+                stats.ports.push(PortStats {
+                    id: id.parse().map_err(|_| bad_line())?,
+                    vport: vport.to_string(),
+                    session: session.to_string(),
+                    rx_frames,
+                    rx_bytes,
+                    tx_frames,
+                    tx_bytes,
+                    idle_ms,
+                });
+            }
+            "latency" => {
+                let fields: Vec<&str> = fields.split(' ').collect();
+                let [id, name, _samples, _p50, _p95, p99] = fields[..] else {
+                    return Err(bad_line());
+                };
+                stats.p99.insert(
+                    (id.parse().map_err(|_| bad_line())?, name.to_string()),
+                    p99.parse().map_err(|_| bad_line())?,
+                );
+            }
+            "event" => {
+                let (time, event) = fields.split_once(' ').ok_or_else(bad_line)?;
+                stats
+                    .events
+                    .push((time.parse().map_err(|_| bad_line())?, event.to_string()));
+            }
+            /* Ignore anything added by newer vswitches */
+            _ => {}
+        }
+    }
+
+    Ok(stats)
+}
+
+/// Returns the traffic rates of each port, keyed by port ID,
+/// given the stats polled elapsed time apart
+fn rates(previous: &Stats, current: &Stats, elapsed: Duration) -> HashMap<u32, Rates> {
+    let secs = elapsed.as_secs_f64();
+    let per_sec = |now: u64, then: u64| now.saturating_sub(then) as f64 / secs;
+
+    current
+        .ports
+        .iter()
+        .filter_map(|port| {
+            let prev = previous.ports.iter().find(|prev| prev.id == port.id)?;
+            Some((
+                port.id,
+                Rates {
+                    rx_frames: per_sec(port.rx_frames, prev.rx_frames),
+                    rx_bytes: per_sec(port.rx_bytes, prev.rx_bytes),
+                    tx_frames: per_sec(port.tx_frames, prev.tx_frames),
+                    tx_bytes: per_sec(port.tx_bytes, prev.tx_bytes),
+                },
+            ))
+        })
+        .collect()
+}
+
+/// Draw the dashboard
+fn draw(frame: &mut Frame, socket_path: &str, stats: &Stats, rates: &HashMap<u32, Rates>) {
+    let [header_area, ports_area, events_area] = Layout::vertical([
+        Constraint::Length(1),
+        Constraint::Min(4),
+        Constraint::Length(EVENTS_SHOWN + 2),
+    ])
+    .areas(frame.area());
+
+    let stale = stats
+        .ports
+        .iter()
+        .filter(|port| port.idle_ms >= STALE_AFTER_MS)
+        .count();
+    frame.render_widget(
+        Paragraph::new(format!(
+            "vswitch at {}: {} port(s), {} stale, {} MAC(s) learned (q to quit)",
+            socket_path,
+            stats.ports.len(),
+            stale,
+            stats.macs
+        ))
+        .bold(),
+        header_area,
+    );
+
+    let no_rates = Rates::default();
+    let rows = stats.ports.iter().map(|port| {
+        let rate = rates.get(&port.id).unwrap_or(&no_rates);
+        let health = match port.idle_ms < STALE_AFTER_MS {
+            true => "up".green(),
+            false => format!("stale {}s", port.idle_ms / 1000).yellow(),
+        };
+        let p99 = |name: &str| match stats.p99.get(&(port.id, name.to_string())) {
+            Some(micros) => latency(*micros),
+            None => "-".to_string(),
+        };
+        Row::new(vec![
+            port.id.to_string().into(),
+            port.vport.clone().into(),
+            port.session.chars().take(8).collect::<String>().into(),
+            health,
+            format!("{:.0}", rate.rx_frames).into(),
+            byte_rate(rate.rx_bytes).into(),
+            format!("{:.0}", rate.tx_frames).into(),
+            byte_rate(rate.tx_bytes).into(),
+            p99("forwarding").into(),
+            p99("rtt").into(),
+            port.rx_frames.to_string().into(),
+            port.tx_frames.to_string().into(),
+        ])
+    });
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Length(5),
+            Constraint::Min(22),
+            Constraint::Length(8),
+            Constraint::Length(10),
+            Constraint::Length(8),
+            Constraint::Length(10),
+            Constraint::Length(8),
+            Constraint::Length(10),
+            Constraint::Length(9),
+            Constraint::Length(9),
+            Constraint::Length(10),
+            Constraint::Length(10),
+        ],
+    )
+    .header(
+        Row::new([
+            "port", "vport", "session", "health", "rx fps", "rx rate", "tx fps", "tx rate",
+            "fwd p99", "rtt p99", "rx total", "tx total",
+        ])
+        .style(Style::new().bold()),
+    )
+    .block(Block::bordered().title(" Ports "));
+    frame.render_widget(table, ports_area);
+
+    /* Newest events first, with their time of day in UTC */
+    let events = stats
+        .events
+        .iter()
+        .rev()
+        .take(EVENTS_SHOWN as usize)
+        .map(|(time, event)| {
+            let secs = time % 86400;
+            format!(
+                "{:02}:{:02}:{:02}  {}",
+                secs / 3600,
+                secs / 60 % 60,
+                secs % 60,
+                event
+            )
+        });
+    frame.render_widget(
+        List::new(events).block(Block::bordered().title(" Recent events (UTC) ")),
+        events_area,
+    );
+}
+
+/// Returns a byte rate in human readable units
+fn byte_rate(bytes_per_sec: f64) -> String {
+    match bytes_per_sec {
+        b if b >= 1e9 => format!("{:.1} GB/s", b / 1e9),
+        b if b >= 1e6 => format!("{:.1} MB/s", b / 1e6),
+        b if b >= 1e3 => format!("{:.1} kB/s", b / 1e3),
+        b => format!("{:.0} B/s", b),
+    }
+}
+
+/// Returns a latency given in microseconds in human readable units
+fn latency(micros: u64) -> String {
+    match micros {
+        m if m >= 1_000_000 => format!("{:.1} s", m as f64 / 1e6),
+        m if m >= 1_000 => format!("{:.1} ms", m as f64 / 1e3),
+        m => format!("{} us", m),
+    }
+}
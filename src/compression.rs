@@ -0,0 +1,57 @@
+//! LZ4 compression of the frames between vports and the vswitch
+//!
+//! A vport can ask to compress its frames when it joins the vswitch,
+//! which saves underlay bandwidth on metered links when they carry
+//! compressible traffic. If the vswitch agrees, each of them compresses
+//! the frames it sends the other with LZ4, unless that wouldn't make
+//! them smaller. The MACs and hop limit tag are left as they are, and
+//! a flag in the tag says whether the rest of the frame is compressed,
+//! so frames which don't compress are sent as they are, and anything
+//! which hasn't agreed to compression is never sent a compressed frame
+
+use crate::{
+    tunnel::{
+        hop_limit_flags, set_hop_limit_flags, FLAG_COMPRESSED, HOP_LIMIT_TAG_LEN, TUNNEL_FRAME_MAX,
+    },
+    utilities::ETHER_HDR,
+};
+use lz4_flex::block::{compress_into, decompress_into, get_maximum_output_size};
+
+/// Offset of what is compressed, which follows the MACs and hop limit tag
+const BODY_OFFSET: usize = ETHER_HDR - 2 + HOP_LIMIT_TAG_LEN;
+
+/// Returns frame, which must carry a hop limit tag, with everything
+/// after its tag compressed, or None if that wouldn't make it smaller
+pub fn compress(frame: &[u8]) -> Option<Vec<u8>> {
+    let flags = hop_limit_flags(frame)?;
+    let (head, body) = frame.split_at(BODY_OFFSET);
+
+    let mut compressed = vec![0u8; BODY_OFFSET + get_maximum_output_size(body.len())];
+    let len = compress_into(body, &mut compressed[BODY_OFFSET..]).ok()?;
+    if BODY_OFFSET + len >= frame.len() {
+        return None;
+    }
+    compressed.truncate(BODY_OFFSET + len);
+    compressed[..BODY_OFFSET].copy_from_slice(head);
+    set_hop_limit_flags(&mut compressed, flags | FLAG_COMPRESSED);
+    Some(compressed)
+}
+
+/// Returns true if the rest of frame, after its hop limit tag, is compressed
+pub fn is_compressed(frame: &[u8]) -> bool {
+    hop_limit_flags(frame).is_some_and(|flags| flags & FLAG_COMPRESSED != 0)
+}
+
+/// Returns the frame which the compressed frame was made from, or None
+/// if it isn't valid LZ4, or would be larger than any frame can be
+pub fn decompress(frame: &[u8]) -> Option<Vec<u8>> {
+    let flags = hop_limit_flags(frame)?;
+    let (head, body) = frame.split_at(BODY_OFFSET);
+
+    let mut decompressed = vec![0u8; TUNNEL_FRAME_MAX];
+    let len = decompress_into(body, &mut decompressed[BODY_OFFSET..]).ok()?;
+    decompressed.truncate(BODY_OFFSET + len);
+    decompressed[..BODY_OFFSET].copy_from_slice(head);
+    set_hop_limit_flags(&mut decompressed, flags & !FLAG_COMPRESSED);
+    Some(decompressed)
+}
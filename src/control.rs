@@ -0,0 +1,498 @@
+//! In-band control messages between vports and the vswitch
+//!
+//! Control messages are carried in Ethernet frames with the IEEE
+//! local experimental EtherType, sent to a reserved multicast MAC.
+//! This means they travel over every transport exactly like data
+//! frames do, and the vswitch consumes them rather than forwarding
+//! them (older vswitches drop them as unknown multicast)
+
+use crate::{
+    error::ProtocolError,
+    mac::MacAddr,
+    stun::{NatType, PublicEndpoint},
+    utilities::{ETHER_HDR, ETHER_MTU},
+};
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+/// IEEE 802 local experimental EtherType 1
+pub const CONTROL_ETHER_TYPE: u16 = 0x88B5;
+
+/// Locally administered multicast MAC which control messages are sent to
+pub const CONTROL_MAC: [u8; 6] = [0x03, 0x4c, 0x32, 0x56, 0x50, 0x4e];
+
+/// Version of the control message format, which is
+/// the first byte of every control message
+const CONTROL_VERSION: u8 = 1;
+
+/* Message types, which are the second byte of every control message */
+const MSG_HELLO: u8 = 1;
+const MSG_ECHO_REQUEST: u8 = 2;
+const MSG_ECHO_REPLY: u8 = 3;
+const MSG_TOPOLOGY_CHANGE: u8 = 4;
+const MSG_GROUP_MEMBER: u8 = 5;
+const MSG_LAG_MEMBER: u8 = 6;
+const MSG_LEAVE: u8 = 7;
+const MSG_JOIN: u8 = 8;
+const MSG_JOINED: u8 = 9;
+const MSG_PATH_MTU_PROBE: u8 = 10;
+const MSG_PATH_MTU_ACK: u8 = 11;
+const MSG_INTRODUCE: u8 = 12;
+const MSG_PUNCH: u8 = 13;
+const MSG_PUNCH_ACK: u8 = 14;
+const MSG_NEIGHBOURS: u8 = 15;
+
+/* Capabilities which vports say they have in their joins */
+/// The vport tags its frames with a hop limit
+pub const CAP_HOP_LIMIT: u32 = 1 << 0;
+/// The vport is connected to a second vswitch too
+pub const CAP_MULTIHOMED: u32 = 1 << 1;
+/// The vport reaches the vswitch over several links
+pub const CAP_LAG: u32 = 1 << 2;
+/// The vport has joined the vswitch's BUM group
+pub const CAP_BUM_GROUP: u32 = 1 << 3;
+/// The vport would like to compress the frames it exchanges with the
+/// vswitch, and the vswitch agrees to when it answers with it
+pub const CAP_COMPRESSION: u32 = 1 << 4;
+/// The vport would like frames too large for the underlay to be sent
+/// in fragments, and the vswitch agrees to when it answers with it
+pub const CAP_FRAGMENTATION: u32 = 1 << 5;
+/// The vport would like to be introduced to the vports it exchanges
+/// frames with, to send them frames directly, and the vswitch agrees
+/// to introduce it when it answers with it
+pub const CAP_DIRECT_PATHS: u32 = 1 << 6;
+/// The vport would like small frames to be batched into shared
+/// datagrams, and the vswitch agrees to when it answers with it
+pub const CAP_BATCHING: u32 = 1 << 7;
+/// The vport would like the datagrams it exchanges with the vswitch to
+/// be protected with FEC, and the vswitch agrees to when it answers with it
+pub const CAP_FEC: u32 = 1 << 8;
+/// The vport answers its hosts' ARP requests for the addresses it knows
+/// the MACs of, and would like to be told those the vswitch learns, which
+/// the vswitch agrees to when it answers with it
+pub const CAP_ARP_SUPPRESSION: u32 = 1 << 9;
+
+/// Names of the capabilities, as shown to admin clients
+const CAPABILITY_NAMES: [(u32, &str); 10] = [
+    (CAP_HOP_LIMIT, "hop-limit"),
+    (CAP_MULTIHOMED, "multihomed"),
+    (CAP_LAG, "lag"),
+    (CAP_BUM_GROUP, "bum-group"),
+    (CAP_COMPRESSION, "compression"),
+    (CAP_FRAGMENTATION, "fragmentation"),
+    (CAP_DIRECT_PATHS, "direct-paths"),
+    (CAP_BATCHING, "batching"),
+    (CAP_FEC, "fec"),
+    (CAP_ARP_SUPPRESSION, "arp-suppression"),
+];
+
+/// Most MACs carried by a single topology change message, which
+/// fills an Ethernet frame after the version, type and MAC count
+pub const MAX_TOPOLOGY_CHANGE_MACS: usize = (ETHER_MTU - ETHER_HDR - 4 - 10) / 6;
+
+/// Most addresses carried by a single neighbours message, which fills
+/// an Ethernet frame after the version, type and address count
+pub const MAX_NEIGHBOURS: usize = (ETHER_MTU - ETHER_HDR - 4 - 10) / 10;
+
+/// Control message exchanged between a vport and the vswitch
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ControlMsg {
+    /// Sent periodically by vports to tell the vswitch which session
+    /// they belong to, so it can recognise them across restarts. The
+    /// token is a secret kept with the session ID, which the vswitch
+    /// checks before moving a session to a new address (it is 0 in
+    /// hellos from vports too old to send one). The MTU is the vport's
+    /// overlay MTU, which the vswitch checks matches its own (it is 0
+    /// in hellos from vports too old to send one)
+    Hello {
+        session_id: u64,
+        token: u64,
+        mtu: u16,
+    },
+    /// Sent periodically by the vswitch to measure the round trip
+    /// time to a vport, which answers with an echo reply
+    EchoRequest { timestamp: u64 },
+    /// Sent by vports in answer to an echo request, carrying
+    /// the timestamp from the request unchanged
+    EchoReply { timestamp: u64 },
+    /// Sent by a vswitch to its peers when MACs which they may have
+    /// learned through it can no longer be reached there, so they
+    /// forget them rather than black-holing frames sent to them
+    TopologyChange { macs: Vec<MacAddr> },
+    /// Sent periodically by vports which have joined an underlay
+    /// multicast group, so the vswitch sends them broadcast, unknown
+    /// unicast and multicast frames once through the group, rather
+    /// than sending each of them a copy
+    GroupMember { group: SocketAddrV4 },
+    /// Sent periodically by vports over each of their links to the
+    /// vswitch but the first, when they reach it over several underlay
+    /// paths, so it aggregates the links into the session's port, rather
+    /// than moving the session to them. The token is the session's, as
+    /// in hellos
+    LagMember { session_id: u64, token: u64 },
+    /// Sent by vports when they are stopped, so the vswitch flushes their
+    /// MACs straight away, rather than black-holing frames sent to them
+    /// until it stops hearing from them. The token is the session's, as
+    /// in hellos, so nobody else can make a vport leave
+    Leave { session_id: u64, token: u64 },
+    /// Sent by vports when they start, until the vswitch answers, to
+    /// register with it before they have sent any frames, saying what
+    /// they can do and which VLAN they would like to be in, if any. The
+    /// token and MTU are the session's, as in hellos, so the join is
+    /// refused as a hello would be. vports which would like frames to be
+    /// fragmented say how large a fragment they can receive, those which
+    /// asked STUN servers say their public endpoint and NAT type, and
+    /// those which would like FEC say how many datagrams it protects together
+    Join {
+        session_id: u64,
+        token: u64,
+        mtu: u16,
+        capabilities: u32,
+        vlan: Option<u16>,
+        fragment_size: Option<u16>,
+        public_endpoint: Option<PublicEndpoint>,
+        fec_group: Option<u8>,
+    },
+    /// Sent by the vswitch in answer to a join, with the ID of the
+    /// vport's port, the VLAN its untagged frames are in, if any, and
+    /// which of the capabilities the vport asked for the vswitch agreed
+    /// to (none, from vswitches too old to send any)
+    Joined {
+        session_id: u64,
+        port_id: u32,
+        vlan: Option<u16>,
+        capabilities: u32,
+    },
+    /// Sent by vports to find the path MTU to the vswitch, padded to the
+    /// frame size being tried, with the don't fragment flag set
+    PathMtuProbe { id: u64 },
+    /// Sent by the vswitch in answer to a path MTU probe, with the
+    /// probe's ID, padded to the probe's size so the path back is tried too
+    PathMtuAck { id: u64 },
+    /// Sent by the vswitch to a vport which agreed to direct paths, when
+    /// it forwards its frames to the MAC of a host behind another such
+    /// vport, with the other vport's session and the public endpoint its
+    /// datagrams arrive from, so the vports can punch holes through
+    /// their NATs to each other
+    Introduce {
+        session_id: u64,
+        mac: MacAddr,
+        endpoint: SocketAddrV4,
+    },
+    /// Sent by vports straight to the endpoint of a vport they were
+    /// introduced to, with their own session, to open a path to it
+    Punch { session_id: u64 },
+    /// Sent by vports in answer to a punch from a vport they were
+    /// introduced to, with their own session
+    PunchAck { session_id: u64 },
+    /// Sent by the vswitch to vports which agreed to ARP suppression, with
+    /// the MACs of IPv4 addresses it learned from the ARP in their broadcast
+    /// domain, when they join and as it learns new ones, so they can answer
+    /// their hosts' requests for them
+    Neighbours { entries: Vec<(Ipv4Addr, MacAddr)> },
+}
+
+impl ControlMsg {
+    /// Returns the Ethernet frame carrying this message
+    ///
+    /// A topology change message must carry no more than
+    /// MAX_TOPOLOGY_CHANGE_MACS MACs, and a neighbours
+    /// message no more than MAX_NEIGHBOURS addresses
+    pub fn encode(&self) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(ETHER_HDR + 10);
+        frame.extend_from_slice(&CONTROL_MAC);
+        /* Control messages are never learned, so have no source MAC */
+        frame.extend_from_slice(&[0u8; 6]);
+        frame.extend_from_slice(&CONTROL_ETHER_TYPE.to_be_bytes());
+        frame.push(CONTROL_VERSION);
+
+        match self {
+            ControlMsg::Hello {
+                session_id,
+                token,
+                mtu,
+            } => {
+                frame.push(MSG_HELLO);
+                frame.extend_from_slice(&session_id.to_be_bytes());
+                frame.extend_from_slice(&token.to_be_bytes());
+                frame.extend_from_slice(&mtu.to_be_bytes());
+            }
+            ControlMsg::EchoRequest { timestamp } => {
+                frame.push(MSG_ECHO_REQUEST);
+                frame.extend_from_slice(&timestamp.to_be_bytes());
+            }
+            ControlMsg::EchoReply { timestamp } => {
+                frame.push(MSG_ECHO_REPLY);
+                frame.extend_from_slice(&timestamp.to_be_bytes());
+            }
+            ControlMsg::TopologyChange { macs } => {
+                frame.push(MSG_TOPOLOGY_CHANGE);
+                frame.extend_from_slice(&(macs.len() as u64).to_be_bytes());
+                for mac in macs {
+                    frame.extend_from_slice(&mac.0);
+                }
+            }
+            ControlMsg::GroupMember { group } => {
+                frame.push(MSG_GROUP_MEMBER);
+                frame.extend_from_slice(&group.ip().octets());
+                frame.extend_from_slice(&group.port().to_be_bytes());
+                frame.extend_from_slice(&[0u8; 2]);
+            }
+            ControlMsg::LagMember { session_id, token } => {
+                frame.push(MSG_LAG_MEMBER);
+                frame.extend_from_slice(&session_id.to_be_bytes());
+                frame.extend_from_slice(&token.to_be_bytes());
+            }
+            ControlMsg::Leave { session_id, token } => {
+                frame.push(MSG_LEAVE);
+                frame.extend_from_slice(&session_id.to_be_bytes());
+                frame.extend_from_slice(&token.to_be_bytes());
+            }
+            ControlMsg::Join {
+                session_id,
+                token,
+                mtu,
+                capabilities,
+                vlan,
+                fragment_size,
+                public_endpoint,
+                fec_group,
+            } => {
+                frame.push(MSG_JOIN);
+                frame.extend_from_slice(&session_id.to_be_bytes());
+                frame.extend_from_slice(&token.to_be_bytes());
+                frame.extend_from_slice(&mtu.to_be_bytes());
+                frame.extend_from_slice(&capabilities.to_be_bytes());
+                frame.extend_from_slice(&vlan.unwrap_or(0).to_be_bytes());
+                frame.extend_from_slice(&fragment_size.unwrap_or(0).to_be_bytes());
+                match public_endpoint {
+                    Some(PublicEndpoint { addr, nat }) => {
+                        frame.push(nat.code());
+                        frame.extend_from_slice(&addr.ip().octets());
+                        frame.extend_from_slice(&addr.port().to_be_bytes());
+                    }
+                    None => frame.extend_from_slice(&[0; 7]),
+                }
+                frame.push(fec_group.unwrap_or(0));
+            }
+            ControlMsg::Joined {
+                session_id,
+                port_id,
+                vlan,
+                capabilities,
+            } => {
+                frame.push(MSG_JOINED);
+                frame.extend_from_slice(&session_id.to_be_bytes());
+                frame.extend_from_slice(&port_id.to_be_bytes());
+                frame.extend_from_slice(&vlan.unwrap_or(0).to_be_bytes());
+                frame.extend_from_slice(&capabilities.to_be_bytes());
+            }
+            ControlMsg::PathMtuProbe { id } => {
+                frame.push(MSG_PATH_MTU_PROBE);
+                frame.extend_from_slice(&id.to_be_bytes());
+            }
+            ControlMsg::PathMtuAck { id } => {
+                frame.push(MSG_PATH_MTU_ACK);
+                frame.extend_from_slice(&id.to_be_bytes());
+            }
+            ControlMsg::Introduce {
+                session_id,
+                mac,
+                endpoint,
+            } => {
+                frame.push(MSG_INTRODUCE);
+                frame.extend_from_slice(&session_id.to_be_bytes());
+                frame.extend_from_slice(&mac.0);
+                frame.extend_from_slice(&endpoint.ip().octets());
+                frame.extend_from_slice(&endpoint.port().to_be_bytes());
+            }
+            ControlMsg::Punch { session_id } => {
+                frame.push(MSG_PUNCH);
+                frame.extend_from_slice(&session_id.to_be_bytes());
+            }
+            ControlMsg::PunchAck { session_id } => {
+                frame.push(MSG_PUNCH_ACK);
+                frame.extend_from_slice(&session_id.to_be_bytes());
+            }
+            ControlMsg::Neighbours { entries } => {
+                frame.push(MSG_NEIGHBOURS);
+                frame.extend_from_slice(&(entries.len() as u64).to_be_bytes());
+                for (ip, mac) in entries {
+                    frame.extend_from_slice(&ip.octets());
+                    frame.extend_from_slice(&mac.0);
+                }
+            }
+        }
+
+        frame
+    }
+
+    /// Returns the control message carried by frame, or
+    /// an error if it is not a control frame which we understand
+    pub fn decode(frame: &[u8]) -> Result<ControlMsg, ProtocolError> {
+        if !is_control_frame(frame) {
+            return Err(ProtocolError::NotControlFrame);
+        }
+
+        let payload = &frame[ETHER_HDR..];
+        let (version, msg_type, value) = match payload {
+            [version, msg_type, value @ ..] => (*version, *msg_type, value),
+            _ => return Err(ProtocolError::Truncated),
+        };
+        if version != CONTROL_VERSION {
+            return Err(ProtocolError::UnsupportedVersion(version));
+        }
+        let (value, rest) = match value.split_first_chunk::<8>() {
+            Some((value, rest)) => (u64::from_be_bytes(*value), rest),
+            None => return Err(ProtocolError::Truncated),
+        };
+
+        match msg_type {
+            MSG_HELLO => Ok(ControlMsg::Hello {
+                session_id: value,
+                /* Older vports send no token or MTU, and pad their hellos with zeroes */
+                token: rest
+                    .first_chunk::<8>()
+                    .map_or(0, |token| u64::from_be_bytes(*token)),
+                mtu: rest
+                    .get(8..10)
+                    .map_or(0, |mtu| u16::from_be_bytes([mtu[0], mtu[1]])),
+            }),
+            MSG_ECHO_REQUEST => Ok(ControlMsg::EchoRequest { timestamp: value }),
+            MSG_ECHO_REPLY => Ok(ControlMsg::EchoReply { timestamp: value }),
+            MSG_TOPOLOGY_CHANGE => {
+                let len = usize::try_from(value)
+                    .ok()
+                    .and_then(|count| count.checked_mul(6))
+                    .ok_or(ProtocolError::Truncated)?;
+                let macs = rest
+                    .get(..len)
+                    .ok_or(ProtocolError::Truncated)?
+                    .chunks_exact(6)
+                    .map(|mac| MacAddr(mac.try_into().unwrap()))
+                    .collect();
+                Ok(ControlMsg::TopologyChange { macs })
+            }
+            MSG_GROUP_MEMBER => {
+                let [a, b, c, d, port @ .., _, _] = value.to_be_bytes();
+                Ok(ControlMsg::GroupMember {
+                    group: SocketAddrV4::new(Ipv4Addr::new(a, b, c, d), u16::from_be_bytes(port)),
+                })
+            }
+            MSG_LAG_MEMBER => Ok(ControlMsg::LagMember {
+                session_id: value,
+                token: rest
+                    .first_chunk::<8>()
+                    .map(|token| u64::from_be_bytes(*token))
+                    .ok_or(ProtocolError::Truncated)?,
+            }),
+            MSG_LEAVE => Ok(ControlMsg::Leave {
+                session_id: value,
+                token: rest
+                    .first_chunk::<8>()
+                    .map(|token| u64::from_be_bytes(*token))
+                    .ok_or(ProtocolError::Truncated)?,
+            }),
+            MSG_JOIN => {
+                let fields = rest.get(..16).ok_or(ProtocolError::Truncated)?;
+                Ok(ControlMsg::Join {
+                    session_id: value,
+                    token: u64::from_be_bytes(fields[..8].try_into().unwrap()),
+                    mtu: u16::from_be_bytes([fields[8], fields[9]]),
+                    capabilities: u32::from_be_bytes(fields[10..14].try_into().unwrap()),
+                    vlan: vlan_id(u16::from_be_bytes([fields[14], fields[15]])),
+                    /* Joins from older vports are padded with zeroes, which means none */
+                    fragment_size: rest
+                        .get(16..18)
+                        .map(|size| u16::from_be_bytes([size[0], size[1]]))
+                        .filter(|size| *size != 0),
+                    /* As are joins from vports which didn't ask STUN servers */
+                    public_endpoint: rest.get(18..25).and_then(|fields| {
+                        Some(PublicEndpoint {
+                            addr: SocketAddrV4::new(
+                                Ipv4Addr::new(fields[1], fields[2], fields[3], fields[4]),
+                                u16::from_be_bytes([fields[5], fields[6]]),
+                            ),
+                            nat: NatType::from_code(fields[0])?,
+                        })
+                    }),
+                    /* And from vports which don't want FEC */
+                    fec_group: rest.get(25).copied().filter(|group| *group != 0),
+                })
+            }
+            MSG_JOINED => {
+                let fields = rest.get(..6).ok_or(ProtocolError::Truncated)?;
+                Ok(ControlMsg::Joined {
+                    session_id: value,
+                    port_id: u32::from_be_bytes(fields[..4].try_into().unwrap()),
+                    vlan: vlan_id(u16::from_be_bytes([fields[4], fields[5]])),
+                    /* Older vswitches agree to nothing, and pad their answers with zeroes */
+                    capabilities: rest
+                        .get(6..10)
+                        .map_or(0, |caps| u32::from_be_bytes(caps.try_into().unwrap())),
+                })
+            }
+            MSG_PATH_MTU_PROBE => Ok(ControlMsg::PathMtuProbe { id: value }),
+            MSG_PATH_MTU_ACK => Ok(ControlMsg::PathMtuAck { id: value }),
+            MSG_INTRODUCE => {
+                let fields = rest.get(..12).ok_or(ProtocolError::Truncated)?;
+                Ok(ControlMsg::Introduce {
+                    session_id: value,
+                    mac: MacAddr(fields[..6].try_into().unwrap()),
+                    endpoint: SocketAddrV4::new(
+                        Ipv4Addr::new(fields[6], fields[7], fields[8], fields[9]),
+                        u16::from_be_bytes([fields[10], fields[11]]),
+                    ),
+                })
+            }
+            MSG_PUNCH => Ok(ControlMsg::Punch { session_id: value }),
+            MSG_PUNCH_ACK => Ok(ControlMsg::PunchAck { session_id: value }),
+            MSG_NEIGHBOURS => {
+                let len = usize::try_from(value)
+                    .ok()
+                    .and_then(|count| count.checked_mul(10))
+                    .ok_or(ProtocolError::Truncated)?;
+                let entries = rest
+                    .get(..len)
+                    .ok_or(ProtocolError::Truncated)?
+                    .chunks_exact(10)
+                    .map(|entry| {
+                        (
+                            Ipv4Addr::new(entry[0], entry[1], entry[2], entry[3]),
+                            MacAddr(entry[4..].try_into().unwrap()),
+                        )
+                    })
+                    .collect();
+                Ok(ControlMsg::Neighbours { entries })
+            }
+            _ => Err(ProtocolError::UnknownMessage(msg_type)),
+        }
+    }
+}
+
+/// Returns the VLAN ID carried in a join or joined message, where
+/// 0 (or anything which isn't a VLAN ID) means there is none
+fn vlan_id(vid: u16) -> Option<u16> {
+    (1..=4094).contains(&vid).then_some(vid)
+}
+
+/// Returns the names of the capabilities in capabilities, or
+/// "none" if there are none, e.g. "hop-limit,lag"
+pub fn capability_names(capabilities: u32) -> String {
+    let names: Vec<&str> = CAPABILITY_NAMES
+        .iter()
+        .filter(|(capability, _)| capabilities & capability != 0)
+        .map(|(_, name)| *name)
+        .collect();
+    match names.is_empty() {
+        true => "none".to_string(),
+        false => names.join(","),
+    }
+}
+
+/// Returns true if frame is a control frame, whether or
+/// not it carries a message which we understand
+pub fn is_control_frame(frame: &[u8]) -> bool {
+    frame.len() >= ETHER_HDR
+        && frame[..6] == CONTROL_MAC
+        && frame[12..14] == CONTROL_ETHER_TYPE.to_be_bytes()
+}
@@ -0,0 +1,211 @@
+//! Running the vswitch and vports as traditional daemons
+//!
+//! Without a supervisor such as systemd to start them, the vswitch and
+//! vports can detach from their terminal themselves, forking twice and
+//! starting a session of their own, so they carry on after the shell
+//! which started them exits. The process which was started waits until
+//! the daemon is ready, and then exits successfully, or unsuccessfully if
+//! the daemon stops first, so whatever started it can tell if it worked
+//!
+//! The daemon writes its process ID to a pidfile, which it holds a lock
+//! on for as long as it runs, so a second daemon given the same pidfile
+//! refuses to start, while one left behind by a daemon which was killed
+//! doesn't stop another from starting
+//!
+//! Everything is logged to stdout and stderr, which a daemon has no
+//! terminal for, so they can be redirected to a file, which is appended
+//! to, or to syslog, where stdout's lines are logged at LOG_INFO and
+//! stderr's at LOG_ERR. The daemon stays in the directory it was started
+//! in, so relative paths given to it still work
+
+use crate::fdpass::set_cloexec;
+use nix::{
+    errno::Errno,
+    fcntl::{Flock, FlockArg},
+    libc::{self, c_int},
+    sys::wait::waitpid,
+    unistd::{dup2, fork, pipe, setsid, ForkResult},
+};
+use std::{
+    ffi::{CStr, CString},
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Write},
+    os::{
+        fd::{AsRawFd, OwnedFd},
+        unix::fs::OpenOptionsExt,
+    },
+    process, thread,
+};
+
+/// Byte which the daemon sends the process which was started once it is ready
+const READY: u8 = 1;
+
+/// Permissions of the pidfile, which anyone can read
+const PID_FILE_MODE: u32 = 0o644;
+
+/// Where a daemon's logs go
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LogTarget {
+    /// Appended to the file at the path
+    File(String),
+    /// Sent to syslog, with the daemon facility
+    Syslog,
+}
+
+impl LogTarget {
+    /// Parse "syslog", or the path of a file
+    pub fn parse(value: &str) -> LogTarget {
+        match value {
+            "syslog" => LogTarget::Syslog,
+            path => LogTarget::File(path.to_string()),
+        }
+    }
+}
+
+/// Process which has detached from its terminal, and holds the lock on
+/// its pidfile, which is removed once it is dropped
+#[derive(Debug)]
+pub struct Daemon {
+    pid_file: Flock<File>,
+    pid_path: String,
+    /* Pipe to the process which was started, which exits once it is told the daemon is ready */
+    ready: Option<File>,
+}
+
+impl Daemon {
+    /// Tell the process which was started that the daemon is ready, so
+    /// it exits successfully. This does nothing after the first time
+    pub fn ready(&mut self) {
+        if let Some(mut ready) = self.ready.take() {
+            let _ = ready.write_all(&[READY]);
+        }
+    }
+}
+
+impl Drop for Daemon {
+    /// Empty the pidfile, in case it can't be removed, and remove it
+    fn drop(&mut self) {
+        let _ = self.pid_file.set_len(0);
+        let _ = fs::remove_file(&self.pid_path);
+    }
+}
+
+/// Detach from the terminal, and write the daemon's process ID to the
+/// pidfile at pid_path, which is refused if another daemon holds it.
+/// Only the calling thread survives, so this has to be called before
+/// any threads are started
+///
+/// Returns in the daemon, while the process which was started exits once
+/// the daemon is ready, or has stopped
+pub fn daemonize(pid_path: &str) -> io::Result<Daemon> {
+    let (ready_rx, ready_tx) = pipe()?;
+    set_cloexec(&ready_rx)?;
+    set_cloexec(&ready_tx)?;
+    if let ForkResult::Parent { child } = unsafe { fork() }? {
+        drop(ready_tx);
+        let _ = waitpid(child, None);
+        process::exit(wait_ready(ready_rx));
+    }
+    drop(ready_rx);
+
+    /*
+     * A session of its own leaves the daemon without a controlling
+     * terminal, and as it then isn't the session leader, it can never
+     * be given one again by opening a terminal
+     */
+    setsid()?;
+    if let ForkResult::Parent { .. } = unsafe { fork() }? {
+        process::exit(0);
+    }
+
+    let pid_file = lock_pid_file(pid_path)?;
+    let null = File::open("/dev/null")?;
+    dup2(null.as_raw_fd(), libc::STDIN_FILENO)?;
+
+    Ok(Daemon {
+        pid_file,
+        pid_path: pid_path.to_string(),
+        ready: Some(File::from(ready_tx)),
+    })
+}
+
+/// Send everything written to stdout and stderr to target from now on,
+/// tagging it with ident in syslog
+pub fn redirect_logs(target: &LogTarget, ident: &'static CStr) -> io::Result<()> {
+    match target {
+        LogTarget::File(path) => {
+            let file = File::options().append(true).create(true).open(path)?;
+            dup2(file.as_raw_fd(), libc::STDOUT_FILENO)?;
+            dup2(file.as_raw_fd(), libc::STDERR_FILENO)?;
+        }
+        LogTarget::Syslog => {
+            unsafe { libc::openlog(ident.as_ptr(), libc::LOG_PID, libc::LOG_DAEMON) };
+            for (fd, priority) in [
+                (libc::STDOUT_FILENO, libc::LOG_INFO),
+                (libc::STDERR_FILENO, libc::LOG_ERR),
+            ] {
+                /* Only stdout or stderr is left holding the pipe open */
+                let (rx, tx) = pipe()?;
+                set_cloexec(&rx)?;
+                dup2(tx.as_raw_fd(), fd)?;
+                thread::spawn(move || log_lines(rx, priority));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Wait for the daemon to say it is ready on ready_rx, returning the
+/// exit code for the process which was started
+fn wait_ready(ready_rx: OwnedFd) -> i32 {
+    let mut byte = [0];
+    match File::from(ready_rx).read(&mut byte) {
+        Ok(1) if byte[0] == READY => 0,
+        _ => {
+            eprintln!("The daemon stopped before it was ready, see its log for why");
+            1
+        }
+    }
+}
+
+/// Open the pidfile at path, locking it and writing our process ID to
+/// it, unless another daemon holds the lock
+fn lock_pid_file(path: &str) -> io::Result<Flock<File>> {
+    let file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(PID_FILE_MODE)
+        .open(path)?;
+    let mut file = match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+        Ok(file) => file,
+        Err((mut file, Errno::EWOULDBLOCK)) => {
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            return Err(io::Error::other(format!(
+                "{} is held by process {}, which is still running",
+                path,
+                pid.trim()
+            )));
+        }
+        Err((_, e)) => return Err(e.into()),
+    };
+
+    file.set_len(0)?;
+    writeln!(*file, "{}", process::id())?;
+    Ok(file)
+}
+
+/// Log each line read from rx to syslog at priority, until stdout or
+/// stderr, whichever writes to it, is closed
+fn log_lines(rx: OwnedFd, priority: c_int) {
+    for line in BufReader::new(File::from(rx)).split(b'\n') {
+        let Ok(mut line) = line else {
+            return;
+        };
+        line.retain(|b| *b != 0);
+        let line = CString::new(line).unwrap_or_default();
+        unsafe { libc::syslog(priority, c"%s".as_ptr(), line.as_ptr()) };
+    }
+}
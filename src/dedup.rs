@@ -0,0 +1,71 @@
+//! Duplicate frame suppression for multihomed vports
+//!
+//! A vport connected to two vswitches sends every frame to both of
+//! them, and so receives a copy of every frame from each of them.
+//! The vport remembers a fingerprint of each frame it receives for a
+//! short window, and drops a frame if an identical one has already
+//! arrived over the other link during that window, so the host only
+//! sees each frame once. Identical frames arriving over the same link
+//! are never dropped, as those were really sent more than once
+
+use std::{
+    collections::{hash_map::DefaultHasher, VecDeque},
+    hash::{Hash, Hasher},
+    time::{Duration, Instant},
+};
+
+/// How long a frame is remembered for, which must be longer than the
+/// difference in latency between the paths through the two vswitches
+pub const DUPLICATE_WINDOW: Duration = Duration::from_millis(200);
+
+/// Most frames remembered at once, to bound the cost of a burst
+const MAX_REMEMBERED: usize = 4096;
+
+/// Remembers recently received frames, to spot the second copy of each
+#[derive(Debug, Default)]
+pub struct DuplicateFilter {
+    /* (arrival time, fingerprint, link) of each frame, oldest first */
+    recent: VecDeque<(Instant, u64, usize)>,
+    suppressed: u64,
+}
+
+impl DuplicateFilter {
+    /// Returns true if frame, which was received over the given
+    /// link, is a copy of a frame already received over another link
+    pub fn is_duplicate(&mut self, frame: &[u8], link: usize) -> bool {
+        let now = Instant::now();
+        while let Some((arrived, _, _)) = self.recent.front() {
+            let expired = now.duration_since(*arrived) >= DUPLICATE_WINDOW;
+            if !expired && self.recent.len() < MAX_REMEMBERED {
+                break;
+            }
+            self.recent.pop_front();
+        }
+
+        let mut hasher = DefaultHasher::new();
+        frame.hash(&mut hasher);
+        let fingerprint = hasher.finish();
+
+        /* Each copy only cancels out one copy from another link */
+        match self
+            .recent
+            .iter()
+            .position(|(_, fp, from)| *fp == fingerprint && *from != link)
+        {
+            Some(index) => {
+                self.recent.remove(index);
+                self.suppressed += 1;
+                true
+            }
+            None => {
+                self.recent.push_back((now, fingerprint, link));
+                false
+            }
+        }
+    }
+
+    /// Returns how many duplicates have been dropped
+    pub fn suppressed(&self) -> u64 {
+        self.suppressed
+    }
+}
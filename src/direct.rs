@@ -0,0 +1,147 @@
+//! Direct paths between vports behind NATs
+//!
+//! Frames between vports normally go through the vswitch, which is the
+//! only thing either of them can reach when both are behind NAT. vports
+//! which agree to direct paths when they join are introduced to each
+//! other by the vswitch when it forwards frames between them: each is
+//! told the MAC of the other's host, the other's session, and the public
+//! endpoint the vswitch sees the other's datagrams come from. Both then
+//! send punches to the other's endpoint from the socket they reach the
+//! vswitch on, which opens a hole through each NAT for the other's
+//! datagrams, as long as the NAT maps the socket to the same public
+//! endpoint whatever it sends to
+//!
+//! Once a vport hears a punch or its ack from the other, it sends the
+//! frames for the other's MAC straight to its endpoint, and keeps the
+//! hole open with a punch every KEEPALIVE_INTERVAL. Until then, or if
+//! nothing is heard for PATH_TIMEOUT, the frames are relayed through the
+//! vswitch, which introduces the vports again if they keep using it
+//!
+//! The paths only decide where frames go, so they can be driven by
+//! whatever sends the punches and frames
+
+use crate::mac::MacAddr;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// How often a vport which hasn't answered is punched
+pub const PUNCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many punches go unanswered before the path is given up on
+pub const PUNCH_ATTEMPTS: u32 = 10;
+
+/// How often a vport with a path is punched, to keep the hole open
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a path lasts without a punch or ack from the other vport
+pub const PATH_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// vport which we were introduced to
+#[derive(Clone, Debug)]
+struct Peer {
+    endpoint: SocketAddr,
+    /* When a punch or ack was last heard from it, if one has been */
+    heard: Option<Instant>,
+    /* When it was last punched, and how many punches it hasn't answered */
+    punched: Option<Instant>,
+    unanswered: u32,
+}
+
+impl Peer {
+    /// Whether there is a path to the vport at now
+    fn is_up(&self, now: Instant) -> bool {
+        self.heard
+            .is_some_and(|heard| now.duration_since(heard) < PATH_TIMEOUT)
+    }
+}
+
+/// The vports which a vport was introduced to, and the paths to them
+#[derive(Debug, Default)]
+pub struct DirectPaths {
+    /* Session of the vport behind each MAC we were told of */
+    macs: HashMap<MacAddr, u64>,
+    peers: HashMap<u64, Peer>,
+}
+
+impl DirectPaths {
+    /// Note the vswitch's introduction to the vport in session_id, which
+    /// mac is behind, and whose datagrams come from endpoint. A vport
+    /// without a path to it is punched again from the start
+    pub fn introduce(&mut self, session_id: u64, mac: MacAddr, endpoint: SocketAddr) {
+        self.macs.insert(mac, session_id);
+        let peer = self.peers.entry(session_id).or_insert(Peer {
+            endpoint,
+            heard: None,
+            punched: None,
+            unanswered: 0,
+        });
+        if peer.endpoint != endpoint {
+            peer.endpoint = endpoint;
+            peer.heard = None;
+        }
+        if peer.heard.is_none() {
+            peer.unanswered = 0;
+        }
+    }
+
+    /// Returns the endpoint which frames to mac are sent straight to at
+    /// now, or None if they are sent through the vswitch
+    pub fn route(&self, mac: &MacAddr, now: Instant) -> Option<SocketAddr> {
+        let peer = self.peers.get(self.macs.get(mac)?)?;
+        peer.is_up(now).then_some(peer.endpoint)
+    }
+
+    /// Note that a punch or ack arrived from the vport in session_id at
+    /// now, returning its endpoint if we were introduced to it, and
+    /// whether that opened the path to it
+    pub fn heard(&mut self, session_id: u64, now: Instant) -> Option<(SocketAddr, bool)> {
+        let peer = self.peers.get_mut(&session_id)?;
+        let opened = !peer.is_up(now);
+        peer.heard = Some(now);
+        peer.unanswered = 0;
+        Some((peer.endpoint, opened))
+    }
+
+    /// Returns the endpoints to punch at now, and forgets the vports
+    /// which didn't answer PUNCH_ATTEMPTS punches, or whose paths timed
+    /// out, returning the endpoints of those which had a path too
+    pub fn punches(&mut self, now: Instant) -> (Vec<SocketAddr>, Vec<SocketAddr>) {
+        let mut lost = Vec::new();
+        self.peers.retain(|_, peer| {
+            let keep = match peer.heard {
+                Some(_) => peer.is_up(now),
+                None => peer.unanswered < PUNCH_ATTEMPTS,
+            };
+            if !keep && peer.heard.is_some() {
+                lost.push(peer.endpoint);
+            }
+            keep
+        });
+        let peers = &self.peers;
+        self.macs
+            .retain(|_, session_id| peers.contains_key(session_id));
+
+        let mut endpoints = Vec::new();
+        for peer in self.peers.values_mut() {
+            let interval = match peer.heard {
+                Some(_) => KEEPALIVE_INTERVAL,
+                None => PUNCH_INTERVAL,
+            };
+            if peer
+                .punched
+                .is_some_and(|punched| now.duration_since(punched) < interval)
+            {
+                continue;
+            }
+            peer.punched = Some(now);
+            if peer.heard.is_none() {
+                peer.unanswered += 1;
+            }
+            endpoints.push(peer.endpoint);
+        }
+        (endpoints, lost)
+    }
+}
@@ -0,0 +1,132 @@
+//! Marking the datagrams which carry frames with a DSCP
+//!
+//! The vport and vswitch can set the DSCP in the outer IP header of the
+//! UDP datagrams they send, so the underlay network can give the tunnel
+//! the treatment it needs, e.g. queueing it ahead of bulk transfers. The
+//! DSCP is either fixed, or copied from the 802.1p priority in the VLAN
+//! tag of each frame, as the class selector of the same number (so
+//! priority 5 is sent as CS5), with untagged frames sent as best effort
+//!
+//! The DSCP is set on the socket, rather than given with each datagram,
+//! so it is only set again when a frame needs another one than the last
+
+use crate::{
+    batch::{is_batch, unbatch},
+    compression::{decompress, is_compressed},
+    error::ConfigError,
+    fec,
+    platform::set_option,
+    utilities::vlan_tag,
+};
+#[cfg(unix)]
+use nix::libc::{IPPROTO_IP, IP_TOS};
+use std::{
+    fmt, io,
+    net::UdpSocket,
+    sync::atomic::{AtomicU8, Ordering},
+};
+#[cfg(windows)]
+use windows_sys::Win32::Networking::WinSock::{IPPROTO_IP, IP_TOS};
+
+/// Largest DSCP, which is a 6 bit field
+pub const MAX_DSCP: u8 = 63;
+
+/// DSCP of a socket which hasn't been marked yet
+const UNMARKED: u8 = u8::MAX;
+
+/// DSCP which datagrams are marked with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dscp {
+    /// Every datagram has this DSCP
+    Fixed(u8),
+    /// Each datagram has the class selector of its frame's 802.1p priority
+    FromPcp,
+}
+
+impl Dscp {
+    /// Parse a DSCP as given on the command line, i.e. a
+    /// number from 0 to 63, or "pcp" to copy the frames' priorities
+    pub fn parse(value: &str) -> Result<Dscp, ConfigError> {
+        match value {
+            "pcp" => Ok(Dscp::FromPcp),
+            _ => value
+                .parse::<u8>()
+                .ok()
+                .filter(|dscp| *dscp <= MAX_DSCP)
+                .map(Dscp::Fixed)
+                .ok_or_else(|| ConfigError::Dscp(value.to_string())),
+        }
+    }
+
+    /// Returns the DSCP of the datagram carrying frame, which may have a
+    /// hop limit tag, and be compressed, in which case it is decompressed
+    /// to find its priority. A batch of frames has the highest DSCP of
+    /// its frames, so none is sent with less priority than it should be.
+    /// An FEC data shard has the DSCP of the datagram it carries, and a
+    /// parity shard, which carries none, is sent as best effort
+    pub fn for_frame(&self, frame: &[u8]) -> u8 {
+        match self {
+            Dscp::Fixed(dscp) => *dscp,
+            Dscp::FromPcp if fec::is_shard(frame) => {
+                fec::data(frame).map_or(0, |datagram| self.for_frame(datagram))
+            }
+            Dscp::FromPcp if is_batch(frame) => unbatch(frame)
+                .unwrap_or_default()
+                .iter()
+                .map(|frame| self.for_frame(frame))
+                .max()
+                .unwrap_or(0),
+            Dscp::FromPcp => {
+                let tag = match is_compressed(frame) {
+                    true => decompress(frame).and_then(|frame| vlan_tag(&frame)),
+                    false => vlan_tag(frame),
+                };
+                tag.map_or(0, |tag| tag.pcp << 3)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Dscp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Dscp::Fixed(dscp) => write!(f, "DSCP {}", dscp),
+            Dscp::FromPcp => f.write_str("the DSCP of their 802.1p priority"),
+        }
+    }
+}
+
+/// Set the DSCP of the datagrams sent from sock to dscp
+pub fn set_dscp(sock: &UdpSocket, dscp: u8) -> io::Result<()> {
+    set_option(sock, IPPROTO_IP, IP_TOS, i32::from(dscp) << 2)
+}
+
+/// Marks the datagrams sent from one socket, which its clones share
+#[derive(Debug)]
+pub struct Marker {
+    dscp: Dscp,
+    /* DSCP which the socket's datagrams are marked with, or UNMARKED */
+    marked: AtomicU8,
+}
+
+impl Marker {
+    /// Returns the marker of a socket which hasn't been marked yet
+    pub fn new(dscp: Dscp) -> Marker {
+        Marker {
+            dscp,
+            marked: AtomicU8::new(UNMARKED),
+        }
+    }
+
+    /// Mark the datagram carrying frame, which is about to be sent from
+    /// sock, setting the socket's DSCP if the last frame had another one
+    pub fn mark(&self, sock: &UdpSocket, frame: &[u8]) -> io::Result<()> {
+        let dscp = self.dscp.for_frame(frame);
+        match self.marked.swap(dscp, Ordering::Relaxed) == dscp {
+            true => Ok(()),
+            false => set_dscp(sock, dscp).inspect_err(|_| {
+                self.marked.store(UNMARKED, Ordering::Relaxed);
+            }),
+        }
+    }
+}
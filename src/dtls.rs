@@ -0,0 +1,441 @@
+//! DTLS encryption of the frames between vports and the vswitch
+//!
+//! With DTLS on, a vport which reaches the vswitch over UDP performs a
+//! DTLS 1.2 handshake with it, and every datagram after that carries
+//! a frame encrypted and authenticated under the keys they agreed, so
+//! nobody on the underlay can read the frames, or forge or alter them.
+//! Both ends are given the same pre-shared key, which authenticates
+//! them to each other without needing certificates
+//!
+//! OpenSSL is handed the datagrams through an in-memory stream rather
+//! than the socket, so the vswitch can keep a session for every vport
+//! on its one socket. OpenSSL doesn't resend lost handshake messages
+//! through such a stream, so a vport starts its handshake again if it
+//! isn't finished within HANDSHAKE_TIMEOUT, and starts a new session
+//! if it hasn't heard from the vswitch within IDLE_TIMEOUT (which
+//! sends it an echo request every 5 seconds), e.g. after the vswitch
+//! restarted and lost its sessions
+
+use crate::{error::TransportError, tunnel::TUNNEL_DATAGRAM_MAX};
+use openssl::ssl::{
+    ErrorCode, Ssl, SslContext, SslContextBuilder, SslMethod, SslOptions, SslRef, SslStream,
+    SslVersion,
+};
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{self, Read, Write},
+    mem,
+    net::{SocketAddr, UdpSocket},
+    sync::{Arc, Mutex, PoisonError, RwLock},
+    time::{Duration, Instant},
+};
+
+/// How long a vport waits for its handshake to finish before starting again
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How many times a vport starts its handshake before it carries on
+/// without a session, which it keeps trying to set up in the background
+const HANDSHAKE_ATTEMPTS: u32 = 5;
+
+/// How long a vport can go without hearing from the vswitch before
+/// it assumes the vswitch has lost their session
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Identity the vport gives along with the pre-shared key
+const PSK_IDENTITY: &[u8] = b"l2vpn";
+
+/// Ciphers which use the pre-shared key, strongest first. Each is an
+/// AEAD, so adds no more than DTLS_OVERHEAD to the frames it carries
+const CIPHERS: &str = "PSK-AES256-GCM-SHA384:PSK-CHACHA20-POLY1305:PSK-AES128-GCM-SHA256";
+
+/// Returns the context of the sessions a vport starts with psk
+pub fn client_context(psk: &[u8]) -> Result<SslContext, TransportError> {
+    let mut builder = context_builder()?;
+    let psk = psk.to_vec();
+    builder.set_psk_client_callback(move |_, _, identity, psk_buf| {
+        /* The identity is NUL terminated */
+        identity[..PSK_IDENTITY.len()].copy_from_slice(PSK_IDENTITY);
+        identity[PSK_IDENTITY.len()] = 0;
+        psk_buf[..psk.len()].copy_from_slice(&psk);
+        Ok(psk.len())
+    });
+    Ok(builder.build())
+}
+
+/// Returns the context of the sessions which the vswitch accepts with psk
+pub fn server_context(psk: &[u8]) -> Result<SslContext, TransportError> {
+    let mut builder = context_builder()?;
+    let psk = psk.to_vec();
+    builder.set_psk_server_callback(move |_, identity, psk_buf| {
+        /* A key of no length refuses the handshake */
+        if identity != Some(PSK_IDENTITY) {
+            return Ok(0);
+        }
+        psk_buf[..psk.len()].copy_from_slice(&psk);
+        Ok(psk.len())
+    });
+    Ok(builder.build())
+}
+
+/// Returns a builder of the contexts shared by vports and the vswitch
+fn context_builder() -> Result<SslContextBuilder, TransportError> {
+    let mut builder = SslContext::builder(SslMethod::dtls()).map_err(dtls_error)?;
+    builder
+        .set_min_proto_version(Some(SslVersion::DTLS1_2))
+        .map_err(dtls_error)?;
+    builder.set_cipher_list(CIPHERS).map_err(dtls_error)?;
+    /* The MTU is set on each session, as the stream can't be asked for it */
+    builder.set_options(SslOptions::NO_QUERY_MTU);
+    Ok(builder)
+}
+
+/// Returns true if datagram starts with a DTLS record carrying a
+/// ClientHello, i.e. a vport starting a new session
+pub fn is_client_hello(datagram: &[u8]) -> bool {
+    /* Handshake content type, epoch 0, and the ClientHello type after the 13 byte header */
+    datagram.first() == Some(&22)
+        && datagram.get(3..5) == Some(&[0, 0])
+        && datagram.get(13) == Some(&1)
+}
+
+/// Datagrams passed between a session and the socket
+#[derive(Debug, Default)]
+struct Datagrams {
+    /// Received from the other end, for OpenSSL to read
+    incoming: VecDeque<Vec<u8>>,
+    /// Written by OpenSSL, to be sent to the other end
+    outgoing: Vec<Vec<u8>>,
+}
+
+/// OpenSSL reads one whole datagram at a time
+impl Read for Datagrams {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(datagram) = self.incoming.pop_front() else {
+            return Err(io::ErrorKind::WouldBlock.into());
+        };
+        let len = datagram.len().min(buf.len());
+        buf[..len].copy_from_slice(&datagram[..len]);
+        Ok(len)
+    }
+}
+
+/// Each write by OpenSSL is one datagram
+impl Write for Datagrams {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// DTLS session between a vport and the vswitch, which is given the
+/// datagrams received from the other end, and returns those to send
+#[derive(Debug)]
+pub struct DtlsSession {
+    stream: SslStream<Datagrams>,
+    established: bool,
+    /// When the handshake started
+    started: Instant,
+    /// When a frame was last received in the session
+    last_heard: Instant,
+}
+
+impl DtlsSession {
+    /// Returns a session which a vport starts with the vswitch, whose
+    /// first handshake message is ready to be sent
+    pub fn connect(context: &SslContext, now: Instant) -> Result<DtlsSession, TransportError> {
+        let mut session = DtlsSession::new(context, now, SslRef::set_connect_state)?;
+        session.handshake()?;
+        Ok(session)
+    }
+
+    /// Returns a session which the vswitch accepts from a vport,
+    /// which is set up once its first handshake message is received
+    pub fn accept(context: &SslContext, now: Instant) -> Result<DtlsSession, TransportError> {
+        DtlsSession::new(context, now, SslRef::set_accept_state)
+    }
+
+    fn new(
+        context: &SslContext,
+        now: Instant,
+        role: fn(&mut SslRef),
+    ) -> Result<DtlsSession, TransportError> {
+        let mut ssl = Ssl::new(context).map_err(dtls_error)?;
+        /* Frames are sent whole, as they would be without DTLS */
+        ssl.set_mtu(TUNNEL_DATAGRAM_MAX as u32)
+            .map_err(dtls_error)?;
+        role(&mut ssl);
+        Ok(DtlsSession {
+            stream: SslStream::new(ssl, Datagrams::default()).map_err(dtls_error)?,
+            established: false,
+            started: now,
+            last_heard: now,
+        })
+    }
+
+    /// Returns true once the handshake has finished, so frames can be sent
+    pub fn is_established(&self) -> bool {
+        self.established
+    }
+
+    /// Returns when the handshake started
+    pub fn started(&self) -> Instant {
+        self.started
+    }
+
+    /// Returns when a frame was last received in the session
+    pub fn last_heard(&self) -> Instant {
+        self.last_heard
+    }
+
+    /// Take datagram, received from the other end at now, returning
+    /// the frames it carried. Datagrams which can't be authenticated
+    /// are dropped, and an error is only returned if the session failed
+    pub fn receive(
+        &mut self,
+        datagram: &[u8],
+        now: Instant,
+    ) -> Result<Vec<Vec<u8>>, TransportError> {
+        self.stream.get_mut().incoming.push_back(datagram.to_vec());
+        if !self.established {
+            self.handshake()?;
+        }
+
+        let mut frames = Vec::new();
+        while self.established {
+            let mut frame = vec![0u8; TUNNEL_DATAGRAM_MAX];
+            match self.stream.ssl_read(&mut frame) {
+                Ok(len) => {
+                    frame.truncate(len);
+                    frames.push(frame);
+                }
+                Err(e) if e.code() == ErrorCode::WANT_READ => break,
+                Err(e) if e.code() == ErrorCode::ZERO_RETURN => {
+                    return Err(TransportError::Closed);
+                }
+                Err(e) => return Err(dtls_error(e)),
+            }
+        }
+        if !frames.is_empty() {
+            self.last_heard = now;
+        }
+
+        Ok(frames)
+    }
+
+    /// Encrypt frame, which is then one of the datagrams to send
+    pub fn seal(&mut self, frame: &[u8]) -> Result<(), TransportError> {
+        if !self.established {
+            return Err(io::Error::from(io::ErrorKind::NotConnected).into());
+        }
+        self.stream.ssl_write(frame).map_err(dtls_error)?;
+        Ok(())
+    }
+
+    /// Take the datagrams which are to be sent to the other end
+    pub fn take_datagrams(&mut self) -> Vec<Vec<u8>> {
+        mem::take(&mut self.stream.get_mut().outgoing)
+    }
+
+    /// Move the handshake on as far as the datagrams received so far allow
+    fn handshake(&mut self) -> Result<(), TransportError> {
+        match self.stream.do_handshake() {
+            Ok(()) => {
+                self.established = true;
+                Ok(())
+            }
+            Err(e) if e.code() == ErrorCode::WANT_READ => Ok(()),
+            Err(e) => Err(dtls_error(e)),
+        }
+    }
+}
+
+/// Session of a DtlsLink, and the frames received but not yet taken
+#[derive(Debug)]
+struct LinkState {
+    session: DtlsSession,
+    pending: VecDeque<Vec<u8>>,
+}
+
+/// UDP link from a vport to the vswitch, whose frames are carried in a
+/// DTLS session. Clones of the link share the session
+#[derive(Debug)]
+pub struct DtlsLink {
+    sock: UdpSocket,
+    /// Address which the vswitch's host name last resolved to
+    vswitch_addr: Arc<RwLock<SocketAddr>>,
+    context: SslContext,
+    state: Arc<Mutex<LinkState>>,
+}
+
+impl DtlsLink {
+    /// Start a session with the vswitch at vswitch_addr over sock, and
+    /// wait for its handshake to finish, starting it again each time it
+    /// isn't finished within HANDSHAKE_TIMEOUT. If it still isn't after
+    /// HANDSHAKE_ATTEMPTS, the link is returned without a session, and
+    /// keep() carries on setting one up
+    pub fn connect(
+        sock: UdpSocket,
+        vswitch_addr: Arc<RwLock<SocketAddr>>,
+        context: SslContext,
+    ) -> Result<DtlsLink, TransportError> {
+        let link = DtlsLink {
+            state: Arc::new(Mutex::new(LinkState {
+                session: DtlsSession::connect(&context, Instant::now())?,
+                pending: VecDeque::new(),
+            })),
+            sock,
+            vswitch_addr,
+            context,
+        };
+
+        link.sock.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let mut buf = [0u8; TUNNEL_DATAGRAM_MAX];
+        for attempt in 0..HANDSHAKE_ATTEMPTS {
+            if attempt > 0 {
+                link.restart()?;
+            }
+            link.flush(&mut link.state.lock().unwrap_or_else(PoisonError::into_inner))?;
+            let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+            while !link.is_established() && Instant::now() < deadline {
+                /* Timing out waiting for a datagram is transient too */
+                match link.receive(&mut buf) {
+                    Err(e) if !e.is_transient() => return Err(e),
+                    _ => {}
+                }
+            }
+            if link.is_established() {
+                break;
+            }
+        }
+        link.sock.set_read_timeout(None)?;
+
+        Ok(link)
+    }
+
+    /// Returns true if the session with the vswitch is set up
+    pub fn is_established(&self) -> bool {
+        self.state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .session
+            .is_established()
+    }
+
+    /// Encrypt frame and send it to the vswitch
+    pub fn send_frame(&self, frame: &[u8]) -> Result<(), TransportError> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.session.seal(frame)?;
+        self.flush(&mut state)
+    }
+
+    /// Receive a frame from the vswitch into buf, returning its length
+    ///
+    /// A session which fails, e.g. as the vswitch restarted and can't
+    /// read the frames sent in it, is replaced with a new one
+    pub fn recv_frame(&self, buf: &mut [u8]) -> Result<usize, TransportError> {
+        loop {
+            if let Some(frame) = self
+                .state
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .pending
+                .pop_front()
+            {
+                let Some(dst) = buf.get_mut(..frame.len()) else {
+                    return Err(TransportError::FrameTooLarge {
+                        len: frame.len(),
+                        max: buf.len(),
+                    });
+                };
+                dst.copy_from_slice(&frame);
+                return Ok(frame.len());
+            }
+
+            let mut datagram = [0u8; TUNNEL_DATAGRAM_MAX];
+            match self.receive(&mut datagram) {
+                Err(TransportError::Dtls(_) | TransportError::Closed) => self.restart()?,
+                result => result?,
+            }
+        }
+    }
+
+    /// Start the session again if its handshake hasn't finished within
+    /// HANDSHAKE_TIMEOUT, or nothing has been heard from the vswitch in
+    /// IDLE_TIMEOUT. This is called periodically, as OpenSSL doesn't
+    /// resend lost handshake messages itself
+    pub fn keep(&self, now: Instant) -> Result<(), TransportError> {
+        let expired = {
+            let session = &self
+                .state
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .session;
+            match session.is_established() {
+                true => now.duration_since(session.last_heard()) >= IDLE_TIMEOUT,
+                false => now.duration_since(session.started()) >= HANDSHAKE_TIMEOUT,
+            }
+        };
+        match expired {
+            true => self.restart(),
+            false => Ok(()),
+        }
+    }
+
+    /// Returns another handle to the link, sharing its socket and session
+    pub fn try_clone(&self) -> Result<DtlsLink, TransportError> {
+        Ok(DtlsLink {
+            sock: self.sock.try_clone()?,
+            vswitch_addr: self.vswitch_addr.clone(),
+            context: self.context.clone(),
+            state: self.state.clone(),
+        })
+    }
+
+    /// Receive a datagram from the vswitch into buf, and take the frames it carries
+    fn receive(&self, buf: &mut [u8]) -> Result<(), TransportError> {
+        let (len, src) = self.sock.recv_from(buf)?;
+        if src
+            != *self
+                .vswitch_addr
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+        {
+            return Ok(());
+        }
+
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let frames = state.session.receive(&buf[..len], Instant::now())?;
+        state.pending.extend(frames);
+        self.flush(&mut state)
+    }
+
+    /// Replace the session with a new one, and send its first handshake message
+    fn restart(&self) -> Result<(), TransportError> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.session = DtlsSession::connect(&self.context, Instant::now())?;
+        state.pending.clear();
+        self.flush(&mut state)
+    }
+
+    /// Send the datagrams which the session has written to the vswitch
+    fn flush(&self, state: &mut LinkState) -> Result<(), TransportError> {
+        let vswitch_addr = *self
+            .vswitch_addr
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        for datagram in state.session.take_datagrams() {
+            self.sock.send_to(&datagram, vswitch_addr)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the transport error for a failure in OpenSSL
+fn dtls_error(e: impl fmt::Display) -> TransportError {
+    TransportError::Dtls(e.to_string())
+}
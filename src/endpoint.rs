@@ -0,0 +1,505 @@
+//! I/O-free core of the vport
+//!
+//! What the vport does with each frame, i.e. fitting frames to the
+//! tunnel MTU (or the path MTU, once it is found), tagging them with a hop limit, decompressing those the
+//! vswitch compressed, answering the vswitch's echo requests,
+//! dropping the second copy of each frame from a second vswitch and
+//! those the host has asked to keep out of (or in) the overlay,
+//! answering the host's ARP requests for addresses it knows, and
+//! the messages it joins and registers with the vswitch (and leaves it
+//! with), are kept free of the tap interface and sockets. The core is
+//! given frames, and returns what should be sent where, so it can be
+//! tested, fuzzed and simulated without a network, and the vport binary
+//! only has to move bytes
+
+use crate::{
+    arp::ArpCache,
+    compression::{decompress, is_compressed},
+    control::{
+        is_control_frame, ControlMsg, CAP_ARP_SUPPRESSION, CAP_BUM_GROUP, CAP_FEC,
+        CAP_FRAGMENTATION, CAP_HOP_LIMIT,
+    },
+    dedup::DuplicateFilter,
+    filter::Filter,
+    log_frame,
+    mac::MacAddr,
+    mtu::{clamp_mss, too_big_reply, UDP_TUNNEL_OVERHEAD},
+    stun::PublicEndpoint,
+    tunnel::{pop_hop_limit, push_hop_limit, DEFAULT_TTL},
+    utilities::{pad_frame, FrameLogMsg, ETHER_FRAME_MIN},
+};
+use std::{
+    net::SocketAddrV4,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::Instant,
+};
+
+/// Session which the vport tells the vswitch it belongs to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Session {
+    pub id: u64,
+    /// Secret which proves the session is ours when our address changes
+    pub token: u64,
+}
+
+/// What to do with a frame once the core has handled it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Send the first len bytes of the buffer on, i.e. to the vswitch for
+    /// frames from the tap interface, and to the tap interface for frames
+    /// from the vswitch
+    Forward(usize),
+    /// Drop the frame, and send this frame back where it came from
+    Reply(Vec<u8>),
+    /// Drop the frame, which was the vswitch's answer to our join,
+    /// giving our port's ID, the VLAN our frames are put in, if any,
+    /// and the capabilities it agreed to
+    Joined {
+        port_id: u32,
+        vlan: Option<u16>,
+        capabilities: u32,
+    },
+    /// Drop the frame, which was the vswitch's answer to
+    /// the path MTU probe with this ID
+    ProbeAcked(u64),
+    /// Drop the frame, which was the vswitch's introduction to the vport
+    /// in session_id, which mac is behind, and whose datagrams come
+    /// from endpoint
+    Introduced {
+        session_id: u64,
+        mac: MacAddr,
+        endpoint: SocketAddrV4,
+    },
+    /// Drop the frame, which was a punch from the vport in session_id,
+    /// or the answer to one of ours if ack
+    Punched { session_id: u64, ack: bool },
+    /// Drop the frame
+    Drop,
+}
+
+/// Frame handling and registration of a vport
+#[derive(Clone, Debug)]
+pub struct VportCore {
+    session: Session,
+    /// MTU of the L2VPN network, which is told to the vswitch
+    mtu: usize,
+    /// MTU of the underlay network, which packets from the host are fitted to
+    tunnel_mtu: Option<usize>,
+    /// Largest frame which path MTU discovery found reaches the vswitches,
+    /// which packets are fitted to as well, or 0 until it is found. It is
+    /// shared by the clones of the core, as the search runs in a thread of
+    /// its own
+    largest_frame: Arc<AtomicUsize>,
+    /// Underlay multicast group which the vport has joined
+    bum_group: Option<SocketAddrV4>,
+    /// Frames from the tap interface which are dropped rather than sent
+    /// into the overlay, if any of the filters match them
+    egress_filters: Arc<[Filter]>,
+    /// Frames from the overlay which are dropped rather than written to
+    /// the tap interface, if any of the filters match them
+    ingress_filters: Arc<[Filter]>,
+    /// MACs of the addresses behind other vports, which the host's ARP
+    /// requests for are answered from, if ARP suppression is on. It is
+    /// shared by the clones of the core, as frames from the tap interface
+    /// and from the vswitch are handled in threads of their own
+    arp_cache: Option<Arc<Mutex<ArpCache>>>,
+}
+
+impl VportCore {
+    /// Returns the core of a vport in session, with an overlay MTU of mtu,
+    /// which fits packets to tunnel_mtu if it is given, and has joined
+    /// bum_group if it is given
+    pub fn new(
+        session: Session,
+        mtu: usize,
+        tunnel_mtu: Option<usize>,
+        bum_group: Option<SocketAddrV4>,
+    ) -> VportCore {
+        VportCore {
+            session,
+            mtu,
+            tunnel_mtu,
+            largest_frame: Arc::default(),
+            bum_group,
+            egress_filters: Arc::from([]),
+            ingress_filters: Arc::from([]),
+            arp_cache: None,
+        }
+    }
+
+    /// Answer the host's ARP requests for the addresses learned from the
+    /// ARP received from the overlay, and those the vswitch tells us,
+    /// rather than sending them into the overlay
+    pub fn suppress_arp(&mut self) {
+        self.arp_cache = Some(Arc::default());
+    }
+
+    /// Returns how many ARP requests have been answered, and how many
+    /// addresses are cached, if ARP suppression is on
+    pub fn arp_stats(&self) -> Option<(u64, usize)> {
+        self.arp_cache.as_ref().map(|cache| {
+            let cache = cache.lock().unwrap_or_else(PoisonError::into_inner);
+            (cache.answered(), cache.len())
+        })
+    }
+
+    /// Drop the frames from the tap interface which match any of egress,
+    /// and those from the overlay which match any of ingress, so the host
+    /// can keep traffic such as router advertisements or LLDP local.
+    /// Control frames from the vswitch are never dropped
+    pub fn set_filters(&mut self, egress: Vec<Filter>, ingress: Vec<Filter>) {
+        self.egress_filters = Arc::from(egress);
+        self.ingress_filters = Arc::from(ingress);
+    }
+
+    /// Fit packets to frames of len bytes, with their hop limit tag, which
+    /// path MTU discovery found is the largest to reach the vswitches, as
+    /// well as to the tunnel MTU
+    pub fn set_largest_frame(&self, len: usize) {
+        self.largest_frame.store(len, Ordering::Relaxed);
+    }
+
+    /// Returns the tunnel MTU which packets are fitted to, if any
+    fn fit_mtu(&self) -> Option<usize> {
+        let found = match self.largest_frame.load(Ordering::Relaxed) {
+            0 => None,
+            len => Some(len + UDP_TUNNEL_OVERHEAD),
+        };
+        match (self.tunnel_mtu, found) {
+            (Some(tunnel_mtu), Some(found)) => Some(tunnel_mtu.min(found)),
+            (tunnel_mtu, found) => tunnel_mtu.or(found),
+        }
+    }
+
+    /// Returns the session which the vport belongs to
+    pub fn session(&self) -> Session {
+        self.session
+    }
+
+    /// Returns the MTU of the L2VPN network
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// Handle the frame of len bytes at the start of buf, which was
+    /// read from the tap interface. buf must have room for the frame
+    /// to be padded to the Ethernet minimum and given a hop limit tag
+    pub fn from_tap(&self, buf: &mut [u8], len: usize) -> Action {
+        if matches_any(&self.egress_filters, &buf[..len]) {
+            log_frame!(
+                "Dropped frame matching an egress filter: {}",
+                FrameLogMsg(&buf[..len], len)
+            );
+            return Action::Drop;
+        }
+
+        /* ARP requests for addresses we know are answered without leaving the vport */
+        if let Some(cache) = &self.arp_cache {
+            let mut cache = cache.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Some(reply) = cache.answer(&buf[..len], Instant::now()) {
+                log_frame!(
+                    "Answered ARP request locally: {}",
+                    FrameLogMsg(&buf[..len], len)
+                );
+                return Action::Reply(reply);
+            }
+        }
+
+        if let Some(tunnel_mtu) = self.fit_mtu() {
+            /* The MSS the host advertises limits the segments sent to it through the tunnel */
+            if let Some((mss, clamped)) = clamp_mss(&mut buf[..len], tunnel_mtu) {
+                log_frame!(
+                    "Clamped the MSS of a TCP SYN sent from {} to {}",
+                    mss,
+                    clamped
+                );
+            }
+
+            /* Packets too large for the tunnel, which mustn't be fragmented, are refused */
+            if let Some(reply) = too_big_reply(&buf[..len], tunnel_mtu) {
+                log_frame!(
+                    "Dropped frame too large for the tunnel MTU of {}, and sent ICMP error: {}",
+                    tunnel_mtu,
+                    FrameLogMsg(&buf[..len], len)
+                );
+                return Action::Reply(reply);
+            }
+        }
+
+        /*
+         * If the frame is shorter than the Ethernet minimum, add
+         * some padding to the buffer. The tap interface gives us
+         * frames without an FCS, so the minimum here is 60 bytes
+         */
+        let frame_len = pad_frame(buf, len);
+
+        /* Tag the frame with a TTL, which each vswitch it passes through decrements */
+        Action::Forward(push_hop_limit(buf, frame_len, DEFAULT_TTL))
+    }
+
+    /// Handle the frame of len bytes at the start of buf, which was
+    /// received from the vswitch over the link with index link_index
+    ///
+    /// If the vport is multihomed, duplicates is shared by the links
+    /// to both vswitches, to drop the copy of each frame which arrives second
+    pub fn from_vswitch(
+        &self,
+        buf: &mut [u8],
+        len: usize,
+        link_index: usize,
+        duplicates: Option<&Mutex<DuplicateFilter>>,
+    ) -> Action {
+        /* A vswitch which agreed to compress frames compresses those which it can */
+        let len = match is_compressed(&buf[..len]) {
+            true => match decompress(&buf[..len]).filter(|frame| frame.len() <= buf.len()) {
+                Some(frame) => {
+                    buf[..frame.len()].copy_from_slice(&frame);
+                    frame.len()
+                }
+                None => {
+                    eprintln!("Received compressed frame which could not be decompressed");
+                    return Action::Drop;
+                }
+            },
+            false => len,
+        };
+
+        /* The frame has left the L2VPN network, so its TTL is no longer needed */
+        let len = pop_hop_limit(buf, len);
+
+        /*
+         * Frames carried over the L2VPN network do not include an
+         * FCS, so anything shorter than 60 bytes is a runt
+         */
+        if len < ETHER_FRAME_MIN {
+            eprintln!("Received runt frame which was {} bytes long", len);
+            return Action::Drop;
+        }
+
+        /*
+         * Control frames are meant for the vport rather than the host,
+         * so answer echo requests, note the answers to our joins and
+         * path MTU probes, introductions and punches for direct paths,
+         * and the addresses the vswitch tells us, and never pass
+         * control frames on
+         */
+        if is_control_frame(&buf[..len]) {
+            return match ControlMsg::decode(&buf[..len]) {
+                Ok(ControlMsg::EchoRequest { timestamp }) => {
+                    let mut reply = ControlMsg::EchoReply { timestamp }.encode();
+                    reply.resize(ETHER_FRAME_MIN, 0);
+                    Action::Reply(reply)
+                }
+                Ok(ControlMsg::Joined {
+                    session_id,
+                    port_id,
+                    vlan,
+                    capabilities,
+                }) if session_id == self.session.id => Action::Joined {
+                    port_id,
+                    vlan,
+                    capabilities,
+                },
+                Ok(ControlMsg::PathMtuAck { id }) => Action::ProbeAcked(id),
+                Ok(ControlMsg::Introduce {
+                    session_id,
+                    mac,
+                    endpoint,
+                }) if session_id != self.session.id => Action::Introduced {
+                    session_id,
+                    mac,
+                    endpoint,
+                },
+                Ok(ControlMsg::Punch { session_id }) => Action::Punched {
+                    session_id,
+                    ack: false,
+                },
+                Ok(ControlMsg::PunchAck { session_id }) => Action::Punched {
+                    session_id,
+                    ack: true,
+                },
+                Ok(ControlMsg::Neighbours { entries }) => {
+                    if let Some(cache) = &self.arp_cache {
+                        let mut cache = cache.lock().unwrap_or_else(PoisonError::into_inner);
+                        let now = Instant::now();
+                        for (ip, mac) in entries.iter() {
+                            cache.insert(*ip, *mac, now);
+                        }
+                        log_frame!("The vswitch told us {} address(es)", entries.len());
+                    }
+                    Action::Drop
+                }
+                _ => Action::Drop,
+            };
+        }
+
+        if let Some(duplicates) = duplicates {
+            /* The filter is still usable if another thread panicked while holding it */
+            let mut duplicates = duplicates.lock().unwrap_or_else(PoisonError::into_inner);
+            if duplicates.is_duplicate(&buf[..len], link_index) {
+                log_frame!(
+                    "Dropped duplicate frame ({} so far): {}",
+                    duplicates.suppressed(),
+                    FrameLogMsg(&buf[..len], len)
+                );
+                return Action::Drop;
+            }
+        }
+
+        if matches_any(&self.ingress_filters, &buf[..len]) {
+            log_frame!(
+                "Dropped frame matching an ingress filter: {}",
+                FrameLogMsg(&buf[..len], len)
+            );
+            return Action::Drop;
+        }
+
+        /* The ARP of hosts behind other vports tells us the MACs of their addresses */
+        if let Some(cache) = &self.arp_cache {
+            cache
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .snoop(&buf[..len], Instant::now());
+        }
+
+        /* The MSS advertised to the host limits the segments it sends through the tunnel */
+        if let Some(tunnel_mtu) = self.fit_mtu() {
+            if let Some((mss, clamped)) = clamp_mss(&mut buf[..len], tunnel_mtu) {
+                log_frame!(
+                    "Clamped the MSS of a TCP SYN received from {} to {}",
+                    mss,
+                    clamped
+                );
+            }
+        }
+
+        Action::Forward(len)
+    }
+
+    /// Returns the frames which register the vport with the vswitch, and
+    /// are sent to it periodically, so it knows which session we belong to
+    /// even if it has restarted, or we have moved, since we started. They
+    /// carry our MTU, so the vswitch can refuse us if it doesn't match its own
+    ///
+    /// If we have joined the BUM group of this vswitch (the first one, when
+    /// multihomed), we tell it so, so it keeps sending us flooded frames
+    /// through the group
+    pub fn hellos(&self, first_vswitch: bool) -> Vec<Vec<u8>> {
+        let mut msgs = vec![ControlMsg::Hello {
+            session_id: self.session.id,
+            token: self.session.token,
+            mtu: u16::try_from(self.mtu).unwrap_or(u16::MAX),
+        }];
+        if let Some(group) = self.bum_group.filter(|_| first_vswitch) {
+            msgs.push(ControlMsg::GroupMember { group });
+        }
+
+        msgs.iter()
+            .map(|msg| {
+                let mut frame = msg.encode();
+                frame.resize(frame.len().max(ETHER_FRAME_MIN), 0);
+                frame
+            })
+            .collect()
+    }
+
+    /// Returns the frame which registers the vport with the vswitch (the
+    /// first one, when multihomed, if first_vswitch), which is sent until
+    /// the vswitch answers it. It says we tag our frames with a hop limit,
+    /// and have joined the vswitch's BUM group, if we have, along with
+    /// the capabilities given, and asks for our frames to be put in vlan,
+    /// and for frames larger than fragment_size to be sent in fragments,
+    /// and for FEC over groups of fec_group datagrams, and to be told the
+    /// addresses the vswitch learns if ARP suppression is on. It reports
+    /// our public endpoint, if STUN servers told us it
+    pub fn join(
+        &self,
+        first_vswitch: bool,
+        capabilities: u32,
+        vlan: Option<u16>,
+        fragment_size: Option<u16>,
+        public_endpoint: Option<PublicEndpoint>,
+        fec_group: Option<u8>,
+    ) -> Vec<u8> {
+        let mut capabilities = capabilities | CAP_HOP_LIMIT;
+        if self.bum_group.is_some() && first_vswitch {
+            capabilities |= CAP_BUM_GROUP;
+        }
+        if fragment_size.is_some() {
+            capabilities |= CAP_FRAGMENTATION;
+        }
+        if fec_group.is_some() {
+            capabilities |= CAP_FEC;
+        }
+        if self.arp_cache.is_some() {
+            capabilities |= CAP_ARP_SUPPRESSION;
+        }
+        let mut frame = ControlMsg::Join {
+            session_id: self.session.id,
+            token: self.session.token,
+            mtu: u16::try_from(self.mtu).unwrap_or(u16::MAX),
+            capabilities,
+            vlan,
+            fragment_size,
+            public_endpoint,
+            fec_group,
+        }
+        .encode();
+        frame.resize(frame.len().max(ETHER_FRAME_MIN), 0);
+        frame
+    }
+
+    /// Returns the path MTU probe with id, padded to a frame of len bytes
+    pub fn path_mtu_probe(&self, id: u64, len: usize) -> Vec<u8> {
+        let mut frame = ControlMsg::PathMtuProbe { id }.encode();
+        frame.resize(len.max(ETHER_FRAME_MIN), 0);
+        frame
+    }
+
+    /// Returns the punch which is sent straight to the vports we were
+    /// introduced to, or the answer to one of theirs if ack
+    pub fn punch(&self, ack: bool) -> Vec<u8> {
+        let session_id = self.session.id;
+        let mut frame = match ack {
+            true => ControlMsg::PunchAck { session_id },
+            false => ControlMsg::Punch { session_id },
+        }
+        .encode();
+        frame.resize(frame.len().max(ETHER_FRAME_MIN), 0);
+        frame
+    }
+
+    /// Returns the frame which is sent periodically over each of the
+    /// vport's links to the first vswitch but the first link, so the
+    /// vswitch aggregates them into our port
+    pub fn lag_member(&self) -> Vec<u8> {
+        let mut frame = ControlMsg::LagMember {
+            session_id: self.session.id,
+            token: self.session.token,
+        }
+        .encode();
+        frame.resize(frame.len().max(ETHER_FRAME_MIN), 0);
+        frame
+    }
+
+    /// Returns the frame which is sent to each vswitch when the vport is
+    /// stopped, so it flushes our MACs straight away
+    pub fn leave(&self) -> Vec<u8> {
+        let mut frame = ControlMsg::Leave {
+            session_id: self.session.id,
+            token: self.session.token,
+        }
+        .encode();
+        frame.resize(frame.len().max(ETHER_FRAME_MIN), 0);
+        frame
+    }
+}
+
+/// Returns true if any of filters matches frame. They can't test the
+/// port it came from, as the vport has none, so 0 stands in for it
+fn matches_any(filters: &[Filter], frame: &[u8]) -> bool {
+    filters.iter().any(|filter| filter.matches(frame, 0))
+}
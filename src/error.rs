@@ -0,0 +1,261 @@
+//! Errors returned by the library
+//!
+//! Failures are split by where they come from, so callers can match
+//! on the cause and decide what to do about it, e.g. keep retrying a
+//! vswitch which can't be reached yet, but give up on a proxy which
+//! rejects our password, or on a configuration which can't be parsed
+
+use crate::{
+    dscp::MAX_DSCP,
+    utilities::{MAX_OVERLAY_MTU, MIN_OVERLAY_MTU},
+};
+use std::io;
+use thiserror::Error;
+
+/// Any error returned by the library
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Tap(#[from] TapError),
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+}
+
+/* Exit statuses from sysexits.h */
+const EX_UNAVAILABLE: u8 = 69;
+const EX_OSERR: u8 = 71;
+const EX_IOERR: u8 = 74;
+const EX_PROTOCOL: u8 = 76;
+const EX_CONFIG: u8 = 78;
+
+impl Error {
+    /// Returns true if the same operation may succeed if it is tried again later
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Transport(e) => e.is_transient(),
+            Error::Tap(_) | Error::Protocol(_) | Error::Config(_) => false,
+        }
+    }
+
+    /// Returns the status which a binary stopped by this error exits
+    /// with, following sysexits.h, so scripts can tell why it stopped
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Tap(_) => EX_OSERR,
+            Error::Transport(e) if e.is_transient() => EX_UNAVAILABLE,
+            Error::Transport(_) => EX_IOERR,
+            Error::Protocol(_) => EX_PROTOCOL,
+            Error::Config(_) => EX_CONFIG,
+        }
+    }
+}
+
+/// I/O errors which aren't the tap interface's are the transport's, e.g. a socket's
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Transport(e.into())
+    }
+}
+
+/// Error creating or configuring a tap interface
+#[derive(Debug, Error)]
+pub enum TapError {
+    #[error("Interface name '{name}' is {reason}")]
+    InvalidName { name: String, reason: &'static str },
+    /// Another process is already attached to the tap (or tun) interface
+    #[error("Interface '{name}' is already in use, so give another name, or one holding %d")]
+    InUse { name: String },
+    #[error("Could not open {path}: '{source}'")]
+    Open {
+        path: &'static str,
+        #[source]
+        source: io::Error,
+    },
+    #[cfg(unix)]
+    #[error("{op} failed with error: '{source}'")]
+    Ioctl {
+        op: &'static str,
+        #[source]
+        source: nix::Error,
+    },
+    #[cfg(windows)]
+    #[error("{op} failed with error: '{source}'")]
+    Wintun {
+        op: &'static str,
+        #[source]
+        source: wintun::Error,
+    },
+    #[error("Could not read from the tap interface: '{0}'")]
+    Read(#[source] io::Error),
+    /// The tap interface reached EOF, which it never should
+    #[error("Reached EOF for the tap interface")]
+    Eof,
+    /// The tap interface couldn't be received from the helper which opened it
+    #[error("Could not receive a tap interface from {path}: {reason}")]
+    Receive { path: String, reason: String },
+    /// The platform's tap driver can't do what was asked of it
+    #[error("{op} isn't supported on this platform")]
+    Unsupported { op: &'static str },
+}
+
+/// Error carrying frames or commands between vports, vswitches and admin clients
+#[derive(Debug, Error)]
+pub enum TransportError {
+    #[error(transparent)]
+    Io(io::Error),
+    /// The other end has gone away
+    #[error("Connection closed by the other end")]
+    Closed,
+    /// A frame was received which is larger than the buffer given for it
+    #[error("Frame of {len} bytes exceeds the {max} byte MTU")]
+    FrameTooLarge { len: usize, max: usize },
+    /// The proxy could not connect us to the vswitch
+    #[error("Proxy could not connect to {target}: {reason}")]
+    ProxyRefused { target: String, reason: String },
+    /// The proxy needs credentials which we don't have, or rejected ours
+    #[error("Proxy {0}")]
+    ProxyAuth(&'static str),
+    /// The proxy did not speak the protocol we expected of it
+    #[error("Proxy {0}")]
+    ProxyProtocol(String),
+    /// The DTLS session with the other end couldn't be set up, or failed
+    #[error("DTLS {0}")]
+    Dtls(String),
+    /// The QUIC connection with the other end couldn't be set up, or failed
+    #[error("QUIC {0}")]
+    Quic(String),
+    /// The vhost-user frontend couldn't be served, or its connection failed
+    #[error("vhost-user {0}")]
+    VhostUser(String),
+    /// We were told to stop, by the signal given, before the other end could be reached
+    #[error("Got {0} before the connection was made")]
+    Stopped(&'static str),
+}
+
+impl TransportError {
+    /// Returns true if the same operation may succeed if it is tried
+    /// again later, e.g. because the vswitch or the network was down
+    pub fn is_transient(&self) -> bool {
+        match self {
+            TransportError::Io(e) => matches!(
+                e.kind(),
+                io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::NotConnected
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::Interrupted
+                    | io::ErrorKind::WouldBlock
+                    | io::ErrorKind::HostUnreachable
+                    | io::ErrorKind::NetworkUnreachable
+                    | io::ErrorKind::NetworkDown
+                    | io::ErrorKind::AddrNotAvailable
+                    | io::ErrorKind::OutOfMemory
+            ),
+            TransportError::Closed | TransportError::ProxyRefused { .. } => true,
+            TransportError::FrameTooLarge { .. }
+            | TransportError::ProxyAuth(_)
+            | TransportError::ProxyProtocol(_)
+            | TransportError::Dtls(_)
+            | TransportError::Quic(_)
+            | TransportError::VhostUser(_)
+            | TransportError::Stopped(_) => false,
+        }
+    }
+}
+
+/// Reaching the end of a stream means the other end has closed it
+impl From<io::Error> for TransportError {
+    fn from(e: io::Error) -> TransportError {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => TransportError::Closed,
+            _ => TransportError::Io(e),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl From<nix::Error> for TransportError {
+    fn from(e: nix::Error) -> TransportError {
+        TransportError::Io(e.into())
+    }
+}
+
+/// Lets transport errors be returned from functions which return io::Result
+impl From<TransportError> for io::Error {
+    fn from(e: TransportError) -> io::Error {
+        match e {
+            TransportError::Io(e) => e,
+            TransportError::Closed => io::ErrorKind::UnexpectedEof.into(),
+            e => io::Error::other(e),
+        }
+    }
+}
+
+/// Error understanding a message from a vport, vswitch or admin client
+#[derive(Debug, Error)]
+pub enum ProtocolError {
+    #[error("Frame is not a control frame")]
+    NotControlFrame,
+    #[error("Control message version {0} is not supported")]
+    UnsupportedVersion(u8),
+    #[error("Control message type {0} is unknown")]
+    UnknownMessage(u8),
+    #[error("Control message is truncated")]
+    Truncated,
+    /// The frame is too short to hold its Ethernet header
+    #[error("Frame of {len} bytes is too short for its Ethernet header")]
+    TruncatedFrame { len: usize },
+    /// The vswitch refused an admin command, for the reason given
+    #[error("{0}")]
+    CommandFailed(String),
+}
+
+/// Error in a configuration which the library is given
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Expected <scheme>://<host>:<port> as proxy, got '{0}'")]
+    ProxyUrl(String),
+    #[error("Unknown proxy scheme '{0}', expected socks5 or http")]
+    ProxyScheme(String),
+    #[error("Missing port in proxy '{0}'")]
+    MissingProxyPort(String),
+    #[error("Could not parse '{0}' as proxy port")]
+    ProxyPort(String),
+    #[error("Missing host in proxy '{0}'")]
+    MissingProxyHost(String),
+    /// Proxy credentials which are malformed, or which SOCKS5 can't carry
+    #[error("Bad proxy credentials: {0}")]
+    ProxyCredentials(&'static str),
+    #[error("Bad percent escape in '{0}'")]
+    PercentEscape(String),
+    #[error("'{0}' does not decode to UTF-8")]
+    NotUtf8(String),
+    /// Pre-shared key which isn't hex, or is too short or long
+    #[error("Bad pre-shared key: {0}")]
+    Psk(&'static str),
+    #[error("Expected a MAC in the form aa:bb:cc:dd:ee:ff, got '{0}'")]
+    Mac(String),
+    /// Options which can't be used together, or with this build or platform
+    #[error("{0}")]
+    Unsupported(&'static str),
+    #[error("Could not parse session file '{path}': '{reason}'")]
+    Session { path: String, reason: String },
+    #[error("Expected an MTU from {min} to {max} bytes, not '{0}'", min = MIN_OVERLAY_MTU, max = MAX_OVERLAY_MTU)]
+    OverlayMtu(String),
+    #[error("Expected a DSCP from 0 to {max}, or 'pcp', not '{0}'", max = MAX_DSCP)]
+    Dscp(String),
+    /// A filter term which is missing the value it takes, e.g. a MAC after src
+    #[error("Expected {what} after '{term}'")]
+    MissingFilterValue { what: &'static str, term: String },
+    #[error("Unknown filter term '{0}'")]
+    FilterTerm(String),
+    /// A value in a filter which can't be parsed as what its term takes
+    #[error("Invalid {what} '{value}'")]
+    FilterValue { what: &'static str, value: String },
+}
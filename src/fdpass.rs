@@ -0,0 +1,113 @@
+//! Handing tap interfaces to unprivileged vports
+//!
+//! Creating and configuring a tap interface needs CAP_NET_ADMIN, but
+//! reading and writing one which is already open doesn't. A privileged
+//! helper (l2vpn-tap-helper) creates and configures it, and listens on
+//! a Unix stream socket, passing its file handle to each vport which
+//! connects in an SCM_RIGHTS message, so the vport runs without any
+//! capabilities. The message's one line of data says whether it is a
+//! tap or tun interface, and what it is called, e.g. "l2 tap0"
+//!
+//! The helper keeps the interface open, so it outlives the vports it is
+//! handed to, and a restarted vport is handed the same one
+
+use crate::{
+    error::TapError,
+    tap::{self, VirtualNic},
+};
+use nix::sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags};
+use std::{
+    io::{IoSlice, IoSliceMut},
+    os::{
+        fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+        unix::net::UnixStream,
+    },
+};
+
+/// Longest line which a helper describes the interface it hands over in
+const DESCRIPTION_MAX: usize = 64;
+
+/// Hand the tap (or tun, if l3) interface called name, whose file
+/// handle is fd, to the vport at the other end of stream
+pub fn send_tap(stream: &UnixStream, fd: BorrowedFd, name: &str, l3: bool) -> nix::Result<()> {
+    let description = format!("{} {}", if l3 { "l3" } else { "l2" }, name);
+    let fds = [fd.as_raw_fd()];
+    sendmsg::<()>(
+        stream.as_raw_fd(),
+        &[IoSlice::new(description.as_bytes())],
+        &[ControlMessage::ScmRights(&fds)],
+        MsgFlags::empty(),
+        None,
+    )?;
+    Ok(())
+}
+
+/// Receive a tap (or tun, if l3) interface from the helper listening on
+/// the Unix socket at path, which is refused if it is of the other kind
+pub fn receive_tap(path: &str, l3: bool) -> Result<Box<dyn VirtualNic>, TapError> {
+    let receive_err = |reason: String| TapError::Receive {
+        path: path.to_string(),
+        reason,
+    };
+    let stream = UnixStream::connect(path).map_err(|e| receive_err(e.to_string()))?;
+
+    let mut description = [0u8; DESCRIPTION_MAX];
+    let mut cmsg_buffer = nix::cmsg_space!([std::os::fd::RawFd; 1]);
+    let mut iov = [IoSliceMut::new(&mut description)];
+    let msg = recvmsg::<()>(
+        stream.as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsg_buffer),
+        MsgFlags::empty(),
+    )
+    .map_err(|e| receive_err(e.to_string()))?;
+    let len = msg.bytes;
+
+    /* Take ownership of every fd sent, so none are leaked if the message is refused */
+    let mut fds = Vec::new();
+    for cmsg in msg.cmsgs().map_err(|e| receive_err(e.to_string()))? {
+        if let ControlMessageOwned::ScmRights(raw_fds) = cmsg {
+            fds.extend(
+                raw_fds
+                    .into_iter()
+                    .map(|raw_fd| unsafe { OwnedFd::from_raw_fd(raw_fd) }),
+            );
+        }
+    }
+    let Some(fd) = fds.pop() else {
+        return Err(receive_err("no file handle was sent".to_string()));
+    };
+    set_cloexec(&fd).map_err(|e| receive_err(e.to_string()))?;
+
+    let description = String::from_utf8_lossy(&description[..len]).into_owned();
+    let (kind, name) = match description.trim_end().split_once(' ') {
+        Some(("l2", name)) => ("l2", name),
+        Some(("l3", name)) => ("l3", name),
+        _ => {
+            return Err(receive_err(format!(
+                "'{}' doesn't describe an interface",
+                description
+            )))
+        }
+    };
+    tap::check_name(name)?;
+    match (kind == "l3", l3) {
+        (true, false) => Err(receive_err(format!(
+            "{} is a tun interface, which needs --mode l3",
+            name
+        ))),
+        (false, true) => Err(receive_err(format!(
+            "{} is a tap interface, so --mode l3 can't be used",
+            name
+        ))),
+        _ => Ok(tap::from_fd(fd, name.to_string(), l3)),
+    }
+}
+
+/// Make fd close-on-exec, which file handles received from other
+/// processes aren't, as MSG_CMSG_CLOEXEC is only found on some platforms,
+/// and those inherited from systemd had to survive its exec
+pub(crate) fn set_cloexec(fd: &OwnedFd) -> nix::Result<()> {
+    use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+    fcntl(fd.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).map(drop)
+}
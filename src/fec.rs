@@ -0,0 +1,288 @@
+//! Forward error correction for lossy underlays
+//!
+//! On radio and satellite links which lose a few percent of datagrams,
+//! every lost frame costs the TCP connections inside the tunnel a
+//! retransmission timeout, and their throughput collapses. A vport can
+//! ask the vswitch for FEC when it joins, saying how many datagrams to
+//! protect together, and once the vswitch agrees, each of them sends the
+//! other its datagrams over UDP as the data shards of groups of that
+//! many, followed by a parity shard holding the XOR of the group. The
+//! receiver rebuilds any one datagram of a group which is lost from the
+//! others and the parity, without waiting for it to be sent again
+//!
+//! Each shard is sent after a header shaped like a control frame's, but
+//! sent to a reserved multicast MAC of its own, so it is never taken for
+//! a frame. The header holds the group's ID, the data shard's index in
+//! its group, and for parity shards, how many data shards the group
+//! has. A group which isn't full after FEC_FLUSH_TIMEOUT has its parity
+//! sent anyway, so the last datagrams of a burst are protected too.
+//! Data shards are handed on as soon as they arrive, so FEC never
+//! delays a datagram which isn't lost
+
+use crate::{control::CONTROL_ETHER_TYPE, utilities::ETHER_HDR};
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+/// Locally administered multicast MAC which shards are sent to
+pub const FEC_MAC: [u8; 6] = [0x03, 0x4c, 0x32, 0x56, 0x50, 0x52];
+
+/// Length of the header in front of every shard: the Ethernet header,
+/// and the group's ID, the shard's index and the group's data shards
+pub const FEC_HDR_LEN: usize = ETHER_HDR + 4 + 1 + 1;
+
+/// Most bytes which FEC adds to a datagram, as parity shards hold the
+/// XOR of their group's lengths too
+pub const FEC_OVERHEAD: usize = FEC_HDR_LEN + 2;
+
+/// Most datagrams which a group can protect
+pub const FEC_GROUP_MAX: u8 = 16;
+
+/// How long a group which isn't full waits for more datagrams before
+/// its parity is sent
+pub const FEC_FLUSH_TIMEOUT: Duration = Duration::from_millis(5);
+
+/// How long a group is kept for the datagrams it may rebuild
+const GROUP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Most groups kept at once, to bound the memory which lost shards (or a
+/// flood of bogus ones) can take. Data shards of further groups are still
+/// handed on, but can't be rebuilt
+const MAX_GROUPS: usize = 1024;
+
+/// Splits the datagrams sent to one receiver into groups, returning
+/// each as a data shard, and the parity shard of each group
+#[derive(Clone, Debug)]
+pub struct Encoder {
+    group_size: u8,
+    group: u32,
+    /* Data shards sent in the group so far, and the XOR of their lengths and contents */
+    sent: u8,
+    len_xor: u16,
+    parity: Vec<u8>,
+    /* When the group's first data shard was sent */
+    started: Option<Instant>,
+}
+
+impl Encoder {
+    /// Returns an encoder which protects groups of group_size datagrams,
+    /// which is kept between 1 and FEC_GROUP_MAX
+    pub fn new(group_size: u8) -> Encoder {
+        Encoder {
+            group_size: group_size.clamp(1, FEC_GROUP_MAX),
+            group: 0,
+            sent: 0,
+            len_xor: 0,
+            parity: Vec::new(),
+            started: None,
+        }
+    }
+
+    /// Returns the data shard which sends datagram at now, and the parity
+    /// shard to send after it, if that fills the group
+    pub fn encode(&mut self, datagram: &[u8], now: Instant) -> (Vec<u8>, Option<Vec<u8>>) {
+        let mut shard = shard_header(self.group, self.sent, 0);
+        shard.extend_from_slice(datagram);
+
+        self.len_xor ^= datagram.len() as u16;
+        if self.parity.len() < datagram.len() {
+            self.parity.resize(datagram.len(), 0);
+        }
+        xor_into(&mut self.parity, datagram);
+        self.sent += 1;
+        self.started.get_or_insert(now);
+
+        let parity = match self.sent >= self.group_size {
+            true => self.flush(),
+            false => None,
+        };
+        (shard, parity)
+    }
+
+    /// Returns the parity shard of the group, starting the next one,
+    /// or None if no datagrams have been sent in it
+    pub fn flush(&mut self) -> Option<Vec<u8>> {
+        if self.sent == 0 {
+            return None;
+        }
+        let mut shard = shard_header(self.group, self.sent, self.sent);
+        shard.extend_from_slice(&self.len_xor.to_be_bytes());
+        shard.append(&mut self.parity);
+
+        self.group = self.group.wrapping_add(1);
+        self.sent = 0;
+        self.len_xor = 0;
+        self.started = None;
+        Some(shard)
+    }
+
+    /// Returns when the group's parity has to be sent, if it has
+    /// any data shards
+    pub fn deadline(&self) -> Option<Instant> {
+        self.started.map(|started| started + FEC_FLUSH_TIMEOUT)
+    }
+}
+
+/// Returns the header of a shard of group with index, for a parity
+/// shard if data_shards isn't 0
+fn shard_header(group: u32, index: u8, data_shards: u8) -> Vec<u8> {
+    let mut shard = Vec::with_capacity(FEC_HDR_LEN);
+    shard.extend_from_slice(&FEC_MAC);
+    shard.extend_from_slice(&[0u8; 6]);
+    shard.extend_from_slice(&CONTROL_ETHER_TYPE.to_be_bytes());
+    shard.extend_from_slice(&group.to_be_bytes());
+    shard.push(index);
+    shard.push(data_shards);
+    shard
+}
+
+/// XOR bytes into the start of acc, which is at least as long
+fn xor_into(acc: &mut [u8], bytes: &[u8]) {
+    for (acc, byte) in acc.iter_mut().zip(bytes) {
+        *acc ^= byte;
+    }
+}
+
+/// Returns true if datagram is an FEC shard, rather than a frame
+pub fn is_shard(datagram: &[u8]) -> bool {
+    datagram.len() > FEC_HDR_LEN
+        && datagram[..6] == FEC_MAC
+        && datagram[12..14] == CONTROL_ETHER_TYPE.to_be_bytes()
+}
+
+/// Returns the datagram which shard carries, or None if it isn't a data shard
+pub fn data(shard: &[u8]) -> Option<&[u8]> {
+    match is_shard(shard) && shard[FEC_HDR_LEN - 1] == 0 {
+        true => Some(&shard[FEC_HDR_LEN..]),
+        false => None,
+    }
+}
+
+/// Group which some of the shards of have arrived
+#[derive(Debug)]
+struct Group {
+    /* Bit for each data shard which has arrived or been rebuilt, so copies are ignored */
+    received: u32,
+    len_xor: u16,
+    xor: Vec<u8>,
+    /* How many data shards the group has, and their XOR, once its parity arrives */
+    parity: Option<(u8, u16, Vec<u8>)>,
+    started: Instant,
+}
+
+impl Group {
+    /// Returns the datagram which the group's parity rebuilds, if it has
+    /// arrived, and all of the group's data shards but one have too
+    fn rebuild(&mut self) -> Option<Vec<u8>> {
+        let (data_shards, len_xor, parity) = self.parity.as_ref()?;
+        let missing: Vec<u8> = (0..*data_shards)
+            .filter(|index| self.received & (1 << index) == 0)
+            .collect();
+        let [index] = missing[..] else {
+            return None;
+        };
+        let len = usize::from(len_xor ^ self.len_xor);
+        if len > parity.len() {
+            return None;
+        }
+        let mut datagram = parity[..len].to_vec();
+        xor_into(&mut datagram, &self.xor);
+        self.received |= 1 << index;
+        Some(datagram)
+    }
+
+    /// Whether every data shard of the group has arrived or been rebuilt
+    fn is_complete(&self) -> bool {
+        self.parity
+            .as_ref()
+            .is_some_and(|(data_shards, ..)| self.received.count_ones() >= u32::from(*data_shards))
+    }
+}
+
+/// Rebuilds the datagrams lost from the groups which arrive from each
+/// sender, which is identified by a K (e.g. its address)
+#[derive(Debug)]
+pub struct Decoder<K> {
+    groups: HashMap<(K, u32), Group>,
+}
+
+impl<K> Default for Decoder<K> {
+    fn default() -> Decoder<K> {
+        Decoder {
+            groups: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Copy + Eq + Hash> Decoder<K> {
+    /// Take shard, which arrived from sender at now, returning the
+    /// datagrams to handle: a data shard's own, unless it is a copy, and
+    /// the one it or a parity shard rebuilt, if any. None is returned for
+    /// shards which aren't shards, or don't fit their group
+    pub fn add(&mut self, sender: K, shard: &[u8], now: Instant) -> Option<Vec<Vec<u8>>> {
+        if !is_shard(shard) {
+            return None;
+        }
+        let header = &shard[ETHER_HDR..FEC_HDR_LEN];
+        let id = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let (index, data_shards) = (header[4], header[5]);
+        let payload = &shard[FEC_HDR_LEN..];
+        let fits = match data_shards {
+            0 => index < FEC_GROUP_MAX,
+            _ => data_shards <= FEC_GROUP_MAX && index == data_shards,
+        };
+        if !fits {
+            return None;
+        }
+
+        /* Groups which have waited too long can't be rebuilt any more */
+        self.groups
+            .retain(|_, group| now.duration_since(group.started) < GROUP_TIMEOUT);
+
+        /* Data shards of groups which can't be kept are still handed on */
+        if !self.groups.contains_key(&(sender, id)) && self.groups.len() >= MAX_GROUPS {
+            return Some(match data_shards {
+                0 => vec![payload.to_vec()],
+                _ => Vec::new(),
+            });
+        }
+        let group = self.groups.entry((sender, id)).or_insert_with(|| Group {
+            received: 0,
+            len_xor: 0,
+            xor: Vec::new(),
+            parity: None,
+            started: now,
+        });
+
+        let mut datagrams = Vec::new();
+        match data_shards {
+            0 if group.received & (1 << index) != 0 => {}
+            0 => {
+                group.received |= 1 << index;
+                group.len_xor ^= payload.len() as u16;
+                if group.xor.len() < payload.len() {
+                    group.xor.resize(payload.len(), 0);
+                }
+                xor_into(&mut group.xor, payload);
+                datagrams.push(payload.to_vec());
+            }
+            _ => {
+                let [l0, l1, parity @ ..] = payload else {
+                    return None;
+                };
+                if group.parity.is_none() {
+                    group.parity =
+                        Some((data_shards, u16::from_be_bytes([*l0, *l1]), parity.to_vec()));
+                }
+            }
+        }
+        datagrams.extend(group.rebuild());
+
+        if group.is_complete() {
+            self.groups.remove(&(sender, id));
+        }
+        Some(datagrams)
+    }
+}
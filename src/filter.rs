@@ -0,0 +1,170 @@
+//! Frame filters
+//!
+//! Filters are written in a small subset of tcpdump's syntax,
+//! e.g. `arp and not src 02:00:00:00:00:01` or `vlan 10 and port 2`,
+//! and are made up of terms which must all match the frame. The
+//! vswitch's monitors and ACLs use them, as do the vport's rules for
+//! frames it drops rather than letting them enter or leave the overlay,
+//! which can't test the port frames came from, as only the vswitch has
+//! ports
+
+use crate::{error::ConfigError, frame::EthernetFrame, mac::MacAddr};
+
+/// Words which can start a term of a filter
+pub const FILTER_WORDS: [&str; 13] = [
+    "and",
+    "not",
+    "src",
+    "dst",
+    "mac",
+    "type",
+    "arp",
+    "ip",
+    "ip6",
+    "vlan",
+    "broadcast",
+    "multicast",
+    "port",
+];
+
+/// Property of a frame which a filter term tests
+#[derive(Clone, Debug)]
+enum Primitive {
+    /// Source MAC is the given MAC
+    Src(MacAddr),
+    /// Destination MAC is the given MAC
+    Dst(MacAddr),
+    /// Either MAC is the given MAC
+    Mac(MacAddr),
+    /// EtherType (inside the VLAN tag, if there is one) is the given type
+    EtherType(u16),
+    /// Frame is VLAN tagged, with the given VLAN ID if there is one
+    Vlan(Option<u16>),
+    /// Destination is the broadcast MAC
+    Broadcast,
+    /// Destination is a multicast (or broadcast) MAC
+    Multicast,
+    /// Frame was received from the port with the given ID
+    Port(u32),
+}
+
+/// Filter which frames can be matched against
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    /* Every (negated, primitive) term must match */
+    terms: Vec<(bool, Primitive)>,
+}
+
+impl Filter {
+    /// Parse a filter from its words, where no words matches every frame
+    pub fn parse(words: &[&str]) -> Result<Filter, ConfigError> {
+        let mut filter = Filter::default();
+        let mut words = words.iter().copied().peekable();
+
+        while let Some(word) = words.next() {
+            if word == "and" && !filter.terms.is_empty() {
+                continue;
+            }
+
+            let (negated, word) = match word {
+                "not" => (true, next_value(&mut words, "a term", word)?),
+                word => (false, word),
+            };
+
+            let mut value = |what: &'static str| next_value(&mut words, what, word);
+            let primitive = match word {
+                "src" => Primitive::Src(value("a MAC")?.parse()?),
+                "dst" => Primitive::Dst(value("a MAC")?.parse()?),
+                "mac" => Primitive::Mac(value("a MAC")?.parse()?),
+                "type" => Primitive::EtherType(parse_ether_type(value("an EtherType")?)?),
+                "arp" => Primitive::EtherType(0x0806),
+                "ip" => Primitive::EtherType(0x0800),
+                "ip6" => Primitive::EtherType(0x86DD),
+                "broadcast" => Primitive::Broadcast,
+                "multicast" => Primitive::Multicast,
+                "port" => {
+                    let id = value("a port ID")?;
+                    Primitive::Port(id.parse().map_err(|_| ConfigError::FilterValue {
+                        what: "port ID",
+                        value: id.to_string(),
+                    })?)
+                }
+                /* The VLAN ID is optional, as in tcpdump */
+                "vlan" => {
+                    let id = words.peek().and_then(|id| id.parse::<u16>().ok());
+                    if id.is_some() {
+                        words.next();
+                    }
+                    Primitive::Vlan(id)
+                }
+                _ => return Err(ConfigError::FilterTerm(word.to_string())),
+            };
+
+            filter.terms.push((negated, primitive));
+        }
+
+        Ok(filter)
+    }
+
+    /// Returns true if the filter tests the port frames were received
+    /// from, which only the vswitch knows
+    pub fn tests_port(&self) -> bool {
+        self.terms
+            .iter()
+            .any(|(_, primitive)| matches!(primitive, Primitive::Port(_)))
+    }
+
+    /// Returns true if frame, received from the port with ID in_port, matches the filter
+    pub fn matches(&self, frame: &[u8], in_port: u32) -> bool {
+        let Ok(EthernetFrame {
+            dst: dst_mac,
+            src: src_mac,
+            vlan,
+            ether_type,
+            ..
+        }) = EthernetFrame::parse(frame)
+        else {
+            return false;
+        };
+        let vlan_id = vlan.map(|tag| tag.vid);
+
+        self.terms.iter().all(|(negated, primitive)| {
+            let matched = match primitive {
+                Primitive::Src(mac) => src_mac == *mac,
+                Primitive::Dst(mac) => dst_mac == *mac,
+                Primitive::Mac(mac) => src_mac == *mac || dst_mac == *mac,
+                Primitive::EtherType(t) => ether_type == *t,
+                Primitive::Vlan(None) => vlan_id.is_some(),
+                Primitive::Vlan(Some(id)) => vlan_id == Some(*id),
+                Primitive::Broadcast => dst_mac.is_broadcast(),
+                Primitive::Multicast => dst_mac.is_multicast(),
+                Primitive::Port(id) => in_port == *id,
+            };
+            matched != *negated
+        })
+    }
+}
+
+/// Returns the next word, which is the value of the term word
+fn next_value<'a>(
+    words: &mut impl Iterator<Item = &'a str>,
+    what: &'static str,
+    word: &str,
+) -> Result<&'a str, ConfigError> {
+    words.next().ok_or_else(|| ConfigError::MissingFilterValue {
+        what,
+        term: word.to_string(),
+    })
+}
+
+/// Parse an EtherType given in decimal, or in hex with a 0x prefix
+pub fn parse_ether_type(value: &str) -> Result<u16, ConfigError> {
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => value.parse::<u16>(),
+    };
+    parsed.map_err(|_| ConfigError::FilterValue {
+        what: "EtherType",
+        value: value.to_string(),
+    })
+}
@@ -0,0 +1,159 @@
+//! Fragmentation of frames too large for the underlay
+//!
+//! A full-size frame, with its hop limit tag and the IP and UDP headers
+//! of the datagram carrying it, is larger than a 1500 byte underlay MTU,
+//! so would otherwise be fragmented by IP, which some paths drop. A
+//! vport which is told its tunnel MTU can ask the vswitch to fragment
+//! frames itself instead when it joins, saying how large a fragment it
+//! can receive. Once the vswitch agrees, each of them splits the frames
+//! it sends the other over UDP which are too large into fragments which
+//! fit, and reassembles the frames it receives in fragments
+//!
+//! Each fragment is sent in a datagram of its own, after a header
+//! shaped like a control frame's, but sent to a reserved multicast MAC
+//! of its own, so it is never taken for a frame. The header holds the
+//! ID of the frame the fragment is part of, the fragment's offset in
+//! the frame, and the frame's length, so the receiver knows when it
+//! has all of the frame. Frames whose fragments don't all arrive within
+//! REASSEMBLY_TIMEOUT are dropped, as one of them has been lost
+
+use crate::{control::CONTROL_ETHER_TYPE, tunnel::TUNNEL_FRAME_MAX, utilities::ETHER_HDR};
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    ops::Range,
+    time::{Duration, Instant},
+};
+
+/// Locally administered multicast MAC which fragments are sent to
+pub const FRAGMENT_MAC: [u8; 6] = [0x03, 0x4c, 0x32, 0x56, 0x50, 0x46];
+
+/// Length of the header in front of every fragment: the Ethernet
+/// header, and the frame's ID, the fragment's offset and frame's length
+pub const FRAGMENT_HDR_LEN: usize = ETHER_HDR + 4 + 2 + 2;
+
+/// Smallest fragment (including its header) which a vport can ask for,
+/// so no frame is split into more than a few dozen fragments
+pub const MIN_FRAGMENT_SIZE: usize = 256;
+
+/// How long the fragments of a frame have to all arrive
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Most frames being reassembled at once, to bound the memory which
+/// lost fragments (or a flood of bogus ones) can take
+const MAX_PARTIAL_FRAMES: usize = 256;
+
+/// Returns the fragments which frame, whose ID is id, is split into,
+/// each of which is at most size bytes long, including its header
+///
+/// size must be at least MIN_FRAGMENT_SIZE, and frame no larger
+/// than TUNNEL_FRAME_MAX
+pub fn fragment(frame: &[u8], id: u32, size: usize) -> Vec<Vec<u8>> {
+    frame
+        .chunks(size - FRAGMENT_HDR_LEN)
+        .enumerate()
+        .map(|(index, piece)| {
+            let offset = index * (size - FRAGMENT_HDR_LEN);
+            let mut fragment = Vec::with_capacity(FRAGMENT_HDR_LEN + piece.len());
+            fragment.extend_from_slice(&FRAGMENT_MAC);
+            fragment.extend_from_slice(&[0u8; 6]);
+            fragment.extend_from_slice(&CONTROL_ETHER_TYPE.to_be_bytes());
+            fragment.extend_from_slice(&id.to_be_bytes());
+            fragment.extend_from_slice(&(offset as u16).to_be_bytes());
+            fragment.extend_from_slice(&(frame.len() as u16).to_be_bytes());
+            fragment.extend_from_slice(piece);
+            fragment
+        })
+        .collect()
+}
+
+/// Returns true if datagram is a fragment of a frame, rather than a frame
+pub fn is_fragment(datagram: &[u8]) -> bool {
+    datagram.len() > FRAGMENT_HDR_LEN
+        && datagram[..6] == FRAGMENT_MAC
+        && datagram[12..14] == CONTROL_ETHER_TYPE.to_be_bytes()
+}
+
+/// Frame which some of the fragments of have arrived
+#[derive(Debug)]
+struct PartialFrame {
+    frame: Vec<u8>,
+    /* Bytes of the frame which the fragments which have arrived hold, which
+     * never overlap, so the frame is whole once they add up to its length */
+    pieces: Vec<Range<usize>>,
+    received: usize,
+    started: Instant,
+}
+
+/// Reassembles the frames whose fragments arrive from each sender,
+/// which is identified by a K (e.g. its address)
+#[derive(Debug)]
+pub struct Reassembler<K> {
+    partial: HashMap<(K, u32), PartialFrame>,
+}
+
+impl<K> Default for Reassembler<K> {
+    fn default() -> Reassembler<K> {
+        Reassembler {
+            partial: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Copy + Eq + Hash> Reassembler<K> {
+    /// Take fragment, which arrived from sender at now, returning the frame
+    /// it is part of if it was the last of its fragments to arrive. Fragments
+    /// which don't fit the frame they say they are part of, or which overlap
+    /// one which has already arrived (such as a copy of it), are dropped
+    pub fn add(&mut self, sender: K, fragment: &[u8], now: Instant) -> Option<Vec<u8>> {
+        if !is_fragment(fragment) {
+            return None;
+        }
+        let header = &fragment[ETHER_HDR..FRAGMENT_HDR_LEN];
+        let id = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let offset = u16::from_be_bytes([header[4], header[5]]);
+        let len = usize::from(u16::from_be_bytes([header[6], header[7]]));
+        let piece = &fragment[FRAGMENT_HDR_LEN..];
+        let start = usize::from(offset);
+        if len > TUNNEL_FRAME_MAX || start + piece.len() > len {
+            return None;
+        }
+
+        /* Frames which have waited too long have lost a fragment */
+        self.partial
+            .retain(|_, partial| now.duration_since(partial.started) < REASSEMBLY_TIMEOUT);
+
+        if !self.partial.contains_key(&(sender, id)) && self.partial.len() >= MAX_PARTIAL_FRAMES {
+            return None;
+        }
+        let partial = self
+            .partial
+            .entry((sender, id))
+            .or_insert_with(|| PartialFrame {
+                frame: vec![0u8; len],
+                pieces: Vec::new(),
+                received: 0,
+                started: now,
+            });
+        let range = start..start + piece.len();
+        if partial.frame.len() != len
+            || partial
+                .pieces
+                .iter()
+                .any(|known| known.start < range.end && range.start < known.end)
+        {
+            return None;
+        }
+        partial.frame[range.clone()].copy_from_slice(piece);
+        partial.pieces.push(range);
+        partial.received += piece.len();
+
+        match partial.received == len {
+            true => self
+                .partial
+                .remove(&(sender, id))
+                .map(|partial| partial.frame),
+            false => None,
+        }
+    }
+}
@@ -0,0 +1,270 @@
+//! Typed views of Ethernet frames
+//!
+//! EthernetFrame parses the header of a frame it borrows, i.e. its dst
+//! and src MACs, its 802.1Q tag if it has one, and the EtherType after
+//! that, and gives the payload which follows, checking that the frame
+//! is long enough to hold them, and copying none of the payload. A frame with
+//! any other outer tag, such as an 802.1ad service tag, or a hop limit
+//! tag (see l2vpn::tunnel), has that tag's EtherType, and the rest of
+//! the tag starts its payload
+//!
+//! push_vlan(), pop_vlan() and rewrite_vid() add, remove and change the
+//! outer tag of a frame, which follows its MACs, as VlanHeaders, so they
+//! work on 802.1ad service tags as well as 802.1Q tags
+
+use crate::{
+    error::ProtocolError,
+    mac::MacAddr,
+    utilities::{VlanTag, ETHER_HDR, QINQ_ETHER_TYPE, VLAN_ETHER_TYPE},
+};
+
+/// Offset of the outer tag (or EtherType) of a frame, which follows its MACs
+const TAG_OFFSET: usize = 12;
+
+/// Length of an 802.1Q tag (its EtherType and TCI)
+const VLAN_TAG_LEN: usize = 4;
+
+/// Header and payload of an Ethernet frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EthernetFrame<'a> {
+    pub dst: MacAddr,
+    pub src: MacAddr,
+    /// The frame's 802.1Q tag, if it has one
+    pub vlan: Option<VlanTag>,
+    /// EtherType of the payload, which follows the 802.1Q tag if there is one
+    pub ether_type: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> EthernetFrame<'a> {
+    /// Parse frame's header, refusing a frame too short to hold it
+    pub fn parse(frame: &'a [u8]) -> Result<EthernetFrame<'a>, ProtocolError> {
+        let truncated = || ProtocolError::TruncatedFrame { len: frame.len() };
+        let (dst, rest) = frame.split_first_chunk().ok_or_else(truncated)?;
+        let (src, rest) = rest.split_first_chunk().ok_or_else(truncated)?;
+        let (outer_type, rest) = rest.split_first_chunk().ok_or_else(truncated)?;
+
+        let (vlan, ether_type, payload) = match u16::from_be_bytes(*outer_type) {
+            VLAN_ETHER_TYPE => {
+                let (tci, rest) = rest.split_first_chunk().ok_or_else(truncated)?;
+                let (ether_type, payload) = rest.split_first_chunk().ok_or_else(truncated)?;
+                (
+                    Some(VlanTag::from_tci(u16::from_be_bytes(*tci))),
+                    u16::from_be_bytes(*ether_type),
+                    payload,
+                )
+            }
+            ether_type => (None, ether_type, rest),
+        };
+
+        Ok(EthernetFrame {
+            dst: MacAddr(*dst),
+            src: MacAddr(*src),
+            vlan,
+            ether_type,
+            payload,
+        })
+    }
+
+    /// Returns the length of the frame's header, which is the offset of its payload
+    pub fn header_len(&self) -> usize {
+        match self.vlan {
+            Some(_) => ETHER_HDR + VLAN_TAG_LEN,
+            None => ETHER_HDR,
+        }
+    }
+}
+
+/// 802.1Q tag (or 802.1ad service tag) as it is carried in a frame,
+/// i.e. the EtherType which marks it as a tag, and its fields
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VlanHeader {
+    /// EtherType of the tag, VLAN_ETHER_TYPE or QINQ_ETHER_TYPE
+    pub tpid: u16,
+    pub tag: VlanTag,
+}
+
+impl VlanHeader {
+    /// Returns an 802.1Q tag for vid, with no priority
+    pub fn new(vid: u16) -> VlanHeader {
+        VlanHeader {
+            tpid: VLAN_ETHER_TYPE,
+            tag: VlanTag {
+                pcp: 0,
+                dei: false,
+                vid,
+            },
+        }
+    }
+
+    /// Parse the tag at the start of bytes, or return None if
+    /// they don't start with an 802.1Q or 802.1ad tag
+    pub fn parse(bytes: &[u8]) -> Option<VlanHeader> {
+        let [tpid_hi, tpid_lo, tci_hi, tci_lo] = *bytes.first_chunk()?;
+        match u16::from_be_bytes([tpid_hi, tpid_lo]) {
+            tpid @ (VLAN_ETHER_TYPE | QINQ_ETHER_TYPE) => Some(VlanHeader {
+                tpid,
+                tag: VlanTag::from_tci(u16::from_be_bytes([tci_hi, tci_lo])),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Returns the tag as it is carried in a frame
+    pub fn encode(&self) -> [u8; VLAN_TAG_LEN] {
+        let [tpid_hi, tpid_lo] = self.tpid.to_be_bytes();
+        let [tci_hi, tci_lo] = self.tag.tci().to_be_bytes();
+        [tpid_hi, tpid_lo, tci_hi, tci_lo]
+    }
+}
+
+/// Push header onto frame, in front of any tags it already has,
+/// refusing a frame too short to hold its MACs
+pub fn push_vlan(frame: &mut Vec<u8>, header: VlanHeader) -> Result<(), ProtocolError> {
+    if frame.len() < TAG_OFFSET {
+        return Err(ProtocolError::TruncatedFrame { len: frame.len() });
+    }
+    frame.splice(TAG_OFFSET..TAG_OFFSET, header.encode());
+    Ok(())
+}
+
+/// Remove the outer tag of frame and return it, or None if it is
+/// untagged, or too short to hold the EtherType which follows the tag
+pub fn pop_vlan(frame: &mut Vec<u8>) -> Option<VlanHeader> {
+    if frame.len() < ETHER_HDR + VLAN_TAG_LEN {
+        return None;
+    }
+    let header = VlanHeader::parse(frame.get(TAG_OFFSET..)?)?;
+    frame.drain(TAG_OFFSET..TAG_OFFSET + VLAN_TAG_LEN);
+    Some(header)
+}
+
+/// Change the VLAN ID of frame's outer tag to vid, keeping its kind and
+/// priority, and return the one it had, or None if frame is untagged
+pub fn rewrite_vid(frame: &mut [u8], vid: u16) -> Option<u16> {
+    let tag_bytes = frame.get_mut(TAG_OFFSET..)?;
+    let mut header = VlanHeader::parse(tag_bytes)?;
+    let old_vid = header.tag.vid;
+    header.tag.vid = vid;
+    tag_bytes[..VLAN_TAG_LEN].copy_from_slice(&header.encode());
+    Some(old_vid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utilities::ETHER_FRAME_MIN;
+
+    /// Returns an untagged IPv4 frame of len bytes
+    fn untagged(len: usize) -> Vec<u8> {
+        let mut frame = vec![0u8; len];
+        frame[..6].copy_from_slice(&[0xff; 6]);
+        frame[6..12].copy_from_slice(&[0x02, 0, 0, 0, 0, 1]);
+        frame[12..14].copy_from_slice(&0x0800u16.to_be_bytes());
+        frame
+    }
+
+    #[test]
+    fn push_then_pop_round_trips() {
+        let original = untagged(ETHER_FRAME_MIN);
+        let mut frame = original.clone();
+        let header = VlanHeader {
+            tpid: VLAN_ETHER_TYPE,
+            tag: VlanTag {
+                pcp: 5,
+                dei: true,
+                vid: 100,
+            },
+        };
+
+        push_vlan(&mut frame, header).unwrap();
+        assert_eq!(frame.len(), ETHER_FRAME_MIN + VLAN_TAG_LEN);
+        let parsed = EthernetFrame::parse(&frame).unwrap();
+        assert_eq!(parsed.vlan, Some(header.tag));
+        assert_eq!(parsed.ether_type, 0x0800);
+        assert_eq!(parsed.header_len(), ETHER_HDR + VLAN_TAG_LEN);
+
+        assert_eq!(pop_vlan(&mut frame), Some(header));
+        assert_eq!(frame, original);
+    }
+
+    #[test]
+    fn push_keeps_outer_qinq_tpid() {
+        let mut frame = untagged(ETHER_FRAME_MIN);
+        push_vlan(&mut frame, VlanHeader::new(10)).unwrap();
+        let service = VlanHeader {
+            tpid: QINQ_ETHER_TYPE,
+            ..VlanHeader::new(20)
+        };
+        push_vlan(&mut frame, service).unwrap();
+
+        assert_eq!(&frame[12..14], &QINQ_ETHER_TYPE.to_be_bytes());
+        assert_eq!(
+            EthernetFrame::parse(&frame).unwrap().ether_type,
+            QINQ_ETHER_TYPE
+        );
+        assert_eq!(rewrite_vid(&mut frame, 30), Some(20));
+        assert_eq!(
+            pop_vlan(&mut frame),
+            Some(VlanHeader {
+                tpid: QINQ_ETHER_TYPE,
+                ..VlanHeader::new(30)
+            })
+        );
+        assert_eq!(pop_vlan(&mut frame), Some(VlanHeader::new(10)));
+        assert_eq!(frame, untagged(ETHER_FRAME_MIN));
+    }
+
+    #[test]
+    fn rewrite_vid_keeps_pcp_and_dei() {
+        let mut frame = untagged(ETHER_FRAME_MIN);
+        let tag = VlanTag {
+            pcp: 6,
+            dei: true,
+            vid: 100,
+        };
+        push_vlan(
+            &mut frame,
+            VlanHeader {
+                tpid: VLAN_ETHER_TYPE,
+                tag,
+            },
+        )
+        .unwrap();
+
+        assert_eq!(rewrite_vid(&mut frame, 200), Some(100));
+        assert_eq!(
+            EthernetFrame::parse(&frame).unwrap().vlan,
+            Some(VlanTag { vid: 200, ..tag })
+        );
+    }
+
+    #[test]
+    fn untagged_frames_are_left_alone() {
+        let mut frame = untagged(ETHER_FRAME_MIN);
+        assert_eq!(pop_vlan(&mut frame), None);
+        assert_eq!(rewrite_vid(&mut frame, 200), None);
+        assert_eq!(frame, untagged(ETHER_FRAME_MIN));
+    }
+
+    #[test]
+    fn short_frames_are_not_popped() {
+        let mut tagged = untagged(ETHER_FRAME_MIN);
+        push_vlan(&mut tagged, VlanHeader::new(100)).unwrap();
+        for len in 0..ETHER_HDR + VLAN_TAG_LEN {
+            let mut frame = tagged[..len].to_vec();
+            assert_eq!(pop_vlan(&mut frame), None, "{len} byte frame");
+            assert_eq!(frame, tagged[..len]);
+        }
+    }
+
+    #[test]
+    fn push_refuses_frames_without_macs() {
+        let mut frame = vec![0u8; TAG_OFFSET - 1];
+        assert!(matches!(
+            push_vlan(&mut frame, VlanHeader::new(100)),
+            Err(ProtocolError::TruncatedFrame { len }) if len == TAG_OFFSET - 1
+        ));
+        assert_eq!(frame.len(), TAG_OFFSET - 1);
+    }
+}
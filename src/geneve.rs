@@ -0,0 +1,135 @@
+//! GENEVE encapsulation of the frames between vports and the vswitch
+//!
+//! In GENEVE mode, every frame is carried in a UDP datagram after the
+//! header of RFC 8926, which holds the 24 bit VNI of the network the
+//! frame belongs to, followed by option TLVs carrying metadata about
+//! it. This lets the vswitch exchange frames with OVS and other GENEVE
+//! tunnel endpoints, as well as with vports
+//!
+//! The vswitch adds two options of its own to the frames it sends: the
+//! tenant ID (the segment the frame was switched in) and the ingress
+//! port (the ID of the vswitch's port the frame came from). They are
+//! in the experimental option class, and not critical, so endpoints
+//! which don't know them skip them. Datagrams with a critical option
+//! which isn't known are dropped, as RFC 8926 requires, as are control
+//! (OAM) datagrams and those carrying anything but Ethernet frames
+//!
+//! As with VXLAN, datagrams are always sent to the GENEVE port of
+//! their destination, rather than the port they came from
+
+/// UDP port assigned to GENEVE by IANA
+pub const GENEVE_PORT: u16 = 6081;
+
+/// Length of the GENEVE header in front of the options
+pub const GENEVE_HDR_LEN: usize = 8;
+
+/// Longest GENEVE header, with as many options as it can carry
+pub const GENEVE_HDR_MAX: usize = GENEVE_HDR_LEN + 0x3F * 4;
+
+/// Most bytes which the header and the options added by the
+/// vswitch add to a frame, taken from the tunnel MTU
+pub const GENEVE_OVERHEAD: usize = GENEVE_HDR_LEN + 2 * (OPTION_HDR_LEN + 4);
+
+/// Option class which the vswitch's own options are in, the first
+/// of those set aside for experimental use
+pub const OPTION_CLASS: u16 = 0xFFF0;
+
+/// Option types of the tenant ID and ingress port
+pub const OPTION_TENANT_ID: u8 = 0x01;
+pub const OPTION_INGRESS_PORT: u8 = 0x02;
+
+/// Protocol type of Ethernet frames, as in NVGRE (Transparent Ethernet Bridging)
+const ETHERNET_PROTOCOL: u16 = 0x6558;
+
+/// Length of the class, type and length before each option's data
+const OPTION_HDR_LEN: usize = 4;
+
+/// Flag in the second byte of the header saying it is a control datagram
+const FLAG_OAM: u8 = 0x80;
+
+/// Bit of an option's type which says it is critical
+const OPTION_CRITICAL: u8 = 0x80;
+
+/// Metadata carried in the options of a GENEVE datagram
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Metadata {
+    /// Segment the frame was switched in
+    pub tenant_id: Option<u32>,
+    /// ID of the vswitch port the frame was received on
+    pub ingress_port: Option<u32>,
+}
+
+/// Returns frame after a GENEVE header carrying vni and the options
+/// holding metadata, ready to be sent
+pub fn encapsulate(vni: u32, metadata: &Metadata, frame: &[u8]) -> Vec<u8> {
+    let options: Vec<(u8, u32)> = [
+        (OPTION_TENANT_ID, metadata.tenant_id),
+        (OPTION_INGRESS_PORT, metadata.ingress_port),
+    ]
+    .into_iter()
+    .filter_map(|(kind, value)| value.map(|value| (kind, value)))
+    .collect();
+    let options_len = options.len() * (OPTION_HDR_LEN + 4);
+
+    let [_, vni_hi, vni_mid, vni_lo] = vni.to_be_bytes();
+    let [protocol_hi, protocol_lo] = ETHERNET_PROTOCOL.to_be_bytes();
+    let mut datagram = Vec::with_capacity(GENEVE_HDR_LEN + options_len + frame.len());
+    datagram.extend_from_slice(&[
+        (options_len / 4) as u8,
+        0,
+        protocol_hi,
+        protocol_lo,
+        vni_hi,
+        vni_mid,
+        vni_lo,
+        0,
+    ]);
+    for (kind, value) in options {
+        /* Each option's length is given in 4 byte words, excluding its header */
+        datagram.extend_from_slice(&OPTION_CLASS.to_be_bytes());
+        datagram.extend_from_slice(&[kind, 1]);
+        datagram.extend_from_slice(&value.to_be_bytes());
+    }
+    datagram.extend_from_slice(frame);
+    datagram
+}
+
+/// Returns the VNI of the GENEVE datagram, the metadata in its options
+/// and the frame it carries, or None if it is malformed, isn't an
+/// Ethernet frame, is a control datagram or has a critical option
+/// which isn't known
+pub fn decapsulate(datagram: &[u8]) -> Option<(u32, Metadata, &[u8])> {
+    if datagram.len() < GENEVE_HDR_LEN
+        || datagram[0] >> 6 != 0
+        || datagram[1] & FLAG_OAM != 0
+        || datagram[2..4] != ETHERNET_PROTOCOL.to_be_bytes()
+    {
+        return None;
+    }
+    let frame_start = GENEVE_HDR_LEN + usize::from(datagram[0] & 0x3F) * 4;
+    let mut options = datagram.get(GENEVE_HDR_LEN..frame_start)?;
+    let vni = u32::from_be_bytes([0, datagram[4], datagram[5], datagram[6]]);
+
+    let mut metadata = Metadata::default();
+    while !options.is_empty() {
+        let option_len = OPTION_HDR_LEN + usize::from(*options.get(3)? & 0x1F) * 4;
+        let option = options.get(..option_len)?;
+        let class = u16::from_be_bytes([option[0], option[1]]);
+        let kind = option[2];
+        let value = option
+            .get(OPTION_HDR_LEN..)
+            .and_then(|data| data.try_into().ok())
+            .map(u32::from_be_bytes);
+
+        match (class, kind, value) {
+            (OPTION_CLASS, OPTION_TENANT_ID, Some(value)) => metadata.tenant_id = Some(value),
+            (OPTION_CLASS, OPTION_INGRESS_PORT, Some(value)) => metadata.ingress_port = Some(value),
+            /* Options which aren't known can only be skipped if they aren't critical */
+            _ if kind & OPTION_CRITICAL != 0 => return None,
+            _ => {}
+        }
+        options = &options[option_len..];
+    }
+
+    Some((vni, metadata, &datagram[frame_start..]))
+}
@@ -0,0 +1,59 @@
+//! L2TPv3 encapsulation of the frames between a vport and a pseudowire peer
+//!
+//! In L2TP mode, every frame is carried in a UDP datagram after the
+//! 8 byte data message header of RFC 3931 (UDP encapsulation), which
+//! holds the 32 bit ID of the session the frame belongs to. This lets
+//! a vport terminate an Ethernet pseudowire against a router or a Linux
+//! l2tp_eth interface, rather than needing a vswitch on the other end
+//!
+//! Sessions are set up statically on both ends, with no control
+//! connection, so each end is told the session ID it receives with and
+//! the one its peer receives with. Cookies and the L2-specific sublayer
+//! aren't supported, so the peer's session must be set up without them
+//! (e.g. ```l2spec_type none``` for Linux)
+
+/// UDP port assigned to L2TP by IANA
+pub const L2TP_PORT: u16 = 1701;
+
+/// Length of the L2TPv3 data message header in front of every frame
+pub const L2TP_HDR_LEN: usize = 8;
+
+/// Version carried in the low bits of the first two bytes of the header
+const VERSION: u8 = 3;
+
+/// Flag in the first byte of the header saying it is a control message
+const FLAG_CONTROL: u8 = 0x80;
+
+/// Parse the session IDs given on the command line as
+/// <session_id>:<peer_session_id>, neither of which can be 0, as
+/// that is kept for control messages
+pub fn parse_sessions(value: &str) -> Option<(u32, u32)> {
+    let (session_id, peer_session_id) = value.split_once(':')?;
+    let session_id = session_id.parse::<u32>().ok().filter(|id| *id != 0)?;
+    let peer_session_id = peer_session_id.parse::<u32>().ok().filter(|id| *id != 0)?;
+    Some((session_id, peer_session_id))
+}
+
+/// Returns frame after an L2TPv3 data message header carrying
+/// session_id, ready to be sent
+pub fn encapsulate(session_id: u32, frame: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(L2TP_HDR_LEN + frame.len());
+    datagram.extend_from_slice(&[0, VERSION, 0, 0]);
+    datagram.extend_from_slice(&session_id.to_be_bytes());
+    datagram.extend_from_slice(frame);
+    datagram
+}
+
+/// Returns the session ID of the L2TPv3 data message, and the frame
+/// it carries, or None if it is too short, is a control message or
+/// isn't version 3
+pub fn decapsulate(datagram: &[u8]) -> Option<(u32, &[u8])> {
+    if datagram.len() < L2TP_HDR_LEN
+        || datagram[0] & FLAG_CONTROL != 0
+        || datagram[1] & 0x0F != VERSION
+    {
+        return None;
+    }
+    let session_id = u32::from_be_bytes([datagram[4], datagram[5], datagram[6], datagram[7]]);
+    (session_id != 0).then_some((session_id, &datagram[L2TP_HDR_LEN..]))
+}
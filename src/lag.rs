@@ -0,0 +1,82 @@
+//! Link aggregation between vports and the vswitch
+//!
+//! A vport with several underlay paths to its vswitch, e.g. through
+//! several NICs, can reach it over a UDP socket on each of them, and
+//! the vswitch aggregates these links into the vport's one port. Both
+//! ends spread the frames they send across the links by a hash of each
+//! frame's flow, so the frames of one flow always take the same link,
+//! and arrive in order, while different flows share the links
+//!
+//! The hash covers the IPv4 addresses and protocol of a packet, and
+//! its TCP or UDP ports unless it is fragmented, or just the MACs of
+//! frames which aren't IPv4. Compressed frames are hashed as they were
+//! before being compressed, as a flow's frames may not all compress
+
+use crate::{
+    compression::{decompress, is_compressed},
+    tunnel::{hop_limit, HOP_LIMIT_TAG_LEN},
+    utilities::{QINQ_ETHER_TYPE, VLAN_ETHER_TYPE},
+};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+const IPV4_ETHER_TYPE: u16 = 0x0800;
+const TCP_PROTOCOL: u8 = 6;
+const UDP_PROTOCOL: u8 = 17;
+
+/// Returns the index of the link, out of links, which frame is sent over
+pub fn pick_link(frame: &[u8], links: usize) -> usize {
+    match links {
+        0 | 1 => 0,
+        _ => (flow_hash(frame) % links as u64) as usize,
+    }
+}
+
+/// Returns a hash of the flow which frame belongs to, which is the same
+/// for every frame of the flow, whether or not it has a hop limit tag
+pub fn flow_hash(frame: &[u8]) -> u64 {
+    if is_compressed(frame) {
+        if let Some(frame) = decompress(frame) {
+            return flow_hash(&frame);
+        }
+    }
+
+    let mut hasher = DefaultHasher::new();
+    frame.get(..12).unwrap_or(frame).hash(&mut hasher);
+
+    /* The IPv4 header follows any VLAN tags */
+    let mut offset = match hop_limit(frame) {
+        Some(_) => 12 + HOP_LIMIT_TAG_LEN,
+        None => 12,
+    };
+    let ether_type = loop {
+        let Some(ether_type) = frame.get(offset..offset + 2) else {
+            return hasher.finish();
+        };
+        match u16::from_be_bytes([ether_type[0], ether_type[1]]) {
+            VLAN_ETHER_TYPE | QINQ_ETHER_TYPE => offset += 4,
+            ether_type => break ether_type,
+        }
+    };
+    if ether_type != IPV4_ETHER_TYPE {
+        return hasher.finish();
+    }
+
+    let Some(packet) = frame.get(offset + 2..).filter(|packet| packet.len() >= 20) else {
+        return hasher.finish();
+    };
+    let (protocol, addrs) = (packet[9], &packet[12..20]);
+    (protocol, addrs).hash(&mut hasher);
+
+    /* Later fragments carry no ports, so no fragment is hashed by them */
+    let header_len = usize::from(packet[0] & 0x0F) * 4;
+    let fragmented = u16::from_be_bytes([packet[6], packet[7]]) & 0x3FFF != 0;
+    if matches!(protocol, TCP_PROTOCOL | UDP_PROTOCOL) && !fragmented {
+        if let Some(ports) = packet.get(header_len..header_len + 4) {
+            ports.hash(&mut hasher);
+        }
+    }
+    hasher.finish()
+}
@@ -1,2 +1,67 @@
-//! Declare utilities module
+//! Declare library modules
+pub mod arp;
+pub mod auth;
+pub mod batch;
+pub mod compression;
+pub mod control;
+pub mod dedup;
+pub mod direct;
+pub mod dscp;
+pub mod endpoint;
+pub mod error;
+pub mod fec;
+pub mod filter;
+pub mod fragment;
+pub mod frame;
+pub mod geneve;
+pub mod l2tp;
+pub mod lag;
+pub mod logging;
+pub mod mac;
+pub mod mtu;
+pub mod nic;
+pub mod platform;
+pub mod pmtud;
+pub mod proxy;
+pub mod stp;
+pub mod stun;
+pub mod supervisor;
+pub mod switch;
+pub mod switching;
+pub mod systemd;
+pub mod tap;
+pub mod tcp;
+pub mod tenant;
+pub mod timer;
+pub mod transport;
+pub mod tun;
+pub mod tunnel;
 pub mod utilities;
+pub mod vport;
+pub mod vxlan;
+
+/*
+ * The admin socket, handing over tap interfaces and vsock links need
+ * Unix and AF_VSOCK sockets, and daemons need fork, which Windows
+ * doesn't have
+ */
+#[cfg(unix)]
+pub mod admin;
+#[cfg(unix)]
+pub mod daemon;
+#[cfg(unix)]
+pub mod fdpass;
+#[cfg(unix)]
+pub mod vsock;
+
+/* Shared memory links need memfds and eventfds, which only Linux has */
+#[cfg(target_os = "linux")]
+pub mod shm;
+
+#[cfg(feature = "vhost-user")]
+pub mod vhost_user;
+
+#[cfg(feature = "dtls")]
+pub mod dtls;
+#[cfg(feature = "quic")]
+pub mod quic;
@@ -0,0 +1,73 @@
+//! Logging of the frames passing through the vswitch and vports
+//!
+//! Logging every frame is useful when following traffic through
+//! the L2VPN network, but costly on busy hosts, so the L2VPN_LOG
+//! environment variable sets how much is logged:
+//!
+//! * `frames` (the default) logs every frame, as well as events
+//! * `info` only logs events, such as vports connecting
+//!
+//! When frames are not being logged, log_frame! does no formatting
+//! or allocation at all. Building without the frame-log feature
+//! compiles frame logging out entirely
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Name of the environment variable which sets the log level
+pub const LOG_ENV_VAR: &str = "L2VPN_LOG";
+
+/// True if frame logging was compiled in
+pub const FRAME_LOG: bool = cfg!(feature = "frame-log");
+
+/// How much is logged, in increasing order of verbosity
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum LogLevel {
+    /// Events such as vports connecting and MACs being learned
+    Info = 0,
+    /// Every frame sent and received, in addition to events
+    Frames = 1,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Frames as u8);
+
+/// Set the log level from L2VPN_LOG, warning about (and
+/// ignoring) a value which is not a log level
+pub fn init() {
+    let Ok(value) = std::env::var(LOG_ENV_VAR) else {
+        return;
+    };
+
+    match value.as_str() {
+        "info" => set_level(LogLevel::Info),
+        "frames" => set_level(LogLevel::Frames),
+        _ => eprintln!(
+            "Ignoring {}='{}', expected 'info' or 'frames'",
+            LOG_ENV_VAR, value
+        ),
+    }
+}
+
+/// Set the log level
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Returns true if messages at level are being logged
+#[inline]
+pub fn enabled(level: LogLevel) -> bool {
+    LEVEL.load(Ordering::Relaxed) >= level as u8
+}
+
+/// Print a message about a frame, like println!, if frames are being
+/// logged. The arguments are not evaluated or formatted otherwise
+#[macro_export]
+macro_rules! log_frame {
+    ($($arg:tt)*) => {
+        if $crate::logging::FRAME_LOG
+            && $crate::logging::enabled($crate::logging::LogLevel::Frames)
+        {
+            println!($($arg)*);
+        }
+    };
+}
@@ -0,0 +1,86 @@
+//! MAC addresses
+//!
+//! MacAddr is what MAC tables are keyed by and what log messages print,
+//! in the colon separated form which the admin socket, config files and
+//! command line options take back
+
+use crate::error::ConfigError;
+use std::{fmt, str::FromStr};
+
+/// MAC address of an Ethernet interface, or of a group of them
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    /// The broadcast MAC, which every interface receives
+    pub const BROADCAST: MacAddr = MacAddr([0xFF; 6]);
+
+    /// Returns true if this is the broadcast MAC
+    pub fn is_broadcast(&self) -> bool {
+        *self == MacAddr::BROADCAST
+    }
+
+    /// Returns true if this is a group MAC (which includes the broadcast
+    /// MAC), i.e. the I/G bit of its first octet is set
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 0x01 != 0
+    }
+
+    /// Returns true if this MAC was assigned by an administrator, rather
+    /// than by the NIC's vendor, i.e. the U/L bit of its first octet is set
+    pub fn is_locally_administered(&self) -> bool {
+        self.0[0] & 0x02 != 0
+    }
+
+    /// Returns the MAC's octets
+    pub fn octets(&self) -> [u8; 6] {
+        self.0
+    }
+}
+
+impl From<[u8; 6]> for MacAddr {
+    fn from(octets: [u8; 6]) -> MacAddr {
+        MacAddr(octets)
+    }
+}
+
+impl From<MacAddr> for [u8; 6] {
+    fn from(mac: MacAddr) -> [u8; 6] {
+        mac.0
+    }
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+impl FromStr for MacAddr {
+    type Err = ConfigError;
+
+    /// Parse a MAC in the form it is displayed in, i.e. six octets of two
+    /// hex digits each, separated by colons
+    fn from_str(mac: &str) -> Result<MacAddr, ConfigError> {
+        let invalid = || ConfigError::Mac(mac.to_string());
+        let mut octets = [0u8; 6];
+        let mut parts = mac.split(':');
+        for octet in octets.iter_mut() {
+            let part = parts.next().ok_or_else(invalid)?;
+            /* from_str_radix would also take a sign, or a single digit */
+            if part.len() != 2 || !part.bytes().all(|digit| digit.is_ascii_hexdigit()) {
+                return Err(invalid());
+            }
+            *octet = u8::from_str_radix(part, 16).map_err(|_| invalid())?;
+        }
+        match parts.next() {
+            Some(_) => Err(invalid()),
+            None => Ok(MacAddr(octets)),
+        }
+    }
+}
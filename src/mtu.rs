@@ -0,0 +1,305 @@
+//! Fitting the IP packets carried by the L2VPN into the tunnel MTU
+//!
+//! Frames are carried in UDP datagrams, so the largest IP packet a
+//! frame can hold is smaller than the MTU of the underlay network
+//! (the tunnel MTU) by the headers wrapped around it. A vport told
+//! the tunnel MTU clamps the MSS option of the TCP SYNs crossing its
+//! tap interface, so TCP connections never send segments too large
+//! to be carried
+//!
+//! Other IP packets which are too large, and which can't be fragmented
+//! (IPv4 packets with the don't fragment flag, and IPv6 packets), are
+//! answered with an ICMPv4 fragmentation needed or ICMPv6 packet too
+//! big message, as a router would, so the sender's path MTU discovery
+//! lowers its packet size, rather than the packets being lost
+
+use crate::{frame::EthernetFrame, tunnel::HOP_LIMIT_TAG_LEN, utilities::ETHER_HDR};
+
+/// Size of the outer IPv4 and UDP headers of the datagrams carrying frames
+pub const UDP_TUNNEL_OVERHEAD: usize = 20 + 8;
+
+/// Most bytes which the DTLS record carrying a frame adds to it, when
+/// DTLS is on: the record header, explicit nonce and authentication tag
+pub const DTLS_OVERHEAD: usize = 13 + 8 + 16;
+
+/// Most bytes which the QUIC packet carrying a frame adds to it, when
+/// vports reach the vswitch over QUIC: the short header with its
+/// connection ID and packet number, the DATAGRAM frame's type and
+/// length, and the authentication tag
+pub const QUIC_OVERHEAD: usize = 1 + 8 + 4 + 1 + 2 + 16;
+
+/// Bytes which authenticating a frame adds to it, when frame
+/// authentication is on: its sender's ID, its sequence number,
+/// and the first half of its HMAC-SHA256
+pub const AUTH_OVERHEAD: usize = 8 + 8 + 16;
+
+/// Returns the smallest tunnel MTU which carries the untagged frames
+/// holding packets of overlay_mtu bytes without them being fragmented
+pub fn tunnel_mtu_needed(overlay_mtu: usize) -> usize {
+    overlay_mtu + ETHER_HDR + HOP_LIMIT_TAG_LEN + UDP_TUNNEL_OVERHEAD
+}
+
+/// Smallest tunnel MTU which leaves room for the IPv4 minimum
+/// reassembly size of 576 bytes (minus the headers, this is
+/// what every host can be expected to handle in one datagram)
+pub const MIN_TUNNEL_MTU: usize = 576;
+
+const IPV4_ETHER_TYPE: u16 = 0x0800;
+const IPV6_ETHER_TYPE: u16 = 0x86DD;
+const ICMP_PROTOCOL: u8 = 1;
+const TCP_PROTOCOL: u8 = 6;
+const ICMPV6_PROTOCOL: u8 = 58;
+const IPV4_HDR: usize = 20;
+const IPV6_HDR: usize = 40;
+const TCP_HDR: usize = 20;
+const TCP_SYN: u8 = 0x02;
+const TCP_OPTION_END: u8 = 0;
+const TCP_OPTION_NOP: u8 = 1;
+const TCP_OPTION_MSS: u8 = 2;
+const ICMP_HDR: usize = 8;
+
+/// Smallest MTU IPv6 links may have, below which packet too big
+/// messages are ignored, so larger packets are left to the underlay
+const IPV6_MIN_MTU: usize = 1280;
+
+/// Most of an IPv4 packet quoted in a fragmentation needed message,
+/// which keeps the message within the minimum reassembly size
+const ICMPV4_QUOTE_MAX: usize = 576 - IPV4_HDR - ICMP_HDR;
+
+/// Most of an IPv6 packet quoted in a packet too big message,
+/// which keeps the message within the IPv6 minimum MTU
+const ICMPV6_QUOTE_MAX: usize = IPV6_MIN_MTU - IPV6_HDR - ICMP_HDR;
+
+/// Hop limit of the ICMP messages sent back to the host
+const ICMP_TTL: u8 = 64;
+
+/// IP packet in a frame, and where it starts
+pub struct IpPacket {
+    /// Offset of the IP header in the frame, after any VLAN tag
+    pub start: usize,
+    /// True for IPv6, false for IPv4
+    pub ipv6: bool,
+}
+
+impl IpPacket {
+    /// Returns the IP packet in frame, which is untagged or has one
+    /// 802.1Q tag, but no hop limit tag, or None if it doesn't hold one
+    pub fn find(frame: &[u8]) -> Option<IpPacket> {
+        let frame = EthernetFrame::parse(frame).ok()?;
+        let ipv6 = match frame.ether_type {
+            IPV4_ETHER_TYPE => false,
+            IPV6_ETHER_TYPE => true,
+            _ => return None,
+        };
+        Some(IpPacket {
+            start: frame.header_len(),
+            ipv6,
+        })
+    }
+
+    /// Returns the largest IP packet which fits in a frame
+    /// like this one, carried over a tunnel with tunnel_mtu
+    pub fn max_len(&self, tunnel_mtu: usize) -> usize {
+        tunnel_mtu.saturating_sub(UDP_TUNNEL_OVERHEAD + HOP_LIMIT_TAG_LEN + self.start)
+    }
+}
+
+/// If frame holds a TCP SYN (or SYN-ACK) whose MSS option is too
+/// large for a tunnel with tunnel_mtu, lower it to the largest which
+/// fits, returning the old and new MSS. The TCP checksum is updated to match
+pub fn clamp_mss(frame: &mut [u8], tunnel_mtu: usize) -> Option<(u16, u16)> {
+    let packet = IpPacket::find(frame)?;
+    let ip = packet.start;
+
+    let (tcp, headers_len) = match packet.ipv6 {
+        false => {
+            let header_len = usize::from(frame.get(ip)? & 0x0F) * 4;
+            let fragment = u16::from_be_bytes([*frame.get(ip + 6)?, *frame.get(ip + 7)?]);
+            /* Only the first fragment holds the TCP header */
+            if header_len < IPV4_HDR
+                || *frame.get(ip + 9)? != TCP_PROTOCOL
+                || fragment & 0x1FFF != 0
+            {
+                return None;
+            }
+            (ip + header_len, IPV4_HDR + TCP_HDR)
+        }
+        /* Extension headers are rare on SYNs, so only TCP directly after the header is clamped */
+        true => match *frame.get(ip + 6)? {
+            TCP_PROTOCOL => (ip + IPV6_HDR, IPV6_HDR + TCP_HDR),
+            _ => return None,
+        },
+    };
+
+    /* A truncated packet could otherwise end inside the TCP header */
+    if tcp + TCP_HDR > frame.len() || frame[tcp + 13] & TCP_SYN == 0 {
+        return None;
+    }
+    let data_offset = usize::from(frame[tcp + 12] >> 4) * 4;
+    let options_end = (tcp + data_offset).min(frame.len());
+
+    let max_mss =
+        u16::try_from(packet.max_len(tunnel_mtu).saturating_sub(headers_len)).unwrap_or(u16::MAX);
+
+    let mut option = tcp + TCP_HDR;
+    while option < options_end {
+        match frame[option] {
+            TCP_OPTION_END => return None,
+            TCP_OPTION_NOP => option += 1,
+            kind => {
+                let len = usize::from(*frame.get(option + 1)?);
+                if len < 2 || option + len > options_end {
+                    return None;
+                }
+                if kind == TCP_OPTION_MSS && len == 4 {
+                    let mss = u16::from_be_bytes([frame[option + 2], frame[option + 3]]);
+                    if mss <= max_mss {
+                        return None;
+                    }
+                    frame[option + 2..option + 4].copy_from_slice(&max_mss.to_be_bytes());
+
+                    let checksum = u16::from_be_bytes([frame[tcp + 16], frame[tcp + 17]]);
+                    let checksum = update_checksum(checksum, mss, max_mss);
+                    frame[tcp + 16..tcp + 18].copy_from_slice(&checksum.to_be_bytes());
+                    return Some((mss, max_mss));
+                }
+                option += len;
+            }
+        }
+    }
+
+    None
+}
+
+/// Returns an Internet checksum updated for one 16 bit word of the
+/// data it covers changing from old to new, as in RFC 1624
+pub fn update_checksum(checksum: u16, old: u16, new: u16) -> u16 {
+    let mut sum = u32::from(!checksum) + u32::from(!old) + u32::from(new);
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// If frame holds an IP packet too large for a tunnel with tunnel_mtu,
+/// which can't be fragmented, returns the frame holding the ICMPv4
+/// fragmentation needed or ICMPv6 packet too big message to send back
+/// to its sender, as it is to be dropped
+pub fn too_big_reply(frame: &[u8], tunnel_mtu: usize) -> Option<Vec<u8>> {
+    /* The message can't come from a group MAC */
+    if frame.first()? & 0x01 != 0 {
+        return None;
+    }
+    let packet = IpPacket::find(frame)?;
+    let ip = frame.get(packet.start..)?;
+    let mtu = packet.max_len(tunnel_mtu);
+    if ip.len() <= mtu {
+        return None;
+    }
+
+    let (ip_reply, ether_type) = match packet.ipv6 {
+        false => (frag_needed(ip, mtu)?, IPV4_ETHER_TYPE),
+        true => (packet_too_big(ip, mtu)?, IPV6_ETHER_TYPE),
+    };
+
+    /* The message appears to come from where the packet was going, in the same VLAN */
+    let mut reply = Vec::with_capacity(packet.start + ip_reply.len());
+    reply.extend_from_slice(&frame[6..12]);
+    reply.extend_from_slice(&frame[..6]);
+    reply.extend_from_slice(&frame[12..packet.start - 2]);
+    reply.extend_from_slice(&ether_type.to_be_bytes());
+    reply.extend_from_slice(&ip_reply);
+    Some(reply)
+}
+
+/// Returns the ICMPv4 fragmentation needed message for the IPv4
+/// packet in ip, or None if it may be fragmented, or mustn't be
+/// answered with an ICMP error
+fn frag_needed(ip: &[u8], mtu: usize) -> Option<Vec<u8>> {
+    let header_len = usize::from(ip.first()? & 0x0F) * 4;
+    if ip.len() < header_len + ICMP_HDR || header_len < IPV4_HDR || ip[6] & 0x40 == 0 {
+        return None;
+    }
+    let src: [u8; 4] = ip[12..16].try_into().unwrap();
+    let dst: [u8; 4] = ip[16..20].try_into().unwrap();
+
+    /* Errors are never sent about errors, or to addresses which aren't a single host */
+    let is_error = ip[9] == ICMP_PROTOCOL && matches!(ip[header_len], 3 | 4 | 5 | 11 | 12);
+    if is_error || src[0] == 0 || src[0] >= 224 || dst[0] >= 224 {
+        return None;
+    }
+
+    let quote = &ip[..ip.len().min(ICMPV4_QUOTE_MAX)];
+    let mut icmp = vec![3, 4, 0, 0, 0, 0];
+    icmp.extend_from_slice(&u16::try_from(mtu).unwrap_or(u16::MAX).to_be_bytes());
+    icmp.extend_from_slice(quote);
+    let icmp_checksum = checksum(&[&icmp]);
+    icmp[2..4].copy_from_slice(&icmp_checksum.to_be_bytes());
+
+    let total_len = IPV4_HDR + icmp.len();
+    let mut reply = vec![0x45, 0];
+    reply.extend_from_slice(&(total_len as u16).to_be_bytes());
+    reply.extend_from_slice(&[0, 0, 0, 0, ICMP_TTL, ICMP_PROTOCOL, 0, 0]);
+    reply.extend_from_slice(&dst);
+    reply.extend_from_slice(&src);
+    let header_checksum = checksum(&[&reply]);
+    reply[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+    reply.extend_from_slice(&icmp);
+    Some(reply)
+}
+
+/// Returns the ICMPv6 packet too big message for the IPv6 packet in
+/// ip, or None if the tunnel can't carry the IPv6 minimum MTU, or
+/// the packet mustn't be answered with an ICMPv6 error
+fn packet_too_big(ip: &[u8], mtu: usize) -> Option<Vec<u8>> {
+    if mtu < IPV6_MIN_MTU || ip.len() < IPV6_HDR + ICMP_HDR {
+        return None;
+    }
+    let src: [u8; 16] = ip[8..24].try_into().unwrap();
+    let dst: [u8; 16] = ip[24..40].try_into().unwrap();
+
+    /* Errors are never sent about errors, and can't come from or go to a multicast */
+    let is_error = ip[6] == ICMPV6_PROTOCOL && ip[IPV6_HDR] < 128;
+    if is_error || src == [0; 16] || src[0] == 0xFF || dst[0] == 0xFF {
+        return None;
+    }
+
+    let quote = &ip[..ip.len().min(ICMPV6_QUOTE_MAX)];
+    let mut icmp = vec![2, 0, 0, 0];
+    icmp.extend_from_slice(&u32::try_from(mtu).unwrap_or(u32::MAX).to_be_bytes());
+    icmp.extend_from_slice(quote);
+    let icmp_len = (icmp.len() as u32).to_be_bytes();
+
+    /* The checksum covers a pseudo header of the addresses, length and next header */
+    let pseudo_header = [&icmp_len[..], &[0, 0, 0, ICMPV6_PROTOCOL][..]].concat();
+    let icmp_checksum = checksum(&[&dst, &src, &pseudo_header, &icmp]);
+    icmp[2..4].copy_from_slice(&icmp_checksum.to_be_bytes());
+
+    let mut reply = vec![0x60, 0, 0, 0];
+    reply.extend_from_slice(&(icmp.len() as u16).to_be_bytes());
+    reply.extend_from_slice(&[ICMPV6_PROTOCOL, ICMP_TTL]);
+    reply.extend_from_slice(&dst);
+    reply.extend_from_slice(&src);
+    reply.extend_from_slice(&icmp);
+    Some(reply)
+}
+
+/// Returns the Internet checksum of the concatenation of parts,
+/// each of which but the last has an even length
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum: u32 = 0;
+    for part in parts {
+        for word in part.chunks(2) {
+            let word = match *word {
+                [hi, lo] => u16::from_be_bytes([hi, lo]),
+                [hi] => u16::from_be_bytes([hi, 0]),
+                _ => 0,
+            };
+            sum += u32::from(word);
+        }
+    }
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
@@ -0,0 +1,144 @@
+//! Bridging an existing NIC into the L2VPN network
+//!
+//! Rather than creating a tap interface, a vport can attach to a NIC
+//! with a raw AF_PACKET socket, which receives every frame on the NIC's
+//! segment, as it is put in promiscuous mode, and sends frames out of
+//! it, so a whole physical LAN is stitched into the overlay
+//!
+//! The frames which the socket sends are not received by it again, so
+//! frames from the overlay are never sent back into it. The host's own
+//! frames out of the NIC are not received either, so the host is only
+//! reached through the overlay from the LAN, not from its own address
+//! on the NIC
+//!
+//! AF_PACKET sockets are only found on Linux, so attaching to a NIC is
+//! refused elsewhere
+
+use crate::{error::TapError, tap::VirtualNic};
+#[cfg(target_os = "linux")]
+use {
+    crate::{
+        platform::socket,
+        tap::{ipv4_addrs, poll_readable},
+    },
+    nix::{
+        errno::Errno,
+        libc::{
+            self, c_int, c_void, packet_mreq, sockaddr, sockaddr_ll, socklen_t, AF_PACKET,
+            ETH_P_ALL, PACKET_ADD_MEMBERSHIP, PACKET_IGNORE_OUTGOING, PACKET_MR_PROMISC,
+            SOL_PACKET,
+        },
+        net::if_::if_nametoindex,
+        sys::socket::{AddressFamily, SockProtocol, SockType},
+    },
+    std::{
+        fs::File,
+        io::{self, Read, Write},
+        mem,
+        net::Ipv4Addr,
+        os::fd::{AsRawFd, OwnedFd},
+        time::Duration,
+    },
+};
+
+/// Packet socket bound to a NIC, which every frame on its segment is
+/// read from, and which frames written to are sent out of the NIC, as
+/// with a tap interface. The NIC is left as it is, so can't be configured
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+pub struct PacketSocket {
+    file: File,
+    name: String,
+}
+
+#[cfg(target_os = "linux")]
+impl VirtualNic for PacketSocket {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        (&self.file).read(buf)
+    }
+
+    fn write(&self, frame: &[u8]) -> io::Result<usize> {
+        (&self.file).write(frame)
+    }
+
+    fn readable(&self, wait: Duration) -> io::Result<bool> {
+        poll_readable(&self.file, wait)
+    }
+
+    fn ipv4_addrs(&self) -> Result<Vec<Ipv4Addr>, TapError> {
+        ipv4_addrs(&self.name)
+    }
+}
+
+/// Attach to the NIC called name, which must exist
+#[cfg(target_os = "linux")]
+pub fn attach(name: &str) -> Result<Box<dyn VirtualNic>, TapError> {
+    let ioctl_err = |op| move |source| TapError::Ioctl { op, source };
+    let index = if_nametoindex(name).map_err(ioctl_err("Getting NIC index"))?;
+    let fd = socket(
+        AddressFamily::Packet,
+        SockType::Raw,
+        Some(SockProtocol::EthAll),
+    )
+    .map_err(ioctl_err("Opening packet socket"))?;
+
+    /* Only the NIC's frames are received, and frames are sent out of it */
+    let mut sll: sockaddr_ll = unsafe { mem::zeroed() };
+    sll.sll_family = AF_PACKET as u16;
+    sll.sll_protocol = (ETH_P_ALL as u16).to_be();
+    sll.sll_ifindex = index as c_int;
+    Errno::result(unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            &sll as *const sockaddr_ll as *const sockaddr,
+            mem::size_of::<sockaddr_ll>() as socklen_t,
+        )
+    })
+    .map_err(ioctl_err("Binding packet socket to NIC"))?;
+
+    /* Frames to other hosts' MACs are received too, until the socket is closed */
+    let mreq = packet_mreq {
+        mr_ifindex: index as c_int,
+        mr_type: PACKET_MR_PROMISC as u16,
+        mr_alen: 0,
+        mr_address: [0; 8],
+    };
+    set_packet_option(&fd, PACKET_ADD_MEMBERSHIP, &mreq)
+        .map_err(ioctl_err("Putting NIC in promiscuous mode"))?;
+
+    /* The frames we send, and the host's own, would otherwise be received as well */
+    set_packet_option(&fd, PACKET_IGNORE_OUTGOING, &(1 as c_int))
+        .map_err(ioctl_err("Ignoring frames sent out of NIC"))?;
+
+    Ok(Box::new(PacketSocket {
+        file: File::from(fd),
+        name: name.to_string(),
+    }))
+}
+
+/// Attach to the NIC called name, which can't be done without AF_PACKET
+#[cfg(not(target_os = "linux"))]
+pub fn attach(_name: &str) -> Result<Box<dyn VirtualNic>, TapError> {
+    Err(TapError::Unsupported {
+        op: "Attaching to a NIC",
+    })
+}
+
+/// Set the packet socket option called option on fd to value
+#[cfg(target_os = "linux")]
+fn set_packet_option<T>(fd: &OwnedFd, option: c_int, value: &T) -> Result<(), Errno> {
+    Errno::result(unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            SOL_PACKET,
+            option,
+            value as *const T as *const c_void,
+            mem::size_of::<T>() as socklen_t,
+        )
+    })
+    .map(drop)
+}
@@ -0,0 +1,375 @@
+//! Papering over the differences between the platforms a vport runs on
+//!
+//! The vport runs on Linux, macOS and Windows. Sockets are opened
+//! close-on-exec (or not inherited, on Windows), atomically where the
+//! platform allows it, and sends on stream sockets never raise SIGPIPE,
+//! so a peer which has gone away is reported as an error rather than
+//! killing the process. Tap and tun interfaces are created with each
+//! platform's own driver (see l2vpn::tap), while facilities only Linux
+//! has, such as AF_PACKET sockets (l2vpn::nic) and shared memory links
+//! (l2vpn::shm), are refused or left out elsewhere, as are Unix sockets
+//! and vsock on Windows
+//!
+//! The vport and vswitch are stopped by SIGINT or SIGTERM, which
+//! StopSignals waits for, or the vport on Windows, by Ctrl+C or Ctrl+Break, or its console closing.
+//! They can also be waited for with a timeout, e.g. between attempts to
+//! connect to a vswitch which is down
+
+use std::{
+    io, mem,
+    net::{SocketAddrV4, UdpSocket},
+    time::Duration,
+};
+#[cfg(unix)]
+use {
+    nix::{
+        libc::{self, c_int, socklen_t},
+        sys::{
+            signal::{SigSet, Signal},
+            socket::{bind, setsockopt, sockopt, AddressFamily, MsgFlags, SockProtocol, SockType},
+        },
+    },
+    std::{
+        os::fd::{AsRawFd, OwnedFd},
+        thread,
+        time::Instant,
+    },
+};
+#[cfg(windows)]
+use {
+    std::{
+        os::windows::io::{AsRawSocket, FromRawSocket, OwnedSocket, RawSocket},
+        sync::{Condvar, Mutex},
+    },
+    windows_sys::Win32::{
+        Foundation::{BOOL, FALSE, TRUE},
+        NetworkManagement::{
+            IpHelper::{ConvertInterfaceAliasToLuid, ConvertInterfaceLuidToIndex},
+            Ndis::NET_LUID_LH,
+        },
+        Networking::WinSock::{
+            self, WSASocketW, WSAStartup, AF_INET, INVALID_SOCKET, IPPROTO_IP, IPPROTO_UDP,
+            IP_UNICAST_IF, SOCKADDR, SOCKADDR_IN, SOCKET, SOCK_DGRAM, SOL_SOCKET, SO_REUSEADDR,
+            WSADATA, WSA_FLAG_NO_HANDLE_INHERIT, WSA_FLAG_OVERLAPPED,
+        },
+        System::Console::{
+            SetConsoleCtrlHandler, CTRL_BREAK_EVENT, CTRL_CLOSE_EVENT, CTRL_C_EVENT,
+        },
+    },
+};
+
+/// How often the signals which stop the vport are checked for while
+/// waiting for them with a timeout
+#[cfg(unix)]
+const SIGNAL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Flags to send with on stream sockets, so a peer which has gone away
+/// is an error rather than SIGPIPE. Elsewhere, sockets are opened with
+/// SO_NOSIGPIPE set instead
+#[cfg(target_os = "linux")]
+pub const SEND_FLAGS: MsgFlags = MsgFlags::MSG_NOSIGNAL;
+#[cfg(all(unix, not(target_os = "linux")))]
+pub const SEND_FLAGS: MsgFlags = MsgFlags::empty();
+
+/// Returns a socket of family, ty and protocol, as nix's socket does,
+/// which is close-on-exec, and never raises SIGPIPE when sent on
+#[cfg(target_os = "linux")]
+pub fn socket(
+    family: AddressFamily,
+    ty: SockType,
+    protocol: Option<SockProtocol>,
+) -> nix::Result<OwnedFd> {
+    use nix::sys::socket::SockFlag;
+    nix::sys::socket::socket(family, ty, SockFlag::SOCK_CLOEXEC, protocol)
+}
+
+/// Returns a socket of family, ty and protocol, as nix's socket does,
+/// which is close-on-exec, and never raises SIGPIPE when sent on
+#[cfg(all(unix, not(target_os = "linux")))]
+pub fn socket(
+    family: AddressFamily,
+    ty: SockType,
+    protocol: Option<SockProtocol>,
+) -> nix::Result<OwnedFd> {
+    use nix::{
+        errno::Errno,
+        fcntl::{fcntl, FcntlArg, FdFlag},
+        sys::socket::SockFlag,
+    };
+
+    /* Without SOCK_CLOEXEC, there is a moment in which a fork could inherit it */
+    let fd = nix::sys::socket::socket(family, ty, SockFlag::empty(), protocol)?;
+    fcntl(fd.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+    set_option(&fd, libc::SOL_SOCKET, libc::SO_NOSIGPIPE, 1).map_err(|_| Errno::last())?;
+    Ok(fd)
+}
+
+/// Returns a UDP socket bound to addr, which only sends out of the
+/// interface called device, if it is given, and which can be bound to
+/// an address and port other sockets are bound to too, if reuse_addr
+#[cfg(unix)]
+pub fn bind_udp(
+    addr: SocketAddrV4,
+    device: Option<&str>,
+    reuse_addr: bool,
+) -> io::Result<UdpSocket> {
+    let fd = socket(AddressFamily::Inet, SockType::Datagram, None)?;
+    if let Some(device) = device {
+        bind_to_device(&fd, device)?;
+    }
+    if reuse_addr {
+        setsockopt(&fd, sockopt::ReuseAddr, &true)?;
+    }
+    bind(fd.as_raw_fd(), &nix::sys::socket::SockaddrIn::from(addr))?;
+    Ok(UdpSocket::from(fd))
+}
+
+/// Returns a UDP socket bound to addr, which only sends out of the
+/// interface called device, if it is given, and which can be bound to
+/// an address and port other sockets are bound to too, if reuse_addr
+#[cfg(windows)]
+pub fn bind_udp(
+    addr: SocketAddrV4,
+    device: Option<&str>,
+    reuse_addr: bool,
+) -> io::Result<UdpSocket> {
+    /*
+     * std starts Winsock before opening its own sockets, but this one is
+     * opened without it. Starting it again only counts how often it was
+     */
+    let mut data: WSADATA = unsafe { mem::zeroed() };
+    match unsafe { WSAStartup(0x202, &mut data) } {
+        0 => {}
+        e => return Err(io::Error::from_raw_os_error(e)),
+    }
+
+    let flags = WSA_FLAG_OVERLAPPED | WSA_FLAG_NO_HANDLE_INHERIT;
+    let raw = unsafe {
+        WSASocketW(
+            AF_INET as i32,
+            SOCK_DGRAM,
+            IPPROTO_UDP,
+            std::ptr::null(),
+            0,
+            flags,
+        )
+    };
+    if raw == INVALID_SOCKET {
+        return Err(io::Error::last_os_error());
+    }
+    let sock = unsafe { OwnedSocket::from_raw_socket(raw as RawSocket) };
+
+    if let Some(device) = device {
+        /* The index is in network byte order, unlike every other option */
+        let index = interface_index(device)?;
+        set_option(&sock, IPPROTO_IP, IP_UNICAST_IF, index.to_be() as i32)?;
+    }
+    if reuse_addr {
+        set_option(&sock, SOL_SOCKET, SO_REUSEADDR, 1)?;
+    }
+
+    let mut sin: SOCKADDR_IN = unsafe { mem::zeroed() };
+    sin.sin_family = AF_INET;
+    sin.sin_port = addr.port().to_be();
+    sin.sin_addr.S_un.S_addr = u32::from(*addr.ip()).to_be();
+    let result = unsafe {
+        WinSock::bind(
+            raw,
+            &sin as *const SOCKADDR_IN as *const SOCKADDR,
+            mem::size_of::<SOCKADDR_IN>() as i32,
+        )
+    };
+    match result {
+        0 => Ok(UdpSocket::from(sock)),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Only send the datagrams sent from fd out of the interface called
+/// device, whatever the routing table says
+#[cfg(target_os = "linux")]
+pub fn bind_to_device(fd: &OwnedFd, device: &str) -> io::Result<()> {
+    setsockopt(fd, sockopt::BindToDevice, &std::ffi::OsString::from(device))?;
+    Ok(())
+}
+
+/// Only send the datagrams sent from fd out of the interface called
+/// device, whatever the routing table says
+#[cfg(target_os = "macos")]
+pub fn bind_to_device(fd: &OwnedFd, device: &str) -> io::Result<()> {
+    let index = nix::net::if_::if_nametoindex(device)?;
+    set_option(fd, libc::IPPROTO_IP, libc::IP_BOUND_IF, index as c_int)
+}
+
+/// Returns the index of the interface whose alias (the name shown in
+/// the Control Panel, e.g. "Ethernet") is name
+#[cfg(windows)]
+pub fn interface_index(name: &str) -> io::Result<u32> {
+    let alias: Vec<u16> = name.encode_utf16().chain([0]).collect();
+    let mut luid: NET_LUID_LH = unsafe { mem::zeroed() };
+    match unsafe { ConvertInterfaceAliasToLuid(alias.as_ptr(), &mut luid) } {
+        0 => {}
+        e => return Err(io::Error::from_raw_os_error(e as i32)),
+    }
+    let mut index = 0;
+    match unsafe { ConvertInterfaceLuidToIndex(&luid, &mut index) } {
+        0 => Ok(index),
+        e => Err(io::Error::from_raw_os_error(e as i32)),
+    }
+}
+
+/// Set the socket option called option, at level, on fd to value
+#[cfg(unix)]
+pub fn set_option(fd: &impl AsRawFd, level: c_int, option: c_int, value: c_int) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            level,
+            option,
+            &value as *const c_int as *const _,
+            mem::size_of::<c_int>() as socklen_t,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Set the socket option called option, at level, on sock to value
+#[cfg(windows)]
+pub fn set_option(sock: &impl AsRawSocket, level: i32, option: i32, value: i32) -> io::Result<()> {
+    let result = unsafe {
+        WinSock::setsockopt(
+            sock.as_raw_socket() as SOCKET,
+            level,
+            option,
+            &value as *const i32 as *const u8,
+            mem::size_of::<i32>() as i32,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// The signals which stop the vport or vswitch, SIGINT and SIGTERM,
+/// which are blocked in every thread, so one thread can wait for them
+#[cfg(unix)]
+pub struct StopSignals(SigSet);
+
+#[cfg(unix)]
+impl StopSignals {
+    /// Block the stop signals in this thread, and the threads it
+    /// goes on to start, which has to be done before any are
+    pub fn block() -> io::Result<StopSignals> {
+        let signals = SigSet::from_iter([Signal::SIGINT, Signal::SIGTERM]);
+        signals.thread_block()?;
+        Ok(StopSignals(signals))
+    }
+
+    /// Wait for a stop signal, returning its name
+    pub fn wait(&self) -> io::Result<&'static str> {
+        Ok(self.0.wait()?.as_str())
+    }
+
+    /// Wait for up to timeout for a stop signal, returning its
+    /// name, or None if there wasn't one
+    ///
+    /// macOS has no sigtimedwait, so the signals are polled for
+    pub fn wait_timeout(&self, timeout: Duration) -> io::Result<Option<&'static str>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let mut pending = mem::MaybeUninit::<libc::sigset_t>::uninit();
+            if unsafe { libc::sigpending(pending.as_mut_ptr()) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let pending = unsafe { pending.assume_init() };
+            let is_pending = |signal| unsafe { libc::sigismember(&pending, signal as c_int) } == 1;
+            if self.0.iter().any(is_pending) {
+                return self.wait().map(Some);
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+            thread::sleep(remaining.min(SIGNAL_POLL_INTERVAL));
+        }
+    }
+}
+
+/// The console events which stop the vport, Ctrl+C, Ctrl+Break and the
+/// console closing, which are handled by noting them, so one thread can
+/// wait for them
+#[cfg(windows)]
+pub struct StopSignals;
+
+/*
+ * The last console event which stops the vport, which the handler
+ * notes and wakes the thread waiting for it with
+ */
+#[cfg(windows)]
+static STOP_EVENT: (Mutex<Option<u32>>, Condvar) = (Mutex::new(None), Condvar::new());
+
+#[cfg(windows)]
+impl StopSignals {
+    /// Handle the console events which stop the vport, rather than
+    /// letting them kill it
+    pub fn block() -> io::Result<StopSignals> {
+        match unsafe { SetConsoleCtrlHandler(Some(note_stop_event), TRUE) } {
+            FALSE => Err(io::Error::last_os_error()),
+            _ => Ok(StopSignals),
+        }
+    }
+
+    /// Wait for a console event which stops the vport, returning its name
+    pub fn wait(&self) -> io::Result<&'static str> {
+        let (event, noted) = &STOP_EVENT;
+        let mut event = event.lock().map_err(|_| io::ErrorKind::Other)?;
+        loop {
+            match *event {
+                Some(ctrl_type) => return Ok(stop_event_name(ctrl_type)),
+                None => event = noted.wait(event).map_err(|_| io::ErrorKind::Other)?,
+            }
+        }
+    }
+
+    /// Wait for up to timeout for a console event which stops the vport,
+    /// returning its name, or None if there wasn't one
+    pub fn wait_timeout(&self, timeout: Duration) -> io::Result<Option<&'static str>> {
+        let (event, noted) = &STOP_EVENT;
+        let event = event.lock().map_err(|_| io::ErrorKind::Other)?;
+        let (event, _) = noted
+            .wait_timeout_while(event, timeout, |event| event.is_none())
+            .map_err(|_| io::ErrorKind::Other)?;
+        Ok(event.map(stop_event_name))
+    }
+}
+
+/// Returns the name of a console event which stops the vport
+#[cfg(windows)]
+fn stop_event_name(ctrl_type: u32) -> &'static str {
+    match ctrl_type {
+        CTRL_C_EVENT => "Ctrl+C",
+        CTRL_BREAK_EVENT => "Ctrl+Break",
+        _ => "console close",
+    }
+}
+
+/// Handler of console events, which notes those which stop the vport
+#[cfg(windows)]
+unsafe extern "system" fn note_stop_event(ctrl_type: u32) -> BOOL {
+    match ctrl_type {
+        CTRL_C_EVENT | CTRL_BREAK_EVENT | CTRL_CLOSE_EVENT => {
+            let (event, noted) = &STOP_EVENT;
+            if let Ok(mut event) = event.lock() {
+                *event = Some(ctrl_type);
+                noted.notify_all();
+            }
+            TRUE
+        }
+        _ => FALSE,
+    }
+}
@@ -0,0 +1,107 @@
+//! Discovering the path MTU between a vport and the vswitch
+//!
+//! Rather than being told the tunnel MTU, a vport can find it itself,
+//! by sending the vswitch path MTU probes padded to the frame sizes it
+//! tries, with the don't fragment flag set, so probes too large for the
+//! path are dropped by the router which can't carry them rather than
+//! fragmented. The vswitch answers each probe with an ack padded to the
+//! same size, so the path back is tried too. The largest frame which
+//! gets through is found by a binary search, trying each size a few
+//! times before taking it to be too large, in case the probe was lost
+//!
+//! The search only decides which size to try next, so it can be driven
+//! by whatever sends the probes and waits for their acks
+
+use crate::platform::set_option;
+#[cfg(unix)]
+use nix::libc::IPPROTO_IP;
+use std::{io, net::UdpSocket, time::Duration};
+#[cfg(windows)]
+use windows_sys::Win32::Networking::WinSock::IPPROTO_IP;
+
+/// How long the vswitch has to answer a probe before it is taken to be lost
+pub const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How many times a size is tried before it is taken to be too large
+pub const PROBE_ATTEMPTS: u32 = 3;
+
+/// How often the path MTU is searched for again, in case the path changed
+pub const REPROBE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Binary search for the largest frame which crosses the path
+#[derive(Clone, Debug)]
+pub struct PathMtuSearch {
+    /* Largest frame known to get through */
+    low: usize,
+    /* Largest frame which may get through */
+    high: usize,
+    /* Probes of the size being tried which were lost */
+    losses: u32,
+}
+
+impl PathMtuSearch {
+    /// Returns a search for the largest frame no larger than high,
+    /// taking frames of low bytes to get through
+    pub fn new(low: usize, high: usize) -> PathMtuSearch {
+        PathMtuSearch {
+            low,
+            high: high.max(low),
+            losses: 0,
+        }
+    }
+
+    /// Returns the size of frame to probe with next,
+    /// or None if the search is over
+    pub fn next_size(&self) -> Option<usize> {
+        (self.low < self.high).then(|| self.low + (self.high - self.low).div_ceil(2))
+    }
+
+    /// Note that the probe of size bytes was answered
+    pub fn acked(&mut self, size: usize) {
+        self.low = self.low.max(size.min(self.high));
+        self.losses = 0;
+    }
+
+    /// Note that the probe of size bytes wasn't answered, which once it
+    /// has happened PROBE_ATTEMPTS times means it is too large
+    pub fn lost(&mut self, size: usize) {
+        self.losses += 1;
+        if self.losses >= PROBE_ATTEMPTS && size > self.low {
+            self.high = self.high.min(size - 1);
+            self.losses = 0;
+        }
+    }
+
+    /// Returns the largest frame known to cross the path,
+    /// which is its largest frame once the search is over
+    pub fn largest_frame(&self) -> usize {
+        self.low
+    }
+}
+
+/// Set the don't fragment flag on every datagram sock sends, whatever
+/// the kernel thinks the path MTU is, so probes too large for the path
+/// are dropped rather than fragmented. Datagrams larger than the MTU
+/// of the interface they leave through fail to send
+#[cfg(target_os = "linux")]
+pub fn set_dont_fragment(sock: &UdpSocket) -> io::Result<()> {
+    use nix::libc::{IP_MTU_DISCOVER, IP_PMTUDISC_PROBE};
+    set_option(sock, IPPROTO_IP, IP_MTU_DISCOVER, IP_PMTUDISC_PROBE)
+}
+
+/// Set the don't fragment flag on every datagram sock sends, so probes
+/// too large for the path are dropped rather than fragmented. macOS
+/// never takes the path MTU it has learned to be the limit for these
+#[cfg(target_os = "macos")]
+pub fn set_dont_fragment(sock: &UdpSocket) -> io::Result<()> {
+    use nix::libc::IP_DONTFRAG;
+    set_option(sock, IPPROTO_IP, IP_DONTFRAG, 1)
+}
+
+/// Set the don't fragment flag on every datagram sock sends, so probes
+/// too large for the path are dropped rather than fragmented
+#[cfg(windows)]
+pub fn set_dont_fragment(sock: &UdpSocket) -> io::Result<()> {
+    use windows_sys::Win32::Networking::WinSock::IP_DONTFRAGMENT;
+    set_option(sock, IPPROTO_IP, IP_DONTFRAGMENT, 1)
+}
@@ -0,0 +1,364 @@
+//! Connecting through SOCKS5 and HTTP CONNECT proxies
+//!
+//! Hosts on locked-down networks can often only reach the outside
+//! through a proxy, so the stream transports can be tunneled through
+//! one. SOCKS5 proxies are given the name of the host to connect to,
+//! rather than its address, as are HTTP proxies, so names only need
+//! to resolve on the proxy's side
+
+use crate::error::{ConfigError, TransportError};
+use std::{
+    fmt,
+    io::{Read, Write},
+    net::{IpAddr, TcpStream},
+};
+
+/// Most bytes of an HTTP proxy's response headers which are read
+const HTTP_HEADERS_MAX: usize = 8192;
+
+/// Kind of proxy, which decides how the connection is requested
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyKind {
+    Socks5,
+    HttpConnect,
+}
+
+/// Proxy which streams are tunneled through
+#[derive(Clone, PartialEq, Eq)]
+pub struct Proxy {
+    pub kind: ProxyKind,
+    pub host: String,
+    pub port: u16,
+    /// User name and password to authenticate with, if the proxy needs them
+    pub credentials: Option<(String, String)>,
+}
+
+/// Shows the proxy as a URL, leaving out the password
+impl fmt::Display for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let scheme = match self.kind {
+            ProxyKind::Socks5 => "socks5",
+            ProxyKind::HttpConnect => "http",
+        };
+        write!(f, "{}://", scheme)?;
+        if let Some((user, _)) = &self.credentials {
+            write!(f, "{}@", user)?;
+        }
+        write!(f, "{}", host_port(&self.host, self.port))
+    }
+}
+
+/// Debug output is the same as Display, so passwords don't end up in logs
+impl fmt::Debug for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl Proxy {
+    /// Parse a proxy URL of the form
+    /// socks5://[<user>:<password>@]<host>:<port> or
+    /// http://[<user>:<password>@]<host>:<port>, where the user
+    /// name and password can be percent-encoded
+    pub fn parse(url: &str) -> Result<Proxy, ConfigError> {
+        let (scheme, rest) = url
+            .split_once("://")
+            .ok_or_else(|| ConfigError::ProxyUrl(url.to_string()))?;
+        let kind = match scheme {
+            "socks5" | "socks5h" => ProxyKind::Socks5,
+            "http" => ProxyKind::HttpConnect,
+            _ => return Err(ConfigError::ProxyScheme(scheme.to_string())),
+        };
+        let rest = rest.strip_suffix('/').unwrap_or(rest);
+
+        let (credentials, host_port) = match rest.rsplit_once('@') {
+            Some((userinfo, host_port)) => {
+                let (user, password) = userinfo
+                    .split_once(':')
+                    .ok_or(ConfigError::ProxyCredentials("expected <user>:<password>"))?;
+                let credentials =
+                    check_credentials(percent_decode(user)?, percent_decode(password)?)?;
+                (Some(credentials), host_port)
+            }
+            None => (None, rest),
+        };
+
+        let (host, port) = host_port
+            .rsplit_once(':')
+            .ok_or_else(|| ConfigError::MissingProxyPort(url.to_string()))?;
+        let port = port
+            .parse::<u16>()
+            .ok()
+            .filter(|port| *port != 0)
+            .ok_or_else(|| ConfigError::ProxyPort(port.to_string()))?;
+
+        /* IPv6 addresses are written in brackets, so their colons aren't taken for the port */
+        let host = match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+            Some(ip) => ip,
+            None => host,
+        };
+        if host.is_empty() {
+            return Err(ConfigError::MissingProxyHost(url.to_string()));
+        }
+
+        Ok(Proxy {
+            kind,
+            host: host.to_string(),
+            port,
+            credentials,
+        })
+    }
+
+    /// Connect to the proxy, and ask it to connect to host and port,
+    /// returning the stream, which then leads to host
+    pub fn connect(&self, host: &str, port: u16) -> Result<TcpStream, TransportError> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port))?;
+        match self.kind {
+            ProxyKind::Socks5 => self.socks5_connect(&mut stream, host, port)?,
+            ProxyKind::HttpConnect => self.http_connect(&mut stream, host, port)?,
+        }
+        Ok(stream)
+    }
+
+    /// Ask a SOCKS5 proxy to connect to host and port (RFC 1928),
+    /// authenticating with a user name and password (RFC 1929) if
+    /// we have them and the proxy asks for them
+    fn socks5_connect(
+        &self,
+        stream: &mut TcpStream,
+        host: &str,
+        port: u16,
+    ) -> Result<(), TransportError> {
+        /* Offer no authentication, and user name and password if we have them */
+        match self.credentials {
+            Some(_) => stream.write_all(&[5, 2, 0, 2])?,
+            None => stream.write_all(&[5, 1, 0])?,
+        }
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply)?;
+        if reply[0] != 5 {
+            return Err(TransportError::ProxyProtocol(
+                "does not speak SOCKS5".to_string(),
+            ));
+        }
+
+        match (reply[1], &self.credentials) {
+            (0, _) => {}
+            (2, Some((user, password))) => {
+                let mut auth = vec![1, user.len() as u8];
+                auth.extend_from_slice(user.as_bytes());
+                auth.push(password.len() as u8);
+                auth.extend_from_slice(password.as_bytes());
+                stream.write_all(&auth)?;
+
+                stream.read_exact(&mut reply)?;
+                if reply[1] != 0 {
+                    return Err(TransportError::ProxyAuth(
+                        "rejected the user name and password",
+                    ));
+                }
+            }
+            (2, None) => return Err(TransportError::ProxyAuth("needs a user name and password")),
+            _ => {
+                return Err(TransportError::ProxyAuth(
+                    "accepted none of the authentication methods offered",
+                ))
+            }
+        }
+
+        /* CONNECT, to an IPv4 address, a domain name or an IPv6 address */
+        let mut request = vec![5, 1, 0];
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(1);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(4);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                let name_len = u8::try_from(host.len()).map_err(|_| {
+                    TransportError::ProxyProtocol(format!(
+                        "can't be given host name '{}', which is too long for SOCKS5",
+                        host
+                    ))
+                })?;
+                request.push(3);
+                request.push(name_len);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request)?;
+
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply)?;
+        if reply[1] != 0 {
+            return Err(TransportError::ProxyRefused {
+                target: host_port(host, port),
+                reason: socks5_reply_reason(reply[1]).to_string(),
+            });
+        }
+
+        /* Skip the address the proxy bound to, which we don't need */
+        let bound_len = match reply[3] {
+            1 => 4,
+            4 => 16,
+            3 => {
+                let mut name_len = [0u8; 1];
+                stream.read_exact(&mut name_len)?;
+                usize::from(name_len[0])
+            }
+            atyp => {
+                return Err(TransportError::ProxyProtocol(format!(
+                    "replied with unknown address type {}",
+                    atyp
+                )))
+            }
+        };
+        let mut bound = vec![0u8; bound_len + 2];
+        stream.read_exact(&mut bound)?;
+
+        Ok(())
+    }
+
+    /// Ask an HTTP proxy to connect to host and port with CONNECT,
+    /// authenticating with Basic authentication if we have credentials
+    fn http_connect(
+        &self,
+        stream: &mut TcpStream,
+        host: &str,
+        port: u16,
+    ) -> Result<(), TransportError> {
+        let target = host_port(host, port);
+        let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+        if let Some((user, password)) = &self.credentials {
+            request.push_str(&format!(
+                "Proxy-Authorization: Basic {}\r\n",
+                base64(format!("{}:{}", user, password).as_bytes())
+            ));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes())?;
+
+        /*
+         * Read the response a byte at a time, so nothing after
+         * the headers, which belongs to the tunnel, is consumed
+         */
+        let mut response = Vec::new();
+        let mut byte = [0u8; 1];
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() == HTTP_HEADERS_MAX {
+                return Err(TransportError::ProxyProtocol(
+                    "sent response headers which are too long".to_string(),
+                ));
+            }
+            stream.read_exact(&mut byte)?;
+            response.push(byte[0]);
+        }
+
+        let response = String::from_utf8_lossy(&response);
+        let status_line = response.lines().next().unwrap_or_default();
+        let status = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse::<u16>().ok());
+        match status {
+            Some(200..=299) => Ok(()),
+            Some(407) => Err(TransportError::ProxyAuth(match self.credentials {
+                Some(_) => "rejected the user name and password",
+                None => "needs a user name and password",
+            })),
+            _ => Err(TransportError::ProxyRefused {
+                target,
+                reason: format!("'{}'", status_line),
+            }),
+        }
+    }
+}
+
+/// Parse the contents of a proxy credentials file, which hold
+/// <user>:<password> on a single line, without percent-encoding
+pub fn parse_credentials(contents: &str) -> Result<(String, String), ConfigError> {
+    let line = contents.trim_end_matches(['\r', '\n']);
+    let (user, password) = line
+        .split_once(':')
+        .ok_or(ConfigError::ProxyCredentials("expected <user>:<password>"))?;
+    check_credentials(user.to_string(), password.to_string())
+}
+
+/// Returns the user name and password if SOCKS5 can carry them,
+/// as it gives each of them a single byte length
+fn check_credentials(user: String, password: String) -> Result<(String, String), ConfigError> {
+    if user.is_empty() || user.len() > 255 || password.len() > 255 {
+        return Err(ConfigError::ProxyCredentials(
+            "user name must be 1 to 255 bytes, and password at most 255 bytes",
+        ));
+    }
+    Ok((user, password))
+}
+
+/// Returns host and port as written in URLs, with IPv6 addresses in brackets
+fn host_port(host: &str, port: u16) -> String {
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{}]:{}", ip, port),
+        _ => format!("{}:{}", host, port),
+    }
+}
+
+/// Returns the meaning of a SOCKS5 reply code
+fn socks5_reply_reason(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+/// Decode the %XX escapes in s
+fn percent_decode(s: &str) -> Result<String, ConfigError> {
+    let mut decoded = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'%' {
+            decoded.push(b);
+            continue;
+        }
+        let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+        let byte = std::str::from_utf8(&hex)
+            .ok()
+            .filter(|hex| hex.len() == 2)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| ConfigError::PercentEscape(s.to_string()))?;
+        decoded.push(byte);
+    }
+    String::from_utf8(decoded).map_err(|_| ConfigError::NotUtf8(s.to_string()))
+}
+
+/// Returns data encoded as base64, with padding
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |bits, (i, b)| bits | (u32::from(*b) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(char::from(ALPHABET[(bits >> (18 - 6 * i)) as usize & 0x3f]));
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
@@ -0,0 +1,590 @@
+//! QUIC transport between vports and the vswitch
+//!
+//! With QUIC, a vport opens a connection to the vswitch's QUIC address,
+//! authenticating it by the certificate it presents, and everything
+//! sent over it is encrypted and authenticated under the keys agreed
+//! in the TLS 1.3 handshake. Data frames are each carried in a QUIC
+//! DATAGRAM frame, so a lost frame is lost rather than holding up the
+//! frames behind it, as it would over TCP. Control messages are
+//! carried length-prefixed in a stream which the vport opens, so they
+//! are delivered reliably and in order, apart from the data frames
+//!
+//! Connections are told apart by their connection IDs rather than the
+//! address they come from, so a vport behind a NAT which rebinds it to
+//! another address or port carries on in the same connection, as the
+//! vswitch migrates the connection to the new address once it is validated
+//!
+//! quinn-proto only keeps the state of each connection, so the socket
+//! is driven by whoever calls QuicEndpoint::poll(). Frames are sent
+//! whole, as they are over UDP, leaving any fragmentation to the underlay
+
+use crate::{
+    control::is_control_frame,
+    error::TransportError,
+    tunnel::{TUNNEL_DATAGRAM_MAX, TUNNEL_FRAME_MAX},
+};
+use bytes::{Bytes, BytesMut};
+use quinn_proto::{
+    rustls::{
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        RootCertStore,
+    },
+    ClientConfig, Connection, ConnectionHandle, DatagramEvent, Dir, Endpoint, EndpointConfig,
+    Event, IdleTimeout, SendDatagramError, ServerConfig, StreamEvent, StreamId, TransportConfig,
+    VarInt,
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, ErrorKind},
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How long a connection can go without hearing from the other end before it is lost
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a vport waits for its handshake with the vswitch to finish
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often a connection with nothing to send is kept alive, so the
+/// NAT bindings along its path don't expire
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Longest the socket is waited on, so timers set by frames sent from
+/// other threads are handled soon after they are due
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Largest UDP payload sent, which holds the largest frame in a DATAGRAM frame
+const MAX_UDP_PAYLOAD: u16 = (TUNNEL_DATAGRAM_MAX + 64) as u16;
+
+/// Size of the length prefix which precedes each control message on its stream
+const LEN_PREFIX: usize = 4;
+
+/// Returns the configuration of both ends' endpoints, which tell
+/// their peers they can receive UDP payloads holding the largest frame
+fn endpoint_config() -> EndpointConfig {
+    let mut config = EndpointConfig::default();
+    config
+        .max_udp_payload_size(MAX_UDP_PAYLOAD)
+        .expect("QUIC accepts UDP payloads of the largest frame");
+    config
+}
+
+/// Returns the configuration of the connections which the vswitch
+/// accepts, presenting the certificate chain in cert_path, whose
+/// private key is in key_path (both PEM encoded)
+pub fn server_config(cert_path: &str, key_path: &str) -> Result<ServerConfig, TransportError> {
+    let cert_chain = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| quic_error(format!("certificate file '{}': {}", cert_path, e)))?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| quic_error(format!("key file '{}': {}", key_path, e)))?;
+
+    let mut config = ServerConfig::with_single_cert(cert_chain, key).map_err(quic_error)?;
+    config.transport_config(transport_config());
+    Ok(config)
+}
+
+/// Returns the configuration of the connections which a vport opens,
+/// trusting the vswitches whose certificates are issued by (or are)
+/// one of the PEM encoded certificates in ca_path
+pub fn client_config(ca_path: &str) -> Result<ClientConfig, TransportError> {
+    let mut roots = RootCertStore::empty();
+    let certs = CertificateDer::pem_file_iter(ca_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| quic_error(format!("certificate file '{}': {}", ca_path, e)))?;
+    for cert in certs {
+        roots.add(cert).map_err(quic_error)?;
+    }
+
+    let mut config = ClientConfig::with_root_certificates(Arc::new(roots)).map_err(quic_error)?;
+    config.transport_config(transport_config());
+    Ok(config)
+}
+
+/// Returns the transport parameters shared by vports and the vswitch
+fn transport_config() -> Arc<TransportConfig> {
+    let mut transport = TransportConfig::default();
+    transport
+        .max_idle_timeout(IdleTimeout::try_from(IDLE_TIMEOUT).ok())
+        .keep_alive_interval(Some(KEEP_ALIVE_INTERVAL))
+        /* The control stream is the only one */
+        .max_concurrent_bidi_streams(VarInt::from_u32(1))
+        .max_concurrent_uni_streams(VarInt::from_u32(0))
+        .datagram_receive_buffer_size(Some(64 * TUNNEL_FRAME_MAX))
+        /* Frames are never split across datagrams, so the path MTU isn't searched for */
+        .initial_mtu(MAX_UDP_PAYLOAD)
+        .mtu_discovery_config(None);
+    Arc::new(transport)
+}
+
+/// Something which happened on one of an endpoint's connections,
+/// which are numbered by the endpoint
+#[derive(Debug)]
+pub enum QuicEvent {
+    /// The handshake finished, with the other end at this address
+    Connected(usize, SocketAddr),
+    /// A frame, or control message, was received
+    Frame(usize, Vec<u8>),
+    /// The connection was closed or lost
+    Closed(usize),
+}
+
+/// Connection with another endpoint, and its control stream
+struct Peer {
+    connection: Connection,
+    control: Option<StreamId>,
+    /// Bytes of control messages waiting for room on the control stream
+    control_tx: Vec<u8>,
+    /// Bytes read from the control stream which don't make up a whole message yet
+    control_rx: Vec<u8>,
+}
+
+impl Peer {
+    fn new(connection: Connection) -> Peer {
+        Peer {
+            connection,
+            control: None,
+            control_tx: Vec::new(),
+            control_rx: Vec::new(),
+        }
+    }
+
+    /// Write as much of the waiting control messages as the stream has room for
+    fn write_control(&mut self) {
+        let Some(control) = self.control else {
+            return;
+        };
+        if let Ok(written) = self.connection.send_stream(control).write(&self.control_tx) {
+            self.control_tx.drain(..written);
+        }
+    }
+
+    /// Read what has arrived on the control stream, returning the whole
+    /// control messages among it
+    fn read_control(&mut self) -> Vec<Vec<u8>> {
+        let Some(control) = self.control else {
+            return Vec::new();
+        };
+        let mut recv = self.connection.recv_stream(control);
+        if let Ok(mut chunks) = recv.read(true) {
+            while let Ok(Some(chunk)) = chunks.next(usize::MAX) {
+                self.control_rx.extend_from_slice(&chunk.bytes);
+            }
+            /* Whether the peer is given more credit is decided by the next transmit */
+            let _ = chunks.finalize();
+        }
+
+        let mut msgs = Vec::new();
+        while let Some(prefix) = self.control_rx.get(..LEN_PREFIX) {
+            let len = u32::from_be_bytes(prefix.try_into().unwrap()) as usize;
+            if self.control_rx.len() < LEN_PREFIX + len {
+                break;
+            }
+            msgs.push(self.control_rx[LEN_PREFIX..LEN_PREFIX + len].to_vec());
+            self.control_rx.drain(..LEN_PREFIX + len);
+        }
+        msgs
+    }
+}
+
+/// QUIC endpoint and its connections
+struct EndpointState {
+    endpoint: Endpoint,
+    server_config: Option<Arc<ServerConfig>>,
+    peers: HashMap<ConnectionHandle, Peer>,
+}
+
+/// UDP socket carrying a QUIC endpoint's connections. Clones of
+/// the endpoint share its socket and connections
+pub struct QuicEndpoint {
+    sock: Arc<UdpSocket>,
+    state: Arc<Mutex<EndpointState>>,
+}
+
+impl fmt::Debug for QuicEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("QuicEndpoint")
+            .field("sock", &self.sock)
+            .field(
+                "connections",
+                &self
+                    .state
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .peers
+                    .len(),
+            )
+            .finish()
+    }
+}
+
+impl QuicEndpoint {
+    /// Returns an endpoint accepting connections on sock with config
+    pub fn server(sock: UdpSocket, config: ServerConfig) -> QuicEndpoint {
+        QuicEndpoint::new(sock, Some(Arc::new(config)))
+    }
+
+    /// Returns an endpoint opening connections from sock
+    pub fn client(sock: UdpSocket) -> QuicEndpoint {
+        QuicEndpoint::new(sock, None)
+    }
+
+    fn new(sock: UdpSocket, server_config: Option<Arc<ServerConfig>>) -> QuicEndpoint {
+        QuicEndpoint {
+            sock: Arc::new(sock),
+            state: Arc::new(Mutex::new(EndpointState {
+                endpoint: Endpoint::new(
+                    Arc::new(endpoint_config()),
+                    server_config.clone(),
+                    false,
+                    None,
+                ),
+                server_config,
+                peers: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Open a connection with config to the endpoint at addr, whose
+    /// certificate is for server_name, returning its number, and open
+    /// its control stream. It can be used once a Connected event is polled
+    pub fn connect(
+        &self,
+        config: ClientConfig,
+        addr: SocketAddr,
+        server_name: &str,
+    ) -> Result<usize, TransportError> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let (handle, connection) = state
+            .endpoint
+            .connect(Instant::now(), config, addr, server_name)
+            .map_err(quic_error)?;
+        let mut peer = Peer::new(connection);
+        peer.control = peer.connection.streams().open(Dir::Bi);
+        state.peers.insert(handle, peer);
+        self.flush(&mut state);
+        Ok(handle.0)
+    }
+
+    /// Send frame over the connection numbered id: in a DATAGRAM
+    /// frame, or on the control stream if it is a control message
+    pub fn send_frame(&self, id: usize, frame: &[u8]) -> Result<(), TransportError> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(peer) = state.peers.get_mut(&ConnectionHandle(id)) else {
+            return Err(TransportError::Closed);
+        };
+
+        if is_control_frame(frame) {
+            peer.control_tx
+                .extend_from_slice(&(frame.len() as u32).to_be_bytes());
+            peer.control_tx.extend_from_slice(frame);
+            peer.write_control();
+        } else {
+            let max = peer.connection.datagrams().max_size().unwrap_or(0);
+            match peer
+                .connection
+                .datagrams()
+                .send(Bytes::copy_from_slice(frame), true)
+            {
+                Ok(()) => {}
+                Err(SendDatagramError::TooLarge) => {
+                    return Err(TransportError::FrameTooLarge {
+                        len: frame.len(),
+                        max,
+                    })
+                }
+                Err(e) => return Err(quic_error(e)),
+            }
+        }
+
+        self.flush(&mut state);
+        Ok(())
+    }
+
+    /// Wait up to POLL_INTERVAL for a datagram, handle it and any timers
+    /// which are due, and add what happened on the connections to events.
+    /// An error is only returned if the socket fails
+    pub fn poll(&self, events: &mut Vec<QuicEvent>) -> Result<(), TransportError> {
+        let timeout = {
+            let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            let now = Instant::now();
+            state
+                .peers
+                .values_mut()
+                .filter_map(|peer| peer.connection.poll_timeout())
+                .min()
+                .map_or(POLL_INTERVAL, |due| due.saturating_duration_since(now))
+                .clamp(Duration::from_millis(1), POLL_INTERVAL)
+        };
+        self.sock.set_read_timeout(Some(timeout))?;
+
+        let mut buf = vec![0u8; MAX_UDP_PAYLOAD as usize];
+        let received = match self.sock.recv_from(&mut buf) {
+            Ok((len, src)) => Some((src, BytesMut::from(&buf[..len]))),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => None,
+            /* ICMP errors for earlier datagrams are left to QUIC's loss recovery */
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        if let Some((src, datagram)) = received {
+            self.receive(&mut state, now, src, datagram);
+        }
+
+        let state = &mut *state;
+        for (handle, peer) in state.peers.iter_mut() {
+            if peer.connection.poll_timeout().is_some_and(|due| due <= now) {
+                peer.connection.handle_timeout(now);
+            }
+            while let Some(event) = peer.connection.poll_endpoint_events() {
+                if let Some(event) = state.endpoint.handle_event(*handle, event) {
+                    peer.connection.handle_event(event);
+                }
+            }
+            while let Some(event) = peer.connection.poll() {
+                handle_event(handle.0, peer, event, events);
+            }
+        }
+        self.flush(state);
+        state.peers.retain(|_, peer| !peer.connection.is_drained());
+        Ok(())
+    }
+
+    /// Returns another handle to the endpoint, sharing its socket and connections
+    pub fn try_clone(&self) -> QuicEndpoint {
+        QuicEndpoint {
+            sock: self.sock.clone(),
+            state: self.state.clone(),
+        }
+    }
+
+    /// Hand datagram, received from src, to the endpoint, accepting the
+    /// connection it starts if this is the vswitch's endpoint
+    fn receive(
+        &self,
+        state: &mut EndpointState,
+        now: Instant,
+        src: SocketAddr,
+        datagram: BytesMut,
+    ) {
+        let mut response = Vec::new();
+        match state
+            .endpoint
+            .handle(now, src, None, None, datagram, &mut response)
+        {
+            Some(DatagramEvent::ConnectionEvent(handle, event)) => {
+                if let Some(peer) = state.peers.get_mut(&handle) {
+                    peer.connection.handle_event(event);
+                }
+            }
+            Some(DatagramEvent::NewConnection(incoming)) => {
+                match state.endpoint.accept(
+                    incoming,
+                    now,
+                    &mut response,
+                    state.server_config.clone(),
+                ) {
+                    Ok((handle, connection)) => {
+                        state.peers.insert(handle, Peer::new(connection));
+                    }
+                    /* The vport is told it was refused, if it can be */
+                    Err(e) => {
+                        if let Some(transmit) = e.response {
+                            let _ = self
+                                .sock
+                                .send_to(&response[..transmit.size], transmit.destination);
+                        }
+                    }
+                }
+            }
+            Some(DatagramEvent::Response(transmit)) => {
+                let _ = self
+                    .sock
+                    .send_to(&response[..transmit.size], transmit.destination);
+            }
+            None => {}
+        }
+    }
+
+    /// Send the datagrams which the connections have ready. Errors are
+    /// left to QUIC's loss recovery, as they would be for lost datagrams
+    fn flush(&self, state: &mut EndpointState) {
+        let now = Instant::now();
+        let mut buf = Vec::new();
+        for peer in state.peers.values_mut() {
+            peer.write_control();
+            while let Some(transmit) = peer.connection.poll_transmit(now, 1, &mut buf) {
+                let _ = self
+                    .sock
+                    .send_to(&buf[..transmit.size], transmit.destination);
+                buf.clear();
+            }
+        }
+    }
+}
+
+/// Act on event, which happened on peer's connection numbered id,
+/// adding what it means for the frames to events
+fn handle_event(id: usize, peer: &mut Peer, event: Event, events: &mut Vec<QuicEvent>) {
+    match event {
+        Event::Connected => events.push(QuicEvent::Connected(id, peer.connection.remote_address())),
+        Event::ConnectionLost { .. } => events.push(QuicEvent::Closed(id)),
+        /* The control stream is the only one the other end can open */
+        Event::Stream(StreamEvent::Opened { dir: Dir::Bi }) => {
+            if let Some(control) = peer.connection.streams().accept(Dir::Bi) {
+                peer.control.get_or_insert(control);
+            }
+            for msg in peer.read_control() {
+                events.push(QuicEvent::Frame(id, msg));
+            }
+        }
+        Event::Stream(StreamEvent::Readable { .. }) => {
+            for msg in peer.read_control() {
+                events.push(QuicEvent::Frame(id, msg));
+            }
+        }
+        Event::Stream(StreamEvent::Writable { .. }) => peer.write_control(),
+        Event::DatagramReceived => {
+            while let Some(frame) = peer.connection.datagrams().recv() {
+                events.push(QuicEvent::Frame(id, frame.to_vec()));
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Connection from a vport to the vswitch, whose frames are passed
+/// to the link by a thread driving its endpoint
+#[derive(Debug)]
+pub struct QuicLink {
+    endpoint: QuicEndpoint,
+    id: usize,
+    frames: Arc<Mutex<Receiver<Vec<u8>>>>,
+}
+
+impl QuicLink {
+    /// Connect to the vswitch listening for QUIC on host and port,
+    /// whose certificate is checked against config's roots, and
+    /// wait up to HANDSHAKE_TIMEOUT for the handshake to finish
+    pub fn connect(
+        host: &str,
+        port: u16,
+        config: ClientConfig,
+    ) -> Result<QuicLink, TransportError> {
+        let addr = (host, port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "host has no addresses"))?;
+        let sock = match addr {
+            SocketAddr::V4(_) => UdpSocket::bind(("0.0.0.0", 0))?,
+            SocketAddr::V6(_) => UdpSocket::bind(("::", 0))?,
+        };
+        let endpoint = QuicEndpoint::client(sock);
+        let id = endpoint.connect(config, addr, host)?;
+
+        let (frames_tx, frames_rx) = mpsc::channel();
+        let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+        let mut events = Vec::new();
+        loop {
+            if Instant::now() >= deadline {
+                return Err(io::Error::from(ErrorKind::TimedOut).into());
+            }
+            endpoint.poll(&mut events)?;
+            let mut connected = false;
+            for event in events.drain(..) {
+                match event {
+                    QuicEvent::Connected(..) => connected = true,
+                    QuicEvent::Frame(_, frame) => {
+                        let _ = frames_tx.send(frame);
+                    }
+                    QuicEvent::Closed(_) => {
+                        return Err(quic_error("handshake with the vswitch failed"));
+                    }
+                }
+            }
+            if connected {
+                break;
+            }
+        }
+
+        let driver = endpoint.try_clone();
+        thread::spawn(move || drive(&driver, &frames_tx));
+
+        Ok(QuicLink {
+            endpoint,
+            id,
+            frames: Arc::new(Mutex::new(frames_rx)),
+        })
+    }
+
+    /// Send a single frame to the vswitch
+    pub fn send_frame(&self, frame: &[u8]) -> Result<(), TransportError> {
+        self.endpoint.send_frame(self.id, frame)
+    }
+
+    /// Receive a single frame into buf, returning its length
+    ///
+    /// Returns a Closed error once the connection is closed or lost,
+    /// and a FrameTooLarge error if the frame is larger than buf
+    pub fn recv_frame(&self, buf: &mut [u8]) -> Result<usize, TransportError> {
+        let frame = self
+            .frames
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .recv()
+            .map_err(|_| TransportError::Closed)?;
+        let Some(dst) = buf.get_mut(..frame.len()) else {
+            return Err(TransportError::FrameTooLarge {
+                len: frame.len(),
+                max: buf.len(),
+            });
+        };
+        dst.copy_from_slice(&frame);
+        Ok(frame.len())
+    }
+
+    /// Returns another handle to the link, sharing its connection
+    pub fn try_clone(&self) -> Result<QuicLink, TransportError> {
+        Ok(QuicLink {
+            endpoint: self.endpoint.try_clone(),
+            id: self.id,
+            frames: self.frames.clone(),
+        })
+    }
+}
+
+/// Drive the link's endpoint, passing the frames received to frames,
+/// until the connection is closed or the socket fails
+fn drive(endpoint: &QuicEndpoint, frames: &Sender<Vec<u8>>) {
+    let mut events = Vec::new();
+    loop {
+        if endpoint.poll(&mut events).is_err() {
+            return;
+        }
+        for event in events.drain(..) {
+            match event {
+                QuicEvent::Frame(_, frame) => {
+                    if frames.send(frame).is_err() {
+                        return;
+                    }
+                }
+                QuicEvent::Closed(_) => return,
+                QuicEvent::Connected(..) => {}
+            }
+        }
+    }
+}
+
+/// Returns the transport error for a failure in QUIC
+fn quic_error(e: impl fmt::Display) -> TransportError {
+    TransportError::Quic(e.to_string())
+}
@@ -0,0 +1,438 @@
+//! Shared-memory ring transport between the vswitch and
+//! vports running on the same host
+//!
+//! The vport creates a memfd holding two single-producer
+//! single-consumer rings (one in each direction) and two
+//! eventfds, and passes them to the vswitch over a Unix
+//! stream socket. Frames are then exchanged by copying them
+//! in and out of the rings, so no syscalls are needed per
+//! frame. The eventfds are only written when the consumer
+//! has run out of frames and gone to sleep, so they are
+//! only used when the link is idle.
+//!
+//! The Unix stream stays connected for the lifetime of the
+//! link, so that either side can tell when the other has gone
+
+use crate::{error::TransportError, tunnel::TUNNEL_FRAME_MAX};
+use nix::{
+    fcntl::{fcntl, FcntlArg, SealFlag},
+    poll::{poll, PollFd, PollFlags, PollTimeout},
+    sys::{
+        eventfd::{EfdFlags, EventFd},
+        memfd::{memfd_create, MemFdCreateFlag},
+        mman::{mmap, munmap, MapFlags, ProtFlags},
+        socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags},
+    },
+    unistd::{ftruncate, read, write},
+};
+use std::{
+    ffi::c_void,
+    fs::File,
+    hint, io,
+    io::{IoSlice, IoSliceMut},
+    num::NonZeroUsize,
+    os::{
+        fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    ptr::{self, NonNull},
+    sync::{
+        atomic::{fence, AtomicU32, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::Duration,
+};
+
+/// Number of frames each ring can hold
+const RING_SLOTS: u32 = 256;
+
+/// How long a vport which has connected has to pass its rings
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Size of each slot, which holds a 4 byte length and the frame
+const SLOT_SIZE: usize = 16384;
+const SLOT_LEN: usize = 4;
+const SLOT_DATA: usize = SLOT_SIZE - SLOT_LEN;
+
+/*
+ * The head (next slot to consume), tail (next slot to produce)
+ * and waiting flag (consumer is asleep on its eventfd) are each
+ * given their own cache line, so the producer and consumer do
+ * not contend on the same line
+ */
+const CACHE_LINE: usize = 64;
+const RING_HEAD: usize = 0;
+const RING_TAIL: usize = CACHE_LINE;
+const RING_WAITING: usize = 2 * CACHE_LINE;
+const RING_HDR_SIZE: usize = 3 * CACHE_LINE;
+
+const RING_SIZE: usize = RING_HDR_SIZE + RING_SLOTS as usize * SLOT_SIZE;
+const REGION_SIZE: usize = 2 * RING_SIZE;
+
+/// Ring carrying frames from the vport to the vswitch
+const TO_VSWITCH: usize = 0;
+
+/// Ring carrying frames from the vswitch to the vport
+const TO_VPORT: usize = 1;
+
+/// Number of times the consumer polls an empty ring
+/// before going to sleep on its eventfd
+const SPIN_LIMIT: usize = 1024;
+
+/// Shared memory mapping holding both rings
+#[derive(Debug)]
+struct ShmRegion {
+    base: NonNull<c_void>,
+}
+
+/*
+ * The region is only accessed through atomics and copies
+ * into and out of slots which the rings hand to one thread
+ * at a time, so it can be shared between threads
+ */
+unsafe impl Send for ShmRegion {}
+unsafe impl Sync for ShmRegion {}
+
+impl ShmRegion {
+    /// Map the rings held in the given memfd
+    fn map(memfd: &OwnedFd) -> io::Result<ShmRegion> {
+        let base = unsafe {
+            mmap(
+                None,
+                NonZeroUsize::new(REGION_SIZE).unwrap(),
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_SHARED,
+                memfd,
+                0,
+            )?
+        };
+
+        Ok(ShmRegion { base })
+    }
+
+    /// Returns the ring with the given index
+    fn ring(&self, index: usize) -> Ring {
+        Ring {
+            base: unsafe { (self.base.as_ptr() as *mut u8).add(index * RING_SIZE) },
+        }
+    }
+}
+
+impl Drop for ShmRegion {
+    fn drop(&mut self) {
+        let _ = unsafe { munmap(self.base, REGION_SIZE) };
+    }
+}
+
+/// View of a single ring within the shared memory region
+struct Ring {
+    base: *mut u8,
+}
+
+impl Ring {
+    fn index(&self, offset: usize) -> &AtomicU32 {
+        unsafe { &*(self.base.add(offset) as *const AtomicU32) }
+    }
+
+    fn slot(&self, index: u32) -> *mut u8 {
+        unsafe {
+            self.base
+                .add(RING_HDR_SIZE + (index % RING_SLOTS) as usize * SLOT_SIZE)
+        }
+    }
+
+    /// Copy frame into the ring, returning false if it is full
+    fn push(&self, frame: &[u8]) -> bool {
+        let tail = self.index(RING_TAIL).load(Ordering::Relaxed);
+        let head = self.index(RING_HEAD).load(Ordering::Acquire);
+        if tail.wrapping_sub(head) >= RING_SLOTS {
+            return false;
+        }
+
+        let len = frame.len().min(SLOT_DATA);
+        let slot = self.slot(tail);
+        unsafe {
+            ptr::copy_nonoverlapping((len as u32).to_ne_bytes().as_ptr(), slot, SLOT_LEN);
+            ptr::copy_nonoverlapping(frame.as_ptr(), slot.add(SLOT_LEN), len);
+        }
+
+        self.index(RING_TAIL)
+            .store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Copy the next frame out of the ring into buf,
+    /// returning its length, or None if the ring is empty
+    fn pop(&self, buf: &mut [u8]) -> Option<usize> {
+        let head = self.index(RING_HEAD).load(Ordering::Relaxed);
+        let tail = self.index(RING_TAIL).load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+
+        let slot = self.slot(head);
+        let mut len = [0u8; SLOT_LEN];
+        unsafe { ptr::copy_nonoverlapping(slot, len.as_mut_ptr(), SLOT_LEN) };
+
+        /*
+         * The other side of the ring may not be trustworthy, so
+         * a bogus length is turned into an empty (runt) frame
+         * rather than being used to read out of bounds
+         */
+        let mut len = u32::from_ne_bytes(len) as usize;
+        if len > SLOT_DATA || len > buf.len() {
+            len = 0;
+        }
+        unsafe { ptr::copy_nonoverlapping(slot.add(SLOT_LEN), buf.as_mut_ptr(), len) };
+
+        self.index(RING_HEAD)
+            .store(head.wrapping_add(1), Ordering::Release);
+        Some(len)
+    }
+}
+
+/// One end of a shared-memory link
+///
+/// Each ring has a single producer and consumer, so sends from
+/// clones of a link are serialised, and at most one thread may
+/// receive on a link at a time
+#[derive(Debug)]
+pub struct ShmLink {
+    region: Arc<ShmRegion>,
+    tx_lock: Arc<Mutex<()>>,
+    tx_ring: usize,
+    rx_ring: usize,
+    tx_event: Arc<OwnedFd>,
+    rx_event: Arc<OwnedFd>,
+    control: UnixStream,
+}
+
+impl ShmLink {
+    /// Create the shared rings and pass them to the vswitch
+    /// listening on the Unix stream socket at path
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<ShmLink, TransportError> {
+        let memfd = memfd_create(
+            c"l2vpn-shm",
+            MemFdCreateFlag::MFD_CLOEXEC | MemFdCreateFlag::MFD_ALLOW_SEALING,
+        )?;
+        ftruncate(&memfd, REGION_SIZE as i64)?;
+
+        /*
+         * Seal the memfd so it can never shrink, otherwise the
+         * vswitch could be killed by SIGBUS when touching the rings
+         */
+        fcntl(
+            memfd.as_raw_fd(),
+            FcntlArg::F_ADD_SEALS(SealFlag::F_SEAL_SHRINK | SealFlag::F_SEAL_SEAL),
+        )?;
+
+        let to_vswitch_event: OwnedFd = EventFd::from_flags(EfdFlags::EFD_CLOEXEC)?.into();
+        let to_vport_event: OwnedFd = EventFd::from_flags(EfdFlags::EFD_CLOEXEC)?.into();
+
+        let control = UnixStream::connect(path)?;
+        let fds = [
+            memfd.as_raw_fd(),
+            to_vswitch_event.as_raw_fd(),
+            to_vport_event.as_raw_fd(),
+        ];
+        sendmsg::<()>(
+            control.as_raw_fd(),
+            &[IoSlice::new(&[0])],
+            &[ControlMessage::ScmRights(&fds)],
+            MsgFlags::empty(),
+            None,
+        )?;
+
+        Ok(ShmLink {
+            region: Arc::new(ShmRegion::map(&memfd)?),
+            tx_lock: Arc::new(Mutex::new(())),
+            tx_ring: TO_VSWITCH,
+            rx_ring: TO_VPORT,
+            tx_event: Arc::new(to_vswitch_event),
+            rx_event: Arc::new(to_vport_event),
+            control,
+        })
+    }
+
+    /// Returns another handle to the same link, so that
+    /// one thread can send while another receives
+    pub fn try_clone(&self) -> Result<ShmLink, TransportError> {
+        Ok(ShmLink {
+            region: self.region.clone(),
+            tx_lock: self.tx_lock.clone(),
+            tx_ring: self.tx_ring,
+            rx_ring: self.rx_ring,
+            tx_event: self.tx_event.clone(),
+            rx_event: self.rx_event.clone(),
+            control: self.control.try_clone()?,
+        })
+    }
+
+    /// Send a single frame over the link
+    ///
+    /// Frames are dropped if the other side has fallen so far
+    /// behind that the ring is full, as a NIC would do
+    pub fn send_frame(&self, frame: &[u8]) -> Result<(), TransportError> {
+        let ring = self.region.ring(self.tx_ring);
+        let pushed = {
+            let _tx = self.tx_lock.lock().unwrap_or_else(PoisonError::into_inner);
+            ring.push(frame)
+        };
+        if !pushed {
+            eprintln!("Dropped frame as shared memory ring is full");
+            return Ok(());
+        }
+
+        /*
+         * The fence orders the tail update before the check of the
+         * waiting flag, pairing with the fence in recv_frame, so
+         * the consumer can't go to sleep without us seeing it
+         */
+        fence(Ordering::SeqCst);
+        if ring.index(RING_WAITING).load(Ordering::Relaxed) != 0 {
+            write(self.tx_event.as_fd(), &1u64.to_ne_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Receive a single frame into buf, returning its length
+    ///
+    /// Returns a Closed error once the other side has gone
+    pub fn recv_frame(&self, buf: &mut [u8]) -> Result<usize, TransportError> {
+        let ring = self.region.ring(self.rx_ring);
+
+        loop {
+            /* Poll the ring for a while before going to sleep */
+            for _ in 0..SPIN_LIMIT {
+                if let Some(len) = ring.pop(buf) {
+                    return Ok(len);
+                }
+                hint::spin_loop();
+            }
+
+            ring.index(RING_WAITING).store(1, Ordering::Relaxed);
+            fence(Ordering::SeqCst);
+
+            /* Check again, in case a frame arrived before the flag was set */
+            if let Some(len) = ring.pop(buf) {
+                ring.index(RING_WAITING).store(0, Ordering::Relaxed);
+                return Ok(len);
+            }
+
+            let mut fds = [
+                PollFd::new(self.rx_event.as_fd(), PollFlags::POLLIN),
+                PollFd::new(self.control.as_fd(), PollFlags::POLLIN),
+            ];
+            poll(&mut fds, PollTimeout::NONE)?;
+            ring.index(RING_WAITING).store(0, Ordering::Relaxed);
+
+            /* Nothing is ever sent on the control stream, so it being readable means EOF */
+            if fds[1].any().unwrap_or(true) {
+                return Err(TransportError::Closed);
+            }
+
+            if fds[0].any().unwrap_or(false) {
+                let mut count = [0u8; 8];
+                read(self.rx_event.as_raw_fd(), &mut count)?;
+            }
+        }
+    }
+}
+
+/// Unix stream socket which accepts shared-memory links from vports
+#[derive(Debug)]
+pub struct ShmListener {
+    listener: UnixListener,
+}
+
+impl ShmListener {
+    /// Listen for vports on the Unix stream socket at path
+    pub fn bind<P: AsRef<Path>>(path: P) -> Result<ShmListener, TransportError> {
+        Ok(ShmListener {
+            listener: UnixListener::bind(path)?,
+        })
+    }
+
+    /// Wait for a vport to connect, returning it before it has passed its
+    /// rings, so a vport which never passes them only holds up whichever
+    /// thread calls ShmPending::handshake(), rather than the listener
+    pub fn accept(&self) -> Result<ShmPending, TransportError> {
+        let (control, _) = self.listener.accept()?;
+        Ok(ShmPending { control })
+    }
+}
+
+/// Vport which has connected to a ShmListener, but not yet passed its rings
+#[derive(Debug)]
+pub struct ShmPending {
+    control: UnixStream,
+}
+
+impl ShmPending {
+    /// Wait for the vport to pass its rings, for up to HANDSHAKE_TIMEOUT, and map them
+    pub fn handshake(self) -> Result<ShmLink, TransportError> {
+        let ShmPending { control } = self;
+        control.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+
+        let mut byte = [0u8; 1];
+        let mut iov = [IoSliceMut::new(&mut byte)];
+        let mut cmsg_buf = nix::cmsg_space!([RawFd; 3]);
+        let msg = recvmsg::<()>(
+            control.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg_buf),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )?;
+
+        /* Take ownership of every fd passed, so none are leaked */
+        let mut fds = Vec::new();
+        for cmsg in msg.cmsgs()? {
+            if let ControlMessageOwned::ScmRights(raw_fds) = cmsg {
+                fds.extend(
+                    raw_fds
+                        .into_iter()
+                        .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+                );
+            }
+        }
+
+        let [memfd, to_vswitch_event, to_vport_event]: [OwnedFd; 3] =
+            fds.try_into().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "vport did not pass a memfd and two eventfds",
+                )
+            })?;
+
+        /*
+         * Make sure the memfd is big enough that the rings can't be
+         * accessed out of bounds, and is sealed so it stays that way
+         */
+        let seals = SealFlag::from_bits_truncate(fcntl(memfd.as_raw_fd(), FcntlArg::F_GET_SEALS)?);
+        if !seals.contains(SealFlag::F_SEAL_SHRINK)
+            || File::from(memfd.try_clone()?).metadata()?.len() < REGION_SIZE as u64
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "vport passed a memfd which is too small or not sealed",
+            )
+            .into());
+        }
+
+        control.set_read_timeout(None)?;
+        Ok(ShmLink {
+            region: Arc::new(ShmRegion::map(&memfd)?),
+            tx_lock: Arc::new(Mutex::new(())),
+            tx_ring: TO_VPORT,
+            rx_ring: TO_VSWITCH,
+            tx_event: Arc::new(to_vport_event),
+            rx_event: Arc::new(to_vswitch_event),
+            control,
+        })
+    }
+}
+
+/* Make sure a full size frame fits in a slot, even with the largest overlay MTU */
+const _: () = assert!(TUNNEL_FRAME_MAX <= SLOT_DATA);
@@ -0,0 +1,702 @@
+//! I/O-free core of 802.1D spanning tree
+//!
+//! A loop of peered vswitches, or a vport which bridges back into the
+//! overlay, would otherwise carry broadcasts around the loop until their
+//! TTL runs out. Bridge exchanges BPDUs with the bridges on its ports,
+//! elects the bridge with the lowest ID as the root, and only forwards
+//! on its port towards the root and the ports which other bridges reach
+//! the root through. Its other ports are blocked
+//!
+//! Ports which have never received a BPDU are taken to lead to hosts,
+//! so forward straight away rather than listening and learning for
+//! twice the forward delay first. A vport which bridges back into the
+//! overlay without running STP itself passes on the BPDUs it is sent,
+//! so is found out by the bridge receiving its own BPDUs
+//!
+//! Like l2vpn::switching, the bridge is given the BPDUs received and the
+//! current time, and returns the BPDUs to send, so the caller provides
+//! the sockets and the clock. Ports are whatever the caller uses to tell
+//! its vports apart
+
+use crate::{mac::MacAddr, timer::Interval, utilities::ETHER_FRAME_MIN};
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+/// Destination MAC of STP bridge protocol data units
+pub const STP_MAC: [u8; 6] = [0x01, 0x80, 0xC2, 0x00, 0x00, 0x00];
+
+/// LLC header of STP BPDUs (DSAP, SSAP and control)
+const STP_LLC: [u8; 3] = [0x42, 0x42, 0x03];
+
+/// BPDU types of configuration BPDUs, RST BPDUs (whose first 35
+/// bytes are laid out as configuration BPDUs are) and topology
+/// change notifications
+const BPDU_CONFIG: u8 = 0x00;
+const BPDU_RST: u8 = 0x02;
+const BPDU_TCN: u8 = 0x80;
+
+/// Flags set in configuration BPDUs during a topology change,
+/// and to acknowledge a topology change notification
+const BPDU_FLAG_TC: u8 = 0x01;
+const BPDU_FLAG_TCA: u8 = 0x80;
+
+/// Length of a configuration BPDU, after the LLC header
+const CONFIG_BPDU_LEN: usize = 35;
+
+/// Length of a topology change notification, after the LLC header
+const TCN_BPDU_LEN: usize = 4;
+
+/// Timers of 802.1D's defaults, which this bridge uses whichever
+/// bridge is the root
+pub const HELLO_TIME: Duration = Duration::from_secs(2);
+pub const MAX_AGE: Duration = Duration::from_secs(20);
+pub const FORWARD_DELAY: Duration = Duration::from_secs(15);
+
+/// How much older the root's information is by the time it is passed on
+const MESSAGE_AGE_INCREMENT: Duration = Duration::from_secs(1);
+
+/// Cost of reaching the root through any port, as for a 100 Mb/s link,
+/// since the speed of the tunnel behind a port is unknown
+pub const PORT_PATH_COST: u32 = 19;
+
+/// Priority of a bridge which isn't given one
+pub const DEFAULT_BRIDGE_PRIORITY: u16 = 0x8000;
+
+/// Identifies a bridge. The bridge with the lowest ID becomes the root
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BridgeId {
+    pub priority: u16,
+    pub mac: MacAddr,
+}
+
+impl BridgeId {
+    /// Returns the ID as it is carried in BPDUs
+    fn to_bytes(self) -> [u8; 8] {
+        let mut bytes = [0u8; 8];
+        bytes[..2].copy_from_slice(&self.priority.to_be_bytes());
+        bytes[2..].copy_from_slice(&self.mac.0);
+        bytes
+    }
+
+    /// Returns the ID carried in the first 8 bytes of bytes
+    fn from_bytes(bytes: &[u8]) -> Option<BridgeId> {
+        Some(BridgeId {
+            priority: u16::from_be_bytes(*bytes.first_chunk::<2>()?),
+            mac: MacAddr(*bytes.get(2..)?.first_chunk::<6>()?),
+        })
+    }
+}
+
+impl fmt::Display for BridgeId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04x}.{}", self.priority, self.mac)
+    }
+}
+
+/// What a designated port claims about the path to the root through
+/// it. The lowest vector is the best, comparing each field in turn
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PriorityVector {
+    pub root: BridgeId,
+    pub root_cost: u32,
+    pub bridge: BridgeId,
+    pub port: u16,
+}
+
+/// Bridge protocol data unit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bpdu {
+    /// Sent by designated ports every hello time. This bridge's
+    /// timers are sent with it, and those received are ignored
+    Config {
+        vector: PriorityVector,
+        flags: u8,
+        message_age: Duration,
+    },
+    /// Sent towards the root after a bridge's topology changes
+    Tcn,
+}
+
+impl Bpdu {
+    /// Parse frame as a BPDU, returning None if it isn't one
+    pub fn parse(frame: &[u8]) -> Option<Bpdu> {
+        /* The EtherType field of 802.3 frames holds their length */
+        if frame.get(..6)? != STP_MAC || frame.get(14..17)? != STP_LLC {
+            return None;
+        }
+        let bpdu = frame.get(17..)?;
+        if bpdu.len() < TCN_BPDU_LEN || bpdu[..2] != [0, 0] {
+            return None;
+        }
+
+        match bpdu[3] {
+            BPDU_TCN => Some(Bpdu::Tcn),
+            BPDU_CONFIG | BPDU_RST if bpdu.len() >= CONFIG_BPDU_LEN => Some(Bpdu::Config {
+                vector: PriorityVector {
+                    root: BridgeId::from_bytes(&bpdu[5..13])?,
+                    root_cost: u32::from_be_bytes(*bpdu[13..].first_chunk::<4>()?),
+                    bridge: BridgeId::from_bytes(&bpdu[17..25])?,
+                    port: u16::from_be_bytes(*bpdu[25..].first_chunk::<2>()?),
+                },
+                flags: bpdu[4],
+                message_age: from_bpdu_time(u16::from_be_bytes(*bpdu[27..].first_chunk::<2>()?)),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Returns the BPDU in a frame from src_mac, padded to the Ethernet minimum
+    pub fn encode(&self, src_mac: &MacAddr) -> Vec<u8> {
+        let mut bpdu = vec![0u8, 0, 0];
+        match self {
+            Bpdu::Config {
+                vector,
+                flags,
+                message_age,
+            } => {
+                bpdu.push(BPDU_CONFIG);
+                bpdu.push(*flags);
+                bpdu.extend_from_slice(&vector.root.to_bytes());
+                bpdu.extend_from_slice(&vector.root_cost.to_be_bytes());
+                bpdu.extend_from_slice(&vector.bridge.to_bytes());
+                bpdu.extend_from_slice(&vector.port.to_be_bytes());
+                for time in [*message_age, MAX_AGE, HELLO_TIME, FORWARD_DELAY] {
+                    bpdu.extend_from_slice(&to_bpdu_time(time).to_be_bytes());
+                }
+            }
+            Bpdu::Tcn => bpdu.push(BPDU_TCN),
+        }
+
+        let mut frame = Vec::with_capacity(ETHER_FRAME_MIN);
+        frame.extend_from_slice(&STP_MAC);
+        frame.extend_from_slice(&src_mac.0);
+        frame.extend_from_slice(&((STP_LLC.len() + bpdu.len()) as u16).to_be_bytes());
+        frame.extend_from_slice(&STP_LLC);
+        frame.extend_from_slice(&bpdu);
+        if frame.len() < ETHER_FRAME_MIN {
+            frame.resize(ETHER_FRAME_MIN, 0);
+        }
+        frame
+    }
+
+    /// Returns true if the BPDU signals a topology change, i.e. it is a
+    /// topology change notification, or has the topology change flag set
+    pub fn is_topology_change(&self) -> bool {
+        match self {
+            Bpdu::Config { flags, .. } => flags & BPDU_FLAG_TC != 0,
+            Bpdu::Tcn => true,
+        }
+    }
+}
+
+/// Returns a time carried in a BPDU, in 1/256ths of a second
+fn from_bpdu_time(time: u16) -> Duration {
+    Duration::from_micros(u64::from(time) * 1_000_000 / 256)
+}
+
+/// Returns time as it is carried in a BPDU
+fn to_bpdu_time(time: Duration) -> u16 {
+    u16::try_from(time.as_micros() * 256 / 1_000_000).unwrap_or(u16::MAX)
+}
+
+/// Whether a port forwards frames, and learns MACs from them
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PortState {
+    /// The port is down
+    Disabled,
+    /// The port only receives BPDUs, as forwarding on it would make a loop
+    Blocking,
+    /// The port is waiting for the rest of the network to block
+    /// any loops it would make, before learning
+    Listening,
+    /// The port learns MACs, but doesn't yet forward
+    Learning,
+    Forwarding,
+}
+
+impl PortState {
+    /// Returns true if MACs are learned from the frames received on the port
+    pub fn learns(&self) -> bool {
+        matches!(self, PortState::Learning | PortState::Forwarding)
+    }
+
+    /// Returns true if frames are received from and sent to the port
+    pub fn forwards(&self) -> bool {
+        *self == PortState::Forwarding
+    }
+
+    /// Returns the name of the state
+    pub fn name(&self) -> &'static str {
+        match self {
+            PortState::Disabled => "disabled",
+            PortState::Blocking => "blocking",
+            PortState::Listening => "listening",
+            PortState::Learning => "learning",
+            PortState::Forwarding => "forwarding",
+        }
+    }
+}
+
+/// Part a port plays in the spanning tree
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PortRole {
+    /// The port towards the root
+    Root,
+    /// The port which the bridges beyond it reach the root through
+    Designated,
+    /// A port which would make a loop, as the bridges beyond it
+    /// have a better path to the root
+    Alternate,
+    /// A port which has never received a BPDU, so leads to hosts
+    Edge,
+}
+
+impl PortRole {
+    /// Returns the name of the role
+    pub fn name(&self) -> &'static str {
+        match self {
+            PortRole::Root => "root",
+            PortRole::Designated => "designated",
+            PortRole::Alternate => "alternate",
+            PortRole::Edge => "edge",
+        }
+    }
+}
+
+/// Role and state of one of the bridge's ports
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PortStatus {
+    /// Port ID sent in BPDUs
+    pub id: u16,
+    pub role: PortRole,
+    pub state: PortState,
+}
+
+/// Change to the spanning tree, for the caller to act on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Change<P> {
+    /// A new bridge became the root
+    Root(BridgeId),
+    /// A port changed state
+    State(P, PortState),
+    /// The topology changed, so learned MACs may now be reached
+    /// through other ports, and should be flushed
+    TopologyChange,
+}
+
+/// Configuration BPDU received on a port
+#[derive(Clone, Copy, Debug)]
+struct Received {
+    vector: PriorityVector,
+    message_age: Duration,
+    /// When the information is too old to be used
+    expires: Instant,
+}
+
+/// State of one of the bridge's ports
+#[derive(Debug)]
+struct Port {
+    status: PortStatus,
+    /* When the port entered its state, for the forward delay */
+    since: Instant,
+    /* Best BPDU received on the port, until it expires */
+    received: Option<Received>,
+    /* Whether the next BPDU sent on the port acknowledges a TCN received on it */
+    ack_tcn: bool,
+}
+
+/// Spanning tree bridge, running 802.1D on the ports it is given
+#[derive(Debug)]
+pub struct Bridge<P> {
+    id: BridgeId,
+    ports: HashMap<P, Port>,
+    root: BridgeId,
+    root_cost: u32,
+    root_port: Option<P>,
+    hello: Interval,
+    /* Until when the root sets the topology change flag in its BPDUs */
+    tc_until: Option<Instant>,
+    /* Whether the last BPDU from the root port had the topology change flag set */
+    root_tc: bool,
+    /* Whether a TCN is sent through the root port every hello time, until it is acknowledged */
+    tcn_pending: bool,
+    changes: Vec<Change<P>>,
+}
+
+impl<P: Copy + Eq + Hash> Bridge<P> {
+    /// Returns a bridge with no ports, which is its own root
+    pub fn new(id: BridgeId) -> Bridge<P> {
+        Bridge {
+            id,
+            ports: HashMap::new(),
+            root: id,
+            root_cost: 0,
+            root_port: None,
+            hello: Interval::new(HELLO_TIME),
+            tc_until: None,
+            root_tc: false,
+            tcn_pending: false,
+            changes: Vec::new(),
+        }
+    }
+
+    /// Returns the ID of the bridge
+    pub fn id(&self) -> BridgeId {
+        self.id
+    }
+
+    /// Returns the ID of the root bridge
+    pub fn root(&self) -> BridgeId {
+        self.root
+    }
+
+    /// Returns the cost of the path to the root
+    pub fn root_cost(&self) -> u32 {
+        self.root_cost
+    }
+
+    /// Returns the port towards the root, or None if this bridge is the root
+    pub fn root_port(&self) -> Option<&P> {
+        self.root_port.as_ref()
+    }
+
+    /// Returns the role and state of every port
+    pub fn ports(&self) -> impl Iterator<Item = (&P, PortStatus)> {
+        self.ports.iter().map(|(port, info)| (port, info.status))
+    }
+
+    /// Returns the state of port, where ports which the bridge
+    /// hasn't been given yet are edge ports, so forward
+    pub fn state(&self, port: &P) -> PortState {
+        self.ports
+            .get(port)
+            .map_or(PortState::Forwarding, |info| info.status.state)
+    }
+
+    /// Returns the changes made since they were last taken
+    pub fn take_changes(&mut self) -> Vec<Change<P>> {
+        std::mem::take(&mut self.changes)
+    }
+
+    /// Handle bpdu, received at now on port, which has the given ID,
+    /// returning the BPDUs to send in reply
+    pub fn receive(&mut self, port: P, id: u16, bpdu: Bpdu, now: Instant) -> Vec<(P, Bpdu)> {
+        /* A port which receives a BPDU leads to a bridge, so is no longer an edge port */
+        match self.ports.get_mut(&port) {
+            Some(info) if info.status.role == PortRole::Edge => {
+                info.status.role = PortRole::Designated;
+            }
+            Some(_) => {}
+            None => {
+                let status = PortStatus {
+                    id,
+                    role: PortRole::Designated,
+                    state: PortState::Blocking,
+                };
+                self.ports.insert(
+                    port,
+                    Port {
+                        status,
+                        since: now,
+                        received: None,
+                        ack_tcn: false,
+                    },
+                );
+                self.changes.push(Change::State(port, PortState::Blocking));
+            }
+        }
+
+        let mut replies = Vec::new();
+        match bpdu {
+            Bpdu::Config {
+                vector,
+                flags,
+                message_age,
+            } => {
+                if message_age >= MAX_AGE {
+                    return replies;
+                }
+                let Some(info) = self.ports.get_mut(&port) else {
+                    return replies;
+                };
+
+                /* Worse information only replaces what the same bridge and port sent before */
+                let replaces = info.received.is_none_or(|received| {
+                    vector <= received.vector
+                        || (vector.bridge, vector.port)
+                            == (received.vector.bridge, received.vector.port)
+                });
+                if replaces {
+                    info.received = Some(Received {
+                        vector,
+                        message_age,
+                        expires: now + (MAX_AGE - message_age),
+                    });
+                }
+                self.update(now);
+
+                if self.root_port == Some(port) {
+                    if flags & BPDU_FLAG_TCA != 0 {
+                        self.tcn_pending = false;
+                    }
+                    let root_tc = flags & BPDU_FLAG_TC != 0;
+                    if root_tc && !self.root_tc {
+                        self.changes.push(Change::TopologyChange);
+                    }
+                    self.root_tc = root_tc;
+                }
+
+                /* A bridge claiming to be designated with worse information is put right */
+                let designated = self.designated_vector(id);
+                if self.role(&port) == Some(PortRole::Designated) && designated < vector {
+                    replies.extend(self.config_bpdu(port).map(|bpdu| (port, bpdu)));
+                }
+            }
+            Bpdu::Tcn => {
+                self.update(now);
+                if self.role(&port) == Some(PortRole::Designated) {
+                    if let Some(info) = self.ports.get_mut(&port) {
+                        info.ack_tcn = true;
+                    }
+                    replies.extend(self.config_bpdu(port).map(|bpdu| (port, bpdu)));
+                    self.topology_change(now);
+                }
+            }
+        }
+        replies
+    }
+
+    /// Run the bridge's timers at now, where up is every port which is up,
+    /// along with its ID, returning the BPDUs to send
+    pub fn tick(&mut self, up: &[(P, u16)], now: Instant) -> Vec<(P, Bpdu)> {
+        /* Ports which have gone down are forgotten */
+        let down: Vec<P> = self
+            .ports
+            .keys()
+            .filter(|port| !up.iter().any(|(up, _)| up == *port))
+            .copied()
+            .collect();
+        for port in down {
+            self.set_state(port, PortState::Disabled, now);
+            self.ports.remove(&port);
+        }
+
+        /* New ports are taken to lead to hosts, until they receive a BPDU */
+        for (port, id) in up {
+            if !self.ports.contains_key(port) {
+                let status = PortStatus {
+                    id: *id,
+                    role: PortRole::Edge,
+                    state: PortState::Forwarding,
+                };
+                self.ports.insert(
+                    *port,
+                    Port {
+                        status,
+                        since: now,
+                        received: None,
+                        ack_tcn: false,
+                    },
+                );
+                self.changes
+                    .push(Change::State(*port, PortState::Forwarding));
+            }
+        }
+
+        for info in self.ports.values_mut() {
+            if info
+                .received
+                .is_some_and(|received| received.expires <= now)
+            {
+                info.received = None;
+            }
+        }
+        if self.tc_until.is_some_and(|until| until <= now) {
+            self.tc_until = None;
+        }
+        self.update(now);
+
+        /* Ports move on from listening and learning after the forward delay */
+        let due: Vec<(P, PortState)> = self
+            .ports
+            .iter()
+            .filter(|(_, info)| now.saturating_duration_since(info.since) >= FORWARD_DELAY)
+            .filter_map(|(port, info)| match info.status.state {
+                PortState::Listening => Some((*port, PortState::Learning)),
+                PortState::Learning => Some((*port, PortState::Forwarding)),
+                _ => None,
+            })
+            .collect();
+        for (port, state) in due {
+            self.set_state(port, state, now);
+        }
+
+        let mut bpdus = Vec::new();
+        if self.hello.due(now) {
+            let designated: Vec<P> = self
+                .ports
+                .iter()
+                .filter(|(_, info)| {
+                    matches!(info.status.role, PortRole::Designated | PortRole::Edge)
+                })
+                .map(|(port, _)| *port)
+                .collect();
+            for port in designated {
+                bpdus.extend(self.config_bpdu(port).map(|bpdu| (port, bpdu)));
+            }
+            if let Some(root_port) = self.root_port.filter(|_| self.tcn_pending) {
+                bpdus.push((root_port, Bpdu::Tcn));
+            }
+        }
+        bpdus
+    }
+
+    /// Returns the role of port, if the bridge has it
+    fn role(&self, port: &P) -> Option<PortRole> {
+        self.ports.get(port).map(|info| info.status.role)
+    }
+
+    /// Returns the vector which this bridge sends on the port with ID id
+    fn designated_vector(&self, id: u16) -> PriorityVector {
+        PriorityVector {
+            root: self.root,
+            root_cost: self.root_cost,
+            bridge: self.id,
+            port: id,
+        }
+    }
+
+    /// Returns the configuration BPDU to send on port, or None if the
+    /// root's information would be too old by the time it arrived
+    fn config_bpdu(&mut self, port: P) -> Option<Bpdu> {
+        let message_age = match self.root_port.and_then(|root| self.ports.get(&root)) {
+            Some(root) => root.received?.message_age + MESSAGE_AGE_INCREMENT,
+            None => Duration::ZERO,
+        };
+        if message_age >= MAX_AGE {
+            return None;
+        }
+
+        let tc = match self.root_port {
+            Some(_) => self.root_tc,
+            None => self.tc_until.is_some(),
+        };
+        let info = self.ports.get_mut(&port)?;
+        let mut flags = if tc { BPDU_FLAG_TC } else { 0 };
+        if std::mem::take(&mut info.ack_tcn) {
+            flags |= BPDU_FLAG_TCA;
+        }
+        let id = info.status.id;
+
+        Some(Bpdu::Config {
+            vector: self.designated_vector(id),
+            flags,
+            message_age,
+        })
+    }
+
+    /// Elect the root and root port from the information received
+    /// on each port, and give every port its role and state
+    fn update(&mut self, now: Instant) {
+        let best = self
+            .ports
+            .iter()
+            .filter_map(|(port, info)| Some((port, info.status.id, info.received?.vector)))
+            .filter(|(_, _, vector)| vector.root < self.id)
+            .min_by_key(|(_, id, vector)| {
+                (
+                    vector.root,
+                    vector.root_cost.saturating_add(PORT_PATH_COST),
+                    vector.bridge,
+                    vector.port,
+                    *id,
+                )
+            });
+        let (root, root_cost, root_port) = match best {
+            Some((port, _, vector)) => (
+                vector.root,
+                vector.root_cost.saturating_add(PORT_PATH_COST),
+                Some(*port),
+            ),
+            None => (self.id, 0, None),
+        };
+
+        if root != self.root {
+            self.changes.push(Change::Root(root));
+        }
+        if root_port != self.root_port {
+            self.root_tc = false;
+        }
+        self.root = root;
+        self.root_cost = root_cost;
+        self.root_port = root_port;
+        if root_port.is_none() {
+            self.tcn_pending = false;
+        }
+
+        let mut states = Vec::new();
+        for (port, info) in self.ports.iter() {
+            let designated = PriorityVector {
+                root,
+                root_cost,
+                bridge: self.id,
+                port: info.status.id,
+            };
+            let role = match info.received {
+                _ if Some(*port) == root_port => PortRole::Root,
+                _ if info.status.role == PortRole::Edge => PortRole::Edge,
+                Some(received) if received.vector < designated => PortRole::Alternate,
+                _ => PortRole::Designated,
+            };
+
+            let state = match (role, info.status.state) {
+                (PortRole::Alternate, _) => PortState::Blocking,
+                (PortRole::Edge, _) => PortState::Forwarding,
+                (_, PortState::Blocking | PortState::Disabled) => PortState::Listening,
+                (_, state) => state,
+            };
+            states.push((*port, role, state));
+        }
+
+        for (port, role, state) in states {
+            if let Some(info) = self.ports.get_mut(&port) {
+                info.status.role = role;
+            }
+            self.set_state(port, state, now);
+        }
+    }
+
+    /// Move port to state at now, noting a topology change if a port
+    /// which leads to other bridges starts or stops forwarding
+    fn set_state(&mut self, port: P, state: PortState, now: Instant) {
+        let Some(info) = self.ports.get_mut(&port) else {
+            return;
+        };
+        let old = info.status.state;
+        if old == state {
+            return;
+        }
+        info.status.state = state;
+        info.since = now;
+        let edge = info.status.role == PortRole::Edge;
+
+        self.changes.push(Change::State(port, state));
+        if !edge && (state.forwards() || old.forwards()) {
+            self.topology_change(now);
+        }
+    }
+
+    /// Note that the topology changed at now, which the root tells every
+    /// bridge about in its BPDUs, and other bridges tell the root about
+    fn topology_change(&mut self, now: Instant) {
+        self.changes.push(Change::TopologyChange);
+        match self.root_port {
+            Some(_) => self.tcn_pending = true,
+            None => self.tc_until = Some(now + MAX_AGE + FORWARD_DELAY),
+        }
+    }
+}
@@ -0,0 +1,198 @@
+//! Finding a vport's public endpoint and NAT type with STUN
+//!
+//! A vport behind NAT only knows its private address, so it can ask
+//! STUN servers (RFC 5389) which endpoint its binding requests arrive
+//! from, from the socket it reaches the vswitch on, and report it when
+//! it joins. The NAT's type is found by comparing the endpoints which
+//! two servers see, or the two addresses of a server which gives its
+//! other address, as in RFC 5780: a NAT which maps the socket to the
+//! same endpoint whatever it sends to lets other vports punch through
+//! to it, and one which maps each destination to another endpoint
+//! doesn't. A vport whose own address is seen is behind no NAT
+//!
+//! Only the messages are built and parsed here, so they can be sent
+//! from whatever socket the vport uses
+
+use std::{
+    fmt,
+    net::{Ipv4Addr, SocketAddrV4},
+    time::Duration,
+};
+
+/// How long a STUN server has to answer before the request is sent again
+pub const STUN_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How many times a binding request is sent before the server is given up on
+pub const STUN_ATTEMPTS: u32 = 3;
+
+/// Magic cookie which every STUN message since RFC 5389 carries
+const MAGIC_COOKIE: u32 = 0x2112a442;
+
+/// Length of a STUN message's header
+const STUN_HDR_LEN: usize = 20;
+
+/// Message types of binding requests and their success responses
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+
+/// Attributes of binding responses holding addresses
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_CHANGED_ADDRESS: u16 = 0x0005;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const ATTR_OTHER_ADDRESS: u16 = 0x802c;
+
+/// Address family of IPv4 addresses in attributes
+const FAMILY_IPV4: u8 = 0x01;
+
+/// How a vport's NAT maps its socket to public endpoints
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NatType {
+    /// The vport's own address is seen, so it is behind no NAT
+    Open,
+    /// The socket is mapped to the same endpoint whatever it sends to
+    Cone,
+    /// Each destination sees the socket from another endpoint
+    Symmetric,
+    /// Only one endpoint was seen, so the NAT's mapping isn't known
+    Unknown,
+}
+
+impl NatType {
+    /// Returns the NAT type's code in join messages
+    pub fn code(&self) -> u8 {
+        match self {
+            NatType::Open => 1,
+            NatType::Cone => 2,
+            NatType::Symmetric => 3,
+            NatType::Unknown => 4,
+        }
+    }
+
+    /// Returns the NAT type with code, or None if it isn't one
+    pub fn from_code(code: u8) -> Option<NatType> {
+        match code {
+            1 => Some(NatType::Open),
+            2 => Some(NatType::Cone),
+            3 => Some(NatType::Symmetric),
+            4 => Some(NatType::Unknown),
+            _ => None,
+        }
+    }
+
+    /// Returns the type of NAT a socket bound to local is behind, given
+    /// the endpoints which STUN servers at different addresses saw it
+    /// from, in the order they answered
+    pub fn classify(local: SocketAddrV4, mapped: &[SocketAddrV4]) -> NatType {
+        match mapped {
+            [first, ..] if *first == local => NatType::Open,
+            [first, rest @ ..] if !rest.is_empty() => match rest.iter().all(|m| m == first) {
+                true => NatType::Cone,
+                false => NatType::Symmetric,
+            },
+            _ => NatType::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for NatType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            NatType::Open => "no NAT",
+            NatType::Cone => "cone NAT",
+            NatType::Symmetric => "symmetric NAT",
+            NatType::Unknown => "NAT of unknown type",
+        })
+    }
+}
+
+/// Endpoint which a vport's datagrams arrive from beyond its NAT,
+/// and the NAT's type, as found with STUN
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PublicEndpoint {
+    pub addr: SocketAddrV4,
+    pub nat: NatType,
+}
+
+impl fmt::Display for PublicEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.addr, self.nat)
+    }
+}
+
+/// What a STUN server answered a binding request with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BindingResponse {
+    /// Endpoint which the server saw the request come from
+    pub mapped: SocketAddrV4,
+    /// Other address of the server, if it has one, which sees the
+    /// request come from the same endpoint unless the NAT is symmetric
+    pub other: Option<SocketAddrV4>,
+}
+
+/// Returns a binding request with transaction_id, ready to be sent
+pub fn binding_request(transaction_id: &[u8; 12]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(STUN_HDR_LEN);
+    msg.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    msg.extend_from_slice(&0u16.to_be_bytes());
+    msg.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    msg.extend_from_slice(transaction_id);
+    msg
+}
+
+/// Returns what the binding response in datagram answered, or None if
+/// it isn't a success response to the request with transaction_id, or
+/// doesn't say where the request came from over IPv4
+pub fn parse_binding_response(
+    datagram: &[u8],
+    transaction_id: &[u8; 12],
+) -> Option<BindingResponse> {
+    let (hdr, attrs) = datagram.split_at_checked(STUN_HDR_LEN)?;
+    if hdr[..2] != BINDING_SUCCESS.to_be_bytes()
+        || hdr[4..8] != MAGIC_COOKIE.to_be_bytes()
+        || hdr[8..] != transaction_id[..]
+    {
+        return None;
+    }
+    let len = usize::from(u16::from_be_bytes([hdr[2], hdr[3]]));
+    let mut attrs = attrs.get(..len)?;
+
+    /* Servers older than RFC 5389 only send the address unXORed */
+    let (mut mapped, mut xor_mapped, mut other) = (None, None, None);
+    while let [t0, t1, l0, l1, rest @ ..] = attrs {
+        let attr_len = usize::from(u16::from_be_bytes([*l0, *l1]));
+        let value = rest.get(..attr_len)?;
+        match u16::from_be_bytes([*t0, *t1]) {
+            ATTR_MAPPED_ADDRESS => mapped = address(value),
+            ATTR_XOR_MAPPED_ADDRESS => xor_mapped = address(value).map(unxor),
+            ATTR_OTHER_ADDRESS | ATTR_CHANGED_ADDRESS => other = other.or(address(value)),
+            _ => {}
+        }
+        /* Attributes are padded to a multiple of 4 bytes */
+        attrs = rest.get(attr_len.next_multiple_of(4)..).unwrap_or_default();
+    }
+
+    Some(BindingResponse {
+        mapped: xor_mapped.or(mapped)?,
+        other,
+    })
+}
+
+/// Returns the IPv4 endpoint held by an address attribute's value
+fn address(value: &[u8]) -> Option<SocketAddrV4> {
+    match value {
+        [_, FAMILY_IPV4, p0, p1, a0, a1, a2, a3, ..] => Some(SocketAddrV4::new(
+            Ipv4Addr::new(*a0, *a1, *a2, *a3),
+            u16::from_be_bytes([*p0, *p1]),
+        )),
+        _ => None,
+    }
+}
+
+/// Returns the endpoint of an XOR-MAPPED-ADDRESS, which is XORed
+/// with the magic cookie so NATs don't rewrite it
+fn unxor(addr: SocketAddrV4) -> SocketAddrV4 {
+    SocketAddrV4::new(
+        Ipv4Addr::from(u32::from(*addr.ip()) ^ MAGIC_COOKIE),
+        addr.port() ^ (MAGIC_COOKIE >> 16) as u16,
+    )
+}
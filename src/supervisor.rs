@@ -0,0 +1,128 @@
+//! Keeping the forwarding loops running
+//!
+//! The vport's forwarding loops and the vswitch's switching loop run
+//! for as long as the process does, so a panic or an error in one of
+//! them would otherwise leave the process running with part of the
+//! tunnel gone. supervise restarts a loop which fails, waiting longer
+//! between restarts while it keeps failing, and a watchdog notices a
+//! loop which has been stuck on the same frame for too long, which
+//! nothing inside the process can fix
+
+use std::{
+    fmt::Display,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// Shortest and longest waits before a failed loop is restarted
+const RESTART_DELAY_MIN: Duration = Duration::from_millis(100);
+const RESTART_DELAY_MAX: Duration = Duration::from_secs(10);
+
+/// How long a loop has to run before failing for its
+/// next restart to wait the shortest time again
+const RESTART_RESET: Duration = Duration::from_secs(60);
+
+/// Run the loop called name until it returns Ok, restarting it whenever
+/// it returns an error or panics. Panics are reported by the panic hook,
+/// so only errors are printed here
+pub fn supervise<F, E>(name: &str, mut run: F)
+where
+    F: FnMut() -> Result<(), E>,
+    E: Display,
+{
+    let mut delay = RESTART_DELAY_MIN;
+
+    loop {
+        let started = Instant::now();
+        match panic::catch_unwind(AssertUnwindSafe(&mut run)) {
+            Ok(Ok(())) => return,
+            Ok(Err(e)) => eprintln!("{} failed with error: '{}'", name, e),
+            Err(_) => eprintln!("{} panicked", name),
+        }
+
+        if started.elapsed() >= RESTART_RESET {
+            delay = RESTART_DELAY_MIN;
+        }
+        eprintln!("Restarting {} in {:?}", name, delay);
+        thread::sleep(delay);
+        delay = (delay * 2).min(RESTART_DELAY_MAX);
+    }
+}
+
+/// Shows when a loop started on the frame or event it is handling,
+/// so a watchdog can tell if it is stuck. Clones share the same state
+#[derive(Clone, Debug)]
+pub struct Heartbeat {
+    epoch: Instant,
+    /* Milliseconds after epoch, plus one, when the loop became busy, or 0 when it is idle */
+    busy_since: Arc<AtomicU64>,
+}
+
+impl Heartbeat {
+    /// Returns the heartbeat of a loop which is idle
+    pub fn new() -> Heartbeat {
+        Heartbeat {
+            epoch: Instant::now(),
+            busy_since: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Note that the loop started handling something at now
+    pub fn busy(&self, now: Instant) {
+        let since = now.saturating_duration_since(self.epoch).as_millis() as u64;
+        self.busy_since.store(since + 1, Ordering::Relaxed);
+    }
+
+    /// Note that the loop is waiting for something to handle, which
+    /// may take any amount of time without the loop being stuck
+    pub fn idle(&self) {
+        self.busy_since.store(0, Ordering::Relaxed);
+    }
+
+    /// Returns how long the loop has been busy with the same thing at now,
+    /// or None if it is idle
+    pub fn busy_for(&self, now: Instant) -> Option<Duration> {
+        match self.busy_since.load(Ordering::Relaxed) {
+            0 => None,
+            since => {
+                let busy_since = self.epoch + Duration::from_millis(since - 1);
+                Some(now.saturating_duration_since(busy_since))
+            }
+        }
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Heartbeat {
+        Heartbeat::new()
+    }
+}
+
+/// Start a thread which calls on_stall with the name of any of loops,
+/// and how long it has been stuck, once it has been busy with the same
+/// thing for timeout. on_stall is called again every time the loop is
+/// checked while it stays stuck
+pub fn watchdog<F>(
+    loops: Vec<(String, Heartbeat)>,
+    timeout: Duration,
+    on_stall: F,
+) -> JoinHandle<()>
+where
+    F: Fn(&str, Duration) + Send + 'static,
+{
+    thread::spawn(move || loop {
+        thread::sleep(timeout / 4);
+
+        let now = Instant::now();
+        for (name, heartbeat) in loops.iter() {
+            if let Some(busy_for) = heartbeat.busy_for(now).filter(|d| *d >= timeout) {
+                on_stall(name, busy_for);
+            }
+        }
+    })
+}
@@ -0,0 +1,520 @@
+//! vswitch which can be embedded in another program
+//!
+//! A Switch switches the frames of the vports which send datagrams to
+//! the transport it is given (see l2vpn::transport), so a program (or a
+//! test) can run an L2VPN node in-process, on whatever socket it bound,
+//! e.g. a UDP socket on an ephemeral port on the loopback. It answers
+//! joins, learns MACs with the same I/O-free core as the vswitch (see
+//! l2vpn::switching), and flushes the MACs of vports which leave or stop
+//! being heard from. What only the vswitch binary does, such as its
+//! other transports, VLANs, spanning tree and admin socket, is left out
+//!
+//! The program can add FrameHooks to the switch, which are shown each
+//! frame as it arrives, once where it is going has been decided, and as
+//! it is sent to each port, and can change or drop it there, e.g. to
+//! filter frames, rewrite their addresses or count them
+//!
+//! Programs which subscribe() to the switch are sent SwitchEvents, such
+//! as MACs being learned or ports timing out, over a channel, rather
+//! than having to scrape what it prints
+//!
+//! run() switches frames until shutdown() is called from another
+//! thread, so the transport is read with a timeout of POLL_INTERVAL
+
+use crate::{
+    control::{is_control_frame, ControlMsg},
+    endpoint::Session,
+    error::Error,
+    frame::EthernetFrame,
+    log_frame,
+    mac::MacAddr,
+    switching::{self, Decision, DropReason, ForwardingTable, Learned, MacAges, MacTable},
+    timer::Interval,
+    transport::OverlayTransport,
+    tunnel::{
+        hop_limit, pop_hop_limit, push_hop_limit, DEFAULT_TTL, HOP_LIMIT_TAG_LEN,
+        TUNNEL_DATAGRAM_MAX,
+    },
+    utilities::{FrameLogMsg, ETHER_FRAME_MIN},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, io,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Mutex, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+/// How long run() waits for a datagram before checking whether it was
+/// told to stop, and for vports which stopped being heard from
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a vport can go unheard before its MACs are flushed, which
+/// is three of the hellos vports send every 10 seconds
+pub const PORT_DOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many events can wait for each subscriber, beyond which
+/// the events it is too slow to take are dropped
+pub const EVENT_QUEUE_LEN: usize = 1024;
+
+/// Something which happened in a Switch, which is sent to its subscribers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SwitchEvent<P> {
+    /// mac was learned on the vport at port
+    MacLearned { mac: MacAddr, port: P },
+    /// mac, which was learned on the vport at from, moved to the one at to
+    MacMoved { mac: MacAddr, from: P, to: P },
+    /// mac wasn't received from for the MAC aging time, so was forgotten
+    MacAged { mac: MacAddr },
+    /// The vport at port sent the switch its first datagram, so was given a port with ID id
+    PortJoined { port: P, id: u32 },
+    /// The vport at port left, so its MACs were flushed
+    PortLeft { port: P, id: u32 },
+    /// The vport at port stopped being heard from, so its MACs were flushed
+    PortTimedOut { port: P, id: u32 },
+    /// A frame from the vport at src was dropped
+    FrameDropped { src: P, reason: DropReason },
+}
+
+/// What a FrameHook decided should happen to a frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// The frame carries on, as the hook may have changed it
+    Pass,
+    /// The frame is dropped, and later hooks aren't shown it
+    Drop,
+}
+
+/// Hook which is shown the frames a Switch switches, and can change
+/// or drop them. Data frames are shown to hooks in the order they were
+/// added, while control frames, which are meant for the switch, aren't
+/// shown to them. Each stage passes the frame by default
+pub trait FrameHook<P>: fmt::Debug + Send + Sync {
+    /// Shown frame as it arrives from the vport at src, before its
+    /// source MAC is learned or it is checked against the MTU
+    fn on_ingress(&self, frame: &mut Vec<u8>, src: &P) -> Verdict {
+        let _ = (frame, src);
+        Verdict::Pass
+    }
+
+    /// Shown frame from the vport at src once the ports it is sent to
+    /// have been decided, which dsts can be changed to
+    fn pre_forward(&self, frame: &mut Vec<u8>, src: &P, dsts: &mut Vec<P>) -> Verdict {
+        let _ = (frame, src, dsts);
+        Verdict::Pass
+    }
+
+    /// Shown a copy of frame as it is about to be sent to the vport at
+    /// dst, so changing it only changes what that vport is sent
+    fn on_egress(&self, frame: &mut Vec<u8>, dst: &P) -> Verdict {
+        let _ = (frame, dst);
+        Verdict::Pass
+    }
+}
+
+/// A vport which has sent the switch a datagram
+struct Port {
+    id: u32,
+    /// Session the vport registered with its hellos and join, if it has
+    session: Option<Session>,
+    last_seen: Instant,
+    /// The vport tags its frames with a hop limit, so is sent tagged frames
+    tagged: bool,
+    /// MTU the vport gave, if it isn't ours, when its data frames are dropped
+    mtu_mismatch: Option<usize>,
+}
+
+/// vswitch switching the frames of the vports which send datagrams to its transport
+#[derive(Debug)]
+pub struct Switch<T: OverlayTransport = UdpSocket> {
+    /// Transport which the vports send their datagrams to
+    transport: T,
+    /// MTU of the L2VPN network, which vports with another are refused for
+    mtu: usize,
+    /// Set by shutdown(), to stop run()
+    stopping: AtomicBool,
+    /// Shown each data frame, in the order they were added
+    hooks: Vec<Box<dyn FrameHook<T::Peer>>>,
+    /// How long MACs are kept without being received from, if they are aged out
+    mac_aging: Option<Duration>,
+    /// Where events are sent to each subscriber
+    subscribers: Mutex<Vec<SyncSender<SwitchEvent<T::Peer>>>>,
+}
+
+impl<T: OverlayTransport> Switch<T> {
+    /// Returns a switch for the vports which send datagrams to transport,
+    /// in an L2VPN network with an MTU of mtu
+    pub fn new(transport: T, mtu: usize) -> Switch<T> {
+        Switch {
+            transport,
+            mtu,
+            stopping: AtomicBool::new(false),
+            hooks: Vec::new(),
+            mac_aging: None,
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Forget MACs which haven't been received from for aging, or
+    /// keep them until their port leaves or times out if it is None,
+    /// which is the default
+    pub fn set_mac_aging(&mut self, aging: Option<Duration>) {
+        self.mac_aging = aging;
+    }
+
+    /// Returns a channel which every event from now on is sent over,
+    /// until it is dropped. If more than EVENT_QUEUE_LEN events are
+    /// waiting to be received, those which follow are dropped, rather
+    /// than stalling the switch
+    pub fn subscribe(&self) -> Receiver<SwitchEvent<T::Peer>> {
+        let (tx, rx) = mpsc::sync_channel(EVENT_QUEUE_LEN);
+        self.subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(tx);
+        rx
+    }
+
+    /// Add hook after those already added, so it is shown the frames
+    /// they pass, as they left them
+    pub fn add_hook(&mut self, hook: impl FrameHook<T::Peer> + 'static) {
+        self.hooks.push(Box::new(hook));
+    }
+
+    /// Switch frames until shutdown() is called, returning the error
+    /// which stopped the switch if something else does
+    pub fn run(&self) -> Result<(), Error> {
+        self.transport.set_read_timeout(Some(POLL_INTERVAL))?;
+
+        let mut ports: HashMap<T::Peer, Port> = HashMap::new();
+        let mut mac_table: MacTable<T::Peer> = HashMap::new();
+        let mut mac_ages = MacAges::new(Instant::now());
+        let mut next_id = 1;
+        let mut sweep = Interval::new(POLL_INTERVAL);
+        let mut buf = [0u8; TUNNEL_DATAGRAM_MAX];
+
+        while !self.stopping.load(Ordering::Relaxed) {
+            let received = self.transport.recv_from_peer(&mut buf);
+            let now = Instant::now();
+
+            /* Flush the MACs of vports which have stopped being heard from */
+            if sweep.due(now) {
+                ports.retain(|addr, port| {
+                    let up = now.saturating_duration_since(port.last_seen) < PORT_DOWN_TIMEOUT;
+                    if !up {
+                        mac_table.flush(&mut |_, mac_port| mac_port == addr);
+                        eprintln!("Port {} ({}) stopped being heard from", port.id, addr);
+                        self.notify(SwitchEvent::PortTimedOut {
+                            port: *addr,
+                            id: port.id,
+                        });
+                    }
+                    up
+                });
+            }
+            for mac in mac_ages.expire(&mut mac_table, self.mac_aging, &HashSet::new(), now) {
+                self.notify(SwitchEvent::MacAged { mac });
+            }
+
+            let (len, src) = match received {
+                Ok(received) => received,
+                Err(e) => {
+                    /* Nothing arrived in time, or a vport which has gone refused a frame */
+                    if e.is_transient() {
+                        continue;
+                    }
+                    return Err(e.into());
+                }
+            };
+
+            /* Frames from vports carry a TTL, while frames from anything else are entering the network */
+            let tagged_ttl = hop_limit(&buf[..len]);
+            let len = pop_hop_limit(&mut buf, len);
+            let ttl = tagged_ttl.unwrap_or(DEFAULT_TTL).saturating_sub(1);
+            if switching::is_runt(len) {
+                log_frame!("Dropped runt frame of {} bytes from '{}'", len, src);
+                self.dropped(src, DropReason::Runt);
+                continue;
+            }
+            let mut frame = buf[..len].to_vec();
+            if frame.len() < ETHER_FRAME_MIN {
+                frame.resize(ETHER_FRAME_MIN, 0);
+            }
+
+            let port = ports.entry(src).or_insert_with(|| {
+                let id = next_id;
+                next_id += 1;
+                eprintln!("New vport {} as port {}", src, id);
+                self.notify(SwitchEvent::PortJoined { port: src, id });
+                Port {
+                    id,
+                    session: None,
+                    last_seen: now,
+                    tagged: false,
+                    mtu_mismatch: None,
+                }
+            });
+            port.last_seen = now;
+            port.tagged |= tagged_ttl.is_some();
+
+            /* Control frames are meant for the switch, so are never forwarded */
+            if is_control_frame(&frame) {
+                if self.control(src, port, &frame) {
+                    mac_table.flush(&mut |_, mac_port| *mac_port == src);
+                    self.notify(SwitchEvent::PortLeft {
+                        port: src,
+                        id: port.id,
+                    });
+                    ports.remove(&src);
+                }
+                continue;
+            }
+
+            /* The vport was refused for having another MTU than ours */
+            if port.mtu_mismatch.is_some() {
+                log_frame!(
+                    "Dropped frame from port {}, whose MTU doesn't match ours: {}",
+                    port.id,
+                    FrameLogMsg(&frame, frame.len())
+                );
+                self.dropped(src, DropReason::MtuMismatch);
+                continue;
+            }
+
+            if self
+                .hooks
+                .iter()
+                .any(|hook| hook.on_ingress(&mut frame, &src) == Verdict::Drop)
+            {
+                log_frame!(
+                    "Dropped frame in an ingress hook: {}",
+                    FrameLogMsg(&frame, frame.len())
+                );
+                self.dropped(src, DropReason::Hook);
+                continue;
+            }
+
+            /* The hooks may have cut the frame short, which is checked before it is padded again */
+            let Ok(EthernetFrame {
+                dst: dst_mac,
+                src: src_mac,
+                ..
+            }) = EthernetFrame::parse(&frame)
+            else {
+                log_frame!("Dropped runt frame of {} bytes from '{}'", frame.len(), src);
+                self.dropped(src, DropReason::Runt);
+                continue;
+            };
+            if frame.len() < ETHER_FRAME_MIN {
+                frame.resize(ETHER_FRAME_MIN, 0);
+            }
+            if switching::is_oversize(frame.len(), self.mtu) {
+                log_frame!(
+                    "Dropped frame too large for the MTU of {}: {}",
+                    self.mtu,
+                    FrameLogMsg(&frame, frame.len())
+                );
+                self.dropped(src, DropReason::Oversize);
+                continue;
+            }
+            if ttl == 0 {
+                log_frame!(
+                    "Dropped frame whose TTL expired: {}",
+                    FrameLogMsg(&frame, frame.len())
+                );
+                self.dropped(src, DropReason::TtlExpired);
+                continue;
+            }
+
+            mac_ages.seen(src_mac, now);
+            match switching::learn(&mut mac_table, src_mac, src) {
+                Some(Learned::New) => self.notify(SwitchEvent::MacLearned {
+                    mac: src_mac,
+                    port: src,
+                }),
+                Some(Learned::Moved(from)) => self.notify(SwitchEvent::MacMoved {
+                    mac: src_mac,
+                    from,
+                    to: src,
+                }),
+                None => {}
+            }
+            /* Broadcasts reach the vports which have joined, before any of their hosts have sent a frame */
+            let peers: Vec<T::Peer> = ports
+                .iter()
+                .filter(|(_, port)| port.mtu_mismatch.is_none())
+                .map(|(peer, _)| *peer)
+                .collect();
+            let mut dst_ports =
+                match switching::decide(&mac_table, &peers, &src_mac, &dst_mac, false, |dst| {
+                    *dst == src
+                }) {
+                    Decision::Unicast(dst) => vec![dst],
+                    /* A port is flooded once, however many of its MACs were learned */
+                    Decision::Flood(mut dsts) => {
+                        dsts.sort();
+                        dsts.dedup();
+                        dsts
+                    }
+                    /* A port is a split-horizon group of its own, so isn't sent its own frames */
+                    Decision::Excluded => {
+                        self.dropped(src, DropReason::SplitHorizon);
+                        continue;
+                    }
+                    Decision::Unknown => {
+                        self.dropped(src, DropReason::UnknownUnicast);
+                        continue;
+                    }
+                };
+
+            if self
+                .hooks
+                .iter()
+                .any(|hook| hook.pre_forward(&mut frame, &src, &mut dst_ports) == Verdict::Drop)
+            {
+                log_frame!(
+                    "Dropped frame in a pre-forward hook: {}",
+                    FrameLogMsg(&frame, frame.len())
+                );
+                self.dropped(src, DropReason::Hook);
+                continue;
+            }
+
+            for dst in dst_ports {
+                let mut out = frame.clone();
+                if self
+                    .hooks
+                    .iter()
+                    .any(|hook| hook.on_egress(&mut out, &dst) == Verdict::Drop)
+                {
+                    log_frame!(
+                        "Dropped frame to '{}' in an egress hook: {}",
+                        dst,
+                        FrameLogMsg(&out, out.len())
+                    );
+                    self.dropped(src, DropReason::Hook);
+                    continue;
+                }
+                if out.len() < ETHER_FRAME_MIN {
+                    out.resize(ETHER_FRAME_MIN, 0);
+                }
+
+                /* The tag is only sent to vports which send it themselves */
+                if ports.get(&dst).is_some_and(|port| port.tagged) {
+                    let len = out.len();
+                    out.resize(len + HOP_LIMIT_TAG_LEN, 0);
+                    let tagged_len = push_hop_limit(&mut out, len, ttl);
+                    out.truncate(tagged_len);
+                }
+                if let Err(e) = self.transport.send_to_peer(&out, &dst) {
+                    eprintln!("Got error while sending frame to '{}': {}", dst, e);
+                    self.dropped(src, DropReason::TxError);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stop run(), which returns within POLL_INTERVAL
+    pub fn shutdown(&self) {
+        self.stopping.store(true, Ordering::Relaxed);
+    }
+
+    /// Send event to every subscriber, forgetting those which have gone
+    fn notify(&self, event: SwitchEvent<T::Peer>) {
+        self.subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|tx| !matches!(tx.try_send(event), Err(TrySendError::Disconnected(_))));
+    }
+
+    /// Tell subscribers that a frame from the vport at src was dropped, for reason
+    fn dropped(&self, src: T::Peer, reason: DropReason) {
+        self.notify(SwitchEvent::FrameDropped { src, reason });
+    }
+
+    /// Handle the control message in frame from the vport at src,
+    /// returning true if it left, so its port is removed
+    fn control(&self, src: T::Peer, port: &mut Port, frame: &[u8]) -> bool {
+        let Ok(msg) = ControlMsg::decode(frame) else {
+            return false;
+        };
+        match msg {
+            ControlMsg::Hello {
+                session_id,
+                token,
+                mtu,
+            }
+            | ControlMsg::Join {
+                session_id,
+                token,
+                mtu,
+                ..
+            } => {
+                /* A vport with another MTU is refused until its MTU matches ours, as the vswitch does */
+                let mismatch = switching::mtu_mismatch(mtu, self.mtu);
+                if port.mtu_mismatch != mismatch {
+                    port.mtu_mismatch = mismatch;
+                    match mismatch {
+                        Some(mtu) => eprintln!(
+                            "Refused port {} ({}), as its MTU of {} doesn't match ours of {}",
+                            port.id, src, mtu, self.mtu
+                        ),
+                        None => eprintln!(
+                            "Accepted port {} ({}), as its MTU now matches ours",
+                            port.id, src
+                        ),
+                    }
+                }
+                if mismatch.is_some() {
+                    return false;
+                }
+                port.session = Some(Session {
+                    id: session_id,
+                    token,
+                });
+
+                /* A join is answered with the vport's port, agreeing to none of the capabilities asked for */
+                if matches!(msg, ControlMsg::Join { .. }) {
+                    let mut reply = ControlMsg::Joined {
+                        session_id,
+                        port_id: port.id,
+                        vlan: None,
+                        capabilities: 0,
+                    }
+                    .encode();
+                    reply.resize(ETHER_FRAME_MIN, 0);
+                    if let Err(e) = self.transport.send_to_peer(&reply, &src) {
+                        eprintln!("Got error while answering join from '{}': {}", src, e);
+                    }
+                }
+                false
+            }
+            /* Only the vport in the port's own session can make it leave */
+            ControlMsg::Leave { session_id, token } => {
+                let leaves = port.session
+                    == Some(Session {
+                        id: session_id,
+                        token,
+                    });
+                if leaves {
+                    eprintln!("Port {} ({}) left, so flushed its MACs", port.id, src);
+                }
+                leaves
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Switch<UdpSocket> {
+    /// Returns the address vports send their datagrams to
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.transport.local_addr()
+    }
+}
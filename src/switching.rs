@@ -0,0 +1,635 @@
+//! I/O-free core of the vswitch's switching
+//!
+//! MAC learning, MAC aging and the decision of where each frame is
+//! forwarded are kept free of sockets and of the clock. They are given
+//! MACs, the ports frames came from and the current time, and return
+//! what should happen, so they can be tested, fuzzed and simulated
+//! without a network, and driven by any runtime. The vswitch provides
+//! the sockets, and acts on what they return
+//!
+//! The checks which refuse frames before they are switched, and the
+//! reasons frames are dropped for, are kept here too, so the vswitch
+//! and a Switch (see l2vpn::switch) refuse the same frames and vports
+//!
+//! Ports are whatever the caller uses to tell its vports apart, and MAC
+//! tables are anything implementing ForwardingTable, of which MacTable
+//! is the one the binaries use
+
+use crate::{
+    mac::MacAddr,
+    utilities::{frame_max, ETHER_HDR},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// How often learned MACs are checked for having aged out
+const AGING_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Port which each MAC was learned on
+pub type MacTable<P> = HashMap<MacAddr, P>;
+
+/// Table of the port which each MAC was learned on, which learning,
+/// forwarding decisions and aging are made against, so other backends
+/// (e.g. sharded, persistent or lock-free ones) can be used in place of
+/// MacTable, and tested on their own
+pub trait ForwardingTable<P> {
+    /// Record that frames from mac come from port, returning the port
+    /// it was learned on before, if any
+    fn learn(&mut self, mac: MacAddr, port: P) -> Option<P>;
+
+    /// Returns the port mac was learned on, if it has been learned
+    fn lookup(&self, mac: &MacAddr) -> Option<&P>;
+
+    /// Forget the MACs which matches returns true for, returning them
+    fn flush(&mut self, matches: &mut dyn FnMut(&MacAddr, &P) -> bool) -> Vec<MacAddr>;
+
+    /// Returns every learned MAC, with the port it was learned on, in no particular order
+    fn entries(&self) -> Box<dyn Iterator<Item = (&MacAddr, &P)> + '_>;
+
+    /// Forget mac, which has aged out, returning the port it was learned on
+    fn age(&mut self, mac: &MacAddr) -> Option<P>;
+}
+
+impl<P> ForwardingTable<P> for MacTable<P> {
+    fn learn(&mut self, mac: MacAddr, port: P) -> Option<P> {
+        self.insert(mac, port)
+    }
+
+    fn lookup(&self, mac: &MacAddr) -> Option<&P> {
+        self.get(mac)
+    }
+
+    fn flush(&mut self, matches: &mut dyn FnMut(&MacAddr, &P) -> bool) -> Vec<MacAddr> {
+        let mut flushed = Vec::new();
+        self.retain(|mac, port| {
+            let flush = matches(mac, port);
+            if flush {
+                flushed.push(*mac);
+            }
+            !flush
+        });
+        flushed
+    }
+
+    fn entries(&self) -> Box<dyn Iterator<Item = (&MacAddr, &P)> + '_> {
+        Box::new(self.iter())
+    }
+
+    fn age(&mut self, mac: &MacAddr) -> Option<P> {
+        self.remove(mac)
+    }
+}
+
+/// Why a frame was dropped, by the vswitch or a Switch, which counts
+/// and reports them under the same reasons
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropReason {
+    /// Too short to hold an Ethernet header
+    Runt,
+    /// Chosen to be dropped by chaos mode
+    Chaos,
+    /// Received from a port an admin client has shut down
+    PortShutdown,
+    /// Received from a port which is rate limited for being over its quota
+    OverQuota,
+    /// Control frame beyond the port's control plane rate
+    ControlPoliced,
+    /// Control frame which the vswitch couldn't decode
+    MalformedControl,
+    /// Passed through too many vswitches, so probably looping
+    TtlExpired,
+    /// Denied by the ACL
+    AclDenied,
+    /// Sent to an unknown unicast or multicast MAC, while flooding is off
+    UnknownUnicast,
+    /// Sent to a MAC on a port an admin client has shut down
+    DestinationShutdown,
+    /// Could not be sent to its destination's vport or peer
+    TxError,
+    /// Tagged for a VLAN which the port it came from doesn't carry
+    VlanMismatch,
+    /// Received from, or sent to a MAC on, a port which STP is blocking
+    StpBlocked,
+    /// From a new source MAC, on a port which has already learned its limit of MACs
+    MacLimit,
+    /// Flooded beyond the port's storm control rate
+    StormControl,
+    /// Multicast to a group which no vport has joined, while IGMP snooping is on
+    NoListeners,
+    /// Sent to a MAC on a port in the same split-horizon group as the port it came from
+    SplitHorizon,
+    /// From a MAC which is dampened for flapping, on a port other than the one it is held on
+    MacMove,
+    /// Received from a port beyond its ingress rate limit
+    RateLimit,
+    /// Sent to a MAC on a port beyond its egress rate limit
+    DestinationRateLimit,
+    /// Sent to a port whose egress queue for the frame's priority is full
+    QueueFull,
+    /// Larger than the overlay MTU allows
+    Oversize,
+    /// Received from a vport whose MTU isn't the vswitch's
+    MtuMismatch,
+    /// Compressed, but couldn't be decompressed
+    BadCompression,
+    /// Carried a VNI header, on an address whose segment is fixed
+    UnexpectedVni,
+    /// A batch of frames whose lengths overran it
+    BadBatch,
+    /// An FEC shard which didn't fit its group
+    BadFec,
+    /// Dropped by a FrameHook of a Switch embedded in another program
+    Hook,
+}
+
+impl DropReason {
+    /// Every reason, in the order they are counted and shown
+    pub const ALL: [DropReason; 28] = [
+        DropReason::Runt,
+        DropReason::Chaos,
+        DropReason::PortShutdown,
+        DropReason::OverQuota,
+        DropReason::ControlPoliced,
+        DropReason::MalformedControl,
+        DropReason::TtlExpired,
+        DropReason::AclDenied,
+        DropReason::UnknownUnicast,
+        DropReason::DestinationShutdown,
+        DropReason::TxError,
+        DropReason::VlanMismatch,
+        DropReason::StpBlocked,
+        DropReason::MacLimit,
+        DropReason::StormControl,
+        DropReason::NoListeners,
+        DropReason::SplitHorizon,
+        DropReason::MacMove,
+        DropReason::RateLimit,
+        DropReason::DestinationRateLimit,
+        DropReason::QueueFull,
+        DropReason::Oversize,
+        DropReason::MtuMismatch,
+        DropReason::BadCompression,
+        DropReason::UnexpectedVni,
+        DropReason::BadBatch,
+        DropReason::BadFec,
+        DropReason::Hook,
+    ];
+
+    /// Returns the name of the reason used in stats and 'show drops'
+    pub fn name(&self) -> &'static str {
+        match self {
+            DropReason::Runt => "runt",
+            DropReason::Chaos => "chaos",
+            DropReason::PortShutdown => "port-shutdown",
+            DropReason::OverQuota => "over-quota",
+            DropReason::ControlPoliced => "control-policed",
+            DropReason::MalformedControl => "malformed-control",
+            DropReason::TtlExpired => "ttl-expired",
+            DropReason::AclDenied => "acl-denied",
+            DropReason::UnknownUnicast => "unknown-unicast",
+            DropReason::DestinationShutdown => "destination-shutdown",
+            DropReason::TxError => "tx-error",
+            DropReason::VlanMismatch => "vlan-mismatch",
+            DropReason::StpBlocked => "stp-blocked",
+            DropReason::MacLimit => "mac-limit",
+            DropReason::StormControl => "storm-control",
+            DropReason::NoListeners => "no-listeners",
+            DropReason::SplitHorizon => "split-horizon",
+            DropReason::MacMove => "mac-move",
+            DropReason::RateLimit => "rate-limit",
+            DropReason::DestinationRateLimit => "destination-rate-limit",
+            DropReason::QueueFull => "queue-full",
+            DropReason::Oversize => "oversize",
+            DropReason::MtuMismatch => "mtu-mismatch",
+            DropReason::BadCompression => "bad-compression",
+            DropReason::UnexpectedVni => "unexpected-vni",
+            DropReason::BadBatch => "bad-batch",
+            DropReason::BadFec => "bad-fec",
+            DropReason::Hook => "hook",
+        }
+    }
+}
+
+/// Describes the reason, following "dropped", e.g. "dropped as its TTL expired"
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            DropReason::Runt => "as it is too short to hold an Ethernet header",
+            DropReason::Chaos => "by chaos mode",
+            DropReason::PortShutdown => "as the port is shut down",
+            DropReason::OverQuota => "as the port is over its quota",
+            DropReason::ControlPoliced => "by control plane policing",
+            DropReason::MalformedControl => "as it is an unrecognised control message",
+            DropReason::TtlExpired => "as its TTL expired",
+            DropReason::AclDenied => "as the ACL denied it",
+            DropReason::UnknownUnicast => "as its destination is unknown",
+            DropReason::DestinationShutdown => "as its destination's port is shut down",
+            DropReason::TxError => "as it could not be sent to its destination",
+            DropReason::VlanMismatch => "as it is tagged for a VLAN its port doesn't carry",
+            DropReason::StpBlocked => "as STP is blocking the port it would use",
+            DropReason::MacLimit => "as its port has learned as many MACs as it may",
+            DropReason::StormControl => "by storm control",
+            DropReason::NoListeners => "as no vport has joined its multicast group",
+            DropReason::SplitHorizon => {
+                "as its destination is in the same split-horizon group as its port"
+            }
+            DropReason::MacMove => "as its source MAC is dampened for moving too often",
+            DropReason::RateLimit => "as the port is over its ingress rate limit",
+            DropReason::DestinationRateLimit => {
+                "as its destination's port is over its egress rate limit"
+            }
+            DropReason::QueueFull => "as its destination's egress queue for its priority is full",
+            DropReason::Oversize => "as it is larger than the overlay MTU allows",
+            DropReason::MtuMismatch => "as its port's vport has another MTU than ours",
+            DropReason::BadCompression => "as it could not be decompressed",
+            DropReason::UnexpectedVni => "as it has a VNI header, which only our port accepts",
+            DropReason::BadBatch => "as its frames overran the batch they were in",
+            DropReason::BadFec => "as it was an FEC shard which didn't fit its group",
+            DropReason::Hook => "by a frame hook",
+        })
+    }
+}
+
+/// Returns true if a frame of frame_len bytes is too short to hold an
+/// Ethernet header, so can't be switched. This is checked before the
+/// frame is padded, so padding can't make it look like a frame from 00:00:00:00:00:00
+pub fn is_runt(frame_len: usize) -> bool {
+    frame_len < ETHER_HDR
+}
+
+/// Returns true if a frame of frame_len bytes is too large for an L2VPN
+/// network with an MTU of mtu, so the vports it is sent to would drop it
+pub fn is_oversize(frame_len: usize, mtu: usize) -> bool {
+    frame_len > frame_max(mtu)
+}
+
+/// Returns the MTU a vport gave in its hello or join if it isn't mtu,
+/// when the vport is refused, and its data frames are dropped, as it would
+/// send frames too large for the others, or lose theirs. Older vports
+/// give none, so are never refused
+pub fn mtu_mismatch(vport_mtu: u16, mtu: usize) -> Option<usize> {
+    let vport_mtu = usize::from(vport_mtu);
+    (vport_mtu != 0 && vport_mtu != mtu).then_some(vport_mtu)
+}
+
+/// Change which learning a source MAC made to a MAC table
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Learned<P> {
+    /// The MAC was not in the table
+    New,
+    /// The MAC had been learned on this other port
+    Moved(P),
+}
+
+/// Learn that frames from mac come from port, returning how the
+/// table changed, or None if the MAC was already learned there
+pub fn learn<P: Copy + Eq>(
+    table: &mut impl ForwardingTable<P>,
+    mac: MacAddr,
+    port: P,
+) -> Option<Learned<P>> {
+    match table.learn(mac, port) {
+        Some(old_port) if old_port == port => None,
+        Some(old_port) => Some(Learned::Moved(old_port)),
+        None => Some(Learned::New),
+    }
+}
+
+/// Where a frame is forwarded to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Decision<P> {
+    /// Sent to the port which the destination MAC was learned on
+    Unicast(P),
+    /// Sent to the ports of every MAC except the source MAC, and to
+    /// every peer except the one it came from, as it is a broadcast,
+    /// or is flooded
+    Flood(Vec<P>),
+    /// Dropped, as the destination MAC's port is excluded
+    Excluded,
+    /// Dropped, as the destination MAC is unknown, and not flooded
+    Unknown,
+}
+
+/// Decide where a frame from src_mac to dst_mac is forwarded to,
+/// flooding frames to unknown MACs if flood_unknown is set (broadcasts
+/// are always flooded), and never to ports which excluded returns true for
+pub fn decide<P: Copy + Eq>(
+    table: &impl ForwardingTable<P>,
+    peers: &[P],
+    src_mac: &MacAddr,
+    dst_mac: &MacAddr,
+    flood_unknown: bool,
+    excluded: impl Fn(&P) -> bool,
+) -> Decision<P> {
+    match table.lookup(dst_mac) {
+        Some(dst_port) if excluded(dst_port) => Decision::Excluded,
+        Some(dst_port) => Decision::Unicast(*dst_port),
+        None if dst_mac.is_broadcast() || flood_unknown => {
+            let mut dst_ports: Vec<P> = table
+                .entries()
+                .filter(|(mac, dst_port)| *mac != src_mac && !excluded(dst_port))
+                .map(|(_, dst_port)| *dst_port)
+                .collect();
+
+            /* Peers are flooded even before any of their MACs are learned */
+            let src_port = table.lookup(src_mac);
+            for peer in peers {
+                if Some(peer) != src_port && !dst_ports.contains(peer) && !excluded(peer) {
+                    dst_ports.push(*peer);
+                }
+            }
+            Decision::Flood(dst_ports)
+        }
+        None => Decision::Unknown,
+    }
+}
+
+/// When each learned MAC was last received from, for aging them out
+#[derive(Debug)]
+pub struct MacAges {
+    last_seen: HashMap<MacAddr, Instant>,
+    last_sweep: Instant,
+}
+
+impl MacAges {
+    /// Returns the ages of a MAC table which was created at now
+    pub fn new(now: Instant) -> MacAges {
+        MacAges {
+            last_seen: HashMap::new(),
+            last_sweep: now,
+        }
+    }
+
+    /// Note that a frame was received from mac at now
+    pub fn seen(&mut self, mac: MacAddr, now: Instant) {
+        self.last_seen.insert(mac, now);
+    }
+
+    /// Remove the MACs in table which have not been received from for
+    /// aging by now (except static_macs) and return them, or just forget
+    /// the flushed MACs if aging is None. MACs which have not been
+    /// received from since the table was created are aged from the first check
+    pub fn expire<P>(
+        &mut self,
+        table: &mut impl ForwardingTable<P>,
+        aging: Option<Duration>,
+        static_macs: &HashSet<MacAddr>,
+        now: Instant,
+    ) -> Vec<MacAddr> {
+        if now.saturating_duration_since(self.last_sweep) < AGING_SWEEP_INTERVAL {
+            return Vec::new();
+        }
+        self.last_sweep = now;
+
+        /* Forget MACs which have been flushed since */
+        self.last_seen.retain(|mac, _| table.lookup(mac).is_some());
+        let Some(aging) = aging else {
+            return Vec::new();
+        };
+
+        let mut expired = Vec::new();
+        for (mac, _) in table.entries() {
+            let last_seen = *self.last_seen.entry(*mac).or_insert(now);
+            if now.saturating_duration_since(last_seen) >= aging && !static_macs.contains(mac) {
+                expired.push(*mac);
+            }
+        }
+
+        for mac in expired.iter() {
+            table.age(mac);
+            self.last_seen.remove(mac);
+        }
+
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: MacAddr = MacAddr([0x02, 0, 0, 0, 0, 0x0a]);
+    const B: MacAddr = MacAddr([0x02, 0, 0, 0, 0, 0x0b]);
+    const C: MacAddr = MacAddr([0x02, 0, 0, 0, 0, 0x0c]);
+
+    /// Returns a table with A learned on port 1 and B on port 2
+    fn table() -> MacTable<u32> {
+        let mut table = MacTable::new();
+        learn(&mut table, A, 1);
+        learn(&mut table, B, 2);
+        table
+    }
+
+    /// Returns the ports a flood decision sends to, in order
+    fn flooded(decision: Decision<u32>) -> Vec<u32> {
+        let Decision::Flood(mut ports) = decision else {
+            panic!("expected a flood, got {:?}", decision);
+        };
+        ports.sort();
+        ports
+    }
+
+    #[test]
+    fn learning_reports_new_moved_and_known_macs() {
+        let mut table = MacTable::new();
+        assert_eq!(learn(&mut table, A, 1), Some(Learned::New));
+        assert_eq!(learn(&mut table, A, 1), None);
+        assert_eq!(learn(&mut table, A, 2), Some(Learned::Moved(1)));
+        assert_eq!(table.lookup(&A), Some(&2));
+    }
+
+    #[test]
+    fn known_destinations_are_unicast() {
+        let decision = decide(&table(), &[], &A, &B, false, |_| false);
+        assert_eq!(decision, Decision::Unicast(2));
+    }
+
+    #[test]
+    fn excluded_destinations_are_dropped() {
+        let decision = decide(&table(), &[], &A, &B, true, |port| *port == 2);
+        assert_eq!(decision, Decision::Excluded);
+    }
+
+    #[test]
+    fn unknown_destinations_are_dropped_unless_flooded() {
+        assert_eq!(
+            decide(&table(), &[], &A, &C, false, |_| false),
+            Decision::Unknown
+        );
+        assert_eq!(flooded(decide(&table(), &[], &A, &C, true, |_| false)), [2]);
+    }
+
+    #[test]
+    fn broadcasts_are_flooded_to_every_other_port() {
+        let mut table = table();
+        learn(&mut table, C, 3);
+        let decision = decide(&table, &[], &A, &MacAddr::BROADCAST, false, |port| {
+            *port == 3
+        });
+        assert_eq!(flooded(decision), [2]);
+    }
+
+    #[test]
+    fn peers_are_flooded_except_the_source() {
+        let decision = decide(&table(), &[1, 2, 4], &A, &MacAddr::BROADCAST, false, |_| {
+            false
+        });
+        assert_eq!(flooded(decision), [2, 4]);
+
+        /* Peers are only flooded, never sent unknown unicasts which aren't */
+        let decision = decide(&table(), &[4], &A, &C, false, |_| false);
+        assert_eq!(decision, Decision::Unknown);
+    }
+
+    #[test]
+    fn macs_age_out_unless_static_or_seen() {
+        let start = Instant::now();
+        let aging = Some(Duration::from_secs(10));
+        let static_macs = HashSet::from([B]);
+        let mut table = table();
+        learn(&mut table, C, 3);
+        let mut ages = MacAges::new(start);
+        ages.seen(A, start);
+        ages.seen(B, start);
+        ages.seen(C, start);
+
+        /* Nothing is checked until the sweep interval has passed */
+        let soon = start + AGING_SWEEP_INTERVAL / 2;
+        assert!(ages
+            .expire(&mut table, aging, &static_macs, soon)
+            .is_empty());
+
+        let later = start + Duration::from_secs(8);
+        ages.seen(C, later);
+        assert!(ages
+            .expire(&mut table, aging, &static_macs, later)
+            .is_empty());
+
+        let expired = ages.expire(
+            &mut table,
+            aging,
+            &static_macs,
+            start + Duration::from_secs(12),
+        );
+        assert_eq!(expired, [A]);
+        assert_eq!(table.lookup(&A), None);
+        assert_eq!(table.lookup(&B), Some(&2));
+        assert_eq!(table.lookup(&C), Some(&3));
+    }
+
+    #[test]
+    fn macs_never_age_out_without_aging() {
+        let start = Instant::now();
+        let mut table = table();
+        let mut ages = MacAges::new(start);
+        let expired = ages.expire(
+            &mut table,
+            None,
+            &HashSet::new(),
+            start + Duration::from_secs(3600),
+        );
+        assert!(expired.is_empty());
+        assert_eq!(table.len(), 2);
+    }
+
+    #[test]
+    fn frames_shorter_than_a_header_are_runts() {
+        assert!(is_runt(0));
+        assert!(is_runt(ETHER_HDR - 1));
+        assert!(!is_runt(ETHER_HDR));
+    }
+
+    #[test]
+    fn frames_larger_than_the_mtu_allows_are_oversize() {
+        assert!(!is_oversize(frame_max(1500), 1500));
+        assert!(is_oversize(frame_max(1500) + 1, 1500));
+    }
+
+    #[test]
+    fn only_vports_giving_another_mtu_are_refused() {
+        assert_eq!(mtu_mismatch(0, 1500), None);
+        assert_eq!(mtu_mismatch(1500, 1500), None);
+        assert_eq!(mtu_mismatch(9000, 1500), Some(9000));
+    }
+
+    /// Table kept as a list, to show that learning, forwarding and
+    /// aging work against any ForwardingTable, not just MacTable
+    #[derive(Default)]
+    struct ListTable(Vec<(MacAddr, u32)>);
+
+    impl ForwardingTable<u32> for ListTable {
+        fn learn(&mut self, mac: MacAddr, port: u32) -> Option<u32> {
+            match self.0.iter_mut().find(|(known, _)| *known == mac) {
+                Some((_, old_port)) => Some(std::mem::replace(old_port, port)),
+                None => {
+                    self.0.push((mac, port));
+                    None
+                }
+            }
+        }
+
+        fn lookup(&self, mac: &MacAddr) -> Option<&u32> {
+            self.0
+                .iter()
+                .find(|(known, _)| known == mac)
+                .map(|(_, port)| port)
+        }
+
+        fn flush(&mut self, matches: &mut dyn FnMut(&MacAddr, &u32) -> bool) -> Vec<MacAddr> {
+            let mut flushed = Vec::new();
+            self.0.retain(|(mac, port)| {
+                let flush = matches(mac, port);
+                if flush {
+                    flushed.push(*mac);
+                }
+                !flush
+            });
+            flushed
+        }
+
+        fn entries(&self) -> Box<dyn Iterator<Item = (&MacAddr, &u32)> + '_> {
+            Box::new(self.0.iter().map(|(mac, port)| (mac, port)))
+        }
+
+        fn age(&mut self, mac: &MacAddr) -> Option<u32> {
+            let index = self.0.iter().position(|(known, _)| known == mac)?;
+            Some(self.0.remove(index).1)
+        }
+    }
+
+    #[test]
+    fn other_tables_can_be_switched_against() {
+        let mut table = ListTable::default();
+        assert_eq!(learn(&mut table, A, 1), Some(Learned::New));
+        assert_eq!(learn(&mut table, B, 2), Some(Learned::New));
+        assert_eq!(learn(&mut table, B, 3), Some(Learned::Moved(2)));
+
+        assert_eq!(
+            decide(&table, &[], &A, &B, false, |_| false),
+            Decision::Unicast(3)
+        );
+        assert_eq!(
+            decide(&table, &[], &A, &C, false, |_| false),
+            Decision::Unknown
+        );
+        let decision = decide(&table, &[4], &A, &MacAddr::BROADCAST, false, |_| false);
+        assert_eq!(flooded(decision), [3, 4]);
+
+        let start = Instant::now();
+        let mut ages = MacAges::new(start);
+        ages.seen(A, start);
+        ages.seen(B, start + Duration::from_secs(5));
+        let expired = ages.expire(
+            &mut table,
+            Some(Duration::from_secs(10)),
+            &HashSet::new(),
+            start + Duration::from_secs(10),
+        );
+        assert_eq!(expired, [A]);
+        assert_eq!(table.flush(&mut |_, port| *port == 3), [B]);
+        assert!(table.0.is_empty());
+    }
+}
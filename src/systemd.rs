@@ -0,0 +1,166 @@
+//! Running as a systemd service
+//!
+//! systemd can open a service's sockets (or other files) itself, and
+//! pass them to the process it starts as file handles 3 onwards, which
+//! LISTEN_PID, LISTEN_FDS and LISTEN_FDNAMES describe. This is socket
+//! activation, which lets the vswitch be given its UDP socket, and the
+//! vport its tap interface. A service can also hand file handles back to
+//! systemd to keep (its file descriptor store), which passes them to the
+//! process again the same way when the service restarts
+//!
+//! A service of Type=notify tells systemd when it is ready, what it is
+//! doing and when it is stopping, in datagrams to the Unix socket named
+//! by NOTIFY_SOCKET, and one with WatchdogSec= has to send WATCHDOG=1
+//! more often than WATCHDOG_USEC, or it is restarted
+//!
+//! Nothing is done unless systemd set these variables, so the binaries
+//! behave the same outside systemd, or on platforms without it
+
+use crate::supervisor::Heartbeat;
+use std::{
+    env, io, process,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+#[cfg(unix)]
+use {
+    crate::{fdpass::set_cloexec, platform::socket},
+    nix::sys::socket::{sendmsg, AddressFamily, ControlMessage, MsgFlags, SockType, UnixAddr},
+    std::{
+        io::IoSlice,
+        os::{
+            fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+            unix::ffi::OsStrExt,
+        },
+    },
+};
+
+/// First file handle which systemd passes
+#[cfg(unix)]
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Take the file handles which systemd passed to the process, each with
+/// the name it was given (by FileDescriptorName= or FDNAME=), or
+/// "unknown". The variables describing them are removed, so they aren't
+/// taken twice or passed on to child processes, which means this should
+/// be called before any threads are started
+#[cfg(unix)]
+pub fn listen_fds() -> Vec<(String, OwnedFd)> {
+    let pid = env::var("LISTEN_PID").ok();
+    let count = env::var("LISTEN_FDS").ok();
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(var);
+    }
+
+    /* The variables may have been left by a parent which systemd started */
+    if pid.and_then(|pid| pid.parse::<u32>().ok()) != Some(process::id()) {
+        return Vec::new();
+    }
+    let Some(count) = count.and_then(|count| count.parse::<RawFd>().ok()) else {
+        return Vec::new();
+    };
+
+    let mut names = names.split(':');
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|raw_fd| {
+            let fd = unsafe { OwnedFd::from_raw_fd(raw_fd) };
+            let _ = set_cloexec(&fd);
+            let name = names.next().filter(|name| !name.is_empty());
+            (name.unwrap_or("unknown").to_string(), fd)
+        })
+        .collect()
+}
+
+/// Send systemd state, such as "READY=1", or several assignments on
+/// lines of their own
+///
+/// Returns whether systemd is listening, i.e. NOTIFY_SOCKET is set
+#[cfg(unix)]
+pub fn notify(state: &str) -> io::Result<bool> {
+    notify_with_fds(state, &[])
+}
+
+/// Send systemd state, as notify does, which systemd never listens for
+/// on Windows
+#[cfg(windows)]
+pub fn notify(_state: &str) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Send systemd state, along with fds, which it keeps in the service's
+/// file descriptor store if state holds FDSTORE=1 (and FDNAME=<name>)
+///
+/// Returns whether systemd is listening, i.e. NOTIFY_SOCKET is set
+#[cfg(unix)]
+pub fn notify_with_fds(state: &str, fds: &[BorrowedFd]) -> io::Result<bool> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+    let addr = match path.as_bytes() {
+        [b'@', name @ ..] => abstract_addr(name)?,
+        path => UnixAddr::new(path)?,
+    };
+
+    let sock = socket(AddressFamily::Unix, SockType::Datagram, None)?;
+    let raw_fds: Vec<RawFd> = fds.iter().map(|fd| fd.as_raw_fd()).collect();
+    let cmsgs = match raw_fds.is_empty() {
+        true => Vec::new(),
+        false => vec![ControlMessage::ScmRights(&raw_fds)],
+    };
+    sendmsg(
+        sock.as_raw_fd(),
+        &[IoSlice::new(state.as_bytes())],
+        &cmsgs,
+        MsgFlags::empty(),
+        Some(&addr),
+    )?;
+    Ok(true)
+}
+
+/// Returns the address of the abstract Unix socket called name
+#[cfg(target_os = "linux")]
+fn abstract_addr(name: &[u8]) -> io::Result<UnixAddr> {
+    Ok(UnixAddr::new_abstract(name)?)
+}
+
+/// Returns the address of the abstract Unix socket called name, which
+/// only Linux has
+#[cfg(all(unix, not(target_os = "linux")))]
+fn abstract_addr(_name: &[u8]) -> io::Result<UnixAddr> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Returns how often systemd expects WATCHDOG=1 from the process, or
+/// None if it isn't watching it
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    match env::var("WATCHDOG_PID").map(|pid| pid.parse::<u32>()) {
+        Ok(Ok(pid)) if pid != process::id() => None,
+        Ok(Err(_)) => None,
+        _ => Some(Duration::from_micros(usec)).filter(|interval| !interval.is_zero()),
+    }
+}
+
+/// Start a thread which sends systemd WATCHDOG=1 twice in every interval
+/// it expects one in, for as long as none of loops has been busy with the
+/// same thing for the whole interval, so that systemd restarts the process
+/// if one of them gets stuck
+///
+/// Returns None, without starting the thread, if systemd isn't watching
+/// the process
+pub fn watchdog(loops: Vec<Heartbeat>) -> Option<JoinHandle<()>> {
+    let interval = watchdog_interval()?;
+    Some(thread::spawn(move || loop {
+        let now = Instant::now();
+        let stuck = loops
+            .iter()
+            .any(|heartbeat| heartbeat.busy_for(now).is_some_and(|d| d >= interval));
+        if !stuck {
+            if let Err(e) = notify("WATCHDOG=1") {
+                eprintln!("Got error while pinging the systemd watchdog: '{}'", e);
+            }
+        }
+        thread::sleep(interval / 2);
+    }))
+}
@@ -0,0 +1,231 @@
+//! Creating and configuring tap interfaces
+//!
+//! A tap interface hands the host's Ethernet frames to whoever holds
+//! its device open, which is how frames enter and leave the L2VPN
+//! network at a vport
+//!
+//! A tap interface's name can hold a "%d", which is replaced with the
+//! lowest number which makes it unique, so several vports on one host
+//! can each create their own without being given names
+//!
+//! A vport in L3 mode uses a tun interface instead, which hands it the
+//! host's IP packets, without Ethernet headers (see l2vpn::tun)
+//!
+//! Each platform creates them with its own driver, which is hidden
+//! behind the VirtualNic trait, so the vport reads, writes and configures
+//! them the same way everywhere. On Linux, /dev/net/tun creates both,
+//! and persistent ones can be handed to unprivileged vports. On macOS,
+//! which has no tap interfaces of its own, tap interfaces are the
+//! /dev/tapN devices of the tuntaposx driver, and tun interfaces are the
+//! kernel's utun interfaces, whose names it picks. On Windows, tun
+//! interfaces are wintun adapters, and there are no tap interfaces
+//!
+//! On Linux and macOS, addresses are given to interfaces, and they are
+//! brought up, with the same ioctls as ifconfig uses, so no netlink or
+//! routing socket is needed
+
+use crate::{error::TapError, mac::MacAddr};
+use std::{
+    fmt, io,
+    net::{IpAddr, Ipv4Addr},
+    time::Duration,
+};
+#[cfg(unix)]
+use {
+    crate::platform::socket,
+    nix::{
+        ifaddrs::getifaddrs,
+        libc::{ifreq, sockaddr, sockaddr_in},
+        poll::{poll, PollFd, PollFlags, PollTimeout},
+        sys::socket::{AddressFamily, SockType, SockaddrIn},
+    },
+    std::{
+        ffi::c_char,
+        fs::File,
+        net::SocketAddrV4,
+        os::fd::{AsFd, BorrowedFd, OwnedFd},
+    },
+};
+
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(target_os = "linux")]
+pub use linux::*;
+
+#[cfg(target_os = "macos")]
+mod macos;
+#[cfg(target_os = "macos")]
+pub use macos::*;
+
+#[cfg(windows)]
+mod windows;
+#[cfg(windows)]
+pub use windows::*;
+
+/// Longest interface name, which has to fit in IFNAMSIZ bytes along with its NUL
+const IFNAMSIZ: usize = 16;
+
+/// A tap (or tun) interface, or whatever stands in for one, which the
+/// host's frames (or IP packets) are read from and written to. Each
+/// platform's driver implements it, and what a driver can't do is
+/// refused with TapError::Unsupported
+pub trait VirtualNic: fmt::Debug + Send + Sync {
+    /// Returns the interface's name
+    fn name(&self) -> &str;
+
+    /// Read the next frame (or IP packet) which the host sends into buf,
+    /// returning its length
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Write frame (or IP packet) to the interface, returning how many
+    /// of its bytes were written
+    fn write(&self, frame: &[u8]) -> io::Result<usize>;
+
+    /// Returns true if the interface has something to read within wait
+    fn readable(&self, wait: Duration) -> io::Result<bool>;
+
+    /// Returns the interface's IPv4 addresses
+    fn ipv4_addrs(&self) -> Result<Vec<Ipv4Addr>, TapError>;
+
+    /// Set the interface's MAC, which it allows while it is up
+    fn set_mac(&self, _mac: &MacAddr) -> Result<(), TapError> {
+        Err(TapError::Unsupported {
+            op: "Setting tap MAC",
+        })
+    }
+
+    /// Set the interface's MTU
+    fn set_mtu(&self, _mtu: usize) -> Result<(), TapError> {
+        Err(TapError::Unsupported {
+            op: "Setting tap MTU",
+        })
+    }
+
+    /// Give the interface addr with a prefix of prefix_len bits
+    fn add_addr(&self, _addr: IpAddr, _prefix_len: u8) -> Result<(), TapError> {
+        Err(TapError::Unsupported {
+            op: "Setting tap address",
+        })
+    }
+
+    /// Bring the interface up, or down if not up
+    fn set_up(&self, _up: bool) -> Result<(), TapError> {
+        Err(TapError::Unsupported {
+            op: "Setting tap flags",
+        })
+    }
+
+    /// Make the interface persistent, so it is kept once the vport
+    /// closes it, or not, so it is removed then
+    fn set_persist(&self, _persist: bool) -> Result<(), TapError> {
+        Err(TapError::Unsupported {
+            op: "Setting tap persistence",
+        })
+    }
+
+    /// Let the user with uid attach to the interface
+    fn set_owner(&self, _uid: u32) -> Result<(), TapError> {
+        Err(TapError::Unsupported {
+            op: "Setting tap owner",
+        })
+    }
+
+    /// Let the members of the group with gid attach to the interface
+    fn set_group(&self, _gid: u32) -> Result<(), TapError> {
+        Err(TapError::Unsupported {
+            op: "Setting tap group",
+        })
+    }
+
+    /// Returns the file handle which the interface is read and written
+    /// through, if it has one, so it can be handed to another process
+    #[cfg(unix)]
+    fn fd(&self) -> Option<BorrowedFd<'_>> {
+        None
+    }
+}
+
+/// Check that name can be given to a tap interface, as the kernel
+/// would, so a bad name is reported before anything is created
+pub fn check_name(name: &str) -> Result<(), TapError> {
+    let invalid = |reason| {
+        Err(TapError::InvalidName {
+            name: name.to_string(),
+            reason,
+        })
+    };
+
+    /*
+     * The Linux/C network stack expects ASCII interface names, and if
+     * the interface name is ASCII encoded, then every UTF-8 char is 1 byte,
+     * so we do not need to worry about the distinction after this.
+     */
+    if !name.is_ascii() {
+        return invalid("not valid ASCII");
+    }
+
+    /* The name has to fit in IFNAMSIZ bytes along with its NUL */
+    if name.len() >= IFNAMSIZ {
+        return invalid("longer than IFNAMSIZ(16) - 1");
+    }
+    if name.is_empty() || name == "." || name == ".." {
+        return invalid("not a name");
+    }
+    if name.contains(['/', ':']) || name.contains(|c: char| c.is_ascii_whitespace()) {
+        return invalid("holding '/', ':' or whitespace");
+    }
+
+    /* The kernel only numbers names with a single %d */
+    match name.matches('%').count() {
+        0 => check_platform_name(name),
+        1 if name.contains("%d") => check_platform_name(name),
+        _ => invalid("holding a '%' other than a single %d"),
+    }
+}
+
+/// Returns the IPv4 addresses of the interface called name
+#[cfg(unix)]
+pub(crate) fn ipv4_addrs(name: &str) -> Result<Vec<Ipv4Addr>, TapError> {
+    let addrs = getifaddrs().map_err(|source| TapError::Ioctl {
+        op: "Getting interface addresses",
+        source,
+    })?;
+    Ok(addrs
+        .filter(|addr| addr.interface_name == name)
+        .filter_map(|addr| Some(addr.address?.as_sockaddr_in()?.ip()))
+        .collect())
+}
+
+/// Returns true if file has something to read within wait
+#[cfg(unix)]
+pub(crate) fn poll_readable(file: &File, wait: Duration) -> io::Result<bool> {
+    let millis = wait.as_micros().div_ceil(1000);
+    let timeout = PollTimeout::from(u16::try_from(millis).unwrap_or(u16::MAX));
+    let mut fds = [PollFd::new(file.as_fd(), PollFlags::POLLIN)];
+    Ok(poll(&mut fds, timeout)? > 0)
+}
+
+/// Returns an ifreq struct naming the interface called name, which is
+/// cut short to fit, as the name of an interface which exists would
+#[cfg(unix)]
+fn named_ifreq(name: &str) -> ifreq {
+    let mut ifr: ifreq = unsafe { std::mem::zeroed() };
+    for (i, b) in name.bytes().take(IFNAMSIZ - 1).enumerate() {
+        ifr.ifr_name[i] = b as c_char;
+    }
+    ifr
+}
+
+/// Returns a sockaddr holding addr, as ioctls which set IPv4 addresses take
+#[cfg(unix)]
+fn ipv4_sockaddr(addr: Ipv4Addr) -> sockaddr {
+    let sin: sockaddr_in = *SockaddrIn::from(SocketAddrV4::new(addr, 0)).as_ref();
+    unsafe { std::mem::transmute::<sockaddr_in, sockaddr>(sin) }
+}
+
+/// Returns a socket of family which interfaces can be configured
+/// through with ioctls, where op says what it is opened for
+#[cfg(unix)]
+fn ioctl_socket(family: AddressFamily, op: &'static str) -> Result<OwnedFd, TapError> {
+    socket(family, SockType::Datagram, None).map_err(|source| TapError::Ioctl { op, source })
+}
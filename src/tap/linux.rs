@@ -0,0 +1,399 @@
+//! Creating and configuring tap interfaces on Linux
+//!
+//! Tap and tun interfaces are both created through /dev/net/tun, whose
+//! file handle is attached to the interface, and a "%d" in their names
+//! is replaced by the kernel
+//!
+//! An interface is removed once its last file handle is closed, unless
+//! it is made persistent. A persistent interface given an owner or group
+//! can be created by root, and attached to later by a vport running as
+//! that user or group, without CAP_NET_ADMIN
+//!
+//! Addresses are given to interfaces, and they are brought up, with the
+//! same ioctls as ifconfig uses. The kernel only keeps one IPv4 address
+//! per interface this way, but any number of IPv6
+
+use super::{
+    check_name, ioctl_socket, ipv4_addrs, ipv4_sockaddr, named_ifreq, poll_readable, VirtualNic,
+};
+use crate::{error::TapError, mac::MacAddr};
+use nix::{
+    errno::Errno,
+    ioctl_read, ioctl_read_bad, ioctl_write_int, ioctl_write_ptr, ioctl_write_ptr_bad,
+    libc::{
+        ifreq, in6_addr, in6_ifreq, sockaddr, ARPHRD_ETHER, IFF_NO_PI, IFF_TAP, IFF_TUN, IFF_UP,
+        SIOCGIFFLAGS, SIOCSIFADDR, SIOCSIFFLAGS, SIOCSIFHWADDR, SIOCSIFMTU, SIOCSIFNETMASK,
+    },
+    net::if_::if_nametoindex,
+    sys::socket::AddressFamily,
+};
+use std::{
+    ffi::{c_char, c_int, c_uint, CStr},
+    fs::File,
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr},
+    os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd},
+    time::Duration,
+};
+
+/// Names of the tap and tun interfaces which are created when
+/// vports aren't given one
+pub const DEFAULT_TAP_NAME: &str = "tap0";
+pub const DEFAULT_TUN_NAME: &str = "tun0";
+
+/*
+ * These constants are defined in linux/if_tun.h
+ * and ioctl uses them to identify that an operation
+ * should affect the tuntap driver, and that it should
+ * be setting interface flags, persistence, owner or
+ * group, or getting the interface, respectively
+ */
+const TUNTAP_DRIVER: u8 = b'T';
+const TUNTAP_SET_FLAGS: u8 = 202;
+const TUNTAP_SET_PERSIST: u8 = 203;
+const TUNTAP_SET_OWNER: u8 = 204;
+const TUNTAP_SET_GROUP: u8 = 206;
+const TUNTAP_GET_IFF: u8 = 210;
+
+/*
+ * This macro generates a function called tunsetiff
+ * which is a wrapper around the ioctl call which points
+ * /dev/net/tun to the device specified in the ifreq struct,
+ * and configures it with the flags set in the ifreq struct
+ */
+ioctl_write_ptr!(tunsetiff, TUNTAP_DRIVER, TUNTAP_SET_FLAGS, c_int);
+
+/*
+ * These macros generate functions called tunsetpersist, tunsetowner
+ * and tunsetgroup, which take their value as the ioctl's argument,
+ * rather than a pointer to it, and affect the interface which
+ * /dev/net/tun points to
+ */
+ioctl_write_int!(tunsetpersist, TUNTAP_DRIVER, TUNTAP_SET_PERSIST);
+ioctl_write_int!(tunsetowner, TUNTAP_DRIVER, TUNTAP_SET_OWNER);
+ioctl_write_int!(tunsetgroup, TUNTAP_DRIVER, TUNTAP_SET_GROUP);
+
+/*
+ * This macro generates a function called tungetiff, which fills in the
+ * ifreq struct with the name and flags of the interface which
+ * /dev/net/tun points to
+ */
+ioctl_read!(tungetiff, TUNTAP_DRIVER, TUNTAP_GET_IFF, c_uint);
+
+/*
+ * This macro generates a function called set_hw_addr which sets the
+ * MAC of the tap interface named in the ifreq struct, through a socket
+ */
+ioctl_write_ptr_bad!(set_hw_addr, SIOCSIFHWADDR, ifreq);
+
+/*
+ * This macro generates a function called set_if_mtu which sets the
+ * MTU of the interface named in the ifreq struct, through a socket
+ */
+ioctl_write_ptr_bad!(set_if_mtu, SIOCSIFMTU, ifreq);
+
+/*
+ * These macros generate functions called get_if_flags and set_if_flags,
+ * which get and set the flags (e.g. IFF_UP) of the interface named in
+ * the ifreq struct, and set_if_addr and set_if_netmask, which set its
+ * IPv4 address and netmask, through a socket like set_if_mtu. For IPv6,
+ * set_if_addr6 adds an address to the interface with the index given
+ * in the in6_ifreq struct, through an IPv6 socket
+ */
+ioctl_read_bad!(get_if_flags, SIOCGIFFLAGS, ifreq);
+ioctl_write_ptr_bad!(set_if_flags, SIOCSIFFLAGS, ifreq);
+ioctl_write_ptr_bad!(set_if_addr, SIOCSIFADDR, ifreq);
+ioctl_write_ptr_bad!(set_if_netmask, SIOCSIFNETMASK, ifreq);
+ioctl_write_ptr_bad!(set_if_addr6, SIOCSIFADDR, in6_ifreq);
+
+/// Tap or tun interface which a /dev/net/tun file handle is attached to.
+/// Frames (or IP packets) are read from and written to it without any
+/// extra headers
+#[derive(Debug)]
+pub struct TunTap {
+    file: File,
+    name: String,
+}
+
+impl VirtualNic for TunTap {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        (&self.file).read(buf)
+    }
+
+    fn write(&self, frame: &[u8]) -> io::Result<usize> {
+        (&self.file).write(frame)
+    }
+
+    fn readable(&self, wait: Duration) -> io::Result<bool> {
+        poll_readable(&self.file, wait)
+    }
+
+    fn ipv4_addrs(&self) -> Result<Vec<Ipv4Addr>, TapError> {
+        ipv4_addrs(&self.name)
+    }
+
+    fn set_mac(&self, mac: &MacAddr) -> Result<(), TapError> {
+        set_mac(&self.name, mac)
+    }
+
+    fn set_mtu(&self, mtu: usize) -> Result<(), TapError> {
+        set_mtu(&self.name, mtu)
+    }
+
+    fn add_addr(&self, addr: IpAddr, prefix_len: u8) -> Result<(), TapError> {
+        add_addr(&self.name, addr, prefix_len)
+    }
+
+    fn set_up(&self, up: bool) -> Result<(), TapError> {
+        set_up(&self.name, up)
+    }
+
+    fn set_persist(&self, persist: bool) -> Result<(), TapError> {
+        tuntap_ioctl(
+            &self.file,
+            tunsetpersist,
+            persist.into(),
+            "Setting tap persistence",
+        )
+    }
+
+    fn set_owner(&self, uid: u32) -> Result<(), TapError> {
+        tuntap_ioctl(&self.file, tunsetowner, uid.into(), "Setting tap owner")
+    }
+
+    fn set_group(&self, gid: u32) -> Result<(), TapError> {
+        tuntap_ioctl(&self.file, tunsetgroup, gid.into(), "Setting tap group")
+    }
+
+    fn fd(&self) -> Option<BorrowedFd<'_>> {
+        Some(self.file.as_fd())
+    }
+}
+
+/// Check that name can be given to an interface on Linux, which takes
+/// any name which got through check_name
+pub(super) fn check_platform_name(_name: &str) -> Result<(), TapError> {
+    Ok(())
+}
+
+/// Create the tap interface called name, or attach to it if it exists,
+/// where a "%d" in name is replaced by the kernel to make it unique
+///
+/// Returns the interface, whose name is that which the kernel gave it
+pub fn create(name: &str) -> Result<Box<dyn VirtualNic>, TapError> {
+    Ok(Box::new(attach(name, IFF_TAP)?))
+}
+
+/// Create the tun interface called name, or attach to it if it exists,
+/// as create does for tap interfaces
+///
+/// Returns the interface, whose name is that which the kernel gave it
+pub fn create_tun(name: &str) -> Result<Box<dyn VirtualNic>, TapError> {
+    Ok(Box::new(attach(name, IFF_TUN)?))
+}
+
+/// Returns the tap (or tun) interface called name, which fd was attached
+/// to by another process, and handed over (see l2vpn::fdpass)
+pub fn from_fd(fd: OwnedFd, name: String, _l3: bool) -> Box<dyn VirtualNic> {
+    Box::new(TunTap {
+        file: File::from(fd),
+        name,
+    })
+}
+
+/// Returns the interface which fd, a /dev/net/tun file handle another
+/// process attached (e.g. systemd, from its file descriptor store), points
+/// to, along with whether it is a tun interface rather than a tap interface
+pub fn adopt(fd: OwnedFd) -> Result<(Box<dyn VirtualNic>, bool), TapError> {
+    let mut ifr: ifreq = unsafe { std::mem::zeroed() };
+    unsafe { tungetiff(fd.as_raw_fd(), &mut ifr as *mut _ as *mut c_uint) }.map_err(|source| {
+        TapError::Ioctl {
+            op: "tungetiff",
+            source,
+        }
+    })?;
+
+    let name = unsafe { CStr::from_ptr(ifr.ifr_name.as_ptr()) }
+        .to_string_lossy()
+        .into_owned();
+    let tun = unsafe { ifr.ifr_ifru.ifru_flags } & IFF_TUN as i16 != 0;
+    let tap = TunTap {
+        file: File::from(fd),
+        name,
+    };
+    Ok((Box::new(tap), tun))
+}
+
+/// Create the interface called name, or attach to it if it exists,
+/// as a tap or tun interface according to mode (IFF_TAP or IFF_TUN)
+fn attach(name: &str, mode: c_int) -> Result<TunTap, TapError> {
+    check_name(name)?;
+
+    /* Open the /dev/net/tun file which is the interface to the tun/tap driver */
+    let tap_file = File::options()
+        .read(true)
+        .write(true)
+        .open("/dev/net/tun")
+        .map_err(|source| TapError::Open {
+            path: "/dev/net/tun",
+            source,
+        })?;
+
+    /*
+     * Initialise the ifreq struct which indicates the
+     * interface we are going to use, and specifies
+     * the mode and IFF_NO_PI flags which indicate we want
+     * to configure it as an L2 tap (or L3 tun) interface, and that we
+     * want it to handle raw data without any extra headers
+     */
+    let mut ifr: ifreq = unsafe { std::mem::zeroed() };
+    ifr.ifr_ifru.ifru_flags = (mode | IFF_NO_PI) as i16;
+    for (i, b) in name.bytes().enumerate() {
+        ifr.ifr_name[i] = b as c_char;
+    }
+
+    /*
+     * Perform the ioctl call to configure the tap interface. It is busy
+     * if another process (e.g. another vport) is already attached to it
+     */
+    unsafe { tunsetiff(tap_file.as_raw_fd(), &mut ifr as *mut _ as *const c_int) }.map_err(
+        |source| match source {
+            Errno::EBUSY => TapError::InUse {
+                name: name.to_string(),
+            },
+            _ => TapError::Ioctl {
+                op: "tunsetiff",
+                source,
+            },
+        },
+    )?;
+
+    /* The kernel writes the name it gave the interface back into the ifreq struct */
+    let name = unsafe { CStr::from_ptr(ifr.ifr_name.as_ptr()) }
+        .to_string_lossy()
+        .into_owned();
+
+    Ok(TunTap {
+        file: tap_file,
+        name,
+    })
+}
+
+/// Set the MAC of the tap interface called name, which must exist
+///
+/// tap interfaces allow their MAC to be changed while they are up
+fn set_mac(name: &str, mac: &MacAddr) -> Result<(), TapError> {
+    let socket = ioctl_socket(AddressFamily::Inet, "Opening socket to set tap MAC")?;
+
+    let mut hwaddr: sockaddr = unsafe { std::mem::zeroed() };
+    hwaddr.sa_family = ARPHRD_ETHER;
+    for (i, b) in mac.0.iter().enumerate() {
+        hwaddr.sa_data[i] = *b as c_char;
+    }
+
+    let mut ifr = named_ifreq(name);
+    ifr.ifr_ifru.ifru_hwaddr = hwaddr;
+
+    unsafe { set_hw_addr(socket.as_raw_fd(), &ifr) }.map_err(|source| TapError::Ioctl {
+        op: "Setting tap MAC",
+        source,
+    })?;
+
+    Ok(())
+}
+
+/// Call ioctl, one of tunsetpersist, tunsetowner or tunsetgroup, with
+/// value on the interface which tap_file points to, where op says what
+/// it does
+fn tuntap_ioctl(
+    tap_file: &File,
+    ioctl: unsafe fn(c_int, nix::sys::ioctl::ioctl_param_type) -> nix::Result<c_int>,
+    value: nix::sys::ioctl::ioctl_param_type,
+    op: &'static str,
+) -> Result<(), TapError> {
+    unsafe { ioctl(tap_file.as_raw_fd(), value) }
+        .map_err(|source| TapError::Ioctl { op, source })?;
+    Ok(())
+}
+
+/// Set the MTU of the tap interface called name, which must exist
+fn set_mtu(name: &str, mtu: usize) -> Result<(), TapError> {
+    let socket = ioctl_socket(AddressFamily::Inet, "Opening socket to set tap MTU")?;
+
+    let mut ifr = named_ifreq(name);
+    ifr.ifr_ifru.ifru_mtu = mtu as c_int;
+
+    unsafe { set_if_mtu(socket.as_raw_fd(), &ifr) }.map_err(|source| TapError::Ioctl {
+        op: "Setting tap MTU",
+        source,
+    })?;
+
+    Ok(())
+}
+
+/// Bring the interface called name, which must exist, up, or down if not up
+fn set_up(name: &str, up: bool) -> Result<(), TapError> {
+    let socket = ioctl_socket(AddressFamily::Inet, "Opening socket to bring tap up")?;
+
+    /* The other flags are kept as they are */
+    let mut ifr = named_ifreq(name);
+    unsafe { get_if_flags(socket.as_raw_fd(), &mut ifr) }.map_err(|source| TapError::Ioctl {
+        op: "Getting tap flags",
+        source,
+    })?;
+    let flags = unsafe { ifr.ifr_ifru.ifru_flags };
+    ifr.ifr_ifru.ifru_flags = match up {
+        true => flags | IFF_UP as i16,
+        false => flags & !(IFF_UP as i16),
+    };
+
+    unsafe { set_if_flags(socket.as_raw_fd(), &ifr) }.map_err(|source| TapError::Ioctl {
+        op: "Setting tap flags",
+        source,
+    })?;
+
+    Ok(())
+}
+
+/// Give the interface called name, which must exist, addr with a prefix
+/// of prefix_len bits. An IPv4 address replaces the one it has, if any,
+/// and an IPv6 address is added to the ones it has
+fn add_addr(name: &str, addr: IpAddr, prefix_len: u8) -> Result<(), TapError> {
+    let ioctl_err = |op| move |source| TapError::Ioctl { op, source };
+    match addr {
+        IpAddr::V4(addr) => {
+            let socket = ioctl_socket(AddressFamily::Inet, "Opening socket to set tap address")?;
+            let netmask = u32::MAX
+                .checked_shl(32 - u32::from(prefix_len.min(32)))
+                .unwrap_or(0);
+
+            let mut ifr = named_ifreq(name);
+            ifr.ifr_ifru.ifru_addr = ipv4_sockaddr(addr);
+            unsafe { set_if_addr(socket.as_raw_fd(), &ifr) }
+                .map_err(ioctl_err("Setting tap address"))?;
+            ifr.ifr_ifru.ifru_netmask = ipv4_sockaddr(Ipv4Addr::from(netmask));
+            unsafe { set_if_netmask(socket.as_raw_fd(), &ifr) }
+                .map_err(ioctl_err("Setting tap netmask"))?;
+        }
+        IpAddr::V6(addr) => {
+            let socket = ioctl_socket(AddressFamily::Inet6, "Opening socket to set tap address")?;
+            let index = if_nametoindex(name).map_err(ioctl_err("Getting tap index"))?;
+
+            let ifr6 = in6_ifreq {
+                ifr6_addr: in6_addr {
+                    s6_addr: addr.octets(),
+                },
+                ifr6_prefixlen: u32::from(prefix_len),
+                ifr6_ifindex: index as c_int,
+            };
+            unsafe { set_if_addr6(socket.as_raw_fd(), &ifr6) }
+                .map_err(ioctl_err("Adding tap address"))?;
+        }
+    }
+
+    Ok(())
+}
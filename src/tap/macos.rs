@@ -0,0 +1,424 @@
+//! Creating and configuring tap interfaces on macOS
+//!
+//! macOS has no tap interfaces of its own, so they are the /dev/tapN
+//! devices of the tuntaposx driver, which has to be installed, and which
+//! creates the interface tapN when its device is opened. Only names of
+//! the form tapN are taken, and tap%d is given the first device which
+//! isn't open already
+//!
+//! tun interfaces are the kernel's utun interfaces, which are created
+//! by connecting a system control socket, and removed once it is closed.
+//! Only names of the form utunN are taken, and utun%d has the kernel
+//! pick the number. Each packet read from or written to a utun socket
+//! follows the 4 byte protocol family it belongs to, which is taken off
+//! and put on as they are read and written
+//!
+//! Neither can be made persistent or given an owner or group, and utun
+//! interfaces are point-to-point, so hosts reached through them need a
+//! route to them (e.g. "route add -net 10.9.0.0/24 -interface utun3")
+
+use super::{
+    check_name, ioctl_socket, ipv4_addrs, ipv4_sockaddr, named_ifreq, poll_readable, VirtualNic,
+};
+use crate::{error::TapError, mac::MacAddr, platform::socket};
+use nix::{
+    errno::Errno,
+    ioctl_readwrite, ioctl_write_ptr,
+    libc::{
+        c_char, c_int, ifreq, in6_addr, in6_addrlifetime, sockaddr, sockaddr_in6, AF_INET, AF_LINK,
+        IFF_UP, IFNAMSIZ,
+    },
+    sys::{
+        socket::{
+            connect, getsockopt, sockopt, AddressFamily, SockProtocol, SockType, SysControlAddr,
+        },
+        uio::{readv, writev},
+    },
+};
+use std::{
+    fs::File,
+    io::{self, IoSlice, IoSliceMut, Read, Write},
+    mem,
+    net::{IpAddr, Ipv4Addr},
+    os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd},
+    time::Duration,
+};
+
+/// Names of the tap and tun interfaces which are created when
+/// vports aren't given one
+pub const DEFAULT_TAP_NAME: &str = "tap0";
+pub const DEFAULT_TUN_NAME: &str = "utun%d";
+
+/// Most /dev/tapN devices which tuntaposx creates
+const TAP_DEVICES: u32 = 16;
+
+/// Name of the kernel control which utun interfaces are created through
+const UTUN_CONTROL_NAME: &str = "com.apple.net.utun_control";
+
+/// Length of the protocol family in front of each packet on a utun socket
+const UTUN_HDR_LEN: usize = 4;
+
+/// Lifetime of addresses which never expire, from netinet6/nd6.h
+const ND6_INFINITE_LIFETIME: u32 = u32::MAX;
+
+/*
+ * The ifaliasreq and in6_aliasreq structs from net/if.h and
+ * netinet6/in6_var.h, which libc doesn't have for macOS, and which
+ * SIOCAIFADDR and SIOCAIFADDR_IN6 take an interface's new address in
+ */
+#[repr(C)]
+struct ifaliasreq {
+    ifra_name: [c_char; IFNAMSIZ],
+    ifra_addr: sockaddr,
+    ifra_broadaddr: sockaddr,
+    ifra_mask: sockaddr,
+}
+
+#[repr(C)]
+struct in6_aliasreq {
+    ifra_name: [c_char; IFNAMSIZ],
+    ifra_addr: sockaddr_in6,
+    ifra_dstaddr: sockaddr_in6,
+    ifra_prefixmask: sockaddr_in6,
+    ifra_flags: c_int,
+    ifra_lifetime: in6_addrlifetime,
+}
+
+/*
+ * These macros generate functions wrapping the ioctls from sys/sockio.h
+ * which libc doesn't have for macOS. get_if_flags and set_if_flags get
+ * and set the flags (e.g. IFF_UP) of the interface named in the ifreq
+ * struct, set_if_mtu and set_if_lladdr set its MTU and MAC, and
+ * add_if_addr and add_if_addr6 give it an IPv4 or IPv6 address, all
+ * through a socket
+ */
+ioctl_readwrite!(get_if_flags, b'i', 17, ifreq);
+ioctl_write_ptr!(set_if_flags, b'i', 16, ifreq);
+ioctl_write_ptr!(set_if_mtu, b'i', 52, ifreq);
+ioctl_write_ptr!(set_if_lladdr, b'i', 60, ifreq);
+ioctl_write_ptr!(add_if_addr, b'i', 26, ifaliasreq);
+ioctl_write_ptr!(add_if_addr6, b'i', 26, in6_aliasreq);
+
+/// Tap interface which a tuntaposx device is open for, or utun interface
+/// which a utun socket is connected to. Frames are read from and written
+/// to a tap interface without any extra headers, and IP packets to a
+/// utun interface after their protocol family. Neither can be made
+/// persistent or given an owner or group
+#[derive(Debug)]
+pub struct TunTap {
+    file: File,
+    name: String,
+    /* Whether it is a utun interface, whose packets follow their protocol family */
+    utun: bool,
+}
+
+impl VirtualNic for TunTap {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.utun {
+            return (&self.file).read(buf);
+        }
+        let mut family = [0u8; UTUN_HDR_LEN];
+        let len = readv(
+            self.file.as_fd(),
+            &mut [IoSliceMut::new(&mut family), IoSliceMut::new(buf)],
+        )?;
+        Ok(len.saturating_sub(UTUN_HDR_LEN))
+    }
+
+    fn write(&self, frame: &[u8]) -> io::Result<usize> {
+        if !self.utun {
+            return (&self.file).write(frame);
+        }
+        /* Only IPv4 packets are written in L3 mode */
+        let family = (AF_INET as u32).to_be_bytes();
+        let len = writev(
+            self.file.as_fd(),
+            &[IoSlice::new(&family), IoSlice::new(frame)],
+        )?;
+        Ok(len.saturating_sub(UTUN_HDR_LEN))
+    }
+
+    fn readable(&self, wait: Duration) -> io::Result<bool> {
+        poll_readable(&self.file, wait)
+    }
+
+    fn ipv4_addrs(&self) -> Result<Vec<Ipv4Addr>, TapError> {
+        ipv4_addrs(&self.name)
+    }
+
+    fn set_mac(&self, mac: &MacAddr) -> Result<(), TapError> {
+        set_mac(&self.name, mac)
+    }
+
+    fn set_mtu(&self, mtu: usize) -> Result<(), TapError> {
+        set_mtu(&self.name, mtu)
+    }
+
+    fn add_addr(&self, addr: IpAddr, prefix_len: u8) -> Result<(), TapError> {
+        add_addr(&self.name, self.utun, addr, prefix_len)
+    }
+
+    fn set_up(&self, up: bool) -> Result<(), TapError> {
+        set_up(&self.name, up)
+    }
+
+    fn fd(&self) -> Option<BorrowedFd<'_>> {
+        Some(self.file.as_fd())
+    }
+}
+
+/// Check that name can be given to an interface on macOS, which only
+/// takes names of the tapN or utunN devices, or those with %d for N
+pub(super) fn check_platform_name(name: &str) -> Result<(), TapError> {
+    match ["tap", "utun"]
+        .iter()
+        .any(|prefix| unit(name, prefix).is_some())
+    {
+        true => Ok(()),
+        false => Err(TapError::InvalidName {
+            name: name.to_string(),
+            reason: "not of the form tapN or utunN on macOS",
+        }),
+    }
+}
+
+/// Returns the number of the interface called name, or None for %d,
+/// if it is prefix followed by either, or nothing otherwise
+fn unit(name: &str, prefix: &str) -> Option<Option<u32>> {
+    match name.strip_prefix(prefix)? {
+        "%d" => Some(None),
+        n if n.bytes().all(|b| b.is_ascii_digit()) => Some(Some(n.parse().ok()?)),
+        _ => None,
+    }
+}
+
+/// Returns the tap interface (or utun interface, if l3) called name,
+/// which fd was opened for by another process, and handed over (see
+/// l2vpn::fdpass)
+pub fn from_fd(fd: OwnedFd, name: String, l3: bool) -> Box<dyn VirtualNic> {
+    Box::new(TunTap {
+        file: File::from(fd),
+        name,
+        utun: l3,
+    })
+}
+
+/// Returns the interface which fd, a file handle another process opened,
+/// points to, which can't be found from the file handle on macOS
+pub fn adopt(_fd: OwnedFd) -> Result<(Box<dyn VirtualNic>, bool), TapError> {
+    Err(TapError::Unsupported {
+        op: "Finding the interface of a file handle",
+    })
+}
+
+/// Create the tap interface called name, by opening its tuntaposx
+/// device, where tap%d takes the first device which isn't open
+pub fn create(name: &str) -> Result<Box<dyn VirtualNic>, TapError> {
+    check_name(name)?;
+    let Some(unit) = unit(name, "tap") else {
+        return Err(TapError::InvalidName {
+            name: name.to_string(),
+            reason: "not of the form tapN, as tuntaposx names them",
+        });
+    };
+
+    /* Each device can only be opened once, so one which is busy is in use */
+    for n in unit.map_or(0..TAP_DEVICES, |n| n..n + 1) {
+        let path = format!("/dev/tap{}", n);
+        match File::options().read(true).write(true).open(&path) {
+            Ok(file) => {
+                return Ok(Box::new(TunTap {
+                    file,
+                    name: format!("tap{}", n),
+                    utun: false,
+                }))
+            }
+            Err(e) if e.raw_os_error() == Some(Errno::EBUSY as i32) && unit.is_none() => {}
+            Err(e) if e.raw_os_error() == Some(Errno::EBUSY as i32) => {
+                return Err(TapError::InUse {
+                    name: name.to_string(),
+                })
+            }
+            Err(source) => {
+                return Err(TapError::Open {
+                    path: "/dev/tapN (from tuntaposx)",
+                    source,
+                })
+            }
+        }
+    }
+    Err(TapError::InUse {
+        name: name.to_string(),
+    })
+}
+
+/// Create the utun interface called name, where utun%d has the kernel
+/// number it
+pub fn create_tun(name: &str) -> Result<Box<dyn VirtualNic>, TapError> {
+    check_name(name)?;
+    let Some(unit) = unit(name, "utun") else {
+        return Err(TapError::InvalidName {
+            name: name.to_string(),
+            reason: "not of the form utunN, as macOS names tun interfaces",
+        });
+    };
+    let ioctl_err = |op| move |source| TapError::Ioctl { op, source };
+
+    let fd = socket(
+        AddressFamily::System,
+        SockType::Datagram,
+        Some(SockProtocol::KextControl),
+    )
+    .map_err(ioctl_err("Opening utun control socket"))?;
+
+    /* Unit 0 has the kernel pick the number, and unit N + 1 is utunN */
+    let addr =
+        SysControlAddr::from_name(fd.as_raw_fd(), UTUN_CONTROL_NAME, unit.map_or(0, |n| n + 1))
+            .map_err(ioctl_err("Finding utun control"))?;
+    connect(fd.as_raw_fd(), &addr).map_err(|source| match source {
+        Errno::EBUSY => TapError::InUse {
+            name: name.to_string(),
+        },
+        _ => TapError::Ioctl {
+            op: "Creating utun interface",
+            source,
+        },
+    })?;
+
+    let name = getsockopt(&fd, sockopt::UtunIfname)
+        .map_err(ioctl_err("Getting utun name"))?
+        .to_string_lossy()
+        .into_owned();
+
+    Ok(Box::new(TunTap {
+        file: File::from(fd),
+        name,
+        utun: true,
+    }))
+}
+
+/// Set the MAC of the tap interface called name, which must exist
+fn set_mac(name: &str, mac: &MacAddr) -> Result<(), TapError> {
+    let socket = ioctl_socket(AddressFamily::Inet, "Opening socket to set tap MAC")?;
+
+    let mut lladdr: sockaddr = unsafe { mem::zeroed() };
+    lladdr.sa_len = mac.0.len() as u8;
+    lladdr.sa_family = AF_LINK as u8;
+    for (i, b) in mac.0.iter().enumerate() {
+        lladdr.sa_data[i] = *b as c_char;
+    }
+
+    let mut ifr = named_ifreq(name);
+    ifr.ifr_ifru.ifru_addr = lladdr;
+
+    unsafe { set_if_lladdr(socket.as_raw_fd(), &ifr) }.map_err(|source| TapError::Ioctl {
+        op: "Setting tap MAC",
+        source,
+    })?;
+
+    Ok(())
+}
+
+/// Set the MTU of the tap interface called name, which must exist
+fn set_mtu(name: &str, mtu: usize) -> Result<(), TapError> {
+    let socket = ioctl_socket(AddressFamily::Inet, "Opening socket to set tap MTU")?;
+
+    let mut ifr = named_ifreq(name);
+    ifr.ifr_ifru.ifru_mtu = mtu as c_int;
+
+    unsafe { set_if_mtu(socket.as_raw_fd(), &ifr) }.map_err(|source| TapError::Ioctl {
+        op: "Setting tap MTU",
+        source,
+    })?;
+
+    Ok(())
+}
+
+/// Bring the interface called name, which must exist, up, or down if not up
+fn set_up(name: &str, up: bool) -> Result<(), TapError> {
+    let socket = ioctl_socket(AddressFamily::Inet, "Opening socket to bring tap up")?;
+
+    /* The other flags are kept as they are */
+    let mut ifr = named_ifreq(name);
+    unsafe { get_if_flags(socket.as_raw_fd(), &mut ifr) }.map_err(|source| TapError::Ioctl {
+        op: "Getting tap flags",
+        source,
+    })?;
+    let flags = unsafe { ifr.ifr_ifru.ifru_flags };
+    ifr.ifr_ifru.ifru_flags = match up {
+        true => flags | IFF_UP as i16,
+        false => flags & !(IFF_UP as i16),
+    };
+
+    unsafe { set_if_flags(socket.as_raw_fd(), &ifr) }.map_err(|source| TapError::Ioctl {
+        op: "Setting tap flags",
+        source,
+    })?;
+
+    Ok(())
+}
+
+/// Give the interface called name, which must exist, addr with a prefix
+/// of prefix_len bits, alongside any addresses it has. A utun interface
+/// is given addr as the address of its other end too, as it has no
+/// broadcast address
+fn add_addr(name: &str, utun: bool, addr: IpAddr, prefix_len: u8) -> Result<(), TapError> {
+    let ioctl_err = |op| move |source| TapError::Ioctl { op, source };
+    match addr {
+        IpAddr::V4(addr) => {
+            let socket = ioctl_socket(AddressFamily::Inet, "Opening socket to set tap address")?;
+            let netmask = u32::MAX
+                .checked_shl(32 - u32::from(prefix_len.min(32)))
+                .unwrap_or(0);
+            let broadcast = match utun {
+                true => addr,
+                false => Ipv4Addr::from(u32::from(addr) | !netmask),
+            };
+
+            let ifra = ifaliasreq {
+                ifra_name: named_ifreq(name).ifr_name,
+                ifra_addr: ipv4_sockaddr(addr),
+                ifra_broadaddr: ipv4_sockaddr(broadcast),
+                ifra_mask: ipv4_sockaddr(Ipv4Addr::from(netmask)),
+            };
+            unsafe { add_if_addr(socket.as_raw_fd(), &ifra) }
+                .map_err(ioctl_err("Setting tap address"))?;
+        }
+        IpAddr::V6(addr) => {
+            let socket = ioctl_socket(AddressFamily::Inet6, "Opening socket to set tap address")?;
+            let prefix_len = u32::from(prefix_len.min(128));
+            let mask = u128::MAX.checked_shl(128 - prefix_len).unwrap_or(0);
+
+            let ifra6 = in6_aliasreq {
+                ifra_name: named_ifreq(name).ifr_name,
+                ifra_addr: ipv6_sockaddr(addr.octets()),
+                ifra_dstaddr: unsafe { mem::zeroed() },
+                ifra_prefixmask: ipv6_sockaddr(mask.to_be_bytes()),
+                ifra_flags: 0,
+                ifra_lifetime: in6_addrlifetime {
+                    ia6t_expire: 0,
+                    ia6t_preferred: 0,
+                    ia6t_vltime: ND6_INFINITE_LIFETIME,
+                    ia6t_pltime: ND6_INFINITE_LIFETIME,
+                },
+            };
+            unsafe { add_if_addr6(socket.as_raw_fd(), &ifra6) }
+                .map_err(ioctl_err("Adding tap address"))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns a sockaddr_in6 holding the address (or mask) octets
+fn ipv6_sockaddr(octets: [u8; 16]) -> sockaddr_in6 {
+    let mut sin6: sockaddr_in6 = unsafe { mem::zeroed() };
+    sin6.sin6_len = mem::size_of::<sockaddr_in6>() as u8;
+    sin6.sin6_family = AddressFamily::Inet6 as u8;
+    sin6.sin6_addr = in6_addr { s6_addr: octets };
+    sin6
+}
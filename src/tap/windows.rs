@@ -0,0 +1,236 @@
+//! Creating and configuring tun interfaces on Windows
+//!
+//! Windows has no tap or tun interfaces of its own, so tun interfaces
+//! are adapters of the wintun driver, whose wintun.dll has to be next
+//! to the vport, or elsewhere on the DLL search path. wintun has no tap
+//! interfaces, so the vport only runs in L3 mode on Windows
+//!
+//! An adapter is named by its alias (the name shown in the Control
+//! Panel), and is opened if one of that name exists, or created, and
+//! removed once the vport stops, if not. A name holding %d takes the
+//! first adapter which no other vport has a session on. IP packets are
+//! read from and written to the adapter's rings through a session, and
+//! the adapter is up for as long as the session is open
+//!
+//! Adapters can't be made persistent, or given an owner or group, or a
+//! MAC, as they have none
+
+use super::{check_name, VirtualNic};
+use crate::error::TapError;
+use std::{
+    fmt, io, mem,
+    net::{IpAddr, Ipv4Addr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use windows_sys::Win32::{
+    Foundation::{ERROR_OBJECT_ALREADY_EXISTS, WAIT_OBJECT_0},
+    NetworkManagement::IpHelper::{
+        CreateUnicastIpAddressEntry, InitializeUnicastIpAddressEntry, MIB_UNICASTIPADDRESS_ROW,
+    },
+    Networking::WinSock::{AF_INET, AF_INET6},
+    System::Threading::{WaitForSingleObject, INFINITE},
+};
+use wintun::{Adapter, Packet, Session};
+
+/// Names of the tap and tun interfaces which are created when
+/// vports aren't given one
+pub const DEFAULT_TAP_NAME: &str = "l2vpn%d";
+pub const DEFAULT_TUN_NAME: &str = "l2vpn%d";
+
+/// Most adapters which a name holding %d is tried with
+const ADAPTERS: u32 = 16;
+
+/// Type of tunnel which adapters are created as, which Windows shows
+const TUNNEL_TYPE: &str = "l2vpn";
+
+/// Bytes in each of a session's rings, as WireGuard uses
+const RING_CAPACITY: u32 = 0x80_0000;
+
+/// Session on a wintun adapter, which IP packets are read from and
+/// written to without any extra headers
+pub struct Wintun {
+    session: Arc<Session>,
+    name: String,
+    /* Packet which readable found, which is read before any other */
+    pending: Mutex<Option<Packet>>,
+}
+
+impl fmt::Debug for Wintun {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Wintun")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl VirtualNic for Wintun {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let pending = self
+            .pending
+            .lock()
+            .map_err(|_| io::ErrorKind::Other)?
+            .take();
+        let packet = match pending {
+            Some(packet) => packet,
+            None => self.session.receive_blocking()?,
+        };
+        let len = packet.bytes().len().min(buf.len());
+        buf[..len].copy_from_slice(&packet.bytes()[..len]);
+        Ok(len)
+    }
+
+    fn write(&self, frame: &[u8]) -> io::Result<usize> {
+        let size = u16::try_from(frame.len()).map_err(|_| io::ErrorKind::InvalidInput)?;
+        let mut packet = self.session.allocate_send_packet(size)?;
+        packet.bytes_mut().copy_from_slice(frame);
+        self.session.send_packet(packet);
+        Ok(frame.len())
+    }
+
+    fn readable(&self, wait: Duration) -> io::Result<bool> {
+        let mut pending = self.pending.lock().map_err(|_| io::ErrorKind::Other)?;
+        if pending.is_some() {
+            return Ok(true);
+        }
+
+        /*
+         * The read event is only set as the ring stops being empty, so
+         * it is only waited for once the ring is found to be empty
+         */
+        *pending = self.session.try_receive()?;
+        if pending.is_none() {
+            let millis = u32::try_from(wait.as_millis()).unwrap_or(INFINITE - 1);
+            let event = self.session.get_read_wait_event()?;
+            if unsafe { WaitForSingleObject(event, millis) } == WAIT_OBJECT_0 {
+                *pending = self.session.try_receive()?;
+            }
+        }
+        Ok(pending.is_some())
+    }
+
+    fn ipv4_addrs(&self) -> Result<Vec<Ipv4Addr>, TapError> {
+        let addrs = self
+            .session
+            .get_adapter()
+            .get_addresses()
+            .map_err(|source| TapError::Wintun {
+                op: "Getting interface addresses",
+                source,
+            })?;
+        Ok(addrs
+            .into_iter()
+            .filter_map(|addr| match addr {
+                IpAddr::V4(addr) => Some(addr),
+                IpAddr::V6(_) => None,
+            })
+            .collect())
+    }
+
+    fn set_mtu(&self, mtu: usize) -> Result<(), TapError> {
+        self.session
+            .get_adapter()
+            .set_mtu(mtu)
+            .map_err(|source| TapError::Wintun {
+                op: "Setting tap MTU",
+                source,
+            })
+    }
+
+    fn add_addr(&self, addr: IpAddr, prefix_len: u8) -> Result<(), TapError> {
+        add_addr(&self.session.get_adapter(), addr, prefix_len)
+    }
+
+    fn set_up(&self, up: bool) -> Result<(), TapError> {
+        match up {
+            true => Ok(()),
+            false => Err(TapError::Unsupported {
+                op: "Bringing wintun adapters down",
+            }),
+        }
+    }
+}
+
+/// Check that name can be given to an interface on Windows, which takes
+/// any name which got through check_name
+pub(super) fn check_platform_name(_name: &str) -> Result<(), TapError> {
+    Ok(())
+}
+
+/// Create a tap interface, which wintun has none of
+pub fn create(_name: &str) -> Result<Box<dyn VirtualNic>, TapError> {
+    Err(TapError::Unsupported {
+        op: "Creating tap interfaces",
+    })
+}
+
+/// Open a session on the wintun adapter called name, which is created
+/// if it doesn't exist, where a name holding %d takes the first adapter
+/// which no other vport has a session on
+pub fn create_tun(name: &str) -> Result<Box<dyn VirtualNic>, TapError> {
+    check_name(name)?;
+    let wintun_err = |op| move |source| TapError::Wintun { op, source };
+    let wintun = unsafe { wintun::load() }.map_err(wintun_err("Loading wintun.dll"))?;
+
+    let numbered = name.contains("%d");
+    let names: Vec<String> = match numbered {
+        true => (0..ADAPTERS)
+            .map(|n| name.replace("%d", &n.to_string()))
+            .collect(),
+        false => vec![name.to_string()],
+    };
+    for name in names {
+        let adapter = match Adapter::open(&wintun, &name) {
+            Ok(adapter) => adapter,
+            Err(_) => Adapter::create(&wintun, &name, TUNNEL_TYPE, None)
+                .map_err(wintun_err("Creating wintun adapter"))?,
+        };
+
+        /* An adapter only takes one session, so one which refuses it is in use */
+        match adapter.start_session(RING_CAPACITY) {
+            Ok(session) => {
+                return Ok(Box::new(Wintun {
+                    session: Arc::new(session),
+                    name,
+                    pending: Mutex::default(),
+                }))
+            }
+            Err(_) if numbered => {}
+            Err(_) => return Err(TapError::InUse { name }),
+        }
+    }
+    Err(TapError::InUse {
+        name: name.to_string(),
+    })
+}
+
+/// Give adapter addr with a prefix of prefix_len bits, alongside any
+/// addresses it has
+fn add_addr(adapter: &Adapter, addr: IpAddr, prefix_len: u8) -> Result<(), TapError> {
+    let mut row: MIB_UNICASTIPADDRESS_ROW = unsafe { mem::zeroed() };
+    unsafe { InitializeUnicastIpAddressEntry(&mut row) };
+    row.InterfaceLuid = adapter.get_luid();
+    row.OnLinkPrefixLength = prefix_len;
+    match addr {
+        IpAddr::V4(addr) => {
+            row.Address.Ipv4.sin_family = AF_INET;
+            row.Address.Ipv4.sin_addr.S_un.S_addr = u32::from(addr).to_be();
+        }
+        IpAddr::V6(addr) => {
+            row.Address.Ipv6.sin6_family = AF_INET6;
+            row.Address.Ipv6.sin6_addr.u.Byte = addr.octets();
+        }
+    }
+
+    match unsafe { CreateUnicastIpAddressEntry(&row) } {
+        0 | ERROR_OBJECT_ALREADY_EXISTS => Ok(()),
+        e => Err(TapError::Wintun {
+            op: "Setting tap address",
+            source: io::Error::from_raw_os_error(e as i32).into(),
+        }),
+    }
+}
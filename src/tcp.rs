@@ -0,0 +1,106 @@
+//! TCP transport between vports and the vswitch
+//!
+//! This lets a vport on a network which blocks UDP reach the
+//! vswitch, if need be through a SOCKS5 or HTTP CONNECT proxy
+//!
+//! As with vsock, each frame is sent with a 4 byte big-endian
+//! length prefix, which is the same framing QEMU uses for its
+//! stream netdevs
+
+use crate::{error::TransportError, proxy::Proxy};
+use std::{
+    io::{Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+/// Size of the length prefix which precedes each frame on the stream
+const LEN_PREFIX: usize = 4;
+
+/// Connected TCP stream carrying length-prefixed frames
+#[derive(Debug)]
+pub struct TcpLink {
+    stream: TcpStream,
+    /* Shared between clones so frames sent from different threads can't interleave */
+    tx_lock: Arc<Mutex<()>>,
+}
+
+impl TcpLink {
+    /// Connect to the vswitch listening on host and port,
+    /// through proxy if one is given
+    ///
+    /// The proxy resolves host itself, so the vport's host
+    /// doesn't need to be able to resolve names outside its network
+    pub fn connect(
+        host: &str,
+        port: u16,
+        proxy: Option<&Proxy>,
+    ) -> Result<TcpLink, TransportError> {
+        let stream = match proxy {
+            Some(proxy) => proxy.connect(host, port)?,
+            None => TcpStream::connect((host, port))?,
+        };
+        TcpLink::new(stream)
+    }
+
+    /// Returns a link carrying frames over stream, which is already connected
+    pub fn new(stream: TcpStream) -> Result<TcpLink, TransportError> {
+        /* Frames are sent whole, so there is nothing to gain by delaying them */
+        stream.set_nodelay(true)?;
+
+        Ok(TcpLink {
+            stream,
+            tx_lock: Arc::new(Mutex::new(())),
+        })
+    }
+
+    /// Returns the address of the other end of the stream
+    pub fn peer_addr(&self) -> Result<SocketAddr, TransportError> {
+        Ok(self.stream.peer_addr()?)
+    }
+
+    /// Returns another handle to the same stream, so that
+    /// one thread can send while another receives
+    pub fn try_clone(&self) -> Result<TcpLink, TransportError> {
+        Ok(TcpLink {
+            stream: self.stream.try_clone()?,
+            tx_lock: self.tx_lock.clone(),
+        })
+    }
+
+    /// Shut the stream down, which wakes any thread receiving from it
+    pub fn shutdown(&self) -> Result<(), TransportError> {
+        Ok(self.stream.shutdown(Shutdown::Both)?)
+    }
+
+    /// Send a single frame over the stream
+    pub fn send_frame(&self, frame: &[u8]) -> Result<(), TransportError> {
+        let mut msg = Vec::with_capacity(LEN_PREFIX + frame.len());
+        msg.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        msg.extend_from_slice(frame);
+
+        /* std sends with MSG_NOSIGNAL, so a disconnected peer is reported as an error */
+        let _tx = self.tx_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        Ok((&self.stream).write_all(&msg)?)
+    }
+
+    /// Receive a single frame into buf, returning its length
+    ///
+    /// Returns a Closed error if the peer has disconnected,
+    /// and a FrameTooLarge error if the frame is larger than buf
+    pub fn recv_frame(&self, buf: &mut [u8]) -> Result<usize, TransportError> {
+        let mut len_prefix = [0u8; LEN_PREFIX];
+        (&self.stream).read_exact(&mut len_prefix)?;
+
+        let len = u32::from_be_bytes(len_prefix) as usize;
+        if len > buf.len() {
+            return Err(TransportError::FrameTooLarge {
+                len,
+                max: buf.len(),
+            });
+        }
+
+        (&self.stream).read_exact(&mut buf[..len])?;
+        Ok(len)
+    }
+}
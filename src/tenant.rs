@@ -0,0 +1,51 @@
+//! VNI headers multiplexing tenants on the vswitch's port
+//!
+//! The vports of many tenants can share the vswitch's own UDP port,
+//! each in its own segment, by sending every datagram after a header
+//! holding the VNI of their network. The vswitch switches the frames
+//! received with each VNI in the segment of the same number, as it does
+//! those from VXLAN and GENEVE VTEPs, so each VNI has a MAC table and
+//! flooding domain of its own, and sends the datagrams for each vport
+//! after a header holding its VNI too
+//!
+//! The header is shaped like a control frame's, but sent to a reserved
+//! multicast MAC of its own, so it is never taken for a frame, and is
+//! followed by the VNI. It wraps whatever else is sent, including
+//! control frames and fragments, and is signed along with them when
+//! frames are authenticated
+
+use crate::{control::CONTROL_ETHER_TYPE, utilities::ETHER_HDR};
+
+/// Locally administered multicast MAC which VNI headers are sent to
+pub const TENANT_MAC: [u8; 6] = [0x03, 0x4c, 0x32, 0x56, 0x50, 0x54];
+
+/// Length of the VNI header in front of every datagram's payload:
+/// the Ethernet header, and the VNI
+pub const VNI_HDR_LEN: usize = ETHER_HDR + 4;
+
+/// Returns payload after a VNI header carrying vni, ready to be sent
+pub fn encapsulate(vni: u32, payload: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(VNI_HDR_LEN + payload.len());
+    datagram.extend_from_slice(&TENANT_MAC);
+    datagram.extend_from_slice(&[0u8; 6]);
+    datagram.extend_from_slice(&CONTROL_ETHER_TYPE.to_be_bytes());
+    datagram.extend_from_slice(&vni.to_be_bytes());
+    datagram.extend_from_slice(payload);
+    datagram
+}
+
+/// Returns the VNI of the datagram, and the payload it carries,
+/// or None if it doesn't start with a VNI header
+pub fn decapsulate(datagram: &[u8]) -> Option<(u32, &[u8])> {
+    if datagram.len() < VNI_HDR_LEN
+        || datagram[..6] != TENANT_MAC
+        || datagram[12..14] != CONTROL_ETHER_TYPE.to_be_bytes()
+    {
+        return None;
+    }
+    let vni = &datagram[ETHER_HDR..VNI_HDR_LEN];
+    Some((
+        u32::from_be_bytes([vni[0], vni[1], vni[2], vni[3]]),
+        &datagram[VNI_HDR_LEN..],
+    ))
+}
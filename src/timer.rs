@@ -0,0 +1,154 @@
+//! Timers which are given the time, rather than reading the clock
+//!
+//! This keeps the periodic work of the vport and vswitch, such as
+//! hellos and echo requests, free of the clock, so it can be driven
+//! by simulated time as easily as by the real one
+
+use std::time::{Duration, Instant};
+
+/// Timer which is due once every period
+#[derive(Clone, Copy, Debug)]
+pub struct Interval {
+    period: Duration,
+    /* When the timer was last due, or None if it never has been */
+    last: Option<Instant>,
+}
+
+impl Interval {
+    /// Returns a timer which is due straight away, and then every period
+    pub fn new(period: Duration) -> Interval {
+        Interval { period, last: None }
+    }
+
+    /// Returns a timer which is first due a period after now
+    pub fn starting_at(period: Duration, now: Instant) -> Interval {
+        Interval {
+            period,
+            last: Some(now),
+        }
+    }
+
+    /// Returns true if the timer is due at now, in which
+    /// case the next period starts from now
+    pub fn due(&mut self, now: Instant) -> bool {
+        if self
+            .last
+            .is_some_and(|last| now.saturating_duration_since(last) < self.period)
+        {
+            return false;
+        }
+        self.last = Some(now);
+        true
+    }
+
+    /// Returns how long after now the timer is next due
+    pub fn remaining(&self, now: Instant) -> Duration {
+        match self.last {
+            Some(last) => self
+                .period
+                .saturating_sub(now.saturating_duration_since(last)),
+            None => Duration::ZERO,
+        }
+    }
+}
+
+/// Tracks whether something which should be heard from regularly,
+/// such as a vswitch sending echo requests, still is
+#[derive(Clone, Copy, Debug)]
+pub struct Liveness {
+    timeout: Duration,
+    last_heard: Instant,
+    /* Whether it had stopped being heard from when last checked */
+    down: bool,
+}
+
+impl Liveness {
+    /// Returns a tracker which considers something down once it
+    /// hasn't been heard from for timeout, counting from now
+    pub fn new(timeout: Duration, now: Instant) -> Liveness {
+        Liveness {
+            timeout,
+            last_heard: now,
+            down: false,
+        }
+    }
+
+    /// Record that it was heard from at now
+    pub fn heard(&mut self, now: Instant) {
+        self.last_heard = self.last_heard.max(now);
+    }
+
+    /// Returns Some(false) if it has stopped being heard from since it was
+    /// last checked, Some(true) if it has been heard from again since it
+    /// stopped, and None if neither has happened
+    pub fn check(&mut self, now: Instant) -> Option<bool> {
+        let down = now.saturating_duration_since(self.last_heard) >= self.timeout;
+        if down == self.down {
+            return None;
+        }
+        self.down = down;
+        Some(!down)
+    }
+
+    /// Returns how long it has gone without being heard from, at now
+    pub fn silent_for(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.last_heard)
+    }
+}
+
+/// Timer for retrying something until it works, which is due straight
+/// away, and then waits twice as long after each time it is due, from
+/// min up to max, so something which is down for a while isn't hammered
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    /* How long after the next time it is due it is due again */
+    delay: Duration,
+    /* When it is next due, or None if straight away */
+    next: Option<Instant>,
+    attempts: u32,
+}
+
+impl Backoff {
+    /// Returns a timer which is due straight away, and then after min
+    pub fn new(min: Duration, max: Duration) -> Backoff {
+        Backoff {
+            min,
+            max,
+            delay: min,
+            next: None,
+            attempts: 0,
+        }
+    }
+
+    /// Returns true if the timer is due at now, in which case it
+    /// is next due after twice the wait before this, up to max
+    pub fn due(&mut self, now: Instant) -> bool {
+        if self.next.is_some_and(|next| now < next) {
+            return false;
+        }
+        self.next = Some(now + self.delay);
+        self.delay = (self.delay * 2).min(self.max);
+        self.attempts += 1;
+        true
+    }
+
+    /// Returns how long after now the timer is next due
+    pub fn remaining(&self, now: Instant) -> Duration {
+        self.next
+            .map_or(Duration::ZERO, |next| next.saturating_duration_since(now))
+    }
+
+    /// Returns how many times the timer has been due since it was
+    /// created or reset
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Make the timer due straight away, and then after min again,
+    /// once what it was retrying has worked
+    pub fn reset(&mut self) {
+        *self = Backoff::new(self.min, self.max);
+    }
+}
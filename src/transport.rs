@@ -0,0 +1,384 @@
+//! Transports which carry the datagrams of the overlay
+//!
+//! Switch and Vport send and receive their datagrams through an
+//! OverlayTransport, so which transport carries them can be chosen at
+//! runtime without touching the forwarding code. Each transport has its
+//! own type of Peer, which tells apart whatever it exchanges datagrams
+//! with, and which MACs are learned on
+//!
+//! A UdpSocket is a transport of itself. TcpTransport carries datagrams
+//! over TCP streams, with the framing of l2vpn::tcp, and UnixTransport
+//! over a Unix datagram socket, for vports on the same host
+
+use crate::{error::TransportError, tcp::TcpLink, tunnel::TUNNEL_DATAGRAM_MAX};
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::Hash,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex, PoisonError,
+    },
+    thread,
+    time::Duration,
+};
+#[cfg(unix)]
+use std::{
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+/// Carries datagrams between this end of the overlay and its peers
+pub trait OverlayTransport: Send + Sync {
+    /// Identifies a peer which datagrams are received from and sent to
+    type Peer: Copy + Eq + Ord + Hash + fmt::Debug + fmt::Display + Send + Sync;
+
+    /// Send datagram to peer
+    fn send_to_peer(&self, datagram: &[u8], peer: &Self::Peer) -> Result<(), TransportError>;
+
+    /// Receive a datagram into buf, returning its length and the peer
+    /// it came from. Waiting longer than the read timeout returns a
+    /// transient error
+    fn recv_from_peer(&self, buf: &mut [u8]) -> Result<(usize, Self::Peer), TransportError>;
+
+    /// Set how long recv_from_peer() waits for a datagram, or None to wait forever
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), TransportError>;
+}
+
+impl OverlayTransport for UdpSocket {
+    type Peer = SocketAddr;
+
+    fn send_to_peer(&self, datagram: &[u8], peer: &SocketAddr) -> Result<(), TransportError> {
+        self.send_to(datagram, peer)?;
+        Ok(())
+    }
+
+    fn recv_from_peer(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), TransportError> {
+        Ok(self.recv_from(buf)?)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), TransportError> {
+        Ok(UdpSocket::set_read_timeout(self, timeout)?)
+    }
+}
+
+/// Links to the peers of a TcpTransport, keyed by their address
+type TcpLinks = Arc<Mutex<HashMap<SocketAddr, TcpLink>>>;
+
+/// What the threads of a TcpTransport hand to recv_from_peer(): a
+/// datagram and the peer it came from, or the error which stopped the listener
+type TcpReceived = Result<(SocketAddr, Vec<u8>), TransportError>;
+
+/// Transport carrying datagrams over TCP streams, to the vswitch it
+/// connected to, or from the vports which connected to its listener
+///
+/// The listener is accepted from, and each stream read, by a thread
+/// of its own, which hand what they receive to recv_from_peer()
+#[derive(Debug)]
+pub struct TcpTransport {
+    /// Address the listener is bound to, if there is one, and whether it is closed
+    listener: Option<(SocketAddr, Arc<AtomicBool>)>,
+    links: TcpLinks,
+    tx: Sender<TcpReceived>,
+    rx: Mutex<Receiver<TcpReceived>>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl TcpTransport {
+    /// Returns a transport for the vports which connect to listener
+    pub fn listen(listener: TcpListener) -> Result<TcpTransport, TransportError> {
+        listener.set_nonblocking(false)?;
+        let mut transport = TcpTransport::new();
+        let closed = Arc::new(AtomicBool::new(false));
+        transport.listener = Some((listener.local_addr()?, closed.clone()));
+
+        let links = transport.links.clone();
+        let tx = transport.tx.clone();
+        thread::spawn(move || loop {
+            let accepted = listener.accept();
+            if closed.load(Ordering::Relaxed) {
+                return;
+            }
+            let stream = match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    let _ = tx.send(Err(e.into()));
+                    return;
+                }
+            };
+            /* A vport which disconnects at once is simply never heard from */
+            let _ = TcpLink::new(stream).and_then(|link| add_link(&links, &tx, link));
+        });
+        Ok(transport)
+    }
+
+    /// Returns a transport over link, which is connected to the vswitch,
+    /// whose address is its peer
+    pub fn connect(link: TcpLink) -> Result<TcpTransport, TransportError> {
+        let transport = TcpTransport::new();
+        add_link(&transport.links, &transport.tx, link)?;
+        Ok(transport)
+    }
+
+    fn new() -> TcpTransport {
+        let (tx, rx) = mpsc::channel();
+        TcpTransport {
+            listener: None,
+            links: Arc::new(Mutex::new(HashMap::new())),
+            tx,
+            rx: Mutex::new(rx),
+            read_timeout: Mutex::new(None),
+        }
+    }
+}
+
+/// Start reading the datagrams which link receives into tx, until its
+/// peer disconnects, when it is forgotten from links
+fn add_link(
+    links: &TcpLinks,
+    tx: &Sender<TcpReceived>,
+    link: TcpLink,
+) -> Result<(), TransportError> {
+    let peer = link.peer_addr()?;
+    let reader = link.try_clone()?;
+    links
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(peer, link);
+
+    let links = links.clone();
+    let tx = tx.clone();
+    thread::spawn(move || {
+        let mut buf = vec![0u8; TUNNEL_DATAGRAM_MAX];
+        while let Ok(len) = reader.recv_frame(&mut buf) {
+            if tx.send(Ok((peer, buf[..len].to_vec()))).is_err() {
+                break;
+            }
+        }
+        links
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&peer);
+    });
+    Ok(())
+}
+
+impl OverlayTransport for TcpTransport {
+    type Peer = SocketAddr;
+
+    fn send_to_peer(&self, datagram: &[u8], peer: &SocketAddr) -> Result<(), TransportError> {
+        match self
+            .links
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(peer)
+        {
+            Some(link) => link.send_frame(datagram),
+            /* The peer has disconnected, so there is nowhere to send the datagram */
+            None => Ok(()),
+        }
+    }
+
+    fn recv_from_peer(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), TransportError> {
+        let timeout = *self
+            .read_timeout
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let rx = self.rx.lock().unwrap_or_else(PoisonError::into_inner);
+        let (peer, datagram) = match timeout {
+            Some(timeout) => rx.recv_timeout(timeout).map_err(|e| match e {
+                RecvTimeoutError::Timeout => io::Error::from(io::ErrorKind::TimedOut),
+                RecvTimeoutError::Disconnected => io::Error::from(io::ErrorKind::BrokenPipe),
+            })?,
+            /* We hold a sender, so the channel can't be disconnected */
+            None => rx
+                .recv()
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?,
+        }?;
+
+        if datagram.len() > buf.len() {
+            return Err(TransportError::FrameTooLarge {
+                len: datagram.len(),
+                max: buf.len(),
+            });
+        }
+        buf[..datagram.len()].copy_from_slice(&datagram);
+        Ok((datagram.len(), peer))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), TransportError> {
+        *self
+            .read_timeout
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = timeout;
+        Ok(())
+    }
+}
+
+/// The streams are shut down, so the threads reading them stop, and the
+/// thread accepting from the listener is woken up to close it
+impl Drop for TcpTransport {
+    fn drop(&mut self) {
+        if let Some((mut addr, closed)) = self.listener.take() {
+            closed.store(true, Ordering::Relaxed);
+            /* A listener bound to every address can be reached on loopback */
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr {
+                    SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                    SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                });
+            }
+            let _ = TcpStream::connect(addr);
+        }
+        for link in self
+            .links
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+        {
+            let _ = link.shutdown();
+        }
+    }
+}
+
+/// Peer of a UnixTransport, numbered in the order it was first given
+/// or received datagrams from, so a peer which has been forgotten never
+/// shares its number with another
+#[cfg(unix)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct UnixPeer(usize);
+
+#[cfg(unix)]
+impl fmt::Display for UnixPeer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unix#{}", self.0)
+    }
+}
+
+/// Transport carrying datagrams over a Unix datagram socket, to and
+/// from the sockets of the vports or the vswitch on the same host
+///
+/// Only sockets bound to a path can be answered, so datagrams from
+/// unbound ones are ignored. Peers whose sockets are gone are forgotten
+/// when a datagram to them is refused, and once there are MAX_UNIX_PEERS,
+/// the one heard from least recently is forgotten to make room for another
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct UnixTransport {
+    socket: UnixDatagram,
+    peers: Mutex<UnixPeers>,
+}
+
+/// Most peers a UnixTransport remembers the paths of
+#[cfg(unix)]
+pub const MAX_UNIX_PEERS: usize = 1024;
+
+/// Paths of the peers of a UnixTransport, and when each was last heard from
+#[cfg(unix)]
+#[derive(Debug, Default)]
+struct UnixPeers {
+    by_path: HashMap<PathBuf, UnixPeer>,
+    paths: HashMap<UnixPeer, (PathBuf, Instant)>,
+    next: usize,
+}
+
+#[cfg(unix)]
+impl UnixPeers {
+    fn forget(&mut self, peer: &UnixPeer) {
+        if let Some((path, _)) = self.paths.remove(peer) {
+            self.by_path.remove(&path);
+        }
+    }
+}
+
+#[cfg(unix)]
+impl UnixTransport {
+    /// Returns a transport over socket, which must be bound to a path
+    /// for its peers to answer it
+    pub fn new(socket: UnixDatagram) -> UnixTransport {
+        UnixTransport {
+            socket,
+            peers: Mutex::new(UnixPeers::default()),
+        }
+    }
+
+    /// Returns the peer whose socket is bound to path
+    pub fn peer(&self, path: &Path) -> UnixPeer {
+        let now = Instant::now();
+        let mut peers = self.peers.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(&peer) = peers.by_path.get(path) {
+            if let Some((_, last_heard)) = peers.paths.get_mut(&peer) {
+                *last_heard = now;
+            }
+            return peer;
+        }
+
+        if peers.paths.len() >= MAX_UNIX_PEERS {
+            if let Some(oldest) = peers
+                .paths
+                .iter()
+                .min_by_key(|(_, (_, last_heard))| *last_heard)
+                .map(|(&peer, _)| peer)
+            {
+                peers.forget(&oldest);
+            }
+        }
+        let peer = UnixPeer(peers.next);
+        peers.next += 1;
+        peers.by_path.insert(path.to_path_buf(), peer);
+        peers.paths.insert(peer, (path.to_path_buf(), now));
+        peer
+    }
+
+    /// Forget peer, e.g. as it has left, so it no longer takes up room
+    pub fn forget(&self, peer: &UnixPeer) {
+        self.peers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .forget(peer);
+    }
+}
+
+#[cfg(unix)]
+impl OverlayTransport for UnixTransport {
+    type Peer = UnixPeer;
+
+    fn send_to_peer(&self, datagram: &[u8], peer: &UnixPeer) -> Result<(), TransportError> {
+        let mut peers = self.peers.lock().unwrap_or_else(PoisonError::into_inner);
+        /* The peer has been forgotten, so there is nowhere to send the datagram */
+        let Some((path, _)) = peers.paths.get(peer) else {
+            return Ok(());
+        };
+        match self.socket.send_to(datagram, path) {
+            Ok(_) => Ok(()),
+            /* Nothing is bound to the path any more, so the peer has gone */
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+                ) =>
+            {
+                peers.forget(peer);
+                Err(e.into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn recv_from_peer(&self, buf: &mut [u8]) -> Result<(usize, UnixPeer), TransportError> {
+        loop {
+            let (len, addr) = self.socket.recv_from(buf)?;
+            if let Some(path) = addr.as_pathname() {
+                return Ok((len, self.peer(path)));
+            }
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), TransportError> {
+        Ok(self.socket.set_read_timeout(timeout)?)
+    }
+}
@@ -0,0 +1,97 @@
+//! Carrying the IP packets of tun interfaces as Ethernet frames
+//!
+//! A vport in L3 mode reads IPv4 packets from a tun interface, which
+//! has no Ethernet semantics, for deployments which only need routed
+//! connectivity. Each packet is sent in a frame from and to MACs derived
+//! from its source and destination addresses, so the vswitch learns the
+//! addresses behind each vport as it learns MACs, and forwards each
+//! packet to the vport behind its destination as an IP forwarder would.
+//! As no ARP precedes the packets, the vport announces the addresses of
+//! its tun interface with gratuitous ARPs, so the vswitch learns them
+//! before they send anything. Multicast and broadcast packets are sent
+//! to the MACs hosts would send them to
+//!
+//! Frames received in L3 mode have their Ethernet header taken off, and
+//! those which carry anything but IPv4 (e.g. ARP) are dropped, as the
+//! tun interface can't take them
+
+use crate::{
+    frame::EthernetFrame,
+    mac::MacAddr,
+    utilities::{ETHER_FRAME_MIN, ETHER_HDR},
+};
+use std::net::Ipv4Addr;
+
+/// Locally administered unicast prefix of the MACs derived from IPv4
+/// addresses, which are followed by the address
+pub const IP_MAC_PREFIX: [u8; 2] = [0x02, 0x4c];
+
+/// Ether types of IPv4 packets and ARP messages
+const IPV4_ETHER_TYPE: u16 = 0x0800;
+const ARP_ETHER_TYPE: u16 = 0x0806;
+
+/// ARP hardware type of Ethernet, and opcode of requests
+const ARP_HTYPE_ETHERNET: u16 = 1;
+const ARP_REQUEST: u16 = 1;
+
+/// Length of an IPv4 header without options
+const IPV4_HDR_LEN: usize = 20;
+
+/// Returns the MAC which frames to or from ip are sent to or from
+pub fn ip_mac(ip: Ipv4Addr) -> MacAddr {
+    let [a, b, c, d] = ip.octets();
+    if ip.is_broadcast() {
+        MacAddr::BROADCAST
+    } else if ip.is_multicast() {
+        /* As in RFC 1112, the low 23 bits of the group follow 01:00:5e */
+        MacAddr([0x01, 0x00, 0x5e, b & 0x7f, c, d])
+    } else {
+        MacAddr([IP_MAC_PREFIX[0], IP_MAC_PREFIX[1], a, b, c, d])
+    }
+}
+
+/// Returns the Ethernet header which packet, read from a tun interface,
+/// is sent after, or None if it isn't an IPv4 packet
+pub fn frame_header(packet: &[u8]) -> Option<[u8; ETHER_HDR]> {
+    if packet.len() < IPV4_HDR_LEN || packet[0] >> 4 != 4 {
+        return None;
+    }
+    let src = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+    let dst = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+
+    let mut header = [0u8; ETHER_HDR];
+    header[..6].copy_from_slice(&ip_mac(dst).0);
+    header[6..12].copy_from_slice(&ip_mac(src).0);
+    header[12..].copy_from_slice(&IPV4_ETHER_TYPE.to_be_bytes());
+    Some(header)
+}
+
+/// Returns the gratuitous ARP which announces ip, from the MAC derived
+/// from it, so the vswitch learns which vport it is behind
+pub fn announcement(ip: Ipv4Addr) -> Vec<u8> {
+    let mac = ip_mac(ip);
+    let mut frame = Vec::with_capacity(ETHER_FRAME_MIN);
+    frame.extend_from_slice(&[0xff; 6]);
+    frame.extend_from_slice(&mac.0);
+    frame.extend_from_slice(&ARP_ETHER_TYPE.to_be_bytes());
+    frame.extend_from_slice(&ARP_HTYPE_ETHERNET.to_be_bytes());
+    frame.extend_from_slice(&IPV4_ETHER_TYPE.to_be_bytes());
+    frame.extend_from_slice(&[6, 4]);
+    frame.extend_from_slice(&ARP_REQUEST.to_be_bytes());
+    frame.extend_from_slice(&mac.0);
+    frame.extend_from_slice(&ip.octets());
+    frame.extend_from_slice(&[0u8; 6]);
+    frame.extend_from_slice(&ip.octets());
+    frame.resize(ETHER_FRAME_MIN, 0);
+    frame
+}
+
+/// Returns the IPv4 packet which frame carries, to be written to a tun
+/// interface, or None if it carries anything else
+pub fn packet(frame: &[u8]) -> Option<&[u8]> {
+    EthernetFrame::parse(frame)
+        .ok()
+        .filter(|frame| frame.vlan.is_none() && frame.ether_type == IPV4_ETHER_TYPE)
+        .map(|frame| frame.payload)
+        .filter(|packet| !packet.is_empty())
+}
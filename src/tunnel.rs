@@ -0,0 +1,96 @@
+//! Hop limit carried by frames between vports and vswitches
+//!
+//! vports, and vswitches peered with other vswitches, insert a 4 byte
+//! tag after the MACs of every frame they send, much like an 802.1Q
+//! tag. It holds the IEEE local experimental EtherType 2 and a TTL,
+//! which every vswitch decrements, dropping the frame once the TTL
+//! reaches zero. This stops a loop between misconfigured vswitches
+//! from forwarding frames forever. Its last byte holds flags, which
+//! say whether the rest of the frame is compressed
+//!
+//! Frames are only sent with the tag to those which sent the tag
+//! themselves, so QEMU netdevs and older vports still receive plain
+//! Ethernet frames
+
+use crate::{
+    mtu::DTLS_OVERHEAD,
+    utilities::{frame_max, ETHER_HDR, MAX_OVERLAY_MTU},
+};
+
+/// IEEE 802 local experimental EtherType 2
+pub const HOP_LIMIT_ETHER_TYPE: u16 = 0x88B6;
+
+/// Length of the hop limit tag (EtherType, TTL and flags)
+pub const HOP_LIMIT_TAG_LEN: usize = 4;
+
+/// Flag in the hop limit tag saying the rest of the frame is
+/// compressed, which is only set by those which agreed to compress
+pub const FLAG_COMPRESSED: u8 = 0x01;
+
+/// TTL of frames entering the L2VPN network
+pub const DEFAULT_TTL: u8 = 16;
+
+/// Maximum size of a frame carrying a hop limit tag, excluding the
+/// FCS, with the largest overlay MTU which can be configured
+pub const TUNNEL_FRAME_MAX: usize = tunnel_frame_max(MAX_OVERLAY_MTU);
+
+/// Maximum size of a datagram carrying such a frame, which is the DTLS
+/// record holding it when DTLS is on (larger than the frame and its
+/// tag when frame authentication is on)
+pub const TUNNEL_DATAGRAM_MAX: usize = TUNNEL_FRAME_MAX + DTLS_OVERHEAD;
+
+/// Offset of the hop limit tag, which follows the dst and src MACs
+const TAG_OFFSET: usize = 12;
+
+/// Offset of the flags in the hop limit tag
+const FLAGS_OFFSET: usize = TAG_OFFSET + 3;
+
+/// Returns the maximum size of a frame carrying a hop
+/// limit tag, excluding the FCS, with overlay_mtu
+pub const fn tunnel_frame_max(overlay_mtu: usize) -> usize {
+    frame_max(overlay_mtu) + HOP_LIMIT_TAG_LEN
+}
+
+/// Returns the TTL of frame, or None if it has no hop limit tag
+pub fn hop_limit(frame: &[u8]) -> Option<u8> {
+    match frame.len() >= ETHER_HDR + HOP_LIMIT_TAG_LEN
+        && frame[TAG_OFFSET..TAG_OFFSET + 2] == HOP_LIMIT_ETHER_TYPE.to_be_bytes()
+    {
+        true => Some(frame[TAG_OFFSET + 2]),
+        false => None,
+    }
+}
+
+/// Returns the flags of frame's hop limit tag, or None if it has none
+pub fn hop_limit_flags(frame: &[u8]) -> Option<u8> {
+    hop_limit(frame).map(|_| frame[FLAGS_OFFSET])
+}
+
+/// Set the flags of frame's hop limit tag, which it must have, to flags
+pub fn set_hop_limit_flags(frame: &mut [u8], flags: u8) {
+    frame[FLAGS_OFFSET] = flags;
+}
+
+/// Insert a hop limit tag with ttl into the frame in buf (which is
+/// frame_len bytes long), and return the new length of the frame
+///
+/// buf must be at least frame_len + HOP_LIMIT_TAG_LEN bytes long
+pub fn push_hop_limit(buf: &mut [u8], frame_len: usize, ttl: u8) -> usize {
+    buf.copy_within(TAG_OFFSET..frame_len, TAG_OFFSET + HOP_LIMIT_TAG_LEN);
+
+    let [type_hi, type_lo] = HOP_LIMIT_ETHER_TYPE.to_be_bytes();
+    buf[TAG_OFFSET..TAG_OFFSET + HOP_LIMIT_TAG_LEN].copy_from_slice(&[type_hi, type_lo, ttl, 0]);
+
+    frame_len + HOP_LIMIT_TAG_LEN
+}
+
+/// Remove the hop limit tag from the frame in buf (which is frame_len
+/// bytes long) if it has one, and return the new length of the frame
+pub fn pop_hop_limit(buf: &mut [u8], frame_len: usize) -> usize {
+    if hop_limit(&buf[..frame_len]).is_none() {
+        return frame_len;
+    }
+
+    buf.copy_within(TAG_OFFSET + HOP_LIMIT_TAG_LEN..frame_len, TAG_OFFSET);
+    frame_len - HOP_LIMIT_TAG_LEN
+}
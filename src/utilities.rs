@@ -1,21 +1,216 @@
 //! Share utilities between vswitch.rs and vport.rs
 
-/// Returns string representation of passed MAC bytes
-pub fn mac_string(mac: &[u8]) -> String {
-    mac.iter()
-        .take(6)
-        .map(|b| format!("{:02x}", b))
-        .collect::<Vec<String>>()
-        .join(":")
+use crate::{
+    error::ConfigError,
+    mac::MacAddr,
+    tunnel::{hop_limit, HOP_LIMIT_TAG_LEN},
+};
+use std::{fmt, net::IpAddr};
+
+/// Maximum size of an Ethernet frame including the FCS
+pub const ETHER_MTU: usize = 1518;
+
+/// MTU of the L2VPN network (the overlay MTU) unless it is configured
+/// otherwise, which is that of standard Ethernet, and fills ETHER_MTU
+pub const DEFAULT_OVERLAY_MTU: usize = 1500;
+
+/// Smallest overlay MTU which can be configured, which is the
+/// IPv4 minimum reassembly size
+pub const MIN_OVERLAY_MTU: usize = 576;
+
+/// Largest overlay MTU which can be configured, which is
+/// the largest jumbo frame MTU switches commonly support
+pub const MAX_OVERLAY_MTU: usize = 9216;
+
+/// Minimum size of an Ethernet frame including the FCS
+pub const ETHER_MIN: usize = 64;
+
+/// Size of the Ethernet header (dst MAC, src MAC and EtherType)
+pub const ETHER_HDR: usize = 14;
+
+/// Size of the Ethernet frame check sequence
+pub const ETHER_FCS: usize = 4;
+
+/// Minimum size of an Ethernet frame as seen by software, where
+/// the FCS has already been stripped by the NIC (or was never
+/// added, as is the case for tap interfaces and QEMU netdevs)
+pub const ETHER_FRAME_MIN: usize = ETHER_MIN - ETHER_FCS;
+
+/// EtherType of 802.1Q VLAN tags
+pub const VLAN_ETHER_TYPE: u16 = 0x8100;
+
+/// EtherType of 802.1ad service VLAN tags, the outer tags of QinQ frames
+pub const QINQ_ETHER_TYPE: u16 = 0x88A8;
+
+/// Offset of the EtherType (or first tag) of a frame,
+/// which follows the dst and src MACs
+const TYPE_OFFSET: usize = 12;
+
+/// Returns the largest frame, excluding the FCS, which the L2VPN network
+/// carries with overlay_mtu, i.e. a packet of overlay_mtu bytes with an
+/// Ethernet header and an 802.1Q tag
+pub const fn frame_max(overlay_mtu: usize) -> usize {
+    overlay_mtu + ETHER_HDR + 4
+}
+
+/// Parse an overlay MTU, as given to --mtu
+pub fn parse_overlay_mtu(value: &str) -> Result<usize, ConfigError> {
+    match value.parse::<usize>() {
+        Ok(mtu) if (MIN_OVERLAY_MTU..=MAX_OVERLAY_MTU).contains(&mtu) => Ok(mtu),
+        _ => Err(ConfigError::OverlayMtu(value.to_string())),
+    }
+}
+
+/// Fields of an 802.1Q (or 802.1ad) VLAN tag
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VlanTag {
+    /// Priority code point
+    pub pcp: u8,
+    /// Drop eligible indicator
+    pub dei: bool,
+    /// VLAN ID
+    pub vid: u16,
+}
+
+impl VlanTag {
+    /// Returns the VLAN tag at offset in frame, or None if
+    /// there isn't one there followed by another EtherType
+    fn at(frame: &[u8], offset: usize) -> Option<VlanTag> {
+        let [type_hi, type_lo, tci_hi, tci_lo, _, _] = *frame.get(offset..offset + 6)? else {
+            return None;
+        };
+
+        match u16::from_be_bytes([type_hi, type_lo]) {
+            VLAN_ETHER_TYPE | QINQ_ETHER_TYPE => {
+                Some(VlanTag::from_tci(u16::from_be_bytes([tci_hi, tci_lo])))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the fields of a tag's TCI
+    pub(crate) fn from_tci(tci: u16) -> VlanTag {
+        VlanTag {
+            pcp: (tci >> 13) as u8,
+            dei: tci & 0x1000 != 0,
+            vid: tci & 0x0FFF,
+        }
+    }
+
+    /// Returns the tag's TCI, ignoring any bits of pcp and vid which don't fit in it
+    pub fn tci(&self) -> u16 {
+        (u16::from(self.pcp & 0x07) << 13) | (u16::from(self.dei) << 12) | (self.vid & 0x0FFF)
+    }
+}
+
+impl fmt::Display for VlanTag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "vlan={} (pcp={}, dei={})",
+            self.vid, self.pcp, self.dei as u8
+        )
+    }
+}
+
+/// Returns the offset of frame's first VLAN tag or EtherType,
+/// which is after its hop limit tag, if it has one
+fn tag_offset(frame: &[u8]) -> usize {
+    match hop_limit(frame) {
+        Some(_) => TYPE_OFFSET + HOP_LIMIT_TAG_LEN,
+        None => TYPE_OFFSET,
+    }
+}
+
+/// Returns the outermost VLAN tag of frame, or None if it is untagged
+pub fn vlan_tag(frame: &[u8]) -> Option<VlanTag> {
+    VlanTag::at(frame, tag_offset(frame))
+}
+
+/// Displays the outermost VLAN tag of a frame for log
+/// messages, or "untagged" if it has none, without allocating
+pub struct VlanLogMsg<'a>(pub &'a [u8]);
+
+impl fmt::Display for VlanLogMsg<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match vlan_tag(self.0) {
+            Some(tag) => write!(f, "{}", tag),
+            None => f.write_str("untagged"),
+        }
+    }
+}
+
+/// Returns the address and prefix length represented by a string in the
+/// form <ip>/<prefix_len>, as given to --tap-addr, or None if it is not
+/// one, or the prefix is longer than the address
+pub fn parse_ip_prefix(value: &str) -> Option<(IpAddr, u8)> {
+    let (ip, prefix_len) = value.split_once('/')?;
+    let ip = ip.parse::<IpAddr>().ok()?;
+    let prefix_len = prefix_len.parse::<u8>().ok()?;
+    let max = if ip.is_ipv4() { 32 } else { 128 };
+    (prefix_len <= max).then_some((ip, prefix_len))
+}
+
+/// Pads the frame in buf (which is frame_len bytes long) with
+/// zeroes up to ETHER_FRAME_MIN bytes, as a NIC would do before
+/// transmitting it, and returns the new length of the frame
+///
+/// buf must be at least ETHER_FRAME_MIN bytes long
+pub fn pad_frame(buf: &mut [u8], frame_len: usize) -> usize {
+    if frame_len >= ETHER_FRAME_MIN {
+        return frame_len;
+    }
+
+    buf[frame_len..ETHER_FRAME_MIN].fill(0);
+    ETHER_FRAME_MIN
 }
 
 /// Returns log message with details of frame
 pub fn get_frame_log_msg(frame: &[u8], size: usize) -> String {
-    let dst_mac = mac_string(&frame[0..6]);
-    let src_mac = mac_string(&frame[6..12]);
-    let ether_type = ((frame[12] as u16) << 8) + frame[13] as u16;
-    format!(
-        "dst_mac={}, src_mac={}, type={}, size={}",
-        dst_mac, src_mac, ether_type, size
-    )
+    FrameLogMsg(frame, size).to_string()
+}
+
+/// Displays the log message returned by get_frame_log_msg for
+/// a frame and its size, without allocating. Every VLAN tag of
+/// the frame is shown, followed by the EtherType inside them
+/// (skipping the frame's hop limit tag, if it has one). Fields
+/// which a runt frame is too short to hold are shown as "runt"
+pub struct FrameLogMsg<'a>(pub &'a [u8], pub usize);
+
+impl fmt::Display for FrameLogMsg<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let FrameLogMsg(frame, size) = self;
+        let mac = |at: usize| {
+            OrRunt(
+                frame
+                    .get(at..at + 6)
+                    .and_then(|mac| mac.try_into().ok())
+                    .map(MacAddr),
+            )
+        };
+        write!(f, "dst_mac={}, src_mac={}", mac(0), mac(6))?;
+
+        let mut type_offset = tag_offset(frame);
+        while let Some(tag) = VlanTag::at(frame, type_offset) {
+            write!(f, ", {}", tag)?;
+            type_offset += 4;
+        }
+
+        let ether_type = frame
+            .get(type_offset..type_offset + 2)
+            .map(|ether_type| u16::from_be_bytes([ether_type[0], ether_type[1]]));
+        write!(f, ", type={}, size={}", OrRunt(ether_type), size)
+    }
+}
+
+/// Displays a field of a frame, or "runt" if the frame is too short to hold it
+struct OrRunt<T>(Option<T>);
+
+impl<T: fmt::Display> fmt::Display for OrRunt<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.0 {
+            Some(field) => field.fmt(f),
+            None => f.write_str("runt"),
+        }
+    }
 }
@@ -0,0 +1,383 @@
+//! vhost-user backend for the vswitch
+//!
+//! This lets QEMU/cloud-hypervisor guests connect their
+//! virtio-net devices straight to the vswitch. The guest's
+//! memory is shared with the vswitch over the vhost-user
+//! socket, so frames are copied directly out of and into
+//! the guest's virtqueues, bypassing tap interfaces and the
+//! host's network stack entirely
+
+use crate::{error::TransportError, utilities::ETHER_HDR};
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    os::fd::AsRawFd,
+    sync::{Arc, Mutex, PoisonError, RwLock},
+    thread,
+};
+use vhost::vhost_user::message::{VhostUserProtocolFeatures, VhostUserVirtioFeatures};
+use vhost_user_backend::{VhostUserBackendMut, VhostUserDaemon, VringRwLock, VringT};
+use virtio_queue::{QueueOwnedT, QueueT};
+use vm_memory::{GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
+use vmm_sys_util::{
+    epoll::EventSet,
+    event::{new_event_consumer_and_notifier, EventConsumer, EventFlag, EventNotifier},
+    eventfd::{EventFd, EFD_NONBLOCK},
+};
+
+/*
+ * These feature bits are defined in linux/virtio_config.h
+ * and linux/virtio_ring.h. VERSION_1 means every frame is
+ * preceded by the 12 byte virtio_net_hdr_v1 header
+ */
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+const VIRTIO_RING_F_INDIRECT_DESC: u64 = 1 << 28;
+const VIRTIO_RING_F_EVENT_IDX: u64 = 1 << 29;
+
+/// Size of the virtio_net_hdr_v1 header which prefixes every frame
+const VIRTIO_NET_HDR_LEN: usize = 12;
+
+/// Offset of the num_buffers field within virtio_net_hdr_v1
+const VIRTIO_NET_HDR_NUM_BUFFERS: usize = 10;
+
+/*
+ * A virtio-net device with a single queue pair has
+ * the RX queue at index 0, and the TX queue at index 1
+ */
+const NUM_QUEUES: usize = 2;
+const QUEUE_SIZE: usize = 256;
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+
+/*
+ * Epoll event ids up to NUM_QUEUES are reserved by
+ * vhost-user-backend for the queues and the exit event,
+ * so the event signalling that the vswitch has queued
+ * frames for the guest uses the next id along
+ */
+const RX_PENDING_EVENT: u16 = NUM_QUEUES as u16 + 1;
+
+/// Maximum number of frames which are queued for the guest
+/// while it has no RX buffers available, after which the
+/// oldest frames are dropped
+const MAX_PENDING_FRAMES: usize = 256;
+
+/// Frames queued by the vswitch for delivery to the guest
+type FrameQueue = Arc<Mutex<VecDeque<Vec<u8>>>>;
+
+/// Callback which hands frames transmitted by the guest to the vswitch
+type FrameHandler = Arc<dyn Fn(&[u8]) + Send + Sync>;
+
+/// Handle which the vswitch uses to send frames to the
+/// guest attached to a vhost-user socket
+pub struct VhostUserPort {
+    socket_path: String,
+    pending: FrameQueue,
+    rx_event: EventFd,
+}
+
+impl VhostUserPort {
+    /// Listen for a vhost-user frontend on socket_path, and call
+    /// on_frame for every frame the guest transmits
+    ///
+    /// The socket is served from a background thread which accepts
+    /// a new frontend whenever the previous one disconnects, so the
+    /// guest can be restarted without restarting the vswitch
+    pub fn spawn<F>(socket_path: &str, on_frame: F) -> Result<VhostUserPort, TransportError>
+    where
+        F: Fn(&[u8]) + Send + Sync + 'static,
+    {
+        let port = VhostUserPort {
+            socket_path: socket_path.to_string(),
+            pending: Arc::new(Mutex::new(VecDeque::new())),
+            rx_event: EventFd::new(EFD_NONBLOCK)?,
+        };
+
+        let path = port.socket_path.clone();
+        let pending = port.pending.clone();
+        let rx_event = port.rx_event.try_clone()?;
+        let on_frame: FrameHandler = Arc::new(on_frame);
+
+        thread::spawn(move || loop {
+            if let Err(e) = serve_frontend(&path, &pending, &rx_event, &on_frame) {
+                eprintln!("vhost-user socket '{}' failed with error: '{}'", path, e);
+                return;
+            }
+
+            println!("vhost-user frontend disconnected from '{}'", path);
+        });
+
+        Ok(port)
+    }
+
+    /// Returns the path of the vhost-user socket this port is served on
+    pub fn socket_path(&self) -> &str {
+        &self.socket_path
+    }
+
+    /// Queue frame for delivery to the guest, and wake
+    /// the backend so that it copies it into the RX queue
+    pub fn send(&self, frame: &[u8]) -> Result<(), TransportError> {
+        {
+            let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+            if pending.len() == MAX_PENDING_FRAMES {
+                pending.pop_front();
+            }
+            pending.push_back(frame.to_vec());
+        }
+
+        Ok(self.rx_event.write(1)?)
+    }
+}
+
+/// Serve a single vhost-user frontend connection until it disconnects
+fn serve_frontend(
+    path: &str,
+    pending: &FrameQueue,
+    rx_event: &EventFd,
+    on_frame: &FrameHandler,
+) -> Result<(), TransportError> {
+    let backend = Arc::new(RwLock::new(VhostUserNet {
+        mem: None,
+        event_idx: false,
+        pending: pending.clone(),
+        rx_event: rx_event.try_clone()?,
+        on_frame: on_frame.clone(),
+    }));
+
+    let mut daemon = VhostUserDaemon::new(
+        "vhost-user-net".to_string(),
+        backend,
+        GuestMemoryAtomic::new(GuestMemoryMmap::new()),
+    )
+    .map_err(|e| TransportError::VhostUser(format!("{:?}", e)))?;
+
+    /*
+     * Have the backend's worker thread wake up whenever the
+     * vswitch queues a frame for the guest
+     */
+    for handler in daemon.get_epoll_handlers() {
+        handler.register_listener(rx_event.as_raw_fd(), EventSet::IN, RX_PENDING_EVENT as u64)?;
+    }
+
+    println!("Waiting for vhost-user frontend on '{}'", path);
+
+    daemon
+        .serve(path)
+        .map_err(|e| TransportError::VhostUser(format!("{:?}", e)))?;
+
+    Ok(())
+}
+
+/// State of the virtio-net device which is emulated
+/// for a single vhost-user frontend
+struct VhostUserNet {
+    mem: Option<GuestMemoryAtomic<GuestMemoryMmap>>,
+    event_idx: bool,
+    pending: FrameQueue,
+    rx_event: EventFd,
+    on_frame: FrameHandler,
+}
+
+impl VhostUserNet {
+    /// Process the queue with process_queue, and when event
+    /// suppression is in use, keep going until the guest has
+    /// stopped adding buffers, so no notifications are missed
+    fn handle_queue(
+        &self,
+        vring: &VringRwLock,
+        process_queue: fn(&Self, &VringRwLock) -> io::Result<()>,
+    ) -> io::Result<()> {
+        if !self.event_idx {
+            return process_queue(self, vring);
+        }
+
+        loop {
+            vring.disable_notification().map_err(io::Error::other)?;
+            process_queue(self, vring)?;
+            if !vring.enable_notification().map_err(io::Error::other)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Signal the guest that buffers have been used, if it wants to be told
+    fn signal_guest(&self, vring: &VringRwLock) -> io::Result<()> {
+        if !self.event_idx || vring.needs_notification().map_err(io::Error::other)? {
+            vring.signal_used_queue()?;
+        }
+
+        Ok(())
+    }
+
+    /// Take every frame the guest has placed on the TX
+    /// queue and hand it to the vswitch
+    fn process_tx(&self, vring: &VringRwLock) -> io::Result<()> {
+        let mem = match &self.mem {
+            Some(mem) if vring.get_ref().get_queue().ready() => mem.memory(),
+            _ => return Ok(()),
+        };
+
+        let chains: Vec<_> = vring
+            .get_mut()
+            .get_queue_mut()
+            .iter(mem.clone())
+            .map_err(io::Error::other)?
+            .collect();
+
+        if chains.is_empty() {
+            return Ok(());
+        }
+
+        for chain in chains {
+            let head_index = chain.head_index();
+            let mut reader = chain.reader(&mem).map_err(io::Error::other)?;
+
+            let mut buf = vec![0u8; reader.available_bytes()];
+            reader.read_exact(&mut buf)?;
+
+            /* Strip the virtio_net_hdr, and ignore anything too small to switch */
+            if buf.len() >= VIRTIO_NET_HDR_LEN + ETHER_HDR {
+                (self.on_frame)(&buf[VIRTIO_NET_HDR_LEN..]);
+            } else {
+                eprintln!(
+                    "Received runt frame which was {} bytes long from vhost-user guest",
+                    buf.len().saturating_sub(VIRTIO_NET_HDR_LEN)
+                );
+            }
+
+            vring.add_used(head_index, 0).map_err(io::Error::other)?;
+        }
+
+        self.signal_guest(vring)
+    }
+
+    /// Copy frames queued by the vswitch into the guest's
+    /// RX buffers, until either runs out
+    fn process_rx(&self, vring: &VringRwLock) -> io::Result<()> {
+        let mem = match &self.mem {
+            Some(mem) if vring.get_ref().get_queue().ready() => mem.memory(),
+            _ => return Ok(()),
+        };
+
+        let mut used_any = false;
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+
+        while let Some(frame) = pending.front() {
+            let chain = vring
+                .get_mut()
+                .get_queue_mut()
+                .iter(mem.clone())
+                .map_err(io::Error::other)?
+                .next();
+
+            /* Guest has no free RX buffers, so leave the frame queued */
+            let Some(chain) = chain else {
+                break;
+            };
+
+            let head_index = chain.head_index();
+            let mut writer = chain.writer(&mem).map_err(io::Error::other)?;
+
+            let mut used_len = 0;
+            if writer.available_bytes() >= VIRTIO_NET_HDR_LEN + frame.len() {
+                /*
+                 * No offloads are negotiated, so the header is all
+                 * zeroes apart from num_buffers, which must be 1
+                 */
+                let mut hdr = [0u8; VIRTIO_NET_HDR_LEN];
+                hdr[VIRTIO_NET_HDR_NUM_BUFFERS] = 1;
+                writer.write_all(&hdr)?;
+                writer.write_all(frame)?;
+                used_len = (VIRTIO_NET_HDR_LEN + frame.len()) as u32;
+            } else {
+                eprintln!(
+                    "Dropped {} byte frame as it does not fit in the guest's RX buffer",
+                    frame.len()
+                );
+            }
+
+            vring
+                .add_used(head_index, used_len)
+                .map_err(io::Error::other)?;
+            used_any = true;
+            pending.pop_front();
+        }
+
+        if used_any {
+            self.signal_guest(vring)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl VhostUserBackendMut for VhostUserNet {
+    type Bitmap = ();
+    type Vring = VringRwLock;
+
+    fn num_queues(&self) -> usize {
+        NUM_QUEUES
+    }
+
+    fn max_queue_size(&self) -> usize {
+        QUEUE_SIZE
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_F_VERSION_1
+            | VIRTIO_RING_F_INDIRECT_DESC
+            | VIRTIO_RING_F_EVENT_IDX
+            | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits()
+    }
+
+    fn protocol_features(&self) -> VhostUserProtocolFeatures {
+        VhostUserProtocolFeatures::MQ | VhostUserProtocolFeatures::REPLY_ACK
+    }
+
+    fn set_event_idx(&mut self, enabled: bool) {
+        self.event_idx = enabled;
+    }
+
+    fn update_memory(&mut self, mem: GuestMemoryAtomic<GuestMemoryMmap>) -> io::Result<()> {
+        self.mem = Some(mem);
+        Ok(())
+    }
+
+    fn exit_event(&self, _thread_index: usize) -> Option<(EventConsumer, EventNotifier)> {
+        new_event_consumer_and_notifier(EventFlag::NONBLOCK).ok()
+    }
+
+    fn handle_event(
+        &mut self,
+        device_event: u16,
+        evset: EventSet,
+        vrings: &[VringRwLock],
+        _thread_id: usize,
+    ) -> io::Result<()> {
+        if evset != EventSet::IN {
+            return Err(io::Error::other(format!(
+                "Unexpected epoll event {:?}",
+                evset
+            )));
+        }
+
+        match device_event {
+            RX_QUEUE => self.handle_queue(&vrings[RX_QUEUE as usize], Self::process_rx),
+            TX_QUEUE => self.handle_queue(&vrings[TX_QUEUE as usize], Self::process_tx),
+            RX_PENDING_EVENT => {
+                /*
+                 * The eventfd is non-blocking, so reading it just
+                 * resets the counter, and EAGAIN means another
+                 * event already consumed the wakeup
+                 */
+                let _ = self.rx_event.read();
+                self.process_rx(&vrings[RX_QUEUE as usize])
+            }
+            _ => Err(io::Error::other(format!(
+                "Unknown vhost-user event {}",
+                device_event
+            ))),
+        }
+    }
+}
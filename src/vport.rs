@@ -0,0 +1,211 @@
+//! vport which can be embedded in another program
+//!
+//! A Vport carries frames between a virtual NIC and the vswitch (or a
+//! Switch, see l2vpn::switch) over the transport it is given (see
+//! l2vpn::transport), e.g. a UDP socket, so a program (or a test) can run an L2VPN
+//! node in-process. The NIC can be a tap interface (see l2vpn::nic), or
+//! anything else which implements VirtualNic, e.g. queues in memory.
+//! What is done with each frame is decided by the same I/O-free core as
+//! the vport binary's (see l2vpn::endpoint). It joins the vswitch,
+//! sends it hellos, and leaves it when it is shut down. What only the
+//! vport binary does, such as its other transports, multihoming and
+//! failing over to backup vswitches, is left out
+//!
+//! run() carries frames until shutdown() is called from another
+//! thread, so the NIC and the transport are read with a timeout of
+//! POLL_INTERVAL
+
+use crate::{
+    endpoint::{Action, Session, VportCore},
+    error::{Error, TapError},
+    tap::VirtualNic,
+    timer::Interval,
+    transport::OverlayTransport,
+    tunnel::{TUNNEL_DATAGRAM_MAX, TUNNEL_FRAME_MAX},
+    utilities::frame_max,
+};
+use std::{
+    net::UdpSocket,
+    panic,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How long run() waits for a frame before checking whether it was told to stop
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often the vport registers with the vswitch
+const HELLO_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often a join the vswitch hasn't answered is sent again
+const JOIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// vport carrying the frames of a virtual NIC to and from the vswitch
+#[derive(Debug)]
+pub struct Vport<T: OverlayTransport = UdpSocket> {
+    /// Decides what is done with each frame
+    core: VportCore,
+    /// Transport which the vswitch is reached over
+    transport: T,
+    /// The vswitch's peer on the transport, which datagrams from any other are ignored from
+    vswitch: T::Peer,
+    /// NIC which the host's frames are read from and written to
+    nic: Box<dyn VirtualNic>,
+    /// ID of our port on the vswitch, once it has answered our join
+    port_id: OnceLock<u32>,
+    /// Set by shutdown(), to stop run()
+    stopping: AtomicBool,
+}
+
+impl<T: OverlayTransport> Vport<T> {
+    /// Returns a vport in session, in an L2VPN network with an MTU of
+    /// mtu, which carries the frames of nic over transport, to and from
+    /// the vswitch at its peer vswitch. A UDP socket is sent to with
+    /// send_to(), so shouldn't be connected, as some platforms refuse that
+    pub fn new(
+        session: Session,
+        mtu: usize,
+        transport: T,
+        vswitch: T::Peer,
+        nic: Box<dyn VirtualNic>,
+    ) -> Vport<T> {
+        Vport {
+            core: VportCore::new(session, mtu, None, None),
+            transport,
+            vswitch,
+            nic,
+            port_id: OnceLock::new(),
+            stopping: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns the ID of our port on the vswitch, or None until it has answered our join
+    pub fn port_id(&self) -> Option<u32> {
+        self.port_id.get().copied()
+    }
+
+    /// Carry frames until shutdown() is called, then leave the vswitch,
+    /// returning the error which stopped the vport if something else does
+    pub fn run(&self) -> Result<(), Error> {
+        self.transport.set_read_timeout(Some(POLL_INTERVAL))?;
+
+        /* Each direction has a thread of its own, and either failing stops the other */
+        let result = thread::scope(|scope| {
+            let tap = scope.spawn(|| self.stop_on_error(self.tap_to_vswitch()));
+            let vswitch = self.stop_on_error(self.vswitch_to_tap());
+            let tap = tap.join().unwrap_or_else(|e| panic::resume_unwind(e));
+            vswitch.and(tap)
+        });
+
+        /* The vswitch flushes our MACs straight away, rather than once it stops hearing from us */
+        if let Err(e) = self
+            .transport
+            .send_to_peer(&self.core.leave(), &self.vswitch)
+        {
+            eprintln!("Got error while leaving vswitch: '{}'", e);
+        }
+        result
+    }
+
+    /// Stop run(), which returns within POLL_INTERVAL
+    pub fn shutdown(&self) {
+        self.stopping.store(true, Ordering::Relaxed);
+    }
+
+    /// Stop the other direction if result is an error, and return it
+    fn stop_on_error(&self, result: Result<(), Error>) -> Result<(), Error> {
+        if result.is_err() {
+            self.shutdown();
+        }
+        result
+    }
+
+    /// Forward the frames read from the NIC to the vswitch
+    fn tap_to_vswitch(&self) -> Result<(), Error> {
+        let mut buf = [0u8; TUNNEL_FRAME_MAX];
+        let len_max = frame_max(self.core.mtu());
+
+        while !self.stopping.load(Ordering::Relaxed) {
+            if !self.nic.readable(POLL_INTERVAL).map_err(TapError::Read)? {
+                continue;
+            }
+            let len = self.nic.read(&mut buf[..len_max]).map_err(TapError::Read)?;
+            if len == 0 {
+                return Err(TapError::Eof.into());
+            }
+
+            match self.core.from_tap(&mut buf, len) {
+                Action::Forward(len) => {
+                    if let Err(e) = self.transport.send_to_peer(&buf[..len], &self.vswitch) {
+                        eprintln!("Dropped frame as sending it to the vswitch failed: '{}'", e);
+                    }
+                }
+                Action::Reply(reply) => {
+                    if let Err(e) = self.nic.write(&reply) {
+                        eprintln!("Got error while sending reply to the NIC: '{}'", e);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Forward the frames received from the vswitch to the NIC, joining
+    /// the vswitch until it answers, and sending it hellos
+    fn vswitch_to_tap(&self) -> Result<(), Error> {
+        let mut buf = [0u8; TUNNEL_DATAGRAM_MAX];
+        let mut join = Interval::new(JOIN_INTERVAL);
+        let mut hello = Interval::new(HELLO_INTERVAL);
+
+        while !self.stopping.load(Ordering::Relaxed) {
+            let now = Instant::now();
+            let mut msgs = Vec::new();
+            if self.port_id.get().is_none() && join.due(now) {
+                msgs.push(self.core.join(true, 0, None, None, None, None));
+            }
+            if hello.due(now) {
+                msgs.extend(self.core.hellos(true));
+            }
+            for msg in msgs {
+                if let Err(e) = self.transport.send_to_peer(&msg, &self.vswitch) {
+                    eprintln!("Got error while registering with vswitch: '{}'", e);
+                }
+            }
+
+            let len = match self.transport.recv_from_peer(&mut buf) {
+                Ok((len, peer)) if peer == self.vswitch => len,
+                Ok(_) => continue,
+                Err(e) => {
+                    /* Nothing arrived in time, or the vswitch isn't up yet, so refused our join */
+                    if e.is_transient() {
+                        continue;
+                    }
+                    return Err(e.into());
+                }
+            };
+
+            match self.core.from_vswitch(&mut buf, len, 0, None) {
+                Action::Forward(len) => {
+                    if let Err(e) = self.nic.write(&buf[..len]) {
+                        eprintln!("Dropped frame as writing it to the NIC failed: '{}'", e);
+                    }
+                }
+                Action::Reply(reply) => {
+                    if let Err(e) = self.transport.send_to_peer(&reply, &self.vswitch) {
+                        eprintln!("Got error while answering vswitch: '{}'", e);
+                    }
+                }
+                Action::Joined { port_id, .. } if self.port_id.set(port_id).is_ok() => {
+                    println!("Joined the vswitch as port {}", port_id);
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
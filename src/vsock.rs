@@ -0,0 +1,144 @@
+//! AF_VSOCK transport between vports and the vswitch
+//!
+//! This lets a vport running inside a VM reach a vswitch
+//! running on the hypervisor without the guest needing any
+//! IP networking for the underlay.
+//!
+//! vsock sockets are connection oriented, so each frame is
+//! sent with a 4 byte big-endian length prefix, which is the
+//! same framing QEMU uses for its stream netdevs
+
+use crate::{
+    error::TransportError,
+    platform::{socket, SEND_FLAGS},
+};
+use nix::{
+    libc::VMADDR_CID_ANY,
+    sys::socket::{
+        accept, bind, connect, getpeername, listen, recv, send, AddressFamily, Backlog, MsgFlags,
+        SockType, VsockAddr,
+    },
+};
+use std::{
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+/// Size of the length prefix which precedes each frame on the stream
+const LEN_PREFIX: usize = 4;
+
+/// Connected vsock stream carrying length-prefixed frames
+#[derive(Debug)]
+pub struct VsockStream {
+    fd: OwnedFd,
+    /* Shared between clones so frames sent from different threads can't interleave */
+    tx_lock: Arc<Mutex<()>>,
+}
+
+impl VsockStream {
+    /// Connect to the vswitch listening on the given vsock CID and port
+    pub fn connect(cid: u32, port: u32) -> Result<VsockStream, TransportError> {
+        let fd = socket(AddressFamily::Vsock, SockType::Stream, None)?;
+        connect(fd.as_raw_fd(), &VsockAddr::new(cid, port))?;
+
+        Ok(VsockStream {
+            fd,
+            tx_lock: Arc::new(Mutex::new(())),
+        })
+    }
+
+    /// Returns the (CID, port) of the other end of the stream
+    pub fn peer_addr(&self) -> Result<(u32, u32), TransportError> {
+        let addr: VsockAddr = getpeername(self.fd.as_raw_fd())?;
+        Ok((addr.cid(), addr.port()))
+    }
+
+    /// Returns another handle to the same stream, so that
+    /// one thread can send while another receives
+    pub fn try_clone(&self) -> Result<VsockStream, TransportError> {
+        Ok(VsockStream {
+            fd: self.fd.try_clone()?,
+            tx_lock: self.tx_lock.clone(),
+        })
+    }
+
+    /// Send a single frame over the stream
+    pub fn send_frame(&self, frame: &[u8]) -> Result<(), TransportError> {
+        let mut msg = Vec::with_capacity(LEN_PREFIX + frame.len());
+        msg.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        msg.extend_from_slice(frame);
+
+        /*
+         * SEND_FLAGS stop a disconnected peer from killing
+         * the process with SIGPIPE, so it is reported as an error
+         */
+        let _tx = self.tx_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut sent = 0;
+        while sent < msg.len() {
+            sent += send(self.fd.as_raw_fd(), &msg[sent..], SEND_FLAGS)?;
+        }
+
+        Ok(())
+    }
+
+    /// Receive a single frame into buf, returning its length
+    ///
+    /// Returns a Closed error if the peer has disconnected,
+    /// and a FrameTooLarge error if the frame is larger than buf
+    pub fn recv_frame(&self, buf: &mut [u8]) -> Result<usize, TransportError> {
+        let mut len_prefix = [0u8; LEN_PREFIX];
+        self.recv_exact(&mut len_prefix)?;
+
+        let len = u32::from_be_bytes(len_prefix) as usize;
+        if len > buf.len() {
+            return Err(TransportError::FrameTooLarge {
+                len,
+                max: buf.len(),
+            });
+        }
+
+        self.recv_exact(&mut buf[..len])?;
+        Ok(len)
+    }
+
+    /// Fill buf from the stream, waiting for more data as required
+    fn recv_exact(&self, buf: &mut [u8]) -> Result<(), TransportError> {
+        let mut received = 0;
+        while received < buf.len() {
+            match recv(self.fd.as_raw_fd(), &mut buf[received..], MsgFlags::empty())? {
+                0 => return Err(TransportError::Closed),
+                n => received += n,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// vsock socket which accepts connections from vports
+#[derive(Debug)]
+pub struct VsockListener {
+    fd: OwnedFd,
+}
+
+impl VsockListener {
+    /// Listen for connections on the given port from any CID
+    pub fn bind(port: u32) -> Result<VsockListener, TransportError> {
+        let fd = socket(AddressFamily::Vsock, SockType::Stream, None)?;
+        bind(fd.as_raw_fd(), &VsockAddr::new(VMADDR_CID_ANY, port))?;
+        listen(&fd, Backlog::MAXCONN)?;
+
+        Ok(VsockListener { fd })
+    }
+
+    /// Wait for a vport to connect, and return the stream to it
+    pub fn accept(&self) -> Result<VsockStream, TransportError> {
+        let fd = accept(self.fd.as_raw_fd())?;
+
+        /* Safe as accept returned a new fd which nothing else owns */
+        Ok(VsockStream {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            tx_lock: Arc::new(Mutex::new(())),
+        })
+    }
+}
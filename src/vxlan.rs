@@ -0,0 +1,49 @@
+//! VXLAN encapsulation of the frames between vports and the vswitch
+//!
+//! In VXLAN mode, every frame is carried in a UDP datagram after the
+//! 8 byte header of RFC 7348, which holds a flag saying the VNI is
+//! valid and the 24 bit VNI of the network the frame belongs to. This
+//! lets the vswitch exchange frames with Linux VXLAN devices and
+//! hardware VTEPs, as well as with vports
+//!
+//! VTEPs send their datagrams from a source port picked by hashing
+//! each flow, and receive on the VXLAN port (4789 unless configured
+//! otherwise), so datagrams are always sent to the VXLAN port of
+//! their destination, rather than to the port they came from
+
+/// UDP port assigned to VXLAN by IANA
+pub const VXLAN_PORT: u16 = 4789;
+
+/// Length of the VXLAN header in front of every frame
+pub const VXLAN_HDR_LEN: usize = 8;
+
+/// Largest VNI, which is 24 bits long
+pub const MAX_VNI: u32 = 0xFF_FFFF;
+
+/// Flag in the first byte of the header saying the VNI is valid
+const FLAG_VNI: u8 = 0x08;
+
+/// Parse a VNI given on the command line, which is 24 bits
+/// long in GENEVE as well as VXLAN
+pub fn parse_vni(value: &str) -> Option<u32> {
+    value.parse::<u32>().ok().filter(|vni| *vni <= MAX_VNI)
+}
+
+/// Returns frame after a VXLAN header carrying vni, ready to be sent
+pub fn encapsulate(vni: u32, frame: &[u8]) -> Vec<u8> {
+    let [_, vni_hi, vni_mid, vni_lo] = vni.to_be_bytes();
+    let mut datagram = Vec::with_capacity(VXLAN_HDR_LEN + frame.len());
+    datagram.extend_from_slice(&[FLAG_VNI, 0, 0, 0, vni_hi, vni_mid, vni_lo, 0]);
+    datagram.extend_from_slice(frame);
+    datagram
+}
+
+/// Returns the VNI of the VXLAN datagram, and the frame it carries,
+/// or None if it is too short or its VNI isn't flagged as valid
+pub fn decapsulate(datagram: &[u8]) -> Option<(u32, &[u8])> {
+    if datagram.len() < VXLAN_HDR_LEN || datagram[0] & FLAG_VNI == 0 {
+        return None;
+    }
+    let vni = u32::from_be_bytes([0, datagram[4], datagram[5], datagram[6]]);
+    Some((vni, &datagram[VXLAN_HDR_LEN..]))
+}